@@ -0,0 +1,38 @@
+//! Answers `!echo <text>` with the text and `!ping` with `pong`, at most
+//! ten times a minute per sender. Stops on Ctrl-C.
+//!
+//!     cargo run --example echo_bot -- [bot_id] [server_addr]
+
+use messaging_proto::bot::{BotBuilder, Logging, RateLimit};
+use messaging_proto::client::Client;
+use messaging_proto::paths::ClientPaths;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let mut args = std::env::args().skip(1);
+    let id = args.next().unwrap_or_else(|| "echo-bot".to_string());
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+
+    let paths = ClientPaths::resolve(None)?;
+    paths.create()?;
+    let key_file = paths.key_file(&id);
+    let mut client = Client::new(&id, paths, key_file, false)?;
+    client.connect_to(&addr).await?;
+
+    let bot = BotBuilder::new(client)
+        .middleware(Logging)
+        .middleware(RateLimit::per_user(10, Duration::from_secs(60)))
+        .command("!echo", |ctx| async move {
+            ctx.reply(ctx.args.clone());
+            Ok(())
+        })
+        .command("!ping", |ctx| async move {
+            ctx.reply("pong");
+            Ok(())
+        })
+        .build();
+    bot.run_until(tokio::signal::ctrl_c()).await;
+    Ok(())
+}
@@ -0,0 +1,52 @@
+//! `!remind 10m stretch` answers "⏰ stretch" ten minutes later; units are
+//! s, m and h. The server has no scheduled delivery, so the bot holds each
+//! reminder until it is due, and reminders still pending when it stops on
+//! Ctrl-C are lost.
+//!
+//!     cargo run --example reminder_bot -- [bot_id] [server_addr]
+
+use anyhow::anyhow;
+use messaging_proto::bot::{BotBuilder, Logging};
+use messaging_proto::client::Client;
+use messaging_proto::paths::ClientPaths;
+use regex::Regex;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let mut args = std::env::args().skip(1);
+    let id = args.next().unwrap_or_else(|| "reminder-bot".to_string());
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+
+    let paths = ClientPaths::resolve(None)?;
+    paths.create()?;
+    let key_file = paths.key_file(&id);
+    let mut client = Client::new(&id, paths, key_file, false)?;
+    client.connect_to(&addr).await?;
+
+    let bot = BotBuilder::new(client)
+        .middleware(Logging)
+        .pattern(Regex::new(r"^!remind (\d+)([smh]) (.+)$")?, |ctx| async move {
+            let (Some(Some(amount)), Some(Some(unit)), Some(Some(text))) = (ctx.captures.get(1), ctx.captures.get(2), ctx.captures.get(3)) else {
+                return Err(anyhow!("the pattern always captures three groups"));
+            };
+            let seconds = amount.parse::<u64>()? * match unit.as_str() {
+                "h" => 3600,
+                "m" => 60,
+                _ => 1,
+            };
+            ctx.reply(format!("👍 I'll remind you in {}{}", amount, unit));
+            tokio::time::sleep(Duration::from_secs(seconds)).await;
+            ctx.reply(format!("⏰ {}", text));
+            Ok(())
+        })
+        .command("!remind", |ctx| async move {
+            ctx.reply("Usage: !remind <n><s|m|h> <text>");
+            Ok(())
+        })
+        .shutdown_grace(Duration::from_secs(1))
+        .build();
+    bot.run_until(tokio::signal::ctrl_c()).await;
+    Ok(())
+}
@@ -0,0 +1,327 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// Alerts waiting for the sink; past this, new ones are dropped, not queued.
+const ALERT_QUEUE_LEN: usize = 64;
+/// Delivery attempts per alert, waiting twice as long after each failure.
+const DELIVERY_ATTEMPTS: u32 = 5;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an alert of the same type about the same subject is suppressed.
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Webhook header carrying the HMAC-SHA256 of the body, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Msgproto-Signature";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// An admin used an admin-only command, or someone tried to.
+    AdminAction,
+    /// Stored messages failed signature verification and were quarantined.
+    IntegrityFailure,
+    StorageDegraded,
+    StorageRecovered,
+    /// Data went over the warning size.
+    DiskThreshold,
+}
+
+impl AlertKind {
+    pub const ALL: [AlertKind; 5] = [
+        AlertKind::AdminAction, AlertKind::IntegrityFailure, AlertKind::StorageDegraded,
+        AlertKind::StorageRecovered, AlertKind::DiskThreshold,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AlertKind::AdminAction => "admin_action",
+            AlertKind::IntegrityFailure => "integrity_failure",
+            AlertKind::StorageDegraded => "storage_degraded",
+            AlertKind::StorageRecovered => "storage_recovered",
+            AlertKind::DiskThreshold => "disk_threshold",
+        }
+    }
+
+    pub fn severity(self) -> Severity {
+        match self {
+            AlertKind::AdminAction | AlertKind::StorageRecovered => Severity::Info,
+            AlertKind::DiskThreshold => Severity::Warning,
+            AlertKind::IntegrityFailure | AlertKind::StorageDegraded => Severity::Critical,
+        }
+    }
+}
+
+impl std::str::FromStr for AlertKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        AlertKind::ALL.into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| anyhow!("unknown alert type '{}'; use {}", s,
+                AlertKind::ALL.map(AlertKind::name).join(", ")))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// What a sink receives, as JSON.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    #[serde(rename = "type")]
+    pub kind: AlertKind,
+    pub severity: Severity,
+    pub message: String,
+    pub details: BTreeMap<String, String>,
+    pub at: DateTime<Utc>,
+}
+
+/// Where alerts go.
+#[derive(Debug, Clone)]
+pub enum Sink {
+    /// POST the alert to an HTTP(S) URL, signed with the shared secret.
+    Webhook { url: String, secret: Vec<u8> },
+    /// Run a program with the alert on stdin. No shell is involved.
+    Command { program: String, args: Vec<String> },
+}
+
+pub struct AlertConfig {
+    pub sink: Option<Sink>,
+    pub enabled: BTreeSet<AlertKind>,
+    pub dedup_window: Duration,
+}
+
+/// Operator alerts. Raising one never waits on the sink: alerts go through a
+/// bounded queue to a single delivery task.
+pub struct Alerts {
+    queue: Option<mpsc::Sender<Alert>>,
+    enabled: BTreeSet<AlertKind>,
+    dedup_window: Duration,
+    // (type, subject) -> when it was last raised
+    recent: Mutex<HashMap<(AlertKind, String), Instant>>,
+    dropped: AtomicU64,
+}
+
+impl Alerts {
+    /// Start delivering to the configured sink, if any. Needs a tokio runtime.
+    pub fn start(config: AlertConfig) -> Self {
+        let queue = config.sink.map(|sink| {
+            let (sender, receiver) = mpsc::channel(ALERT_QUEUE_LEN);
+            tokio::spawn(deliver_all(sink, receiver));
+            sender
+        });
+        Self {
+            queue,
+            enabled: config.enabled,
+            dedup_window: config.dedup_window,
+            recent: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Raise an alert about `subject` (a client id, a component, or "" for
+    /// the server as a whole), unless its type is disabled or the same alert
+    /// was raised within the dedup window.
+    pub fn raise(&self, kind: AlertKind, subject: &str, message: impl Into<String>, details: &[(&str, String)]) {
+        let Some(queue) = &self.queue else { return };
+        if !self.enabled.contains(&kind) {
+            return;
+        }
+        {
+            let now = Instant::now();
+            let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+            recent.retain(|_, raised| now.duration_since(*raised) < self.dedup_window);
+            if recent.contains_key(&(kind, subject.to_string())) {
+                return;
+            }
+            recent.insert((kind, subject.to_string()), now);
+        }
+
+        let alert = Alert {
+            kind,
+            severity: kind.severity(),
+            message: message.into(),
+            details: details.iter().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+            at: Utc::now(),
+        };
+        if queue.try_send(alert).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("🚨 Alert queue is full; dropped alert {} ({} dropped so far)", kind.name(), dropped);
+        }
+    }
+
+    /// Alerts waiting for the sink, the most that can wait, and how many
+    /// were dropped because the queue was full. None without a sink.
+    pub fn queue_depth(&self) -> Option<(usize, usize, u64)> {
+        let queue = self.queue.as_ref()?;
+        Some((queue.max_capacity() - queue.capacity(), queue.max_capacity(), self.dropped.load(Ordering::Relaxed)))
+    }
+}
+
+async fn deliver_all(sink: Sink, mut receiver: mpsc::Receiver<Alert>) {
+    while let Some(alert) = receiver.recv().await {
+        let body = match serde_json::to_string(&alert) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("🚨 Failed to encode alert {}: {}", alert.kind.name(), e);
+                continue;
+            }
+        };
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            match deliver(&sink, &body).await {
+                Ok(()) => break,
+                Err(e) if attempt == DELIVERY_ATTEMPTS => {
+                    error!("🚨 Gave up delivering alert {} after {} attempts: {}", alert.kind.name(), attempt, e);
+                }
+                Err(e) => {
+                    warn!("🚨 Failed to deliver alert {}, retrying in {}s: {}", alert.kind.name(), delay.as_secs(), e);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+}
+
+async fn deliver(sink: &Sink, body: &str) -> Result<()> {
+    match sink {
+        Sink::Webhook { url, secret } => {
+            let (url, body) = (url.clone(), body.to_string());
+            let signature = format!("sha256={}", sign(secret, body.as_bytes())?);
+            tokio::task::spawn_blocking(move || {
+                ureq::AgentBuilder::new().timeout(DELIVERY_TIMEOUT).build()
+                    .post(&url)
+                    .set("Content-Type", "application/json")
+                    .set(SIGNATURE_HEADER, &signature)
+                    .send_string(&body)
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("{}", e))
+            }).await?
+        }
+        Sink::Command { program, args } => {
+            let mut child = tokio::process::Command::new(program)
+                .args(args)
+                .stdin(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            // A program that doesn't read the alert may exit first; its status decides
+            if let Some(mut stdin) = child.stdin.take() {
+                match stdin.write_all(body.as_bytes()).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            let status = tokio::time::timeout(DELIVERY_TIMEOUT, child.wait()).await
+                .map_err(|_| anyhow!("{} took longer than {}s", program, DELIVERY_TIMEOUT.as_secs()))??;
+            if !status.success() {
+                return Err(anyhow!("{} exited with {}", program, status));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `body`, for receivers to check against the shared secret.
+pub fn sign(secret: &[u8], body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|e| anyhow!("Invalid alert secret: {}", e))?;
+    mac.update(body);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// A webhook receiver on a free local port: each request's headers,
+    /// lowercased, and body, as they arrive.
+    async fn capture() -> (String, mpsc::UnboundedReceiver<(HashMap<String, String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    // One connection may carry several requests
+                    loop {
+                        let mut headers = HashMap::new();
+                        let mut line = String::new();
+                        if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        loop {
+                            line.clear();
+                            stream.read_line(&mut line).await.unwrap();
+                            let Some((name, value)) = line.trim_end().split_once(':') else { break };
+                            headers.insert(name.to_lowercase(), value.trim().to_string());
+                        }
+                        let mut body = vec![0; headers["content-length"].parse().unwrap()];
+                        stream.read_exact(&mut body).await.unwrap();
+                        stream.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+                        let _ = sender.send((headers, String::from_utf8(body).unwrap()));
+                    }
+                });
+            }
+        });
+        (url, received)
+    }
+
+    #[test]
+    fn sign_matches_a_known_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(sign(b"Jefe", b"what do ya want for nothing?").unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[tokio::test]
+    async fn webhooks_are_signed_with_the_shared_secret() {
+        let (url, mut received) = capture().await;
+        let secret = b"shared with the receiver".to_vec();
+        let alerts = Alerts::start(AlertConfig {
+            sink: Some(Sink::Webhook { url, secret: secret.clone() }),
+            enabled: AlertKind::ALL.into_iter().filter(|kind| *kind != AlertKind::StorageRecovered).collect(),
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+        });
+        alerts.raise(AlertKind::AdminAction, "root", "root banned mallory", &[("actor", "root".to_string())]);
+        // Repeats within the window, and disabled types, never go out
+        alerts.raise(AlertKind::AdminAction, "root", "root banned mallory again", &[]);
+        alerts.raise(AlertKind::StorageRecovered, "", "storage is back", &[]);
+        alerts.raise(AlertKind::DiskThreshold, "", "data is over the warning size", &[]);
+
+        let mut kinds = Vec::new();
+        for _ in 0..2 {
+            let (headers, body) = tokio::time::timeout(Duration::from_secs(10), received.recv()).await.unwrap().unwrap();
+            assert_eq!(headers["content-type"], "application/json");
+            assert_eq!(headers[&SIGNATURE_HEADER.to_lowercase()], format!("sha256={}", sign(&secret, body.as_bytes()).unwrap()));
+            assert_ne!(headers[&SIGNATURE_HEADER.to_lowercase()], format!("sha256={}", sign(b"another secret", body.as_bytes()).unwrap()));
+            let alert: serde_json::Value = serde_json::from_str(&body).unwrap();
+            kinds.push(alert["type"].as_str().unwrap().to_string());
+            if alert["type"] == "admin_action" {
+                assert_eq!(alert["message"], "root banned mallory");
+                assert_eq!(alert["details"]["actor"], "root");
+                assert_eq!(alert["severity"], "info");
+            }
+        }
+        assert_eq!(kinds, ["admin_action", "disk_threshold"]);
+        assert!(tokio::time::timeout(Duration::from_millis(200), received.recv()).await.is_err(), "a suppressed alert was delivered");
+    }
+}
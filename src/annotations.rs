@@ -0,0 +1,61 @@
+use crate::types::Hlc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Key of the starred flag, shown as a star rather than a label.
+pub const STARRED: &str = "starred";
+
+/// One key of a message's annotations, stamped with the server clock value
+/// of the annotation that set it. A `None` value records a removal, so an
+/// older annotation can't bring the key back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationValue {
+    pub value: Option<String>,
+    pub hlc: Hlc,
+}
+
+/// Private metadata on messages: labels, the starred flag, or any other
+/// key/value pair. Devices sharing an identity sync it by sending each other
+/// encrypted annotations addressed to self, which the server can't tell from
+/// ordinary messages. Each key is last-writer-wins by clock value, so devices
+/// converge whatever order annotations arrive in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Annotations {
+    /// message id -> key -> value
+    #[serde(default)]
+    pub messages: BTreeMap<String, BTreeMap<String, AnnotationValue>>,
+}
+
+impl Annotations {
+    /// Merge values for one message, as of `hlc`. Returns whether anything changed.
+    pub fn apply(&mut self, message_id: &str, values: &BTreeMap<String, Option<String>>, hlc: Hlc) -> bool {
+        let entries = self.messages.entry(message_id.to_string()).or_default();
+        let mut changed = false;
+        for (key, value) in values {
+            if entries.get(key).is_some_and(|current| current.hlc >= hlc) {
+                continue;
+            }
+            entries.insert(key.clone(), AnnotationValue { value: value.clone(), hlc });
+            changed = true;
+        }
+        changed
+    }
+
+    /// Merge another set, such as one restored from an export.
+    pub fn merge(&mut self, other: Annotations) {
+        for (message_id, entries) in other.messages {
+            for (key, entry) in entries {
+                self.apply(&message_id, &BTreeMap::from([(key, entry.value)]), entry.hlc);
+            }
+        }
+    }
+
+    /// Whether the message is starred, and its other labels in order.
+    pub fn labels(&self, message_id: &str) -> (bool, Vec<String>) {
+        let Some(entries) = self.messages.get(message_id) else { return (false, Vec::new()) };
+        let set = |key: &String| entries[key].value.is_some();
+        let starred = entries.get(STARRED).is_some_and(|entry| entry.value.is_some());
+        let labels = entries.keys().filter(|key| key.as_str() != STARRED && set(key)).cloned().collect();
+        (starred, labels)
+    }
+}
@@ -0,0 +1,161 @@
+use crate::storage::Storage;
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a challenge can be answered for.
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+/// Unanswered challenges kept at most; the oldest are dropped past this.
+const MAX_CHALLENGES: usize = 4096;
+
+/// What a provider says about a registration.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthDecision {
+    Allow,
+    Deny { reason: String },
+    /// The id may register, but only with a credential, and none was given.
+    RequireCredential,
+}
+
+/// Decides who may register a new id. Ids already registered re-register
+/// without asking, as clients do on every connect.
+pub trait AuthProvider: Send + Sync {
+    /// Name shown in logs and at startup.
+    fn name(&self) -> &'static str;
+
+    /// Whether `client_id` may register, given the credential it presented.
+    /// Providers that consume credentials do so here, through `storage`.
+    fn check<'a>(&'a self, storage: &'a Storage, client_id: &'a str, credential: Option<&'a str>) -> BoxFuture<'a, Result<AuthDecision>>;
+
+    /// Give back what `check` consumed when the registration it allowed
+    /// then failed, so the credential can be tried again.
+    fn release<'a>(&'a self, _storage: &'a Storage, _client_id: &'a str, _credential: Option<&'a str>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Anyone may register any free id.
+pub struct Open;
+
+impl AuthProvider for Open {
+    fn name(&self) -> &'static str {
+        "open"
+    }
+
+    fn check<'a>(&'a self, _storage: &'a Storage, _client_id: &'a str, _credential: Option<&'a str>) -> BoxFuture<'a, Result<AuthDecision>> {
+        Box::pin(async { Ok(AuthDecision::Allow) })
+    }
+}
+
+/// Only ids listed in a file, one per line, may register. Blank lines and
+/// lines starting with `#` are skipped.
+pub struct AllowList {
+    ids: HashSet<String>,
+}
+
+impl AllowList {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read allow list {}: {}", path.display(), e))?;
+        let ids = contents.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        Ok(AllowList { ids })
+    }
+}
+
+impl AuthProvider for AllowList {
+    fn name(&self) -> &'static str {
+        "allow-list"
+    }
+
+    fn check<'a>(&'a self, _storage: &'a Storage, client_id: &'a str, _credential: Option<&'a str>) -> BoxFuture<'a, Result<AuthDecision>> {
+        Box::pin(async move {
+            Ok(if self.ids.contains(client_id) {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::Deny { reason: format!("{} is not on this server's allow list", client_id) }
+            })
+        })
+    }
+}
+
+/// Registering needs an invite code an admin made. Each code works once.
+pub struct InviteCodes;
+
+impl AuthProvider for InviteCodes {
+    fn name(&self) -> &'static str {
+        "invite-code"
+    }
+
+    fn check<'a>(&'a self, storage: &'a Storage, client_id: &'a str, credential: Option<&'a str>) -> BoxFuture<'a, Result<AuthDecision>> {
+        Box::pin(async move {
+            let Some(code) = credential else { return Ok(AuthDecision::RequireCredential) };
+            Ok(if storage.redeem_invite_code(code, client_id).await? {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::Deny { reason: "The invite code is unknown or already used".to_string() }
+            })
+        })
+    }
+
+    fn release<'a>(&'a self, storage: &'a Storage, client_id: &'a str, credential: Option<&'a str>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if let Some(code) = credential {
+                storage.restore_invite_code(code, client_id).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Nonces handed to clients that must prove they hold an id's key before
+/// `Register` or `GetMessages` acts. Each is for one id and answers once.
+#[derive(Default)]
+pub struct Challenges {
+    /// Nonce -> the id it was issued for, and when.
+    pending: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Challenges {
+    /// A fresh nonce for `client_id` to sign.
+    pub fn issue(&self, client_id: &str, now: Instant) -> String {
+        let nonce = hex::encode(rand::random::<[u8; 32]>());
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, (_, issued)| now.duration_since(*issued) < CHALLENGE_TTL);
+        if pending.len() >= MAX_CHALLENGES {
+            if let Some(oldest) = pending.iter().min_by_key(|(_, (_, issued))| *issued).map(|(nonce, _)| nonce.clone()) {
+                pending.remove(&oldest);
+            }
+        }
+        pending.insert(nonce.clone(), (client_id.to_string(), now));
+        nonce
+    }
+
+    /// Use up `nonce`. False if it wasn't issued for `client_id`, has
+    /// expired, or was already used.
+    pub fn redeem(&self, nonce: &str, client_id: &str, now: Instant) -> bool {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        match pending.remove(nonce) {
+            Some((issued_for, issued)) => issued_for == client_id && now.duration_since(issued) < CHALLENGE_TTL,
+            None => false,
+        }
+    }
+}
+
+/// The provider named by `--registration`: `open`, `allow-list` (which
+/// needs `allow_list`, a file) or `invite-code`.
+pub fn provider(name: &str, allow_list: Option<&str>) -> Result<Box<dyn AuthProvider>> {
+    match (name, allow_list) {
+        ("open", _) => Ok(Box::new(Open)),
+        ("allow-list", Some(path)) => Ok(Box::new(AllowList::load(Path::new(path))?)),
+        ("allow-list", None) => Err(anyhow!("--registration allow-list needs --allow-list <file>")),
+        ("invite-code", _) => Ok(Box::new(InviteCodes)),
+        (other, _) => Err(anyhow!("unknown registration policy '{}'; use open, allow-list or invite-code", other)),
+    }
+}
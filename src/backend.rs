@@ -0,0 +1,1004 @@
+use crate::secure_fs;
+use crate::output::{eout, out};
+use crate::types::{ClientInfo, Message};
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use redb::{Database, ReadableTable, TableDefinition};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Mailbox logs of the JSON backend, relative to the data directory.
+pub const MESSAGES_DIR: &str = "messages";
+/// Where the JSON backend kept every mailbox, before it kept one log each.
+pub const MESSAGES_FILE: &str = "messages.json";
+/// Clients file of the JSON backend, relative to the data directory.
+pub const CLIENTS_FILE: &str = "clients.json";
+/// When clients were last seen since the JSON backend last wrote
+/// `clients.json`, relative to the data directory.
+pub const LAST_SEEN_FILE: &str = "last_seen.jsonl";
+/// Database of the SQLite backend, relative to the data directory.
+pub const SQLITE_FILE: &str = "storage.db";
+/// Database of the redb backend, relative to the data directory.
+pub const REDB_FILE: &str = "storage.redb";
+/// Size at which a mailbox log is compacted, unless it was more than half
+/// that straight after its last compaction.
+pub const DEFAULT_COMPACT_AT_BYTES: u64 = 1024 * 1024;
+
+/// Which backend keeps the server's mailboxes and clients on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Json,
+    Sqlite,
+    Redb,
+}
+
+impl StorageKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageKind::Json => "json",
+            StorageKind::Sqlite => "sqlite",
+            StorageKind::Redb => "redb",
+        }
+    }
+}
+
+impl std::str::FromStr for StorageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(StorageKind::Json),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "redb" => Ok(StorageKind::Redb),
+            other => Err(anyhow!("unknown storage '{}'; use json, sqlite or redb", other)),
+        }
+    }
+}
+
+/// Where the server's mailboxes and clients are kept. `Storage` holds them
+/// in memory as well, for lookups across every mailbox, and tells the
+/// backend about each change as it makes it. A failed call changed nothing.
+pub trait StorageBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Insert a client, or replace the one with the same id.
+    fn register_client(&self, info: &ClientInfo) -> Result<()>;
+    fn get_client_info(&self, client_id: &str) -> Result<Option<ClientInfo>>;
+    fn get_all_clients(&self) -> Result<HashMap<String, ClientInfo>>;
+    /// Does nothing for an unknown client.
+    fn update_last_seen(&self, client_id: &str, at: DateTime<Utc>) -> Result<()>;
+    /// Append a message to its recipient's mailbox.
+    fn add_message(&self, message: &Message) -> Result<()>;
+    /// A mailbox in clock order; messages of the same clock value, as from
+    /// before there was one, in the order they were added.
+    fn get_messages_for_client(&self, client_id: &str) -> Result<Vec<Message>>;
+    /// Remove messages from a mailbox; returns how many were there.
+    fn delete_messages(&self, client_id: &str, message_ids: &[String]) -> Result<usize>;
+    /// Overwrite stored messages with these versions, matched by id; any no
+    /// longer in the mailbox are skipped.
+    fn update_messages(&self, client_id: &str, messages: &[Message]) -> Result<()>;
+    /// Every mailbox, each in clock order.
+    fn get_all_messages(&self) -> Result<HashMap<String, Vec<Message>>>;
+    /// Replace every mailbox, as when a standby takes a primary's copy.
+    fn replace_all_messages(&self, messages: &HashMap<String, Vec<Message>>) -> Result<()>;
+    /// Replace every client, as when a standby takes a primary's copy.
+    fn replace_all_clients(&self, clients: &HashMap<String, ClientInfo>) -> Result<()>;
+    /// Make sure everything is on disk, before a shutdown.
+    fn flush(&self) -> Result<()>;
+    /// Reclaim the space taken by deleted messages and by the versions of
+    /// messages since rewritten, such as before they were delivered, where
+    /// it has grown enough to be worth it. Returns how many mailboxes were.
+    fn compact(&self) -> Result<usize> {
+        Ok(0)
+    }
+    /// Bytes on disk, by storage component.
+    fn disk_usage(&self) -> Vec<(&'static str, u64)>;
+}
+
+/// Open the selected backend in `data_dir`, moving the JSON backend's
+/// files into SQLite or redb the first time either is selected.
+pub fn open(kind: StorageKind, data_dir: &Path) -> Result<Box<dyn StorageBackend>> {
+    let json_files = [data_dir.join(MESSAGES_DIR), data_dir.join(MESSAGES_FILE), data_dir.join(CLIENTS_FILE)];
+    let has_json = json_files.iter().any(|file| file.exists());
+    let backend: Box<dyn StorageBackend> = match kind {
+        StorageKind::Json => {
+            for (file, kind) in [(SQLITE_FILE, StorageKind::Sqlite), (REDB_FILE, StorageKind::Redb)] {
+                if data_dir.join(file).exists() && !has_json {
+                    eout!("⚠️ Warning: {} holds messages from --storage {}; they stay there while storage is json", file, kind.name());
+                }
+            }
+            return Ok(Box::new(JsonBackend::open(data_dir)?));
+        }
+        StorageKind::Sqlite => Box::new(SqliteBackend::open(&data_dir.join(SQLITE_FILE))?),
+        StorageKind::Redb => Box::new(RedbBackend::open(&data_dir.join(REDB_FILE))?),
+    };
+    if has_json {
+        let (clients, messages) = migrate(&JsonBackend::open(data_dir)?, backend.as_ref())?;
+        for file in [data_dir.join(MESSAGES_DIR), data_dir.join(CLIENTS_FILE), data_dir.join(LAST_SEEN_FILE)] {
+            if file.exists() {
+                let mut migrated = file.clone().into_os_string();
+                migrated.push(".migrated");
+                fs::rename(&file, migrated)?;
+            }
+        }
+        out!("📦 Migrated {} client(s) and {} message(s) to {}", clients, messages, backend.name());
+    }
+    Ok(backend)
+}
+
+/// Copy the clients and mailboxes of the JSON backend in `json_dir` into
+/// `to`, which must hold neither yet. The JSON files are left as they are.
+pub fn import_json(json_dir: &Path, to: &dyn StorageBackend) -> Result<(usize, usize)> {
+    if [MESSAGES_DIR, MESSAGES_FILE, CLIENTS_FILE].iter().all(|file| !json_dir.join(file).exists()) {
+        return Err(anyhow!("{} has no JSON storage to import", json_dir.display()));
+    }
+    if !to.get_all_clients()?.is_empty() || to.get_all_messages()?.values().any(|mailbox| !mailbox.is_empty()) {
+        return Err(anyhow!("{} storage already holds clients or messages; import into an empty data directory", to.name()));
+    }
+    migrate(&JsonBackend::open(json_dir)?, to)
+}
+
+/// Copy every client and mailbox from one backend into another.
+pub fn migrate(from: &dyn StorageBackend, to: &dyn StorageBackend) -> Result<(usize, usize)> {
+    let clients = from.get_all_clients()?;
+    to.replace_all_clients(&clients)?;
+    let messages = from.get_all_messages()?;
+    to.replace_all_messages(&messages)?;
+    Ok((clients.len(), messages.values().map(Vec::len).sum()))
+}
+
+/// One line of a mailbox log.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum LogEntry {
+    /// The message with this id was deleted.
+    Deleted { deleted: String },
+    /// A message, or a newer version of one earlier in the log.
+    Message(Box<Message>),
+}
+
+/// One line of the last-seen log.
+#[derive(Serialize, Deserialize)]
+struct Seen {
+    id: String,
+    at: DateTime<Utc>,
+}
+
+/// What's known of a mailbox log without reading it: how big it is, how
+/// big it was straight after it was last compacted (0 if it hasn't been
+/// since startup), and the ids of the messages in it, once it has been
+/// read.
+#[derive(Default)]
+struct MailboxLog {
+    bytes: u64,
+    compacted: u64,
+    ids: Option<HashSet<String>>,
+}
+
+/// Clients as of the last write of `clients.json`, with the last-seen
+/// times logged since.
+struct Clients {
+    info: HashMap<String, ClientInfo>,
+    seen_bytes: u64,
+}
+
+/// Clients in `clients.json`, rewritten whole on every registration with
+/// the one before kept as `.bak`, and each mailbox in its own append-only
+/// log, `messages/<client_id>.jsonl`. A change to a mailbox appends a line
+/// to its log: the message, a newer version of it, or that it was deleted.
+/// Logs that have grown past the compaction threshold are rewritten with
+/// only their messages' current versions by `compact`. Last-seen times,
+/// which change on every send, are appended to `last_seen.jsonl` instead,
+/// until `clients.json` is next written.
+pub struct JsonBackend {
+    messages_dir: PathBuf,
+    clients_path: PathBuf,
+    last_seen_path: PathBuf,
+    clients: Mutex<Clients>,
+    // log file name -> what's known of it; held while any log is read or written
+    logs: Mutex<HashMap<String, MailboxLog>>,
+    compact_at: u64,
+}
+
+impl JsonBackend {
+    /// Load the clients, and take stock of the mailbox logs. A clients file
+    /// that won't parse is replaced by its backup, and with neither usable
+    /// there are no clients. A `messages.json` from before the logs is
+    /// split into them, and renamed `messages.json.migrated`.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let messages_dir = data_dir.join(MESSAGES_DIR);
+        secure_fs::create_private_dir(&messages_dir)?;
+        let clients_path = data_dir.join(CLIENTS_FILE);
+        let last_seen_path = data_dir.join(LAST_SEEN_FILE);
+        let mut info: HashMap<String, ClientInfo> = load_with_backup(&clients_path, "clients").unwrap_or_default();
+        let seen_bytes = replay_last_seen(&last_seen_path, &mut info)?;
+        let backend = Self {
+            clients: Mutex::new(Clients { info, seen_bytes }),
+            messages_dir,
+            clients_path,
+            last_seen_path,
+            logs: Mutex::new(HashMap::new()),
+            compact_at: DEFAULT_COMPACT_AT_BYTES,
+        };
+
+        let messages_file = data_dir.join(MESSAGES_FILE);
+        if let Some(messages) = load_with_backup::<HashMap<String, Vec<Message>>>(&messages_file, "messages") {
+            backend.replace_all_messages(&messages)?;
+            fs::rename(&messages_file, data_dir.join("messages.json.migrated"))?;
+            out!("📦 Split {} message(s) into a log per mailbox", messages.values().map(Vec::len).sum::<usize>());
+        }
+
+        let mut logs = backend.lock_logs()?;
+        for entry in fs::read_dir(&backend.messages_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "jsonl") {
+                // A crash mid-append leaves a partial line, which the next one mustn't run into
+                if ends_mid_line(&path)? {
+                    secure_fs::append_private(&path, "\n")?;
+                }
+                let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                logs.insert(name, MailboxLog { bytes: file_size(&path), ..MailboxLog::default() });
+            }
+        }
+        drop(logs);
+        Ok(backend)
+    }
+
+    /// Compact a mailbox log once it reaches `bytes`, or twice its size
+    /// after the last compaction if that's more.
+    pub fn compact_at(mut self, bytes: u64) -> Self {
+        self.compact_at = bytes;
+        self
+    }
+
+    fn lock_clients(&self) -> Result<MutexGuard<'_, Clients>> {
+        self.clients.lock().map_err(|_| anyhow!("JSON storage lock poisoned"))
+    }
+
+    fn lock_logs(&self) -> Result<MutexGuard<'_, HashMap<String, MailboxLog>>> {
+        self.logs.lock().map_err(|_| anyhow!("JSON storage lock poisoned"))
+    }
+
+    /// Apply `change` and write the clients file; if the write fails, the
+    /// change is undone.
+    fn update_clients(&self, change: impl FnOnce(&mut HashMap<String, ClientInfo>)) -> Result<()> {
+        let mut clients = self.lock_clients()?;
+        let before = clients.info.clone();
+        change(&mut clients.info);
+        if let Err(e) = self.write_clients(&mut clients) {
+            clients.info = before;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Write the clients file, which then has every last-seen time logged
+    /// since the one before, and empty the last-seen log.
+    fn write_clients(&self, clients: &mut Clients) -> Result<()> {
+        write_json(&self.clients_path, &clients.info)?;
+        if clients.seen_bytes > 0 {
+            // Left behind, it's replayed onto times as new as its own, which changes nothing
+            match fs::remove_file(&self.last_seen_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    eout!("⚠️ Warning: Failed to remove {}: {}", self.last_seen_path.display(), e);
+                }
+                _ => clients.seen_bytes = 0,
+            }
+        }
+        Ok(())
+    }
+
+    /// The ids of the messages in a mailbox log, read from it the first
+    /// time they're needed. `None` if there's no log.
+    fn log_ids<'a>(&self, logs: &'a mut HashMap<String, MailboxLog>, name: &str) -> Result<Option<&'a HashSet<String>>> {
+        let Some(log) = logs.get_mut(name) else {
+            return Ok(None);
+        };
+        if log.ids.is_none() {
+            let mailbox = read_log(&self.messages_dir.join(name))?;
+            log.ids = Some(mailbox.into_iter().map(|m| m.id).collect());
+        }
+        Ok(log.ids.as_ref())
+    }
+
+    /// Add lines to a mailbox log, all or none of them.
+    fn append(&self, logs: &mut HashMap<String, MailboxLog>, name: &str, entries: &[LogEntry]) -> Result<()> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        secure_fs::append_private(self.messages_dir.join(name), &lines)?;
+        // Every log on disk is known since open, so one that isn't is new and empty
+        let log = logs.entry(name.to_string()).or_insert_with(|| MailboxLog { ids: Some(HashSet::new()), ..MailboxLog::default() });
+        log.bytes += lines.len() as u64;
+        if let Some(ids) = &mut log.ids {
+            for entry in entries {
+                match entry {
+                    LogEntry::Message(message) => ids.insert(message.id.clone()),
+                    LogEntry::Deleted { deleted } => ids.remove(deleted),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a mailbox log with just these messages, replacing what it had.
+    fn rewrite(&self, logs: &mut HashMap<String, MailboxLog>, name: &str, mailbox: &[Message]) -> Result<()> {
+        let path = self.messages_dir.join(name);
+        if mailbox.is_empty() {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            logs.remove(name);
+            return Ok(());
+        }
+        let mut lines = String::new();
+        for message in mailbox {
+            lines.push_str(&serde_json::to_string(message)?);
+            lines.push('\n');
+        }
+        secure_fs::write_private_atomic(&path, &lines, false)?;
+        let bytes = lines.len() as u64;
+        let ids = mailbox.iter().map(|m| m.id.clone()).collect();
+        logs.insert(name.to_string(), MailboxLog { bytes, compacted: bytes, ids: Some(ids) });
+        Ok(())
+    }
+}
+
+/// File name of a client's mailbox log. Ids that aren't safe as a file
+/// name are hashed; the messages inside still say whose they are.
+fn log_name(client_id: &str) -> String {
+    let safe = !client_id.is_empty() && client_id.len() <= 128
+        && client_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if safe {
+        format!("{}.jsonl", client_id)
+    } else {
+        format!("~{}.jsonl", hex::encode(&Sha256::digest(client_id.as_bytes())[..16]))
+    }
+}
+
+/// Whether a non-empty file's last byte isn't a newline.
+fn ends_mid_line(path: &Path) -> Result<bool> {
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Apply the last-seen log to clients, keeping the later time where the
+/// clients file already has one. Returns the log's size.
+fn replay_last_seen(path: &Path, clients: &mut HashMap<String, ClientInfo>) -> Result<u64> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+    };
+    // A line cut short by a crash is skipped, and mustn't run into the next
+    for seen in content.lines().filter_map(|line| serde_json::from_str::<Seen>(line).ok()) {
+        if let Some(info) = clients.get_mut(&seen.id) {
+            info.last_seen = info.last_seen.max(seen.at);
+        }
+    }
+    if ends_mid_line(path)? {
+        secure_fs::append_private(path, "\n")?;
+    }
+    Ok(file_size(path))
+}
+
+/// A mailbox as its log leaves it, in the order messages were first added.
+/// A line that won't parse, as when a crash cut the last one short, is
+/// skipped.
+fn read_log(path: &Path) -> Result<Vec<Message>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+    };
+    // Deleted messages leave an empty slot, so the positions in `index` hold
+    let mut slots: Vec<Option<Message>> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (number, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        match serde_json::from_str::<LogEntry>(line) {
+            Ok(LogEntry::Message(message)) => match index.get(&message.id) {
+                Some(&slot) => slots[slot] = Some(*message),
+                None => {
+                    index.insert(message.id.clone(), slots.len());
+                    slots.push(Some(*message));
+                }
+            },
+            Ok(LogEntry::Deleted { deleted }) => {
+                if let Some(slot) = index.remove(&deleted) {
+                    slots[slot] = None;
+                }
+            }
+            Err(e) => eout!("⚠️ Warning: Skipped line {} of {}: {}", number + 1, path.display(), e),
+        }
+    }
+    // A log is in the order lines were appended, which a replicated or
+    // migrated mailbox needn't have been in
+    Ok(in_clock_order(slots.into_iter().flatten().collect()))
+}
+
+/// A mailbox sorted by clock, keeping the order of equal values.
+fn in_clock_order(mut mailbox: Vec<Message>) -> Vec<Message> {
+    mailbox.sort_by_key(|m| m.hlc);
+    mailbox
+}
+
+impl StorageBackend for JsonBackend {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn register_client(&self, info: &ClientInfo) -> Result<()> {
+        self.update_clients(|clients| {
+            clients.insert(info.id.clone(), info.clone());
+        })
+    }
+
+    fn get_client_info(&self, client_id: &str) -> Result<Option<ClientInfo>> {
+        Ok(self.lock_clients()?.info.get(client_id).cloned())
+    }
+
+    fn get_all_clients(&self) -> Result<HashMap<String, ClientInfo>> {
+        Ok(self.lock_clients()?.info.clone())
+    }
+
+    fn update_last_seen(&self, client_id: &str, at: DateTime<Utc>) -> Result<()> {
+        let mut clients = self.lock_clients()?;
+        if !clients.info.contains_key(client_id) {
+            return Ok(());
+        }
+        let mut line = serde_json::to_string(&Seen { id: client_id.to_string(), at })?;
+        line.push('\n');
+        secure_fs::append_private(&self.last_seen_path, &line)?;
+        clients.seen_bytes += line.len() as u64;
+        if let Some(info) = clients.info.get_mut(client_id) {
+            info.last_seen = at;
+        }
+        Ok(())
+    }
+
+    fn add_message(&self, message: &Message) -> Result<()> {
+        let mut logs = self.lock_logs()?;
+        self.append(&mut logs, &log_name(&message.recipient_id), &[LogEntry::Message(Box::new(message.clone()))])
+    }
+
+    fn get_messages_for_client(&self, client_id: &str) -> Result<Vec<Message>> {
+        let _logs = self.lock_logs()?;
+        read_log(&self.messages_dir.join(log_name(client_id)))
+    }
+
+    fn delete_messages(&self, client_id: &str, message_ids: &[String]) -> Result<usize> {
+        let mut logs = self.lock_logs()?;
+        let name = log_name(client_id);
+        let Some(stored) = self.log_ids(&mut logs, &name)? else {
+            return Ok(0);
+        };
+        let found: HashSet<&String> = message_ids.iter().filter(|id| stored.contains(*id)).collect();
+        let deleted: Vec<LogEntry> = found.into_iter().map(|id| LogEntry::Deleted { deleted: id.clone() }).collect();
+        if !deleted.is_empty() {
+            self.append(&mut logs, &name, &deleted)?;
+        }
+        Ok(deleted.len())
+    }
+
+    fn update_messages(&self, client_id: &str, messages: &[Message]) -> Result<()> {
+        let mut logs = self.lock_logs()?;
+        let name = log_name(client_id);
+        let Some(stored) = self.log_ids(&mut logs, &name)? else {
+            return Ok(());
+        };
+        let updated: Vec<LogEntry> = messages.iter()
+            .filter(|message| stored.contains(&message.id))
+            .map(|message| LogEntry::Message(Box::new(message.clone())))
+            .collect();
+        if updated.is_empty() {
+            return Ok(());
+        }
+        self.append(&mut logs, &name, &updated)
+    }
+
+    fn get_all_messages(&self) -> Result<HashMap<String, Vec<Message>>> {
+        let logs = self.lock_logs()?;
+        let mut mailboxes: HashMap<String, Vec<Message>> = HashMap::new();
+        for name in logs.keys() {
+            for message in read_log(&self.messages_dir.join(name))? {
+                mailboxes.entry(message.recipient_id.clone()).or_default().push(message);
+            }
+        }
+        Ok(mailboxes)
+    }
+
+    fn replace_all_messages(&self, messages: &HashMap<String, Vec<Message>>) -> Result<()> {
+        let mut logs = self.lock_logs()?;
+        let mut by_log: HashMap<String, Vec<Message>> = HashMap::new();
+        for message in messages.values().flatten() {
+            by_log.entry(log_name(&message.recipient_id)).or_default().push(message.clone());
+        }
+        let stale: Vec<String> = logs.keys().filter(|name| !by_log.contains_key(*name)).cloned().collect();
+        for name in stale {
+            self.rewrite(&mut logs, &name, &[])?;
+        }
+        for (name, mailbox) in by_log {
+            self.rewrite(&mut logs, &name, &mailbox)?;
+        }
+        Ok(())
+    }
+
+    fn replace_all_clients(&self, clients: &HashMap<String, ClientInfo>) -> Result<()> {
+        self.update_clients(|stored| *stored = clients.clone())
+    }
+
+    /// Logs are synced on every append; this writes the clients file with
+    /// the logged last-seen times, so the next start needn't replay them.
+    fn flush(&self) -> Result<()> {
+        self.write_clients(&mut *self.lock_clients()?)
+    }
+
+    /// Also folds the last-seen log into the clients file once it reaches
+    /// the compaction threshold, without counting it as a mailbox.
+    fn compact(&self) -> Result<usize> {
+        let mut clients = self.lock_clients()?;
+        if clients.seen_bytes >= self.compact_at {
+            self.write_clients(&mut clients)?;
+        }
+        drop(clients);
+        let mut logs = self.lock_logs()?;
+        let due: Vec<String> = logs.iter()
+            .filter(|(_, size)| size.bytes >= self.compact_at.max(size.compacted.saturating_mul(2)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &due {
+            let mailbox = read_log(&self.messages_dir.join(name))?;
+            self.rewrite(&mut logs, name, &mailbox)?;
+        }
+        Ok(due.len())
+    }
+
+    fn disk_usage(&self) -> Vec<(&'static str, u64)> {
+        let mailboxes = self.lock_logs().map_or(0, |logs| logs.values().map(|size| size.bytes).sum());
+        vec![("mailboxes", mailboxes), ("clients", file_size(&self.clients_path) + file_size(&self.last_seen_path))]
+    }
+}
+
+/// Mailboxes and clients in one SQLite database. Each change is one
+/// statement or transaction, so it touches only the rows it changes.
+pub struct SqliteBackend {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        secure_fs::restrict_file(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = FULL;
+            CREATE TABLE IF NOT EXISTS clients (
+                id TEXT PRIMARY KEY,
+                info TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS messages (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                recipient_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                message TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_by_recipient ON messages (recipient_id);
+            CREATE INDEX IF NOT EXISTS messages_by_timestamp ON messages (timestamp);",
+        )?;
+        Ok(Self { path: path.to_path_buf(), conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>> {
+        self.conn.lock().map_err(|_| anyhow!("SQLite storage lock poisoned"))
+    }
+
+    fn query_messages(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Message>> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(sql)?;
+        let rows = statement.query_map(params, |row| row.get::<_, String>(0))?;
+        rows.map(|json| Ok(serde_json::from_str(&json?)?)).collect()
+    }
+}
+
+fn insert_message(conn: &Connection, message: &Message) -> Result<()> {
+    conn.execute(
+        "INSERT INTO messages (recipient_id, message_id, timestamp, message) VALUES (?1, ?2, ?3, ?4)",
+        params![message.recipient_id, message.id, message.timestamp.timestamp_millis(), serde_json::to_string(message)?],
+    )?;
+    Ok(())
+}
+
+fn insert_client(conn: &Connection, info: &ClientInfo) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO clients (id, info) VALUES (?1, ?2)",
+        params![info.id, serde_json::to_string(info)?],
+    )?;
+    Ok(())
+}
+
+impl StorageBackend for SqliteBackend {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn register_client(&self, info: &ClientInfo) -> Result<()> {
+        let conn = self.conn()?;
+        insert_client(&conn, info)
+    }
+
+    fn get_client_info(&self, client_id: &str) -> Result<Option<ClientInfo>> {
+        let info: Option<String> = self.conn()?
+            .query_row("SELECT info FROM clients WHERE id = ?1", params![client_id], |row| row.get(0))
+            .optional()?;
+        Ok(info.map(|info| serde_json::from_str(&info)).transpose()?)
+    }
+
+    fn get_all_clients(&self) -> Result<HashMap<String, ClientInfo>> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare("SELECT info FROM clients")?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        rows.map(|json| {
+            let info: ClientInfo = serde_json::from_str(&json?)?;
+            Ok((info.id.clone(), info))
+        })
+        .collect()
+    }
+
+    fn update_last_seen(&self, client_id: &str, at: DateTime<Utc>) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let info: Option<String> = tx
+            .query_row("SELECT info FROM clients WHERE id = ?1", params![client_id], |row| row.get(0))
+            .optional()?;
+        if let Some(info) = info {
+            let mut info: ClientInfo = serde_json::from_str(&info)?;
+            info.last_seen = at;
+            insert_client(&tx, &info)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_message(&self, message: &Message) -> Result<()> {
+        let conn = self.conn()?;
+        insert_message(&conn, message)
+    }
+
+    fn get_messages_for_client(&self, client_id: &str) -> Result<Vec<Message>> {
+        self.query_messages("SELECT message FROM messages WHERE recipient_id = ?1 ORDER BY seq", params![client_id]).map(in_clock_order)
+    }
+
+    fn delete_messages(&self, client_id: &str, message_ids: &[String]) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let mut deleted = 0;
+        for message_id in message_ids {
+            deleted += tx.execute(
+                "DELETE FROM messages WHERE recipient_id = ?1 AND message_id = ?2",
+                params![client_id, message_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn update_messages(&self, client_id: &str, messages: &[Message]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        for message in messages {
+            tx.execute(
+                "UPDATE messages SET message = ?1 WHERE recipient_id = ?2 AND message_id = ?3",
+                params![serde_json::to_string(message)?, client_id, message.id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_all_messages(&self) -> Result<HashMap<String, Vec<Message>>> {
+        let mut mailboxes: HashMap<String, Vec<Message>> = HashMap::new();
+        for message in self.query_messages("SELECT message FROM messages ORDER BY seq", [])? {
+            mailboxes.entry(message.recipient_id.clone()).or_default().push(message);
+        }
+        Ok(mailboxes.into_iter().map(|(recipient, mailbox)| (recipient, in_clock_order(mailbox))).collect())
+    }
+
+    fn replace_all_messages(&self, messages: &HashMap<String, Vec<Message>>) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM messages", [])?;
+        for message in messages.values().flatten() {
+            insert_message(&tx, message)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn replace_all_clients(&self, clients: &HashMap<String, ClientInfo>) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM clients", [])?;
+        for info in clients.values() {
+            insert_client(&tx, info)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Every change is already committed; this folds the write-ahead log
+    /// back into the database file.
+    fn flush(&self) -> Result<()> {
+        self.conn()?.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        Ok(())
+    }
+
+    /// The whole database counts as mailboxes, which are nearly all of it.
+    fn disk_usage(&self) -> Vec<(&'static str, u64)> {
+        let wal = PathBuf::from(format!("{}-wal", self.path.display()));
+        vec![("mailboxes", file_size(&self.path) + file_size(&wal)), ("clients", 0)]
+    }
+}
+
+/// Messages of the redb backend, keyed by recipient then clock, so a
+/// mailbox is one range scan in clock order. The last part is the order
+/// the message was added in, for messages of the same clock value.
+const REDB_MESSAGES: TableDefinition<(&str, i64, u32, u64), &str> = TableDefinition::new("messages");
+/// Where each message is in `REDB_MESSAGES`, by recipient and message id.
+const REDB_MESSAGE_KEYS: TableDefinition<(&str, &str), (i64, u32, u64)> = TableDefinition::new("message_keys");
+const REDB_CLIENTS: TableDefinition<&str, &str> = TableDefinition::new("clients");
+/// Counters: `next_seq`, the next addition order.
+const REDB_META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+
+/// Mailboxes and clients in one redb file, as JSON values under binary
+/// keys. Each change, including one to many messages as with an ack, is
+/// one write transaction, committed durably before it returns, so a crash
+/// loses nothing acknowledged and nothing half-written. Groups, bans and
+/// the other components stay in Storage's data files, as with every
+/// backend.
+pub struct RedbBackend {
+    path: PathBuf,
+    db: Database,
+}
+
+/// Key of a message in `REDB_MESSAGES`, less its recipient.
+type RedbPosition = (i64, u32, u64);
+
+impl RedbBackend {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Database::create(path)?;
+        secure_fs::restrict_file(path)?;
+        let tx = db.begin_write()?;
+        tx.open_table(REDB_MESSAGES)?;
+        tx.open_table(REDB_MESSAGE_KEYS)?;
+        tx.open_table(REDB_CLIENTS)?;
+        tx.open_table(REDB_META)?;
+        tx.commit()?;
+        Ok(Self { path: path.to_path_buf(), db })
+    }
+}
+
+/// Store `message`, replacing any of the same id in its mailbox; `seq` is
+/// its addition order if it's new.
+fn redb_put(tx: &redb::WriteTransaction, message: &Message, seq: u64) -> Result<()> {
+    let mut messages = tx.open_table(REDB_MESSAGES)?;
+    let mut keys = tx.open_table(REDB_MESSAGE_KEYS)?;
+    let recipient = message.recipient_id.as_str();
+    let stored = keys.get((recipient, message.id.as_str()))?.map(|position| position.value());
+    let seq = match stored {
+        Some((wall_ms, counter, seq)) => {
+            messages.remove((recipient, wall_ms, counter, seq))?;
+            seq
+        }
+        None => seq,
+    };
+    let position: RedbPosition = (message.hlc.wall_ms, message.hlc.counter, seq);
+    messages.insert((recipient, position.0, position.1, position.2), serde_json::to_string(message)?.as_str())?;
+    keys.insert((recipient, message.id.as_str()), position)?;
+    Ok(())
+}
+
+/// Take the next `count` addition orders.
+fn redb_seqs(tx: &redb::WriteTransaction, count: u64) -> Result<u64> {
+    let mut meta = tx.open_table(REDB_META)?;
+    let next = meta.get("next_seq")?.map_or(0, |seq| seq.value());
+    meta.insert("next_seq", next + count)?;
+    Ok(next)
+}
+
+impl StorageBackend for RedbBackend {
+    fn name(&self) -> &'static str {
+        "redb"
+    }
+
+    fn register_client(&self, info: &ClientInfo) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        tx.open_table(REDB_CLIENTS)?.insert(info.id.as_str(), serde_json::to_string(info)?.as_str())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_client_info(&self, client_id: &str) -> Result<Option<ClientInfo>> {
+        let tx = self.db.begin_read()?;
+        let clients = tx.open_table(REDB_CLIENTS)?;
+        let info = clients.get(client_id)?;
+        Ok(info.map(|json| serde_json::from_str(json.value())).transpose()?)
+    }
+
+    fn get_all_clients(&self) -> Result<HashMap<String, ClientInfo>> {
+        let tx = self.db.begin_read()?;
+        let clients = tx.open_table(REDB_CLIENTS)?;
+        let mut all = HashMap::new();
+        for entry in clients.iter()? {
+            let (id, json) = entry?;
+            all.insert(id.value().to_string(), serde_json::from_str(json.value())?);
+        }
+        Ok(all)
+    }
+
+    fn update_last_seen(&self, client_id: &str, at: DateTime<Utc>) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut clients = tx.open_table(REDB_CLIENTS)?;
+            let stored = clients.get(client_id)?.map(|json| json.value().to_string());
+            if let Some(json) = stored {
+                let mut info: ClientInfo = serde_json::from_str(&json)?;
+                info.last_seen = at;
+                clients.insert(client_id, serde_json::to_string(&info)?.as_str())?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_message(&self, message: &Message) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        let seq = redb_seqs(&tx, 1)?;
+        redb_put(&tx, message, seq)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_messages_for_client(&self, client_id: &str) -> Result<Vec<Message>> {
+        let tx = self.db.begin_read()?;
+        let messages = tx.open_table(REDB_MESSAGES)?;
+        let mut mailbox = Vec::new();
+        for entry in messages.range((client_id, i64::MIN, 0, 0)..=(client_id, i64::MAX, u32::MAX, u64::MAX))? {
+            mailbox.push(serde_json::from_str(entry?.1.value())?);
+        }
+        Ok(mailbox)
+    }
+
+    fn delete_messages(&self, client_id: &str, message_ids: &[String]) -> Result<usize> {
+        let tx = self.db.begin_write()?;
+        let mut deleted = 0;
+        {
+            let mut messages = tx.open_table(REDB_MESSAGES)?;
+            let mut keys = tx.open_table(REDB_MESSAGE_KEYS)?;
+            for message_id in message_ids {
+                let removed = keys.remove((client_id, message_id.as_str()))?.map(|position| position.value());
+                if let Some((wall_ms, counter, seq)) = removed {
+                    messages.remove((client_id, wall_ms, counter, seq))?;
+                    deleted += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn update_messages(&self, client_id: &str, messages: &[Message]) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        for message in messages.iter().filter(|message| message.recipient_id == client_id) {
+            let stored = tx.open_table(REDB_MESSAGE_KEYS)?.get((client_id, message.id.as_str()))?.is_some();
+            if stored {
+                redb_put(&tx, message, 0)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_all_messages(&self) -> Result<HashMap<String, Vec<Message>>> {
+        let tx = self.db.begin_read()?;
+        let messages = tx.open_table(REDB_MESSAGES)?;
+        let mut mailboxes: HashMap<String, Vec<Message>> = HashMap::new();
+        for entry in messages.iter()? {
+            let (key, json) = entry?;
+            mailboxes.entry(key.value().0.to_string()).or_default().push(serde_json::from_str(json.value())?);
+        }
+        Ok(mailboxes)
+    }
+
+    fn replace_all_messages(&self, messages: &HashMap<String, Vec<Message>>) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        tx.open_table(REDB_MESSAGES)?.retain(|_, _| false)?;
+        tx.open_table(REDB_MESSAGE_KEYS)?.retain(|_, _| false)?;
+        let count = messages.values().map(Vec::len).sum::<usize>() as u64;
+        let first = redb_seqs(&tx, count)?;
+        for (seq, message) in (first..).zip(messages.values().flatten()) {
+            redb_put(&tx, message, seq)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn replace_all_clients(&self, clients: &HashMap<String, ClientInfo>) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(REDB_CLIENTS)?;
+            table.retain(|_, _| false)?;
+            for info in clients.values() {
+                table.insert(info.id.as_str(), serde_json::to_string(info)?.as_str())?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Every commit was durable before it returned
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> Vec<(&'static str, u64)> {
+        vec![("mailboxes", file_size(&self.path)), ("clients", 0)]
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> Result<()> {
+    secure_fs::write_private_atomic(path, serde_json::to_string_pretty(value)?, true)
+}
+
+/// Parse a data file written with a backup. If it can't be read or parsed,
+/// as when a crash cut it short, the backup from the write before it takes
+/// its place. `None` when neither is usable, or there is no file yet.
+fn load_with_backup<T: DeserializeOwned>(path: &Path, what: &str) -> Option<T> {
+    let error = match fs::read_to_string(path) {
+        Ok(content) => match serde_json::from_str::<T>(&content) {
+            Ok(value) => return Some(value),
+            Err(e) => format!("Failed to parse {} file: {}", what, e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => format!("Failed to read {} file: {}", what, e),
+    };
+    eout!("⚠️ Warning: {}", error);
+
+    let backup = secure_fs::backup_path(path);
+    let content = fs::read_to_string(&backup).ok()?;
+    match serde_json::from_str::<T>(&content) {
+        Ok(value) => {
+            eout!("♻️ Restored {} from {}, as of the write before last", what, backup.display());
+            // Put the good copy back, so the next write doesn't back up the broken one
+            if let Err(e) = secure_fs::write_private_atomic(path, &content, false) {
+                eout!("⚠️ Warning: Failed to restore {}: {}", path.display(), e);
+            }
+            Some(value)
+        }
+        Err(e) => {
+            eout!("⚠️ Warning: Failed to parse {} backup too: {}", what, e);
+            None
+        }
+    }
+}
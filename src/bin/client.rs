@@ -0,0 +1,5 @@
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    messaging_proto::client::main(std::env::args().collect()).await
+}
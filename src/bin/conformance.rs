@@ -0,0 +1,4 @@
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    messaging_proto::conformance::main(std::env::args().collect()).await
+}
@@ -0,0 +1,8 @@
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    // Serves tokio-console; the build also needs RUSTFLAGS="--cfg tokio_unstable"
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+    messaging_proto::server::main(std::env::args().collect()).await
+}
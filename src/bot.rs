@@ -0,0 +1,342 @@
+//! Bots on a [`Client`]: the connect, fetch, match-a-command, reply loop
+//! everyone writes, done once. Handlers are picked by a command prefix or a
+//! pattern over a message's decrypted text and run as tasks of their own,
+//! so a slow one doesn't hold up the rest and a panicking one is logged
+//! and forgotten. Their replies all go out through the bot's client.
+//! Middleware sees each message first and can drop it.
+
+use crate::client::Client;
+use crate::connections::panic_message;
+use crate::render::MessageView;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{error, info, warn};
+use regex::Regex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// How often a bot fetches messages unless told otherwise.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long handlers still running at shutdown get to finish.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+type Handler = Arc<dyn Fn(Context) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+enum Trigger {
+    Command(String),
+    Pattern(Regex),
+}
+
+struct Route {
+    trigger: Trigger,
+    handler: Handler,
+}
+
+impl Route {
+    /// What follows the command, and the pattern's captures, if this route
+    /// takes `body`.
+    fn accepts(&self, body: &str) -> Option<(String, Vec<Option<String>>)> {
+        match &self.trigger {
+            Trigger::Command(prefix) => {
+                let rest = body.strip_prefix(prefix.as_str())?;
+                (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| (rest.trim().to_string(), Vec::new()))
+            }
+            Trigger::Pattern(pattern) => pattern.captures(body).map(|captures| {
+                (body.to_string(), captures.iter().map(|group| group.map(|group| group.as_str().to_string())).collect())
+            }),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match &self.trigger {
+            Trigger::Command(prefix) => prefix,
+            Trigger::Pattern(pattern) => pattern.as_str(),
+        }
+    }
+}
+
+/// A message for the bot's client to send.
+enum Outgoing {
+    Direct { recipient: String, text: String },
+    Group { group: String, text: String },
+}
+
+/// What a handler is given: the message, and a way to answer it.
+#[derive(Clone)]
+pub struct Context {
+    pub message: MessageView,
+    /// For a command, the text after it; for a pattern, the whole text.
+    pub args: String,
+    /// A pattern's capture groups, the whole match first; empty for a command.
+    pub captures: Vec<Option<String>>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl Context {
+    /// Answer where the message came from: its group, or else its sender.
+    pub fn reply(&self, text: impl Into<String>) {
+        let text = text.into();
+        let outgoing = match &self.message.group {
+            // The group lives on the server the sender wrote from
+            Some(group) => match self.message.sender.rsplit_once('@') {
+                Some((_, server)) => Outgoing::Group { group: format!("{}@{}", group, server), text },
+                None => Outgoing::Group { group: group.clone(), text },
+            },
+            None => Outgoing::Direct { recipient: self.message.sender.clone(), text },
+        };
+        let _ = self.outgoing.send(outgoing);
+    }
+
+    /// Send to someone else, `id` or `id@server`.
+    pub fn send(&self, recipient: impl Into<String>, text: impl Into<String>) {
+        let _ = self.outgoing.send(Outgoing::Direct { recipient: recipient.into(), text: text.into() });
+    }
+}
+
+/// Sees each message before the handlers do.
+pub trait Middleware: Send + Sync {
+    /// Whether `message` goes on to the handlers.
+    fn allow(&self, message: &MessageView) -> bool;
+}
+
+/// Logs every message the bot gets.
+pub struct Logging;
+
+impl Middleware for Logging {
+    fn allow(&self, message: &MessageView) -> bool {
+        info!("🤖 {} from {}: {}", message.timestamp.format("%H:%M:%S"), message.sender, message.body);
+        true
+    }
+}
+
+/// Drops messages from anyone not on the list.
+pub struct AllowList(HashSet<String>);
+
+impl AllowList {
+    pub fn new(senders: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        AllowList(senders.into_iter().map(Into::into).collect())
+    }
+}
+
+impl Middleware for AllowList {
+    fn allow(&self, message: &MessageView) -> bool {
+        self.0.contains(&message.sender)
+    }
+}
+
+/// Drops a sender's messages beyond `limit` in any `window`.
+pub struct RateLimit {
+    limit: usize,
+    window: Duration,
+    /// sender -> when their allowed messages in the window arrived
+    seen: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimit {
+    pub fn per_user(limit: usize, window: Duration) -> Self {
+        RateLimit { limit, window, seen: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn allow(&self, message: &MessageView) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.retain(|_, times| {
+            while times.front().is_some_and(|at| now.duration_since(*at) >= self.window) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = seen.entry(message.sender.clone()).or_default();
+        if times.len() >= self.limit {
+            warn!("🤖 Dropped a message from {}: over {} in {:?}", message.sender, self.limit, self.window);
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// Sets up a [`Bot`]: its handlers, in the order they are tried, and its
+/// middleware, in the order it runs.
+pub struct BotBuilder {
+    client: Client,
+    routes: Vec<Route>,
+    middleware: Vec<Box<dyn Middleware>>,
+    poll_interval: Duration,
+    shutdown_grace: Duration,
+}
+
+impl BotBuilder {
+    /// A bot on `client`, connected already.
+    pub fn new(client: Client) -> Self {
+        BotBuilder {
+            client,
+            routes: Vec::new(),
+            middleware: Vec::new(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+        }
+    }
+
+    /// Handle messages that start with `prefix`, such as `!echo`, followed
+    /// by nothing or by whitespace.
+    pub fn command<F, Fut>(mut self, prefix: &str, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.routes.push(Route { trigger: Trigger::Command(prefix.to_string()), handler: boxed(handler) });
+        self
+    }
+
+    /// Handle messages `pattern` matches.
+    pub fn pattern<F, Fut>(mut self, pattern: Regex, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.routes.push(Route { trigger: Trigger::Pattern(pattern), handler: boxed(handler) });
+        self
+    }
+
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// How often to fetch messages.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// How long handlers still running at shutdown get to finish.
+    pub fn shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    pub fn build(self) -> Bot {
+        Bot {
+            client: self.client,
+            routes: self.routes,
+            middleware: self.middleware,
+            poll_interval: self.poll_interval,
+            shutdown_grace: self.shutdown_grace,
+        }
+    }
+}
+
+fn boxed<F, Fut>(handler: F) -> Handler
+where
+    F: Fn(Context) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    Arc::new(move |context| handler(context).boxed())
+}
+
+pub struct Bot {
+    client: Client,
+    routes: Vec<Route>,
+    middleware: Vec<Box<dyn Middleware>>,
+    poll_interval: Duration,
+    shutdown_grace: Duration,
+}
+
+impl Bot {
+    /// Fetch and handle messages until `shutdown` completes. Then stop
+    /// fetching, give running handlers the shutdown grace to finish, send
+    /// what they replied, and hand the client back.
+    pub async fn run_until(mut self, shutdown: impl Future) -> Client {
+        let (outgoing, mut outbox) = mpsc::unbounded_channel();
+        let mut running = JoinSet::new();
+        let mut poll = tokio::time::interval(self.poll_interval);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut shutdown = std::pin::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = poll.tick() => {
+                    for message in self.client.receive().await {
+                        self.dispatch(message, &outgoing, &mut running);
+                    }
+                    while let Some(event) = self.client.try_next_event() {
+                        info!("🤖 {:?}", event);
+                    }
+                }
+                Some(message) = outbox.recv() => self.deliver(message).await,
+                Some(finished) = running.join_next() => report(finished),
+            }
+        }
+
+        drop(outgoing);
+        let grace = tokio::time::sleep(self.shutdown_grace);
+        let mut grace = std::pin::pin!(grace);
+        loop {
+            tokio::select! {
+                Some(message) = outbox.recv() => self.deliver(message).await,
+                finished = running.join_next() => match finished {
+                    Some(finished) => report(finished),
+                    None => break,
+                },
+                _ = &mut grace => {
+                    warn!("🤖 Stopped {} handler(s) still running at shutdown", running.len());
+                    running.abort_all();
+                    break;
+                }
+            }
+        }
+        while let Ok(message) = outbox.try_recv() {
+            self.deliver(message).await;
+        }
+        self.client
+    }
+
+    /// Run the first handler that takes `message`, if the middleware lets it through.
+    fn dispatch(&self, message: MessageView, outgoing: &mpsc::UnboundedSender<Outgoing>, running: &mut JoinSet<(String, std::thread::Result<Result<()>>)>) {
+        if !self.middleware.iter().all(|middleware| middleware.allow(&message)) {
+            return;
+        }
+        let Some((route, (args, captures))) = self.routes.iter().find_map(|route| route.accepts(&message.body).map(|taken| (route, taken))) else {
+            return;
+        };
+        let (name, handler) = (route.name().to_string(), Arc::clone(&route.handler));
+        let context = Context { message, args, captures, outgoing: outgoing.clone() };
+        running.spawn(async move { (name, AssertUnwindSafe(handler(context)).catch_unwind().await) });
+    }
+
+    async fn deliver(&mut self, message: Outgoing) {
+        let (target, sent) = match message {
+            Outgoing::Direct { recipient, text } => {
+                let sent = self.client.send(&recipient, &text).await.map(|_| ());
+                (recipient, sent)
+            }
+            Outgoing::Group { group, text } => {
+                let sent = self.client.send_to_group(&group, &text).await.map(|_| ());
+                (group, sent)
+            }
+        };
+        if let Err(e) = sent {
+            warn!("🤖 Failed to send to {}: {}", target, e);
+        }
+    }
+}
+
+fn report(finished: Result<(String, std::thread::Result<Result<()>>), tokio::task::JoinError>) {
+    match finished {
+        Ok((_, Ok(Ok(())))) => {}
+        Ok((route, Ok(Err(e)))) => warn!("🤖 Handler for {} failed: {}", route, e),
+        Ok((route, Err(panic))) => error!("💥 Handler for {} panicked: {}", route, panic_message(panic.as_ref())),
+        // Aborted at shutdown
+        Err(_) => {}
+    }
+}
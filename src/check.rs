@@ -0,0 +1,388 @@
+use crate::integrity;
+use crate::rotate;
+use crate::secure_fs;
+use crate::output::out;
+use crate::types::{ClientInfo, KeyEvent, KeyLogEntry, Message, Revocation};
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Records removed by `--repair`, kept so an operator can inspect or restore them.
+#[derive(Default, Serialize)]
+struct Quarantine {
+    messages: Vec<Message>,
+    retention: HashMap<String, HashMap<String, u64>>,
+}
+
+#[derive(Default)]
+pub struct CheckReport {
+    pub problems: Vec<String>,
+    pub repaired: Vec<String>,
+    pub quarantine_file: Option<String>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.is_clean() {
+            out!("✅ Data directory is consistent");
+            return;
+        }
+        out!("⚠️ Found {} problem(s):", self.problems.len());
+        for problem in &self.problems {
+            out!("  - {}", problem);
+        }
+        if !self.repaired.is_empty() {
+            out!("🔧 Repairs:");
+            for repair in &self.repaired {
+                out!("  - {}", repair);
+            }
+        }
+        if let Some(path) = &self.quarantine_file {
+            out!("📦 Removed records were written to {}", path);
+        }
+    }
+}
+
+/// Load every persisted structure and cross-check them. With `repair`, fix what
+/// can be fixed without losing data: misfiled messages are moved to the right
+/// mailbox, and orphaned or duplicate records, and messages whose signature no
+/// longer verifies, go to a quarantine file.
+/// The key log and revocations are only ever reported, never rewritten.
+pub fn check_data(data_dir: &str, repair: bool) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+
+    let mut clients: HashMap<String, ClientInfo> = load(data_dir, "clients.json", &mut report);
+    let mut messages: HashMap<String, Vec<Message>> = load(data_dir, "messages.json", &mut report);
+    let revocations: HashMap<String, Vec<Revocation>> = load(data_dir, "revocations.json", &mut report);
+    let key_log: Vec<KeyLogEntry> = load(data_dir, "key_log.json", &mut report);
+    let mut retention: HashMap<String, HashMap<String, u64>> = load(data_dir, "retention.json", &mut report);
+    if !report.is_clean() {
+        // Cross-checks against a file we couldn't read would only add noise
+        return Ok(report);
+    }
+
+    let mut quarantine = Quarantine::default();
+    let mut clients_changed = false;
+    let mut messages_changed = false;
+    let mut retention_changed = false;
+
+    for (client_id, info) in clients.iter_mut() {
+        if &info.id != client_id {
+            report.problems.push(format!("clients.json: entry {} has id {}", client_id, info.id));
+            if repair {
+                info.id = client_id.clone();
+                clients_changed = true;
+                report.repaired.push(format!("reset id of client {}", client_id));
+            }
+        }
+    }
+
+    // Messages must sit in their recipient's mailbox, once, for a registered recipient
+    let mut seen_ids = HashSet::new();
+    let mut kept: HashMap<String, Vec<Message>> = HashMap::new();
+    for (mailbox, mailbox_messages) in messages.drain() {
+        for message in mailbox_messages {
+            if message.recipient_id != mailbox {
+                report.problems.push(format!("messages.json: message {} for {} is filed under {}", message.id, message.recipient_id, mailbox));
+                messages_changed = true;
+            }
+            if !seen_ids.insert(message.id.clone()) {
+                report.problems.push(format!("messages.json: duplicate message id {}", message.id));
+                messages_changed = true;
+                quarantine.messages.push(message);
+                continue;
+            }
+            if !clients.contains_key(&message.recipient_id) {
+                report.problems.push(format!("messages.json: message {} is for unregistered client {}", message.id, message.recipient_id));
+                messages_changed = true;
+                quarantine.messages.push(message);
+                continue;
+            }
+            let current_key = clients.get(message.signer()).map(|info| info.public_key.as_str());
+            if let Err(reason) = integrity::verify_message(&message, &key_log, current_key) {
+                report.problems.push(format!("messages.json: message {} from {} fails its signature check: {}", message.id, message.sender_id, reason));
+                messages_changed = true;
+                quarantine.messages.push(message);
+                continue;
+            }
+            kept.entry(message.recipient_id.clone()).or_default().push(message);
+        }
+    }
+    messages = kept;
+
+    for (client_id, client_revocations) in &revocations {
+        for revocation in client_revocations {
+            if &revocation.client_id != client_id {
+                report.problems.push(format!("revocations.json: revocation for {} is filed under {}", revocation.client_id, client_id));
+            }
+        }
+    }
+
+    if let Err(e) = KeyLogEntry::verify_chain(&key_log) {
+        report.problems.push(format!("key_log.json: chain is broken: {}", e));
+    }
+    let logged: HashSet<(&str, &str)> = key_log.iter()
+        .filter(|e| e.event == KeyEvent::Registered)
+        .map(|e| (e.client_id.as_str(), e.public_key.as_str()))
+        .collect();
+    for info in clients.values() {
+        if !logged.contains(&(info.id.as_str(), info.public_key.as_str())) {
+            report.problems.push(format!("key_log.json: current key of {} was never logged", info.id));
+        }
+    }
+
+    if let Err(e) = rotate::verify(Path::new(data_dir), "audit.log") {
+        report.problems.push(format!("audit.log: chain is broken: {}", e));
+    }
+
+    retention.retain(|recipient, policies| {
+        if clients.contains_key(recipient) {
+            return true;
+        }
+        report.problems.push(format!("retention.json: policy for unregistered client {}", recipient));
+        quarantine.retention.insert(recipient.clone(), policies.clone());
+        retention_changed = true;
+        false
+    });
+
+    if repair {
+        if clients_changed {
+            save(data_dir, "clients.json", &clients)?;
+        }
+        if messages_changed {
+            save(data_dir, "messages.json", &messages)?;
+            report.repaired.push("rebuilt mailboxes in messages.json".to_string());
+        }
+        if retention_changed {
+            save(data_dir, "retention.json", &retention)?;
+            report.repaired.push("dropped orphaned retention policies".to_string());
+        }
+        if !quarantine.messages.is_empty() || !quarantine.retention.is_empty() {
+            let path = format!("{}/quarantine-{}.json", data_dir, Utc::now().format("%Y%m%dT%H%M%S"));
+            secure_fs::write_private(&path, serde_json::to_string_pretty(&quarantine)?)?;
+            report.quarantine_file = Some(path);
+        }
+    }
+
+    Ok(report)
+}
+
+fn load<T: DeserializeOwned + Default>(data_dir: &str, file: &str, report: &mut CheckReport) -> T {
+    let path = format!("{}/{}", data_dir, file);
+    if !Path::new(&path).exists() {
+        return T::default();
+    }
+    match fs::read_to_string(&path).map_err(anyhow::Error::from)
+        .and_then(|content| Ok(serde_json::from_str(&content)?))
+    {
+        Ok(value) => value,
+        Err(e) => {
+            report.problems.push(format!("{}: unreadable: {}", file, e));
+            T::default()
+        }
+    }
+}
+
+fn save<T: Serialize>(data_dir: &str, file: &str, value: &T) -> Result<()> {
+    secure_fs::write_private(format!("{}/{}", data_dir, file), serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, CryptoManager};
+    use crate::scratch::Scratch;
+    use crate::types::send_payload;
+
+    /// Alice and Bob, registered and logged, with one message from Alice
+    /// waiting for Bob: a data directory that checks clean.
+    struct Fixture {
+        scratch: Scratch,
+        alice: CryptoManager,
+        clients: HashMap<String, ClientInfo>,
+        key_log: Vec<KeyLogEntry>,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let scratch = Scratch::new(&format!("check-{}", name));
+            let alice = CryptoManager::new();
+            let bob = CryptoManager::new();
+            let mut clients = HashMap::new();
+            let mut key_log: Vec<KeyLogEntry> = Vec::new();
+            for (id, crypto) in [("alice", &alice), ("bob", &bob)] {
+                let public_key = hex::encode(crypto.get_ed25519_public_key().to_bytes());
+                let mut entry = KeyLogEntry {
+                    seq: key_log.len() as u64,
+                    client_id: id.to_string(),
+                    public_key: public_key.clone(),
+                    event: KeyEvent::Registered,
+                    timestamp: Utc::now(),
+                    prev_hash: key_log.last().map(|e| e.hash.clone()).unwrap_or_default(),
+                    hash: String::new(),
+                };
+                entry.hash = entry.compute_hash();
+                key_log.push(entry);
+                clients.insert(id.to_string(), serde_json::from_value(serde_json::json!({
+                    "id": id,
+                    "public_key": public_key,
+                    "registered_at": Utc::now(),
+                    "last_seen": Utc::now(),
+                })).unwrap());
+            }
+            let fixture = Self { scratch, alice, clients, key_log };
+            fixture.write("clients.json", &fixture.clients);
+            fixture.write("key_log.json", &fixture.key_log);
+            fixture.write_messages(&[("bob", vec![fixture.message("m1", "bob")])]);
+            fixture
+        }
+
+        /// A message from Alice, signed as `Send` would have checked it.
+        fn message(&self, id: &str, recipient_id: &str) -> Message {
+            let ciphertext = format!("ciphertext of {}", id).into_bytes();
+            let sent_at = Utc::now();
+            let signature = self.alice.sign_with_context(crypto::context::SEND, &send_payload(id, recipient_id, &ciphertext, sent_at));
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "sender_id": "alice",
+                "recipient_id": recipient_id,
+                "content": hex::encode(&ciphertext),
+                "timestamp": Utc::now(),
+                "encrypted": true,
+                "signature": hex::encode(signature.to_bytes()),
+                "sent_at": sent_at,
+            })).unwrap()
+        }
+
+        fn write(&self, file: &str, value: &impl Serialize) {
+            fs::write(self.scratch.0.join(file), serde_json::to_string_pretty(value).unwrap()).unwrap();
+        }
+
+        fn read<T: DeserializeOwned>(&self, file: &str) -> T {
+            serde_json::from_str(&fs::read_to_string(self.scratch.0.join(file)).unwrap()).unwrap()
+        }
+
+        fn write_messages(&self, mailboxes: &[(&str, Vec<Message>)]) {
+            let messages: HashMap<&str, &Vec<Message>> = mailboxes.iter().map(|(mailbox, messages)| (*mailbox, messages)).collect();
+            self.write("messages.json", &messages);
+        }
+
+        fn check(&self, repair: bool) -> CheckReport {
+            check_data(&self.scratch.0.to_string_lossy(), repair).unwrap()
+        }
+    }
+
+    fn mentions(report: &CheckReport, text: &str) -> bool {
+        report.problems.iter().any(|problem| problem.contains(text))
+    }
+
+    #[test]
+    fn a_consistent_directory_checks_clean() {
+        let fixture = Fixture::new("clean");
+        let report = fixture.check(true);
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert!(report.repaired.is_empty());
+        assert!(report.quarantine_file.is_none());
+    }
+
+    #[test]
+    fn bad_messages_are_refiled_or_quarantined() {
+        let fixture = Fixture::new("messages");
+        let mut altered = fixture.message("m3", "bob");
+        altered.content = hex::encode(b"not what alice signed");
+        fixture.write_messages(&[
+            ("alice", vec![fixture.message("m1", "bob")]),
+            ("bob", vec![fixture.message("m1", "bob"), altered]),
+            ("carol", vec![fixture.message("m2", "carol")]),
+        ]);
+        let before = fs::read_to_string(fixture.scratch.0.join("messages.json")).unwrap();
+
+        let report = fixture.check(false);
+        assert!(mentions(&report, "message m1 for bob is filed under alice"), "{:?}", report.problems);
+        assert!(mentions(&report, "duplicate message id m1"));
+        assert!(mentions(&report, "message m2 is for unregistered client carol"));
+        assert!(mentions(&report, "message m3 from alice fails its signature check"));
+        assert!(report.repaired.is_empty());
+        assert_eq!(fs::read_to_string(fixture.scratch.0.join("messages.json")).unwrap(), before, "a check alone must not write");
+
+        let report = fixture.check(true);
+        assert!(report.repaired.iter().any(|repair| repair.contains("rebuilt mailboxes")));
+        let messages: HashMap<String, Vec<Message>> = fixture.read("messages.json");
+        assert_eq!(messages.keys().collect::<Vec<_>>(), ["bob"]);
+        assert_eq!(messages["bob"].iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), ["m1"]);
+
+        let quarantine: serde_json::Value = serde_json::from_str(&fs::read_to_string(report.quarantine_file.unwrap()).unwrap()).unwrap();
+        let mut quarantined: Vec<&str> = quarantine["messages"].as_array().unwrap().iter()
+            .map(|m| m["id"].as_str().unwrap())
+            .collect();
+        quarantined.sort();
+        assert_eq!(quarantined, ["m1", "m2", "m3"]);
+
+        assert!(fixture.check(false).is_clean());
+    }
+
+    #[test]
+    fn client_ids_and_orphaned_policies_are_repaired() {
+        let fixture = Fixture::new("clients");
+        let mut clients = fixture.clients.clone();
+        clients.get_mut("bob").unwrap().id = "robert".to_string();
+        fixture.write("clients.json", &clients);
+        let retention = HashMap::from([
+            ("bob", HashMap::from([("alice", 60u64)])),
+            ("carol", HashMap::from([("alice", 60u64)])),
+        ]);
+        fixture.write("retention.json", &retention);
+
+        let report = fixture.check(false);
+        assert!(mentions(&report, "entry bob has id robert"), "{:?}", report.problems);
+        assert!(mentions(&report, "policy for unregistered client carol"));
+
+        let report = fixture.check(true);
+        assert!(report.repaired.iter().any(|repair| repair.contains("reset id of client bob")));
+        let clients: HashMap<String, ClientInfo> = fixture.read("clients.json");
+        assert_eq!(clients["bob"].id, "bob");
+        let retention: HashMap<String, HashMap<String, u64>> = fixture.read("retention.json");
+        assert!(retention.contains_key("bob") && !retention.contains_key("carol"));
+        let quarantine: serde_json::Value = serde_json::from_str(&fs::read_to_string(report.quarantine_file.unwrap()).unwrap()).unwrap();
+        assert_eq!(quarantine["retention"]["carol"]["alice"], 60);
+
+        assert!(fixture.check(false).is_clean());
+    }
+
+    #[test]
+    fn key_log_damage_is_reported_but_never_rewritten() {
+        let fixture = Fixture::new("key-log");
+        let mut key_log = fixture.key_log.clone();
+        key_log[1].public_key = hex::encode(CryptoManager::new().get_ed25519_public_key().to_bytes());
+        fixture.write("key_log.json", &key_log);
+        let before = fs::read_to_string(fixture.scratch.0.join("key_log.json")).unwrap();
+
+        let report = fixture.check(true);
+        assert!(mentions(&report, "key_log.json: chain is broken: entry 1 has been altered"), "{:?}", report.problems);
+        assert!(mentions(&report, "current key of bob was never logged"));
+        assert!(report.repaired.is_empty());
+        assert_eq!(fs::read_to_string(fixture.scratch.0.join("key_log.json")).unwrap(), before);
+    }
+
+    #[test]
+    fn an_unreadable_file_stops_the_cross_checks() {
+        let fixture = Fixture::new("unreadable");
+        fs::write(fixture.scratch.0.join("messages.json"), "{ not json").unwrap();
+        fixture.write("retention.json", &HashMap::from([("carol", HashMap::from([("alice", 60u64)]))]));
+
+        let report = fixture.check(true);
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert!(mentions(&report, "messages.json: unreadable"));
+        assert!(report.repaired.is_empty());
+        assert_eq!(fs::read_to_string(fixture.scratch.0.join("messages.json")).unwrap(), "{ not json");
+    }
+}
@@ -1,300 +1,4995 @@
-mod types;
-mod crypto;
+mod bench;
+mod dial;
+mod setup;
+#[cfg(feature = "tui")]
+mod tui;
 
-use crate::types::{ServerCommand, ServerResponse, Message};
+use crate::{annotations, confusables, crypto, features, frame, i18n, integrity, keybackup, output, presence, recovery, rules, secure_fs, state, store, telemetry, template, transport};
+use crate::confusables::Lookalikes;
+use crate::types::{ServerCommand, ServerResponse, ack_payload, Hlc, Message, MessageKind, MessageMetadata, MessageSearch, Revocation, DeliveryStatus, SystemNotice, EXPIRED_UNDELIVERED, INVITE_UNCLAIMED, GuestLink, GuestToken, KeyEvent, KeyLogEntry, error_code, guest_link_payload, guest_link_ref_payload, key_directory_payload, message_ref_payload, message_id_time, notice_type, receipt_payload, report_payload, retention_payload, send_payload, sender_key_payload, user_data_payload, user_data_ref_payload, client_details_payload, invite_code_payload, admin_batch_payload, presence_payload, promote_payload, debug_dump_payload, delegation_payload, delegation_ref_payload, delegations_payload, usage_payload, AccountUsage, ClientInfo, Delegation, DelegationAudit, DelegationScope, SenderKey, AdminAction, AdminActionResult, PresenceEntry, DirectoryChangeKind, Group, check_group_name, group_payload, new_message_id};
+use crate::telemetry::SendOutcome;
 use crate::crypto::CryptoManager;
-use ed25519_dalek::PublicKey;
-use hex;
-use serde_json;
+use crate::render::{MessageView, Renderer};
+use crate::rules::{Rule, RuleAction};
+use crate::config::{ClientConfig, KeyLogHead, ServerBinding, ServerProfile};
+use crate::contactsync::{ContactChange, Stamp, Verdict};
+use crate::state::{ContactRecord, StateBundle};
+use crate::keybackup::KeyBackup;
+use crate::store::{HistoryRecord, LocalStore, LocalStoreKind, MessageState, SignatureCheck, StoredContact};
+use crate::recovery::{RecoveryMessage, RecoveryShare};
+use crate::conversation::ConversationId;
+use crate::paths::ClientPaths;
+use crate::i18n::{say, tr};
+use crate::output::{eout, note, out, OutputMode};
+use crate::features::{FeatureSet, NegotiatedFeatures};
+use crate::reorder::{JitterBuffer, Released};
+use crate::sanitize::{self, ControlDisplay};
+use crate::payload::Payload;
+use crate::outgoing::OutgoingEstimate;
+use crate::events::{ClientEvent, ServerInfo};
+use crate::presence::{PresenceEvent, PresenceTracker};
+use crate::transport::Connector;
+use ed25519_dalek::{PublicKey, Signature};
+use tokio::io::{AsyncRead, AsyncWrite, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::error::TryRecvError;
 use anyhow::{Result, anyhow};
+use futures::stream::{self, Stream, StreamExt};
+use chrono::{DateTime, Utc};
 use colored::*;
-use log::{info, error};
-use std::io::{self, Write};
+use serde::{Deserialize, Serialize};
+use log::{debug, info, error};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc;
 use x25519_dalek::PublicKey as X25519PublicKey;
 
-struct Client {
+const DEFAULT_SERVER: &str = "default";
+const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:8080";
+/// Use this address for the default server instead of the configured one.
+const SERVER_FLAG: &str = "--server";
+/// Register with servers this identity isn't bound to without asking.
+const ALLOW_NEW_SERVER_FLAG: &str = "--allow-new-server";
+/// Keep the identity's secret keys in this file instead of the config directory.
+const KEY_FILE_FLAG: &str = "--key-file";
+/// Start even though the key file, or another secret file, is readable by
+/// others; like `--insecure-permissions-ok`, it only warns.
+const FORCE_FLAG: &str = "--force";
+/// Show the bodies of messages whose signature doesn't verify, marked as such,
+/// instead of withholding them.
+const SHOW_INVALID_FLAG: &str = "--show-invalid";
+/// Invite code or token to present when registering on a server that asks for one.
+const CREDENTIAL_FLAG: &str = "--credential";
+/// Start the full-screen interface instead of the prompt. Needs the `tui` feature.
+const TUI_FLAG: &str = "--tui";
+/// `client send --guest-token <token> <message>`: message a guest link's owner
+/// without an identity.
+const GUEST_TOKEN_FLAG: &str = "--guest-token";
+/// A sender's claimed send time is shown next to the server's once they
+/// are further apart than this.
+const CLOCK_SKEW_SHOWN_SECS: i64 = 120;
+/// How often `receive` fetches again while holding a message back.
+const JITTER_POLL_MS: u64 = 100;
+/// `client [<id>] presence-watch <contact,contact...|--all>`: a status
+/// board of who is online, as a presence-only session.
+const PRESENCE_WATCH: &str = "presence-watch";
+/// How often a presence-only session asks who is online.
+const PRESENCE_POLL_SECS: u64 = 5;
+
+/// How long a message to our own id waits in our mailbox. Every device
+/// sharing the identity has to read it, contact syncs and annotations
+/// included, so none acknowledges it; a device offline for longer catches
+/// up from `sync now`.
+const SELF_MESSAGE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Guest link defaults: open for a day, for one message.
+const DEFAULT_GUEST_LINK_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_GUEST_LINK_MESSAGES: u32 = 1;
+
+/// Everything needed to debug which identity this client is presenting.
+#[derive(Debug, Serialize)]
+struct IdentityInfo {
+    client_id: String,
+    /// Keys are generated per run and never written to disk yet.
+    identity_file: Option<String>,
+    ed25519_public_key: String,
+    ed25519_fingerprint: String,
+    x25519_public_key: String,
+    x25519_fingerprint: String,
+    server_name: String,
+    server_addr: Option<String>,
+    registered: bool,
+    protocol: String,
+    session_age_secs: Option<i64>,
+    capabilities: Vec<String>,
+    server_key_fingerprint: Option<String>,
+    /// Whether the server's key is one this identity is bound to.
+    server_key_matched: Option<bool>,
+    bound_servers: Vec<ServerBinding>,
+}
+
+/// Which sections of a state archive to restore.
+struct ImportParts {
+    identity: bool,
+    config: bool,
+    contacts: bool,
+}
+
+/// Per-message delivery settings for `send`.
+#[derive(Debug, Default)]
+struct SendOptions {
+    ttl_secs: Option<u64>,
+    /// Ask the server to bounce the message back if it is not fetched by then.
+    deliver_by: Option<DateTime<Utc>>,
+    /// This message's place among those sent to the recipient.
+    seq: Option<u64>,
+    /// The sender already confirmed a recipient that looks like another id.
+    confirmed: bool,
+    /// An earlier message of ours this one replaces if still unfetched.
+    supersedes: Option<String>,
+    /// The group this is one member's copy of a message to.
+    group: Option<String>,
+}
+
+/// A request the server refused, with the reason code it gave so callers
+/// can tell refusals apart.
+#[derive(Debug)]
+struct Refused {
+    message: String,
+    code: Option<String>,
+}
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{} ({})", self.message, code),
+            None => write!(f, "Server error: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Whether `error` is the server refusing with `code`.
+fn refused_with(error: &anyhow::Error, code: &str) -> bool {
+    error.downcast_ref::<Refused>().is_some_and(|refused| refused.code.as_deref() == Some(code))
+}
+
+/// History pseudo-contact that server notices are filed under.
+const SYSTEM_PEER: &str = "server";
+
+/// Filters for `search` over local history.
+#[derive(Debug, Default)]
+struct HistorySearch {
+    words: Vec<String>,
+    from: Option<String>,
+    since: Option<DateTime<Utc>>,
+}
+
+/// Most matches `search` prints.
+const SEARCH_RESULTS: usize = 20;
+
+/// Interactive-mode help: command syntax, catalog key and English description.
+/// Syntax stays untranslated since it is what gets typed.
+const HELP: &[(&str, &str, &str)] = &[
+    ("send [--ttl <dur>] [--deliver-within <dur>] [--confirm] [--status] <recipient>[@server] <message>", "help.send", "Send encrypted message (--status replaces your last unfetched status)"),
+    ("receive", "help.receive", "Check for new messages"),
+    ("history <contact> [n]", "help.history", "Show the last n messages with a contact"),
+    ("search <words> [--from <c>] [--since <date>]", "help.search", "Search local history (--rebuild to re-index)"),
+    ("send <recipient> --template <name> [k=v ...]", "help.send_template", "Send a saved template (also: tsend)"),
+    ("template add <name> <body>", "help.template_add", "Save a template; {{name}} is a placeholder, \\n a line break"),
+    ("template list|show|remove", "help.template_manage", "Manage templates"),
+    ("cancel <message_id>", "help.cancel", "Unsend a message, or ask for a retraction once delivered"),
+    ("edit <message_id> <message>", "help.edit", "Send a corrected version; replaces the original if still unfetched"),
+    ("label|unlabel <message_id> <label>", "help.label", "Privately label a message, synced to your other devices"),
+    ("star|unstar <message_id>", "help.star", "Privately star a message"),
+    ("labels <contact>", "help.labels", "List a contact's labelled and starred messages"),
+    ("contacts [--all]", "help.contacts", "List online contacts (--all includes archived)"),
+    ("mute|unmute <contact>", "help.mute", "Stop/resume printing a contact's messages"),
+    ("archive|unarchive <contact>", "help.archive", "Hide/show a contact in listings"),
+    ("add <contact_id> <pubkey>", "help.add", "Add contact (hex encoded X25519 key)"),
+    ("whoami [--json]", "help.whoami", "Show the identity in use"),
+    ("account [--json]", "help.account", "Show what the server keeps about you, and your limits"),
+    ("digest [<contact> <dur|off>]", "help.digest", "Summarize a busy contact's messages once per window"),
+    ("retention <contact> <dur|off>", "help.retention", "Expire messages from a contact after e.g. 7d"),
+    ("trust <contact>", "help.trust", "Trust a contact's key after a revocation"),
+    ("sync now", "help.sync", "Send all your contacts to your other devices"),
+    ("report <contact> [reason]", "help.report", "Report spam; the server holds back their messages for a while"),
+    ("audit-key <contact>", "help.audit_key", "Verify and show a contact's key history"),
+    ("revoke [--recovery <secret>] <reason>", "help.revoke", "Revoke this identity's key"),
+    ("recovery-key generate", "help.recovery_key", "Create a recovery key for future registrations"),
+    ("recovery setup <k> <c1,c2,..>", "help.recovery_setup", "Split this identity among contacts, k of them restore it"),
+    ("recovery status | recovery release <owner>", "help.recovery_status", "Inspect or return shares you hold"),
+    ("recover --from <c1,c2,..>", "help.recover", "Ask share holders to restore your identity"),
+    ("export-state <file> [--passphrase <p>]", "help.export", "Save identity, contacts and config"),
+    ("import-state <file> [--passphrase <p>] [--contacts-only|--config-only]", "help.import", "Restore an export-state file"),
+    ("key backup|restore|inspect <file> [--passphrase <p>]", "help.key", "Back up just this identity's keys to a .msgkey file, or restore one"),
+    ("server list|switch <name>", "help.server_switch", "Show or change the targeted server"),
+    ("server stats", "help.server_stats", "Show the targeted server's request latencies"),
+    ("server add <name> <addr>[,<addr>..]", "help.server_add", "Connect to another server, trying each address [--separate-identity] [--allow-new-server]"),
+    ("group create <name> <member,member,..>", "help.group_create", "Create a group of you and the members, on the current server"),
+    ("group info <name> [--refresh] | group list", "help.group_info", "Show a group's members and roles, or the groups you're in"),
+    ("group rename <name> <new_name> | group leave <name> | group refresh", "help.group_manage", "Rename (owners only) or leave a group, or fetch your groups again"),
+    ("gsend <group>[@server] <message>", "help.gsend", "Send a message to every member of a group (Tab completes names in --tui)"),
+    ("guestlink create [--ttl <dur>] [--max <n>] [--plain]", "help.guestlink_create", "Let someone without an identity message you through a token"),
+    ("guestlink list | guestlink revoke <id>", "help.guestlink_manage", "Show guest links and their use, or close one"),
+    ("userdata get|set|delete <key> [value]", "help.userdata", "Keep small encrypted values on the server for your other devices"),
+    ("admin client <client_id>", "help.admin", "Show how a client registered (server admins only)"),
+    ("admin invite-code", "help.admin_invite", "Make a single-use code for registering (server admins only)"),
+    ("admin promote", "help.admin_promote", "Make the current server, a standby, the primary (server admins only)"),
+    ("admin dump", "help.admin_dump", "Show the current server's connections, queues and lock waits as JSON (server admins only)"),
+    ("admin batch [--best-effort] <action>; <action>...", "help.admin_batch", "Run several admin actions at once, all or none unless --best-effort: ban <id> [reason], unban <id>, prune <id>, notice [--to a,b] <text>, invite-code <code>, drain <duration> (server admins only)"),
+    ("delegate grant <contact> <read|read-send> <dur>", "help.delegate_grant", "Let a contact read (and send as) your mailbox without your keys"),
+    ("delegate list | delegate revoke <contact>", "help.delegate_manage", "Show delegates and what they did, or cut one off"),
+    ("delegate fetch <owner> | delegate send <owner> <recipient> <message>", "help.delegate_use", "Use a mailbox delegated to you"),
+    ("mailbox search [filters]", "help.mailbox_search", "Find messages on the server by sender, date, size or kind"),
+    ("set color <on|off>", "help.set_color", "Toggle per-sender colors"),
+    ("set output <rich|plain|quiet>", "help.set_output", "Emoji and color, plain text for screen readers, or results only"),
+    ("set store <file|sqlite>", "help.set_store", "Choose the local store (file data migrates to sqlite)"),
+    ("set search-index <on|off>", "help.set_search_index", "Index history words (as keyed hashes) for faster search"),
+    ("set jitter <ms>", "help.set_jitter", "How long to hold a message while an earlier one from its sender is missing"),
+    ("set escapes <on|off>", "help.set_escapes", "Show control characters from peers escaped instead of removing them"),
+    ("rule add [from=<id>] [text=<regex>] <mute|highlight|run <cmd>>", "help.rule_add", "Add a local filtering rule"),
+    ("rule list | rule remove <n>", "help.rule_manage", "Manage local filtering rules"),
+    ("rules test [from=<id>] <text>", "help.rule_test", "Show which rule would fire"),
+    ("quit", "help.quit", "Exit"),
+];
+
+/// Client-to-client control messages, sent encrypted like ordinary messages.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum ControlMessage {
+    /// Asks the recipient to disregard a message they already fetched.
+    Retraction { message_id: String },
+    /// Sent to self: set (or with `None`, remove) keys in a message's
+    /// annotations on this identity's other devices.
+    Annotation { message_id: String, values: BTreeMap<String, Option<String>> },
+    /// Sent by a mailbox owner to its delegate: the hex key of each of the
+    /// owner's conversations, by peer.
+    ConversationKeys { keys: BTreeMap<String, String> },
+    /// Sent to self: contacts as set on the device called `device`, in its
+    /// sync message numbered `number`.
+    ContactSync { device: String, number: u64, changes: Vec<ContactChange> },
+}
+
+struct Contact {
+    key: X25519PublicKey,
+    /// Set by `trust`; required again after the contact revokes a key.
+    trusted_at: Option<DateTime<Utc>>,
+}
+
+/// A registered session with one configured server. Each server keeps its own
+/// pinned key and contact namespace.
+struct ServerConnection {
+    /// The server's addresses; `preferred` is the one that last answered.
+    addrs: Vec<String>,
+    preferred: AtomicUsize,
+    /// The connection every command goes over, opened when registering.
+    /// `None` after it drops, until the next command dials again.
+    stream: tokio::sync::Mutex<Option<Link>>,
+    crypto: Arc<CryptoManager>,
+    server_pubkey: PublicKey,
+    connected_at: DateTime<Utc>,
+    contacts: HashMap<String, Contact>,
+    /// Protocol version and optional features agreed at registration.
+    features: NegotiatedFeatures,
+    going_away: GoingAway,
+    connector: Arc<dyn Connector>,
+    /// The most ciphertext bytes a message may have, as the server
+    /// advertised at registration.
+    max_message_size: Option<usize>,
+    events: ServerEvents,
+    /// Dials since a connection last opened.
+    redials: AtomicU32,
+}
+
+/// When a draining server said it would be gone by, once it has. Until
+/// then the open connection carries on; after it drops, nothing redials
+/// before the deadline.
+#[derive(Clone, Default)]
+struct GoingAway(Arc<std::sync::Mutex<Option<DateTime<Utc>>>>);
+
+impl GoingAway {
+    /// Note a GoAway from `events`' server, and pass it on.
+    fn heard(&self, deadline: DateTime<Utc>, events: &ServerEvents) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(deadline);
+        events.send(|server| ClientEvent::ServerDraining { server, deadline });
+    }
+
+    /// The deadline, if it hasn't passed; one that has is forgotten.
+    fn pending(&self) -> Option<DateTime<Utc>> {
+        let mut going_away = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match *going_away {
+            Some(deadline) if deadline > Utc::now() => Some(deadline),
+            _ => {
+                *going_away = None;
+                None
+            }
+        }
+    }
+}
+
+/// Where one server's events go: the client's queue, tagged with the
+/// server's name.
+#[derive(Clone)]
+struct ServerEvents {
+    server: String,
+    queue: tokio::sync::mpsc::UnboundedSender<ClientEvent>,
+}
+
+impl ServerEvents {
+    fn send(&self, event: impl FnOnce(String) -> ClientEvent) {
+        let _ = self.queue.send(event(self.server.clone()));
+    }
+}
+
+/// A session's open connection. With push delivery a task owns the read
+/// half, handing answers back here and announcing pushed messages.
+enum Link {
+    Plain(transport::Connection),
+    Pushed { writer: WriteHalf<transport::Connection>, answers: tokio::sync::mpsc::UnboundedReceiver<Result<Vec<u8>>> },
+}
+
+/// Signer -> their current identity key and key log, or None if those
+/// couldn't be fetched.
+type KeyHistories = HashMap<String, Option<(Option<String>, Vec<KeyLogEntry>)>>;
+
+/// One identity's session with its servers, keeping its contacts and
+/// history in a local store.
+pub struct Client {
     id: String,
-    crypto: CryptoManager,
-    server_pubkey: Option<PublicKey>,
-    connected_clients: std::collections::HashMap<String, X25519PublicKey>,
+    crypto: Arc<CryptoManager>,
+    servers: BTreeMap<String, ServerConnection>,
+    current: String,
+    renderer: Renderer,
+    config: ClientConfig,
+    config_path: String,
+    paths: ClientPaths,
+    /// Where this identity's secret keys are kept between runs.
+    key_file: PathBuf,
+    /// Warn instead of refusing when secret files are readable by others.
+    allow_insecure_permissions: bool,
+    /// Register with servers outside `config.bound_servers` without asking.
+    allow_new_server: bool,
+    /// Default server address from `--server`, over the configured one.
+    server_addr: Option<String>,
+    /// Presented when registering; only servers that gate registration look at it.
+    credential: Option<String>,
+    store: Arc<dyn LocalStore>,
+    /// Ids of disappearing messages the store's janitor scrubbed, so their
+    /// annotations can go too.
+    scrubbed: (mpsc::Sender<Vec<String>>, mpsc::Receiver<Vec<String>>),
+    /// Owner -> new X25519 key, for share requests awaiting `recovery release`.
+    recovery_requests: HashMap<String, String>,
+    /// Shares of our own identity returned during `recover`.
+    returned_shares: Vec<RecoveryShare>,
+    /// Server -> ids of messages fetched and recorded but not yet acknowledged.
+    pending_acks: HashMap<String, Vec<String>>,
+    /// Register as a presence-only session: no mailbox, so nothing to fetch,
+    /// decrypt or acknowledge.
+    presence_only: bool,
+    /// Verdicts on the signatures of messages fetched but not shown yet, by id.
+    signature_checks: HashMap<String, SignatureCheck>,
+    show_invalid: bool,
+    /// Offer push delivery when registering. Only set by modes that wait on
+    /// `events` between commands.
+    push_delivery: bool,
+    /// Pushes and connection events from every server, in order.
+    events: (tokio::sync::mpsc::UnboundedSender<ClientEvent>, tokio::sync::mpsc::UnboundedReceiver<ClientEvent>),
+    /// How servers are dialed: TCP, but for simulations.
+    connector: Arc<dyn Connector>,
+    /// How far this client's clock is off the system's; only simulations
+    /// set it.
+    clock_skew: chrono::Duration,
 }
 
 impl Client {
-    fn new(id: &str) -> Self {
-        let crypto = CryptoManager::new();
-        Client {
+    /// Open `id`'s identity from `key_file`, or make one there, with its
+    /// config and store under `paths`. It connects to nothing yet.
+    pub fn new(id: &str, paths: ClientPaths, key_file: PathBuf, allow_insecure_permissions: bool) -> Result<Self> {
+        let crypto = Arc::new(load_identity(&key_file, allow_insecure_permissions)?);
+        let config_path = paths.config_file(id).to_string_lossy().into_owned();
+        let config = ClientConfig::load(&config_path).unwrap_or_else(|e| {
+            eout!("{}", tr!("config.load_failed", "⚠️ Warning: Failed to load config {path}: {error}", path = config_path, error = e));
+            ClientConfig::default()
+        });
+        if let Some(mode) = config.output {
+            output::prefer(mode);
+        }
+        let store: Arc<dyn LocalStore> = Arc::from(store::open(config.local_store, &paths, id)?);
+        let scrubbed = mpsc::channel();
+        store::spawn_janitor(&store, scrubbed.0.clone());
+        Ok(Client {
             id: id.to_string(),
             crypto,
-            server_pubkey: None,
-            connected_clients: std::collections::HashMap::new(),
+            servers: BTreeMap::new(),
+            current: DEFAULT_SERVER.to_string(),
+            renderer: Renderer::for_mode(output::mode(), config.control_display),
+            config,
+            config_path,
+            paths,
+            key_file,
+            allow_insecure_permissions,
+            allow_new_server: false,
+            server_addr: None,
+            credential: None,
+            store,
+            scrubbed,
+            recovery_requests: HashMap::new(),
+            returned_shares: Vec::new(),
+            pending_acks: HashMap::new(),
+            presence_only: false,
+            signature_checks: HashMap::new(),
+            show_invalid: false,
+            push_delivery: false,
+            events: tokio::sync::mpsc::unbounded_channel(),
+            connector: Arc::new(transport::Tcp),
+            clock_skew: chrono::Duration::zero(),
+        })
+    }
+
+    /// Connect and register with the server at `addr` as the default server.
+    /// A server other than the one this identity is bound to is refused.
+    pub async fn connect_to(&mut self, addr: &str) -> Result<()> {
+        let profile = ServerProfile { addr: addr.to_string(), fallback_addrs: Vec::new(), separate_identity: false };
+        self.connect(DEFAULT_SERVER, &profile, self.allow_new_server).await
+    }
+
+    /// Send `text` to `recipient` (`id` or `id@server`), looking up the key
+    /// they published if they aren't a contact. Returns the message id.
+    pub async fn send(&mut self, recipient: &str, text: &str) -> Result<String> {
+        self.send_numbered(recipient, text, SendOptions::default()).await
+    }
+
+    /// Fetch new messages in the order they were sent, checked, decrypted
+    /// and with local rules applied, then acknowledge them. Notices fetched
+    /// with them come as [`ClientEvent::Notice`] instead.
+    pub async fn receive(&mut self) -> Vec<MessageView> {
+        let messages = self.receive_in_order().await;
+        let views = self.apply_rules(&messages);
+        self.acknowledge_received().await;
+        views
+    }
+
+    /// How large a message with `text` to `recipient` (`id` or `id@server`)
+    /// will be, and whether their server will take it, without sending
+    /// anything. A send that doesn't fit fails the same way.
+    pub fn validate_outgoing(&self, recipient: &str, text: &str) -> Result<OutgoingEstimate> {
+        let (server, _) = self.resolve_direct(recipient)?;
+        OutgoingEstimate::for_payload(&Payload::text(text), self.server(server)?.max_message_size)
+    }
+
+    /// Add `contact` (`id` or `id@server`) with its hex X25519 key, as `add`
+    /// does, and sync it to this identity's other devices.
+    pub async fn add_contact_key(&mut self, contact: &str, key: &str) -> Result<()> {
+        let (server, contact_id) = self.resolve_target(contact);
+        let (server, contact_id) = (server.to_string(), contact_id.to_string());
+        let key = x25519_from_hex(key).ok_or_else(|| anyhow!("Invalid public key"))?;
+        self.add_contact(&server, contact_id.clone(), key)?;
+        self.sync_contact(&server, &contact_id).await
+    }
+
+    /// Trust `contact`'s current key, as `trust` does, and sync that to this
+    /// identity's other devices.
+    pub async fn trust_contact(&mut self, contact: &str) -> Result<()> {
+        let (server, contact_id) = self.resolve_target(contact);
+        let (server, contact_id) = (server.to_string(), contact_id.to_string());
+        self.trust(&server, &contact_id)?;
+        self.sync_contact(&server, &contact_id).await
+    }
+
+    /// `contact`'s hex X25519 key and whether it is trusted.
+    pub fn contact(&self, contact: &str) -> Option<(String, bool)> {
+        let (server, contact_id) = self.resolve_target(contact);
+        self.servers.get(server)?.contacts.get(contact_id)
+            .map(|contact| (hex::encode(contact.key.as_bytes()), contact.trusted_at.is_some()))
+    }
+
+    /// Send every contact, as last set, to this identity's other devices on
+    /// each server, as `sync now` does. Returns how many were sent.
+    pub async fn sync_contacts(&mut self) -> Result<usize> {
+        let mut count = 0;
+        let servers: Vec<String> = self.servers.keys().cloned().collect();
+        for server in servers {
+            let changes: Vec<ContactChange> = self.servers[&server].contacts.iter()
+                .map(|(contact_id, contact)| ContactChange {
+                    contact_id: contact_id.clone(),
+                    key: hex::encode(contact.key.as_bytes()),
+                    trusted: contact.trusted_at.is_some(),
+                    stamp: self.config.contact_sync.stamp(&server, contact_id).cloned(),
+                    replaces: None,
+                })
+                .collect();
+            if changes.is_empty() {
+                continue;
+            }
+            let (device, number) = self.config.contact_sync.next_message();
+            let hlc = self.send_to_self(&server, &ControlMessage::ContactSync { device: device.clone(), number, changes: changes.clone() }).await?;
+            // Contacts from before syncing are stamped by this message
+            for change in changes.iter().filter(|change| change.stamp.is_none()) {
+                self.config.contact_sync.set(&server, &change.contact_id, Stamp { hlc, device: device.clone() });
+            }
+            count += changes.len();
         }
+        self.save_config();
+        Ok(count)
     }
 
-    async fn connect(&mut self, addr: &str) -> Result<()> {
-        let mut stream = TcpStream::connect(addr).await?;
-        info!("🔗 Connected to server at {}", addr);
-        
-        // Register with server
+    /// Ask servers connected to from now on to push messages as they
+    /// arrive; `next_event` then says when one has. Messages are still
+    /// fetched with `receive`.
+    pub fn offer_push_delivery(&mut self) {
+        self.push_delivery = true;
+    }
+
+    /// Connect to servers from now on as a presence-only session, for
+    /// status boards: the servers queue nothing for it, and it should only
+    /// call `presence_events`.
+    pub fn offer_presence_only(&mut self) {
+        self.presence_only = true;
+    }
+
+    /// Show bodies whose signature doesn't verify instead of withholding
+    /// them, as `--show-invalid` does.
+    pub fn show_invalid_bodies(&mut self) {
+        self.show_invalid = true;
+    }
+
+    /// Dial servers connected to from now on through `connector` instead
+    /// of TCP.
+    #[cfg(feature = "sim")]
+    pub fn connect_through(&mut self, connector: Arc<dyn Connector>) {
+        self.connector = connector;
+    }
+
+    /// Sign requests as if this client's clock were `skew` off.
+    #[cfg(feature = "sim")]
+    pub fn skew_clock(&mut self, skew: chrono::Duration) {
+        self.clock_skew = skew;
+    }
+
+    /// The time to sign requests with.
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.clock_skew
+    }
+
+    /// Wait for a server to push a message, and name it. Connection events
+    /// on the way are passed over; `next_event` has both.
+    pub async fn next_push(&mut self) -> Option<String> {
+        loop {
+            if let ClientEvent::Pushed { server } = self.next_event().await {
+                return Some(server);
+            }
+        }
+    }
+
+    /// Wait for the next push, notice or connection event, from any server.
+    pub async fn next_event(&mut self) -> ClientEvent {
+        self.events.1.recv().await.expect("the client keeps a sender for its own events")
+    }
+
+    /// The next push, notice or connection event, if one is waiting.
+    pub fn try_next_event(&mut self) -> Option<ClientEvent> {
+        self.events.1.try_recv().ok()
+    }
+
+    async fn connect_presence_only(&mut self) -> Result<()> {
+        self.offer_presence_only();
+        self.connect_all().await
+    }
+
+    /// Connect and register with the default server plus every configured profile.
+    async fn connect_all(&mut self) -> Result<()> {
+        let mut profiles = self.config.servers.clone();
+        let default = match (profiles.remove(DEFAULT_SERVER), &self.server_addr) {
+            (_, Some(addr)) => ServerProfile { addr: addr.clone(), fallback_addrs: Vec::new(), separate_identity: false },
+            (Some(profile), None) => profile,
+            (None, None) => ServerProfile { addr: DEFAULT_SERVER_ADDR.to_string(), fallback_addrs: Vec::new(), separate_identity: false },
+        };
+
+        self.connect(DEFAULT_SERVER, &default, self.allow_new_server).await?;
+
+        for (name, profile) in profiles {
+            if let Err(e) = self.connect(&name, &profile, self.allow_new_server).await {
+                say!("server.connect_warning", "⚠️ Could not connect to server {name} ({addr}): {error}", name = name, addr = profile.addr, error = e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Register with a server, first making sure it's one this identity is
+    /// bound to. Unknown servers need `allow_new` or a yes at the prompt.
+    async fn connect(&mut self, name: &str, profile: &ServerProfile, allow_new: bool) -> Result<()> {
+        // A separate identity is kept like the shared one, since the server
+        // wants the same key back each time
+        let crypto = if profile.separate_identity {
+            Arc::new(load_identity(&self.paths.key_file(&format!("{}@{}", self.id, name)), self.allow_insecure_permissions)?)
+        } else {
+            Arc::clone(&self.crypto)
+        };
+
+        // Refuse before registering, so the key and id never reach a server the user didn't mean
+        let addrs = profile.addrs();
+        let dialed = dial::race(&self.connector, &addrs, None).await?;
+        let addr = &addrs[dialed.index];
+        let fingerprint = crypto::fingerprint(dialed.server_key.as_bytes());
+        let bound = self.config.bound_servers.iter().any(|b| b.fingerprint == fingerprint);
+        let first = self.config.bound_servers.is_empty();
+        let events = ServerEvents { server: name.to_string(), queue: self.events.0.clone() };
+        // Bound to a server at this address with another key
+        if let Some(binding) = self.config.bound_servers.iter().find(|b| b.addr == *addr).filter(|_| !bound) {
+            events.send(|server| ClientEvent::KeyMismatch { server, expected: binding.fingerprint.clone(), actual: fingerprint.clone() });
+        }
+        if !bound && !first && !allow_new && !confirm_new_server(addr, &fingerprint)? {
+            return Err(anyhow!("{} has key {}, which {} is not bound to; use {} to register anyway",
+                addr, fingerprint, self.id, ALLOW_NEW_SERVER_FLAG));
+        }
+
+        // Register with server. A presence-only session offers nothing else
+        let offered = if self.presence_only {
+            FeatureSet::default().with(&features::PRESENCE_ONLY)
+        } else if self.push_delivery {
+            FeatureSet::supported(features::PROTOCOL_VERSION).without(&features::PRESENCE_ONLY)
+        } else {
+            FeatureSet::supported(features::PROTOCOL_VERSION).without(&features::PRESENCE_ONLY).without(&features::PUSH_DELIVERY)
+        };
+        // Publish the key to encrypt to, so contacts can look it up instead of exchanging it
+        let x25519_public_key = hex::encode(crypto.get_x25519_public_key().as_bytes());
+        let x25519_signature = crypto.sign_with_context(crypto::context::SENDER_KEY, &sender_key_payload(&self.id, &x25519_public_key));
         let register_cmd = ServerCommand::Register {
             client_id: self.id.clone(),
-            public_key: hex::encode(self.crypto.get_ed25519_public_key().as_bytes()),
+            public_key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+            recovery_key: self.config.recovery_key.clone(),
+            protocol_version: Some(features::PROTOCOL_VERSION),
+            features: Some(offered.bits()),
+            credential: self.credential.clone(),
+            x25519_public_key: Some(x25519_public_key),
+            x25519_signature: Some(hex::encode(x25519_signature.to_bytes())),
+            challenge: None,
         };
         
-        let request = serde_json::to_string(&register_cmd)?;
-        stream.write_all(request.as_bytes()).await?;
-        
-        let mut buf = [0; 4096];
-        let n = stream.read(&mut buf).await?;
-        let response = String::from_utf8_lossy(&buf[..n]);
-        
-        let server_response: ServerResponse = serde_json::from_str(&response)?;
+        let mut stream = dialed.stream;
+        let server_response = exchange_signed(&mut stream, &register_cmd, &crypto).await?;
+        info!("🔗 Connected to server at {}", addr);
         match server_response {
-            ServerResponse::Registered { server_public_key } => {
-                self.server_pubkey = Some(PublicKey::from_bytes(&hex::decode(&server_public_key)?)?);
+            ServerResponse::Registered { server_public_key, protocol_version, features, max_message_size } => {
+                let server_pubkey = PublicKey::from_bytes(&hex::decode(&server_public_key)?)?;
+                let agreed = protocol_version.map(|version| (version, features.unwrap_or_default()));
+                let features = NegotiatedFeatures::negotiate(offered, agreed);
+                if self.presence_only {
+                    features.require(&features::PRESENCE_ONLY)?;
+                }
+                let going_away = GoingAway::default();
+                let push_delivery = features.has(&features::PUSH_DELIVERY);
+                // Told of before anything the connection pushes
+                events.send(|server| ClientEvent::Connected { server, info: ServerInfo {
+                    addr: addr.clone(),
+                    fingerprint: fingerprint.clone(),
+                    protocol_version: features.protocol,
+                    push_delivery,
+                    max_message_size,
+                } });
+                let link = if push_delivery {
+                    Link::pushed(stream, events.clone(), going_away.clone())
+                } else {
+                    Link::Plain(stream)
+                };
+                self.servers.insert(name.to_string(), ServerConnection {
+                    addrs: addrs.clone(),
+                    preferred: AtomicUsize::new(dialed.index),
+                    stream: tokio::sync::Mutex::new(Some(link)),
+                    crypto,
+                    server_pubkey,
+                    connected_at: Utc::now(),
+                    contacts: self.load_contacts(name),
+                    features,
+                    going_away,
+                    connector: Arc::clone(&self.connector),
+                    max_message_size,
+                    events,
+                    redials: AtomicU32::new(0),
+                });
+                if !bound {
+                    self.config.bound_servers.push(ServerBinding { fingerprint: fingerprint.clone(), addr: addr.clone(), bound_at: Utc::now() });
+                    self.save_config();
+                    say!("bind.added", "📌 {id} is now bound to server {fingerprint} ({addr})", id = self.id, fingerprint = fingerprint, addr = addr);
+                }
                 info!("✅ Successfully registered with server");
                 info!("🔑 Server public key: {}", server_public_key.yellow());
                 Ok(())
             }
+            ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::CREDENTIAL_REQUIRED) => {
+                Err(anyhow!("{} only registers new ids with an invite code or token; start with {} <code>", addr, CREDENTIAL_FLAG))
+            }
+            ServerResponse::Error { code, message, .. } if code.as_deref() == Some(error_code::REGISTRATION_DENIED) => {
+                Err(anyhow!("{} refused to register {}: {}", addr, self.id, message))
+            }
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
             _ => Err(anyhow!("Unexpected response from server"))
         }
     }
 
-    async fn send_message(&self, addr: &str, recipient: &str, message: &str) -> Result<()> {
-        // Get recipient's public key (in a real app, this would be from a key server)
-        let recipient_pubkey = self.connected_clients.get(recipient)
+    fn server(&self, name: &str) -> Result<&ServerConnection> {
+        self.servers.get(name).ok_or_else(|| anyhow!("Not connected to server {}", name))
+    }
+
+    /// Split `bob@work` into (`work`, `bob`); plain ids target the current server.
+    fn resolve_target<'a>(&'a self, target: &'a str) -> (&'a str, &'a str) {
+        match target.rsplit_once('@') {
+            Some((id, server)) if self.servers.contains_key(server) => (server, id),
+            _ => (self.current.as_str(), target),
+        }
+    }
+
+    /// Resolve a conversation target. Groups go through `gsend` and channels
+    /// have no server support yet, so only direct conversations resolve.
+    fn resolve_direct<'a>(&'a self, target: &'a str) -> Result<(&'a str, &'a str)> {
+        let (server, id) = self.resolve_target(target);
+        match id.parse::<ConversationId>()? {
+            ConversationId::Direct(_) => Ok((server, id)),
+            ConversationId::Group(name) => Err(anyhow!("{} is a group; send to it with gsend {}", id, name)),
+            other => Err(anyhow!("{} is a {}; only direct messages are supported so far", other, other.kind())),
+        }
+    }
+
+    /// The ids on `server` a recipient could be mistaken for: contacts,
+    /// registered ids as of the last directory sync, and our own.
+    fn lookalikes(&self, server: &str) -> Lookalikes {
+        let mut known: Vec<String> = self.servers.get(server)
+            .map(|connection| connection.contacts.keys().cloned().collect())
+            .unwrap_or_default();
+        if let Ok(Some((_, clients))) = self.store.directory(server) {
+            known.extend(clients);
+        }
+        known.push(self.id.clone());
+        Lookalikes::new(known.iter().map(String::as_str))
+    }
+
+    /// Peers on the default server keep their plain id; others are tagged with the server name.
+    fn display_id(&self, server: &str, client_id: &str) -> String {
+        if server == DEFAULT_SERVER {
+            client_id.to_string()
+        } else {
+            format!("{}@{}", client_id, server)
+        }
+    }
+
+    fn identity_info(&self) -> IdentityInfo {
+        let connection = self.servers.get(&self.current);
+        let crypto = connection.map(|c| &c.crypto).unwrap_or(&self.crypto);
+        let ed25519 = crypto.get_ed25519_public_key();
+        let x25519 = crypto.get_x25519_public_key();
+        IdentityInfo {
+            client_id: self.id.clone(),
+            identity_file: None,
+            ed25519_public_key: hex::encode(ed25519.as_bytes()),
+            ed25519_fingerprint: crypto::fingerprint(ed25519.as_bytes()),
+            x25519_public_key: hex::encode(x25519.as_bytes()),
+            x25519_fingerprint: crypto::fingerprint(x25519.as_bytes()),
+            server_name: self.current.clone(),
+            server_addr: connection.map(|c| c.addr().to_string()),
+            registered: connection.is_some(),
+            protocol: connection.map_or("json".to_string(), |c| format!("json v{}", c.features.protocol)),
+            session_age_secs: connection.map(|c| (Utc::now() - c.connected_at).num_seconds()),
+            capabilities: connection.map(|c| c.features.features.ids()).unwrap_or_default(),
+            server_key_fingerprint: connection.map(|c| crypto::fingerprint(c.server_pubkey.as_bytes())),
+            server_key_matched: connection.map(|c| {
+                let fingerprint = crypto::fingerprint(c.server_pubkey.as_bytes());
+                self.config.bound_servers.iter().any(|b| b.fingerprint == fingerprint)
+            }),
+            bound_servers: self.config.bound_servers.clone(),
+        }
+    }
+
+    fn print_identity(&self, json: bool) -> Result<()> {
+        let info = self.identity_info();
+        if json {
+            out!("{}", serde_json::to_string_pretty(&info)?);
+            return Ok(());
+        }
+
+        let not_connected = tr!("whoami.not_connected", "not connected");
+        say!("whoami.title", "🪪 Identity");
+        say!("whoami.client_id", "  Client ID:      {id}", id = info.client_id.green());
+        match &info.identity_file {
+            Some(file) => say!("whoami.identity_file", "  Identity file:  {file}", file = file),
+            None => say!("whoami.ephemeral", "  Identity file:  (ephemeral, not saved)"),
+        }
+        say!("whoami.ed25519_key", "  Ed25519 key:    {key}", key = info.ed25519_public_key.yellow());
+        say!("whoami.fingerprint", "  Fingerprint:    {fingerprint}", fingerprint = info.ed25519_fingerprint);
+        say!("whoami.x25519_key", "  X25519 key:     {key}", key = info.x25519_public_key.cyan());
+        say!("whoami.fingerprint", "  Fingerprint:    {fingerprint}", fingerprint = info.x25519_fingerprint);
+        say!("whoami.server", "  Server:         {name} ({addr})", name = info.server_name, addr = info.server_addr.as_deref().unwrap_or(&not_connected));
+        if info.registered {
+            say!("whoami.registered", "  Registered:     yes");
+        } else {
+            say!("whoami.unregistered", "  Registered:     no");
+        }
+        say!("whoami.protocol", "  Protocol:       {protocol}", protocol = info.protocol);
+        if let Some(age) = info.session_age_secs {
+            say!("whoami.session_age", "  Session age:    {secs}s", secs = age);
+        }
+        if info.capabilities.is_empty() {
+            say!("whoami.no_capabilities", "  Capabilities:   (none)");
+        } else {
+            say!("whoami.capabilities", "  Capabilities:   {capabilities}", capabilities = info.capabilities.join(", "));
+        }
+        match (&info.server_key_fingerprint, info.server_key_matched) {
+            (Some(fingerprint), Some(true)) => say!("whoami.server_key_bound", "  Server key:     {fingerprint} (bound)", fingerprint = fingerprint),
+            (Some(fingerprint), _) => say!("whoami.server_key", "  Server key:     {fingerprint} (not bound)", fingerprint = fingerprint),
+            _ => {}
+        }
+        if info.bound_servers.is_empty() {
+            say!("whoami.no_bound_servers", "  Bound servers:  (none yet)");
+        } else {
+            say!("whoami.bound_servers", "  Bound servers:");
+            for binding in &info.bound_servers {
+                say!("whoami.bound_server", "    {fingerprint} ({addr}, since {date})",
+                    fingerprint = binding.fingerprint, addr = binding.addr, date = binding.bound_at.format("%Y-%m-%d"));
+            }
+        }
+        Ok(())
+    }
+
+    /// What the current server keeps about this identity.
+    async fn account_usage(&self) -> Result<AccountUsage> {
+        let connection = self.server(&self.current)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::USAGE, &usage_payload(&self.id, signed_at));
+        let command = ServerCommand::MyUsage { client_id: self.id.clone(), signed_at, signature: hex::encode(signature.to_bytes()) };
+        match connection.request(&command).await? {
+            ServerResponse::Usage { usage } => Ok(usage),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn print_account(&self, json: bool) -> Result<()> {
+        let usage = self.account_usage().await?;
+        if json {
+            out!("{}", serde_json::to_string_pretty(&usage)?);
+            return Ok(());
+        }
+
+        let never = tr!("account.never", "never");
+        let date = |at: Option<DateTime<Utc>>| at.map_or_else(|| never.clone(), |at| at.format("%Y-%m-%d %H:%M").to_string());
+        say!("account.title", "📒 What {server} keeps about {id}", server = self.current, id = usage.client_id);
+        say!("account.registered", "  Registered:       {date}", date = date(usage.registered_at));
+        say!("account.last_seen", "  Last active:      {date}", date = date(usage.last_seen));
+        match usage.mailbox_quota {
+            Some(quota) => say!("account.mailbox_quota", "  Mailbox:          {count} of {quota} message(s), {bytes} bytes",
+                count = usage.stored_messages, quota = quota, bytes = usage.stored_bytes),
+            None => say!("account.mailbox", "  Mailbox:          {count} message(s), {bytes} bytes", count = usage.stored_messages, bytes = usage.stored_bytes),
+        }
+        if usage.stored_messages > 0 {
+            say!("account.stored_range", "  Stored between:   {oldest} and {newest}", oldest = date(usage.oldest_stored), newest = date(usage.newest_stored));
+        }
+        say!("account.sent_pending", "  Sent, unfetched:  {undelivered} waiting, {held} held for unregistered ids",
+            undelivered = usage.sent_undelivered, held = usage.sent_held);
+        say!("account.guest_links", "  Guest links:      {count} active", count = usage.guest_links);
+        say!("account.user_data", "  User data:        {keys} key(s), {bytes} of {quota} bytes",
+            keys = usage.user_data_keys, bytes = usage.user_data_bytes, quota = usage.user_data_quota_bytes);
+        say!("account.delegations", "  Delegations:      {granted} granted, {received} received",
+            granted = usage.delegations_granted.len(), received = usage.delegations_received.len());
+        let now = Utc::now();
+        for grant in &usage.delegations_granted {
+            say!("account.granted", "    → {delegate} ({scope}, {state})", delegate = grant.delegate, scope = grant.scope, state = delegation_state(grant, now));
+        }
+        for grant in &usage.delegations_received {
+            say!("account.received", "    ← {owner} ({scope}, {state})", owner = grant.owner, scope = grant.scope, state = delegation_state(grant, now));
+        }
+        Ok(())
+    }
+
+    async fn send_message(&mut self, server: &str, recipient: &str, message: &str, options: &SendOptions) -> Result<String> {
+        // Refuse before any lookups what the server would refuse anyway
+        let payload = Payload::text(message).with_supersedes(options.supersedes.clone()).with_group(options.group.clone());
+        OutgoingEstimate::for_payload(&payload, self.server(server)?.max_message_size)?.check()?;
+
+        // A recipient we have no key for yet may have published one
+        if !self.server(server)?.contacts.contains_key(recipient) {
+            if let Err(e) = self.fetch_contact_key(server, recipient).await {
+                return Err(anyhow!("Recipient {} not found and their key couldn't be looked up ({}). You need to exchange keys first.", recipient, e));
+            }
+        }
+        let connection = self.server(server)?;
+        let contact = connection.contacts.get(recipient)
             .ok_or_else(|| anyhow!("Recipient {} not found. You need to exchange keys first.", recipient))?;
         
+        // Refuse to encrypt to a key the contact has revoked since we last trusted them
+        let revocations = self.get_revocations(server, recipient).await?;
+        if let Some(latest) = revocations.iter().max_by_key(|r| r.timestamp) {
+            if contact.trusted_at.is_none_or(|trusted_at| trusted_at < latest.timestamp) {
+                out!("{}", tr!("send.revoked", "🚨 {recipient} revoked a key on {timestamp} ({reason}).",
+                    recipient = recipient, timestamp = latest.timestamp, reason = latest.reason).red().bold());
+                out!("{}", tr!("send.revoked_hint", "🚨 Get their new key, re-add it and run 'trust {recipient}' before sending.", recipient = recipient).red().bold());
+                return Err(anyhow!("{} has a revoked key", recipient));
+            }
+        }
+        
         // Encrypt message for recipient
-        let encrypted_content = self.crypto.encrypt_message(recipient_pubkey, message)?;
-        let encrypted_hex = hex::encode(&encrypted_content);
+        let encrypted_content = connection.crypto.encrypt_message(&contact.key, &payload.encode()?)?;
+        
+        match self.submit(server, recipient, &encrypted_content, options).await? {
+            ServerResponse::MessageSent { message_id, expires_at, retention_applied, pending_until, received_at, server_signature, replaced, .. } => {
+                info!("✅ Message sent successfully (ID: {})", message_id);
+                let received_at = received_at.unwrap_or_else(Utc::now);
+                if let Err(e) = self.check_receipt(server, &message_id, &self.id, recipient, received_at, server_signature.as_deref()) {
+                    telemetry::verify_failed();
+                    out!("{}", tr!("send.receipt_rejected", "🚨 The server's receipt for message {id} doesn't verify: {error}", id = message_id, error = e).red().bold());
+                }
+                let record = self.seal_record(server, recipient, true, &message_id, received_at, message);
+                self.save_history(record.map(|record| HistoryRecord { expires_at, ..record }));
+                if let Some(superseded) = options.supersedes.as_deref().filter(|_| replaced) {
+                    say!("send.replaced", "♻️ {recipient} hadn't fetched {id} yet; only this version will be delivered", recipient = recipient, id = superseded);
+                    if let Err(e) = self.store.set_state(superseded, MessageState::Superseded) {
+                        say!("history.update_failed", "❌ Failed to update history: {error}", error = e);
+                    }
+                }
+                if let Some(expires_at) = expires_at {
+                    if retention_applied {
+                        say!("send.retention_expires", "⏳ {recipient}'s retention policy expires this message at {expires_at}", recipient = recipient, expires_at = expires_at);
+                    } else {
+                        say!("send.expires", "⏳ Message expires at {expires_at}", expires_at = expires_at);
+                    }
+                }
+                if let Some(pending_until) = pending_until {
+                    say!("send.pending_invite", "📨 {recipient} hasn't registered yet; the server holds the message for them until {until}",
+                        recipient = recipient, until = pending_until.format("%Y-%m-%d %H:%M UTC"));
+                }
+                if let Some(deliver_by) = options.deliver_by {
+                    say!("send.deliver_by", "⌛ You'll be notified if {recipient} hasn't fetched it by {deliver_by}", recipient = recipient, deliver_by = deliver_by);
+                }
+                Ok(message_id)
+            }
+            _ => Err(anyhow!("Unexpected response from server"))
+        }
+    }
+
+    /// Send a message typed by the user, numbered so the recipient can
+    /// show it in order. The number is only taken once the server accepts it.
+    /// A replacement goes unnumbered: it takes the original's number if it
+    /// replaces it, and is shown as it comes otherwise.
+    async fn send_numbered(&mut self, target: &str, message: &str, mut options: SendOptions) -> Result<String> {
+        let (server, recipient) = self.resolve_direct(target)
+            .map(|(server, recipient)| (server.to_string(), recipient.to_string()))?;
+        let (server, recipient) = (server.as_str(), recipient.as_str());
+        if options.supersedes.is_some() {
+            return self.send_message(server, recipient, message, &options).await;
+        }
+        let peer = self.display_id(server, recipient);
+        let seq = self.config.sequences.next(&peer);
+        // Only the first message needs confirming; after that the id is one
+        // this user chose to write to
+        if seq == 1 && !options.confirmed {
+            if let Some(warning) = lookalike_warning(&self.lookalikes(server), recipient) {
+                if !confirm_lookalike(&warning)? {
+                    return Err(anyhow!(tr!("lookalike.not_sent", "Not sent: {warning}. Check the id, or send again with --confirm",
+                        warning = warning)));
+                }
+            }
+        }
+        options.seq = Some(seq);
+        let message_id = self.send_message(server, recipient, message, &options).await?;
+        self.config.sequences.sent(&peer, seq);
+        self.save_config();
+        Ok(message_id)
+    }
+
+    /// Split `team@work` (or `@group:team@work`) into (`work`, `team`), as
+    /// with contacts.
+    fn resolve_group(&self, target: &str) -> Result<(String, String)> {
+        let (server, name) = self.resolve_target(target.strip_prefix("@group:").unwrap_or(target));
+        check_group_name(name).map_err(|e| anyhow!(e))?;
+        Ok((server.to_string(), name.to_string()))
+    }
+
+    /// Sign a group request for `server` and send it.
+    async fn group_request(&self, server: &str, action: &str, group: &str, detail: &str,
+        command: impl FnOnce(DateTime<Utc>, String) -> ServerCommand) -> Result<ServerResponse> {
+        let connection = self.server(server)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::GROUP, &group_payload(&self.id, action, group, detail, signed_at));
+        match connection.request(&command(signed_at, hex::encode(signature.to_bytes()))).await? {
+            ServerResponse::Error { message, code, .. } => Err(Refused { message, code }.into()),
+            response => Ok(response),
+        }
+    }
+
+    fn cache_group(&mut self, server: &str, group: Group) {
+        self.config.groups.insert(self.display_id(server, &group.name), group);
+        self.save_config();
+    }
+
+    fn forget_group(&mut self, server: &str, name: &str) {
+        if self.config.groups.remove(&self.display_id(server, name)).is_some() {
+            self.save_config();
+        }
+    }
+
+    async fn create_group(&mut self, target: &str, members: Vec<String>) -> Result<Group> {
+        let (server, name) = self.resolve_group(target)?;
+        let id = self.id.clone();
+        let response = self.group_request(&server, "CreateGroup", &name, &members.join(","), |signed_at, signature| ServerCommand::CreateGroup {
+            client_id: id, group: name.clone(), members, signed_at, signature,
+        }).await?;
+        let ServerResponse::Group { group } = response else { return Err(anyhow!("Unexpected response from server")) };
+        self.cache_group(&server, group.clone());
+        Ok(group)
+    }
+
+    /// A group as the server has it now, cached for next time. A group
+    /// the server no longer has us in is dropped from the cache.
+    async fn fetch_group(&mut self, server: &str, name: &str) -> Result<Group> {
+        let id = self.id.clone();
+        let response = self.group_request(server, "GetGroup", name, "", |signed_at, signature| ServerCommand::GetGroup {
+            client_id: id, group: name.to_string(), signed_at, signature,
+        }).await;
+        match response {
+            Ok(ServerResponse::Group { group }) => {
+                self.cache_group(server, group.clone());
+                Ok(group)
+            }
+            Ok(_) => Err(anyhow!("Unexpected response from server")),
+            Err(e) => {
+                if refused_with(&e, error_code::UNKNOWN_GROUP) || refused_with(&e, error_code::NOT_GROUP_MEMBER) {
+                    self.forget_group(server, name);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Replace the cached groups on `server` with the ones the server has
+    /// us in. Returns how many there are.
+    async fn refresh_groups(&mut self, server: &str) -> Result<usize> {
+        let id = self.id.clone();
+        let response = self.group_request(server, "ListGroups", "", "", |signed_at, signature| ServerCommand::ListGroups {
+            client_id: id, signed_at, signature,
+        }).await?;
+        let ServerResponse::Groups { groups } = response else { return Err(anyhow!("Unexpected response from server")) };
+        let stale: Vec<String> = self.config.groups.iter()
+            .filter(|(key, group)| **key == self.display_id(server, &group.name))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            self.config.groups.remove(&key);
+        }
+        let count = groups.len();
+        for group in groups {
+            self.config.groups.insert(self.display_id(server, &group.name), group);
+        }
+        self.save_config();
+        Ok(count)
+    }
+
+    async fn rename_group(&mut self, target: &str, new_name: &str) -> Result<Group> {
+        let (server, name) = self.resolve_group(target)?;
+        let id = self.id.clone();
+        let response = self.group_request(&server, "RenameGroup", &name, new_name, |signed_at, signature| ServerCommand::RenameGroup {
+            client_id: id, group: name.clone(), new_name: new_name.to_string(), signed_at, signature,
+        }).await?;
+        let ServerResponse::Group { group } = response else { return Err(anyhow!("Unexpected response from server")) };
+        self.forget_group(&server, &name);
+        self.cache_group(&server, group.clone());
+        Ok(group)
+    }
+
+    async fn leave_group(&mut self, target: &str) -> Result<()> {
+        let (server, name) = self.resolve_group(target)?;
+        let id = self.id.clone();
+        self.group_request(&server, "LeaveGroup", &name, "", |signed_at, signature| ServerCommand::LeaveGroup {
+            client_id: id, group: name.clone(), signed_at, signature,
+        }).await?;
+        self.forget_group(&server, &name);
+        Ok(())
+    }
+
+    /// Send each other member of a group their own copy of a message, using
+    /// the cached member list. If the server says someone isn't a member
+    /// any more, the list is fetched again once and the rest go to that.
+    /// `target` is `name` or `name@server`. Returns the ids of the copies sent.
+    pub async fn send_to_group(&mut self, target: &str, message: &str) -> Result<Vec<String>> {
+        let (server, name) = self.resolve_group(target)?;
+        let group = match self.config.groups.get(&self.display_id(&server, &name)) {
+            Some(group) => group.clone(),
+            None => self.fetch_group(&server, &name).await?,
+        };
+        let me = self.id.clone();
+        let others = |group: &Group, sent: &[String]| -> VecDeque<String> {
+            group.members.keys().filter(|member| **member != me && !sent.contains(member)).cloned().collect()
+        };
+        let mut pending = others(&group, &[]);
+        if pending.is_empty() {
+            return Err(anyhow!(tr!("gsend.alone", "Nobody else is in {group}", group = target)));
+        }
+        let (mut sent, mut ids, mut refreshed) = (Vec::new(), Vec::new(), false);
+        while let Some(member) = pending.front().cloned() {
+            let options = SendOptions { group: Some(name.clone()), ..SendOptions::default() };
+            match self.send_message(&server, &member, message, &options).await {
+                Ok(id) => {
+                    ids.push(id);
+                    sent.push(member);
+                    pending.pop_front();
+                }
+                Err(e) if !refreshed && refused_with(&e, error_code::NOT_GROUP_MEMBER) => {
+                    refreshed = true;
+                    let group = self.fetch_group(&server, &name).await?;
+                    pending = others(&group, &sent);
+                }
+                Err(e) if sent.is_empty() => return Err(e),
+                Err(e) => return Err(anyhow!(tr!("gsend.partial", "Sent to {sent} of {total} member(s); {member} failed: {error}",
+                    sent = sent.len(), total = sent.len() + pending.len(), member = member, error = e))),
+            }
+        }
+        Ok(ids)
+    }
+
+    fn print_group(&self, server: &str, group: &Group) {
+        say!("group.title", "👥 {group}: {count} member(s), created by {creator} on {date}", group = self.display_id(server, &group.name),
+            count = group.members.len(), creator = group.created_by, date = group.created_at.format("%Y-%m-%d"));
+        let contacts = self.servers.get(server).map(|connection| &connection.contacts);
+        for (member, role) in &group.members {
+            let key = if *member == self.id || contacts.is_some_and(|contacts| contacts.contains_key(member)) {
+                tr!("group.key_known", "key known")
+            } else {
+                tr!("group.key_unknown", "key looked up on first send")
+            };
+            say!("group.member", "  {member} ({role}, {key})", member = member, role = role, key = key);
+        }
+        if let Ok(connection) = self.server(server) {
+            say!("group.sender_key", "  Your sender key: {fingerprint}, published at registration and attached to each copy",
+                fingerprint = crypto::fingerprint(connection.crypto.get_x25519_public_key().as_bytes()));
+        }
+    }
+
+    /// `input` with a partly typed group name after `gsend` or a `group`
+    /// subcommand completed, as far as the cached names agree.
+    #[cfg(feature = "tui")]
+    pub(crate) fn complete_group(&self, input: &str) -> Option<String> {
+        let (command, partial) = input.rsplit_once(' ')?;
+        if !matches!(command.trim_start_matches('/').split_whitespace().collect::<Vec<_>>().as_slice(), ["gsend"] | ["group", "info" | "rename" | "leave"]) {
+            return None;
+        }
+        let matches: Vec<&String> = self.config.groups.keys().filter(|name| name.starts_with(partial)).collect();
+        let first = matches.first()?;
+        let common = matches.iter().fold(first.len(), |len, name| {
+            first.chars().zip(name.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum::<usize>().min(len)
+        });
+        let completed = if matches.len() == 1 { format!("{} ", first) } else { first[..common].to_string() };
+        (completed.len() > partial.len()).then(|| format!("{} {}", command, completed))
+    }
+
+    /// Sign and send already-encoded message content; returns the server's
+    /// MessageSent response.
+    async fn submit(&self, server: &str, recipient: &str, content: &[u8], options: &SendOptions) -> Result<ServerResponse> {
+        let connection = self.server(server)?;
         
-        // Sign the encrypted content
-        let signature = self.crypto.sign(encrypted_content.as_slice());
+        // Sign the encrypted content with the time, id and recipient, and the
+        // key it was encrypted with
+        let message_id = new_message_id();
+        let sent_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::SEND, &send_payload(&message_id, recipient, content, sent_at));
+        let x25519_public_key = hex::encode(connection.crypto.get_x25519_public_key().as_bytes());
+        let key_signature = connection.crypto.sign_with_context(crypto::context::SENDER_KEY, &sender_key_payload(&self.id, &x25519_public_key));
         
         let send_cmd = ServerCommand::Send {
             sender_id: self.id.clone(),
             recipient_id: recipient.to_string(),
-            encrypted_content: encrypted_hex,
+            encrypted_content: hex::encode(content),
             signature: hex::encode(signature.to_bytes()),
-            message_id: uuid::Uuid::new_v4().to_string(),
+            message_id,
+            ttl_secs: options.ttl_secs.or((recipient == self.id).then_some(SELF_MESSAGE_TTL_SECS)),
+            deliver_by: options.deliver_by,
+            sender_key: Some(SenderKey { x25519_public_key, signature: hex::encode(key_signature.to_bytes()) }),
+            seq: options.seq,
+            on_behalf_of: None,
+            supersedes: options.supersedes.clone(),
+            sent_at: Some(sent_at),
+            group: options.group.clone(),
         };
         
-        let mut stream = TcpStream::connect(addr).await?;
-        let request = serde_json::to_string(&send_cmd)?;
-        stream.write_all(request.as_bytes()).await?;
-        
-        let mut buf = [0; 4096];
-        let n = stream.read(&mut buf).await?;
-        let response = String::from_utf8_lossy(&buf[..n]);
-        
-        let server_response: ServerResponse = serde_json::from_str(&response)?;
-        match server_response {
-            ServerResponse::MessageSent { message_id } => {
-                info!("✅ Message sent successfully (ID: {})", message_id);
-                Ok(())
-            }
-            ServerResponse::Error { message } => {
+        let response = connection.request(&send_cmd).await
+            .inspect_err(|_| telemetry::sent(SendOutcome::Failed))?;
+        match response {
+            ServerResponse::Error { message, code, .. } => {
+                telemetry::sent(SendOutcome::Refused);
                 error!("❌ Failed to send message: {}", message);
-                Err(anyhow!("Server error: {}", message))
+                Err(Refused { message, code }.into())
+            }
+            response => {
+                telemetry::sent(SendOutcome::Accepted);
+                Ok(response)
             }
-            _ => Err(anyhow!("Unexpected response from server"))
         }
     }
 
-    async fn receive_messages(&self, addr: &str) -> Result<Vec<Message>> {
+    async fn receive_messages(&self, server: &str) -> Result<Vec<Message>> {
+        let connection = self.server(server)?;
         let get_messages_cmd = ServerCommand::GetMessages {
             client_id: self.id.clone(),
+            since: None,
+            on_behalf_of: None,
+            signature: None,
+            challenge: None,
         };
         
-        let mut stream = TcpStream::connect(addr).await?;
-        let request = serde_json::to_string(&get_messages_cmd)?;
-        stream.write_all(request.as_bytes()).await?;
-        
-        let mut buf = [0; 4096];
-        let n = stream.read(&mut buf).await?;
-        let response = String::from_utf8_lossy(&buf[..n]);
-        
-        let server_response: ServerResponse = serde_json::from_str(&response)?;
+        let server_response = connection.request(&get_messages_cmd).await?;
         match server_response {
-            ServerResponse::MessageReceived { message } => {
-                Ok(vec![message])
-            }
-            ServerResponse::Error { message } => {
-                if message.contains("No messages found") {
-                    Ok(vec![])
-                } else {
-                    Err(anyhow!("Server error: {}", message))
-                }
-            }
+            ServerResponse::Messages { messages } => Ok(messages),
+            // Servers from before `Messages` send only the newest message, or an error for none
+            ServerResponse::MessageReceived { message } => Ok(vec![*message]),
+            ServerResponse::Error { message, .. } if message.contains("No messages found") => Ok(vec![]),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
             _ => Err(anyhow!("Unexpected response from server"))
         }
     }
 
-    async fn get_online_clients(&self, addr: &str) -> Result<Vec<String>> {
-        let get_clients_cmd = ServerCommand::GetClients;
-        
-        let mut stream = TcpStream::connect(addr).await?;
-        let request = serde_json::to_string(&get_clients_cmd)?;
-        stream.write_all(request.as_bytes()).await?;
-        
-        let mut buf = [0; 4096];
-        let n = stream.read(&mut buf).await?;
-        let response = String::from_utf8_lossy(&buf[..n]);
-        
-        let server_response: ServerResponse = serde_json::from_str(&response)?;
-        match server_response {
-            ServerResponse::ClientList { clients } => {
-                Ok(clients)
-            }
-            _ => Err(anyhow!("Unexpected response from server"))
+    /// Have the server delete messages this client has fetched and recorded.
+    async fn acknowledge(&self, server: &str, message_ids: &[String]) -> Result<()> {
+        let connection = self.server(server)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::ACK, &ack_payload(&self.id, message_ids, signed_at));
+        let command = ServerCommand::Ack {
+            client_id: self.id.clone(),
+            message_ids: message_ids.to_vec(),
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::Ok => Ok(()),
+            // Servers from before Ack keep every message; nothing to do
+            ServerResponse::Error { code: Some(code), .. } if code == error_code::INVALID_REQUEST => Ok(()),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
         }
     }
 
-    fn add_contact(&mut self, contact_id: String, public_key: X25519PublicKey) {
-        self.connected_clients.insert(contact_id, public_key);
-        info!("👤 Added contact with public key: {}", hex::encode(public_key.as_bytes()).yellow());
+    /// Acknowledge everything fetched since the last time, so the next
+    /// fetch doesn't return it again. Its signature verdicts are in history
+    /// by now, so they go too.
+    async fn acknowledge_received(&mut self) {
+        self.signature_checks.clear();
+        for (server, message_ids) in std::mem::take(&mut self.pending_acks) {
+            if let Err(e) = self.acknowledge(&server, &message_ids).await {
+                say!("receive.ack_failed", "⚠️ Failed to acknowledge {count} message(s) on {server}; they will be fetched again: {error}",
+                    count = message_ids.len(), server = server, error = e);
+            }
+        }
     }
 
-    async fn interactive_mode(&mut self, addr: &str) -> Result<()> {
-        println!("\n🔐 Secure Messaging Client - Interactive Mode");
-        println!("=============================================");
-        println!("Commands:");
-        println!("  send <recipient> <message>  - Send encrypted message");
-        println!("  receive                     - Check for new messages");
-        println!("  contacts                    - List online contacts");
-        println!("  add <contact_id> <pubkey>   - Add contact (hex encoded X25519 key)");
-        println!("  quit                        - Exit");
-        println!();
+    /// Search this client's mailbox on a server by metadata.
+    async fn search_mailbox(&self, server: &str, search: MessageSearch) -> Result<(Vec<MessageMetadata>, bool)> {
+        let connection = self.server(server)?;
+        connection.features.require(&features::MESSAGE_SEARCH)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::SEARCH, &search.payload(&self.id, signed_at));
+        let command = ServerCommand::SearchMessages {
+            client_id: self.id.clone(),
+            search,
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::SearchResults { results, truncated } => Ok((results, truncated)),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
 
-        loop {
-            print!("{} > ", self.id.green());
+    /// Who of `contacts` is online on the current server. No contacts asks
+    /// about everyone registered, which only server admins may.
+    async fn presence(&self, contacts: &[String]) -> Result<Vec<PresenceEntry>> {
+        let connection = self.server(&self.current)?;
+        let signature = connection.crypto.sign_with_context(crypto::context::PRESENCE, &presence_payload(&self.id, contacts));
+        let command = ServerCommand::GetPresence {
+            client_id: self.id.clone(),
+            contacts: contacts.to_vec(),
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::Presence { entries } => Ok(entries),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Presence changes for `contacts`, polled from the current server: an
+    /// event per contact at first, then one whenever a contact comes or
+    /// goes. A failed poll yields its error and the stream carries on.
+    pub fn presence_events(&self, contacts: Vec<String>) -> impl Stream<Item = Result<PresenceEvent>> + '_ {
+        let poll = tokio::time::interval(std::time::Duration::from_secs(PRESENCE_POLL_SECS));
+        stream::unfold((contacts, PresenceTracker::default(), VecDeque::new(), poll), move |(contacts, mut tracker, mut pending, mut poll)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (contacts, tracker, pending, poll)));
+                }
+                poll.tick().await;
+                match self.presence(&contacts).await {
+                    Ok(entries) => pending.extend(tracker.update(entries)),
+                    Err(e) => return Some((Err(e), (contacts, tracker, pending, poll))),
+                }
+            }
+        })
+    }
+
+    /// Show who of `contacts` is online until interrupted: a table redrawn
+    /// on every change on a terminal, a line per change otherwise.
+    async fn watch_presence(&self, contacts: Vec<String>) -> Result<()> {
+        let watching = if contacts.is_empty() { tr!("presence.everyone", "everyone") } else { contacts.join(", ") };
+        let redraw = io::stdout().is_terminal();
+        let mut board = BTreeMap::new();
+        let events = self.presence_events(contacts);
+        futures::pin_mut!(events);
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                // Nothing shown yet: most likely the request itself is refused
+                Err(e) if board.is_empty() => return Err(e),
+                Err(e) => {
+                    say!("presence.poll_failed", "⚠️ Couldn't check who is online: {error}", error = e);
+                    continue;
+                }
+            };
+            if !redraw {
+                if event.online {
+                    say!("presence.online", "● {id} is online", id = self.display_id(&self.current, &event.client_id));
+                } else {
+                    say!("presence.offline", "○ {id} is offline", id = self.display_id(&self.current, &event.client_id));
+                }
+            }
+            board.insert(event.client_id.clone(), event);
+            if redraw {
+                print!("\x1b[2J\x1b[H");
+                say!("presence.title", "👥 Watching {who} on {server}", who = watching, server = self.current);
+                for row in presence::table(&board, self.config.control_display) {
+                    out!("{}", row);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The server's client list, brought up to date from the local cache
+    /// with only the changes since it was last synced.
+    async fn get_online_clients(&self, server: &str) -> Result<Vec<String>> {
+        let connection = self.server(server)?;
+        let (mut revision, cached) = self.store.directory(server)?.unwrap_or_default();
+        let mut clients: BTreeSet<String> = cached.into_iter().collect();
+        let synced_from = revision;
+        loop {
+            let command = ServerCommand::GetClientsDelta { since_revision: revision };
+            match connection.request(&command).await? {
+                ServerResponse::ClientsDelta { revision: now, reset, changes, more } => {
+                    if reset {
+                        clients.clear();
+                    }
+                    for change in changes {
+                        match change.kind {
+                            DirectoryChangeKind::Added | DirectoryChangeKind::Changed => clients.insert(change.client_id),
+                            DirectoryChangeKind::Removed => clients.remove(&change.client_id),
+                        };
+                    }
+                    revision = now;
+                    if !more {
+                        break;
+                    }
+                }
+                // Servers from before deltas only send the whole list
+                ServerResponse::Error { code: Some(code), .. } if code == error_code::INVALID_REQUEST => {
+                    return match connection.request(&ServerCommand::GetClients).await? {
+                        ServerResponse::ClientList { clients } => Ok(clients),
+                        ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+                        _ => Err(anyhow!("Unexpected response from server")),
+                    };
+                }
+                ServerResponse::Error { message, .. } => return Err(anyhow!("Server error: {}", message)),
+                _ => return Err(anyhow!("Unexpected response from server")),
+            }
+        }
+        let clients: Vec<String> = clients.into_iter().collect();
+        if revision != synced_from {
+            if let Err(e) = self.store.save_directory(server, revision, &clients) {
+                say!("contacts.cache_failed", "⚠️ Failed to cache the client list: {error}", error = e);
+            }
+        }
+        Ok(clients)
+    }
+
+    async fn get_revocations(&self, server: &str, client_id: &str) -> Result<Vec<Revocation>> {
+        let connection = self.server(server)?;
+        let command = ServerCommand::GetRevocations { client_id: client_id.to_string() };
+        match connection.request(&command).await? {
+            ServerResponse::Revocations { revocations, .. } => Ok(revocations),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn set_retention(&self, server: &str, sender_id: &str, ttl_secs: Option<u64>) -> Result<()> {
+        let connection = self.server(server)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::RETENTION, &retention_payload(&self.id, sender_id, ttl_secs, signed_at));
+        let command = ServerCommand::SetRetention {
+            client_id: self.id.clone(),
+            sender_id: sender_id.to_string(),
+            ttl_secs,
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::Ok => Ok(()),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Read one of our user data keys from the current server: the decrypted
+    /// value, if any, and its version.
+    async fn get_user_data(&self, key: &str) -> Result<(Option<String>, u64)> {
+        let connection = self.server(&self.current)?;
+        connection.features.require(&features::USER_DATA)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::USER_DATA_REF, &user_data_ref_payload(&self.id, key, signed_at));
+        let command = ServerCommand::GetUserData {
+            client_id: self.id.clone(),
+            key: key.to_string(),
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::UserData { value, version, .. } => {
+                let value = value.map(|value| connection.crypto.open_user_data(&value)).transpose()?;
+                Ok((value, version))
+            }
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Write (or with no value, delete) one of our user data keys, provided
+    /// it is still at `version`. Returns the new version.
+    async fn put_user_data(&self, key: &str, value: Option<&str>, version: u64) -> Result<u64> {
+        let connection = self.server(&self.current)?;
+        connection.features.require(&features::USER_DATA)?;
+        let value = value.map(|value| crypto::seal(&connection.crypto.user_data_key(), value)).transpose()?;
+        let signature = connection.crypto.sign_with_context(crypto::context::USER_DATA, &user_data_payload(&self.id, key, value.as_deref(), version));
+        let command = ServerCommand::PutUserData {
+            client_id: self.id.clone(),
+            key: key.to_string(),
+            value,
+            version,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::UserData { version, .. } => Ok(version),
+            ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::USER_DATA_CONFLICT) => {
+                Err(anyhow!("{}", tr!("userdata.conflict", "{key} was changed elsewhere; read it again before writing", key = key)))
+            }
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn handle_userdata_command(&self, args: &[&str]) {
+        match args {
+            ["get", key] => match self.get_user_data(key).await {
+                Ok((Some(value), version)) => {
+                    output::print(output::Level::Data, &value);
+                    note!("{}", tr!("userdata.version", "(version {version})", version = version));
+                }
+                Ok((None, _)) => say!("userdata.missing", "📭 No user data under {key}", key = key),
+                Err(e) => say!("userdata.get_failed", "❌ Failed to read user data: {error}", error = e),
+            },
+            ["set", key, value @ ..] if !value.is_empty() => {
+                let result = match self.get_user_data(key).await {
+                    Ok((_, version)) => self.put_user_data(key, Some(&value.join(" ")), version).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(version) => say!("userdata.stored", "💾 Stored {key} (version {version})", key = key, version = version),
+                    Err(e) => say!("userdata.put_failed", "❌ Failed to store user data: {error}", error = e),
+                }
+            }
+            ["delete", key] => {
+                let result = match self.get_user_data(key).await {
+                    Ok((None, _)) => Ok(false),
+                    Ok((Some(_), version)) => self.put_user_data(key, None, version).await.map(|_| true),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(true) => say!("userdata.deleted", "🗑️ Deleted {key}", key = key),
+                    Ok(false) => say!("userdata.missing", "📭 No user data under {key}", key = key),
+                    Err(e) => say!("userdata.put_failed", "❌ Failed to store user data: {error}", error = e),
+                }
+            }
+            _ => say!("userdata.usage", "❌ Usage: userdata <get <key>|set <key> <value>|delete <key>>"),
+        }
+    }
+
+    /// How and where a client registered on the current server. Admins only.
+    async fn client_details(&self, client_id: &str) -> Result<ClientInfo> {
+        let connection = self.server(&self.current)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::CLIENT_DETAILS, &client_details_payload(&self.id, client_id, signed_at));
+        let command = ServerCommand::GetClientDetails {
+            admin_id: self.id.clone(),
+            client_id: client_id.to_string(),
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::ClientDetails { client } => Ok(client),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Make a fresh single-use invite code on the current server.
+    async fn create_invite_code(&self) -> Result<String> {
+        let connection = self.server(&self.current)?;
+        let code = hex::encode(rand::random::<[u8; 12]>());
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::INVITE_CODE, &invite_code_payload(&self.id, &code, signed_at));
+        let command = ServerCommand::CreateInviteCode {
+            admin_id: self.id.clone(),
+            code: code.clone(),
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::Ok => Ok(code),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Run admin actions on the current server as one batch. Returns whether
+    /// anything was applied, and how each action went.
+    async fn admin_batch(&self, actions: Vec<AdminAction>, transactional: bool) -> Result<(bool, Vec<AdminActionResult>)> {
+        let connection = self.server(&self.current)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::ADMIN_BATCH, &admin_batch_payload(&self.id, &actions, transactional, signed_at));
+        let command = ServerCommand::AdminBatch {
+            admin_id: self.id.clone(),
+            actions,
+            transactional,
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::AdminBatch { applied, results } => Ok((applied, results)),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Promote the current server from standby to primary.
+    async fn promote_server(&self) -> Result<()> {
+        let connection = self.server(&self.current)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::PROMOTE, &promote_payload(&self.id, signed_at));
+        let command = ServerCommand::Promote {
+            admin_id: self.id.clone(),
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::Ok => Ok(()),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// A snapshot of the current server's internals.
+    async fn debug_dump(&self) -> Result<serde_json::Value> {
+        let connection = self.server(&self.current)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::DEBUG_DUMP, &debug_dump_payload(&self.id, signed_at));
+        let command = ServerCommand::DebugDump {
+            admin_id: self.id.clone(),
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::DebugDump { dump } => Ok(dump),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn handle_admin_command(&self, args: &[&str], input: &str) {
+        match args {
+            ["batch", _, ..] => {
+                let result = match parse_admin_batch(words_after(input, 2)) {
+                    Ok((actions, transactional)) => self.admin_batch(actions, transactional).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok((applied, results)) => {
+                        if applied {
+                            say!("admin.batch_applied", "🧺 Batch applied:");
+                        } else {
+                            say!("admin.batch_not_applied", "🧺 Nothing in the batch was applied:");
+                        }
+                        for (index, result) in results.iter().enumerate() {
+                            out!("   {} {}. {}", if result.ok { "✅" } else { "❌" }, index + 1, result.detail);
+                        }
+                    }
+                    Err(e) => say!("admin.batch_failed", "❌ Failed to run the batch: {error}", error = e),
+                }
+            }
+            ["dump"] => match self.debug_dump().await {
+                Ok(dump) => output::print(output::Level::Data, &serde_json::to_string_pretty(&dump).unwrap_or_default()),
+                Err(e) => say!("admin.dump_failed", "❌ Failed to get a debug dump: {error}", error = e),
+            },
+            ["promote"] => match self.promote_server().await {
+                Ok(()) => say!("admin.promoted", "🪞 {server} is now the primary and takes writes", server = self.current),
+                Err(e) => say!("admin.promote_failed", "❌ Failed to promote the server: {error}", error = e),
+            },
+            ["invite-code"] => match self.create_invite_code().await {
+                Ok(code) => {
+                    say!("admin.invite_code", "🎟️ Invite code, good for one registration:");
+                    output::print(output::Level::Data, &code);
+                    say!("admin.invite_code_hint", "   They register with: client <id> {flag} <code>", flag = CREDENTIAL_FLAG);
+                }
+                Err(e) => say!("admin.invite_code_failed", "❌ Failed to make an invite code: {error}", error = e),
+            },
+            ["client", client_id] => match self.client_details(client_id).await {
+                Ok(client) => {
+                    out!("👤 {}", client.id);
+                    out!("   {}", tr!("admin.registered", "Registered: {time}", time = client.registered_at.format("%Y-%m-%d %H:%M UTC")));
+                    match client.registration {
+                        Some(registration) => {
+                            out!("   {}", tr!("admin.source", "Source: {source}",
+                                source = registration.source.unwrap_or_else(|| tr!("admin.not_kept", "not kept"))));
+                            out!("   {}", tr!("admin.transport", "Transport: {transport}", transport = registration.transport));
+                            out!("   {}", tr!("admin.via", "Via: {via}",
+                                via = registration.via.unwrap_or_else(|| tr!("admin.direct", "direct registration"))));
+                            if let Some(version) = registration.protocol_version {
+                                out!("   {}", tr!("admin.protocol", "Protocol: {version}", version = version));
+                            }
+                            if !registration.capabilities.is_empty() {
+                                out!("   {}", tr!("admin.capabilities", "Capabilities: {capabilities}", capabilities = registration.capabilities.join(", ")));
+                            }
+                        }
+                        None => out!("   {}", tr!("admin.no_registration", "Registered before registration details were kept")),
+                    }
+                }
+                Err(e) => say!("admin.details_failed", "❌ Failed to get client details: {error}", error = e),
+            },
+            _ => say!("admin.usage", "❌ Usage: admin client <client_id> | admin invite-code | admin promote | admin dump | admin batch [--best-effort] <action>; <action>..."),
+        }
+    }
+
+    /// Report a sender for spam. The server drops their messages to us for a
+    /// while; returns until when.
+    async fn report_sender(&self, server: &str, sender_id: &str, reason: &str) -> Result<DateTime<Utc>> {
+        let connection = self.server(server)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::REPORT, &report_payload(&self.id, sender_id, reason, signed_at));
+        let command = ServerCommand::ReportSender {
+            client_id: self.id.clone(),
+            sender_id: sender_id.to_string(),
+            reason: reason.to_string(),
+            signed_at,
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::SenderReported { muted_until, .. } => Ok(muted_until),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Open a guest link on the current server. Unless `plain`, the link
+    /// carries our X25519 key and guests must encrypt to it.
+    async fn create_guest_link(&self, ttl_secs: u64, max_messages: u32, plain: bool) -> Result<(GuestLink, GuestToken)> {
+        let connection = self.server(&self.current)?;
+        connection.features.require(&features::GUEST_LINKS)?;
+        let x25519_public_key = (!plain).then(|| hex::encode(connection.crypto.get_x25519_public_key().as_bytes()));
+        let signed_at = self.now();
+        let payload = guest_link_payload(&self.id, ttl_secs, max_messages, x25519_public_key.as_deref(), signed_at);
+        let command = ServerCommand::CreateGuestLink {
+            client_id: self.id.clone(),
+            ttl_secs,
+            max_messages,
+            x25519_public_key,
+            signed_at,
+            signature: hex::encode(connection.crypto.sign_with_context(crypto::context::GUEST_LINK, &payload).to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::GuestLinkCreated { link, secret } => {
+                let token = GuestToken {
+                    server: connection.addr().to_string(),
+                    owner: self.id.clone(),
+                    link_id: link.id.clone(),
+                    secret,
+                    x25519_public_key: link.x25519_public_key.clone(),
+                };
+                Ok((link, token))
+            }
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn guest_links(&self) -> Result<Vec<GuestLink>> {
+        let connection = self.server(&self.current)?;
+        connection.features.require(&features::GUEST_LINKS)?;
+        let signature = connection.crypto.sign_with_context(crypto::context::GUEST_LINK_REF, &guest_link_ref_payload(&self.id, ""));
+        let command = ServerCommand::ListGuestLinks { client_id: self.id.clone(), signature: hex::encode(signature.to_bytes()) };
+        match connection.request(&command).await? {
+            ServerResponse::GuestLinks { links } => Ok(links),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn revoke_guest_link(&self, link_id: &str) -> Result<()> {
+        let connection = self.server(&self.current)?;
+        connection.features.require(&features::GUEST_LINKS)?;
+        let signature = connection.crypto.sign_with_context(crypto::context::GUEST_LINK_REF, &guest_link_ref_payload(&self.id, link_id));
+        let command = ServerCommand::RevokeGuestLink {
+            client_id: self.id.clone(),
+            link_id: link_id.to_string(),
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::Ok => Ok(()),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Let a contact act for this mailbox until `ttl_secs` from now, then
+    /// hand them the key of each conversation with our other contacts on
+    /// that server, so they can read (and with read-send, write) them without
+    /// this identity's keys. Returns how many conversations were shared.
+    async fn grant_delegation(&self, target: &str, scope: DelegationScope, ttl_secs: u64) -> Result<(DateTime<Utc>, usize)> {
+        let (server, delegate) = self.resolve_direct(target)?;
+        let connection = self.server(server)?;
+        if !connection.contacts.contains_key(delegate) {
+            return Err(anyhow!("Unknown contact {}; add their key first", target));
+        }
+        let issued_at = self.now();
+        let expires_at = issued_at + chrono::Duration::seconds(ttl_secs as i64);
+        let payload = delegation_payload(&self.id, delegate, scope, issued_at, expires_at);
+        let delegation = Delegation {
+            owner: self.id.clone(),
+            delegate: delegate.to_string(),
+            scope,
+            issued_at,
+            expires_at,
+            signature: hex::encode(connection.crypto.sign_with_context(crypto::context::DELEGATE, &payload).to_bytes()),
+            revoked_at: None,
+        };
+        match connection.request(&ServerCommand::Delegate { delegation }).await? {
+            ServerResponse::Ok => {}
+            ServerResponse::Error { message, .. } => return Err(anyhow!("Server error: {}", message)),
+            _ => return Err(anyhow!("Unexpected response from server")),
+        }
+
+        let keys: BTreeMap<String, String> = connection.contacts.iter()
+            .filter(|(id, _)| id.as_str() != delegate)
+            .map(|(id, contact)| (id.clone(), hex::encode(connection.crypto.conversation_key(&contact.key))))
+            .collect();
+        let shared = keys.len();
+        self.send_control(server, delegate, &ControlMessage::ConversationKeys { keys }).await?;
+        Ok((expires_at, shared))
+    }
+
+    async fn revoke_delegation(&self, target: &str) -> Result<()> {
+        let (server, delegate) = self.resolve_direct(target)?;
+        let connection = self.server(server)?;
+        let signature = connection.crypto.sign_with_context(crypto::context::DELEGATION_REF, &delegation_ref_payload(&self.id, delegate));
+        let command = ServerCommand::RevokeDelegation {
+            owner: self.id.clone(),
+            delegate: delegate.to_string(),
+            signature: hex::encode(signature.to_bytes()),
+        };
+        match connection.request(&command).await? {
+            ServerResponse::Ok => Ok(()),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Our grants on the current server, and what the delegates did.
+    async fn delegations(&self) -> Result<(Vec<Delegation>, Vec<DelegationAudit>)> {
+        let connection = self.server(&self.current)?;
+        let signed_at = self.now();
+        let signature = connection.crypto.sign_with_context(crypto::context::DELEGATION_REF, &delegations_payload(&self.id, signed_at));
+        let command = ServerCommand::ListDelegations { client_id: self.id.clone(), signed_at, signature: hex::encode(signature.to_bytes()) };
+        match connection.request(&command).await? {
+            ServerResponse::Delegations { delegations, audit } => Ok((delegations, audit)),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Keep conversation keys a mailbox owner shared with us, adding to any
+    /// they shared before. Returns how many we now hold for them.
+    fn store_delegated_keys(&mut self, owner: &str, keys: BTreeMap<String, String>) -> Result<usize> {
+        let mut held = self.delegated_keys(owner)?;
+        for (peer, key) in keys {
+            let key: [u8; 32] = hex::decode(&key)?.try_into().map_err(|_| anyhow!("Invalid conversation key for {}", peer))?;
+            held.insert(peer, key);
+        }
+        let hex_keys: BTreeMap<&String, String> = held.iter().map(|(peer, key)| (peer, hex::encode(key))).collect();
+        let sealed = crypto::seal(&self.crypto.local_store_key(), &serde_json::to_string(&hex_keys)?)?;
+        self.config.delegated_keys.insert(owner.to_string(), sealed);
+        self.save_config();
+        Ok(held.len())
+    }
+
+    /// Conversation keys `owner` shared with us, by peer.
+    fn delegated_keys(&self, owner: &str) -> Result<BTreeMap<String, [u8; 32]>> {
+        let Some(sealed) = self.config.delegated_keys.get(owner) else { return Ok(BTreeMap::new()) };
+        let hex_keys: BTreeMap<String, String> = serde_json::from_str(&self.crypto.open_local(sealed)?)?;
+        hex_keys.into_iter()
+            .map(|(peer, key)| {
+                let key: [u8; 32] = hex::decode(&key)?.try_into().map_err(|_| anyhow!("Invalid conversation key for {}", peer))?;
+                Ok((peer, key))
+            })
+            .collect()
+    }
+
+    /// Fetch from a mailbox delegated to us. Each message comes with its text
+    /// if the owner shared that conversation's key.
+    async fn fetch_delegated(&self, target: &str) -> Result<Vec<(Message, Option<String>)>> {
+        let (server, owner) = self.resolve_direct(target)?;
+        let keys = self.delegated_keys(&self.display_id(server, owner))?;
+        let connection = self.server(server)?;
+        let signature = connection.crypto.sign_with_context(crypto::context::DELEGATED_FETCH, &delegation_ref_payload(owner, &self.id));
+        let command = ServerCommand::GetMessages {
+            client_id: self.id.clone(),
+            since: None,
+            on_behalf_of: Some(owner.to_string()),
+            signature: Some(hex::encode(signature.to_bytes())),
+            challenge: None,
+        };
+        let messages = match connection.request(&command).await? {
+            ServerResponse::Messages { messages } => messages,
+            ServerResponse::MessageReceived { message } => vec![*message],
+            ServerResponse::Error { message, .. } if message.contains("No messages found") => vec![],
+            ServerResponse::Error { message, .. } => return Err(anyhow!("Server error: {}", message)),
+            _ => return Err(anyhow!("Unexpected response from server")),
+        };
+        Ok(messages.into_iter()
+            .map(|message| {
+                let text = keys.get(&message.sender_id)
+                    .and_then(|key| hex::decode(&message.content).ok()
+                        .and_then(|content| crypto::open_message(key, Some(key), &content).ok()))
+                    .map(|body| Payload::decode(&body).display());
+                (message, text)
+            })
+            .collect())
+    }
+
+    /// Send as a mailbox owner that gave us read-send access, encrypting
+    /// with the key of their conversation with `recipient`.
+    async fn send_as(&self, target: &str, recipient: &str, message: &str) -> Result<String> {
+        let (server, owner) = self.resolve_direct(target)?;
+        let keys = self.delegated_keys(&self.display_id(server, owner))?;
+        let key = keys.get(recipient)
+            .ok_or_else(|| anyhow!("{} hasn't shared their conversation with {}", target, recipient))?;
+        let connection = self.server(server)?;
+        let content = crypto::seal_message(key, Payload::text(message).encode()?.as_bytes())?;
+        let message_id = new_message_id();
+        let sent_at = self.now();
+        let command = ServerCommand::Send {
+            sender_id: self.id.clone(),
+            recipient_id: recipient.to_string(),
+            encrypted_content: hex::encode(&content),
+            signature: hex::encode(connection.crypto.sign_with_context(crypto::context::SEND, &send_payload(&message_id, recipient, &content, sent_at)).to_bytes()),
+            message_id,
+            ttl_secs: None,
+            deliver_by: None,
+            sender_key: None,
+            seq: None,
+            on_behalf_of: Some(owner.to_string()),
+            supersedes: None,
+            sent_at: Some(sent_at),
+            group: None,
+        };
+        let response = connection.request(&command).await
+            .inspect_err(|_| telemetry::sent(SendOutcome::Failed))?;
+        match response {
+            ServerResponse::MessageSent { message_id, .. } => {
+                telemetry::sent(SendOutcome::Accepted);
+                Ok(message_id)
+            }
+            ServerResponse::Error { message, .. } => {
+                telemetry::sent(SendOutcome::Refused);
+                Err(anyhow!("Server error: {}", message))
+            }
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn handle_group_command(&mut self, args: &[&str]) {
+        match args {
+            ["create", target, members] => {
+                let members: Vec<String> = members.split(',').filter(|member| !member.is_empty()).map(str::to_string).collect();
+                match self.create_group(target, members).await {
+                    Ok(group) => say!("group.created", "👥 Created {group} with {count} member(s)", group = target, count = group.members.len()),
+                    Err(e) => say!("group.create_failed", "❌ Failed to create the group: {error}", error = e),
+                }
+            }
+            ["info", target, rest @ ..] if rest.is_empty() || rest == ["--refresh"] => {
+                let result = match self.resolve_group(target) {
+                    Ok((server, name)) => match self.config.groups.get(&self.display_id(&server, &name)) {
+                        Some(group) if rest.is_empty() => Ok((server, group.clone())),
+                        _ => self.fetch_group(&server, &name).await.map(|group| (server, group)),
+                    },
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok((server, group)) => self.print_group(&server, &group),
+                    Err(e) => say!("group.info_failed", "❌ Failed to get the group: {error}", error = e),
+                }
+            }
+            ["list"] => {
+                if self.config.groups.is_empty() {
+                    say!("group.none", "👥 You aren't in any groups; group refresh fetches them from the server");
+                }
+                for (name, group) in &self.config.groups {
+                    let role = group.members.get(&self.id).map(ToString::to_string).unwrap_or_default();
+                    say!("group.entry", "  {group}  {count} member(s), {role}", group = name, count = group.members.len(), role = role);
+                }
+            }
+            ["refresh"] => {
+                let server = self.current.clone();
+                match self.refresh_groups(&server).await {
+                    Ok(count) => say!("group.refreshed", "👥 You're in {count} group(s) on {server}", count = count, server = server),
+                    Err(e) => say!("group.refresh_failed", "❌ Failed to fetch your groups: {error}", error = e),
+                }
+            }
+            ["rename", target, new_name] => match self.rename_group(target, new_name).await {
+                Ok(group) => say!("group.renamed", "👥 Renamed {old} to {new}", old = target, new = group.name),
+                Err(e) => say!("group.rename_failed", "❌ Failed to rename the group: {error}", error = e),
+            },
+            ["leave", target] => match self.leave_group(target).await {
+                Ok(()) => say!("group.left", "👥 You left {group}", group = target),
+                Err(e) => say!("group.leave_failed", "❌ Failed to leave the group: {error}", error = e),
+            },
+            _ => say!("group.usage", "❌ Usage: group create <name> <member,member,..> | group info <name> [--refresh] | group list | group refresh | group rename <name> <new_name> | group leave <name>"),
+        }
+    }
+
+    async fn handle_delegate_command(&self, args: &[&str], input: &str) {
+        match args {
+            ["grant", contact, scope, duration] => {
+                let parsed = scope.parse::<DelegationScope>()
+                    .and_then(|scope| Ok((scope, parse_duration(duration).ok_or_else(|| anyhow!("Invalid duration; use e.g. 30s, 15m, 12h or 7d"))?)));
+                let result = match parsed {
+                    Ok((scope, ttl_secs)) => self.grant_delegation(contact, scope, ttl_secs).await.map(|granted| (scope, granted)),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok((scope, (expires_at, shared))) => say!("delegate.granted", "🛂 {delegate} has {scope} access to your mailbox until {until}; shared {count} conversation(s) with them",
+                        delegate = contact, scope = scope, until = expires_at.format("%Y-%m-%d %H:%M UTC"), count = shared),
+                    Err(e) => say!("delegate.grant_failed", "❌ Failed to delegate your mailbox: {error}", error = e),
+                }
+            }
+            ["revoke", contact] => match self.revoke_delegation(contact).await {
+                Ok(()) => say!("delegate.revoked", "🛂 {delegate} can no longer use your mailbox", delegate = contact),
+                Err(e) => say!("delegate.revoke_failed", "❌ Failed to revoke the delegation: {error}", error = e),
+            },
+            ["list"] => match self.delegations().await {
+                Ok((delegations, audit)) => {
+                    if delegations.is_empty() {
+                        say!("delegate.none", "🛂 You haven't delegated your mailbox");
+                    }
+                    let now = Utc::now();
+                    for delegation in &delegations {
+                        say!("delegate.entry", "  {delegate}  {scope}, {state}, until {until}", delegate = delegation.delegate,
+                            scope = delegation.scope, state = delegation_state(delegation, now), until = delegation.expires_at.format("%Y-%m-%d %H:%M UTC"));
+                    }
+                    if !audit.is_empty() {
+                        say!("delegate.audit_title", "🛂 What your delegates did:");
+                        for entry in &audit {
+                            say!("delegate.audit_entry", "  {at}  {delegate} {action} {id}", at = entry.at.format("%Y-%m-%d %H:%M:%S"),
+                                delegate = entry.delegate, action = entry.action, id = entry.message_id.as_deref().unwrap_or("-"));
+                        }
+                    }
+                }
+                Err(e) => say!("delegate.list_failed", "❌ Failed to list delegations: {error}", error = e),
+            },
+            ["fetch", owner] => match self.fetch_delegated(owner).await {
+                Ok(messages) if messages.is_empty() => say!("delegate.fetched_none", "📭 No new messages for {owner}", owner = owner),
+                Ok(messages) => {
+                    for (message, text) in messages {
+                        let sender = if message.sender_id.is_empty() { SYSTEM_PEER } else { &message.sender_id };
+                        match text {
+                            Some(text) => out!("📨 [{}] {} {}: {}", owner, message.timestamp.format("%Y-%m-%d %H:%M"), sender, text),
+                            None => say!("delegate.no_key", "🔒 [{owner}] message {id} from {sender}: {owner} hasn't shared that conversation with you",
+                                owner = owner, id = message.id, sender = sender),
+                        }
+                    }
+                }
+                Err(e) => say!("delegate.fetch_failed", "❌ Failed to fetch {owner}'s messages: {error}", owner = owner, error = e),
+            },
+            ["send", owner, recipient, _, ..] => match self.send_as(owner, recipient, words_after(input, 4)).await {
+                Ok(message_id) => say!("delegate.sent", "✅ Sent to {recipient} as {owner} ({id})", recipient = recipient, owner = owner, id = message_id),
+                Err(e) => say!("delegate.send_failed", "❌ Failed to send as {owner}: {error}", owner = owner, error = e),
+            },
+            _ => say!("delegate.usage", "❌ Usage: delegate <grant <contact> <read|read-send> <duration>|list|revoke <contact>|fetch <owner>|send <owner> <recipient> <message>>"),
+        }
+    }
+
+    async fn handle_guestlink_command(&self, args: &[&str]) {
+        match args {
+            ["create", options @ ..] => {
+                let Some((ttl_secs, max_messages, plain)) = parse_guest_link_options(options) else {
+                    say!("guestlink.create_usage", "❌ Usage: guestlink create [--ttl <dur>] [--max <n>] [--plain]");
+                    return;
+                };
+                match self.create_guest_link(ttl_secs, max_messages, plain).await {
+                    Ok((link, token)) => {
+                        say!("guestlink.created", "🔗 Guest link {id} takes {max} message(s) until {until}. Share this token:",
+                            id = link.id, max = link.max_messages, until = link.expires_at.format("%Y-%m-%d %H:%M UTC"));
+                        output::print(output::Level::Data, &token.to_string());
+                        say!("guestlink.created_hint", "   They send with: client send --guest-token <token> <message>");
+                        if plain {
+                            say!("guestlink.plain_warning", "⚠️ Messages through this link are not encrypted; the server can read them");
+                        }
+                    }
+                    Err(e) => say!("guestlink.create_failed", "❌ Failed to create guest link: {error}", error = e),
+                }
+            }
+            ["list"] => match self.guest_links().await {
+                Ok(links) if links.is_empty() => say!("guestlink.none", "🔗 No guest links"),
+                Ok(links) => {
+                    let now = Utc::now();
+                    for link in links {
+                        let state = if link.revoked {
+                            tr!("guestlink.state_revoked", "revoked")
+                        } else if link.expires_at <= now {
+                            tr!("guestlink.state_expired", "expired")
+                        } else if link.used >= link.max_messages {
+                            tr!("guestlink.state_used_up", "used up")
+                        } else {
+                            tr!("guestlink.state_open", "open")
+                        };
+                        let kind = if link.x25519_public_key.is_some() {
+                            tr!("guestlink.encrypted", "encrypted")
+                        } else {
+                            tr!("guestlink.plain", "plain")
+                        };
+                        say!("guestlink.entry", "  {id}  {state}, {used}/{max} message(s), {kind}, until {until}",
+                            id = link.id, state = state, used = link.used, max = link.max_messages, kind = kind,
+                            until = link.expires_at.format("%Y-%m-%d %H:%M UTC"));
+                    }
+                }
+                Err(e) => say!("guestlink.list_failed", "❌ Failed to list guest links: {error}", error = e),
+            },
+            ["revoke", link_id] => match self.revoke_guest_link(link_id).await {
+                Ok(()) => say!("guestlink.revoked", "🔗 Guest link {id} revoked", id = link_id),
+                Err(e) => say!("guestlink.revoke_failed", "❌ Failed to revoke guest link: {error}", error = e),
+            },
+            _ => say!("guestlink.usage", "❌ Usage: guestlink <create [--ttl <dur>] [--max <n>] [--plain]|list|revoke <id>>"),
+        }
+    }
+
+    /// Revoke this client's key on a server, signed by the key itself or a recovery secret.
+    async fn revoke_key(&self, server: &str, reason: &str, recovery_secret: Option<&str>) -> Result<()> {
+        let connection = self.server(server)?;
+        let mut revocation = Revocation {
+            client_id: self.id.clone(),
+            key: hex::encode(connection.crypto.get_ed25519_public_key().as_bytes()),
+            reason: reason.to_string(),
+            timestamp: self.now(),
+            signature: String::new(),
+        };
+        let signature = match recovery_secret {
+            Some(secret) => crypto::sign_with_secret(secret, crypto::context::REVOKE, &revocation.signed_payload())?,
+            None => connection.crypto.sign_with_context(crypto::context::REVOKE, &revocation.signed_payload()),
+        };
+        revocation.signature = hex::encode(signature.to_bytes());
+
+        match connection.request(&ServerCommand::Revoke { revocation }).await? {
+            ServerResponse::Ok => Ok(()),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Fetch a client's entry in the key directory, checking the server's
+    /// signature and the log's hash chain. Returns the current key, epoch,
+    /// head hash and log.
+    async fn fetch_key_history(&self, server: &str, client_id: &str) -> Result<(Option<String>, u64, String, Vec<KeyLogEntry>)> {
+        let connection = self.server(server)?;
+        let command = ServerCommand::GetKeyHistory { client_id: client_id.to_string() };
+        let (public_key, epoch, head_hash, entries, signature) = match connection.request(&command).await? {
+            ServerResponse::KeyHistory { public_key, epoch, head_hash, entries, signature, .. } =>
+                (public_key, epoch, head_hash, entries, signature),
+            ServerResponse::Error { message, .. } => return Err(anyhow!("Server error: {}", message)),
+            _ => return Err(anyhow!("Unexpected response from server")),
+        };
+
+        let payload = key_directory_payload(client_id, public_key.as_deref(), epoch, &head_hash);
+        let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+        // Servers from before context labels sign the bare payload
+        connection.crypto.verify_with_context(crypto::context::KEY_DIRECTORY, &payload, &signature, &connection.server_pubkey)
+            .or_else(|_| connection.crypto.verify_legacy(&payload, &signature, &connection.server_pubkey))
+            .map_err(|_| anyhow!("Directory signature does not match the pinned server key"))?;
+
+        KeyLogEntry::verify_chain(&entries).map_err(|e| anyhow!("Key log is corrupt: {}", e))?;
+        if entries.len() as u64 != epoch || entries.last().map(|e| e.hash.as_str()).unwrap_or("") != head_hash {
+            return Err(anyhow!("Key log does not match the signed head"));
+        }
+        Ok((public_key, epoch, head_hash, entries))
+    }
+
+    /// Audit a contact's key history and check the log still extends the last
+    /// head we verified.
+    async fn audit_key(&mut self, server: &str, client_id: &str) -> Result<()> {
+        let (public_key, epoch, head_hash, entries) = self.fetch_key_history(server, client_id).await?;
+        if let Some(seen) = self.config.key_log_heads.get(server) {
+            let still_present = seen.epoch == 0
+                || entries.get(seen.epoch as usize - 1).is_some_and(|e| e.hash == seen.hash);
+            if !still_present {
+                return Err(anyhow!("Key log was rewritten since epoch {} was verified", seen.epoch));
+            }
+        }
+
+        say!("audit.title", "🔎 Key history for {client} (epoch {epoch}, chain verified):", client = client_id, epoch = epoch);
+        let history: Vec<_> = entries.iter().filter(|e| e.client_id == client_id).collect();
+        if history.is_empty() {
+            say!("audit.empty", "  (no keys logged)");
+        }
+        for entry in history {
+            let event = match entry.event {
+                KeyEvent::Registered => tr!("audit.registered", "registered"),
+                KeyEvent::Revoked => tr!("audit.revoked", "revoked"),
+            };
+            out!("  #{} {} {} {}", entry.seq, entry.timestamp.format("%Y-%m-%d %H:%M:%S"), event,
+                crypto::fingerprint(&hex::decode(&entry.public_key).unwrap_or_default()));
+        }
+        if let Some(key) = &public_key {
+            say!("audit.current", "  current: {fingerprint}", fingerprint = crypto::fingerprint(&hex::decode(key)?));
+        }
+
+        self.config.key_log_heads.insert(server.to_string(), KeyLogHead { epoch, hash: head_hash });
+        self.save_config();
+        Ok(())
+    }
+
+    fn export_state(&self, path: &str, passphrase: Option<&str>) -> Result<()> {
+        let (ed25519_secret, x25519_secret) = self.crypto.export_secrets();
+        let contacts = self.servers.iter()
+            .flat_map(|(server, connection)| connection.contacts.iter().map(move |(client_id, contact)| ContactRecord {
+                server: server.clone(),
+                client_id: client_id.clone(),
+                x25519_public_key: hex::encode(contact.key.as_bytes()),
+                trusted_at: contact.trusted_at,
+            }))
+            .collect();
+        let bundle = StateBundle {
+            client_id: self.id.clone(),
+            exported_at: Utc::now(),
+            ed25519_secret: Some(hex::encode(ed25519_secret)),
+            x25519_secret: Some(hex::encode(x25519_secret)),
+            contacts,
+            config: Some(self.config.clone()),
+        };
+        state::write_archive(path, &bundle, passphrase)
+    }
+
+    /// Restore an exported archive. `parts` selects what to restore: identity,
+    /// config and contacts by default.
+    async fn import_state(&mut self, path: &str, passphrase: Option<&str>, parts: &ImportParts) -> Result<()> {
+        secure_fs::check_private(Path::new(path), self.allow_insecure_permissions)?;
+        let bundle = state::read_archive(path, passphrase)?;
+        if bundle.client_id != self.id {
+            say!("import.other_identity", "⚠️ Archive belongs to {owner}, importing into {id}", owner = bundle.client_id, id = self.id);
+        }
+
+        if parts.config {
+            if let Some(mut config) = bundle.config {
+                config.compile_rules()?;
+                // Annotations made here since the export are kept; newer ones win
+                config.annotations.merge(std::mem::take(&mut self.config.annotations));
+                // Contact sync is numbered per device, so this one keeps its own
+                config.contact_sync = std::mem::take(&mut self.config.contact_sync);
+                self.config = config;
+                self.save_config();
+                say!("import.config", "✅ Restored config");
+            }
+        }
+
+        if parts.identity {
+            if let (Some(ed25519), Some(x25519)) = (&bundle.ed25519_secret, &bundle.x25519_secret) {
+                self.restore_identity(CryptoManager::from_secrets(&hex::decode(ed25519)?, &hex::decode(x25519)?)?).await?;
+            }
+        }
+
+        if parts.contacts {
+            let mut restored = 0;
+            for record in &bundle.contacts {
+                let key: [u8; 32] = match hex::decode(&record.x25519_public_key).ok().and_then(|k| k.try_into().ok()) {
+                    Some(key) => key,
+                    None => {
+                        say!("import.invalid_key", "⚠️ Skipping {contact}: invalid key", contact = record.client_id);
+                        continue;
+                    }
+                };
+                match self.servers.get_mut(&record.server) {
+                    Some(connection) => {
+                        connection.contacts.insert(record.client_id.clone(), Contact {
+                            key: X25519PublicKey::from(key),
+                            trusted_at: record.trusted_at,
+                        });
+                        self.persist_contact(&record.server, &record.client_id);
+                        restored += 1;
+                    }
+                    None => say!("import.not_connected", "⚠️ Skipping {contact}: not connected to server {server}", contact = record.client_id, server = record.server),
+                }
+            }
+            say!("import.contacts", "✅ Restored {count} contact(s)", count = restored);
+        }
+        Ok(())
+    }
+
+    fn backup_key(&self, path: &str, passphrase: Option<&str>) -> Result<()> {
+        let (ed25519_seed, x25519_secret) = self.crypto.export_secrets();
+        let backup = KeyBackup {
+            client_id: self.id.clone(),
+            created_at: Utc::now(),
+            ed25519_seed: hex::encode(ed25519_seed),
+            x25519_secret: hex::encode(x25519_secret),
+            bound_servers: self.config.bound_servers.clone(),
+        };
+        keybackup::write(path, &backup, passphrase)
+    }
+
+    /// Check a key backup and describe it without touching this identity.
+    fn inspect_key_backup(&self, path: &str, passphrase: Option<&str>) -> Result<()> {
+        let restored = keybackup::read(path, passphrase)?;
+        let crypto = CryptoManager::from_secrets(&hex::decode(&restored.backup.ed25519_seed)?, &hex::decode(&restored.backup.x25519_secret)?)?;
+        say!("key.inspect", "🔑 {path}: format v{version}, {wrapping}", path = path, version = restored.version,
+            wrapping = if restored.passphrase { tr!("key.wrapped", "passphrase-protected") } else { tr!("key.unwrapped", "no passphrase") });
+        say!("key.inspect_identity", "  Identity: {id} ({fingerprint}), backed up {date}", id = restored.backup.client_id,
+            fingerprint = crypto::fingerprint(crypto.get_ed25519_public_key().as_bytes()),
+            date = restored.backup.created_at.format("%Y-%m-%d %H:%M UTC"));
+        if !restored.backup.bound_servers.is_empty() {
+            say!("whoami.bound_servers", "  Bound servers:");
+        }
+        for binding in &restored.backup.bound_servers {
+            say!("whoami.bound_server", "    {fingerprint} ({addr}, since {date})",
+                fingerprint = binding.fingerprint, addr = binding.addr, date = binding.bound_at.format("%Y-%m-%d"));
+        }
+        Ok(())
+    }
+
+    /// Restore the keys from a backup, keeping the servers they were bound to.
+    async fn restore_key(&mut self, path: &str, passphrase: Option<&str>) -> Result<()> {
+        secure_fs::check_private(Path::new(path), self.allow_insecure_permissions)?;
+        let restored = keybackup::read(path, passphrase)?;
+        if restored.backup.client_id != self.id {
+            say!("import.other_identity", "⚠️ Archive belongs to {owner}, importing into {id}", owner = restored.backup.client_id, id = self.id);
+        }
+        let crypto = CryptoManager::from_secrets(&hex::decode(&restored.backup.ed25519_seed)?, &hex::decode(&restored.backup.x25519_secret)?)?;
+        for binding in restored.backup.bound_servers {
+            if !self.config.bound_servers.iter().any(|b| b.fingerprint == binding.fingerprint) {
+                self.config.bound_servers.push(binding);
+            }
+        }
+        self.save_config();
+        self.restore_identity(crypto).await
+    }
+
+    /// Switch to a restored identity, keep it for later runs and
+    /// re-register it everywhere.
+    async fn restore_identity(&mut self, crypto: CryptoManager) -> Result<()> {
+        crypto.save_to_file(&self.key_file)
+            .map_err(|e| anyhow!("Failed to write key file {}: {}", self.key_file.display(), e))?;
+        self.crypto = Arc::new(crypto);
+        self.servers.clear();
+        self.connect_all().await?;
+        say!("import.identity", "✅ Restored identity {fingerprint}", fingerprint = crypto::fingerprint(self.crypto.get_ed25519_public_key().as_bytes()));
+        Ok(())
+    }
+
+    /// Split this identity among trusted contacts, `threshold` of whom can restore it.
+    async fn setup_recovery(&mut self, threshold: u8, contacts: &[String]) -> Result<()> {
+        let total = u8::try_from(contacts.len()).map_err(|_| anyhow!("Too many recovery contacts"))?;
+        let targets = contacts.iter()
+            .map(|c| {
+                let (server, id) = self.resolve_target(c);
+                let key = self.server(server)?.contacts.get(id)
+                    .ok_or_else(|| anyhow!("Unknown contact {}; add their key first", c))?.key;
+                Ok((server.to_string(), id.to_string(), key))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let shares = recovery::make_shares(&self.crypto, &self.id, threshold, total)?;
+        for ((server, id, key), share) in targets.iter().zip(shares) {
+            let connection = self.server(server)?;
+            let body = RecoveryMessage::Share(share).to_bytes()?;
+            let encrypted = connection.crypto.encrypt_message(key, &String::from_utf8(body)?)?;
+            self.submit(server, id, &encrypted, &SendOptions::default()).await?;
+        }
+
+        self.config.recovery_contacts = contacts.to_vec();
+        self.save_config();
+        Ok(())
+    }
+
+    /// Ask share holders to send their shares back to this (new) identity.
+    async fn request_recovery(&self, holders: &[String]) -> Result<()> {
+        for holder in holders {
+            let (server, id) = self.resolve_target(holder);
+            let connection = self.server(server)?;
+            let request = RecoveryMessage::Request {
+                owner: self.id.clone(),
+                reply_key: hex::encode(connection.crypto.get_x25519_public_key().as_bytes()),
+            };
+            self.submit(server, id, &request.to_bytes()?, &SendOptions::default()).await?;
+            say!("recovery.asked", "🛟 Asked {holder} for your recovery share", holder = holder);
+        }
+        Ok(())
+    }
+
+    /// Send a held share back to its owner's new key after a request.
+    async fn release_share(&mut self, owner: &str) -> Result<()> {
+        let reply_key = self.recovery_requests.get(owner)
+            .ok_or_else(|| anyhow!("{} has not asked for their share", owner))?;
+        let sealed = self.config.held_recovery_shares.get(owner)
+            .ok_or_else(|| anyhow!("Not holding a share for {}", owner))?;
+        let share = self.crypto.open_local(sealed)?;
+        let key: [u8; 32] = hex::decode(reply_key)?.try_into().map_err(|_| anyhow!("Invalid reply key"))?;
+
+        let (server, id) = self.resolve_target(owner);
+        let encrypted = self.server(server)?.crypto.encrypt_message(&X25519PublicKey::from(key), &share)?;
+        self.submit(server, id, &encrypted, &SendOptions::default()).await?;
+        self.recovery_requests.remove(owner);
+        Ok(())
+    }
+
+    /// Pass the server's notices on as events, mark failed deliveries, and
+    /// return the real messages.
+    fn take_system_notices(&self, messages: Vec<(String, Message)>) -> Vec<(String, Message)> {
+        let (notices, rest): (Vec<_>, Vec<_>) = messages.into_iter().partition(|(_, m)| m.notice.is_some());
+        for (from, notice) in notices.into_iter().filter_map(|(from, m)| m.notice.map(|notice| (from, notice))) {
+            // Notices have no sender, so they come from "" or "@server"
+            let server = from.rsplit_once('@').map_or(DEFAULT_SERVER, |(_, server)| server).to_string();
+            if notice.notice_type == notice_type::DELIVERY_FAILED {
+                if let Err(e) = self.store.set_state(notice.field("message_id"), MessageState::Failed) {
+                    say!("history.update_failed", "❌ Failed to update history: {error}", error = e);
+                }
+            }
+            let _ = self.events.0.send(ClientEvent::Notice { server, notice });
+        }
+        rest
+    }
+
+    /// Cancel a sent message if the recipient hasn't fetched it yet; otherwise
+    /// ask the recipient to retract it. Returns what was done.
+    async fn cancel_message(&self, message_id: &str) -> Result<MessageState> {
+        let record = self.store.find_history(message_id)?
+            .filter(|record| record.outgoing)
+            .ok_or_else(|| anyhow!("No sent message with id {}", message_id))?;
+        if let Some(state) = record.state {
+            return Err(anyhow!("Message is already {}", format!("{:?}", state).to_lowercase()));
+        }
+
+        let status = match self.message_ref_request(&record.server, message_id, false).await? {
+            DeliveryStatus::Stored => self.message_ref_request(&record.server, message_id, true).await?,
+            status => status,
+        };
+        let state = match status {
+            DeliveryStatus::Cancelled => MessageState::Cancelled,
+            DeliveryStatus::Delivered => {
+                let retraction = ControlMessage::Retraction { message_id: message_id.to_string() };
+                self.send_control(&record.server, &record.peer, &retraction).await?;
+                MessageState::Retracted
+            }
+            DeliveryStatus::Superseded { by } => {
+                self.store.set_state(message_id, MessageState::Superseded)?;
+                return Err(anyhow!("Message {} was already replaced by {}", message_id, by));
+            }
+            DeliveryStatus::Stored | DeliveryStatus::Unknown => {
+                return Err(anyhow!("The server no longer has message {}; it may have expired", message_id));
+            }
+        };
+        self.store.set_state(message_id, state)?;
+        Ok(state)
+    }
+
+    /// Send a corrected version of one of our messages. The server drops the
+    /// original if the recipient hasn't fetched it; otherwise they get both,
+    /// the new one marked as an edit.
+    async fn edit_message(&mut self, message_id: &str, text: &str) -> Result<String> {
+        let record = self.store.find_history(message_id)?
+            .filter(|record| record.outgoing)
+            .ok_or_else(|| anyhow!("No sent message with id {}", message_id))?;
+        if let Some(state) = record.state {
+            return Err(anyhow!("Message is already {}", format!("{:?}", state).to_lowercase()));
+        }
+        let target = self.display_id(&record.server, &record.peer);
+        self.send_numbered(&target, text, SendOptions { supersedes: Some(message_id.to_string()), ..SendOptions::default() }).await
+    }
+
+    /// Set or remove annotation keys on a message in our history, and send
+    /// the change to our other devices through our own mailbox on the
+    /// message's server.
+    async fn annotate(&mut self, message_id: &str, values: BTreeMap<String, Option<String>>) -> Result<()> {
+        let record = self.store.find_history(message_id)?
+            .ok_or_else(|| anyhow!("No message with id {} in history", message_id))?;
+        let annotation = ControlMessage::Annotation { message_id: message_id.to_string(), values: values.clone() };
+        let hlc = self.send_to_self(&record.server, &annotation).await?;
+        if self.config.annotations.apply(message_id, &values, hlc) {
+            self.save_config();
+        }
+        Ok(())
+    }
+
+    /// Send a control message to our other devices through our own mailbox
+    /// on `server`. Returns the clock value the server gave it.
+    async fn send_to_self(&self, server: &str, control: &ControlMessage) -> Result<Hlc> {
+        let connection = self.server(server)?;
+        let own_key = connection.crypto.get_x25519_public_key();
+        let encrypted = connection.crypto.encrypt_message(&own_key, &serde_json::to_string(control)?)?;
+        match self.submit(server, &self.id, &encrypted, &SendOptions::default()).await? {
+            ServerResponse::MessageSent { hlc, .. } => Ok(hlc.unwrap_or_else(|| Hlc::from_timestamp(Utc::now()))),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    /// Send a contact as it is now to our other devices, and stamp it with
+    /// the clock value the server gave the message. It is stamped even if
+    /// the send fails, so `sync now` carries it later.
+    async fn sync_contact(&mut self, server: &str, contact_id: &str) -> Result<()> {
+        let contact = self.server(server)?.contacts.get(contact_id)
+            .ok_or_else(|| anyhow!("Unknown contact {}", contact_id))?;
+        let change = ContactChange {
+            contact_id: contact_id.to_string(),
+            key: hex::encode(contact.key.as_bytes()),
+            trusted: contact.trusted_at.is_some(),
+            stamp: None,
+            replaces: self.config.contact_sync.replaces(server, contact_id),
+        };
+        let (device, number) = self.config.contact_sync.next_message();
+        let sent = self.send_to_self(server, &ControlMessage::ContactSync { device: device.clone(), number, changes: vec![change] }).await;
+        let hlc = sent.as_ref().map_or_else(|_| Hlc::from_timestamp(Utc::now()), |hlc| *hlc);
+        self.config.contact_sync.set(server, contact_id, Stamp { hlc, device });
+        self.save_config();
+        sent.map(|_| ())
+    }
+
+    /// Apply contacts another of our devices sent, newest change winning,
+    /// and warn of conflicts instead of applying them.
+    fn apply_contact_sync(&mut self, server: &str, device: &str, hlc: Hlc, changes: Vec<ContactChange>) {
+        for change in changes {
+            let Some(key) = x25519_from_hex(&change.key) else { continue };
+            let Some(connection) = self.servers.get_mut(server) else { return };
+            let stamp = change.stamp.clone().unwrap_or_else(|| Stamp { hlc, device: device.to_string() });
+            let local = connection.contacts.get(&change.contact_id);
+            let local_key = local.map(|contact| hex::encode(contact.key.as_bytes()));
+            let local_trusted_at = local.and_then(|contact| contact.trusted_at);
+            let view = local_key.as_deref().map(|key| (key, local_trusted_at.is_some()));
+            match self.config.contact_sync.judge(server, &change, &stamp, view) {
+                Verdict::Stale => {}
+                Verdict::Conflict => {
+                    out!("{}", tr!("sync.conflict", "⚠️ Another device set {contact} to key {theirs}, but {ours} is trusted here; 'add' and 'trust' the right key to settle it",
+                        contact = self.display_id(server, &change.contact_id),
+                        ours = crypto::fingerprint(&hex::decode(local_key.unwrap_or_default()).unwrap_or_default()),
+                        theirs = crypto::fingerprint(key.as_bytes())).red().bold());
+                    self.config.contact_sync.record_conflict(server, &change.contact_id, stamp);
+                }
+                Verdict::Apply => {
+                    // Trusted here first keeps its date; otherwise it dates from the change
+                    let trusted_at = match local_trusted_at {
+                        Some(trusted_at) if change.trusted && local_key.as_deref() == Some(change.key.as_str()) => Some(trusted_at),
+                        _ => change.trusted.then(|| DateTime::from_timestamp_millis(stamp.hlc.wall_ms).unwrap_or_else(Utc::now)),
+                    };
+                    connection.contacts.insert(change.contact_id.clone(), Contact { key, trusted_at });
+                    self.persist_contact(server, &change.contact_id);
+                    info!("👤 Synced contact {} from another device", change.contact_id);
+                    self.config.contact_sync.set(server, &change.contact_id, stamp);
+                }
+            }
+        }
+    }
+
+    /// `labels <contact>`: the contact's annotated messages, newest first.
+    fn show_labels(&self, server: &str, peer: &str) -> Result<()> {
+        let now = Utc::now();
+        let mut records: Vec<HistoryRecord> = self.store.all_history()?.into_iter()
+            .filter(|record| record.server == server && record.peer == peer && !record.expired(now))
+            .filter(|record| {
+                let (starred, labels) = self.config.annotations.labels(&record.message_id);
+                starred || !labels.is_empty()
+            })
+            .collect();
+        if records.is_empty() {
+            say!("labels.none", "🏷️ No labelled messages with {contact}", contact = self.display_id(server, peer));
+            return Ok(());
+        }
+        records.sort_by_key(|record| std::cmp::Reverse(record.timestamp));
+        for record in records {
+            let (starred, mut labels) = self.config.annotations.labels(&record.message_id);
+            if starred {
+                labels.insert(0, annotations::STARRED.to_string());
+            }
+            let text = self.history_text(&record).unwrap_or_else(|| tr!("labels.unreadable", "(unreadable)"));
+            out!("  {} {} [{}] {}", record.timestamp.format("%Y-%m-%d %H:%M"), record.message_id,
+                labels.join(", "), text.lines().next().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// Ask the server for a sent message's status, or to cancel it.
+    async fn message_ref_request(&self, server: &str, message_id: &str, cancel: bool) -> Result<DeliveryStatus> {
+        let connection = self.server(server)?;
+        let payload = message_ref_payload(&self.id, message_id);
+        let (sender_id, message_id) = (self.id.clone(), message_id.to_string());
+        let command = if cancel {
+            let signature = hex::encode(connection.crypto.sign_with_context(crypto::context::CANCEL, &payload).to_bytes());
+            ServerCommand::CancelMessage { sender_id, message_id, signature }
+        } else {
+            let signature = hex::encode(connection.crypto.sign_with_context(crypto::context::MESSAGE_STATUS, &payload).to_bytes());
+            ServerCommand::GetMessageStatus { sender_id, message_id, signature }
+        };
+        match connection.request(&command).await? {
+            ServerResponse::MessageStatus { status, .. } => Ok(status),
+            ServerResponse::Error { message, .. } => Err(anyhow!("Server error: {}", message)),
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    }
+
+    async fn send_control(&self, server: &str, recipient: &str, control: &ControlMessage) -> Result<()> {
+        let key = self.server(server)?.contacts.get(recipient)
+            .ok_or_else(|| anyhow!("Unknown contact {}", recipient))?.key;
+        let encrypted = self.server(server)?.crypto.encrypt_message(&key, &serde_json::to_string(control)?)?;
+        self.submit(server, recipient, &encrypted, &SendOptions::default()).await?;
+        Ok(())
+    }
+
+    /// Apply control messages from contacts and return the rest.
+    fn take_control_messages(&mut self, messages: Vec<(String, Message)>) -> Vec<(String, Message)> {
+        let mut rest = Vec::new();
+        for (sender, message) in messages {
+            let control = self.decrypt_from(&sender, &message)
+                .and_then(|plaintext| serde_json::from_str::<ControlMessage>(&plaintext).ok());
+            match control {
+                Some(ControlMessage::Retraction { message_id }) => {
+                    let (server, sender_id) = self.resolve_target(&sender);
+                    // Only the original sender may retract a message
+                    let ours = self.store.find_history(&message_id).ok().flatten()
+                        .filter(|r| !r.outgoing && r.server == server && r.peer == sender_id);
+                    match ours {
+                        Some(_) => {
+                            say!("receive.retracted", "↩️ {sender} retracted message {id}", sender = sender, id = message_id);
+                            if let Err(e) = self.store.set_state(&message_id, MessageState::Retracted) {
+                                say!("history.update_failed", "❌ Failed to update history: {error}", error = e);
+                            }
+                        }
+                        None => say!("receive.retract_unknown", "⚠️ {sender} tried to retract unknown message {id}", sender = sender, id = message_id),
+                    }
+                }
+                Some(ControlMessage::Annotation { message_id, values }) => {
+                    // Only our own devices may annotate our messages
+                    if self.resolve_target(&sender).1 != self.id {
+                        say!("receive.annotation_foreign", "⚠️ Ignored an annotation from {sender}", sender = sender);
+                    } else if self.config.annotations.apply(&message_id, &values, message.hlc) {
+                        self.save_config();
+                    }
+                }
+                Some(ControlMessage::ContactSync { device, number, changes }) => {
+                    let (server, sender_id) = self.resolve_target(&sender);
+                    let server = server.to_string();
+                    // Only our own devices may change our contacts
+                    if sender_id != self.id {
+                        say!("receive.contact_sync_foreign", "⚠️ Ignored a contact sync from {sender}", sender = sender);
+                    } else if self.config.contact_sync.is_new(&device, number) {
+                        self.apply_contact_sync(&server, &device, message.hlc, changes);
+                        self.save_config();
+                    }
+                }
+                Some(ControlMessage::ConversationKeys { keys }) => match self.store_delegated_keys(&sender, keys) {
+                    Ok(count) => say!("delegate.keys_received", "🛂 {owner} shared {count} conversation(s) with you; 'delegate fetch {owner}' reads their mailbox",
+                        owner = sender, count = count),
+                    Err(e) => say!("delegate.keys_failed", "❌ Failed to keep the conversation keys {owner} shared: {error}", owner = sender, error = e),
+                },
+                None => rest.push((sender, message)),
+            }
+        }
+        rest
+    }
+
+    /// Decrypt a message with the sender's contact key or, failing that, the
+    /// key in its envelope, which `receive_all` has checked or removed.
+    fn decrypt_from(&self, sender: &str, message: &Message) -> Option<String> {
+        let content = hex::decode(&message.content).ok()?;
+        let (server, sender_id) = self.resolve_target(sender);
+        let connection = self.servers.get(server)?;
+        self.sender_keys(server, sender_id, message).iter()
+            .find_map(|key| connection.crypto.decrypt_message(key, &content).ok())
+    }
+
+    /// Open a received message for display, or say why it can't be.
+    fn open_received(&self, sender: &str, message: &Message) -> Result<Payload> {
+        let content = hex::decode(&message.content).map_err(|_| anyhow!("the content isn't hex"))?;
+        let (server, sender_id) = self.resolve_target(sender);
+        let connection = self.server(server)?;
+        let keys = self.sender_keys(server, sender_id, message);
+        if keys.is_empty() {
+            return Err(anyhow!("no key for {}", sender));
+        }
+        keys.iter()
+            .find_map(|key| connection.crypto.decrypt_bytes(key, &content).ok())
+            .map(|body| Payload::decode(&body))
+            .ok_or_else(|| anyhow!("it doesn't open with {}'s key", sender))
+    }
+
+    /// The keys a received message may be encrypted with: the contact's,
+    /// then the one in its envelope, then a guest's own.
+    fn sender_keys(&self, server: &str, sender_id: &str, message: &Message) -> Vec<X25519PublicKey> {
+        let contact_key = self.servers.get(server)
+            .and_then(|connection| connection.contacts.get(sender_id))
+            .map(|contact| contact.key);
+        let envelope_key = message.sender_key.as_ref().and_then(|key| x25519_from_hex(&key.x25519_public_key));
+        let guest_key = message.guest.as_ref().and_then(|guest| guest.ephemeral_key.as_deref()).and_then(x25519_from_hex);
+        contact_key.into_iter().chain(envelope_key).chain(guest_key).collect()
+    }
+
+    /// Check the server's signed receipt for a message against its pinned
+    /// key. Servers from before receipts send none, which passes.
+    fn check_receipt(&self, server: &str, message_id: &str, sender_id: &str, recipient_id: &str, received_at: DateTime<Utc>, signature: Option<&str>) -> Result<()> {
+        let Some(signature) = signature else { return Ok(()) };
+        let connection = self.server(server)?;
+        let signature = Signature::from_bytes(&hex::decode(signature)?)?;
+        let payload = receipt_payload(message_id, sender_id, recipient_id, received_at);
+        connection.crypto.verify_with_context(crypto::context::RECEIPT, &payload, &signature, &connection.server_pubkey)
+            .map_err(|_| anyhow!("it is not signed by the pinned server key"))
+    }
+
+    /// Check the key in a message's envelope before anything decrypts with
+    /// it. Unless it is the contact's key already, the sender's registered
+    /// identity key, from the signed key directory, must have signed both
+    /// the message and the key.
+    async fn check_sender_key(&self, server: &str, message: &Message) -> Result<()> {
+        let Some(sender_key) = &message.sender_key else { return Ok(()) };
+        let connection = self.server(server)?;
+        if connection.contacts.get(&message.sender_id).is_some_and(|c| hex::encode(c.key.as_bytes()) == sender_key.x25519_public_key) {
+            return Ok(());
+        }
+        x25519_from_hex(&sender_key.x25519_public_key).ok_or_else(|| anyhow!("the key is malformed"))?;
+
+        let (public_key, ..) = self.fetch_key_history(server, &message.sender_id).await?;
+        let public_key = public_key.ok_or_else(|| anyhow!("{} has no registered identity key", message.sender_id))?;
+        let public_key = PublicKey::from_bytes(&hex::decode(public_key)?)?;
+        let signature = Signature::from_bytes(&hex::decode(message.signature.as_deref().unwrap_or_default())?)?;
+        let sent_at = message.sent_at.ok_or_else(|| anyhow!("the message has no signed send time"))?;
+        connection.crypto.verify_with_context(crypto::context::SEND, &send_payload(&message.id, &message.recipient_id, &hex::decode(&message.content)?, sent_at), &signature, &public_key)
+            .map_err(|_| anyhow!("the message is not signed by {}'s identity key", message.sender_id))?;
+        let key_signature = Signature::from_bytes(&hex::decode(&sender_key.signature)?)?;
+        connection.crypto.verify_with_context(crypto::context::SENDER_KEY,
+            &sender_key_payload(&message.sender_id, &sender_key.x25519_public_key), &key_signature, &public_key)
+            .map_err(|_| anyhow!("the key is not signed by {}'s identity key", message.sender_id))
+    }
+
+    /// Check a received message's signature against the identity key its
+    /// signer (the sender, or their delegate) had when the server accepted
+    /// it, from the signed key directory. Each signer's key history is
+    /// fetched once per batch and kept in `histories`.
+    async fn check_signature(&self, server: &str, message: &Message, histories: &mut KeyHistories) -> SignatureCheck {
+        // Guests and the server's notices sign nothing
+        if message.guest.is_some() || message.notice.is_some() || message.signature.is_none() {
+            return SignatureCheck::Unverified;
+        }
+        let signer = message.signer();
+        if !histories.contains_key(signer) {
+            let history = self.fetch_key_history(server, signer).await
+                .map(|(public_key, _, _, entries)| (public_key, entries));
+            histories.insert(signer.to_string(), history.ok());
+        }
+        let Some((public_key, entries)) = &histories[signer] else { return SignatureCheck::Unverified };
+        if public_key.is_none() && !entries.iter().any(|entry| entry.client_id == signer) {
+            return SignatureCheck::Unverified;
+        }
+        match integrity::verify_message(message, entries, public_key.as_deref()) {
+            Ok(()) => SignatureCheck::Verified,
+            Err(_) => SignatureCheck::Invalid,
+        }
+    }
+
+    /// Pull social-recovery traffic out of a batch of received messages and act
+    /// on it; everything else is returned for normal display.
+    async fn take_recovery_messages(&mut self, messages: Vec<(String, Message)>) -> Vec<(String, Message)> {
+        let mut rest = Vec::new();
+        for (sender, message) in messages {
+            match self.open_recovery_message(&sender, &message) {
+                Some(recovery) => {
+                    if let Err(e) = self.handle_recovery_message(&sender, recovery).await {
+                        say!("recovery.message_failed", "❌ Recovery message from {sender}: {error}", sender = sender, error = e);
+                    }
+                }
+                None => rest.push((sender, message)),
+            }
+        }
+        rest
+    }
+
+    fn open_recovery_message(&self, sender: &str, message: &Message) -> Option<RecoveryMessage> {
+        let decrypted = self.decrypt_from(sender, message)
+            .and_then(|plaintext| RecoveryMessage::parse(plaintext.as_bytes()));
+        match decrypted {
+            Some(recovery) => Some(recovery),
+            // Requests travel unencrypted
+            None => RecoveryMessage::parse(&hex::decode(&message.content).ok()?)
+                .filter(|r| matches!(r, RecoveryMessage::Request { .. })),
+        }
+    }
+
+    async fn handle_recovery_message(&mut self, sender: &str, message: RecoveryMessage) -> Result<()> {
+        let (_, sender_id) = self.resolve_target(sender);
+        match message {
+            RecoveryMessage::Share(share) if share.owner == self.id => {
+                if !self.returned_shares.iter().any(|s| s.index == share.index) {
+                    self.returned_shares.push(share.clone());
+                }
+                say!("recovery.share_returned", "🛟 {sender} returned share {index} ({count} of {threshold} needed)",
+                    sender = sender, index = share.index, count = self.returned_shares.len(), threshold = share.threshold);
+                if self.returned_shares.len() >= share.threshold as usize {
+                    let crypto = recovery::reassemble(&self.returned_shares)?;
+                    self.returned_shares.clear();
+                    self.restore_identity(crypto).await?;
+                }
+            }
+            RecoveryMessage::Share(share) => {
+                if share.owner != sender_id {
+                    return Err(anyhow!("share claims to belong to {}", share.owner));
+                }
+                let sealed = crypto::seal(&self.crypto.local_store_key(), &String::from_utf8(RecoveryMessage::Share(share.clone()).to_bytes()?)?)?;
+                self.config.held_recovery_shares.insert(sender.to_string(), sealed);
+                self.save_config();
+                say!("recovery.holding", "🛟 Holding recovery share {index} of {total} for {owner} ({threshold} needed to restore)",
+                    index = share.index, total = share.total, owner = sender, threshold = share.threshold);
+            }
+            RecoveryMessage::Request { owner, reply_key } => {
+                if owner != sender_id {
+                    return Err(anyhow!("request claims to be from {}", owner));
+                }
+                if !self.config.held_recovery_shares.contains_key(sender) {
+                    return Ok(());
+                }
+                let fingerprint = crypto::fingerprint(&hex::decode(&reply_key)?);
+                self.recovery_requests.insert(sender.to_string(), reply_key);
+                out!("{}", tr!("recovery.requested", "🛟 {owner} asks for their recovery share, to be sent to key {fingerprint}",
+                    owner = sender, fingerprint = fingerprint).yellow().bold());
+                out!("{}", tr!("recovery.requested_warning", "   Anyone can send this request. Confirm the fingerprint with them in person or by phone,").yellow());
+                out!("{}", tr!("recovery.requested_hint", "   then run 'recovery release {owner}'.", owner = sender).yellow());
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up the X25519 key `client_id` published on `server` and keep it
+    /// as a contact.
+    async fn fetch_contact_key(&mut self, server: &str, client_id: &str) -> Result<()> {
+        let published = self.published_key(server, client_id).await?;
+        let key = x25519_from_hex(&published.x25519_public_key).ok_or_else(|| anyhow!("the key is malformed"))?;
+        self.add_contact(server, client_id.to_string(), key)?;
+        say!("contacts.fetched", "🔑 Added {contact} with the key they published ({fingerprint})",
+            contact = self.display_id(server, client_id), fingerprint = crypto::fingerprint(key.as_bytes()));
+        Ok(())
+    }
+
+    /// The X25519 key `client_id` published on `server`. It must be signed by
+    /// the identity key the server's signed directory holds for them.
+    async fn published_key(&self, server: &str, client_id: &str) -> Result<SenderKey> {
+        let connection = self.server(server)?;
+        let command = ServerCommand::GetPublicKey { client_id: client_id.to_string() };
+        let (ed25519, x25519) = match connection.request(&command).await? {
+            ServerResponse::PublicKeys { ed25519, x25519, .. } => (ed25519, x25519),
+            ServerResponse::Error { message, .. } => return Err(anyhow!("Server error: {}", message)),
+            _ => return Err(anyhow!("Unexpected response from server")),
+        };
+        let x25519 = x25519.ok_or_else(|| anyhow!("{} hasn't published a key", client_id))?;
+
+        let (public_key, ..) = self.fetch_key_history(server, client_id).await?;
+        if public_key.as_deref() != Some(ed25519.as_str()) {
+            return Err(anyhow!("the server's key directory disagrees about {}'s identity key", client_id));
+        }
+        let public_key = PublicKey::from_bytes(&hex::decode(&ed25519)?)?;
+        let signature = Signature::from_bytes(&hex::decode(&x25519.signature)?)?;
+        self.crypto.verify_with_context(crypto::context::SENDER_KEY, &sender_key_payload(client_id, &x25519.x25519_public_key), &signature, &public_key)
+            .map_err(|_| anyhow!("the key is not signed by {}'s identity key", client_id))?;
+        x25519_from_hex(&x25519.x25519_public_key).ok_or_else(|| anyhow!("the key is malformed"))?;
+        Ok(x25519)
+    }
+
+    fn add_contact(&mut self, server: &str, contact_id: String, public_key: X25519PublicKey) -> Result<()> {
+        self.servers.get_mut(server)
+            .ok_or_else(|| anyhow!("Not connected to server {}", server))?
+            .contacts.insert(contact_id.clone(), Contact { key: public_key, trusted_at: None });
+        self.persist_contact(server, &contact_id);
+        info!("👤 Added contact with public key: {}", hex::encode(public_key.as_bytes()).yellow());
+        Ok(())
+    }
+
+    /// Trust a contact's current key from now on. Returns its fingerprint.
+    fn trust(&mut self, server: &str, contact_id: &str) -> Result<String> {
+        let contact = self.servers.get_mut(server).and_then(|c| c.contacts.get_mut(contact_id))
+            .ok_or_else(|| anyhow!("Unknown contact {}", contact_id))?;
+        contact.trusted_at = Some(Utc::now());
+        let fingerprint = crypto::fingerprint(contact.key.as_bytes());
+        self.persist_contact(server, contact_id);
+        Ok(fingerprint)
+    }
+
+    /// `sync_contact`, saying so if our other devices couldn't be told.
+    async fn sync_contact_or_warn(&mut self, server: &str, contact_id: &str) {
+        if let Err(e) = self.sync_contact(server, contact_id).await {
+            say!("sync.failed", "⚠️ Couldn't sync {contact} to your other devices: {error}; 'sync now' tries again",
+                contact = self.display_id(server, contact_id), error = e);
+        }
+    }
+
+    fn load_contacts(&self, server: &str) -> HashMap<String, Contact> {
+        let stored = match self.store.contacts(server) {
+            Ok(stored) => stored,
+            Err(e) => {
+                say!("contacts.load_failed", "⚠️ Failed to load contacts for {server}: {error}", server = server, error = e);
+                return HashMap::new();
+            }
+        };
+        stored.into_iter()
+            .filter_map(|c| {
+                let key: [u8; 32] = hex::decode(&c.x25519_public_key).ok()?.try_into().ok()?;
+                Some((c.client_id, Contact { key: X25519PublicKey::from(key), trusted_at: c.trusted_at }))
+            })
+            .collect()
+    }
+
+    fn persist_contact(&self, server: &str, contact_id: &str) {
+        let Some(contact) = self.servers.get(server).and_then(|c| c.contacts.get(contact_id)) else {
+            return;
+        };
+        let stored = StoredContact {
+            server: server.to_string(),
+            client_id: contact_id.to_string(),
+            x25519_public_key: hex::encode(contact.key.as_bytes()),
+            trusted_at: contact.trusted_at,
+        };
+        if let Err(e) = self.store.save_contact(&stored) {
+            say!("contacts.save_failed", "❌ Failed to save contact: {error}", error = e);
+        }
+    }
+
+    /// Seal a message body with the identity's store key into a history record.
+    fn seal_record(&self, server: &str, peer: &str, outgoing: bool, message_id: &str, timestamp: DateTime<Utc>, body: &str) -> Result<HistoryRecord> {
+        crypto::seal(&self.crypto.local_store_key(), body).map(|sealed_body| HistoryRecord {
+            server: server.to_string(),
+            peer: peer.to_string(),
+            outgoing,
+            message_id: message_id.to_string(),
+            timestamp,
+            sealed_body,
+            state: None,
+            sender_key: None,
+            expires_at: None,
+            signature: None,
+        })
+    }
+
+    /// Record a fetched message, keeping its checked envelope key so it can
+    /// be decrypted later even if the sender never becomes a contact.
+    fn record_received(&self, server: &str, message: &Message, signature: SignatureCheck) {
+        if let Some(notice) = &message.notice {
+            self.save_history(self.seal_record(server, SYSTEM_PEER, false, &message.id, message.timestamp, &describe_notice(notice, self.config.control_display)));
+            return;
+        }
+        let record = crypto::seal(&self.crypto.local_store_key(), &message.content).map(|sealed_body| HistoryRecord {
+            server: server.to_string(),
+            peer: message.sender_id.clone(),
+            outgoing: false,
+            message_id: message.id.clone(),
+            timestamp: message.timestamp,
+            sealed_body,
+            state: None,
+            // Guests have no contact key, so keep the one their message came with
+            sender_key: message.sender_key.as_ref().map(|key| key.x25519_public_key.clone())
+                .or_else(|| message.guest.as_ref().and_then(|guest| guest.ephemeral_key.clone())),
+            expires_at: message.expires_at,
+            signature: Some(signature),
+        });
+        self.save_history(record);
+    }
+
+    /// Whether a message with this id was received and recorded before.
+    fn already_received(&self, message_id: &str) -> bool {
+        matches!(self.store.find_history(message_id), Ok(Some(record)) if !record.outgoing)
+    }
+
+    fn save_history(&self, record: Result<HistoryRecord>) {
+        let result = record.and_then(|record| {
+            self.store.append_history(&record)?;
+            if self.config.search_index {
+                self.index_record(&record)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            say!("history.record_failed", "❌ Failed to record history: {error}", error = e);
+        }
+    }
+
+    /// The readable text of a history record. Outgoing bodies are stored as
+    /// plaintext; incoming ones need the sender's key to decrypt.
+    fn history_text(&self, record: &HistoryRecord) -> Option<String> {
+        self.history_entry(record).map(|(text, _)| text)
+    }
+
+    /// A history record's text and, for a received group message, the group.
+    fn history_entry(&self, record: &HistoryRecord) -> Option<(String, Option<String>)> {
+        let body = self.crypto.open_local(&record.sealed_body).ok()?;
+        if record.outgoing || record.peer == SYSTEM_PEER {
+            return Some((body, None));
+        }
+        let connection = self.servers.get(&record.server)?;
+        let content = hex::decode(body).ok()?;
+        let contact_key = connection.contacts.get(&record.peer).map(|contact| contact.key);
+        let envelope_key = record.sender_key.as_deref().and_then(x25519_from_hex);
+        let decrypted = contact_key.into_iter().chain(envelope_key)
+            .find_map(|key| connection.crypto.decrypt_bytes(&key, &content).ok());
+        if decrypted.is_none() {
+            telemetry::decrypt_failed();
+        }
+        decrypted.map(|body| {
+            let payload = Payload::decode(&body);
+            (payload.display(), payload.group().map(|group| self.display_id(&record.server, group)))
+        })
+    }
+
+    /// Add a record's words to the search index; skipped if it can't be read.
+    fn index_record(&self, record: &HistoryRecord) -> Result<bool> {
+        let Some(text) = self.history_text(record) else { return Ok(false) };
+        let key = self.crypto.local_store_key();
+        let tokens: Vec<String> = search_words(&text).iter().map(|word| crypto::search_token(&key, word)).collect();
+        self.store.index_message(&record.message_id, &tokens)?;
+        Ok(true)
+    }
+
+    /// Re-index all history; returns how many messages were indexed and skipped.
+    fn rebuild_search_index(&self) -> Result<(usize, usize)> {
+        self.store.clear_index()?;
+        let (mut indexed, mut skipped) = (0, 0);
+        for record in self.store.all_history()? {
+            if self.index_record(&record)? {
+                indexed += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+        Ok((indexed, skipped))
+    }
+
+    /// Newest history records containing every word of the query, with their text.
+    fn search_history(&self, query: &HistorySearch) -> Result<Vec<(HistoryRecord, String)>> {
+        let candidates = if self.config.search_index && !query.words.is_empty() {
+            // Entries indexed before the key came from HKDF match under the
+            // legacy one, until `search --rebuild`
+            let mut ids = BTreeSet::new();
+            for key in [self.crypto.local_store_key(), self.crypto.legacy_local_store_key()] {
+                let tokens: Vec<String> = query.words.iter().map(|word| crypto::search_token(&key, word)).collect();
+                ids.extend(self.store.search_index(&tokens)?);
+            }
+            ids.iter()
+                .filter_map(|id| self.store.find_history(id).transpose())
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            self.store.all_history()?
+        };
+        let from = query.from.as_deref().map(|from| self.resolve_target(from));
+
+        let now = Utc::now();
+        let mut hits: Vec<_> = candidates.into_iter()
+            .filter(|r| !r.expired(now))
+            .filter(|r| query.since.is_none_or(|since| r.timestamp >= since))
+            .filter(|r| from.is_none_or(|(server, id)| !r.outgoing && r.server == server && r.peer == id))
+            .filter_map(|r| self.history_text(&r).map(|text| (r, text)))
+            .filter(|(_, text)| {
+                let words = search_words(text);
+                query.words.iter().all(|word| words.contains(word))
+            })
+            .collect();
+        hits.sort_by_key(|(r, _)| std::cmp::Reverse(r.timestamp));
+        hits.truncate(SEARCH_RESULTS);
+        Ok(hits)
+    }
+
+    /// Expand a saved template with `name=value` arguments.
+    fn expand_template(&self, name: &str, values: &[&str]) -> Result<String> {
+        let body = self.config.templates.get(name)
+            .ok_or_else(|| anyhow!("No template named {}", name))?;
+        template::expand(body, &template::parse_values(values)?)
+    }
+
+    fn set_search_index(&mut self, enabled: bool) -> Result<()> {
+        self.config.search_index = enabled;
+        self.save_config();
+        if enabled {
+            let (indexed, skipped) = self.rebuild_search_index()?;
+            if skipped > 0 {
+                say!("search.indexed_skipped", "🔎 Indexed {indexed} message(s), skipped {skipped} from unknown contacts", indexed = indexed, skipped = skipped);
+            } else {
+                say!("search.indexed_all", "🔎 Indexed {indexed} message(s)", indexed = indexed);
+            }
+        } else {
+            self.store.clear_index()?;
+        }
+        Ok(())
+    }
+
+    fn show_history(&self, server: &str, peer: &str, limit: usize) -> Result<()> {
+        let (views, unreadable) = self.history_views(server, peer, limit)?;
+        if views.is_empty() && unreadable == 0 {
+            say!("history.none", "📭 No history with {peer}", peer = self.display_id(server, peer));
+        }
+        output::print(output::Level::Data, self.renderer.render(&views).trim_end_matches('\n'));
+        if unreadable > 0 {
+            say!("history.other_identity", "🔒 {count} message(s) were stored under a different identity", count = unreadable);
+        }
+        Ok(())
+    }
+
+    /// The last `limit` messages with `peer`, ready to render, and how many
+    /// more were stored under another identity and can't be read.
+    fn history_views(&self, server: &str, peer: &str, limit: usize) -> Result<(Vec<MessageView>, usize)> {
+        let mut unreadable = 0;
+        let mut views = Vec::new();
+        let now = Utc::now();
+        for record in self.store.history(server, peer, limit)? {
+            if record.expired(now) {
+                continue;
+            }
+            if self.crypto.open_local(&record.sealed_body).is_err() {
+                unreadable += 1;
+                continue;
+            }
+            let (body, group) = self.history_entry(&record)
+                .unwrap_or_else(|| (tr!("message.undecryptable", "⚠️ [can't decrypt: {error}]", error = tr!("history.no_key", "no key opens it")), None));
+            let signature = (!record.outgoing).then(|| record.signature.unwrap_or(SignatureCheck::Unverified));
+            let body = match signature {
+                Some(signature) => self.withhold_invalid(signature, body),
+                None => body,
+            };
+            let (starred, labels) = self.config.annotations.labels(&record.message_id);
+            views.push(MessageView {
+                sender: if record.outgoing { self.id.clone() } else { self.display_id(server, peer) },
+                group,
+                timestamp: record.timestamp,
+                body: match record.state {
+                    Some(MessageState::Cancelled) => tr!("history.cancelled", "{body} (cancelled)", body = body),
+                    Some(MessageState::Retracted) => tr!("history.retracted", "{body} (retracted)", body = body),
+                    Some(MessageState::Failed) => tr!("history.failed", "{body} (not delivered)", body = body),
+                    Some(MessageState::Superseded) => tr!("history.superseded", "{body} (replaced before delivery)", body = body),
+                    None => body,
+                },
+                signature,
+                highlighted: false,
+                starred,
+                labels,
+                expires_in: record.expires_at.map(|expires_at| expires_at - now),
+                claimed_at: None,
+            });
+        }
+        Ok((views, unreadable))
+    }
+
+    /// Drop the annotations of disappearing messages the janitor has scrubbed.
+    fn forget_scrubbed(&mut self) {
+        let mut forgotten = false;
+        while let Ok(ids) = self.scrubbed.1.try_recv() {
+            for id in ids {
+                forgotten |= self.config.annotations.messages.remove(&id).is_some();
+            }
+        }
+        if forgotten {
+            self.save_config();
+        }
+    }
+
+    fn set_local_store(&mut self, kind: LocalStoreKind) -> Result<()> {
+        self.store = Arc::from(store::open(kind, &self.paths, &self.id)?);
+        store::spawn_janitor(&self.store, self.scrubbed.0.clone());
+        self.config.local_store = kind;
+        self.save_config();
+        Ok(())
+    }
+
+    /// A body whose signature doesn't verify is only shown with `--show-invalid`.
+    fn withhold_invalid(&self, signature: SignatureCheck, body: String) -> String {
+        if signature == SignatureCheck::Invalid && !self.show_invalid {
+            tr!("message.invalid_withheld", "[withheld: the signature doesn't verify; start with {flag} to see it]", flag = SHOW_INVALID_FLAG)
+        } else {
+            body
+        }
+    }
+
+    /// Build the views for received messages, applying local rules (first match wins).
+    fn apply_rules(&self, messages: &[(String, Message)]) -> Vec<MessageView> {
+        let mut views = Vec::new();
+        for (sender, msg) in messages {
+            let (body, group) = match &msg.guest {
+                // Guest links without a key take plain text
+                Some(_) if !msg.encrypted => (tr!("message.guest_plain", "[guest, unencrypted] {text}", text = msg.content), None),
+                // The group comes from inside the encrypted payload, not the envelope
+                _ => match self.open_received(sender, msg) {
+                    Ok(payload) => (payload.display(), payload.group().map(str::to_string)),
+                    Err(e) => {
+                        telemetry::decrypt_failed();
+                        (tr!("message.undecryptable", "⚠️ [can't decrypt: {error}]", error = e), None)
+                    }
+                },
+            };
+            let signature = self.signature_checks.get(&msg.id).copied().unwrap_or(SignatureCheck::Unverified);
+            let body = self.withhold_invalid(signature, body);
+            // The original was fetched before the replacement arrived, so both are shown
+            let body = match msg.supersedes.as_deref().filter(|_| !msg.replaced_queued) {
+                Some(original) => tr!("message.edit_of", "{body} (edit of {id})", body = body, id = original),
+                None => body,
+            };
+            let (starred, labels) = self.config.annotations.labels(&msg.id);
+            let mut view = MessageView {
+                sender: sender.clone(),
+                group,
+                timestamp: msg.timestamp,
+                body,
+                signature: Some(signature),
+                highlighted: false,
+                starred,
+                labels,
+                expires_in: msg.expires_at.map(|expires_at| expires_at - Utc::now()),
+                // Only worth showing against a time the server vouches for
+                claimed_at: msg.server_signature.as_ref()
+                    .and(message_id_time(&msg.id))
+                    .filter(|claimed| (*claimed - msg.timestamp).num_seconds().abs() > CLOCK_SKEW_SHOWN_SECS),
+            };
+
+            if let Some((_, rule)) = rules::evaluate(&self.config.rules, &view.sender, &view.body) {
+                match &rule.action {
+                    RuleAction::Mute => continue,
+                    RuleAction::Highlight => {
+                        view.highlighted = true;
+                        print!("\x07");
+                    }
+                    RuleAction::Run(command) => {
+                        if let Err(e) = rules::run_command(command, &view.sender, &view.body) {
+                            say!("rules.command_failed", "❌ Rule command failed: {error}", error = e);
+                        }
+                    }
+                }
+            }
+
+            views.push(view);
+        }
+        views
+    }
+
+    /// Print a summary for each digest whose window has closed.
+    fn show_due_digests(&mut self, now: DateTime<Utc>) {
+        let due = self.config.digests.due(now, &self.config.muted);
+        for (contact, count) in &due {
+            say!("digest.summary", "🗞️ {contact}: {count} new message(s)", contact = contact, count = count);
+        }
+        if !due.is_empty() {
+            self.save_config();
+        }
+    }
+
+    /// New activity brings an archived conversation back into the listings.
+    fn unarchive_active(&mut self, messages: &[(String, Message)]) {
+        let before = self.config.archived.len();
+        for (sender, _) in messages {
+            self.config.archived.remove(sender);
+        }
+        if self.config.archived.len() != before {
+            self.save_config();
+        }
+    }
+
+    fn handle_rule_command(&mut self, args: &[&str]) {
+        match args.first().copied() {
+            Some("add") => match Rule::parse(&args[1..]) {
+                Ok(rule) => {
+                    say!("rules.added", "✅ Added rule {number}: {rule}", number = self.config.rules.len() + 1, rule = rule);
+                    self.config.rules.push(rule);
+                    self.save_config();
+                }
+                Err(e) => out!("❌ {}", e),
+            },
+            Some("list") => {
+                if self.config.rules.is_empty() {
+                    say!("rules.none", "📭 No rules");
+                }
+                for (i, rule) in self.config.rules.iter().enumerate() {
+                    out!("  {}. {}", i + 1, rule);
+                }
+            }
+            Some("remove") => match args.get(1).and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n >= 1 && n <= self.config.rules.len() => {
+                    let rule = self.config.rules.remove(n - 1);
+                    say!("rules.removed", "🗑️ Removed rule: {rule}", rule = rule);
+                    self.save_config();
+                }
+                _ => say!("rules.remove_usage", "❌ Usage: rule remove <n>"),
+            },
+            Some("test") => {
+                let mut sender = "";
+                let mut text = &args[1..];
+                if let Some(from) = text.first().and_then(|arg| arg.strip_prefix("from=")) {
+                    sender = from;
+                    text = &text[1..];
+                }
+                match rules::evaluate(&self.config.rules, sender, &text.join(" ")) {
+                    Some((i, rule)) => say!("rules.would_fire", "🎯 Rule {number} would fire: {rule}", number = i + 1, rule = rule),
+                    None => say!("rules.no_match", "📭 No rule matches"),
+                }
+            }
+            _ => say!("rules.usage", "❌ Usage: rule <add|list|remove|test> ..."),
+        }
+    }
+
+    fn save_config(&self) {
+        if let Err(e) = self.config.save(&self.config_path) {
+            say!("error.save_config", "❌ Failed to save config: {error}", error = e);
+        }
+    }
+
+    /// Fetch messages to show, handling notices, control and recovery
+    /// messages on the way.
+    async fn fetch_incoming(&mut self) -> Vec<(String, Message)> {
+        let (messages, fetched, signatures) = self.receive_all().await;
+        self.signature_checks.extend(signatures);
+        for (server, message_ids) in fetched {
+            self.pending_acks.entry(server).or_default().extend(message_ids);
+        }
+        let messages = self.take_system_notices(messages);
+        let messages = self.take_control_messages(messages);
+        self.take_recovery_messages(messages).await
+    }
+
+    /// Fetch messages in the order their senders wrote them. A numbered
+    /// message that arrives ahead of an earlier one is held while fetching
+    /// again, for up to the jitter window; after that it is shown anyway,
+    /// behind a note about what is missing.
+    async fn receive_in_order(&mut self) -> Vec<(String, Message)> {
+        let mut buffer = JitterBuffer::new(self.config.sequences.window());
+        let mut ordered = Vec::new();
+        let mut numbered = false;
+        let mut arrivals = self.fetch_incoming().await;
+        loop {
+            let now = tokio::time::Instant::now();
+            let mut released = Vec::new();
+            for (sender, message) in arrivals {
+                numbered |= message.seq.is_some();
+                let (key, seq) = (sender.clone(), message.seq);
+                released.extend(buffer.push(&mut self.config.sequences.shown, &key, seq, (sender, message), now));
+            }
+            released.extend(buffer.expire(&mut self.config.sequences.shown, now));
+            for release in released {
+                match release {
+                    Released::Item(item) => ordered.push(item),
+                    Released::Gap { sender, missing } => say!("receive.gap",
+                        "⏳ {count} earlier message(s) from {sender} didn't arrive in time; showing the later ones", count = missing, sender = sender),
+                }
+            }
+            let Some(deadline) = buffer.deadline() else { break };
+            tokio::time::sleep_until(deadline.min(now + std::time::Duration::from_millis(JITTER_POLL_MS))).await;
+            arrivals = self.fetch_incoming().await;
+        }
+        if numbered {
+            self.save_config();
+        }
+        ordered
+    }
+
+    /// Fetch from every connected server, tagging each message with its
+    /// display sender. Also returns the ids fetched from each server, and
+    /// how each message's signature checked out.
+    async fn receive_all(&self) -> (Vec<(String, Message)>, HashMap<String, Vec<String>>, HashMap<String, SignatureCheck>) {
+        let mut received = Vec::new();
+        let mut fetched = HashMap::new();
+        let mut signatures = HashMap::new();
+        for name in self.servers.keys() {
+            let messages = match self.receive_messages(name).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    say!("receive.failed", "❌ Failed to receive messages from {server}: {error}", server = name, error = e);
+                    continue;
+                }
+            };
+            // Acknowledging a message to ourselves would delete it before
+            // our other devices fetch it; it expires instead
+            fetched.insert(name.clone(), messages.iter()
+                .filter(|msg| msg.sender_id != self.id || msg.recipient_id != self.id)
+                .map(|msg| msg.id.clone())
+                .collect());
+            telemetry::received(messages.len());
+            let mut histories = HashMap::new();
+            for mut msg in messages {
+                // Fetched again because the ack after it was lost: shown
+                // already, so it only needs acknowledging this time
+                if self.already_received(&msg.id) {
+                    debug!("📭 Message {} from {} was received before", msg.id, msg.sender_id);
+                    continue;
+                }
+                // Older clients send no key; their published one is the next best
+                let unknown = msg.sender_key.is_none() && msg.guest.is_none() && msg.encrypted
+                    && !self.servers[name].contacts.contains_key(&msg.sender_id);
+                if unknown {
+                    msg.sender_key = self.published_key(name, &msg.sender_id).await.ok();
+                }
+                if let Err(e) = self.check_sender_key(name, &msg).await {
+                    telemetry::verify_failed();
+                    out!("{}", tr!("receive.key_rejected", "🚨 Rejected the key in message {id} from {sender}: {error}",
+                        id = msg.id, sender = msg.sender_id, error = e).red().bold());
+                    msg.sender_key = None;
+                }
+                if let Err(e) = self.check_receipt(name, &msg.id, &msg.sender_id, &msg.recipient_id, msg.timestamp, msg.server_signature.as_deref()) {
+                    telemetry::verify_failed();
+                    out!("{}", tr!("receive.receipt_rejected", "🚨 The server's receipt on message {id} from {sender} doesn't verify: {error}",
+                        id = msg.id, sender = msg.sender_id, error = e).red().bold());
+                    msg.server_signature = None;
+                }
+                if let Some(delegate) = &msg.sent_by {
+                    say!("receive.sent_by", "✍️ Message {id} from {sender} was sent by their delegate {delegate}",
+                        id = msg.id, sender = self.display_id(name, &msg.sender_id), delegate = delegate);
+                }
+                let signature = self.check_signature(name, &msg, &mut histories).await;
+                if signature == SignatureCheck::Invalid {
+                    telemetry::verify_failed();
+                    out!("{}", tr!("receive.signature_invalid", "🚨 Message {id} from {sender} is not signed by their identity key; it may have been altered",
+                        id = msg.id, sender = msg.sender_id).red().bold());
+                }
+                signatures.insert(msg.id.clone(), signature);
+                self.record_received(name, &msg, signature);
+                let first_contact = msg.sender_key.as_ref()
+                    .filter(|_| msg.sender_id != self.id && !self.servers[name].contacts.contains_key(&msg.sender_id));
+                if let Some(key) = first_contact {
+                    let sender = self.display_id(name, &msg.sender_id);
+                    say!("receive.first_contact", "🆕 {sender} is not a contact yet; their key {fingerprint} is signed by their identity. 'add {sender} {key}' to keep it",
+                        sender = sender, fingerprint = crypto::fingerprint(&hex::decode(&key.x25519_public_key).unwrap_or_default()), key = key.x25519_public_key);
+                }
+                received.push((self.display_id(name, &msg.sender_id), msg));
+            }
+        }
+        (received, fetched, signatures)
+    }
+
+    async fn handle_server_command(&mut self, args: &[&str]) {
+        match args {
+            ["list"] => {
+                for (name, profile) in std::iter::once((DEFAULT_SERVER, None))
+                    .chain(self.config.servers.iter()
+                        .filter(|(name, _)| name.as_str() != DEFAULT_SERVER)
+                        .map(|(name, profile)| (name.as_str(), Some(profile))))
+                {
+                    let marker = if name == self.current { "*" } else { " " };
+                    match self.servers.get(name) {
+                        Some(connection) => out!("{} {} {} ({})", marker, name, connection.addr(),
+                            crypto::fingerprint(connection.server_pubkey.as_bytes())),
+                        None => say!("server.list_disconnected", "{marker} {name} {addr} (not connected)", marker = marker, name = name,
+                            addr = profile.map(|p| p.addr.as_str()).unwrap_or(DEFAULT_SERVER_ADDR)),
+                    }
+                }
+            }
+            ["switch", name] => {
+                if self.servers.contains_key(*name) {
+                    self.current = name.to_string();
+                    say!("server.switched", "🔀 Now targeting server {name}", name = name);
+                } else {
+                    say!("server.not_connected", "❌ Not connected to server {name}", name = name);
+                }
+            }
+            ["add", name, addrs, rest @ ..] => {
+                let mut addrs = addrs.split(',').filter(|addr| !addr.is_empty()).map(str::to_string);
+                let Some(addr) = addrs.next() else {
+                    say!("server.usage", "❌ Usage: server <list|switch <name>|stats|add <name> <addr>[,<addr>..] [--separate-identity]>");
+                    return;
+                };
+                let profile = ServerProfile {
+                    addr,
+                    fallback_addrs: addrs.collect(),
+                    separate_identity: rest.contains(&"--separate-identity"),
+                };
+                let allow_new = self.allow_new_server || rest.contains(&ALLOW_NEW_SERVER_FLAG);
+                match self.connect(name, &profile, allow_new).await {
+                    Ok(_) => {
+                        self.config.servers.insert(name.to_string(), profile);
+                        self.save_config();
+                    }
+                    Err(e) => say!("server.connect_failed", "❌ Failed to connect to {addr}: {error}", addr = profile.addrs().join(","), error = e),
+                }
+            }
+            ["stats"] => match self.server(&self.current) {
+                Ok(connection) => match connection.request(&ServerCommand::Stats).await {
+                    Ok(ServerResponse::Stats { uptime_secs, latencies, key_cache_hits, key_cache_misses, disk_usage, reported_senders, integrity, storage_degraded_since, features, log_rotations }) => {
+                        say!("stats.summary", "📊 {server} up {uptime}s, key cache {hits} hits / {misses} misses",
+                            server = self.current, uptime = uptime_secs, hits = key_cache_hits, misses = key_cache_misses);
+                        if let Some(since) = storage_degraded_since {
+                            say!("stats.degraded", "  ⚠️ storage failing since {since}: new messages are refused",
+                                since = since.format("%Y-%m-%d %H:%M UTC"));
+                        }
+                        if !disk_usage.is_empty() {
+                            let usage: Vec<String> = disk_usage.iter().map(|(component, bytes)| format!("{} {}", component, bytes)).collect();
+                            say!("stats.disk", "  disk: {bytes} bytes ({components})", bytes = disk_usage.values().sum::<u64>(), components = usage.join(", "));
+                        }
+                        if !features.is_empty() {
+                            say!("stats.features", "  features: {features}", features = features.join(", "));
+                        }
+                        if log_rotations.values().any(|count| *count > 0) {
+                            let rotations: Vec<String> = log_rotations.iter().map(|(log, count)| format!("{} {}", log, count)).collect();
+                            say!("stats.rotations", "  log rotations: {rotations}", rotations = rotations.join(", "));
+                        }
+                        if !reported_senders.is_empty() {
+                            let reports: Vec<String> = reported_senders.iter().map(|(sender, count)| format!("{} {}", sender, count)).collect();
+                            say!("stats.reports", "  spam reports: {reports}", reports = reports.join(", "));
+                        }
+                        if let Some(progress) = integrity {
+                            let state = if progress.finished { tr!("stats.integrity_done", "done") } else { tr!("stats.integrity_running", "running") };
+                            say!("stats.integrity", "  integrity check ({state}): {checked}/{total} messages checked, {quarantined} quarantined",
+                                state = state, checked = progress.checked, total = progress.total, quarantined = progress.quarantined);
+                        }
+                        for (command, histogram) in latencies {
+                            let bucket = |p| histogram.percentile_ms(p).map(|ms| format!("≤{}ms", ms)).unwrap_or_else(|| ">1s".to_string());
+                            out!("  {:<16} n={:<6} mean={:.1}ms p50={} p99={} max={:.1}ms", command, histogram.count,
+                                histogram.mean_ms(), bucket(50.0), bucket(99.0), histogram.max_micros as f64 / 1000.0);
+                        }
+                    }
+                    Ok(_) => say!("error.unexpected_response", "❌ Unexpected response from server"),
+                    Err(e) => say!("stats.failed", "❌ Failed to get stats: {error}", error = e),
+                },
+                Err(e) => out!("❌ {}", e),
+            },
+            _ => say!("server.usage", "❌ Usage: server <list|switch <name>|stats|add <name> <addr>[,<addr>..] [--separate-identity]>"),
+        }
+    }
+
+    async fn interactive_mode(&mut self) -> Result<()> {
+        note!();
+        note!("{}", tr!("help.title", "🔐 Secure Messaging Client - Interactive Mode"));
+        note!("=============================================");
+        note!("{}", tr!("help.commands", "Commands:"));
+        for (syntax, key, description) in HELP {
+            note!("  {:<27} - {}", syntax, i18n::translate(key, description, &[]));
+        }
+        note!();
+
+        // A line is read off the runtime so events can be shown while the
+        // prompt waits; the read carries on across them
+        let mut reading = None;
+        loop {
+            if self.current == DEFAULT_SERVER {
+                print!("{} > ", self.id.green());
+            } else {
+                print!("{}@{} > ", self.id.green(), self.current);
+            }
             io::stdout().flush()?;
             
-            let mut input = String::new();
-            io::stdin().read_line(&mut input)?;
-            let input = input.trim();
+            let line = reading.get_or_insert_with(|| tokio::task::spawn_blocking(|| {
+                let mut input = String::new();
+                io::stdin().read_line(&mut input).map(|_| input)
+            }));
+            let input = tokio::select! {
+                read = line => {
+                    reading = None;
+                    read??
+                }
+                event = self.next_event() => {
+                    out!();
+                    self.show_events(Some(event)).await?;
+                    continue;
+                }
+            };
+            self.forget_scrubbed();
+            if !self.run_command(input.trim()).await? {
+                break;
+            }
+            self.show_events(None).await?;
+        }
+        
+        Ok(())
+    }
+
+    /// Show `first` and the events waiting after it, in order: pushes by
+    /// fetching, one fetch for a run of them, and the rest as status lines.
+    async fn show_events(&mut self, first: Option<ClientEvent>) -> Result<()> {
+        let events: Vec<ClientEvent> = first.into_iter().chain(std::iter::from_fn(|| self.try_next_event())).collect();
+        let mut fetch = false;
+        for event in events {
+            if matches!(event, ClientEvent::Pushed { .. }) {
+                fetch = true;
+                continue;
+            }
+            if std::mem::take(&mut fetch) {
+                self.forget_scrubbed();
+                self.run_command("receive").await?;
+            }
+            if let Some(line) = self.status_line(&event) {
+                out!("{}", line);
+            }
+        }
+        if fetch {
+            self.forget_scrubbed();
+            self.run_command("receive").await?;
+        }
+        Ok(())
+    }
+
+    /// What the prompt says about an event. A redial isn't worth a line;
+    /// the connection opening, or the command failing, is.
+    fn status_line(&self, event: &ClientEvent) -> Option<String> {
+        Some(match event {
+            ClientEvent::Pushed { .. } | ClientEvent::Reconnecting { .. } => return None,
+            ClientEvent::Notice { notice, .. } => describe_notice(notice, self.config.control_display).red().to_string(),
+            ClientEvent::Connected { server, info } => tr!("server.connected", "✅ Connected to server {name} ({addr})", name = server, addr = info.addr),
+            ClientEvent::Disconnected { server, reason } => tr!("server.disconnected", "🔌 Lost the connection to server {name} ({reason}); the next command reconnects",
+                name = server, reason = reason),
+            ClientEvent::SessionExpired { server } => tr!("server.session_expired", "📴 Server {name} no longer pushes new messages; 'receive' still fetches them",
+                name = server),
+            ClientEvent::ServerDraining { server, deadline } => tr!("server.going_away", "🚧 {addr} is restarting; it finishes what's in hand and takes connections again after {time}",
+                addr = self.servers.get(server).map_or(server.as_str(), |connection| connection.addr()), time = deadline.format("%H:%M:%S UTC")),
+            ClientEvent::KeyMismatch { server, expected, actual } => tr!("server.key_mismatch", "⛔ Server {name} answered with key {actual}, not {expected}",
+                name = server, actual = actual, expected = expected),
+        })
+    }
+
+    /// Run one line typed at the prompt, in the plain interactive mode or
+    /// the TUI. Returns false once the user asks to quit.
+    async fn run_command(&mut self, input: &str) -> Result<bool> {
+        let parts: Vec<&str> = input.split_whitespace().collect();
+        if parts.is_empty() {
+            return Ok(true);
+        }
+        
+        match parts[0] {
+            "send" => {
+                let mut options = SendOptions::default();
+                let mut args = &parts[1..];
+                let mut invalid = false;
+                let mut status = false;
+                while let [flag @ ("--ttl" | "--deliver-within" | "--confirm" | "--status"), rest @ ..] = args {
+                    if matches!(*flag, "--confirm" | "--status") {
+                        options.confirmed |= *flag == "--confirm";
+                        status |= *flag == "--status";
+                        args = rest;
+                        continue;
+                    }
+                    let Some(secs) = rest.first().and_then(|d| parse_duration(d)) else {
+                        invalid = true;
+                        break;
+                    };
+                    match *flag {
+                        "--ttl" => options.ttl_secs = Some(secs),
+                        _ => options.deliver_by = Some(Utc::now() + chrono::Duration::seconds(secs as i64)),
+                    }
+                    args = &rest[1..];
+                }
+                if invalid {
+                    say!("send.invalid_duration", "❌ Invalid duration; use e.g. 30s, 15m, 12h or 7d");
+                    return Ok(true);
+                }
+                if args.len() < 2 {
+                    say!("send.usage", "❌ Usage: send [--ttl <duration>] [--deliver-within <duration>] [--confirm] [--status] <recipient> <message>");
+                    return Ok(true);
+                }
+                let peer = match self.resolve_direct(args[0]) {
+                    Ok((server, recipient)) => self.display_id(server, recipient),
+                    Err(e) => {
+                        out!("❌ {}", e);
+                        return Ok(true);
+                    }
+                };
+                // A status update makes the previous one worthless if it's still unread
+                if status {
+                    options.supersedes = self.config.status_messages.get(&peer).cloned();
+                }
+                let message = match args {
+                    [_, "--template", name, values @ ..] => match self.expand_template(name, values) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            out!("❌ {}", e);
+                            return Ok(true);
+                        }
+                    },
+                    _ => args[1..].join(" "),
+                };
+                
+                match self.send_numbered(args[0], &message, options).await {
+                    Ok(message_id) => {
+                        say!("send.sent", "✅ Message sent to {recipient} ({id})", recipient = args[0], id = message_id);
+                        if status {
+                            self.config.status_messages.insert(peer, message_id);
+                            self.save_config();
+                        }
+                    }
+                    Err(e) => say!("send.failed", "❌ Failed to send message: {error}", error = e),
+                }
+            }
+
+            "edit" => {
+                let [_, message_id, _, ..] = parts.as_slice() else {
+                    say!("edit.usage", "❌ Usage: edit <message_id> <message>");
+                    return Ok(true);
+                };
+                match self.edit_message(message_id, words_after(input, 2)).await {
+                    Ok(new_id) => say!("edit.sent", "✏️ Sent the edit of {id} ({new_id})", id = message_id, new_id = new_id),
+                    Err(e) => say!("edit.failed", "❌ Failed to edit message: {error}", error = e),
+                }
+            }
+            
+            "tsend" => {
+                let [_, target, name, values @ ..] = parts.as_slice() else {
+                    say!("tsend.usage", "❌ Usage: tsend <recipient> <template> [name=value ...]");
+                    return Ok(true);
+                };
+                let result = match self.expand_template(name, values) {
+                    Ok(message) => self.send_numbered(target, &message, SendOptions::default()).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(message_id) => say!("send.sent", "✅ Message sent to {recipient} ({id})", recipient = target, id = message_id),
+                    Err(e) => say!("send.failed", "❌ Failed to send message: {error}", error = e),
+                }
+            }
+
+            "template" => match parts.get(1..).unwrap_or_default() {
+                ["add", name, ..] => {
+                    let body = unquote(words_after(input, 3)).replace("\\n", "\n");
+                    match template::placeholders(&body) {
+                        Ok(_) if body.is_empty() => say!("template.add_usage", "❌ Usage: template add <name> <body>"),
+                        Ok(names) => {
+                            self.config.templates.insert(name.to_string(), body);
+                            self.save_config();
+                            let names: Vec<_> = names.into_iter().collect();
+                            if names.is_empty() {
+                                say!("template.saved", "📝 Saved template {name}", name = name);
+                            } else {
+                                say!("template.saved_placeholders", "📝 Saved template {name} with placeholders {placeholders}",
+                                    name = name, placeholders = names.join(", "));
+                            }
+                        }
+                        Err(e) => out!("❌ {}", e),
+                    }
+                }
+                ["list"] => {
+                    if self.config.templates.is_empty() {
+                        say!("template.none", "📝 No templates");
+                    }
+                    for (name, body) in &self.config.templates {
+                        let first_line = body.lines().next().unwrap_or_default();
+                        out!("  {:<12} {}{}", name, first_line, if body.contains('\n') { " …" } else { "" });
+                    }
+                }
+                ["show", name] => match self.config.templates.get(*name) {
+                    Some(body) => output::print(output::Level::Data, body),
+                    None => say!("template.unknown", "❌ No template named {name}", name = name),
+                },
+                ["remove", name] => {
+                    if self.config.templates.remove(*name).is_some() {
+                        self.save_config();
+                        say!("template.removed", "🗑️ Removed template {name}", name = name);
+                    } else {
+                        say!("template.unknown", "❌ No template named {name}", name = name);
+                    }
+                }
+                _ => say!("template.usage", "❌ Usage: template add <name> <body> | list | show <name> | remove <name>"),
+            },
+
+            "receive" => {
+                let messages = self.receive_in_order().await;
+                let now = Utc::now();
+                if messages.is_empty() {
+                    say!("receive.none", "📭 No new messages");
+                } else {
+                    say!("receive.count", "📥 Received {count} message(s):", count = messages.len());
+                    self.unarchive_active(&messages);
+                    let (audible, muted): (Vec<_>, Vec<_>) = messages.into_iter()
+                        .partition(|(sender, _)| !self.config.muted.contains(sender));
+                    let digests = &mut self.config.digests;
+                    let (audible, digested): (Vec<_>, Vec<_>) = audible.into_iter()
+                        .partition(|(sender, _)| !digests.hold(sender, now));
+                    let views = self.apply_rules(&audible);
+                    if !views.is_empty() {
+                        output::print(output::Level::Data, self.renderer.render(&views).trim_end_matches('\n'));
+                    }
+                    if !muted.is_empty() {
+                        say!("receive.muted", "🔇 {count} message(s) from muted contacts", count = muted.len());
+                    }
+                    if !digested.is_empty() {
+                        self.save_config();
+                    }
+                }
+                // Everything fetched is in history now, shown or held
+                self.acknowledge_received().await;
+                self.show_due_digests(now);
+            }
+            
+            "search" => {
+                if parts.get(1) == Some(&"--rebuild") {
+                    match self.rebuild_search_index() {
+                        Ok((indexed, skipped)) => say!("search.indexed", "🔎 Indexed {indexed} message(s), skipped {skipped}", indexed = indexed, skipped = skipped),
+                        Err(e) => say!("search.rebuild_failed", "❌ Failed to rebuild the search index: {error}", error = e),
+                    }
+                    return Ok(true);
+                }
+                let result = parse_history_search(&parts[1..])
+                    .and_then(|query| Ok((self.search_history(&query)?, query)));
+                match result {
+                    Ok((hits, _)) if hits.is_empty() => say!("search.none", "🔎 No matching messages"),
+                    Ok((hits, query)) => {
+                        for (record, text) in &hits {
+                            let peer = self.display_id(&record.server, &record.peer);
+                            let direction = if record.outgoing { format!("to {}", peer) } else { format!("from {}", peer) };
+                            out!("  {} {} {}: {}", record.timestamp.format("%Y-%m-%d %H:%M"), direction,
+                                record.message_id, snippet(text, &query.words));
+                        }
+                    }
+                    Err(e) => out!("❌ {}", e),
+                }
+            }
+
+            "label" | "unlabel" | "star" | "unstar" => {
+                let (message_id, label) = match (parts[0], &parts[1..]) {
+                    ("label" | "unlabel", [message_id, label]) => (*message_id, *label),
+                    ("star" | "unstar", [message_id]) => (*message_id, annotations::STARRED),
+                    _ => {
+                        say!("label.usage", "❌ Usage: label|unlabel <message_id> <label>, star|unstar <message_id>");
+                        return Ok(true);
+                    }
+                };
+                let value = matches!(parts[0], "label" | "star").then(String::new);
+                match self.annotate(message_id, BTreeMap::from([(label.to_string(), value)])).await {
+                    Ok(()) => say!("label.done", "🏷️ Updated {id}", id = message_id),
+                    Err(e) => say!("label.failed", "❌ Failed to update labels: {error}", error = e),
+                }
+            }
+
+            "labels" => {
+                let Some(target) = parts.get(1) else {
+                    say!("labels.usage", "❌ Usage: labels <contact>");
+                    return Ok(true);
+                };
+                let result = self.resolve_direct(target)
+                    .and_then(|(server, peer)| self.show_labels(server, peer));
+                if let Err(e) = result {
+                    say!("history.read_failed", "❌ Failed to read history: {error}", error = e);
+                }
+            }
+
+            "cancel" => {
+                let Some(message_id) = parts.get(1) else {
+                    say!("cancel.usage", "❌ Usage: cancel <message_id>");
+                    return Ok(true);
+                };
+                match self.cancel_message(message_id).await {
+                    Ok(MessageState::Cancelled) => say!("cancel.cancelled", "🗑️ Cancelled before delivery"),
+                    Ok(_) => say!("cancel.retraction", "↩️ Already delivered; sent a retraction request instead"),
+                    Err(e) => say!("cancel.failed", "❌ Failed to cancel message: {error}", error = e),
+                }
+            }
+
+            "history" => {
+                let limit = match parts.get(2).map(|n| n.parse::<usize>()) {
+                    None => 20,
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => {
+                        say!("history.usage", "❌ Usage: history <contact> [n]");
+                        return Ok(true);
+                    }
+                };
+                let Some(target) = parts.get(1) else {
+                    say!("history.usage", "❌ Usage: history <contact> [n]");
+                    return Ok(true);
+                };
+                let result = self.resolve_direct(target)
+                    .and_then(|(server, peer)| Ok((self.display_id(server, peer), self.show_history(server, peer, limit)?)));
+                match result {
+                    Ok((contact, ())) => {
+                        if self.config.digests.clear(&contact) {
+                            self.save_config();
+                        }
+                    }
+                    Err(e) => say!("history.read_failed", "❌ Failed to read history: {error}", error = e),
+                }
+            }
+            
+            "contacts" => {
+                let show_all = parts.get(1) == Some(&"--all");
+                match self.get_online_clients(&self.current).await {
+                    Ok(clients) => {
+                        say!("contacts.online", "👥 Online contacts:");
+                        let lookalikes = self.lookalikes(&self.current);
+                        for client in clients {
+                            if client == self.id {
+                                continue;
+                            }
+                            let warning = lookalike_warning(&lookalikes, &client);
+                            let client = self.display_id(&self.current, &client);
+                            let archived = self.config.archived.contains(&client);
+                            if archived && !show_all {
+                                continue;
+                            }
+                            let mut markers = String::new();
+                            if self.config.muted.contains(&client) {
+                                markers.push_str(" 🔇");
+                            }
+                            if archived {
+                                markers.push_str(" 🗄️");
+                            }
+                            if let Some(warning) = warning {
+                                markers.push_str(&format!(" {}", format!("⚠️ {}", warning).red().bold()));
+                            }
+                            out!("  - {}{}", client, markers);
+                        }
+                    }
+                    Err(e) => say!("contacts.failed", "❌ Failed to get contacts: {error}", error = e),
+                }
+            }
+            
+            "add" => {
+                if parts.len() != 3 {
+                    say!("contacts.add_usage", "❌ Usage: add <contact_id> <pubkey>");
+                    return Ok(true);
+                }
+                let (server, contact_id) = self.resolve_target(parts[1]);
+                let server = server.to_string();
+                let contact_id = contact_id.to_string();
+                let pubkey_hex = parts[2];
+                
+                match hex::decode(pubkey_hex) {
+                    Ok(bytes) => {
+                        if bytes.len() == 32 {
+                            // Create X25519PublicKey from bytes
+                            let mut key_bytes = [0u8; 32];
+                            key_bytes.copy_from_slice(&bytes);
+                            let pubkey = X25519PublicKey::from(key_bytes);
+                            if let Some(warning) = lookalike_warning(&self.lookalikes(&server), &contact_id) {
+                                out!("{}", format!("⚠️ {}", warning).red().bold());
+                            }
+                            match self.add_contact(&server, contact_id.clone(), pubkey) {
+                                Ok(()) => self.sync_contact_or_warn(&server, &contact_id).await,
+                                Err(e) => out!("❌ {}", e),
+                            }
+                        } else {
+                            say!("contacts.invalid_key", "❌ Invalid public key length");
+                        }
+                    }
+                    Err(_) => say!("contacts.invalid_hex", "❌ Invalid hex encoding"),
+                }
+            }
             
-            if input.is_empty() {
-                continue;
+            "account" => {
+                if let Err(e) = self.print_account(parts.get(1) == Some(&"--json")).await {
+                    say!("account.failed", "❌ Failed to get account usage: {error}", error = e);
+                }
+            }
+
+            "whoami" => {
+                if let Err(e) = self.print_identity(parts.get(1) == Some(&"--json")) {
+                    say!("whoami.failed", "❌ Failed to show identity: {error}", error = e);
+                }
             }
             
-            let parts: Vec<&str> = input.split_whitespace().collect();
-            if parts.is_empty() {
-                continue;
+            "mute" | "unmute" | "archive" | "unarchive" => {
+                if parts.len() != 2 {
+                    say!("contacts.flag_usage", "❌ Usage: {command} <contact>", command = parts[0]);
+                    return Ok(true);
+                }
+                let contact = parts[1].to_string();
+                match parts[0] {
+                    "mute" => self.config.muted.insert(contact.clone()),
+                    "unmute" => self.config.muted.remove(&contact),
+                    "archive" => self.config.archived.insert(contact.clone()),
+                    _ => self.config.archived.remove(&contact),
+                };
+                self.save_config();
+                say!("contacts.flagged", "✅ {command}d {contact}", command = parts[0], contact = contact);
             }
             
-            match parts[0] {
-                "send" => {
-                    if parts.len() < 3 {
-                        println!("❌ Usage: send <recipient> <message>");
-                        continue;
-                    }
-                    let recipient = parts[1];
-                    let message = parts[2..].join(" ");
-                    
-                    match self.send_message(addr, recipient, &message).await {
-                        Ok(_) => println!("✅ Message sent to {}", recipient),
-                        Err(e) => println!("❌ Failed to send message: {}", e),
+            "server" => {
+                self.handle_server_command(&parts[1..]).await;
+            }
+
+            "guestlink" => {
+                self.handle_guestlink_command(&parts[1..]).await;
+            }
+
+            "group" => {
+                self.handle_group_command(&parts[1..]).await;
+            }
+
+            "gsend" => {
+                let message = words_after(input, 2);
+                match parts.get(1) {
+                    Some(target) if !message.is_empty() => match self.send_to_group(target, message).await {
+                        Ok(ids) => say!("gsend.sent", "✅ Message sent to {group} ({count} member(s))", group = target, count = ids.len()),
+                        Err(e) => say!("gsend.failed", "❌ Failed to send to the group: {error}", error = e),
+                    },
+                    _ => say!("gsend.usage", "❌ Usage: gsend <group> <message>"),
+                }
+            }
+
+            "userdata" => {
+                self.handle_userdata_command(&parts[1..]).await;
+            }
+
+            "admin" => {
+                self.handle_admin_command(&parts[1..], input).await;
+            }
+
+            "delegate" => {
+                self.handle_delegate_command(&parts[1..], input).await;
+            }
+
+            "mailbox" => {
+                let search = match parts.get(1) {
+                    Some(&"search") => parse_mailbox_search(&parts[2..]),
+                    _ => Err(anyhow!("Usage: mailbox search [--from <c>] [--after <date>] [--before <date>] [--min-size <n>[k|m]] [--kind message|notice] [--limit <n>] [--cursor <c>]")),
+                };
+                let result = match search {
+                    Ok(search) => self.search_mailbox(&self.current, search).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok((results, truncated)) => {
+                        if results.is_empty() {
+                            say!("mailbox.none", "📭 No matching messages on {server}", server = self.current);
+                        }
+                        for meta in &results {
+                            let kind = match meta.kind {
+                                MessageKind::Notice => format!(" {}", tr!("mailbox.notice", "notice")),
+                                MessageKind::Message => String::new(),
+                            };
+                            let pending = if meta.delivered { String::new() } else { format!(" {}", tr!("mailbox.not_fetched", "(not fetched)")) };
+                            let sender = if meta.sender_id.is_empty() { tr!("mailbox.server", "server") } else { meta.sender_id.clone() };
+                            say!("mailbox.row", "  {id} {time} from {sender} {size} bytes{kind}{pending}", id = meta.id,
+                                time = meta.timestamp.format("%Y-%m-%d %H:%M"), sender = sender, size = meta.size, kind = kind, pending = pending);
+                        }
+                        if let (true, Some(last)) = (truncated, results.last()) {
+                            say!("mailbox.more", "… more messages matched; add --cursor {cursor} for the next page", cursor = last.hlc);
+                        }
                     }
+                    Err(e) => say!("mailbox.failed", "❌ Mailbox search failed: {error}", error = e),
                 }
+            }
+            
+            "export-state" | "import-state" => {
+                let Some(path) = parts.get(1) else {
+                    say!("export.usage", "❌ Usage: {command} <file> [--passphrase <p>]", command = parts[0]);
+                    return Ok(true);
+                };
+                let passphrase = parts.iter().position(|p| *p == "--passphrase")
+                    .and_then(|i| parts.get(i + 1).copied());
                 
-                "receive" => {
-                    match self.receive_messages(addr).await {
-                        Ok(messages) => {
-                            if messages.is_empty() {
-                                println!("📭 No new messages");
-                            } else {
-                                println!("📥 Received {} message(s):", messages.len());
-                                for msg in messages {
-                                    println!("  From: {} at {}", msg.sender_id, msg.timestamp);
-                                    if let Some(signature) = &msg.signature {
-                                        println!("  Signature: {}", signature);
-                                    }
-                                }
-                            }
+                if parts[0] == "export-state" {
+                    if passphrase.is_none() {
+                        out!("{}", tr!("export.no_passphrase", "⚠️ No passphrase: the archive holds your secret keys in plain text.").red().bold());
+                    }
+                    match self.export_state(path, passphrase) {
+                        Ok(_) => say!("export.done", "📦 State exported to {path}", path = path),
+                        Err(e) => say!("export.failed", "❌ Failed to export state: {error}", error = e),
+                    }
+                } else {
+                    let import_parts = if parts.contains(&"--contacts-only") {
+                        ImportParts { identity: false, config: false, contacts: true }
+                    } else if parts.contains(&"--config-only") {
+                        ImportParts { identity: false, config: true, contacts: false }
+                    } else {
+                        ImportParts { identity: true, config: true, contacts: true }
+                    };
+                    if let Err(e) = self.import_state(path, passphrase, &import_parts).await {
+                        say!("import.failed", "❌ Failed to import state: {error}", error = e);
+                    }
+                }
+            }
+            
+            "key" => {
+                let (Some(action), Some(path)) = (parts.get(1).copied(), parts.get(2).copied()) else {
+                    say!("key.usage", "❌ Usage: key <backup|restore|inspect> <file> [--passphrase <p>]");
+                    return Ok(true);
+                };
+                let passphrase = parts.iter().position(|p| *p == "--passphrase")
+                    .and_then(|i| parts.get(i + 1).copied());
+                match action {
+                    "backup" => {
+                        if passphrase.is_none() {
+                            out!("{}", tr!("export.no_passphrase", "⚠️ No passphrase: the archive holds your secret keys in plain text.").red().bold());
+                        }
+                        match self.backup_key(path, passphrase) {
+                            Ok(_) => say!("key.backed_up", "🔑 Keys backed up to {path}", path = path),
+                            Err(e) => say!("key.backup_failed", "❌ Failed to back up keys: {error}", error = e),
+                        }
+                    }
+                    "restore" => {
+                        if let Err(e) = self.restore_key(path, passphrase).await {
+                            say!("key.restore_failed", "❌ Failed to restore keys: {error}", error = e);
+                        }
+                    }
+                    "inspect" => {
+                        if let Err(e) = self.inspect_key_backup(path, passphrase) {
+                            say!("key.inspect_failed", "❌ Key backup check failed: {error}", error = e);
                         }
-                        Err(e) => println!("❌ Failed to receive messages: {}", e),
                     }
+                    _ => say!("key.usage", "❌ Usage: key <backup|restore|inspect> <file> [--passphrase <p>]"),
                 }
-                
-                "contacts" => {
-                    match self.get_online_clients(addr).await {
-                        Ok(clients) => {
-                            println!("👥 Online contacts:");
-                            for client in clients {
-                                if client != self.id {
-                                    println!("  - {}", client);
-                                }
-                            }
+            }
+            
+            "trust" => {
+                if parts.len() != 2 {
+                    say!("trust.usage", "❌ Usage: trust <contact>");
+                    return Ok(true);
+                }
+                let (server, contact_id) = self.resolve_target(parts[1]);
+                let (server, contact_id) = (server.to_string(), contact_id.to_string());
+                match self.trust(&server, &contact_id) {
+                    Ok(fingerprint) => {
+                        say!("trust.done", "🤝 Trusting {contact} with key {fingerprint}", contact = parts[1], fingerprint = fingerprint);
+                        self.sync_contact_or_warn(&server, &contact_id).await;
+                    }
+                    Err(_) => say!("contacts.unknown", "❌ Unknown contact {contact}", contact = parts[1]),
+                }
+            }
+
+            "sync" => {
+                if parts.get(1) != Some(&"now") {
+                    say!("sync.usage", "❌ Usage: sync now");
+                    return Ok(true);
+                }
+                match self.sync_contacts().await {
+                    Ok(count) => say!("sync.sent", "🔄 Sent {count} contact(s) to your other devices", count = count),
+                    Err(e) => say!("sync.all_failed", "❌ Failed to sync contacts: {error}", error = e),
+                }
+            }
+            
+            "digest" => match parts[1..] {
+                [] => {
+                    if self.config.digests.windows.is_empty() {
+                        say!("digest.none", "🗞️ No digested contacts");
+                    }
+                    for (contact, secs) in &self.config.digests.windows {
+                        let held = self.config.digests.pending.get(contact).map_or(0, |pending| pending.count);
+                        say!("digest.entry", "  {contact}: every {secs}s, {held} message(s) held", contact = contact, secs = secs, held = held);
+                    }
+                }
+                [contact, "off"] => {
+                    self.config.digests.set(contact, None);
+                    self.save_config();
+                    say!("digest.cleared", "🗞️ Messages from {contact} are shown as they arrive again", contact = contact);
+                }
+                [contact, window] => match parse_duration(window) {
+                    Some(secs) if secs > 0 => {
+                        self.config.digests.set(contact, Some(secs));
+                        self.save_config();
+                        say!("digest.set", "🗞️ Messages from {contact} are summarized every {window}", contact = contact, window = window);
+                    }
+                    _ => say!("send.invalid_duration", "❌ Invalid duration; use e.g. 30s, 15m, 12h or 7d"),
+                },
+                _ => say!("digest.usage", "❌ Usage: digest [<contact> <duration|off>]"),
+            },
+
+            "retention" => {
+                if parts.len() != 3 {
+                    say!("retention.usage", "❌ Usage: retention <contact> <duration|off>");
+                    return Ok(true);
+                }
+                let ttl_secs = match parts[2] {
+                    "off" => None,
+                    duration => match parse_duration(duration) {
+                        Some(ttl) => Some(ttl),
+                        None => {
+                            say!("send.invalid_duration", "❌ Invalid duration; use e.g. 30s, 15m, 12h or 7d");
+                            return Ok(true);
                         }
-                        Err(e) => println!("❌ Failed to get contacts: {}", e),
+                    },
+                };
+                let (server, contact_id) = self.resolve_target(parts[1]);
+                match self.set_retention(server, contact_id, ttl_secs).await {
+                    Ok(_) => match ttl_secs {
+                        Some(_) => say!("retention.set", "⏳ Messages from {contact} now expire after {duration}", contact = parts[1], duration = parts[2]),
+                        None => say!("retention.cleared", "⏳ Retention for {contact} cleared", contact = parts[1]),
+                    },
+                    Err(e) => say!("retention.failed", "❌ Failed to set retention: {error}", error = e),
+                }
+            }
+            
+            "report" => {
+                if parts.len() < 2 {
+                    say!("report.usage", "❌ Usage: report <contact> [reason]");
+                    return Ok(true);
+                }
+                let reason = match words_after(input, 2) {
+                    "" => "spam",
+                    reason => reason,
+                };
+                let (server, contact_id) = self.resolve_target(parts[1]);
+                match self.report_sender(server, contact_id, reason).await {
+                    Ok(until) => say!("report.done", "🚩 Reported {contact}; the server drops their messages to you until {until}",
+                        contact = parts[1], until = until.format("%Y-%m-%d %H:%M UTC")),
+                    Err(e) => say!("report.failed", "❌ Failed to report {contact}: {error}", contact = parts[1], error = e),
+                }
+            }
+            
+            "audit-key" => {
+                if parts.len() != 2 {
+                    say!("audit.usage", "❌ Usage: audit-key <contact>");
+                    return Ok(true);
+                }
+                let (server, contact_id) = self.resolve_target(parts[1]);
+                let (server, contact_id) = (server.to_string(), contact_id.to_string());
+                if let Err(e) = self.audit_key(&server, &contact_id).await {
+                    out!("{}", tr!("audit.failed", "🚨 Key audit failed: {error}", error = e).red().bold());
+                }
+            }
+            
+            "revoke" => {
+                let (recovery_secret, reason) = match parts.get(1) {
+                    Some(&"--recovery") if parts.len() >= 4 => (Some(parts[2]), parts[3..].join(" ")),
+                    _ if parts.len() >= 2 => (None, parts[1..].join(" ")),
+                    _ => {
+                        say!("revoke.usage", "❌ Usage: revoke [--recovery <secret>] <reason>");
+                        return Ok(true);
                     }
+                };
+                let server = self.current.clone();
+                match self.revoke_key(&server, &reason, recovery_secret).await {
+                    Ok(_) => {
+                        out!("{}", tr!("revoke.done", "🚫 Key revoked. The server will refuse it from now on.").red().bold());
+                        out!("{}", tr!("revoke.restart", "🚫 Restart the client to register a fresh identity.").red().bold());
+                    }
+                    Err(e) => say!("revoke.failed", "❌ Failed to revoke key: {error}", error = e),
                 }
-                
-                "add" => {
-                    if parts.len() != 3 {
-                        println!("❌ Usage: add <contact_id> <pubkey>");
-                        continue;
+            }
+            
+            "recovery-key" => {
+                if parts.get(1) != Some(&"generate") {
+                    say!("recovery_key.usage", "❌ Usage: recovery-key generate");
+                    return Ok(true);
+                }
+                let (secret, public) = crypto::generate_recovery_keypair();
+                self.config.recovery_key = Some(public.clone());
+                self.save_config();
+                say!("recovery_key.public", "🔑 Recovery public key: {key}", key = public.yellow());
+                out!("{}", tr!("recovery_key.secret", "🔑 Recovery SECRET (store offline, shown once): {secret}", secret = secret).red().bold());
+                say!("recovery_key.registered", "   It is registered with servers the next time this client connects.");
+            }
+            
+            "recovery" => {
+                match parts.get(1..).unwrap_or_default() {
+                    ["setup", threshold, contacts] => {
+                        let Ok(threshold) = threshold.parse::<u8>() else {
+                            say!("recovery.setup_usage", "❌ Usage: recovery setup <threshold> <contact,contact,...>");
+                            return Ok(true);
+                        };
+                        let contacts: Vec<String> = contacts.split(',').map(str::to_string).collect();
+                        out!("{}", tr!("recovery.setup_warning", "⚠️ Any {threshold} of {contacts} can together take over this identity.", threshold = threshold, contacts = contacts.join(", ")).red().bold());
+                        out!("{}", tr!("recovery.setup_advice", "⚠️ Choose people who won't collude and whose devices you trust.").red().bold());
+                        match self.setup_recovery(threshold, &contacts).await {
+                            Ok(_) => say!("recovery.setup_done", "🛟 Sent {count} recovery shares; {threshold} are needed to restore", count = contacts.len(), threshold = threshold),
+                            Err(e) => say!("recovery.setup_failed", "❌ Failed to set up recovery: {error}", error = e),
+                        }
                     }
-                    let contact_id = parts[1];
-                    let pubkey_hex = parts[2];
-                    
-                    match hex::decode(pubkey_hex) {
-                        Ok(bytes) => {
-                            if bytes.len() == 32 {
-                                // Create X25519PublicKey from bytes
-                                let mut key_bytes = [0u8; 32];
-                                key_bytes.copy_from_slice(&bytes);
-                                let pubkey = X25519PublicKey::from(key_bytes);
-                                self.add_contact(contact_id.to_string(), pubkey);
+                    ["status"] => {
+                        if self.config.recovery_contacts.is_empty() {
+                            say!("recovery.status_none", "🛟 No recovery contacts set up");
+                        } else {
+                            say!("recovery.status_holders", "🛟 Shares of this identity are held by {contacts}", contacts = self.config.recovery_contacts.join(", "));
+                        }
+                        for owner in self.config.held_recovery_shares.keys() {
+                            if self.recovery_requests.contains_key(owner) {
+                                say!("recovery.status_requested", "  holding a share for {owner} (requested)", owner = owner);
                             } else {
-                                println!("❌ Invalid public key length");
+                                say!("recovery.status_holding", "  holding a share for {owner}", owner = owner);
                             }
                         }
-                        Err(_) => println!("❌ Invalid hex encoding"),
                     }
+                    ["release", owner] => match self.release_share(owner).await {
+                        Ok(_) => say!("recovery.released", "🛟 Returned {owner}'s recovery share", owner = owner),
+                        Err(e) => say!("recovery.release_failed", "❌ Failed to release share: {error}", error = e),
+                    },
+                    _ => say!("recovery.usage", "❌ Usage: recovery <setup <k> <contacts>|status|release <owner>>"),
                 }
-                
-                "quit" => {
-                    println!("👋 Goodbye!");
-                    break;
+            }
+            
+            "recover" => {
+                let ["--from", holders] = parts.get(1..).unwrap_or_default() else {
+                    say!("recover.usage", "❌ Usage: recover --from <contact,contact,...>");
+                    return Ok(true);
+                };
+                let holders: Vec<String> = holders.split(',').map(str::to_string).collect();
+                match self.request_recovery(&holders).await {
+                    Ok(_) => say!("recover.sent", "🛟 Run 'receive' to collect returned shares"),
+                    Err(e) => say!("recover.failed", "❌ Failed to request recovery: {error}", error = e),
                 }
-                
-                _ => {
-                    println!("❌ Unknown command. Type 'quit' to exit.");
+            }
+            
+            "rule" | "rules" => {
+                self.handle_rule_command(&parts[1..]);
+            }
+            
+            "set" => {
+                match (parts.get(1).copied(), parts.get(2).copied()) {
+                    (Some("color"), Some("on")) => {
+                        self.renderer.set_color(true);
+                        say!("set.colors_on", "🎨 Colors enabled");
+                    }
+                    (Some("color"), Some("off")) => {
+                        self.renderer.set_color(false);
+                        say!("set.colors_off", "🎨 Colors disabled");
+                    }
+                    (Some("store"), Some(kind @ ("file" | "sqlite"))) => {
+                        let kind = if kind == "sqlite" { LocalStoreKind::Sqlite } else { LocalStoreKind::File };
+                        if kind == LocalStoreKind::File && self.config.local_store == LocalStoreKind::Sqlite {
+                            say!("set.store_no_copy_back", "⚠️ SQLite data is not copied back to the file store");
+                        }
+                        // The index isn't migrated between backends; rebuild it in the new one
+                        let result = self.set_local_store(kind)
+                            .and_then(|_| if self.config.search_index { self.rebuild_search_index().map(|_| ()) } else { Ok(()) });
+                        match result {
+                            Ok(_) => say!("set.store", "🗃️ Using the {store} local store", store = self.store.name()),
+                            Err(e) => say!("set.store_failed", "❌ Failed to open local store: {error}", error = e),
+                        }
+                    }
+                    (Some("output"), Some(value)) => {
+                        match value.parse::<OutputMode>() {
+                            Ok(mode) => {
+                                self.config.output = Some(mode);
+                                self.save_config();
+                                output::prefer(mode);
+                                self.renderer = Renderer::for_mode(output::mode(), self.config.control_display);
+                                say!("set.output", "✅ Output mode is now {mode}", mode = output::mode());
+                            }
+                            Err(e) => out!("❌ {}", e),
+                        }
+                    }
+                    (Some("jitter"), Some(value)) => match value.parse::<u64>() {
+                        Ok(ms) => {
+                            self.config.sequences.jitter_ms = Some(ms);
+                            self.save_config();
+                            say!("set.jitter", "⏳ Waiting up to {ms}ms for out-of-order messages", ms = ms);
+                        }
+                        Err(_) => say!("set.jitter_invalid", "❌ The jitter window is a number of milliseconds"),
+                    },
+                    (Some("escapes"), Some(value @ ("on" | "off"))) => {
+                        self.config.control_display = if value == "on" { ControlDisplay::Escape } else { ControlDisplay::Strip };
+                        self.save_config();
+                        self.renderer.set_controls(self.config.control_display);
+                        if value == "on" {
+                            say!("set.escapes_on", "🛡️ Control characters from peers are shown escaped, e.g. \\x1b[");
+                        } else {
+                            say!("set.escapes_off", "🛡️ Control characters from peers are removed");
+                        }
+                    }
+                    (Some("search-index"), Some(value @ ("on" | "off"))) => {
+                        match self.set_search_index(value == "on") {
+                            Ok(_) if value == "on" => say!("set.index_on", "🔎 Search index enabled"),
+                            Ok(_) => say!("set.index_off", "🔎 Search index disabled and cleared"),
+                            Err(e) => say!("set.index_failed", "❌ Failed to update the search index: {error}", error = e),
+                        }
+                    }
+                    _ => say!("set.usage", "❌ Usage: set color <on|off> | set store <file|sqlite> | set search-index <on|off> | set output <rich|plain|quiet> | set jitter <ms> | set escapes <on|off>"),
                 }
             }
+            
+            // Not in the help: for debugging what a session has done
+            "metrics" => match telemetry::snapshot() {
+                Some(snapshot) if parts.get(1) == Some(&"json") => output::print(output::Level::Data, &serde_json::to_string_pretty(&snapshot).unwrap_or_default()),
+                Some(snapshot) => output::print(output::Level::Data, snapshot.to_prometheus().trim_end()),
+                None => say!("metrics.disabled", "📈 This client was built without the client-metrics feature"),
+            },
+
+            "quit" => {
+                say!("startup.goodbye", "👋 Goodbye!");
+                return Ok(false);
+            }
+            
+            _ => {
+                say!("error.unknown_command", "❌ Unknown command. Type 'quit' to exit.");
+            }
         }
-        
-        Ok(())
+
+        Ok(true)
+    }
+}
+
+/// Parse durations like `30s`, `15m`, `12h` or `7d` into seconds.
+fn parse_duration(input: &str) -> Option<u64> {
+    let unit_start = input.char_indices().last()?.0;
+    let (amount, unit) = input.split_at(unit_start);
+    let amount: u64 = amount.parse().ok()?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(multiplier)
+}
+
+/// One line describing a server notice, for known types in our own words.
+fn describe_notice(notice: &SystemNotice, display: ControlDisplay) -> String {
+    // The server passes on what others gave it, such as ids and admin text,
+    // so none of it reaches the terminal unsanitized
+    let field = |name: &str| sanitize::line(notice.field(name), display, sanitize::MAX_NAME_WIDTH);
+    match notice.notice_type.as_str() {
+        notice_type::DELIVERY_FAILED => {
+            let reason = match notice.field("reason") {
+                EXPIRED_UNDELIVERED => tr!("notice.expired_undelivered", "was not fetched before its delivery deadline"),
+                INVITE_UNCLAIMED => tr!("notice.invite_unclaimed", "nobody registered that id in time"),
+                _ => field("reason"),
+            };
+            tr!("notice.delivery_failed", "📭 Message {id} to {recipient} failed: {reason}",
+                id = field("message_id"), recipient = field("recipient_id"), reason = reason)
+        }
+        notice_type::INVITE_CLAIMED => tr!("notice.invite_claimed", "📬 {recipient} registered and received message {id}",
+            recipient = field("recipient_id"), id = field("message_id")),
+        notice_type::QUOTA_WARNING => tr!("notice.quota_warning", "📦 Your mailbox holds {used} of at most {limit} messages; receive them before new mail is refused",
+            used = field("used"), limit = field("limit")),
+        notice_type::GROUP_ADDED => tr!("notice.group_added", "👥 {by} added you to group {group}",
+            by = field("by"), group = field("group")),
+        notice_type::DISK_USAGE => tr!("notice.disk_usage", "💽 Server data uses {used} bytes, over the warning size of {threshold}",
+            used = field("used"), threshold = field("threshold")),
+        other => match &notice.text {
+            Some(text) => format!("📢 {}", sanitize::text(text, display)),
+            None => {
+                let fields: BTreeMap<&str, String> = notice.fields.keys().map(|name| (name.as_str(), field(name))).collect();
+                format!("📢 {} {:?}", sanitize::line(other, display, sanitize::MAX_NAME_WIDTH), fields)
+            }
+        },
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+/// The rest of a command line after its first `count` words, spacing intact.
+fn words_after(input: &str, count: usize) -> &str {
+    let mut rest = input.trim_start();
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
+/// Strip one pair of matching surrounding quotes.
+fn unquote(text: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|quote| text.strip_prefix(*quote).and_then(|t| t.strip_suffix(*quote)))
+        .unwrap_or(text)
+}
+
+/// Lowercased words of at least two characters, as `search` matches them.
+fn search_words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 2)
+        .map(str::to_lowercase)
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// Parse `search` arguments: words plus `--from <c>` and `--since <date>`.
+fn parse_history_search(args: &[&str]) -> Result<HistorySearch> {
+    let mut search = HistorySearch::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--from" => search.from = Some(args.next().ok_or_else(|| anyhow!("--from needs a contact"))?.to_string()),
+            "--since" => {
+                let since = args.next().ok_or_else(|| anyhow!("--since needs a date"))?;
+                // Accept a bare month like 2024-01
+                let since = if since.len() == 7 { format!("{}-01", since) } else { since.to_string() };
+                search.since = Some(parse_date(&since)?);
+            }
+            word => search.words.extend(search_words(word)),
+        }
+    }
+    if search.words.is_empty() {
+        return Err(anyhow!("Usage: search <words> [--from <contact>] [--since <date>] | search --rebuild"));
+    }
+    Ok(search)
+}
+
+/// Up to a dozen words around the first match, with matches highlighted.
+fn snippet(text: &str, words: &[String]) -> String {
+    let is_match = |token: &str| search_words(token).iter().any(|w| words.contains(w));
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let first = tokens.iter().position(|token| is_match(token)).unwrap_or(0);
+    let start = first.saturating_sub(5);
+    let end = (first + 7).min(tokens.len());
+    let mut snippet: Vec<String> = tokens[start..end].iter()
+        .map(|token| if is_match(token) { token.yellow().bold().to_string() } else { token.to_string() })
+        .collect();
+    if start > 0 {
+        snippet.insert(0, "…".to_string());
+    }
+    if end < tokens.len() {
+        snippet.push("…".to_string());
+    }
+    snippet.join(" ")
+}
+
+/// Parse `mailbox search` filters.
+/// Parse `admin batch` arguments: an optional `--best-effort`, then actions
+/// separated by `;`. Returns the actions and whether they're all-or-nothing.
+fn parse_admin_batch(text: &str) -> Result<(Vec<AdminAction>, bool)> {
+    let (text, transactional) = match text.strip_prefix("--best-effort") {
+        Some(rest) => (rest, false),
+        None => (text, true),
+    };
+    let actions = text.split(';')
+        .map(str::trim)
+        .filter(|action| !action.is_empty())
+        .map(parse_admin_action)
+        .collect::<Result<Vec<_>>>()?;
+    if actions.is_empty() {
+        return Err(anyhow!("The batch has no actions"));
+    }
+    Ok((actions, transactional))
+}
+
+fn parse_admin_action(text: &str) -> Result<AdminAction> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let rest = |count| unquote(words_after(text, count)).to_string();
+    Ok(match words.as_slice() {
+        ["ban", client_id] => AdminAction::Ban { client_id: client_id.to_string(), reason: None },
+        ["ban", client_id, ..] => AdminAction::Ban { client_id: client_id.to_string(), reason: Some(rest(2)) },
+        ["unban", client_id] => AdminAction::Unban { client_id: client_id.to_string() },
+        ["prune", client_id] => AdminAction::PruneMailbox { client_id: client_id.to_string() },
+        ["notice", "--to", recipients, _, ..] => AdminAction::Notice {
+            text: rest(3),
+            recipients: recipients.split(',').filter(|id| !id.is_empty()).map(str::to_string).collect(),
+        },
+        ["notice", _, ..] => AdminAction::Notice { text: rest(1), recipients: Vec::new() },
+        ["invite-code", code] => AdminAction::CreateInviteCode { code: code.to_string() },
+        ["drain", grace] => AdminAction::Drain {
+            grace_secs: parse_duration(grace).ok_or_else(|| anyhow!("Invalid duration; use e.g. 30s, 15m, 12h or 7d"))?,
+        },
+        _ => return Err(anyhow!("Unknown batch action: {}", text)),
+    })
+}
+
+fn parse_mailbox_search(args: &[&str]) -> Result<MessageSearch> {
+    let mut search = MessageSearch { limit: Some(10), ..Default::default() };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| anyhow!("{} needs a value", flag))?;
+        match *flag {
+            "--from" => search.from = Some(value.to_string()),
+            "--after" => search.after = Some(parse_date(value)?),
+            "--before" => search.before = Some(parse_date(value)?),
+            "--min-size" => search.min_size = Some(parse_size(value).ok_or_else(|| anyhow!("Invalid size {}", value))?),
+            "--kind" => search.kind = Some(match *value {
+                "message" => MessageKind::Message,
+                "notice" => MessageKind::Notice,
+                _ => return Err(anyhow!("Kind must be message or notice")),
+            }),
+            "--limit" => search.limit = Some(value.parse()?),
+            "--cursor" => search.cursor = Some(value.parse()?),
+            other => return Err(anyhow!("Unknown search option {}", other)),
+        }
+    }
+    Ok(search)
+}
+
+/// `[--ttl <dur>] [--max <n>] [--plain]` for `guestlink create`: the TTL in
+/// seconds, the message limit and whether to leave the owner's key out.
+fn parse_guest_link_options(options: &[&str]) -> Option<(u64, u32, bool)> {
+    let (mut ttl_secs, mut max_messages, mut plain) = (DEFAULT_GUEST_LINK_TTL_SECS, DEFAULT_GUEST_LINK_MESSAGES, false);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--ttl" => ttl_secs = parse_duration(options.next()?)?,
+            "--max" => max_messages = options.next()?.parse().ok()?,
+            "--plain" => plain = true,
+            _ => return None,
+        }
+    }
+    Some((ttl_secs, max_messages, plain))
+}
+
+/// Send one message through a guest link, with no identity of our own. When
+/// the link carries the owner's key, encrypt to it with a throwaway key.
+async fn guest_send(token: &GuestToken, text: &str) -> Result<String> {
+    let (content, ephemeral_key) = match &token.x25519_public_key {
+        Some(key) => {
+            let owner_key = x25519_from_hex(key).ok_or_else(|| anyhow!("Guest token is damaged"))?;
+            let ephemeral = CryptoManager::new();
+            let encrypted = ephemeral.encrypt_message(&owner_key, &Payload::text(text).encode()?)?;
+            (hex::encode(encrypted), Some(hex::encode(ephemeral.get_x25519_public_key().as_bytes())))
+        }
+        None => (text.to_string(), None),
+    };
+    let command = ServerCommand::GuestSend {
+        link_id: token.link_id.clone(),
+        secret: token.secret.clone(),
+        message_id: new_message_id(),
+        content,
+        ephemeral_key,
+    };
+    match request(&token.server, &command).await? {
+        ServerResponse::MessageSent { message_id, .. } => Ok(message_id),
+        ServerResponse::Error { message, code, .. } => Err(match code.as_deref() {
+            Some(error_code::GUEST_LINK_EXPIRED) => anyhow!("{}", tr!("guest.expired", "This guest link has expired")),
+            Some(error_code::GUEST_LINK_REVOKED) => anyhow!("{}", tr!("guest.revoked", "This guest link has been revoked")),
+            Some(error_code::GUEST_LINK_USED_UP) => anyhow!("{}", tr!("guest.used_up", "This guest link takes no more messages")),
+            Some(error_code::GUEST_LINK_INVALID) => anyhow!("{}", tr!("guest.invalid", "The server doesn't know this guest link")),
+            _ => anyhow!("Server error: {}", message),
+        }),
+        _ => Err(anyhow!("Unexpected response from server")),
+    }
+}
+
+fn x25519_from_hex(key: &str) -> Option<X25519PublicKey> {
+    let bytes: [u8; 32] = hex::decode(key).ok()?.try_into().ok()?;
+    Some(X25519PublicKey::from(bytes))
+}
+
+/// A date (midnight UTC) or an RFC 3339 timestamp.
+fn parse_date(input: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+    }
+    Ok(DateTime::parse_from_rfc3339(input)
+        .map_err(|_| anyhow!("Invalid date {}; use YYYY-MM-DD or RFC 3339", input))?
+        .with_timezone(&Utc))
+}
+
+/// A byte count with an optional k or m suffix.
+fn parse_size(input: &str) -> Option<usize> {
+    let lower = input.to_lowercase();
+    let lower = lower.trim_end_matches('b');
+    let (amount, multiplier) = match lower.strip_suffix('k') {
+        Some(amount) => (amount, 1024),
+        None => match lower.strip_suffix('m') {
+            Some(amount) => (amount, 1024 * 1024),
+            None => (lower, 1),
+        },
+    };
+    amount.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+impl ServerConnection {
+    fn addr(&self) -> &str {
+        &self.addrs[self.preferred.load(Ordering::Relaxed)]
+    }
+
+    /// Send one command over the session's connection, dialing again first
+    /// if it dropped. Commands on one server go one at a time. A connection
+    /// that fails partway through a command is closed and the command is not
+    /// retried, since the server may already have acted on it. The redialed
+    /// connection isn't registered, so pushes stop until the next start.
+    /// A draining server isn't redialed before the deadline it gave.
+    async fn request(&self, command: &ServerCommand) -> Result<ServerResponse> {
+        let mut stream = self.stream.lock().await;
+        if stream.as_mut().is_some_and(Link::gone) {
+            *stream = None;
+        }
+        let open = match stream.as_mut() {
+            Some(open) => open,
+            None => {
+                if let Some(deadline) = self.going_away.pending() {
+                    return Err(anyhow!("{}", tr!("server.restarting", "{addr} is restarting; try again after {time}",
+                        addr = self.addr(), time = deadline.format("%H:%M:%S UTC"))));
+                }
+                let attempt = self.redials.fetch_add(1, Ordering::Relaxed) + 1;
+                self.events.send(|server| ClientEvent::Reconnecting { server, attempt });
+                let dialed = self.dial().await;
+                telemetry::reconnected(dialed.is_ok());
+                let dialed = dialed?;
+                self.redials.store(0, Ordering::Relaxed);
+                self.events.send(|server| ClientEvent::Connected { server, info: self.info() });
+                stream.insert(Link::Plain(dialed))
+            }
+        };
+        let started = std::time::Instant::now();
+        let exchanged = match open.exchange(command, &self.going_away, &self.events).await {
+            Ok(ServerResponse::Challenge { nonce }) => {
+                let answer = self.crypto.answer_challenge(command.client_id().unwrap_or_default(), &nonce);
+                open.exchange(&command.clone().answering(answer), &self.going_away, &self.events).await
+            }
+            exchanged => exchanged,
+        };
+        telemetry::request_took(command.name(), started.elapsed());
+        match exchanged {
+            Ok(response) => Ok(response),
+            // A response that doesn't parse still arrived whole, so the connection is fine
+            Err(e) if e.is::<serde_json::Error>() => Err(e),
+            Err(e) => {
+                // A pushed link's reader reports its own drop, after what came before it
+                if let Some(Link::Plain(_)) = stream.as_ref() {
+                    let reason = e.to_string();
+                    self.events.send(|server| ClientEvent::Disconnected { server, reason });
+                }
+                *stream = None;
+                Err(anyhow!("Lost the connection to {} ({}); the next command reconnects", self.addr(), e))
+            }
+        }
+    }
+
+    /// Connect to the address that last answered, if it answers with the
+    /// key the server registered with. If it can't be reached, race all of
+    /// the server's addresses and stick with the winner.
+    async fn dial(&self) -> Result<transport::Connection> {
+        match dial::attempt(self.connector.as_ref(), self.addr()).await {
+            Ok((stream, key)) if key == self.server_pubkey => Ok(stream),
+            Ok((_, key)) => {
+                let (expected, actual) = (crypto::fingerprint(self.server_pubkey.as_bytes()), crypto::fingerprint(key.as_bytes()));
+                self.events.send(|server| ClientEvent::KeyMismatch { server, expected: expected.clone(), actual: actual.clone() });
+                Err(anyhow!("{} answered with server key {}, expected {}", self.addr(), actual, expected))
+            }
+            Err(e) if self.addrs.len() == 1 => Err(e),
+            Err(e) => {
+                info!("🔗 {} is unreachable ({}), trying the server's other addresses", self.addr(), e);
+                let dialed = dial::race(&self.connector, &self.addrs, Some(&self.server_pubkey)).await?;
+                self.preferred.store(dialed.index, Ordering::Relaxed);
+                Ok(dialed.stream)
+            }
+        }
+    }
+
+    /// The server as a connection dialed again finds it: as registered,
+    /// but without pushes.
+    fn info(&self) -> ServerInfo {
+        ServerInfo {
+            addr: self.addr().to_string(),
+            fingerprint: crypto::fingerprint(self.server_pubkey.as_bytes()),
+            protocol_version: self.features.protocol,
+            push_delivery: false,
+            max_message_size: self.max_message_size,
+        }
+    }
+}
+
+impl Link {
+    /// Hand `stream`'s read half to a task that sorts what the server sends:
+    /// answers come back to `exchange`; pushes, a GoAway and finally the
+    /// connection dropping go to `events`, in the order they happened.
+    fn pushed(stream: transport::Connection, events: ServerEvents, going_away: GoingAway) -> Link {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let (answer, answers) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let reason = loop {
+                let body = match frame::read_frame(&mut reader, frame::DEFAULT_MAX_FRAME_BYTES).await {
+                    Ok(Some(body)) => body,
+                    Ok(None) => break "the server closed it".to_string(),
+                    Err(e) => {
+                        let reason = e.to_string();
+                        let _ = answer.send(Err(e.into()));
+                        break reason;
+                    }
+                };
+                match serde_json::from_slice(&body) {
+                    Ok(ServerResponse::Incoming { message }) => {
+                        debug!("📲 {} pushed message {}", events.server, message.id);
+                        events.send(|server| ClientEvent::Pushed { server });
+                    }
+                    Ok(ServerResponse::GoAway { deadline }) => {
+                        info!("🚧 {} is draining until {}", events.server, deadline);
+                        going_away.heard(deadline, &events);
+                    }
+                    // Anything else answers the command in hand; `exchange` parses it
+                    _ => {
+                        if answer.send(Ok(body)).is_err() {
+                            // The link was dropped, and with it the connection
+                            return;
+                        }
+                    }
+                }
+            };
+            events.send(|server| ClientEvent::Disconnected { server, reason });
+            events.send(|server| ClientEvent::SessionExpired { server });
+        });
+        Link::Pushed { writer, answers }
+    }
+
+    /// Whether the connection is known to have dropped: a pushed link's
+    /// reader has stopped. A plain one only finds out by being used.
+    fn gone(&mut self) -> bool {
+        match self {
+            Link::Plain(_) => false,
+            Link::Pushed { answers, .. } => matches!(answers.try_recv(), Err(TryRecvError::Disconnected) | Ok(Err(_))),
+        }
+    }
+
+    /// Send one command and read back the response, skipping pushes and
+    /// noting a GoAway.
+    async fn exchange(&mut self, command: &ServerCommand, going_away: &GoingAway, events: &ServerEvents) -> Result<ServerResponse> {
+        match self {
+            Link::Plain(stream) => {
+                let request = serde_json::to_string(command)?;
+                frame::write_frame(stream, request.as_bytes(), frame::DEFAULT_MAX_FRAME_BYTES).await?;
+                loop {
+                    let response = frame::read_frame(stream, frame::DEFAULT_MAX_FRAME_BYTES).await?
+                        .ok_or_else(|| anyhow!("The server closed the connection without answering"))?;
+                    match serde_json::from_slice(&response)? {
+                        ServerResponse::GoAway { deadline } => going_away.heard(deadline, events),
+                        response => return Ok(response),
+                    }
+                }
+            }
+            Link::Pushed { writer, answers } => {
+                let request = serde_json::to_string(command)?;
+                frame::write_frame(writer, request.as_bytes(), frame::DEFAULT_MAX_FRAME_BYTES).await?;
+                let response = answers.recv().await
+                    .ok_or_else(|| anyhow!("The server closed the connection without answering"))??;
+                Ok(serde_json::from_slice(&response)?)
+            }
+        }
+    }
+}
+
+/// Whether a grant is active, expired or revoked.
+fn delegation_state(delegation: &Delegation, now: DateTime<Utc>) -> String {
+    if delegation.revoked_at.is_some() {
+        tr!("delegate.state_revoked", "revoked")
+    } else if delegation.expires_at <= now {
+        tr!("delegate.state_expired", "expired")
+    } else {
+        tr!("delegate.state_active", "active")
+    }
+}
+
+/// The identity kept in `path`, or a new one written there on first use.
+/// A key file others can read is refused unless `allow_insecure`.
+fn load_identity(path: &Path, allow_insecure: bool) -> Result<CryptoManager> {
+    if path.exists() {
+        secure_fs::check_private(path, allow_insecure)?;
+        return CryptoManager::load_from_file(path)
+            .map_err(|e| anyhow!("Failed to read key file {}: {}", path.display(), e));
+    }
+    let crypto = CryptoManager::new();
+    crypto.save_to_file(path)
+        .map_err(|e| anyhow!("Failed to write key file {}: {}", path.display(), e))?;
+    note!("{}", tr!("startup.new_identity", "🔑 Generated a new identity in {path}", path = path.display()));
+    Ok(crypto)
+}
+
+/// Ask whether to register with a server this identity isn't bound to. Without
+/// a terminal to ask on, the answer is no.
+fn confirm_new_server(addr: &str, fingerprint: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!("{} ", tr!("bind.confirm", "{addr} has server key {fingerprint}, which this identity has never registered with. Register anyway? [y/N]",
+        addr = addr, fingerprint = fingerprint));
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes" | "s" | "sí" | "si"))
+}
+
+/// Why `id` could be mistaken for someone else: it looks the same as a
+/// known id, or mixes alphabets.
+fn lookalike_warning(lookalikes: &Lookalikes, id: &str) -> Option<String> {
+    if let Some(other) = lookalikes.of(id) {
+        return Some(tr!("lookalike.collides", "{id} looks the same as {other}", id = id, other = other));
+    }
+    confusables::is_mixed_script(id)
+        .then(|| tr!("lookalike.mixed", "{id} mixes letters from different alphabets", id = id))
+}
+
+/// Warn that a recipient looks like someone else and ask whether to send
+/// anyway. Without a terminal to ask on, the answer is no.
+fn confirm_lookalike(warning: &str) -> Result<bool> {
+    out!("{}", format!("⚠️ {}", warning).red().bold());
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!("{} ", tr!("lookalike.confirm", "Send to this id anyway? [y/N]"));
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes" | "s" | "sí" | "si"))
+}
+
+/// Send one command over a fresh connection and read back the response.
+async fn request(addr: &str, command: &ServerCommand) -> Result<ServerResponse> {
+    let mut stream = TcpStream::connect(addr).await?;
+    exchange(&mut stream, command).await
+}
+
+/// Send one command over a fresh connection, answering a challenge for it
+/// with `crypto`.
+async fn request_signed(addr: &str, command: &ServerCommand, crypto: &CryptoManager) -> Result<ServerResponse> {
+    let mut stream = TcpStream::connect(addr).await?;
+    exchange_signed(&mut stream, command, crypto).await
+}
+
+/// Send one command on an open connection and read back the response.
+async fn exchange(stream: &mut (impl AsyncRead + AsyncWrite + Unpin), command: &ServerCommand) -> Result<ServerResponse> {
+    let request = serde_json::to_string(command)?;
+    frame::write_frame(stream, request.as_bytes(), frame::DEFAULT_MAX_FRAME_BYTES).await?;
+    
+    let response = frame::read_frame(stream, frame::DEFAULT_MAX_FRAME_BYTES).await?
+        .ok_or_else(|| anyhow!("The server closed the connection without answering"))?;
+    
+    Ok(serde_json::from_slice(&response)?)
+}
+
+/// Like `exchange`, but a challenge for the command is answered with
+/// `crypto`'s signature and the command sent again.
+async fn exchange_signed(stream: &mut (impl AsyncRead + AsyncWrite + Unpin), command: &ServerCommand, crypto: &CryptoManager) -> Result<ServerResponse> {
+    match exchange(stream, command).await? {
+        ServerResponse::Challenge { nonce } => {
+            let answer = crypto.answer_challenge(command.client_id().unwrap_or_default(), &nonce);
+            exchange(stream, &command.clone().answering(answer)).await
+        }
+        response => Ok(response),
+    }
+}
+
+/// `client [<id>] [flags]`, or one of its subcommands, given the whole
+/// command line.
+pub async fn main(mut args: Vec<String>) -> Result<()> {
+    if args.get(1).map(String::as_str) == Some("bench") {
+        return bench::run(&args[2..]).await;
+    }
+
+    let home = match args.iter().position(|arg| arg == "--home") {
+        Some(index) if index + 1 < args.len() => Some(args.drain(index..=index + 1).nth(1).unwrap_or_default()),
+        Some(_) => return Err(anyhow!("--home needs a directory")),
+        None => None,
+    };
+    let lang = match args.iter().position(|arg| arg == i18n::LANG_FLAG) {
+        Some(index) if index + 1 < args.len() => Some(args.drain(index..=index + 1).nth(1).unwrap_or_default()),
+        Some(_) => return Err(anyhow!("{} needs a language code", i18n::LANG_FLAG)),
+        None => None,
+    };
+    i18n::init(lang.as_deref());
+    let output_mode = match args.iter().position(|arg| arg == output::OUTPUT_FLAG) {
+        Some(index) if index + 1 < args.len() => Some(args.drain(index..=index + 1).nth(1).unwrap_or_default().parse::<OutputMode>()?),
+        Some(_) => return Err(anyhow!("{} needs a mode", output::OUTPUT_FLAG)),
+        None => None,
+    };
+    output::init(output_mode);
+    let mut allow_insecure_permissions = match args.iter().position(|arg| arg == secure_fs::INSECURE_PERMISSIONS_FLAG) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+    if let Some(index) = args.iter().position(|arg| arg == FORCE_FLAG) {
+        args.remove(index);
+        allow_insecure_permissions = true;
+    }
+    let key_file = match args.iter().position(|arg| arg == KEY_FILE_FLAG) {
+        Some(index) if index + 1 < args.len() => Some(PathBuf::from(args.drain(index..=index + 1).nth(1).unwrap_or_default())),
+        Some(_) => return Err(anyhow!("{} needs a path", KEY_FILE_FLAG)),
+        None => None,
+    };
+    let allow_new_server = match args.iter().position(|arg| arg == ALLOW_NEW_SERVER_FLAG) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+    let tui = match args.iter().position(|arg| arg == TUI_FLAG) {
+        Some(_) if !cfg!(feature = "tui") => return Err(anyhow!("This client was built without {}; rebuild it with --features tui", TUI_FLAG)),
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+    let show_invalid = match args.iter().position(|arg| arg == SHOW_INVALID_FLAG) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+    let credential = match args.iter().position(|arg| arg == CREDENTIAL_FLAG) {
+        Some(index) if index + 1 < args.len() => Some(args.drain(index..=index + 1).nth(1).unwrap_or_default()),
+        Some(_) => return Err(anyhow!("{} needs a code or token", CREDENTIAL_FLAG)),
+        None => None,
+    };
+    // `setup` takes its own, to save as the default
+    let server_addr = match args.iter().position(|arg| arg == SERVER_FLAG) {
+        _ if args.get(1).map(String::as_str) == Some("setup") => None,
+        Some(index) if index + 1 < args.len() => Some(args.drain(index..=index + 1).nth(1).unwrap_or_default()),
+        Some(_) => return Err(anyhow!("{} needs an address", SERVER_FLAG)),
+        None => None,
+    };
+    if args.get(1).map(String::as_str) == Some("send") && args.get(2).map(String::as_str) == Some(GUEST_TOKEN_FLAG) {
+        let token: GuestToken = args.get(3).ok_or_else(|| anyhow!("{} needs a token", GUEST_TOKEN_FLAG))?.parse()?;
+        let text = args.get(4..).unwrap_or_default().join(" ");
+        if text.is_empty() {
+            return Err(anyhow!("Usage: client send {} <token> <message>", GUEST_TOKEN_FLAG));
+        }
+        let message_id = guest_send(&token, &text).await?;
+        say!("guest.sent", "✅ Message sent to {owner} ({id})", owner = token.owner, id = message_id);
+        return Ok(());
+    }
+    // Taken out before the id is read, so it can come with or without one
+    let presence_watch = match args.iter().position(|arg| arg == PRESENCE_WATCH) {
+        Some(index) if index <= 2 => {
+            let watched = args.drain(index..).nth(1)
+                .ok_or_else(|| anyhow!("Usage: client [<id>] {} <contact,contact...|--all>", PRESENCE_WATCH))?;
+            Some(if watched == "--all" { Vec::new() } else { watched.split(',').filter(|id| !id.is_empty()).map(str::to_string).collect() })
+        }
+        _ => None,
+    };
+    let paths = ClientPaths::resolve(home.as_deref())?;
+    if args.get(1).map(String::as_str) == Some("paths") {
+        paths.print(args.get(2).map(String::as_str));
+        return Ok(());
+    }
     
-    let args: Vec<String> = std::env::args().collect();
-    let default_name = "anonymous".to_string();
-    let client_id = args.get(1).unwrap_or(&default_name);
+    let first_run = paths.create()?;
+    let profile = setup::Profile::load(&paths.profile_file())?;
+    let client_id = match args.get(1).map(String::as_str) {
+        Some("setup") => setup::run(&args[2..], &paths).await?,
+        Some(client_id) => client_id.to_string(),
+        None => match profile {
+            Some(setup::Profile { client_id: Some(client_id), completed: true, .. }) => client_id,
+            // A first run, or setup was interrupted: walk through it
+            _ if io::stdin().is_terminal() => setup::run(&[], &paths).await?,
+            _ => "anonymous".to_string(),
+        },
+    };
+    let client_id = &client_id;
+
+    if first_run {
+        note!("{}", tr!("paths.created", "📁 Created {dir}", dir = paths.config_dir.display()));
+        if paths.data_dir != paths.config_dir {
+            note!("{}", tr!("paths.created", "📁 Created {dir}", dir = paths.data_dir.display()));
+        }
+        note!("{}", tr!("paths.hint", "   Run 'client paths' to see where files are kept"));
+    }
+    for moved in paths.adopt_legacy_files(client_id)? {
+        note!("{}", tr!("paths.moved", "📦 Moved {file} from the working directory", file = moved.display()));
+    }
+    paths.check_permissions(allow_insecure_permissions)?;
     
-    let mut client = Client::new(client_id);
+    let key_file = key_file.unwrap_or_else(|| paths.key_file(client_id));
+    let mut client = Client::new(client_id, paths, key_file, allow_insecure_permissions)?;
+    client.allow_new_server = allow_new_server;
+    client.show_invalid = show_invalid;
+    client.credential = credential;
+    client.server_addr = server_addr;
+    // Only the prompt waits on pushes
+    if !tui {
+        client.offer_push_delivery();
+    }
     
-    println!("🔐 Secure Messaging Client");
-    println!("==========================");
-    println!("Client ID: {}", client_id.green());
-    println!("Public Key: {}", hex::encode(client.crypto.get_ed25519_public_key().as_bytes()).yellow());
-    println!("X25519 Key: {}", hex::encode(client.crypto.get_x25519_public_key().as_bytes()).cyan());
+    note!("{}", tr!("startup.title", "🔐 Secure Messaging Client"));
+    note!("==========================");
+    note!("{}", tr!("startup.client_id", "Client ID: {id}", id = client_id.green()));
+    note!("{}", tr!("startup.public_key", "Public Key: {key}", key = hex::encode(client.crypto.get_ed25519_public_key().as_bytes()).yellow()));
+    note!("{}", tr!("startup.x25519_key", "X25519 Key: {key}", key = hex::encode(client.crypto.get_x25519_public_key().as_bytes()).cyan()));
     
-    // Connect to server
-    match client.connect("127.0.0.1:8080").await {
+    if let Some(contacts) = presence_watch {
+        client.connect_presence_only().await?;
+        return client.watch_presence(contacts).await;
+    }
+
+    // Connect to the default server and any configured profiles
+    match client.connect_all().await {
         Ok(_) => {
-            println!("✅ Connected to server successfully!");
+            client.show_events(None).await?;
             
             // Start interactive mode
-            client.interactive_mode("127.0.0.1:8080").await?;
+            if tui {
+                #[cfg(feature = "tui")]
+                tui::run(&mut client).await?;
+            } else {
+                client.interactive_mode().await?;
+            }
         }
         Err(e) => {
             error!("❌ Failed to connect to server: {}", e);
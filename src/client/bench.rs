@@ -0,0 +1,231 @@
+use crate::crypto::{self, CryptoManager};
+use crate::types::{ServerCommand, ServerResponse, new_message_id, send_payload};
+use crate::i18n::say;
+use crate::output::out;
+use super::{parse_duration, request_signed, DEFAULT_SERVER_ADDR};
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+struct BenchOptions {
+    server: String,
+    clients: usize,
+    rate: u32,
+    duration: Duration,
+    size: usize,
+}
+
+impl BenchOptions {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self {
+            server: DEFAULT_SERVER_ADDR.to_string(),
+            clients: 10,
+            rate: 10,
+            duration: Duration::from_secs(10),
+            size: 256,
+        };
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| anyhow!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--server" => options.server = value.clone(),
+                "--clients" => options.clients = value.parse()?,
+                "--rate" => options.rate = value.parse()?,
+                "--duration" => options.duration = Duration::from_secs(
+                    parse_duration(value).ok_or_else(|| anyhow!("Invalid duration {}", value))?),
+                "--size" => options.size = value.parse()?,
+                other => return Err(anyhow!("Unknown bench option {}", other)),
+            }
+        }
+        if options.clients < 2 || options.rate == 0 {
+            return Err(anyhow!("bench needs at least 2 clients and a rate above 0"));
+        }
+        Ok(options)
+    }
+}
+
+struct Identity {
+    id: String,
+    crypto: Arc<CryptoManager>,
+}
+
+/// Outcome of one request: operation, latency, and the error if it failed.
+type Sample = (&'static str, Duration, Option<String>);
+
+/// `client bench`: register synthetic identities against a server, drive
+/// sends and receives at a fixed rate, and report throughput, latency
+/// percentiles, errors and delivery.
+pub async fn run(args: &[String]) -> Result<()> {
+    let options = BenchOptions::parse(args)?;
+    let run_id = &uuid::Uuid::new_v4().simple().to_string()[..8];
+    say!("bench.start", "🏋️ Benchmarking {server} with {clients} clients at {rate} msg/s for {secs}s ({size} byte messages)",
+        server = options.server, clients = options.clients, rate = options.rate, secs = options.duration.as_secs(), size = options.size);
+
+    let identities: Arc<Vec<Identity>> = Arc::new((0..options.clients)
+        .map(|i| Identity { id: format!("bench-{}-{}", run_id, i), crypto: Arc::new(CryptoManager::new()) })
+        .collect());
+
+    let mut samples = Vec::new();
+    let registrations: Vec<_> = identities.iter()
+        .map(|identity| ServerCommand::Register {
+            client_id: identity.id.clone(),
+            public_key: hex::encode(identity.crypto.get_ed25519_public_key().as_bytes()),
+            recovery_key: None,
+            protocol_version: None,
+            features: None,
+            credential: None,
+            x25519_public_key: None,
+            x25519_signature: None,
+            challenge: None,
+        })
+        .zip(identities.iter())
+        .map(|(command, identity)| spawn_request(&options.server, "register", command, &identity.crypto, |_| None))
+        .collect();
+    for handle in registrations {
+        samples.push(handle.await?);
+    }
+
+    // recipient index -> ids the server acknowledged
+    let mut acked: HashMap<usize, HashSet<String>> = HashMap::new();
+    let mut sends: Vec<(usize, String, JoinHandle<Sample>)> = Vec::new();
+    let mut receives = Vec::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / options.rate as f64));
+    let started = Instant::now();
+    let mut tick = 0usize;
+
+    while started.elapsed() < options.duration {
+        ticker.tick().await;
+        let sender = tick % options.clients;
+        let recipient = (tick + 1) % options.clients;
+        let command = send_command(&identities, sender, recipient, options.size)?;
+        let message_id = match &command {
+            ServerCommand::Send { message_id, .. } => message_id.clone(),
+            _ => unreachable!(),
+        };
+        sends.push((recipient, message_id, spawn_request(&options.server, "send", command, &identities[sender].crypto, |_| None)));
+
+        let reader = tick % options.clients;
+        receives.push(spawn_request(&options.server, "receive",
+            ServerCommand::GetMessages { client_id: identities[reader].id.clone(), since: None, on_behalf_of: None, signature: None, challenge: None },
+            &identities[reader].crypto, |_| None));
+        tick += 1;
+    }
+    let elapsed = started.elapsed();
+
+    for (recipient, message_id, handle) in sends {
+        let sample = handle.await?;
+        if sample.2.is_none() {
+            acked.entry(recipient).or_default().insert(message_id);
+        }
+        samples.push(sample);
+    }
+    for handle in receives {
+        samples.push(handle.await?);
+    }
+
+    // Every acknowledged message must still be in its mailbox
+    let mut delivered = 0;
+    let mut missing = Vec::new();
+    for (recipient, ids) in &acked {
+        let command = ServerCommand::GetMessages { client_id: identities[*recipient].id.clone(), since: None, on_behalf_of: None, signature: None, challenge: None };
+        match request_signed(&options.server, &command, &identities[*recipient].crypto).await {
+            Ok(ServerResponse::Messages { messages }) if ids.iter().all(|id| messages.iter().any(|m| m.id == *id)) => delivered += 1,
+            _ => missing.push(identities[*recipient].id.clone()),
+        }
+    }
+
+    report(&samples, elapsed);
+    if missing.is_empty() {
+        say!("bench.delivered", "📬 Delivery: all {count} mailboxes hold every message sent to them", count = delivered);
+    } else {
+        say!("bench.missing", "🚨 Delivery: {missing} of {count} mailboxes are missing their messages: {ids}",
+            missing = missing.len(), count = acked.len(), ids = missing.join(", "));
+    }
+    // Registrations persist on the server; there is no unregister command yet
+    say!("bench.leftover", "🧹 Synthetic identities bench-{run}-* remain registered", run = run_id);
+    Ok(())
+}
+
+fn send_command(identities: &[Identity], sender: usize, recipient: usize, size: usize) -> Result<ServerCommand> {
+    let sender = &identities[sender];
+    let recipient = &identities[recipient];
+    let body: String = (0..size).map(|_| (b'a' + rand::random::<u8>() % 26) as char).collect();
+    let encrypted = sender.crypto.encrypt_message(&recipient.crypto.get_x25519_public_key(), &body)?;
+    let message_id = new_message_id();
+    let sent_at = Utc::now();
+    Ok(ServerCommand::Send {
+        sender_id: sender.id.clone(),
+        recipient_id: recipient.id.clone(),
+        signature: hex::encode(sender.crypto.sign_with_context(crypto::context::SEND, &send_payload(&message_id, &recipient.id, &encrypted, sent_at)).to_bytes()),
+        encrypted_content: hex::encode(encrypted),
+        message_id,
+        ttl_secs: None,
+        deliver_by: None,
+        sender_key: None,
+        seq: None,
+        on_behalf_of: None,
+        supersedes: None,
+        sent_at: Some(sent_at),
+        group: None,
+    })
+}
+
+/// Run a request in the background, answering a challenge as `signer`.
+/// `expected` may turn an error response into a success by returning
+/// `Some(None)`.
+fn spawn_request(
+    addr: &str,
+    operation: &'static str,
+    command: ServerCommand,
+    signer: &Arc<CryptoManager>,
+    expected: fn(&ServerResponse) -> Option<Option<String>>,
+) -> JoinHandle<Sample> {
+    let addr = addr.to_string();
+    let signer = Arc::clone(signer);
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let outcome = match request_signed(&addr, &command, &signer).await {
+            Ok(response) => match expected(&response) {
+                Some(outcome) => outcome,
+                None => match response {
+                    ServerResponse::Error { message, .. } => Some(message),
+                    _ => None,
+                },
+            },
+            Err(e) => Some(format!("transport: {}", e)),
+        };
+        (operation, started.elapsed(), outcome)
+    })
+}
+
+fn report(samples: &[Sample], elapsed: Duration) {
+    let mut latencies: BTreeMap<&str, Vec<Duration>> = BTreeMap::new();
+    let mut errors: BTreeMap<String, usize> = BTreeMap::new();
+    for (operation, latency, error) in samples {
+        match error {
+            None => latencies.entry(operation).or_default().push(*latency),
+            Some(message) => *errors.entry(format!("{}: {}", operation, message)).or_default() += 1,
+        }
+    }
+
+    let sent = latencies.get("send").map(Vec::len).unwrap_or(0);
+    say!("bench.sent", "📊 {sent} messages sent in {secs}s ({rate} msg/s)", sent = sent,
+        secs = format!("{:.1}", elapsed.as_secs_f64()), rate = format!("{:.1}", sent as f64 / elapsed.as_secs_f64()));
+    for (operation, values) in &mut latencies {
+        values.sort();
+        let percentile = |p: usize| values[(values.len() * p / 100).min(values.len() - 1)].as_secs_f64() * 1000.0;
+        out!("  {:<9} n={:<6} p50={:.1}ms p90={:.1}ms p99={:.1}ms max={:.1}ms",
+            operation, values.len(), percentile(50), percentile(90), percentile(99), percentile(100));
+    }
+    if errors.is_empty() {
+        say!("bench.no_errors", "✅ No errors");
+    } else {
+        say!("bench.errors", "❌ Errors:");
+        for (error, count) in errors {
+            out!("  {:>6} × {}", count, error);
+        }
+    }
+}
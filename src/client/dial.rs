@@ -0,0 +1,94 @@
+use crate::crypto;
+use crate::transport::{self, Connector};
+use super::exchange;
+use crate::types::{ServerCommand, ServerResponse};
+use anyhow::{Result, anyhow};
+use ed25519_dalek::PublicKey;
+use log::debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// How long an attempt gets the head start before the next address is tried
+/// alongside it.
+pub const ATTEMPT_STAGGER: Duration = Duration::from_millis(250);
+/// An address that hasn't answered by now counts as failed.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection that won the race: which address, and the server key it
+/// answered with.
+pub struct Dialed {
+    pub index: usize,
+    pub stream: transport::Connection,
+    pub server_key: PublicKey,
+}
+
+/// Connect to whichever of `addrs` answers first, happy-eyeballs style:
+/// attempts start in order, each one `ATTEMPT_STAGGER` after the last or as
+/// soon as the last fails. An attempt only wins once the server has answered
+/// with its key, and with `expected` set, only with that key. The losers are
+/// cancelled. When every address fails, the error lists each one's reason.
+pub async fn race(connector: &Arc<dyn Connector>, addrs: &[String], expected: Option<&PublicKey>) -> Result<Dialed> {
+    if addrs.is_empty() {
+        return Err(anyhow!("No server address configured"));
+    }
+    let mut attempts = JoinSet::new();
+    let mut failures: Vec<Option<String>> = vec![None; addrs.len()];
+    let mut next = 0;
+    loop {
+        if next < addrs.len() {
+            let addr = addrs[next].clone();
+            let (index, connector) = (next, Arc::clone(connector));
+            attempts.spawn(async move { (index, attempt(connector.as_ref(), &addr).await) });
+            next += 1;
+        }
+        if attempts.is_empty() {
+            break;
+        }
+        tokio::select! {
+            Some(joined) = attempts.join_next() => {
+                let (index, outcome) = joined?;
+                let failure = match outcome {
+                    Ok((stream, server_key)) => match expected {
+                        Some(expected) if *expected != server_key => format!("answered with server key {}, expected {}",
+                            crypto::fingerprint(server_key.as_bytes()), crypto::fingerprint(expected.as_bytes())),
+                        _ => {
+                            debug!("🔗 {} answered first of {} address(es)", addrs[index], addrs.len());
+                            attempts.abort_all();
+                            return Ok(Dialed { index, stream, server_key });
+                        }
+                    },
+                    Err(e) => e.to_string(),
+                };
+                debug!("🔗 {} failed: {}", addrs[index], failure);
+                failures[index] = Some(failure);
+            }
+            _ = tokio::time::sleep(ATTEMPT_STAGGER), if next < addrs.len() => {}
+        }
+    }
+
+    if addrs.len() == 1 {
+        return Err(anyhow!("{}", failures[0].take().unwrap_or_default()));
+    }
+    let reasons: Vec<String> = addrs.iter().zip(failures)
+        .map(|(addr, failure)| format!("{}: {}", addr, failure.unwrap_or_default()))
+        .collect();
+    Err(anyhow!("All {} addresses failed ({})", addrs.len(), reasons.join("; ")))
+}
+
+/// Connect to `addr` and ask for the server's key, giving up after
+/// `ATTEMPT_TIMEOUT`.
+pub async fn attempt(connector: &dyn Connector, addr: &str) -> Result<(transport::Connection, PublicKey)> {
+    let answer = async {
+        let mut stream = connector.connect(addr).await?;
+        match exchange(&mut stream, &ServerCommand::GetServerKey).await? {
+            ServerResponse::ServerKey { server_public_key } => {
+                let key = PublicKey::from_bytes(&hex::decode(&server_public_key)?)?;
+                Ok((stream, key))
+            }
+            _ => Err(anyhow!("Unexpected response from server")),
+        }
+    };
+    tokio::time::timeout(ATTEMPT_TIMEOUT, answer).await
+        .map_err(|_| anyhow!("no answer within {}s", ATTEMPT_TIMEOUT.as_secs()))?
+}
@@ -0,0 +1,169 @@
+use crate::config::{ClientConfig, ServerProfile};
+use crate::crypto;
+use crate::i18n::{say, tr};
+use crate::output::note;
+use crate::paths::ClientPaths;
+use crate::secure_fs;
+use crate::types::{ServerCommand, ServerResponse};
+use super::{request, DEFAULT_SERVER, DEFAULT_SERVER_ADDR};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+
+/// Take every answer from flags and never prompt.
+pub const NON_INTERACTIVE_FLAG: &str = "--non-interactive";
+const MAX_CLIENT_ID_LEN: usize = 64;
+
+/// What first-run setup has settled so far. Written after every step, so an
+/// interrupted setup picks up where it stopped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub server: Option<String>,
+    /// Server key fingerprint seen when the address was checked.
+    #[serde(default)]
+    pub server_fingerprint: Option<String>,
+    #[serde(default)]
+    pub completed: bool,
+}
+
+impl Profile {
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        secure_fs::write_private(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+struct SetupOptions {
+    non_interactive: bool,
+    client_id: Option<String>,
+    server: Option<String>,
+}
+
+impl SetupOptions {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self { non_interactive: false, client_id: None, server: None };
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                NON_INTERACTIVE_FLAG => options.non_interactive = true,
+                "--id" => options.client_id = Some(args.next().ok_or_else(|| anyhow!("--id needs a client id"))?.clone()),
+                "--server" => options.server = Some(args.next().ok_or_else(|| anyhow!("--server needs an address"))?.clone()),
+                other => return Err(anyhow!("Unknown setup option: {}", other)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Ids show up in `id@server` targets and conversation names, so they can't
+/// hold the characters those use.
+pub fn validate_client_id(id: &str) -> Result<()> {
+    if id.is_empty() || id.len() > MAX_CLIENT_ID_LEN {
+        return Err(anyhow!("Client ids are 1 to {} characters", MAX_CLIENT_ID_LEN));
+    }
+    if id.starts_with(['#', '@']) || id.contains(|c: char| c.is_whitespace() || c == '@' || c == ':') {
+        return Err(anyhow!("Client ids can't contain spaces, '@' or ':', or start with '#'"));
+    }
+    Ok(())
+}
+
+/// Walk a new user through picking an id and a server, resuming an earlier,
+/// interrupted run. Returns the client id to start with; registering happens
+/// when the client connects, as on every start.
+pub async fn run(args: &[String], paths: &ClientPaths) -> Result<String> {
+    let options = SetupOptions::parse(args)?;
+    let interactive = !options.non_interactive && io::stdin().is_terminal();
+    let profile_path = paths.profile_file();
+    let mut profile = Profile::load(&profile_path)?.unwrap_or_default();
+    if profile.client_id.is_some() && !profile.completed {
+        say!("setup.resuming", "↩️ Resuming setup where it stopped");
+    } else {
+        say!("setup.welcome", "👋 Welcome! A few questions to set up this client.");
+    }
+
+    // 1. Client id
+    let client_id = loop {
+        let answer = match options.client_id.clone() {
+            Some(id) => id,
+            None if interactive => prompt(&tr!("setup.ask_id", "Client id (what others send to)"), profile.client_id.as_deref())?,
+            None => profile.client_id.clone().ok_or_else(|| anyhow!("{} needs --id", NON_INTERACTIVE_FLAG))?,
+        };
+        match validate_client_id(&answer) {
+            Ok(()) => break answer,
+            Err(e) if interactive && options.client_id.is_none() => say!("setup.invalid", "❌ {error}", error = e),
+            Err(e) => return Err(e),
+        }
+    };
+    profile.client_id = Some(client_id.clone());
+    profile.save(&profile_path)?;
+
+    // 2. Server, checked by asking for its key
+    let (server, fingerprint) = loop {
+        let answer = match options.server.clone() {
+            Some(server) => server,
+            None if interactive => prompt(&tr!("setup.ask_server", "Server address"),
+                Some(profile.server.as_deref().unwrap_or(DEFAULT_SERVER_ADDR)))?,
+            None => profile.server.clone().unwrap_or_else(|| DEFAULT_SERVER_ADDR.to_string()),
+        };
+        match server_fingerprint(&answer).await {
+            Ok(fingerprint) => break (answer, fingerprint),
+            Err(e) if interactive && options.server.is_none() => {
+                say!("setup.unreachable", "❌ Could not reach {server}: {error}", server = answer, error = e);
+            }
+            Err(e) => return Err(anyhow!("Could not reach {}: {}", answer, e)),
+        }
+    };
+    say!("setup.server_key", "🔑 {server} has server key {fingerprint}", server = server, fingerprint = fingerprint);
+    profile.server = Some(server.clone());
+    profile.server_fingerprint = Some(fingerprint);
+    profile.save(&profile_path)?;
+
+    // 3. Point the identity's default server there
+    let config_path = paths.config_file(&client_id);
+    let config_path = config_path.to_string_lossy();
+    let mut config = ClientConfig::load(&config_path)?;
+    config.servers.insert(DEFAULT_SERVER.to_string(), ServerProfile { addr: server, fallback_addrs: Vec::new(), separate_identity: false });
+    config.save(&config_path)?;
+
+    profile.completed = true;
+    profile.save(&profile_path)?;
+    say!("setup.done", "✅ Setup done. From now on, 'client' on its own starts as {id}.", id = client_id);
+    note!("{}", tr!("setup.next", "   Share your key fingerprint from 'whoami' with contacts so they can check it."));
+    Ok(client_id)
+}
+
+async fn server_fingerprint(addr: &str) -> Result<String> {
+    match request(addr, &ServerCommand::GetServerKey).await? {
+        ServerResponse::ServerKey { server_public_key } => Ok(crypto::fingerprint(&hex::decode(server_public_key)?)),
+        _ => Err(anyhow!("Unexpected response from server")),
+    }
+}
+
+/// Ask a question on the terminal; an empty answer takes the default.
+fn prompt(question: &str, default: Option<&str>) -> Result<String> {
+    match default {
+        Some(default) => print!("{} [{}]: ", question, default),
+        None => print!("{}: ", question),
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        return Err(anyhow!("Setup was interrupted"));
+    }
+    match (answer.trim(), default) {
+        ("", Some(default)) => Ok(default.to_string()),
+        (answer, _) => Ok(answer.to_string()),
+    }
+}
@@ -0,0 +1,284 @@
+use crate::i18n::tr;
+use crate::output;
+use crate::sanitize::{self, ControlDisplay};
+use super::{Client, SendOptions};
+use anyhow::Result;
+use crossterm::event::{Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::Terminal;
+use ratatui::backend::Backend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How often the TUI fetches messages and presence.
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+/// Messages of history loaded into the conversation pane.
+const SCROLLBACK: usize = 200;
+/// Output lines kept for the log pane.
+const LOG_LINES: usize = 100;
+
+/// Everything the TUI reacts to, merged into one stream so the loop can be
+/// driven by a real terminal or anything else that sends these.
+pub enum Event {
+    Key(KeyEvent),
+    Resize,
+    /// A line the client printed, e.g. the result of a command.
+    Output(String),
+    /// Time to fetch messages and presence.
+    Poll,
+}
+
+/// What the screen shows. Only ever changed from the loop in `run_on`.
+#[derive(Default)]
+struct App {
+    contacts: Vec<String>,
+    selected: usize,
+    unread: BTreeMap<String, usize>,
+    online: BTreeSet<String>,
+    conversation: Vec<String>,
+    /// Lines scrolled back from the newest message.
+    scroll: usize,
+    input: String,
+    log: Vec<String>,
+    /// Log lines can quote the server or a peer, so they are cleaned on the way in.
+    controls: ControlDisplay,
+    quit: bool,
+}
+
+impl App {
+    fn peer(&self) -> Option<&str> {
+        self.contacts.get(self.selected).map(String::as_str)
+    }
+
+    fn log(&mut self, line: String) {
+        self.log.push(sanitize::text(&line, self.controls));
+        if self.log.len() > LOG_LINES {
+            self.log.remove(0);
+        }
+    }
+}
+
+/// `client --tui`: take over the terminal until the user quits. Commands
+/// typed with a leading `/` run as at the plain prompt; anything else is
+/// sent to the selected contact.
+pub async fn run(client: &mut Client) -> Result<()> {
+    let (sender, events) = mpsc::unbounded_channel();
+    let (output, mut lines) = mpsc::unbounded_channel();
+    output::redirect(Some(output));
+
+    // Terminal reads block, so they get their own thread
+    let keys = sender.clone();
+    std::thread::spawn(move || {
+        while let Ok(event) = crossterm::event::read() {
+            let event = match event {
+                TermEvent::Key(key) => Event::Key(key),
+                TermEvent::Resize(..) => Event::Resize,
+                _ => continue,
+            };
+            if keys.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    let forward = sender.clone();
+    tokio::spawn(async move {
+        while let Some(line) = lines.recv().await {
+            if forward.send(Event::Output(line)).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if sender.send(Event::Poll).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let result = run_on(client, &mut terminal, events).await;
+    ratatui::restore();
+    output::redirect(None);
+    result
+}
+
+/// The TUI loop over any backend and event stream, returning once the user
+/// quits or the stream ends.
+pub async fn run_on<B: Backend>(client: &mut Client, terminal: &mut Terminal<B>, mut events: mpsc::UnboundedReceiver<Event>) -> Result<()> {
+    let mut app = App { controls: client.config.control_display, ..App::default() };
+    refresh_contacts(client, &mut app);
+    refresh_conversation(client, &mut app);
+    while !app.quit {
+        terminal.draw(|frame| draw(frame, client, &app))?;
+        let Some(event) = events.recv().await else { break };
+        match event {
+            Event::Key(key) if key.kind != KeyEventKind::Release => handle_key(client, &mut app, key).await,
+            Event::Key(_) | Event::Resize => {}
+            Event::Output(line) => app.log(line),
+            Event::Poll => poll(client, &mut app).await,
+        }
+    }
+    Ok(())
+}
+
+async fn handle_key(client: &mut Client, app: &mut App, key: KeyEvent) {
+    match key.code {
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => app.quit = true,
+        KeyCode::Esc => app.quit = true,
+        KeyCode::Up if !app.contacts.is_empty() => {
+            app.selected = app.selected.checked_sub(1).unwrap_or(app.contacts.len() - 1);
+            open_selected(client, app);
+        }
+        KeyCode::Down if !app.contacts.is_empty() => {
+            app.selected = (app.selected + 1) % app.contacts.len();
+            open_selected(client, app);
+        }
+        KeyCode::PageUp => app.scroll = (app.scroll + 10).min(app.conversation.len().saturating_sub(1)),
+        KeyCode::PageDown => app.scroll = app.scroll.saturating_sub(10),
+        KeyCode::Backspace => {
+            app.input.pop();
+        }
+        KeyCode::Tab => {
+            if let Some(completed) = client.complete_group(&app.input) {
+                app.input = completed;
+            }
+        }
+        KeyCode::Char(c) => app.input.push(c),
+        KeyCode::Enter => submit(client, app).await,
+        _ => {}
+    }
+}
+
+/// Run or send what's in the input box.
+async fn submit(client: &mut Client, app: &mut App) {
+    let input = std::mem::take(&mut app.input);
+    let input = input.trim();
+    if input.is_empty() {
+        return;
+    }
+    client.forget_scrubbed();
+    if let Some(command) = input.strip_prefix('/') {
+        match client.run_command(command).await {
+            Ok(keep_going) => app.quit = !keep_going,
+            Err(e) => app.log(format!("❌ {}", e)),
+        }
+        refresh_contacts(client, app);
+    } else if let Some(peer) = app.peer().map(str::to_string) {
+        if let Err(e) = client.send_numbered(&peer, input, SendOptions::default()).await {
+            app.log(tr!("send.failed", "❌ Failed to send message: {error}", error = e));
+        }
+    } else {
+        app.log(tr!("tui.no_contact", "❌ Pick a contact with ↑↓ first"));
+    }
+    show_events(client, app).await;
+    refresh_conversation(client, app);
+}
+
+/// Log what happened to the connections, as the plain prompt shows it.
+async fn show_events(client: &mut Client, app: &mut App) {
+    if let Err(e) = client.show_events(None).await {
+        app.log(format!("❌ {}", e));
+    }
+}
+
+/// Fetch new messages and who is online. Messages are recorded in history
+/// and acknowledged as they arrive; the TUI only counts them until their
+/// conversation is open.
+async fn poll(client: &mut Client, app: &mut App) {
+    let open = app.peer().map(str::to_string);
+    for (sender, _) in client.receive_in_order().await {
+        if client.config.muted.contains(&sender) || open.as_deref() == Some(sender.as_str()) {
+            continue;
+        }
+        *app.unread.entry(sender).or_default() += 1;
+    }
+    client.acknowledge_received().await;
+    show_events(client, app).await;
+    if let Ok(online) = client.get_online_clients(&client.current).await {
+        app.online = online.iter().map(|id| client.display_id(&client.current, id)).collect();
+    }
+    refresh_contacts(client, app);
+    refresh_conversation(client, app);
+}
+
+fn open_selected(client: &Client, app: &mut App) {
+    if let Some(peer) = app.peer().map(str::to_string) {
+        app.unread.remove(&peer);
+    }
+    app.scroll = 0;
+    refresh_conversation(client, app);
+}
+
+/// Contacts on every server, plus anyone with unread messages, keeping the
+/// selection on the same contact.
+fn refresh_contacts(client: &Client, app: &mut App) {
+    let selected = app.peer().map(str::to_string);
+    let mut contacts: BTreeSet<String> = client.servers.iter()
+        .flat_map(|(server, connection)| connection.contacts.keys().map(move |id| client.display_id(server, id)))
+        .collect();
+    contacts.extend(app.unread.keys().cloned());
+    app.contacts = contacts.into_iter().filter(|contact| *contact != client.id).collect();
+    app.selected = selected.and_then(|peer| app.contacts.iter().position(|contact| *contact == peer)).unwrap_or(0);
+}
+
+fn refresh_conversation(client: &Client, app: &mut App) {
+    app.conversation = match app.peer() {
+        Some(peer) => match client.resolve_direct(peer).and_then(|(server, id)| client.history_views(server, id, SCROLLBACK)) {
+            Ok((views, _)) => client.renderer.render(&views).lines().map(str::to_string).collect(),
+            Err(e) => vec![tr!("history.read_failed", "❌ Failed to read history: {error}", error = e)],
+        },
+        None => Vec::new(),
+    };
+}
+
+fn draw(frame: &mut ratatui::Frame, client: &Client, app: &App) {
+    let [main, log, input, status] = Layout::vertical([
+        Constraint::Min(5), Constraint::Length(6), Constraint::Length(3), Constraint::Length(1),
+    ]).areas(frame.area());
+    let [sidebar, conversation] = Layout::horizontal([Constraint::Length(28), Constraint::Min(20)]).areas(main);
+
+    // Contact ids come from the server and the senders, so they are cleaned like message text
+    let controls = client.config.control_display;
+    let items: Vec<ListItem> = app.contacts.iter().map(|contact| {
+        let presence = if app.online.contains(contact) { "●" } else { "○" };
+        let badge = app.unread.get(contact).map(|count| format!(" ({})", count)).unwrap_or_default();
+        ListItem::new(format!("{} {}{}", presence, sanitize::line(contact, controls, sanitize::MAX_NAME_WIDTH), badge))
+    }).collect();
+    let mut state = ListState::default().with_selected((!app.contacts.is_empty()).then_some(app.selected));
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(tr!("tui.contacts", "Contacts")))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+        sidebar,
+        &mut state,
+    );
+
+    // Keep the newest lines in view unless scrolled back
+    let height = conversation.height.saturating_sub(2) as usize;
+    let end = app.conversation.len().saturating_sub(app.scroll);
+    let lines: Vec<Line> = app.conversation[end.saturating_sub(height)..end].iter().map(|line| Line::raw(line.as_str())).collect();
+    let title = app.peer().map(|peer| sanitize::line(peer, controls, sanitize::MAX_NAME_WIDTH))
+        .unwrap_or_else(|| tr!("tui.no_conversation", "No conversation"));
+    frame.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)), conversation);
+
+    let height = log.height as usize;
+    let lines: Vec<Line> = app.log[app.log.len().saturating_sub(height)..].iter().map(|line| Line::raw(line.as_str())).collect();
+    frame.render_widget(Paragraph::new(lines), log);
+
+    frame.render_widget(Paragraph::new(app.input.as_str()).block(Block::default().borders(Borders::ALL)), input);
+    frame.set_cursor_position((input.x + 1 + app.input.chars().count() as u16, input.y + 1));
+
+    let connection = match client.servers.get(&client.current) {
+        Some(server) => tr!("tui.connected", "connected to {addr}", addr = server.addr()),
+        None => tr!("tui.disconnected", "not connected"),
+    };
+    frame.render_widget(Paragraph::new(format!(" {} @ {} | {} | {}", client.id, client.current, connection,
+        tr!("tui.keys", "↑↓ contact  PgUp/PgDn scroll  Enter send  /command  Esc quit"))), status);
+}
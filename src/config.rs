@@ -0,0 +1,151 @@
+use crate::annotations::Annotations;
+use crate::contactsync::ContactSync;
+use crate::digest::Digests;
+use crate::output::OutputMode;
+use crate::reorder::Sequences;
+use crate::rules::Rule;
+use crate::sanitize::ControlDisplay;
+use crate::secure_fs;
+use crate::store::LocalStoreKind;
+use crate::types::Group;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// A named server the client connects to alongside the default one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerProfile {
+    pub addr: String,
+    /// Other addresses of the same server (another address family, a
+    /// fallback port), raced against `addr` when connecting.
+    #[serde(default)]
+    pub fallback_addrs: Vec<String>,
+    /// Use a dedicated identity for this server instead of the shared one.
+    #[serde(default)]
+    pub separate_identity: bool,
+}
+
+impl ServerProfile {
+    /// Every address of the server, in the order they are tried.
+    pub fn addrs(&self) -> Vec<String> {
+        std::iter::once(self.addr.clone()).chain(self.fallback_addrs.iter().cloned()).collect()
+    }
+}
+
+/// Last key log head verified for a server, used to detect rewritten history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyLogHead {
+    pub epoch: u64,
+    pub hash: String,
+}
+
+/// A server this identity has registered with, by its key fingerprint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerBinding {
+    pub fingerprint: String,
+    /// Where the server was first reached; informational only.
+    pub addr: String,
+    pub bound_at: DateTime<Utc>,
+}
+
+/// Per-identity client settings, persisted as JSON.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Peers whose messages are fetched but not printed.
+    #[serde(default)]
+    pub muted: BTreeSet<String>,
+    /// Peers hidden from the default contact listing until they write again.
+    #[serde(default)]
+    pub archived: BTreeSet<String>,
+    #[serde(default)]
+    pub servers: BTreeMap<String, ServerProfile>,
+    /// Hex Ed25519 public key registered as able to revoke this identity.
+    #[serde(default)]
+    pub recovery_key: Option<String>,
+    #[serde(default)]
+    pub key_log_heads: BTreeMap<String, KeyLogHead>,
+    #[serde(default)]
+    pub local_store: LocalStoreKind,
+    /// Contacts holding shares of this identity.
+    #[serde(default)]
+    pub recovery_contacts: Vec<String>,
+    /// Shares held for other people, keyed by owner and sealed with the local store key.
+    #[serde(default)]
+    pub held_recovery_shares: BTreeMap<String, String>,
+    /// Keep a word index of history so `search` doesn't decrypt every message.
+    /// Words are stored as keyed hashes: the index doesn't reveal the words,
+    /// but it does show which messages share one.
+    #[serde(default)]
+    pub search_index: bool,
+    /// Message bodies with `{name}` placeholders, by template name.
+    #[serde(default)]
+    pub templates: BTreeMap<String, String>,
+    /// Output mode picked with `set output`; the `--output` flag overrides it.
+    #[serde(default)]
+    pub output: Option<OutputMode>,
+    /// Servers this identity may register with without asking. Empty until
+    /// the first registration, which binds that server.
+    #[serde(default)]
+    pub bound_servers: Vec<ServerBinding>,
+    /// Contacts whose messages are summarized per window instead of printed.
+    #[serde(default)]
+    pub digests: Digests,
+    /// Labels and stars on messages, synced with this identity's other devices.
+    #[serde(default)]
+    pub annotations: Annotations,
+    /// Where contacts stand in syncing with this identity's other devices.
+    #[serde(default)]
+    pub contact_sync: ContactSync,
+    /// Message numbers sent and shown per contact, and the jitter window.
+    #[serde(default)]
+    pub sequences: Sequences,
+    /// Conversation keys mailbox owners shared with us as their delegate,
+    /// keyed by owner and sealed with the local store key.
+    #[serde(default)]
+    pub delegated_keys: BTreeMap<String, String>,
+    /// Whether control characters from peers are removed or shown escaped;
+    /// picked with `set escapes`.
+    #[serde(default)]
+    pub control_display: ControlDisplay,
+    /// Id of the last `send --status` message per contact, which the next
+    /// status update replaces.
+    #[serde(default)]
+    pub status_messages: BTreeMap<String, String>,
+    /// Groups this identity is in as the server last described them, by
+    /// name (`team`, or `team@work` on another server). Refreshed from the
+    /// server when asked to, or when a send finds a member has left.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Group>,
+}
+
+impl ClientConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path)?;
+        let mut config: ClientConfig = serde_json::from_str(&content)?;
+        config.compile_rules()?;
+        Ok(config)
+    }
+
+    /// Compile rule patterns after deserializing.
+    pub fn compile_rules(&mut self) -> Result<()> {
+        for rule in &mut self.rules {
+            rule.compile()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        secure_fs::write_private(path, json)?;
+        Ok(())
+    }
+}
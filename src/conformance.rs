@@ -0,0 +1,604 @@
+use crate::{crypto, frame, output, types};
+use crate::crypto::CryptoManager;
+use crate::output::{eout, note, out, OutputMode};
+use crate::types::{Delegation, DelegationScope, DeliveryStatus, SenderKey, ServerCommand, ServerResponse, ack_payload, delegation_payload, delegation_ref_payload, error_code, message_ref_payload, send_payload, sender_key_payload, usage_payload};
+use chrono::Utc;
+use ed25519_dalek::{PublicKey, Signature};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+
+const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:8080";
+/// Malformed requests and the error code a server must answer each with.
+const MALFORMED_FIXTURES: &str = include_str!("../fixtures/conformance/malformed.json");
+
+#[derive(Deserialize)]
+struct MalformedFixture {
+    name: String,
+    request: String,
+    /// None when the request is valid after all and must not be refused.
+    expect_code: Option<String>,
+}
+
+/// Outcome of one check: its name, and what went wrong if it failed.
+struct CheckResult {
+    name: String,
+    failure: Option<String>,
+}
+
+/// Protocol areas this tree has no behavior for yet, so there is nothing to
+/// check an implementation against.
+const NOT_SPECIFIED: &[&str] = &["pagination", "idempotent resends"];
+
+/// `msgproto-conformance [--server <addr>]`: run the protocol checks against
+/// a server implementation and report each one.
+pub async fn main(args: Vec<String>) -> Result<()> {
+    let output_mode = match args.iter().position(|arg| arg == output::OUTPUT_FLAG) {
+        Some(index) => Some(args.get(index + 1).ok_or_else(|| anyhow!("{} needs a mode", output::OUTPUT_FLAG))?.parse::<OutputMode>()?),
+        None => None,
+    };
+    output::init(output_mode);
+    let server = match args.iter().position(|arg| arg == "--server") {
+        Some(index) => args.get(index + 1).ok_or_else(|| anyhow!("--server needs an address"))?.clone(),
+        None => DEFAULT_SERVER_ADDR.to_string(),
+    };
+
+    note!("🧪 Checking {} against the messaging protocol", server);
+    let mut results = Vec::new();
+    check_framing(&server, &mut results).await;
+    check_malformed(&server, &mut results).await?;
+    check_signatures(&server, &mut results).await;
+    check_delegation(&server, &mut results).await;
+    check_usage(&server, &mut results).await;
+
+    let failed = results.iter().filter(|result| result.failure.is_some()).count();
+    for result in &results {
+        match &result.failure {
+            None => out!("✅ {}", result.name),
+            Some(failure) => out!("❌ {}: {}", result.name, failure),
+        }
+    }
+    for area in NOT_SPECIFIED {
+        note!("⏭️ {}: not part of the protocol yet", area);
+    }
+    out!("📊 {} of {} checks passed", results.len() - failed, results.len());
+    if failed > 0 {
+        eout!("❌ {} is not conformant: {} check(s) failed", server, failed);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn record(results: &mut Vec<CheckResult>, name: &str, outcome: Result<()>) {
+    results.push(CheckResult { name: name.to_string(), failure: outcome.err().map(|e| e.to_string()) });
+}
+
+/// Requests and responses are JSON in frames of a 4-byte big-endian length
+/// and the body, several to a connection. A frame over the server's limit is
+/// refused with a coded error.
+async fn check_framing(server: &str, results: &mut Vec<CheckResult>) {
+    let outcome = async {
+        let mut stream = TcpStream::connect(server).await?;
+        for _ in 0..2 {
+            match parse(&exchange(&mut stream, &serde_json::to_string(&ServerCommand::GetServerKey)?).await?)? {
+                ServerResponse::ServerKey { server_public_key } => check_key(&server_public_key)?,
+                other => return Err(anyhow!("expected ServerKey, got {:?}", other)),
+            }
+        }
+        Ok(())
+    }.await;
+    record(results, "framing: two requests on one connection", outcome);
+
+    let outcome = async {
+        let mut stream = TcpStream::connect(server).await?;
+        // Trailing whitespace is still valid JSON, and spans many TCP segments
+        let request = format!("{}{}", serde_json::to_string(&ServerCommand::GetServerKey)?, " ".repeat(100 * 1024));
+        match parse(&exchange(&mut stream, &request).await?)? {
+            ServerResponse::ServerKey { server_public_key } => check_key(&server_public_key),
+            other => Err(anyhow!("expected ServerKey, got {:?}", other)),
+        }
+    }.await;
+    record(results, "framing: a 100 KB request is read whole", outcome);
+
+    let outcome = async {
+        let mut stream = TcpStream::connect(server).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut stream, &u32::MAX.to_be_bytes()).await?;
+        let response = frame::read_frame(&mut stream, frame::DEFAULT_MAX_FRAME_BYTES).await?
+            .ok_or_else(|| anyhow!("connection closed without a response"))?;
+        match parse(&String::from_utf8_lossy(&response))? {
+            ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::FRAME_TOO_LARGE) => Ok(()),
+            other => Err(anyhow!("expected {}, got {:?}", error_code::FRAME_TOO_LARGE, other)),
+        }
+    }.await;
+    record(results, "framing: an oversized frame is refused", outcome);
+}
+
+async fn check_malformed(server: &str, results: &mut Vec<CheckResult>) -> Result<()> {
+    let fixtures: Vec<MalformedFixture> = serde_json::from_str(MALFORMED_FIXTURES)?;
+    for fixture in fixtures {
+        let outcome = async {
+            let mut stream = TcpStream::connect(server).await?;
+            let response = parse(&exchange(&mut stream, &fixture.request).await?)?;
+            match (response, fixture.expect_code.as_deref()) {
+                (ServerResponse::Error { code, .. }, Some(expected)) if code.as_deref() == Some(expected) => Ok(()),
+                (ServerResponse::Error { code, message, .. }, Some(expected)) => {
+                    Err(anyhow!("expected code {}, got {:?} ({})", expected, code, message))
+                }
+                (other, Some(expected)) => Err(anyhow!("expected error {}, got {:?}", expected, other)),
+                (ServerResponse::Error { message, .. }, None) => Err(anyhow!("valid request refused: {}", message)),
+                (_, None) => Ok(()),
+            }
+        }.await;
+        record(results, &format!("malformed input: {}", fixture.name), outcome);
+    }
+    Ok(())
+}
+
+/// Send signatures are Ed25519 over the context-labelled raw ciphertext.
+/// The right envelope must be accepted, and the wrong context or key refused.
+async fn check_signatures(server: &str, results: &mut Vec<CheckResult>) {
+    let run_id = uuid::Uuid::new_v4().simple().to_string();
+    let sender = (format!("conformance-{}-a", &run_id[..8]), CryptoManager::new());
+    let recipient = (format!("conformance-{}-b", &run_id[..8]), CryptoManager::new());
+
+    let registered = async {
+        // The sender registers like a client from before negotiation, the
+        // recipient negotiates but wants no optional features and publishes
+        // its X25519 key
+        for ((id, crypto), offer) in [(&sender, None), (&recipient, Some(0))] {
+            let (x25519_public_key, x25519_signature) = match offer {
+                Some(_) => {
+                    let (key, signature) = signed_x25519_key(id, crypto, crypto);
+                    (Some(key), Some(signature))
+                }
+                None => (None, None),
+            };
+            let command = ServerCommand::Register {
+                client_id: id.clone(),
+                public_key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+                recovery_key: None,
+                protocol_version: offer.map(|_| 1),
+                features: offer,
+                credential: None,
+                x25519_public_key,
+                x25519_signature,
+                challenge: None,
+            };
+            match signed_request(server, &command, crypto).await? {
+                ServerResponse::Registered { server_public_key, protocol_version, features, .. } => {
+                    check_key(&server_public_key)?;
+                    if protocol_version.is_some() != offer.is_some() || features.unwrap_or(0) != 0 {
+                        return Err(anyhow!("{} offered {:?} and was given protocol {:?} with features {:?}", id, offer, protocol_version, features));
+                    }
+                }
+                other => return Err(anyhow!("expected Registered, got {:?}", other)),
+            }
+        }
+        Ok(())
+    }.await;
+    let registered_ok = registered.is_ok();
+    record(results, "register, with and without version negotiation", registered);
+    if !registered_ok {
+        return;
+    }
+
+    let published = async {
+        let expected = hex::encode(recipient.1.get_x25519_public_key().as_bytes());
+        match request(server, &ServerCommand::GetPublicKey { client_id: recipient.0.clone() }).await? {
+            ServerResponse::PublicKeys { ed25519, x25519: Some(key), .. } if key.x25519_public_key == expected => {
+                let identity = PublicKey::from_bytes(&hex::decode(&ed25519)?)?;
+                let signature = Signature::from_bytes(&hex::decode(&key.signature)?)?;
+                recipient.1.verify_with_context(crypto::context::SENDER_KEY, &sender_key_payload(&recipient.0, &key.x25519_public_key), &signature, &identity)
+                    .map_err(|_| anyhow!("the published X25519 key's signature doesn't verify"))
+            }
+            other => Err(anyhow!("expected {}'s X25519 key {}, got {:?}", recipient.0, expected, other)),
+        }?;
+        match request(server, &ServerCommand::GetPublicKey { client_id: sender.0.clone() }).await? {
+            ServerResponse::PublicKeys { x25519: None, .. } => Ok(()),
+            other => Err(anyhow!("expected no X25519 key for {}, got {:?}", sender.0, other)),
+        }
+    }.await;
+    record(results, "public keys: a registered X25519 key is published with its signature", published);
+
+    let forged = async {
+        let id = format!("conformance-{}-c", &run_id[..8]);
+        let crypto = CryptoManager::new();
+        let (key, signature) = signed_x25519_key(&id, &crypto, &CryptoManager::new());
+        let command = ServerCommand::Register {
+            client_id: id.clone(),
+            public_key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+            recovery_key: None,
+            protocol_version: None,
+            features: None,
+            credential: None,
+            x25519_public_key: Some(key),
+            x25519_signature: Some(signature),
+            challenge: None,
+        };
+        match signed_request(server, &command, &crypto).await? {
+            ServerResponse::Error { .. } => Ok(()),
+            other => Err(anyhow!("expected an error for {}, got {:?}", id, other)),
+        }
+    }.await;
+    record(results, "public keys: an X25519 key signed by another identity is refused", forged);
+
+    let send = |context: &'static str, signer: &CryptoManager| -> Result<(String, String, ServerCommand)> {
+        let encrypted = sender.1.encrypt_message(&recipient.1.get_x25519_public_key(), "conformance")?;
+        let message_id = types::new_message_id();
+        let content = hex::encode(&encrypted);
+        let sent_at = Utc::now();
+        let command = ServerCommand::Send {
+            sender_id: sender.0.clone(),
+            recipient_id: recipient.0.clone(),
+            encrypted_content: content.clone(),
+            signature: hex::encode(signer.sign_with_context(context, &send_payload(&message_id, &recipient.0, &encrypted, sent_at)).to_bytes()),
+            message_id: message_id.clone(),
+            ttl_secs: None,
+            deliver_by: None,
+            sender_key: None,
+            seq: None,
+            on_behalf_of: None,
+            supersedes: None,
+            sent_at: Some(sent_at),
+            group: None,
+        };
+        Ok((message_id, content, command))
+    };
+
+    let accepted = async {
+        let (message_id, content, command) = send(crypto::context::SEND, &sender.1)?;
+        match request(server, &command).await? {
+            ServerResponse::MessageSent { message_id: sent, .. } if sent == message_id => {}
+            other => return Err(anyhow!("expected MessageSent for {}, got {:?}", message_id, other)),
+        }
+        let fetch = ServerCommand::GetMessages { client_id: recipient.0.clone(), since: None, on_behalf_of: None, signature: None, challenge: None };
+        match signed_request(server, &fetch, &recipient.1).await? {
+            ServerResponse::Messages { messages } if messages.iter().any(|m| m.id == message_id && m.content == content) => Ok(()),
+            other => Err(anyhow!("expected message {} back unchanged, got {:?}", message_id, other)),
+        }
+    }.await;
+    record(results, "signature: send signed over the raw ciphertext is accepted and delivered", accepted);
+
+    let duplicate = async {
+        let (message_id, _, command) = send(crypto::context::SEND, &sender.1)?;
+        request(server, &command).await?;
+        match request(server, &command).await? {
+            ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::DUPLICATE_MESSAGE_ID) => Ok(()),
+            other => Err(anyhow!("expected {} for a second send of {}, got {:?}", error_code::DUPLICATE_MESSAGE_ID, message_id, other)),
+        }
+    }.await;
+    record(results, "message ids: a second send with a stored id is refused", duplicate);
+
+    let replayed = async {
+        let (message_id, _, command) = send(crypto::context::SEND, &sender.1)?;
+        request(server, &command).await?;
+        let message_ids = vec![message_id.clone()];
+        let signed_at = Utc::now();
+        let signature = recipient.1.sign_with_context(crypto::context::ACK, &ack_payload(&recipient.0, &message_ids, signed_at));
+        request(server, &ServerCommand::Ack { client_id: recipient.0.clone(), message_ids, signed_at, signature: hex::encode(signature.to_bytes()) }).await?;
+        match request(server, &command).await? {
+            ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::REPLAYED_MESSAGE_ID) => Ok(()),
+            other => Err(anyhow!("expected {} for a replay of the acked {}, got {:?}", error_code::REPLAYED_MESSAGE_ID, message_id, other)),
+        }
+    }.await;
+    record(results, "message ids: a send replayed after its message was acked is refused", replayed);
+
+    let stale = async {
+        let encrypted = sender.1.encrypt_message(&recipient.1.get_x25519_public_key(), "stale")?;
+        let message_id = types::new_message_id();
+        let sent_at = Utc::now() - chrono::Duration::days(1);
+        let command = ServerCommand::Send {
+            sender_id: sender.0.clone(),
+            recipient_id: recipient.0.clone(),
+            encrypted_content: hex::encode(&encrypted),
+            signature: hex::encode(sender.1.sign_with_context(crypto::context::SEND, &send_payload(&message_id, &recipient.0, &encrypted, sent_at)).to_bytes()),
+            message_id,
+            ttl_secs: None,
+            deliver_by: None,
+            sender_key: None,
+            seq: None,
+            on_behalf_of: None,
+            supersedes: None,
+            sent_at: Some(sent_at),
+            group: None,
+        };
+        match request(server, &command).await? {
+            ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::STALE_SEND) => Ok(()),
+            other => Err(anyhow!("expected {} for a send a day old, got {:?}", error_code::STALE_SEND, other)),
+        }
+    }.await;
+    record(results, "clock: a send signed a day ago is refused", stale);
+
+    let fetch = ServerCommand::GetMessages { client_id: recipient.0.clone(), since: None, on_behalf_of: None, signature: None, challenge: None };
+
+    let challenged = async {
+        match request(server, &fetch).await? {
+            ServerResponse::Challenge { .. } => {}
+            other => return Err(anyhow!("expected a Challenge for a fetch, got {:?}", other)),
+        }
+        match signed_request(server, &fetch, &CryptoManager::new()).await? {
+            ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::CHALLENGE_FAILED) => Ok(()),
+            other => Err(anyhow!("expected {} for a fetch answered by another key, got {:?}", error_code::CHALLENGE_FAILED, other)),
+        }
+    }.await;
+    record(results, "challenge: a mailbox is only read for the holder of the id's key", challenged);
+
+    let superseded = async {
+        let (original, _, command) = send(crypto::context::SEND, &sender.1)?;
+        request(server, &command).await?;
+        let (replacement, _, command) = send(crypto::context::SEND, &sender.1)?;
+        if !send_superseding(server, command, &original).await? {
+            return Err(anyhow!("expected {} to replace the unfetched {}", replacement, original));
+        }
+        match message_status(server, &sender, &original).await? {
+            DeliveryStatus::Superseded { by } if by == replacement => {}
+            other => return Err(anyhow!("expected {} to be superseded by {}, got {:?}", original, replacement, other)),
+        }
+        match signed_request(server, &fetch, &recipient.1).await? {
+            ServerResponse::Messages { messages } if messages.iter().any(|m| m.id == replacement) && !messages.iter().any(|m| m.id == original) => Ok(()),
+            other => Err(anyhow!("expected only {} to be delivered, got {:?}", replacement, other)),
+        }
+    }.await;
+    record(results, "supersedes: an unfetched message is replaced, and its status names the replacement", superseded);
+
+    // The recipient fetches the original just before the replacement arrives
+    let raced = async {
+        let (original, _, command) = send(crypto::context::SEND, &sender.1)?;
+        request(server, &command).await?;
+        signed_request(server, &fetch, &recipient.1).await?;
+        let (replacement, _, command) = send(crypto::context::SEND, &sender.1)?;
+        if send_superseding(server, command, &original).await? {
+            return Err(anyhow!("{} replaced {}, which was already fetched", replacement, original));
+        }
+        match message_status(server, &sender, &original).await? {
+            DeliveryStatus::Delivered => Ok(()),
+            other => Err(anyhow!("expected {} to stay delivered, got {:?}", original, other)),
+        }
+    }.await;
+    record(results, "supersedes: a message fetched first is kept alongside its replacement", raced);
+
+    let end_to_end = async {
+        let published = match request(server, &ServerCommand::GetPublicKey { client_id: recipient.0.clone() }).await? {
+            ServerResponse::PublicKeys { x25519: Some(key), .. } => key,
+            other => return Err(anyhow!("expected {}'s X25519 key, got {:?}", recipient.0, other)),
+        };
+        let key: [u8; 32] = hex::decode(&published.x25519_public_key)?.try_into().map_err(|_| anyhow!("the published key is not 32 bytes"))?;
+        let encrypted = sender.1.encrypt_message(&key.into(), "end to end")?;
+        let (x25519_public_key, signature) = signed_x25519_key(&sender.0, &sender.1, &sender.1);
+        let message_id = types::new_message_id();
+        let sent_at = Utc::now();
+        let command = ServerCommand::Send {
+            sender_id: sender.0.clone(),
+            recipient_id: recipient.0.clone(),
+            encrypted_content: hex::encode(&encrypted),
+            signature: hex::encode(sender.1.sign_with_context(crypto::context::SEND, &send_payload(&message_id, &recipient.0, &encrypted, sent_at)).to_bytes()),
+            message_id: message_id.clone(),
+            ttl_secs: None,
+            deliver_by: None,
+            sender_key: Some(SenderKey { x25519_public_key, signature }),
+            seq: None,
+            on_behalf_of: None,
+            supersedes: None,
+            sent_at: Some(sent_at),
+            group: None,
+        };
+        request(server, &command).await?;
+        let ServerResponse::Messages { messages } = signed_request(server, &fetch, &recipient.1).await? else {
+            return Err(anyhow!("expected Messages for {}", recipient.0));
+        };
+        let message = messages.into_iter().find(|m| m.id == message_id).ok_or_else(|| anyhow!("message {} wasn't delivered", message_id))?;
+        let sender_key = message.sender_key.ok_or_else(|| anyhow!("message {} lost its sender key", message_id))?;
+        let sender_key: [u8; 32] = hex::decode(&sender_key.x25519_public_key)?.try_into().map_err(|_| anyhow!("the sender key is not 32 bytes"))?;
+        match recipient.1.decrypt_message(&sender_key.into(), &hex::decode(&message.content)?)?.as_str() {
+            "end to end" => Ok(()),
+            other => Err(anyhow!("expected \"end to end\", decrypted {:?}", other)),
+        }
+    }.await;
+    record(results, "end to end: a message to the published key opens with the key in its envelope", end_to_end);
+
+    let stranger = CryptoManager::new();
+    for (name, context, signer) in [
+        ("signature: send signed with another context is refused", crypto::context::REVOKE, &sender.1),
+        ("signature: send signed by another key is refused", crypto::context::SEND, &stranger),
+    ] {
+        let outcome = async {
+            let (_, _, command) = send(context, signer)?;
+            match request(server, &command).await? {
+                ServerResponse::Error { .. } => Ok(()),
+                other => Err(anyhow!("expected an error, got {:?}", other)),
+            }
+        }.await;
+        record(results, name, outcome);
+    }
+}
+
+/// `owner`'s X25519 key in hex, and a signature over it by `signer`.
+fn signed_x25519_key(id: &str, owner: &CryptoManager, signer: &CryptoManager) -> (String, String) {
+    let key = hex::encode(owner.get_x25519_public_key().as_bytes());
+    let signature = signer.sign_with_context(crypto::context::SENDER_KEY, &sender_key_payload(id, &key));
+    (key, hex::encode(signature.to_bytes()))
+}
+
+/// Send `command` as a replacement for `original`; returns whether the
+/// server dropped the original.
+async fn send_superseding(server: &str, mut command: ServerCommand, original: &str) -> Result<bool> {
+    if let ServerCommand::Send { supersedes, .. } = &mut command {
+        *supersedes = Some(original.to_string());
+    }
+    match request(server, &command).await? {
+        ServerResponse::MessageSent { replaced, .. } => Ok(replaced),
+        other => Err(anyhow!("expected MessageSent, got {:?}", other)),
+    }
+}
+
+/// What the server says became of a message `sender` sent.
+async fn message_status(server: &str, sender: &(String, CryptoManager), message_id: &str) -> Result<DeliveryStatus> {
+    let signature = sender.1.sign_with_context(crypto::context::MESSAGE_STATUS, &message_ref_payload(&sender.0, message_id));
+    let command = ServerCommand::GetMessageStatus { sender_id: sender.0.clone(), message_id: message_id.to_string(), signature: hex::encode(signature.to_bytes()) };
+    match request(server, &command).await? {
+        ServerResponse::MessageStatus { status, .. } => Ok(status),
+        other => Err(anyhow!("expected MessageStatus, got {:?}", other)),
+    }
+}
+
+/// A delegate may fetch its owner's mail until the owner revokes the grant,
+/// and not a request longer; replaying the old grant doesn't bring it back.
+async fn check_delegation(server: &str, results: &mut Vec<CheckResult>) {
+    let run_id = uuid::Uuid::new_v4().simple().to_string();
+    let owner = (format!("conformance-{}-owner", &run_id[..8]), CryptoManager::new());
+    let delegate = (format!("conformance-{}-delegate", &run_id[..8]), CryptoManager::new());
+
+    let outcome = async {
+        for (id, crypto) in [&owner, &delegate] {
+            let command = ServerCommand::Register {
+                client_id: id.clone(),
+                public_key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+                recovery_key: None,
+                protocol_version: None,
+                features: None,
+                credential: None,
+                x25519_public_key: None,
+                x25519_signature: None,
+                challenge: None,
+            };
+            match signed_request(server, &command, crypto).await? {
+                ServerResponse::Registered { .. } => {}
+                other => return Err(anyhow!("expected Registered for {}, got {:?}", id, other)),
+            }
+        }
+
+        let issued_at = chrono::Utc::now();
+        let expires_at = issued_at + chrono::Duration::hours(1);
+        let payload = delegation_payload(&owner.0, &delegate.0, DelegationScope::Read, issued_at, expires_at);
+        let grant = ServerCommand::Delegate {
+            delegation: Delegation {
+                owner: owner.0.clone(),
+                delegate: delegate.0.clone(),
+                scope: DelegationScope::Read,
+                issued_at,
+                expires_at,
+                signature: hex::encode(owner.1.sign_with_context(crypto::context::DELEGATE, &payload).to_bytes()),
+                revoked_at: None,
+            },
+        };
+        match request(server, &grant).await? {
+            ServerResponse::Ok => {}
+            other => return Err(anyhow!("expected Ok for the grant, got {:?}", other)),
+        }
+
+        let fetch_signature = delegate.1.sign_with_context(crypto::context::DELEGATED_FETCH, &delegation_ref_payload(&owner.0, &delegate.0));
+        let fetch = ServerCommand::GetMessages {
+            client_id: delegate.0.clone(),
+            since: None,
+            on_behalf_of: Some(owner.0.clone()),
+            signature: Some(hex::encode(fetch_signature.to_bytes())),
+            challenge: None,
+        };
+        let refused = |response: &ServerResponse| matches!(response, ServerResponse::Error { code, .. } if code.as_deref() == Some(error_code::NOT_DELEGATED));
+        let response = signed_request(server, &fetch, &delegate.1).await?;
+        if refused(&response) {
+            return Err(anyhow!("delegate refused while the grant stands: {:?}", response));
+        }
+
+        let revoke_signature = owner.1.sign_with_context(crypto::context::DELEGATION_REF, &delegation_ref_payload(&owner.0, &delegate.0));
+        let revoke = ServerCommand::RevokeDelegation {
+            owner: owner.0.clone(),
+            delegate: delegate.0.clone(),
+            signature: hex::encode(revoke_signature.to_bytes()),
+        };
+        match request(server, &revoke).await? {
+            ServerResponse::Ok => {}
+            other => return Err(anyhow!("expected Ok for the revocation, got {:?}", other)),
+        }
+        let response = signed_request(server, &fetch, &delegate.1).await?;
+        if !refused(&response) {
+            return Err(anyhow!("expected {} right after the revocation, got {:?}", error_code::NOT_DELEGATED, response));
+        }
+
+        if let ServerResponse::Ok = request(server, &grant).await? {
+            return Err(anyhow!("replaying the revoked grant was accepted"));
+        }
+        let response = signed_request(server, &fetch, &delegate.1).await?;
+        if !refused(&response) {
+            return Err(anyhow!("expected {} after replaying the grant, got {:?}", error_code::NOT_DELEGATED, response));
+        }
+        Ok(())
+    }.await;
+    record(results, "delegation: a revoked delegate is cut off at once", outcome);
+}
+
+/// A client may see what the server keeps about it, and nobody else may.
+async fn check_usage(server: &str, results: &mut Vec<CheckResult>) {
+    let run_id = uuid::Uuid::new_v4().simple().to_string();
+    let id = format!("conformance-{}-usage", &run_id[..8]);
+    let crypto = CryptoManager::new();
+
+    let outcome = async {
+        let command = ServerCommand::Register {
+            client_id: id.clone(),
+            public_key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+            recovery_key: None,
+            protocol_version: None,
+            features: None,
+            credential: None,
+            x25519_public_key: None,
+            x25519_signature: None,
+            challenge: None,
+        };
+        match signed_request(server, &command, &crypto).await? {
+            ServerResponse::Registered { .. } => {}
+            other => return Err(anyhow!("expected Registered for {}, got {:?}", id, other)),
+        }
+
+        let signed_at = Utc::now();
+        let own = crypto.sign_with_context(crypto::context::USAGE, &usage_payload(&id, signed_at));
+        match request(server, &ServerCommand::MyUsage { client_id: id.clone(), signed_at, signature: hex::encode(own.to_bytes()) }).await? {
+            ServerResponse::Usage { usage } if usage.client_id == id && usage.registered_at.is_some() && usage.stored_messages == 0 => {}
+            other => return Err(anyhow!("expected a fresh client's usage, got {:?}", other)),
+        }
+
+        let other = CryptoManager::new().sign_with_context(crypto::context::USAGE, &usage_payload(&id, signed_at));
+        if let ServerResponse::Usage { .. } = request(server, &ServerCommand::MyUsage { client_id: id.clone(), signed_at, signature: hex::encode(other.to_bytes()) }).await? {
+            return Err(anyhow!("usage was shown to a request signed by another key"));
+        }
+        Ok(())
+    }.await;
+    record(results, "usage: a client sees its own usage, and only its own", outcome);
+}
+
+fn check_key(key: &str) -> Result<()> {
+    match hex::decode(key) {
+        Ok(bytes) if bytes.len() == 32 => Ok(()),
+        _ => Err(anyhow!("server key {} is not 32 bytes of hex", key)),
+    }
+}
+
+async fn request(addr: &str, command: &ServerCommand) -> Result<ServerResponse> {
+    let mut stream = TcpStream::connect(addr).await?;
+    parse(&exchange(&mut stream, &serde_json::to_string(command)?).await?)
+}
+
+/// Send `command`, and if the server challenges it, send it again with
+/// the challenge answered by `crypto`.
+async fn signed_request(addr: &str, command: &ServerCommand, crypto: &CryptoManager) -> Result<ServerResponse> {
+    let mut stream = TcpStream::connect(addr).await?;
+    match parse(&exchange(&mut stream, &serde_json::to_string(command)?).await?)? {
+        ServerResponse::Challenge { nonce } => {
+            let answer = crypto.answer_challenge(command.client_id().unwrap_or_default(), &nonce);
+            parse(&exchange(&mut stream, &serde_json::to_string(&command.clone().answering(answer))?).await?)
+        }
+        response => Ok(response),
+    }
+}
+
+/// Write one raw request as a frame and read back one raw response.
+async fn exchange(stream: &mut TcpStream, request: &str) -> Result<String> {
+    frame::write_frame(stream, request.as_bytes(), frame::DEFAULT_MAX_FRAME_BYTES).await?;
+    let response = frame::read_frame(stream, frame::DEFAULT_MAX_FRAME_BYTES).await?
+        .ok_or_else(|| anyhow!("connection closed without a response"))?;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+fn parse(response: &str) -> Result<ServerResponse> {
+    serde_json::from_str(response).map_err(|e| anyhow!("response is not a ServerResponse ({}): {}", e, response))
+}
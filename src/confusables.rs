@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters drawn the same as a Latin letter or digit, after NFKD has
+/// already folded fullwidth and styled forms. A subset of the Unicode
+/// confusables data (UTS #39) covering the scripts client ids mix in practice.
+const CONFUSABLES: &[(char, &str)] = &[
+    // Cyrillic
+    ('а', "a"), ('е', "e"), ('о', "o"), ('р', "p"), ('с', "c"), ('у', "y"),
+    ('х', "x"), ('ѕ', "s"), ('і', "i"), ('ј', "j"), ('ԁ', "d"), ('ӏ', "l"), ('һ', "h"),
+    ('ԛ', "q"), ('ԝ', "w"), ('ү', "y"),
+    ('А', "A"), ('В', "B"), ('Е', "E"), ('К', "K"), ('М', "M"), ('Н', "H"), ('О', "O"),
+    ('Р', "P"), ('С', "C"), ('Т', "T"), ('Х', "X"), ('У', "Y"), ('Ѕ', "S"), ('І', "l"),
+    ('Ј', "J"), ('Ү', "Y"), ('Ӏ', "l"), ('Ԛ', "Q"), ('Ԝ', "W"),
+    // Greek
+    ('α', "a"), ('ο', "o"), ('ν', "v"), ('ρ', "p"), ('ι', "i"), ('υ', "u"), ('χ', "x"),
+    ('γ', "y"), ('ϲ', "c"), ('ϳ', "j"),
+    ('Α', "A"), ('Β', "B"), ('Ε', "E"), ('Ζ', "Z"), ('Η', "H"), ('Ι', "l"), ('Κ', "K"),
+    ('Μ', "M"), ('Ν', "N"), ('Ο', "O"), ('Ρ', "P"), ('Τ', "T"), ('Υ', "Y"), ('Χ', "X"),
+    // Latin letters outside ASCII
+    ('ı', "i"), ('ɩ', "i"), ('ɑ', "a"), ('ℓ', "l"), ('ǀ', "l"), ('ɡ', "g"),
+    // ASCII against ASCII
+    ('0', "O"), ('1', "l"), ('I', "l"), ('|', "l"), ('m', "rn"),
+];
+
+/// Format characters that draw nothing, so they can hide inside an id.
+fn invisible(c: char) -> bool {
+    matches!(c, '\u{ad}' | '\u{34f}' | '\u{180e}' | '\u{200b}'..='\u{200f}' | '\u{2060}'..='\u{2064}' | '\u{feff}')
+}
+
+/// What `id` looks like, for comparison: two ids with the same skeleton
+/// are easy to mistake for each other on screen. Case still counts, as
+/// it does in ids themselves.
+pub fn skeleton(id: &str) -> String {
+    let mut out = String::with_capacity(id.len());
+    for c in id.nfkd().filter(|c| !invisible(*c)) {
+        match CONFUSABLES.iter().find(|(from, _)| *from == c) {
+            Some((_, to)) => out.push_str(to),
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{c0}'..='\u{24f}' | '\u{1e00}'..='\u{1eff}' if c.is_alphabetic() => Some(Script::Latin),
+        '\u{370}'..='\u{3ff}' | '\u{1f00}'..='\u{1fff}' if c.is_alphabetic() => Some(Script::Greek),
+        '\u{400}'..='\u{52f}' if c.is_alphabetic() => Some(Script::Cyrillic),
+        _ => None,
+    }
+}
+
+/// Whether `id` mixes letters from Latin, Greek and Cyrillic, which
+/// legitimate ids rarely do and lookalike ones usually must. Digits,
+/// punctuation and other scripts don't count either way.
+pub fn is_mixed_script(id: &str) -> bool {
+    let mut scripts = id.chars().filter_map(script);
+    match scripts.next() {
+        Some(first) => scripts.any(|other| other != first),
+        None => false,
+    }
+}
+
+/// Known ids by skeleton, to find the ones a given id could pass for.
+#[derive(Default)]
+pub struct Lookalikes {
+    by_skeleton: HashMap<String, Vec<String>>,
+}
+
+impl Lookalikes {
+    pub fn new<'a>(ids: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut by_skeleton: HashMap<String, Vec<String>> = HashMap::new();
+        for id in ids {
+            by_skeleton.entry(skeleton(id)).or_default().push(id.to_string());
+        }
+        Self { by_skeleton }
+    }
+
+    /// A known id that differs from `id` but looks the same.
+    pub fn of(&self, id: &str) -> Option<&str> {
+        self.by_skeleton.get(&skeleton(id))?.iter().map(String::as_str).find(|other| *other != id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alike(a: &str, b: &str) -> bool {
+        Lookalikes::new([a]).of(b) == Some(a)
+    }
+
+    #[test]
+    fn classic_homoglyphs_are_caught() {
+        // Cyrillic а and о, Greek ο and Α
+        assert!(alike("paypal", "pаypal"));
+        assert!(alike("bob", "bоb"));
+        assert!(alike("bob", "bοb"));
+        assert!(alike("Alice", "Αlice"));
+        // Digits and letters drawn alike, and rn for m
+        assert!(alike("bill", "bi11"));
+        assert!(alike("bill", "biIl"));
+        assert!(alike("OSCAR", "0SCAR"));
+        assert!(alike("microsoft", "rnicrosoft"));
+        // Fullwidth forms, and characters that draw nothing
+        assert!(alike("alice", "ａｌｉｃｅ"));
+        assert!(alike("alice", "al\u{200b}ice"));
+        assert!(alike("alice", "\u{feff}alice\u{2060}"));
+    }
+
+    #[test]
+    fn distinct_ids_are_left_alone() {
+        for (a, b) in [
+            ("alice", "alicia"),
+            ("bob", "bob2"),
+            ("alice", "Alice"),
+            ("cafe", "café"),
+            ("anna", "ana"),
+            ("ivan", "иван"),
+            ("maria", "мария"),
+        ] {
+            assert!(!alike(a, b), "{} was taken for {}", b, a);
+        }
+    }
+
+    #[test]
+    fn an_id_is_not_its_own_lookalike() {
+        let known = Lookalikes::new(["alice", "bob"]);
+        assert_eq!(known.of("alice"), None);
+        assert_eq!(known.of("carol"), None);
+        assert_eq!(known.of("аlice"), Some("alice"));
+    }
+
+    #[test]
+    fn only_mixed_scripts_are_flagged() {
+        assert!(is_mixed_script("pаypal"));
+        assert!(is_mixed_script("Αlice"));
+        for id in ["alice", "иван", "ναι", "anna_42", "josé", "李雷", "bob-李", "42"] {
+            assert!(!is_mixed_script(id), "{} was flagged", id);
+        }
+    }
+}
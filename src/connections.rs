@@ -0,0 +1,242 @@
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Notify};
+
+struct Entry {
+    addr: SocketAddr,
+    opened: Instant,
+    /// Named by the latest request; nothing proves the peer holds its key.
+    client_id: Option<String>,
+    /// Registered as a presence-only session.
+    presence_only: bool,
+    /// The client registered here with push delivery; frames for it go
+    /// down `pushes`.
+    pushed_to: Option<String>,
+    pushes: mpsc::UnboundedSender<String>,
+    /// The id whose latest registration here didn't negotiate; see
+    /// `NegotiatedFeatures::unlabeled_signatures`.
+    legacy_client: Option<String>,
+    requests: u64,
+    in_flight: Option<(&'static str, Instant)>,
+}
+
+/// One live connection, for the debug dump.
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub client_id: Option<String>,
+    pub presence_only: bool,
+    pub open_for: Duration,
+    pub requests: u64,
+    /// The command being handled and how long it has been running.
+    pub in_flight: Option<(&'static str, Duration)>,
+}
+
+/// Live client connections. Shutdown closes them through it and waits for
+/// them to finish; a connection whose task panics is still unregistered,
+/// because that happens when its guard drops.
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<u64, Entry>>,
+    /// Becomes true once, when the server starts shutting down.
+    shutdown: watch::Sender<bool>,
+    /// Set once, to when open connections must be done by, when an admin
+    /// drains the server.
+    draining: watch::Sender<Option<DateTime<Utc>>>,
+    /// Woken whenever a connection closes.
+    closed: Notify,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            live: Mutex::new(HashMap::new()),
+            shutdown: watch::channel(false).0,
+            draining: watch::channel(None).0,
+            closed: Notify::new(),
+        }
+    }
+
+    /// Register a new connection. It stays registered until the guard drops,
+    /// and frames pushed to it come out of the receiver until then.
+    pub fn open(self: &Arc<Self>, addr: SocketAddr) -> (ConnectionGuard, mpsc::UnboundedReceiver<String>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (pushes, pushed) = mpsc::unbounded_channel();
+        self.live.lock().unwrap_or_else(|e| e.into_inner()).insert(id, Entry {
+            addr, opened: Instant::now(), client_id: None, presence_only: false, pushed_to: None, pushes,
+            legacy_client: None, requests: 0, in_flight: None,
+        });
+        (ConnectionGuard { registry: Arc::clone(self), id, addr }, pushed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Every live connection, oldest first.
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        let live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        let mut connections: Vec<_> = live.iter().map(|(id, entry)| ConnectionInfo {
+            id: *id,
+            addr: entry.addr,
+            client_id: entry.client_id.clone(),
+            presence_only: entry.presence_only,
+            open_for: entry.opened.elapsed(),
+            requests: entry.requests,
+            in_flight: entry.in_flight.map(|(command, started)| (command, started.elapsed())),
+        }).collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Ids with a connection open, leaving out presence-only sessions:
+    /// a status board watching isn't someone being there.
+    pub fn online(&self) -> HashSet<String> {
+        let live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        live.values().filter(|entry| !entry.presence_only).filter_map(|entry| entry.client_id.clone()).collect()
+    }
+
+    /// Where to push frames for `client_id`: one sender per connection it
+    /// registered on with push delivery. Empty when it has none open.
+    pub fn pushes_for(&self, client_id: &str) -> Vec<mpsc::UnboundedSender<String>> {
+        let live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        live.values().filter(|entry| entry.pushed_to.as_deref() == Some(client_id)).map(|entry| entry.pushes.clone()).collect()
+    }
+
+    /// Changes to true when shutdown starts; connections stop reading then.
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Start draining, with open connections done by `deadline`. False if
+    /// the server is already draining.
+    pub fn start_draining(&self, deadline: DateTime<Utc>) -> bool {
+        self.draining.send_if_modified(|draining| draining.is_none() && draining.replace(deadline).is_none())
+    }
+
+    /// When open connections must be done by, once draining has started.
+    pub fn draining(&self) -> Option<DateTime<Utc>> {
+        *self.draining.borrow()
+    }
+
+    /// Changes once, when draining starts.
+    pub fn draining_signal(&self) -> watch::Receiver<Option<DateTime<Utc>>> {
+        self.draining.subscribe()
+    }
+
+    /// Queue a frame on every open connection, between its responses.
+    pub fn broadcast(&self, frame: &str) -> usize {
+        let live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        live.values().filter(|entry| entry.pushes.send(frame.to_string()).is_ok()).count()
+    }
+
+    /// Wait until every connection has closed, or `grace` has passed.
+    /// Returns the peers of those still open.
+    pub async fn drain(&self, grace: Duration) -> Vec<SocketAddr> {
+        let deadline = tokio::time::Instant::now() + grace;
+        loop {
+            // Created before the check, so a close in between still wakes it
+            let closed = self.closed.notified();
+            let open = self.len();
+            if open == 0 || tokio::time::timeout_at(deadline, closed).await.is_err() {
+                let live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+                return live.values().map(|entry| entry.addr).collect();
+            }
+        }
+    }
+}
+
+/// A registered connection; dropping it, normally or while unwinding,
+/// unregisters it.
+pub struct ConnectionGuard {
+    registry: Arc<ConnectionRegistry>,
+    pub id: u64,
+    pub addr: SocketAddr,
+}
+
+impl ConnectionGuard {
+    /// The connection started handling `command`, sent for `client_id`.
+    pub fn begin(&self, command: &'static str, client_id: Option<&str>) {
+        let mut live = self.registry.live.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = live.get_mut(&self.id) {
+            entry.requests += 1;
+            entry.in_flight = Some((command, Instant::now()));
+            if let Some(client_id) = client_id {
+                entry.client_id = Some(client_id.to_string());
+            }
+        }
+    }
+
+    /// The connection registered, as a presence-only session or not.
+    pub fn set_presence_only(&self, presence_only: bool) {
+        let mut live = self.registry.live.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = live.get_mut(&self.id) {
+            entry.presence_only = presence_only;
+        }
+    }
+
+    /// `client_id` registered here and takes pushes, or, with `None`,
+    /// registered without them.
+    pub fn set_pushed_to(&self, client_id: Option<&str>) {
+        let mut live = self.registry.live.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = live.get_mut(&self.id) {
+            entry.pushed_to = client_id.map(str::to_string);
+        }
+    }
+
+    /// A client registered here: `client_id` without negotiating, or,
+    /// with `None`, one that negotiated.
+    pub fn set_legacy_client(&self, client_id: Option<&str>) {
+        let mut live = self.registry.live.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = live.get_mut(&self.id) {
+            entry.legacy_client = client_id.map(str::to_string);
+        }
+    }
+
+    /// Whether `signer` may sign without a context label here: only the id
+    /// that registered on this connection without negotiating, not anyone
+    /// else sending over it.
+    pub fn unlabeled_signatures(&self, signer: &str) -> bool {
+        let live = self.registry.live.lock().unwrap_or_else(|e| e.into_inner());
+        live.get(&self.id).is_some_and(|entry| entry.legacy_client.as_deref() == Some(signer))
+    }
+
+    /// The command in hand is done.
+    pub fn end(&self) {
+        let mut live = self.registry.live.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = live.get_mut(&self.id) {
+            entry.in_flight = None;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let entry = self.registry.live.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+        let open_for = entry.map(|entry| entry.opened.elapsed()).unwrap_or_default();
+        if std::thread::panicking() {
+            warn!("🔌 Connection {} from {} closed by a panic after {:?}", self.id, self.addr, open_for);
+        } else {
+            debug!("🔌 Connection {} from {} closed after {:?}", self.id, self.addr, open_for);
+        }
+        self.registry.closed.notify_waiters();
+    }
+}
+
+/// The message a panic was raised with, if it was a string.
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("(non-string panic payload)")
+}
@@ -0,0 +1,132 @@
+use crate::types::Hlc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Which change last set a contact: its server clock value, and the device
+/// that made it, which breaks ties.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Stamp {
+    pub hlc: Hlc,
+    pub device: String,
+}
+
+/// A contact as a device set it. A change made just now has no `stamp`
+/// and takes the clock value of the message carrying it; a full sync sends
+/// each contact with the stamp it was last set by. `replaces` is the change
+/// the device knew of when it made this one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactChange {
+    pub contact_id: String,
+    pub key: String,
+    pub trusted: bool,
+    #[serde(default)]
+    pub stamp: Option<Stamp>,
+    #[serde(default)]
+    pub replaces: Option<Stamp>,
+}
+
+/// What to do with a synced change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Apply,
+    /// This device already has the change, or a later one.
+    Stale,
+    /// The change would swap a key trusted here for another, made without
+    /// knowing of that trust. The user has to settle it.
+    Conflict,
+}
+
+/// Contacts synced between devices sharing an identity, like annotations:
+/// each change goes to self as an encrypted control message, and each
+/// contact is last-writer-wins by stamp. Sync messages are numbered per
+/// device, so one applied before, or sent by this device, is dropped, and
+/// applying a change never sends one on.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContactSync {
+    /// This device's name in sync messages, picked when it sends its first.
+    #[serde(default)]
+    pub device: String,
+    /// Sync messages this device has sent.
+    #[serde(default)]
+    pub sent: u64,
+    /// device -> number of the last of its sync messages applied here
+    #[serde(default)]
+    pub seen: BTreeMap<String, u64>,
+    /// server -> contact -> the change that last set it here
+    #[serde(default)]
+    pub stamps: BTreeMap<String, BTreeMap<String, Stamp>>,
+    /// server -> contact -> another device's change that conflicts with
+    /// this one's, until the contact is set again here
+    #[serde(default)]
+    pub conflicts: BTreeMap<String, BTreeMap<String, Stamp>>,
+}
+
+impl ContactSync {
+    /// This device's name and the number of the sync message it sends next.
+    pub fn next_message(&mut self) -> (String, u64) {
+        if self.device.is_empty() {
+            self.device = uuid::Uuid::new_v4().simple().to_string();
+        }
+        self.sent += 1;
+        (self.device.clone(), self.sent)
+    }
+
+    /// Whether sync message `number` from `device` is new here: neither
+    /// this device's own nor applied before. Records it as applied.
+    pub fn is_new(&mut self, device: &str, number: u64) -> bool {
+        if device == self.device {
+            return false;
+        }
+        let seen = self.seen.entry(device.to_string()).or_default();
+        if number <= *seen {
+            return false;
+        }
+        *seen = number;
+        true
+    }
+
+    pub fn stamp(&self, server: &str, contact_id: &str) -> Option<&Stamp> {
+        self.stamps.get(server)?.get(contact_id)
+    }
+
+    /// What a change made here should say it replaces: the conflicting
+    /// change if there is one, so making it settles the conflict on the
+    /// other device too, otherwise the one that set the contact last.
+    pub fn replaces(&self, server: &str, contact_id: &str) -> Option<Stamp> {
+        self.conflicts.get(server).and_then(|conflicts| conflicts.get(contact_id))
+            .or_else(|| self.stamp(server, contact_id))
+            .cloned()
+    }
+
+    /// Judge `change`, stamped `stamp`, against the contact as it is here:
+    /// its key and whether it is trusted, if it is a contact at all.
+    pub fn judge(&self, server: &str, change: &ContactChange, stamp: &Stamp, local: Option<(&str, bool)>) -> Verdict {
+        let current = self.stamp(server, &change.contact_id);
+        if current.is_some_and(|current| current >= stamp) {
+            return Verdict::Stale;
+        }
+        match local {
+            Some((key, true)) if key != change.key && change.replaces.as_ref().is_none_or(|replaces| Some(replaces) != current) => Verdict::Conflict,
+            _ => Verdict::Apply,
+        }
+    }
+
+    /// Record that `stamp` set the contact here, settling any conflict.
+    pub fn set(&mut self, server: &str, contact_id: &str, stamp: Stamp) {
+        self.stamps.entry(server.to_string()).or_default().insert(contact_id.to_string(), stamp);
+        self.forget_conflict(server, contact_id);
+    }
+
+    pub fn record_conflict(&mut self, server: &str, contact_id: &str, stamp: Stamp) {
+        self.conflicts.entry(server.to_string()).or_default().insert(contact_id.to_string(), stamp);
+    }
+
+    fn forget_conflict(&mut self, server: &str, contact_id: &str) {
+        if let Some(conflicts) = self.conflicts.get_mut(server) {
+            conflicts.remove(contact_id);
+            if conflicts.is_empty() {
+                self.conflicts.remove(server);
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Error, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Who a conversation is with. Written as `bob`, `#general` or `@group:team`,
+/// and serialized in that same form so it stays readable in JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ConversationId {
+    Direct(String),
+    Group(String),
+    Channel(String),
+}
+
+impl ConversationId {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConversationId::Direct(_) => "direct conversation",
+            ConversationId::Group(_) => "group",
+            ConversationId::Channel(_) => "channel",
+        }
+    }
+}
+
+impl FromStr for ConversationId {
+    type Err = Error;
+
+    fn from_str(target: &str) -> Result<Self> {
+        let (id, name) = if let Some(name) = target.strip_prefix('#') {
+            (ConversationId::Channel(name.to_string()), name)
+        } else if let Some(name) = target.strip_prefix("@group:") {
+            (ConversationId::Group(name.to_string()), name)
+        } else if target.starts_with('@') {
+            return Err(anyhow!("Unknown target {}; use bob, #channel or @group:name", target));
+        } else {
+            (ConversationId::Direct(target.to_string()), target)
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(anyhow!("Invalid {} name '{}'", id.kind(), name));
+        }
+        Ok(id)
+    }
+}
+
+impl fmt::Display for ConversationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversationId::Direct(id) => write!(f, "{}", id),
+            ConversationId::Group(name) => write!(f, "@group:{}", name),
+            ConversationId::Channel(name) => write!(f, "#{}", name),
+        }
+    }
+}
+
+impl TryFrom<String> for ConversationId {
+    type Error = Error;
+
+    fn try_from(target: String) -> Result<Self> {
+        target.parse()
+    }
+}
+
+impl From<ConversationId> for String {
+    fn from(id: ConversationId) -> Self {
+        id.to_string()
+    }
+}
@@ -1,18 +1,80 @@
+use crate::types::{challenge_payload, ChallengeAnswer};
 use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use chacha20poly1305::aead::{Aead, KeyInit};
-use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use hkdf::Hkdf;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
 use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Prefix for every signed payload, so a signature made for one purpose can't
+/// be replayed as another.
+const SIGNATURE_DOMAIN: &str = "msgproto-v1";
+
+/// Signature contexts; each signer and verifier names the one it expects.
+pub mod context {
+    pub const SEND: &str = "send";
+    pub const ACK: &str = "ack";
+    pub const REVOKE: &str = "revoke";
+    pub const RETENTION: &str = "retention";
+    pub const KEY_DIRECTORY: &str = "key-directory";
+    pub const MESSAGE_STATUS: &str = "message-status";
+    pub const CANCEL: &str = "cancel";
+    pub const SEARCH: &str = "search";
+    pub const SENDER_KEY: &str = "sender-key";
+    pub const REPORT: &str = "report";
+    pub const GUEST_LINK: &str = "guest-link";
+    pub const GUEST_LINK_REF: &str = "guest-link-ref";
+    pub const USER_DATA: &str = "user-data";
+    pub const USER_DATA_REF: &str = "user-data-ref";
+    pub const CLIENT_DETAILS: &str = "client-details";
+    pub const RECEIPT: &str = "receipt";
+    pub const INVITE_CODE: &str = "invite-code";
+    pub const DELEGATE: &str = "delegate";
+    pub const DELEGATION_REF: &str = "delegation-ref";
+    pub const DELEGATED_FETCH: &str = "delegated-fetch";
+    pub const USAGE: &str = "usage";
+    pub const PROMOTE: &str = "promote";
+    pub const DEBUG_DUMP: &str = "debug-dump";
+    pub const ADMIN_BATCH: &str = "admin-batch";
+    pub const PRESENCE: &str = "presence";
+    pub const CHALLENGE: &str = "challenge";
+    pub const GROUP: &str = "group";
+
+    /// What clients from before context labels signed, sending and
+    /// registering; only these may fall back to an unlabeled signature.
+    pub const LEGACY: [&str; 2] = [SEND, SENDER_KEY];
+}
+
+fn contextual_payload(context: &str, payload: &[u8]) -> Vec<u8> {
+    let mut labeled = format!("{}:{}\0", SIGNATURE_DOMAIN, context).into_bytes();
+    labeled.extend_from_slice(payload);
+    labeled
+}
 
 pub struct CryptoManager {
     ed25519_keypair: Keypair,
-    #[allow(dead_code)]
     x25519_secret: StaticSecret,
-    #[allow(dead_code)]
     x25519_public: X25519PublicKey,
 }
 
+/// A freshly generated identity, as from [`CryptoManager::new`].
+impl Default for CryptoManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A keypair on disk, as `CryptoManager::save_to_file` writes it.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    ed25519_secret: String,
+    x25519_secret: String,
+}
+
 impl CryptoManager {
     pub fn new() -> Self {
         let ed25519_keypair = Keypair::generate(&mut OsRng);
@@ -26,74 +88,405 @@ impl CryptoManager {
         }
     }
 
+    /// Rebuild an identity from exported secret key bytes.
+    pub fn from_secrets(ed25519_secret: &[u8], x25519_secret: &[u8]) -> Result<Self> {
+        let secret = SecretKey::from_bytes(ed25519_secret)?;
+        let public = PublicKey::from(&secret);
+        let x25519_bytes: [u8; 32] = x25519_secret.try_into()
+            .map_err(|_| anyhow!("Invalid X25519 secret length"))?;
+        let x25519_secret = StaticSecret::from(x25519_bytes);
+        let x25519_public = X25519PublicKey::from(&x25519_secret);
+
+        Ok(Self {
+            ed25519_keypair: Keypair { secret, public },
+            x25519_secret,
+            x25519_public,
+        })
+    }
+
+    /// Write the secret keys to `path`, readable only by the owner.
+    pub fn save_to_file(&self, path: &Path) -> Result<()> {
+        let (ed25519_secret, x25519_secret) = self.export_secrets();
+        let file = KeyFile { ed25519_secret: hex::encode(ed25519_secret), x25519_secret: hex::encode(x25519_secret) };
+        crate::secure_fs::write_private(path, serde_json::to_string_pretty(&file)?)
+    }
+
+    /// Read back keys written by `save_to_file`.
+    pub fn load_from_file(path: &Path) -> Result<Self> {
+        let file: KeyFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Self::from_secrets(&hex::decode(&file.ed25519_secret)?, &hex::decode(&file.x25519_secret)?)
+    }
+
+    /// Secret key bytes as (Ed25519, X25519), for export.
+    pub fn export_secrets(&self) -> ([u8; 32], [u8; 32]) {
+        (self.ed25519_keypair.secret.to_bytes(), self.x25519_secret.to_bytes())
+    }
+
+    /// Key for encrypting local data at rest, derived from the identity so it
+    /// never has to be stored.
+    pub fn local_store_key(&self) -> [u8; 32] {
+        self.derive_key(LOCAL_STORE_KEY_INFO)
+    }
+
+    /// What `local_store_key` was before it came from HKDF: a hash of the
+    /// secret. Only for reading what was sealed with it, and matching
+    /// search index entries made with it.
+    pub fn legacy_local_store_key(&self) -> [u8; 32] {
+        self.legacy_key(b"msgproto-local-store")
+    }
+
+    /// Reverse of [`seal`] with the local store key, falling back to the
+    /// legacy one for data sealed before the switch.
+    pub fn open_local(&self, sealed: &str) -> Result<String> {
+        open(&self.local_store_key(), sealed).or_else(|_| open(&self.legacy_local_store_key(), sealed))
+    }
+
+    /// Key for values kept on the server with `PutUserData`, so only this
+    /// identity can read them.
+    pub fn user_data_key(&self) -> [u8; 32] {
+        self.derive_key(USER_DATA_KEY_INFO)
+    }
+
+    /// Reverse of [`seal`] with the user data key, falling back to the
+    /// hash-derived key values were sealed with before it.
+    pub fn open_user_data(&self, sealed: &str) -> Result<String> {
+        open(&self.user_data_key(), sealed).or_else(|_| open(&self.legacy_key(b"msgproto-user-data"), sealed))
+    }
+
+    /// A key of its own for `info`'s purpose, HKDF-SHA256 over the X25519 secret.
+    fn derive_key(&self, info: &[u8]) -> [u8; 32] {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, &self.x25519_secret.to_bytes())
+            .expand(info, &mut key)
+            .expect("32 bytes is within HKDF-SHA256's output limit");
+        key
+    }
+
+    fn legacy_key(&self, label: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(label);
+        hasher.update(self.x25519_secret.to_bytes());
+        hasher.finalize().into()
+    }
+
     pub fn get_ed25519_public_key(&self) -> PublicKey {
         self.ed25519_keypair.public
     }
 
-    #[allow(dead_code)]
     pub fn get_x25519_public_key(&self) -> X25519PublicKey {
         self.x25519_public
     }
 
-    #[allow(dead_code)]
-    pub fn sign(&self, message: &[u8]) -> Signature {
-        self.ed25519_keypair.sign(message)
+    pub fn sign_with_context(&self, context: &str, payload: &[u8]) -> Signature {
+        self.ed25519_keypair.sign(&contextual_payload(context, payload))
     }
 
-    #[allow(dead_code)]
-    pub fn verify(&self, message: &[u8], signature: &Signature, public_key: &PublicKey) -> Result<()> {
+    /// Answer a server's challenge `nonce` as `client_id`.
+    pub fn answer_challenge(&self, client_id: &str, nonce: &str) -> ChallengeAnswer {
+        let signature = self.sign_with_context(context::CHALLENGE, &challenge_payload(client_id, nonce));
+        ChallengeAnswer { nonce: nonce.to_string(), signature: hex::encode(signature.to_bytes()) }
+    }
+
+    pub fn verify_with_context(&self, context: &str, payload: &[u8], signature: &Signature, public_key: &PublicKey) -> Result<()> {
+        public_key.verify(&contextual_payload(context, payload), signature)?;
+        Ok(())
+    }
+
+    /// Verify a signature over the bare payload, as peers from before context
+    /// labels produce. Only for transition fallbacks.
+    pub fn verify_legacy(&self, message: &[u8], signature: &Signature, public_key: &PublicKey) -> Result<()> {
         public_key.verify(message, signature)?;
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// The symmetric key `encrypt_message` and `decrypt_message` use with
+    /// `peer`. Handing it to someone lets them read and write that one
+    /// conversation, and nothing else, with [`seal_message`] and [`open_message`].
+    pub fn conversation_key(&self, peer: &X25519PublicKey) -> [u8; 32] {
+        message_key(self.x25519_secret.diffie_hellman(peer).as_bytes(), &self.x25519_public, peer)
+    }
+
+    /// The raw shared secret, which clients from before [`CIPHER_V2`] used
+    /// as the key. Only for reading their messages.
+    fn legacy_conversation_key(&self, peer: &X25519PublicKey) -> [u8; 32] {
+        *self.x25519_secret.diffie_hellman(peer).as_bytes()
+    }
+
     pub fn encrypt_message(&self, recipient_public_key: &X25519PublicKey, message: &str) -> Result<Vec<u8>> {
-        // Generate shared secret
-        let shared_secret = self.x25519_secret.diffie_hellman(recipient_public_key);
-        
-        // Derive encryption key from shared secret
-        let key = Key::from_slice(&shared_secret.as_bytes()[..32]);
-        let cipher = ChaCha20Poly1305::new(key);
-        
-        // Generate random nonce
-        let nonce_bytes = rand::random::<[u8; 12]>();
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        
-        // Encrypt message
-        let encrypted = cipher.encrypt(nonce, message.as_bytes())
-            .map_err(|e| anyhow!("Encryption failed: {}", e))?;
-        
-        // Combine nonce and encrypted data
-        let mut result = Vec::new();
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&encrypted);
-        
-        Ok(result)
+        seal_message(&self.conversation_key(recipient_public_key), message.as_bytes())
     }
 
-    #[allow(dead_code)]
     pub fn decrypt_message(&self, sender_public_key: &X25519PublicKey, encrypted_data: &[u8]) -> Result<String> {
-        if encrypted_data.len() < 12 {
+        String::from_utf8(self.decrypt_bytes(sender_public_key, encrypted_data)?)
+            .map_err(|e| anyhow!("Invalid UTF-8 in decrypted message: {}", e))
+    }
+
+    /// [`decrypt_message`](Self::decrypt_message) without requiring the
+    /// plaintext to be text, e.g. for a versioned payload.
+    pub fn decrypt_bytes(&self, sender_public_key: &X25519PublicKey, encrypted_data: &[u8]) -> Result<Vec<u8>> {
+        open_message(
+            &self.conversation_key(sender_public_key),
+            Some(&self.legacy_conversation_key(sender_public_key)),
+            encrypted_data,
+        )
+    }
+}
+
+/// First byte of a message encrypted under the [`message_key`]. Messages
+/// without it are `nonce || ciphertext` under the raw shared secret, from
+/// clients that predate the derivation.
+pub const CIPHER_V2: u8 = 2;
+
+/// HKDF info for message keys; both public keys follow it.
+const MESSAGE_KEY_INFO: &[u8] = b"messaging-protocol v1 msg-key";
+/// HKDF info for the key local data is sealed with.
+const LOCAL_STORE_KEY_INFO: &[u8] = b"messaging-protocol v1 local-store";
+/// HKDF info for the key user data is sealed with.
+const USER_DATA_KEY_INFO: &[u8] = b"messaging-protocol v1 user-data";
+
+/// The AEAD key for a conversation: HKDF-SHA256 over the X25519 shared
+/// secret, bound to both parties' public keys. They go in sorted, so both
+/// ends derive the same key whichever way a message travels.
+pub fn message_key(shared_secret: &[u8; 32], ours: &X25519PublicKey, theirs: &X25519PublicKey) -> [u8; 32] {
+    let (low, high) = if ours.as_bytes() <= theirs.as_bytes() { (ours, theirs) } else { (theirs, ours) };
+    let info = [MESSAGE_KEY_INFO, low.as_bytes(), high.as_bytes()].concat();
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(&info, &mut key)
+        .expect("32 bytes is within HKDF-SHA256's output limit");
+    key
+}
+
+/// Encrypt a message body with a conversation key, as `CIPHER_V2 || nonce
+/// || ciphertext`.
+pub fn seal_message(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce_bytes = rand::random::<[u8; 12]>();
+    let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+        .map_err(|e| anyhow!("Encryption failed: {}", e))?;
+    Ok([&[CIPHER_V2][..], &nonce_bytes, &encrypted].concat())
+}
+
+/// Bytes [`seal_message`] adds to a plaintext: the version byte, the
+/// nonce and the Poly1305 tag.
+pub const SEALED_OVERHEAD_BYTES: usize = 1 + 12 + 16;
+
+/// Length of a `plaintext_len`-byte plaintext once sealed.
+pub fn sealed_len(plaintext_len: usize) -> usize {
+    plaintext_len + SEALED_OVERHEAD_BYTES
+}
+
+/// Reverse of [`seal_message`]. Messages from before [`CIPHER_V2`] open
+/// with `legacy_key`, if given. A legacy nonce can start with the version
+/// byte by chance, so a v2 message that doesn't open is tried that way too.
+pub fn open_message(key: &[u8; 32], legacy_key: Option<&[u8; 32]>, data: &[u8]) -> Result<Vec<u8>> {
+    let open = |key: &[u8; 32], data: &[u8]| -> Result<Vec<u8>> {
+        if data.len() < 12 {
             return Err(anyhow!("Invalid encrypted data length"));
         }
-        
-        // Extract nonce and encrypted data
-        let nonce_bytes = &encrypted_data[..12];
-        let encrypted = &encrypted_data[12..];
-        
-        // Generate shared secret
-        let shared_secret = self.x25519_secret.diffie_hellman(sender_public_key);
-        
-        // Derive decryption key from shared secret
-        let key = Key::from_slice(&shared_secret.as_bytes()[..32]);
-        let cipher = ChaCha20Poly1305::new(key);
-        
-        // Decrypt message
-        let nonce = Nonce::from_slice(nonce_bytes);
-        let decrypted = cipher.decrypt(nonce, encrypted)
-            .map_err(|e| anyhow!("Decryption failed: {}", e))?;
-        
-        String::from_utf8(decrypted)
-            .map_err(|e| anyhow!("Invalid UTF-8 in decrypted message: {}", e))
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(Nonce::from_slice(&data[..12]), &data[12..])
+            .map_err(|e| anyhow!("Decryption failed: {}", e))
+    };
+    let v2 = match data.split_first() {
+        Some((&CIPHER_V2, rest)) => open(key, rest),
+        _ => Err(anyhow!("Not a v2 message")),
+    };
+    match (v2, legacy_key) {
+        (Ok(plaintext), _) => Ok(plaintext),
+        (Err(_), Some(legacy_key)) => open(legacy_key, data),
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// Generate a standalone Ed25519 keypair, returned as (secret, public) hex.
+pub fn generate_recovery_keypair() -> (String, String) {
+    let keypair = Keypair::generate(&mut OsRng);
+    (hex::encode(keypair.secret.as_bytes()), hex::encode(keypair.public.as_bytes()))
+}
+
+/// Sign with a raw Ed25519 secret key, e.g. an offline recovery key.
+pub fn sign_with_secret(secret_hex: &str, context: &str, payload: &[u8]) -> Result<Signature> {
+    let secret = SecretKey::from_bytes(&hex::decode(secret_hex)?)?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public }.sign(&contextual_payload(context, payload)))
+}
+
+/// Check a signature that was accepted earlier, e.g. when re-verifying
+/// stored data. In the contexts old clients signed without a label, it may
+/// have been taken from one of them, so one without passes too.
+pub fn verify_stored(context: &str, payload: &[u8], signature: &Signature, public_key: &PublicKey) -> Result<()> {
+    public_key.verify(&contextual_payload(context, payload), signature)
+        .or_else(|e| if context::LEGACY.contains(&context) { public_key.verify(payload, signature) } else { Err(e) })?;
+    Ok(())
+}
+
+/// Encrypt a value with a symmetric key, returned as hex `nonce || ciphertext`.
+pub fn seal(key: &[u8; 32], plaintext: &str) -> Result<String> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce_bytes = rand::random::<[u8; 12]>();
+    let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), plaintext.as_bytes())
+        .map_err(|e| anyhow!("Encryption failed: {}", e))?;
+    Ok(hex::encode([nonce_bytes.as_slice(), &encrypted].concat()))
+}
+
+/// Keyed hash of a search word, so an index can match words without storing them.
+pub fn search_token(key: &[u8; 32], word: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"msgproto-search-token");
+    hasher.update(key);
+    hasher.update(word.as_bytes());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+/// Reverse of [`seal`].
+pub fn open(key: &[u8; 32], sealed: &str) -> Result<String> {
+    String::from_utf8(open_bytes(key, sealed)?)
+        .map_err(|e| anyhow!("Invalid UTF-8 in decrypted value: {}", e))
+}
+
+/// [`open`] without requiring the plaintext to be text.
+pub fn open_bytes(key: &[u8; 32], sealed: &str) -> Result<Vec<u8>> {
+    let data = hex::decode(sealed)?;
+    if data.len() < 12 {
+        return Err(anyhow!("Invalid encrypted data length"));
+    }
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher.decrypt(Nonce::from_slice(&data[..12]), &data[12..])
+        .map_err(|e| anyhow!("Decryption failed: {}", e))
+}
+
+/// Short human-comparable fingerprint of a public key: the first 16 bytes of its
+/// SHA-256 digest, hex encoded in groups of four.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    hex::encode(&digest[..16])
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_signature_only_verifies_in_its_own_context() {
+        let signer = CryptoManager::new();
+        let public = signer.get_ed25519_public_key();
+        let payload = b"the same bytes";
+        let signature = signer.sign_with_context(context::SEND, payload);
+
+        assert!(signer.verify_with_context(context::SEND, payload, &signature, &public).is_ok());
+        for other in [context::ADMIN_BATCH, context::CHALLENGE, context::DELEGATE, context::PROMOTE, context::REVOKE] {
+            assert!(signer.verify_with_context(other, payload, &signature, &public).is_err(), "a send signature passed as {}", other);
+        }
+        assert!(signer.verify_legacy(payload, &signature, &public).is_err());
+    }
+
+    #[test]
+    fn an_unlabeled_signature_verifies_in_no_context() {
+        let signer = CryptoManager::new();
+        let public = signer.get_ed25519_public_key();
+        let payload = b"the same bytes";
+        let signature = signer.ed25519_keypair.sign(payload);
+
+        assert!(signer.verify_legacy(payload, &signature, &public).is_ok());
+        for context in [context::SEND, context::SENDER_KEY, context::CHALLENGE] {
+            assert!(signer.verify_with_context(context, payload, &signature, &public).is_err());
+        }
+    }
+
+    #[test]
+    fn only_legacy_contexts_take_a_stored_unlabeled_signature() {
+        let signer = CryptoManager::new();
+        let public = signer.get_ed25519_public_key();
+        let payload = b"the same bytes";
+        let unlabeled = signer.ed25519_keypair.sign(payload);
+
+        for context in context::LEGACY {
+            assert!(verify_stored(context, payload, &unlabeled, &public).is_ok(), "{} refused an old client's signature", context);
+        }
+        for context in [context::ACK, context::ADMIN_BATCH, context::CHALLENGE, context::REVOKE] {
+            assert!(verify_stored(context, payload, &unlabeled, &public).is_err(), "an unlabeled signature passed as {}", context);
+            let labeled = signer.sign_with_context(context, payload);
+            assert!(verify_stored(context, payload, &labeled, &public).is_ok());
+        }
+    }
+
+    #[test]
+    fn data_sealed_under_the_hash_derived_keys_still_opens() {
+        let identity = CryptoManager::new();
+        assert_ne!(identity.local_store_key(), identity.legacy_local_store_key());
+        assert_ne!(identity.local_store_key(), identity.user_data_key());
+
+        let old = seal(&identity.legacy_local_store_key(), "sealed before").unwrap();
+        let new = seal(&identity.local_store_key(), "sealed after").unwrap();
+        assert_eq!(identity.open_local(&old).unwrap(), "sealed before");
+        assert_eq!(identity.open_local(&new).unwrap(), "sealed after");
+        let old_value = seal(&identity.legacy_key(b"msgproto-user-data"), "value").unwrap();
+        assert_eq!(identity.open_user_data(&old_value).unwrap(), "value");
+        // Each key opens only its own purpose's data
+        assert!(identity.open_user_data(&new).is_err());
+        assert!(CryptoManager::new().open_local(&old).is_err());
+    }
+
+    #[test]
+    fn message_keys_match_the_rfc_5869_derivation() {
+        // RFC 5869 test case 3: SHA-256, no salt, no info
+        let okm = hex::decode("8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8").unwrap();
+        let mut derived = [0; 42];
+        Hkdf::<Sha256>::new(None, &[0x0b; 22]).expand(&[], &mut derived).unwrap();
+        assert_eq!(derived.as_slice(), okm);
+
+        // Keys derived before the switch to the hkdf crate must not change
+        let (ours, theirs) = (X25519PublicKey::from([9; 32]), X25519PublicKey::from([200; 32]));
+        let shared: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let expected = "c4abc53184efa2a1af76d39a6166a4de74c694a516c96c932f2fe8378045aab2";
+        assert_eq!(hex::encode(message_key(&shared, &ours, &theirs)), expected);
+        assert_eq!(hex::encode(message_key(&shared, &theirs, &ours)), expected);
+    }
+
+    #[test]
+    fn sealed_len_is_exact() {
+        for len in [0, 1, 15, 16, 17, 1000, 65536] {
+            assert_eq!(seal_message(&[7; 32], &vec![0; len]).unwrap().len(), sealed_len(len), "{} bytes", len);
+        }
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn v2_messages_round_trip_and_refuse_a_changed_version() {
+        let key = [7; 32];
+        let sealed = seal_message(&key, b"hello").unwrap();
+        assert_eq!(sealed[0], CIPHER_V2);
+        assert_eq!(open_message(&key, None, &sealed).unwrap(), b"hello");
+        assert!(open_message(&[8; 32], None, &sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        tampered[0] = CIPHER_V2 + 1;
+        assert!(open_message(&key, None, &tampered).is_err());
+        assert!(open_message(&key, Some(&key), &tampered).is_err());
+        let mut tampered = sealed;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_message(&key, None, &tampered).is_err());
+    }
+
+    #[test]
+    fn legacy_messages_open_with_the_raw_shared_secret() {
+        let (alice, bob) = (CryptoManager::new(), CryptoManager::new());
+        let legacy_key = *alice.x25519_secret.diffie_hellman(&bob.get_x25519_public_key()).as_bytes();
+        // What clients from before CIPHER_V2 sent: `nonce || ciphertext`
+        let nonce = [CIPHER_V2; 12];
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&legacy_key))
+            .encrypt(Nonce::from_slice(&nonce), b"from an old client".as_slice())
+            .unwrap();
+        let legacy = [nonce.as_slice(), &ciphertext].concat();
+
+        assert_eq!(bob.decrypt_message(&alice.get_x25519_public_key(), &legacy).unwrap(), "from an old client");
+        assert!(open_message(&bob.conversation_key(&alice.get_x25519_public_key()), None, &legacy).is_err());
+        let current = alice.encrypt_message(&bob.get_x25519_public_key(), "from a new one").unwrap();
+        assert_eq!(bob.decrypt_message(&alice.get_x25519_public_key(), &current).unwrap(), "from a new one");
+    }
+}
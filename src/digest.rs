@@ -0,0 +1,76 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Messages held back from one contact in the current window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingDigest {
+    pub count: usize,
+    /// When the first held message arrived; the summary is due a window later.
+    pub opened: DateTime<Utc>,
+}
+
+/// Per-contact digests: instead of printing every message from a busy
+/// contact, count them and print one summary per window. Messages still go
+/// to history as they arrive. Kept in the config so an open window outlives
+/// reconnects and restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Digests {
+    /// Window length in seconds, by contact.
+    #[serde(default)]
+    pub windows: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub pending: BTreeMap<String, PendingDigest>,
+}
+
+impl Digests {
+    /// Digest `contact`'s messages in windows of `secs`, or print them again
+    /// with `None`. Turning a digest off drops what it was holding.
+    pub fn set(&mut self, contact: &str, secs: Option<u64>) {
+        match secs {
+            Some(secs) => {
+                self.windows.insert(contact.to_string(), secs);
+            }
+            None => {
+                self.windows.remove(contact);
+                self.pending.remove(contact);
+            }
+        }
+    }
+
+    /// Count a message from `sender` towards its digest. Returns false if the
+    /// sender isn't digested and the message should be shown.
+    pub fn hold(&mut self, sender: &str, now: DateTime<Utc>) -> bool {
+        if !self.windows.contains_key(sender) {
+            return false;
+        }
+        self.pending.entry(sender.to_string())
+            .or_insert(PendingDigest { count: 0, opened: now })
+            .count += 1;
+        true
+    }
+
+    /// Take the digests whose window has closed, as (contact, count). Muted
+    /// contacts' digests are dropped instead: mute wins.
+    pub fn due(&mut self, now: DateTime<Utc>, muted: &BTreeSet<String>) -> Vec<(String, usize)> {
+        let mut due = Vec::new();
+        let windows = &self.windows;
+        self.pending.retain(|contact, pending| {
+            if muted.contains(contact) {
+                return false;
+            }
+            let window = Duration::seconds(windows.get(contact).copied().unwrap_or_default() as i64);
+            if pending.opened + window > now {
+                return true;
+            }
+            due.push((contact.clone(), pending.count));
+            false
+        });
+        due
+    }
+
+    /// The contact's conversation was opened; nothing is left to summarize.
+    pub fn clear(&mut self, contact: &str) -> bool {
+        self.pending.remove(contact).is_some()
+    }
+}
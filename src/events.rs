@@ -0,0 +1,51 @@
+//! What a [`Client`](crate::client::Client) has to tell whoever embeds it:
+//! pushed messages, notices its servers sent, and its connections to
+//! servers opening, dropping and being redialed. Each server's events come in the order its connection
+//! saw them, so a message pushed before a disconnect is always told of
+//! first. See [`Client::next_event`](crate::client::Client::next_event).
+
+use chrono::{DateTime, Utc};
+
+use crate::types::SystemNotice;
+
+/// A server as a connection to it found it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub addr: String,
+    /// Fingerprint of the server's key.
+    pub fingerprint: String,
+    pub protocol_version: u32,
+    /// Whether the connection pushes new messages. Only the one opened
+    /// when registering can.
+    pub push_delivery: bool,
+    /// The most ciphertext bytes a message may have, if the server said.
+    pub max_message_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// `server` pushed a message; `receive` fetches it.
+    Pushed { server: String },
+    /// `receive` fetched a notice from `server`, such as a quota warning or
+    /// being added to a group. It isn't among the messages `receive` returns.
+    Notice { server: String, notice: SystemNotice },
+    /// A connection to `server` opened: when registering, or when a
+    /// command dialed again after the last one dropped.
+    Connected { server: String, info: ServerInfo },
+    /// The connection to `server` dropped. The next command dials again.
+    Disconnected { server: String, reason: String },
+    /// A command is dialing `server` again; `attempt` counts the tries
+    /// since a connection last opened. Redialing happens on demand, not on
+    /// a schedule, so there is no delay to report.
+    Reconnecting { server: String, attempt: u32 },
+    /// The connection `server` was registered on dropped, and pushes with
+    /// it: connections dialed again aren't registered, so new messages only
+    /// come with `receive` until the client connects afresh.
+    SessionExpired { server: String },
+    /// `server` is shutting down. It serves open connections until
+    /// `deadline`, and takes none before then.
+    ServerDraining { server: String, deadline: DateTime<Utc> },
+    /// `server` answered with a key other than the one this identity knows
+    /// it by; both are fingerprints. Redials to it are refused.
+    KeyMismatch { server: String, expected: String, actual: String },
+}
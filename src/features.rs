@@ -0,0 +1,203 @@
+use anyhow::{Result, anyhow};
+
+/// Protocol version this build speaks. Peers that send no version predate
+/// negotiation and are taken to speak version 1.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// What a peer that doesn't negotiate is assumed to speak.
+const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// An optional part of the protocol. Peers agree on these at registration;
+/// servers can switch each off with `--disable-feature <id>`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Feature {
+    /// Stable name, shown to users and accepted on the command line.
+    pub id: &'static str,
+    /// Position in the bitsets exchanged at registration. Never reused.
+    pub bit: u32,
+    /// First protocol version that has the feature.
+    pub min_protocol: u32,
+}
+
+pub const MESSAGE_SEARCH: Feature = Feature { id: "message_search", bit: 0, min_protocol: 1 };
+pub const GUEST_LINKS: Feature = Feature { id: "guest_links", bit: 1, min_protocol: 1 };
+pub const USER_DATA: Feature = Feature { id: "user_data", bit: 2, min_protocol: 1 };
+pub const SYNC_CURSORS: Feature = Feature { id: "sync_cursors", bit: 3, min_protocol: 1 };
+/// Offered alone by sessions that only watch presence, such as status
+/// boards: no mailbox, so nothing is queued for them. Full clients leave
+/// it out.
+pub const PRESENCE_ONLY: Feature = Feature { id: "presence_only", bit: 4, min_protocol: 2 };
+/// The server writes `Incoming` frames to the registered connection as
+/// messages arrive, between responses. Only offered by clients that read
+/// their connection in the background.
+pub const PUSH_DELIVERY: Feature = Feature { id: "push_delivery", bit: 5, min_protocol: 2 };
+
+/// Every feature this build knows, in the order they are listed.
+pub const REGISTRY: &[&Feature] = &[&MESSAGE_SEARCH, &GUEST_LINKS, &USER_DATA, &SYNC_CURSORS, &PRESENCE_ONLY, &PUSH_DELIVERY];
+
+/// The feature called `id`.
+pub fn find(id: &str) -> Result<&'static Feature> {
+    REGISTRY.iter().copied().find(|feature| feature.id == id).ok_or_else(|| {
+        let known: Vec<&str> = REGISTRY.iter().map(|feature| feature.id).collect();
+        anyhow!("unknown feature '{}'; known features are {}", id, known.join(", "))
+    })
+}
+
+/// A set of features, sent over the wire as a bitset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureSet(u64);
+
+impl FeatureSet {
+    /// Every feature in the registry that `protocol` has.
+    pub fn supported(protocol: u32) -> FeatureSet {
+        REGISTRY.iter().filter(|feature| feature.min_protocol <= protocol).fold(FeatureSet::default(), |set, feature| set.with(feature))
+    }
+
+    /// Bits for features this build doesn't know are dropped.
+    pub fn from_bits(bits: u64) -> FeatureSet {
+        FeatureSet(bits & FeatureSet::supported(u32::MAX).0)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn with(self, feature: &Feature) -> FeatureSet {
+        FeatureSet(self.0 | 1 << feature.bit)
+    }
+
+    pub fn without(self, feature: &Feature) -> FeatureSet {
+        FeatureSet(self.0 & !(1 << feature.bit))
+    }
+
+    pub fn contains(self, feature: &Feature) -> bool {
+        self.0 & 1 << feature.bit != 0
+    }
+
+    /// Ids of the features in the set, in registry order.
+    pub fn ids(self) -> Vec<String> {
+        REGISTRY.iter().filter(|feature| self.contains(feature)).map(|feature| feature.id.to_string()).collect()
+    }
+}
+
+/// What both ends of a registration agreed on. Query this rather than
+/// keeping a flag per feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NegotiatedFeatures {
+    pub protocol: u32,
+    pub features: FeatureSet,
+    /// The peer didn't negotiate, so it may predate signature context
+    /// labels too, which came first: its sends and registrations may be
+    /// signed without one.
+    pub unlabeled_signatures: bool,
+}
+
+impl NegotiatedFeatures {
+    /// Agree with a peer that offered `theirs`, as (protocol version,
+    /// feature bits); `None` for a peer from before negotiation, which is
+    /// given everything protocol 1 had.
+    pub fn negotiate(ours: FeatureSet, theirs: Option<(u32, u64)>) -> NegotiatedFeatures {
+        let (protocol, offered) = match theirs {
+            Some((protocol, bits)) => (protocol.min(PROTOCOL_VERSION), FeatureSet::from_bits(bits)),
+            None => (LEGACY_PROTOCOL_VERSION, FeatureSet::supported(LEGACY_PROTOCOL_VERSION)),
+        };
+        let usable = FeatureSet::supported(protocol);
+        NegotiatedFeatures { protocol, features: FeatureSet(ours.0 & offered.0 & usable.0), unlabeled_signatures: theirs.is_none() }
+    }
+
+    pub fn has(&self, feature: &Feature) -> bool {
+        self.features.contains(feature)
+    }
+
+    /// An error naming the feature when it wasn't agreed.
+    pub fn require(&self, feature: &Feature) -> Result<()> {
+        if self.has(feature) {
+            Ok(())
+        } else {
+            Err(anyhow!("The server doesn't offer {} (protocol {}, features: {})",
+                feature.id, self.protocol, self.describe()))
+        }
+    }
+
+    /// The agreed features for display, or "none".
+    pub fn describe(&self) -> String {
+        let ids = self.features.ids();
+        if ids.is_empty() { "none".to_string() } else { ids.join(", ") }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(features: &[&Feature]) -> FeatureSet {
+        features.iter().fold(FeatureSet::default(), |set, feature| set.with(feature))
+    }
+
+    fn everything() -> FeatureSet {
+        FeatureSet::supported(PROTOCOL_VERSION)
+    }
+
+    #[test]
+    fn peers_agree_on_what_both_offer() {
+        let ours = everything().without(&GUEST_LINKS);
+        let theirs = set(&[&MESSAGE_SEARCH, &GUEST_LINKS, &PUSH_DELIVERY]);
+        let agreed = NegotiatedFeatures::negotiate(ours, Some((PROTOCOL_VERSION, theirs.bits())));
+        assert_eq!(agreed.protocol, PROTOCOL_VERSION);
+        assert_eq!(agreed.features, set(&[&MESSAGE_SEARCH, &PUSH_DELIVERY]));
+        assert!(!agreed.unlabeled_signatures);
+        let refused = agreed.require(&GUEST_LINKS).expect_err("a feature we switched off was agreed").to_string();
+        assert!(refused.contains("guest_links") && refused.contains("message_search, push_delivery"), "{}", refused);
+    }
+
+    #[test]
+    fn a_newer_peer_is_met_at_our_version() {
+        let theirs = everything().bits() | 1 << 40;
+        let agreed = NegotiatedFeatures::negotiate(everything(), Some((PROTOCOL_VERSION + 7, theirs)));
+        assert_eq!(agreed.protocol, PROTOCOL_VERSION);
+        // The bit we don't know is dropped, not passed back as agreed
+        assert_eq!(agreed.features, everything());
+    }
+
+    #[test]
+    fn an_older_peer_gets_only_what_its_version_had() {
+        // A protocol 1 peer claiming push delivery, which came with 2
+        let theirs = set(&[&MESSAGE_SEARCH, &PUSH_DELIVERY, &PRESENCE_ONLY]);
+        let agreed = NegotiatedFeatures::negotiate(everything(), Some((1, theirs.bits())));
+        assert_eq!(agreed.protocol, 1);
+        assert_eq!(agreed.features, set(&[&MESSAGE_SEARCH]));
+        assert!(agreed.require(&PUSH_DELIVERY).is_err());
+    }
+
+    #[test]
+    fn a_peer_from_before_negotiation_gets_protocol_1() {
+        let agreed = NegotiatedFeatures::negotiate(everything().without(&USER_DATA), None);
+        assert_eq!(agreed.protocol, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(agreed.features, set(&[&MESSAGE_SEARCH, &GUEST_LINKS, &SYNC_CURSORS]));
+        assert!(agreed.unlabeled_signatures);
+    }
+
+    #[test]
+    fn nothing_is_agreed_with_a_peer_offering_nothing() {
+        for theirs in [Some((PROTOCOL_VERSION, 0)), Some((0, everything().bits()))] {
+            let agreed = NegotiatedFeatures::negotiate(everything(), theirs);
+            assert_eq!(agreed.features, FeatureSet::default());
+            assert_eq!(agreed.describe(), "none");
+        }
+    }
+
+    #[test]
+    fn features_are_named_in_registry_order() {
+        assert_eq!(set(&[&PUSH_DELIVERY, &MESSAGE_SEARCH]).ids(), ["message_search", "push_delivery"]);
+        assert_eq!(find("user_data").unwrap(), &USER_DATA);
+        let unknown = find("telepathy").expect_err("an unknown feature was found").to_string();
+        assert!(unknown.contains("message_search, guest_links"), "{}", unknown);
+    }
+
+    #[test]
+    fn bits_are_unique() {
+        let mut bits: Vec<u32> = REGISTRY.iter().map(|feature| feature.bit).collect();
+        bits.sort();
+        bits.dedup();
+        assert_eq!(bits.len(), REGISTRY.len());
+    }
+}
@@ -0,0 +1,115 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest frame accepted unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 8 * 1024 * 1024;
+
+/// Why a frame couldn't be read or written.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The length prefix announced more than the limit. The body is left
+    /// unread, so the connection can't be used after this.
+    TooLarge { size: usize, max: usize },
+    /// The peer closed the connection partway through a frame.
+    Truncated,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } => write!(f, "Frame of {} bytes is over the {}-byte limit", size, max),
+            FrameError::Truncated => write!(f, "Connection closed in the middle of a frame"),
+            FrameError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            FrameError::Truncated
+        } else {
+            FrameError::Io(e)
+        }
+    }
+}
+
+/// Read one frame: a 4-byte big-endian length, then that many bytes.
+/// `None` if the peer closed the connection between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max: usize) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut prefix = [0; 4];
+    let mut filled = 0;
+    while filled < prefix.len() {
+        match reader.read(&mut prefix[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(FrameError::Truncated),
+            n => filled += n,
+        }
+    }
+    let size = u32::from_be_bytes(prefix) as usize;
+    if size > max {
+        return Err(FrameError::TooLarge { size, max });
+    }
+    let mut body = vec![0; size];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(body))
+}
+
+/// Write `payload` as one frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8], max: usize) -> Result<(), FrameError> {
+    if payload.len() > max || u32::try_from(payload.len()).is_err() {
+        return Err(FrameError::TooLarge { size: payload.len(), max });
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn frames_round_trip_in_order() {
+        // A pipe this small splits every frame across many reads
+        let (mut client, mut server) = duplex(3);
+        let payloads: Vec<Vec<u8>> = vec![b"{}".to_vec(), Vec::new(), vec![7; 100_000]];
+        let sent = payloads.clone();
+        let writer = tokio::spawn(async move {
+            for payload in &sent {
+                write_frame(&mut client, payload, DEFAULT_MAX_FRAME_BYTES).await.unwrap();
+            }
+        });
+        for payload in &payloads {
+            assert_eq!(read_frame(&mut server, DEFAULT_MAX_FRAME_BYTES).await.unwrap().as_ref(), Some(payload));
+        }
+        writer.await.unwrap();
+        // Closed between frames
+        assert!(read_frame(&mut server, DEFAULT_MAX_FRAME_BYTES).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn frames_over_the_limit_are_refused() {
+        let (mut client, mut server) = duplex(64);
+        assert!(matches!(write_frame(&mut client, &[0; 11], 10).await, Err(FrameError::TooLarge { size: 11, max: 10 })));
+
+        write_frame(&mut client, &[0; 11], 100).await.unwrap();
+        assert!(matches!(read_frame(&mut server, 10).await, Err(FrameError::TooLarge { size: 11, max: 10 })));
+    }
+
+    #[tokio::test]
+    async fn a_frame_cut_short_is_truncated() {
+        for sent in [&[0u8, 0][..], &[0, 0, 0, 5, b'a', b'b']] {
+            let (mut client, mut server) = duplex(64);
+            client.write_all(sent).await.unwrap();
+            drop(client);
+            assert!(matches!(read_frame(&mut server, DEFAULT_MAX_FRAME_BYTES).await, Err(FrameError::Truncated)), "{:?}", sent);
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use crate::template;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Command-line flag that picks the output language, e.g. `--lang es`.
+pub const LANG_FLAG: &str = "--lang";
+
+/// Languages with a catalog. English needs none: its text is written at
+/// each call site and is what every other language falls back to.
+const LANGUAGES: &[(&str, &[(&str, &str)])] = &[
+    ("en", &[]),
+    ("es", ES),
+];
+
+static LANG: OnceLock<&'static str> = OnceLock::new();
+
+/// Pick the output language: the flag's value, else the POSIX locale
+/// variables, else English. Unknown languages fall back to English.
+pub fn init(flag: Option<&str>) -> &'static str {
+    let requested = flag.map(str::to_string).or_else(|| {
+        ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+    });
+    let code = requested.as_deref()
+        .map(|locale| locale.split(['_', '.', '-']).next().unwrap_or("").to_lowercase())
+        .and_then(|code| LANGUAGES.iter().map(|(name, _)| *name).find(|name| *name == code))
+        .unwrap_or("en");
+    LANG.get_or_init(|| code)
+}
+
+fn catalog() -> &'static [(&'static str, &'static str)] {
+    let lang = LANG.get().copied().unwrap_or("en");
+    LANGUAGES.iter().find(|(name, _)| *name == lang).map(|(_, entries)| *entries).unwrap_or(&[])
+}
+
+/// Text for `key` in the current language with `{name}` placeholders filled
+/// in. A missing or broken translation falls back to `english`.
+pub fn translate(key: &str, english: &str, args: &[(&str, String)]) -> String {
+    let values: BTreeMap<String, String> = args.iter().map(|(name, value)| (name.to_string(), value.clone())).collect();
+    catalog().iter()
+        .find(|(entry, _)| *entry == key)
+        .and_then(|(_, text)| template::expand(text, &values).ok())
+        .or_else(|| template::expand(english, &values).ok())
+        .unwrap_or_else(|| english.to_string())
+}
+
+/// `tr!("key", "English text with {name}", name = value)`: user-facing text
+/// in the current language. Placeholders are named so translations can put
+/// them in any order.
+macro_rules! tr {
+    ($key:literal, $english:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::i18n::translate($key, $english, &[$((stringify!($name), $value.to_string())),*])
+    };
+}
+
+/// `say!(...)`: print a `tr!` line.
+macro_rules! say {
+    ($($args:tt)*) => {
+        $crate::output::out!("{}", $crate::i18n::tr!($($args)*))
+    };
+}
+
+pub(crate) use {say, tr};
+
+/// Spanish. Anything missing here is shown in English.
+const ES: &[(&str, &str)] = &[
+    // Startup
+    ("startup.title", "🔐 Cliente de mensajería segura"),
+    ("startup.client_id", "ID de cliente: {id}"),
+    ("startup.public_key", "Clave pública: {key}"),
+    ("startup.new_identity", "🔑 Nueva identidad generada en {path}"),
+    ("startup.x25519_key", "Clave X25519: {key}"),
+    ("startup.goodbye", "👋 ¡Adiós!"),
+    ("metrics.disabled", "📈 Este cliente se compiló sin la función client-metrics"),
+    // TUI
+    ("tui.contacts", "Contactos"),
+    ("tui.no_conversation", "Sin conversación"),
+    ("tui.no_contact", "❌ Elige primero un contacto con ↑↓"),
+    ("tui.connected", "conectado a {addr}"),
+    ("tui.disconnected", "sin conexión"),
+    ("tui.keys", "↑↓ contacto  RePág/AvPág desplazar  Intro enviar  /comando  Esc salir"),
+    ("config.load_failed", "⚠️ Aviso: no se pudo cargar la configuración {path}: {error}"),
+    ("paths.created", "📁 Creado {dir}"),
+    ("setup.welcome", "👋 ¡Hola! Unas preguntas para configurar este cliente."),
+    ("setup.resuming", "↩️ Retomando la configuración donde se quedó"),
+    ("setup.ask_id", "ID de cliente (al que te escriben)"),
+    ("setup.invalid", "❌ {error}"),
+    ("setup.ask_server", "Dirección del servidor"),
+    ("setup.unreachable", "❌ No se pudo contactar con {server}: {error}"),
+    ("setup.server_key", "🔑 {server} tiene la clave de servidor {fingerprint}"),
+    ("setup.done", "✅ Configuración lista. A partir de ahora, 'client' a secas arranca como {id}."),
+    ("setup.next", "   Comparte la huella de tu clave de 'whoami' con tus contactos para que puedan comprobarla."),
+    ("paths.hint", "   Ejecuta 'client paths' para ver dónde se guardan los archivos"),
+    ("paths.moved", "📦 Movido {file} desde el directorio de trabajo"),
+
+    // Help
+    ("help.title", "🔐 Cliente de mensajería segura - Modo interactivo"),
+    ("help.commands", "Comandos:"),
+    ("help.send", "Enviar un mensaje cifrado (--status sustituye tu último estado no recogido)"),
+    ("help.receive", "Buscar mensajes nuevos"),
+    ("help.history", "Mostrar los últimos n mensajes con un contacto"),
+    ("help.search", "Buscar en el historial local (--rebuild para reindexar)"),
+    ("help.send_template", "Enviar una plantilla guardada (también: tsend)"),
+    ("help.template_add", "Guardar una plantilla; {{name}} es un marcador, \\n un salto de línea"),
+    ("help.template_manage", "Gestionar plantillas"),
+    ("help.cancel", "Anular un mensaje, o pedir que se retire si ya se entregó"),
+    ("help.edit", "Enviar una versión corregida; sustituye al original si aún no se recogió"),
+    ("help.contacts", "Listar contactos en línea (--all incluye los archivados)"),
+    ("help.mute", "Dejar de mostrar / volver a mostrar los mensajes de un contacto"),
+    ("help.archive", "Ocultar / mostrar un contacto en los listados"),
+    ("help.add", "Añadir un contacto (clave X25519 en hexadecimal)"),
+    ("help.whoami", "Mostrar la identidad en uso"),
+    ("help.account", "Mostrar lo que el servidor guarda sobre ti, y tus límites"),
+    ("help.digest", "Resumir los mensajes de un contacto muy activo una vez por intervalo"),
+    ("help.retention", "Caducar los mensajes de un contacto tras p. ej. 7d"),
+    ("help.trust", "Confiar en la clave de un contacto tras una revocación"),
+    ("help.sync", "Envía todos tus contactos a tus otros dispositivos"),
+    ("help.report", "Denunciar spam; el servidor retiene sus mensajes durante un tiempo"),
+    ("help.audit_key", "Verificar y mostrar el historial de claves de un contacto"),
+    ("help.revoke", "Revocar la clave de esta identidad"),
+    ("help.recovery_key", "Crear una clave de recuperación para futuros registros"),
+    ("help.recovery_setup", "Repartir esta identidad entre contactos; k de ellos la restauran"),
+    ("help.recovery_status", "Revisar o devolver las partes que guardas"),
+    ("help.recover", "Pedir a quienes guardan partes que restauren tu identidad"),
+    ("help.export", "Guardar identidad, contactos y configuración"),
+    ("help.import", "Restaurar un archivo de export-state"),
+    ("help.server_switch", "Mostrar o cambiar el servidor de destino"),
+    ("help.server_stats", "Mostrar las latencias del servidor de destino"),
+    ("help.server_add", "Conectar con otro servidor, probando cada dirección [--separate-identity] [--allow-new-server]"),
+    ("help.delegate_grant", "Dejar que un contacto lea tu buzón (y envíe como tú) sin tus claves"),
+    ("help.delegate_manage", "Mostrar los delegados y lo que hicieron, o quitarle el acceso a uno"),
+    ("help.delegate_use", "Usar un buzón que te delegaron"),
+    ("help.guestlink_create", "Dejar que alguien sin identidad te escriba mediante un token"),
+    ("help.guestlink_manage", "Mostrar los enlaces de invitado y su uso, o cerrar uno"),
+    ("help.userdata", "Guardar pequeños valores cifrados en el servidor para tus otros dispositivos"),
+    ("help.mailbox_search", "Buscar mensajes en el servidor por remitente, fecha, tamaño o tipo"),
+    ("help.set_color", "Activar o desactivar colores por remitente"),
+    ("help.set_output", "Emoji y color, texto plano para lectores de pantalla, o solo resultados"),
+    ("help.set_store", "Elegir el almacén local (los datos de archivo migran a sqlite)"),
+    ("help.set_search_index", "Indexar las palabras del historial (como hashes con clave)"),
+    ("help.set_jitter", "Cuánto retener un mensaje mientras falta uno anterior de su remitente"),
+    ("help.set_escapes", "Mostrar escapados los caracteres de control de otros en vez de eliminarlos"),
+    ("help.rule_add", "Añadir una regla de filtrado local"),
+    ("help.rule_manage", "Gestionar las reglas de filtrado locales"),
+    ("help.rule_test", "Mostrar qué regla se aplicaría"),
+    ("help.quit", "Salir"),
+
+    // Identity
+    ("whoami.title", "🪪 Identidad"),
+    ("whoami.client_id", "  ID de cliente:  {id}"),
+    ("whoami.ed25519_key", "  Clave Ed25519:  {key}"),
+    ("whoami.x25519_key", "  Clave X25519:   {key}"),
+    ("whoami.fingerprint", "  Huella:         {fingerprint}"),
+    ("whoami.identity_file", "  Archivo:        {file}"),
+    ("whoami.ephemeral", "  Archivo:        (efímera, no se guarda)"),
+    ("whoami.server", "  Servidor:       {name} ({addr})"),
+    ("whoami.not_connected", "sin conexión"),
+    ("whoami.registered", "  Registrada:     sí"),
+    ("whoami.unregistered", "  Registrada:     no"),
+    ("whoami.protocol", "  Protocolo:      {protocol}"),
+    ("whoami.capabilities", "  Capacidades:    {capabilities}"),
+    ("whoami.no_capabilities", "  Capacidades:    (ninguna)"),
+    ("whoami.server_key", "  Clave servidor: {fingerprint} (sin vincular)"),
+    ("whoami.server_key_bound", "  Clave servidor: {fingerprint} (vinculada)"),
+    ("whoami.no_bound_servers", "  Servidores vinculados: (ninguno aún)"),
+    ("whoami.bound_servers", "  Servidores vinculados:"),
+    ("whoami.bound_server", "    {fingerprint} ({addr}, desde {date})"),
+    ("bind.added", "📌 {id} queda vinculada al servidor {fingerprint} ({addr})"),
+    ("bind.confirm", "{addr} tiene la clave de servidor {fingerprint}, con la que esta identidad nunca se ha registrado. ¿Registrarse de todos modos? [s/N]"),
+    ("whoami.session_age", "  Sesión:         {secs}s"),
+    ("whoami.failed", "❌ No se pudo mostrar la identidad: {error}"),
+    ("account.never", "nunca"),
+    ("account.title", "📒 Lo que {server} guarda sobre {id}"),
+    ("account.registered", "  Registrado:        {date}"),
+    ("account.last_seen", "  Última actividad:  {date}"),
+    ("account.mailbox_quota", "  Buzón:             {count} de {quota} mensaje(s), {bytes} bytes"),
+    ("account.mailbox", "  Buzón:             {count} mensaje(s), {bytes} bytes"),
+    ("account.stored_range", "  Guardados entre:   {oldest} y {newest}"),
+    ("account.sent_pending", "  Enviados sin leer: {undelivered} en espera, {held} retenidos para ids sin registrar"),
+    ("account.guest_links", "  Enlaces de invitado: {count} activos"),
+    ("account.user_data", "  Datos de usuario:  {keys} clave(s), {bytes} de {quota} bytes"),
+    ("account.delegations", "  Delegaciones:      {granted} concedidas, {received} recibidas"),
+    ("account.granted", "    → {delegate} ({scope}, {state})"),
+    ("account.received", "    ← {owner} ({scope}, {state})"),
+    ("account.failed", "❌ No se pudo obtener el uso de la cuenta: {error}"),
+
+    // Guest links
+    ("delegate.granted", "🛂 {delegate} tiene acceso {scope} a tu buzón hasta {until}; se le compartieron {count} conversación(es)"),
+    ("delegate.grant_failed", "❌ No se pudo delegar tu buzón: {error}"),
+    ("delegate.revoked", "🛂 {delegate} ya no puede usar tu buzón"),
+    ("delegate.revoke_failed", "❌ No se pudo revocar la delegación: {error}"),
+    ("delegate.none", "🛂 No has delegado tu buzón"),
+    ("delegate.state_revoked", "revocada"),
+    ("delegate.state_expired", "caducada"),
+    ("delegate.state_active", "activa"),
+    ("delegate.entry", "  {delegate}  {scope}, {state}, hasta {until}"),
+    ("delegate.audit_title", "🛂 Lo que hicieron tus delegados:"),
+    ("delegate.audit_entry", "  {at}  {delegate} {action} {id}"),
+    ("delegate.list_failed", "❌ No se pudieron listar las delegaciones: {error}"),
+    ("delegate.fetched_none", "📭 No hay mensajes nuevos para {owner}"),
+    ("delegate.no_key", "🔒 [{owner}] mensaje {id} de {sender}: {owner} no te ha compartido esa conversación"),
+    ("delegate.fetch_failed", "❌ No se pudieron obtener los mensajes de {owner}: {error}"),
+    ("delegate.sent", "✅ Enviado a {recipient} como {owner} ({id})"),
+    ("delegate.send_failed", "❌ No se pudo enviar como {owner}: {error}"),
+    ("delegate.usage", "❌ Uso: delegate <grant <contacto> <read|read-send> <duración>|list|revoke <contacto>|fetch <dueño>|send <dueño> <destinatario> <mensaje>>"),
+    ("delegate.keys_received", "🛂 {owner} te compartió {count} conversación(es); 'delegate fetch {owner}' lee su buzón"),
+    ("delegate.keys_failed", "❌ No se pudieron guardar las claves de conversación que compartió {owner}: {error}"),
+    ("guestlink.create_usage", "❌ Uso: guestlink create [--ttl <dur>] [--max <n>] [--plain]"),
+    ("guestlink.created", "🔗 El enlace de invitado {id} admite {max} mensaje(s) hasta {until}. Comparte este token:"),
+    ("guestlink.created_hint", "   Se envía con: client send --guest-token <token> <mensaje>"),
+    ("guestlink.plain_warning", "⚠️ Los mensajes por este enlace no van cifrados; el servidor puede leerlos"),
+    ("guestlink.create_failed", "❌ No se pudo crear el enlace de invitado: {error}"),
+    ("guestlink.none", "🔗 No hay enlaces de invitado"),
+    ("guestlink.state_revoked", "revocado"),
+    ("guestlink.state_expired", "caducado"),
+    ("guestlink.state_used_up", "agotado"),
+    ("guestlink.state_open", "abierto"),
+    ("guestlink.encrypted", "cifrado"),
+    ("guestlink.plain", "sin cifrar"),
+    ("guestlink.entry", "  {id}  {state}, {used}/{max} mensaje(s), {kind}, hasta {until}"),
+    ("guestlink.list_failed", "❌ No se pudieron listar los enlaces de invitado: {error}"),
+    ("guestlink.revoked", "🔗 Enlace de invitado {id} revocado"),
+    ("guestlink.revoke_failed", "❌ No se pudo revocar el enlace de invitado: {error}"),
+    ("guestlink.usage", "❌ Uso: guestlink <create [--ttl <dur>] [--max <n>] [--plain]|list|revoke <id>>"),
+    ("guest.expired", "Este enlace de invitado ha caducado"),
+    ("guest.revoked", "Este enlace de invitado ha sido revocado"),
+    ("guest.used_up", "Este enlace de invitado no admite más mensajes"),
+    ("guest.invalid", "El servidor no conoce este enlace de invitado"),
+    ("guest.sent", "✅ Mensaje enviado a {owner} ({id})"),
+    ("message.guest_plain", "[invitado, sin cifrar] {text}"),
+
+    // User data
+    ("userdata.conflict", "{key} cambió en otro lugar; léelo de nuevo antes de escribir"),
+    ("userdata.version", "(versión {version})"),
+    ("userdata.missing", "📭 No hay datos de usuario en {key}"),
+    ("userdata.get_failed", "❌ No se pudieron leer los datos de usuario: {error}"),
+    ("userdata.stored", "💾 {key} guardado (versión {version})"),
+    ("userdata.deleted", "🗑️ {key} eliminado"),
+    ("userdata.put_failed", "❌ No se pudieron guardar los datos de usuario: {error}"),
+    ("help.key", "Respalda solo las claves de esta identidad en un archivo .msgkey, o restaura uno"),
+    ("key.backed_up", "🔑 Claves respaldadas en {path}"),
+    ("key.backup_failed", "❌ No se pudieron respaldar las claves: {error}"),
+    ("key.inspect", "🔑 {path}: formato v{version}, {wrapping}"),
+    ("key.inspect_failed", "❌ La comprobación del respaldo de claves falló: {error}"),
+    ("key.inspect_identity", "  Identidad: {id} ({fingerprint}), respaldada el {date}"),
+    ("key.restore_failed", "❌ No se pudieron restaurar las claves: {error}"),
+    ("key.unwrapped", "sin frase de paso"),
+    ("key.usage", "❌ Uso: key <backup|restore|inspect> <archivo> [--passphrase <p>]"),
+    ("key.wrapped", "protegido con frase de paso"),
+    ("userdata.usage", "❌ Uso: userdata <get <clave>|set <clave> <valor>|delete <clave>>"),
+    ("help.label", "Etiqueta un mensaje en privado, sincronizado con tus otros dispositivos"),
+    ("help.star", "Marca un mensaje con estrella en privado"),
+    ("help.labels", "Lista los mensajes etiquetados y con estrella de un contacto"),
+    ("receive.annotation_foreign", "⚠️ Se ignoró una anotación de {sender}"),
+    ("label.usage", "❌ Uso: label|unlabel <id_mensaje> <etiqueta>, star|unstar <id_mensaje>"),
+    ("label.done", "🏷️ {id} actualizado"),
+    ("label.failed", "❌ No se pudieron actualizar las etiquetas: {error}"),
+    ("labels.usage", "❌ Uso: labels <contacto>"),
+    ("labels.none", "🏷️ No hay mensajes etiquetados con {contact}"),
+    ("labels.unreadable", "(ilegible)"),
+    ("help.admin", "Muestra cómo se registró un cliente (solo administradores del servidor)"),
+    ("help.admin_invite", "Crea un código de un solo uso para registrarse (solo administradores del servidor)"),
+    ("help.admin_promote", "Convierte el servidor actual, un secundario, en el primario (solo administradores del servidor)"),
+    ("help.admin_dump", "Muestra las conexiones, colas y esperas de bloqueos del servidor actual en JSON (solo administradores del servidor)"),
+    ("admin.registered", "Registrado: {time}"),
+    ("admin.source", "Origen: {source}"),
+    ("admin.not_kept", "no guardado"),
+    ("admin.transport", "Transporte: {transport}"),
+    ("admin.via", "Vía: {via}"),
+    ("admin.direct", "registro directo"),
+    ("admin.protocol", "Protocolo: {version}"),
+    ("admin.capabilities", "Capacidades: {capabilities}"),
+    ("admin.no_registration", "Registrado antes de que se guardaran los detalles de registro"),
+    ("admin.details_failed", "❌ No se pudieron obtener los detalles del cliente: {error}"),
+    ("admin.invite_code", "🎟️ Código de invitación, válido para un registro:"),
+    ("admin.invite_code_hint", "   Se registran con: client <id> {flag} <código>"),
+    ("admin.invite_code_failed", "❌ No se pudo crear un código de invitación: {error}"),
+    ("admin.usage", "❌ Uso: admin client <id_cliente> | admin invite-code | admin promote | admin dump | admin batch [--best-effort] <acción>; <acción>..."),
+    ("presence.everyone", "todos"),
+    ("presence.poll_failed", "⚠️ No se pudo comprobar quién está en línea: {error}"),
+    ("presence.online", "● {id} está en línea"),
+    ("presence.offline", "○ {id} está desconectado"),
+    ("presence.title", "👥 Observando a {who} en {server}"),
+    ("help.admin_batch", "Ejecuta varias acciones de administración a la vez, todas o ninguna salvo con --best-effort: ban <id> [motivo], unban <id>, prune <id>, notice [--to a,b] <texto>, invite-code <código> (solo administradores del servidor)"),
+    ("admin.batch_applied", "🧺 Lote aplicado:"),
+    ("admin.batch_not_applied", "🧺 No se aplicó nada del lote:"),
+    ("admin.batch_failed", "❌ No se pudo ejecutar el lote: {error}"),
+    ("admin.promoted", "🪞 {server} es ahora el primario y acepta escrituras"),
+    ("admin.promote_failed", "❌ No se pudo promover el servidor: {error}"),
+    ("admin.dump_failed", "❌ No se pudo obtener el volcado de depuración: {error}"),
+
+    // Servers
+    ("server.list_disconnected", "{marker} {name} {addr} (sin conexión)"),
+    ("server.switched", "🔀 Ahora se usa el servidor {name}"),
+    ("server.not_connected", "❌ Sin conexión con el servidor {name}"),
+    ("server.connected", "✅ Conectado al servidor {name} ({addr})"),
+    ("server.disconnected", "🔌 Se perdió la conexión con el servidor {name} ({reason}); el próximo comando reconecta"),
+    ("server.session_expired", "📴 El servidor {name} ya no avisa de mensajes nuevos; 'receive' los sigue trayendo"),
+    ("server.key_mismatch", "⛔ El servidor {name} respondió con la clave {actual}, no {expected}"),
+    ("server.connect_failed", "❌ No se pudo conectar a {addr}: {error}"),
+    ("server.connect_warning", "⚠️ No se pudo conectar al servidor {name} ({addr}): {error}"),
+    ("server.usage", "❌ Uso: server <list|switch <nombre>|stats|add <nombre> <dirección>[,<dirección>..] [--separate-identity]>"),
+    ("stats.summary", "📊 {server} activo {uptime}s, caché de claves {hits} aciertos / {misses} fallos"),
+    ("stats.degraded", "  ⚠️ almacenamiento fallando desde {since}: se rechazan mensajes nuevos"),
+    ("stats.disk", "  disco: {bytes} bytes ({components})"),
+    ("stats.features", "  funciones: {features}"),
+    ("stats.rotations", "  rotaciones de registros: {rotations}"),
+    ("stats.reports", "  denuncias de spam: {reports}"),
+    ("stats.integrity", "  comprobación de integridad ({state}): {checked}/{total} mensajes comprobados, {quarantined} en cuarentena"),
+    ("stats.integrity_done", "terminada"),
+    ("stats.integrity_running", "en curso"),
+    ("stats.failed", "❌ No se pudieron obtener las estadísticas: {error}"),
+
+    // Sending
+    ("send.usage", "❌ Uso: send [--ttl <duración>] [--deliver-within <duración>] [--confirm] [--status] <destinatario> <mensaje>"),
+    ("send.replaced", "♻️ {recipient} aún no había recogido {id}; solo se entregará esta versión"),
+    ("edit.usage", "❌ Uso: edit <id_del_mensaje> <mensaje>"),
+    ("edit.sent", "✏️ Enviada la edición de {id} ({new_id})"),
+    ("edit.failed", "❌ No se pudo editar el mensaje: {error}"),
+    ("lookalike.collides", "{id} se ve igual que {other}"),
+    ("lookalike.mixed", "{id} mezcla letras de distintos alfabetos"),
+    ("lookalike.confirm", "¿Enviar a este id de todos modos? [s/N]"),
+    ("lookalike.not_sent", "No se envió: {warning}. Revisa el id o vuelve a enviar con --confirm"),
+    ("send.sent", "✅ Mensaje enviado a {recipient} ({id})"),
+    ("send.failed", "❌ No se pudo enviar el mensaje: {error}"),
+    ("send.invalid_duration", "❌ Duración no válida; usa p. ej. 30s, 15m, 12h o 7d"),
+    ("send.expires", "⏳ El mensaje caduca el {expires_at}"),
+    ("send.retention_expires", "⏳ La política de retención de {recipient} hace caducar este mensaje el {expires_at}"),
+    ("send.pending_invite", "📨 {recipient} aún no se ha registrado; el servidor le guarda el mensaje hasta el {until}"),
+    ("send.deliver_by", "⌛ Se te avisará si {recipient} no lo ha recogido antes del {deliver_by}"),
+    ("send.receipt_rejected", "🚨 El recibo del servidor del mensaje {id} no es válido: {error}"),
+    ("send.revoked", "🚨 {recipient} revocó una clave el {timestamp} ({reason})."),
+    ("send.revoked_hint", "🚨 Consigue su nueva clave, vuelve a añadirla y ejecuta 'trust {recipient}' antes de enviar."),
+    ("tsend.usage", "❌ Uso: tsend <destinatario> <plantilla> [nombre=valor ...]"),
+    ("template.add_usage", "❌ Uso: template add <nombre> <cuerpo>"),
+    ("template.saved", "📝 Plantilla {name} guardada"),
+    ("template.saved_placeholders", "📝 Plantilla {name} guardada con los marcadores {placeholders}"),
+    ("template.none", "📝 No hay plantillas"),
+    ("template.unknown", "❌ No hay ninguna plantilla llamada {name}"),
+    ("template.removed", "🗑️ Plantilla {name} eliminada"),
+    ("template.usage", "❌ Uso: template add <nombre> <cuerpo> | list | show <nombre> | remove <nombre>"),
+    ("cancel.usage", "❌ Uso: cancel <id_del_mensaje>"),
+    ("cancel.cancelled", "🗑️ Anulado antes de la entrega"),
+    ("cancel.retraction", "↩️ Ya se entregó; se ha enviado una petición de retirada"),
+    ("cancel.failed", "❌ No se pudo anular el mensaje: {error}"),
+
+    // Receiving
+    ("receive.none", "📭 No hay mensajes nuevos"),
+    ("receive.count", "📥 {count} mensaje(s) recibido(s):"),
+    ("receive.sent_by", "✍️ El mensaje {id} de {sender} lo envió su delegado {delegate}"),
+    ("receive.muted", "🔇 {count} mensaje(s) de contactos silenciados"),
+    ("receive.gap", "⏳ {count} mensaje(s) anteriores de {sender} no llegaron a tiempo; se muestran los posteriores"),
+    ("receive.failed", "❌ No se pudieron recibir mensajes de {server}: {error}"),
+    ("receive.ack_failed", "⚠️ No se pudieron confirmar {count} mensaje(s) en {server}; se volverán a recibir: {error}"),
+    ("receive.receipt_rejected", "🚨 El recibo del servidor del mensaje {id} de {sender} no es válido: {error}"),
+    ("receive.key_rejected", "🚨 Se rechazó la clave del mensaje {id} de {sender}: {error}"),
+    ("receive.signature_invalid", "🚨 El mensaje {id} de {sender} no está firmado con su clave de identidad; puede haber sido alterado"),
+    ("message.invalid_withheld", "[retenido: la firma no es válida; inicia con {flag} para verlo]"),
+    ("receive.first_contact", "🆕 {sender} aún no es un contacto; su clave {fingerprint} está firmada por su identidad. 'add {sender} {key}' para guardarla"),
+    ("receive.retracted", "↩️ {sender} retiró el mensaje {id}"),
+    ("receive.retract_unknown", "⚠️ {sender} intentó retirar el mensaje desconocido {id}"),
+    ("message.undecryptable", "⚠️ [no se puede descifrar: {error}]"),
+    ("history.no_key", "ninguna clave lo abre"),
+    ("message.edit_of", "{body} (edición de {id})"),
+    ("payload.unsupported", "[versión de contenido {version} no compatible; actualiza el cliente para leerlo]"),
+    ("mailbox.none", "📭 No hay mensajes que coincidan en {server}"),
+    ("mailbox.more", "… hay más coincidencias; añade --cursor {cursor} para la página siguiente"),
+    ("mailbox.failed", "❌ Falló la búsqueda en el buzón: {error}"),
+    ("mailbox.row", "  {id} {time} de {sender}, {size} bytes{kind}{pending}"),
+    ("mailbox.notice", "aviso"),
+    ("mailbox.not_fetched", "(sin recoger)"),
+    ("mailbox.server", "servidor"),
+
+    // System notices
+    ("notice.delivery_failed", "📭 El mensaje {id} para {recipient} falló: {reason}"),
+    ("notice.expired_undelivered", "no se recogió antes de su plazo de entrega"),
+    ("notice.invite_unclaimed", "nadie registró ese id a tiempo"),
+    ("notice.invite_claimed", "📬 {recipient} se registró y recibió el mensaje {id}"),
+    ("notice.quota_warning", "📦 Tu buzón tiene {used} de un máximo de {limit} mensajes; recíbelos antes de que se rechace el correo nuevo"),
+    ("notice.group_added", "👥 {by} te añadió al grupo {group}"),
+    ("notice.disk_usage", "💽 Los datos del servidor ocupan {used} bytes, por encima del aviso de {threshold}"),
+
+    // History and search
+    ("history.usage", "❌ Uso: history <contacto> [n]"),
+    ("history.none", "📭 No hay historial con {peer}"),
+    ("history.other_identity", "🔒 {count} mensaje(s) se guardaron con otra identidad"),
+    ("history.cancelled", "{body} (anulado)"),
+    ("history.retracted", "{body} (retirado)"),
+    ("history.failed", "{body} (no entregado)"),
+    ("history.superseded", "{body} (sustituido antes de entregarse)"),
+    ("history.read_failed", "❌ No se pudo leer el historial: {error}"),
+    ("history.record_failed", "❌ No se pudo guardar el historial: {error}"),
+    ("contacts.cache_failed", "⚠️ No se pudo guardar la lista de clientes: {error}"),
+    ("history.update_failed", "❌ No se pudo actualizar el historial: {error}"),
+    ("search.none", "🔎 No hay mensajes que coincidan"),
+    ("search.indexed", "🔎 {indexed} mensaje(s) indexado(s), {skipped} omitido(s)"),
+    ("search.indexed_all", "🔎 {indexed} mensaje(s) indexado(s)"),
+    ("search.indexed_skipped", "🔎 {indexed} mensaje(s) indexado(s), {skipped} omitido(s) de contactos desconocidos"),
+    ("search.rebuild_failed", "❌ No se pudo reconstruir el índice de búsqueda: {error}"),
+
+    // Contacts
+    ("contacts.online", "👥 Contactos en línea:"),
+    ("contacts.failed", "❌ No se pudieron obtener los contactos: {error}"),
+    ("contacts.add_usage", "❌ Uso: add <id_contacto> <clave_publica>"),
+    ("contacts.invalid_key", "❌ Longitud de clave pública no válida"),
+    ("contacts.fetched", "🔑 Añadido {contact} con la clave que publicó ({fingerprint})"),
+    ("contacts.invalid_hex", "❌ Codificación hexadecimal no válida"),
+    ("contacts.unknown", "❌ Contacto desconocido {contact}"),
+    ("contacts.flag_usage", "❌ Uso: {command} <contacto>"),
+    ("contacts.flagged", "✅ {command}: {contact}"),
+    ("contacts.load_failed", "⚠️ No se pudieron cargar los contactos de {server}: {error}"),
+    ("contacts.save_failed", "❌ No se pudo guardar el contacto: {error}"),
+    ("trust.usage", "❌ Uso: trust <contacto>"),
+    ("trust.done", "🤝 Se confía en {contact} con la clave {fingerprint}"),
+    ("sync.usage", "❌ Uso: sync now"),
+    ("sync.sent", "🔄 Se enviaron {count} contacto(s) a tus otros dispositivos"),
+    ("sync.all_failed", "❌ No se pudieron sincronizar los contactos: {error}"),
+    ("sync.failed", "⚠️ No se pudo sincronizar {contact} con tus otros dispositivos: {error}; 'sync now' lo reintenta"),
+    ("sync.conflict", "⚠️ Otro dispositivo asignó a {contact} la clave {theirs}, pero aquí se confía en {ours}; usa 'add' y 'trust' con la clave correcta para resolverlo"),
+    ("receive.contact_sync_foreign", "⚠️ Se ignoró una sincronización de contactos de {sender}"),
+    ("retention.usage", "❌ Uso: retention <contacto> <duración|off>"),
+    ("digest.none", "🗞️ Ningún contacto con resumen"),
+    ("digest.entry", "  {contact}: cada {secs}s, {held} mensaje(s) retenido(s)"),
+    ("digest.cleared", "🗞️ Los mensajes de {contact} vuelven a mostrarse al llegar"),
+    ("digest.set", "🗞️ Los mensajes de {contact} se resumen cada {window}"),
+    ("digest.usage", "❌ Uso: digest [<contacto> <duración|off>]"),
+    ("digest.summary", "🗞️ {contact}: {count} mensaje(s) nuevo(s)"),
+    ("retention.set", "⏳ Los mensajes de {contact} caducan ahora tras {duration}"),
+    ("retention.cleared", "⏳ Retención de {contact} eliminada"),
+    ("retention.failed", "❌ No se pudo fijar la retención: {error}"),
+
+    ("report.usage", "❌ Uso: report <contacto> [motivo]"),
+    ("report.done", "🚩 {contact} denunciado; el servidor descarta sus mensajes para ti hasta el {until}"),
+    ("report.failed", "❌ No se pudo denunciar a {contact}: {error}"),
+
+    // Keys
+    ("audit.usage", "❌ Uso: audit-key <contacto>"),
+    ("audit.title", "🔎 Historial de claves de {client} (época {epoch}, cadena verificada):"),
+    ("audit.registered", "registrada"),
+    ("audit.revoked", "revocada"),
+    ("audit.empty", "  (no hay claves registradas)"),
+    ("audit.current", "  actual: {fingerprint}"),
+    ("audit.failed", "🚨 Falló la auditoría de claves: {error}"),
+    ("revoke.usage", "❌ Uso: revoke [--recovery <secreto>] <motivo>"),
+    ("revoke.done", "🚫 Clave revocada. El servidor la rechazará a partir de ahora."),
+    ("revoke.restart", "🚫 Reinicia el cliente para registrar una identidad nueva."),
+    ("revoke.failed", "❌ No se pudo revocar la clave: {error}"),
+    ("recovery_key.usage", "❌ Uso: recovery-key generate"),
+    ("recovery_key.public", "🔑 Clave pública de recuperación: {key}"),
+    ("recovery_key.secret", "🔑 SECRETO de recuperación (guárdalo sin conexión, solo se muestra una vez): {secret}"),
+    ("recovery_key.registered", "   Se registra en los servidores la próxima vez que este cliente se conecte."),
+
+    // Social recovery
+    ("recovery.setup_usage", "❌ Uso: recovery setup <umbral> <contacto,contacto,...>"),
+    ("recovery.setup_warning", "⚠️ Cualesquiera {threshold} de {contacts} pueden hacerse juntos con esta identidad."),
+    ("recovery.setup_advice", "⚠️ Elige personas que no vayan a confabularse y cuyos dispositivos sean de confianza."),
+    ("recovery.setup_done", "🛟 Enviadas {count} partes de recuperación; hacen falta {threshold} para restaurar"),
+    ("recovery.setup_failed", "❌ No se pudo configurar la recuperación: {error}"),
+    ("recovery.status_none", "🛟 No hay contactos de recuperación configurados"),
+    ("recovery.status_holders", "🛟 Las partes de esta identidad las guardan {contacts}"),
+    ("recovery.status_holding", "  guardas una parte de {owner}"),
+    ("recovery.status_requested", "  guardas una parte de {owner} (solicitada)"),
+    ("recovery.released", "🛟 Devuelta la parte de recuperación de {owner}"),
+    ("recovery.release_failed", "❌ No se pudo devolver la parte: {error}"),
+    ("recovery.usage", "❌ Uso: recovery <setup <k> <contactos>|status|release <propietario>>"),
+    ("recovery.asked", "🛟 Se pidió a {holder} tu parte de recuperación"),
+    ("recovery.holding", "🛟 Guardas la parte {index} de {total} de {owner} (hacen falta {threshold} para restaurar)"),
+    ("recovery.requested", "🛟 {owner} pide su parte de recuperación, para enviarla a la clave {fingerprint}"),
+    ("recovery.requested_warning", "   Cualquiera puede enviar esta petición. Confirma la huella en persona o por teléfono,"),
+    ("recovery.requested_hint", "   y después ejecuta 'recovery release {owner}'."),
+    ("recovery.share_returned", "🛟 {sender} devolvió la parte {index} ({count} de {threshold} necesarias)"),
+    ("recovery.message_failed", "❌ Mensaje de recuperación de {sender}: {error}"),
+    ("recover.usage", "❌ Uso: recover --from <contacto,contacto,...>"),
+    ("recover.sent", "🛟 Ejecuta 'receive' para recoger las partes devueltas"),
+    ("recover.failed", "❌ No se pudo pedir la recuperación: {error}"),
+
+    // State files
+    ("export.usage", "❌ Uso: {command} <archivo> [--passphrase <p>]"),
+    ("export.no_passphrase", "⚠️ Sin contraseña: el archivo guarda tus claves secretas en texto plano."),
+    ("export.done", "📦 Estado exportado a {path}"),
+    ("export.failed", "❌ No se pudo exportar el estado: {error}"),
+    ("import.identity", "✅ Identidad {fingerprint} restaurada"),
+    ("import.contacts", "✅ {count} contacto(s) restaurado(s)"),
+    ("import.config", "✅ Configuración restaurada"),
+    ("import.other_identity", "⚠️ El archivo pertenece a {owner}; se importa en {id}"),
+    ("import.invalid_key", "⚠️ Se omite {contact}: clave no válida"),
+    ("import.not_connected", "⚠️ Se omite {contact}: sin conexión con el servidor {server}"),
+    ("import.failed", "❌ No se pudo importar el estado: {error}"),
+
+    // Rules
+    ("rules.usage", "❌ Uso: rule <add|list|remove|test> ..."),
+    ("rules.added", "✅ Regla {number} añadida: {rule}"),
+    ("rules.none", "📭 No hay reglas"),
+    ("rules.remove_usage", "❌ Uso: rule remove <n>"),
+    ("rules.removed", "🗑️ Regla eliminada: {rule}"),
+    ("rules.would_fire", "🎯 Se aplicaría la regla {number}: {rule}"),
+    ("rules.no_match", "📭 Ninguna regla coincide"),
+    ("rules.command_failed", "❌ Falló el comando de la regla: {error}"),
+
+    // Settings
+    ("set.colors_on", "🎨 Colores activados"),
+    ("set.colors_off", "🎨 Colores desactivados"),
+    ("set.store", "🗃️ Se usa el almacén local {store}"),
+    ("set.store_failed", "❌ No se pudo abrir el almacén local: {error}"),
+    ("set.store_no_copy_back", "⚠️ Los datos de SQLite no se copian de vuelta al almacén de archivos"),
+    ("set.index_on", "🔎 Índice de búsqueda activado"),
+    ("set.index_off", "🔎 Índice de búsqueda desactivado y borrado"),
+    ("set.index_failed", "❌ No se pudo actualizar el índice de búsqueda: {error}"),
+    ("set.output", "✅ El modo de salida es ahora {mode}"),
+    ("set.jitter", "⏳ Se esperará hasta {ms}ms por mensajes desordenados"),
+    ("set.jitter_invalid", "❌ La ventana de espera es un número de milisegundos"),
+    ("set.escapes_on", "🛡️ Los caracteres de control de otros se muestran escapados, p. ej. \\x1b["),
+    ("set.escapes_off", "🛡️ Los caracteres de control de otros se eliminan"),
+    ("set.usage", "❌ Uso: set color <on|off> | set store <file|sqlite> | set search-index <on|off> | set output <rich|plain|quiet> | set jitter <ms> | set escapes <on|off>"),
+
+    // Errors
+    ("error.unknown_command", "❌ Comando desconocido. Escribe 'quit' para salir."),
+    ("error.save_config", "❌ No se pudo guardar la configuración: {error}"),
+    ("error.unexpected_response", "❌ Respuesta inesperada del servidor"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use std::path::Path;
+
+    /// Every client source file, with its text.
+    fn client_sources() -> Vec<(String, String)> {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut files = vec![src.join("client.rs")];
+        for entry in std::fs::read_dir(src.join("client")).unwrap() {
+            files.push(entry.unwrap().path());
+        }
+        files.into_iter()
+            .map(|path| (path.display().to_string(), std::fs::read_to_string(&path).unwrap()))
+            .collect()
+    }
+
+    /// Whether a format string has words in it once placeholders, `name=`
+    /// column labels and millisecond units are taken out.
+    fn has_words(format: &str) -> bool {
+        let placeholders = Regex::new(r"\{[^}]*\}|\\.|\b[a-z0-9_]+=|ms\b").unwrap();
+        Regex::new(r"[A-Za-z]{2,}").unwrap().is_match(&placeholders.replace_all(format, " "))
+    }
+
+    #[test]
+    fn client_output_goes_through_the_catalog() {
+        let print = Regex::new(r#"\b(println|eprintln|print|eprint|out|note|eout)!\(\s*"((?:[^"\\]|\\.)*)""#).unwrap();
+        let mut raw = Vec::new();
+        for (file, text) in client_sources() {
+            for (number, line) in text.lines().enumerate() {
+                if line.contains("println!(") {
+                    raw.push(format!("{}:{}: println! bypasses the output mode", file, number + 1));
+                }
+            }
+            for found in print.captures_iter(&text) {
+                if has_words(&found[2]) {
+                    let number = text[..found.get(0).unwrap().start()].lines().count();
+                    raw.push(format!("{}:{}: {}!(\"{}\")", file, number, &found[1], &found[2]));
+                }
+            }
+        }
+        assert!(raw.is_empty(), "user-facing text not routed through tr!/say!:\n{}", raw.join("\n"));
+    }
+
+    #[test]
+    fn translations_use_the_english_placeholders() {
+        let call = Regex::new(r#"\b(?:tr|say)!\(\s*"([^"]+)",\s*"((?:[^"\\]|\\.)*)""#).unwrap();
+        let english: BTreeMap<String, String> = client_sources().iter()
+            .flat_map(|(_, text)| call.captures_iter(text).map(|found| (found[1].to_string(), found[2].to_string())).collect::<Vec<_>>())
+            .collect();
+        for (lang, entries) in LANGUAGES {
+            for (key, text) in *entries {
+                let Some(original) = english.get(*key) else { continue };
+                let expected = template::placeholders(original).unwrap();
+                let actual = template::placeholders(text).unwrap_or_else(|e| panic!("{} {}: {}", lang, key, e));
+                assert_eq!(actual, expected, "{} translation of {} has different placeholders", lang, key);
+            }
+        }
+    }
+
+    #[test]
+    fn placeholders_can_be_reordered() {
+        let values: BTreeMap<String, String> = [("sender", "ana"), ("count", "3")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(template::expand("{count} messages from {sender}", &values).unwrap(), "3 messages from ana");
+        assert_eq!(template::expand("{sender} te envió {count} mensajes", &values).unwrap(), "ana te envió 3 mensajes");
+    }
+
+    #[test]
+    fn missing_translations_fall_back_to_english() {
+        assert_eq!(translate("no.such.key", "Hello {name}", &[("name", "ana".to_string())]), "Hello ana");
+    }
+}
@@ -0,0 +1,162 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// How often a lock was taken and how long takers waited for it.
+#[derive(Default)]
+pub struct LockStats {
+    acquired: AtomicU64,
+    /// Taken only after waiting for another holder.
+    contended: AtomicU64,
+    waited_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+/// `LockStats` at one instant, for the debug dump.
+#[derive(Debug, Clone, Serialize)]
+pub struct LockSnapshot {
+    pub name: &'static str,
+    pub acquired: u64,
+    pub contended: u64,
+    pub waited_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+impl LockStats {
+    fn record(&self, waited: Option<Duration>) {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        if let Some(waited) = waited {
+            let micros = waited.as_micros() as u64;
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            self.waited_micros.fetch_add(micros, Ordering::Relaxed);
+            self.max_wait_micros.fetch_max(micros, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A tokio `RwLock` that counts how often takers had to wait for it. Taking
+/// it costs one failed `try_` attempt more than a plain lock when contended.
+pub struct TimedRwLock<T> {
+    name: &'static str,
+    lock: RwLock<T>,
+    stats: LockStats,
+}
+
+impl<T> TimedRwLock<T> {
+    pub fn new(name: &'static str, value: T) -> Self {
+        Self { name, lock: RwLock::new(value), stats: LockStats::default() }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        if let Ok(guard) = self.lock.try_read() {
+            self.stats.record(None);
+            return guard;
+        }
+        let start = Instant::now();
+        let guard = self.lock.read().await;
+        self.stats.record(Some(start.elapsed()));
+        guard
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        if let Ok(guard) = self.lock.try_write() {
+            self.stats.record(None);
+            return guard;
+        }
+        let start = Instant::now();
+        let guard = self.lock.write().await;
+        self.stats.record(Some(start.elapsed()));
+        guard
+    }
+
+    pub fn stats(&self) -> LockSnapshot {
+        let micros_to_ms = |micros: u64| micros as f64 / 1000.0;
+        LockSnapshot {
+            name: self.name,
+            acquired: self.stats.acquired.load(Ordering::Relaxed),
+            contended: self.stats.contended.load(Ordering::Relaxed),
+            waited_ms: micros_to_ms(self.stats.waited_micros.load(Ordering::Relaxed)),
+            max_wait_ms: micros_to_ms(self.stats.max_wait_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Writes of one data file: how many, how many failed, and how long they took.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PersistStats {
+    pub writes: u64,
+    pub failures: u64,
+    pub bytes: u64,
+    pub last_ms: f64,
+    pub max_ms: f64,
+    pub last_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl PersistStats {
+    pub fn record(&mut self, took: Duration, bytes: u64, ok: bool) {
+        let ms = took.as_secs_f64() * 1000.0;
+        self.writes += 1;
+        if !ok {
+            self.failures += 1;
+        }
+        self.bytes = bytes;
+        self.last_ms = ms;
+        self.max_ms = self.max_ms.max(ms);
+        self.last_at = Some(chrono::Utc::now());
+    }
+}
+
+/// The last run of the background sweeps.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SweepStats {
+    pub runs: u64,
+    pub last_started: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_ms: f64,
+    pub bounced: usize,
+    pub expired_invites: usize,
+    pub pruned_guest_links: usize,
+    pub compacted_mailboxes: usize,
+    /// Skipped because storage was failing or this is a standby.
+    pub skipped: u64,
+    pub last_error: Option<String>,
+}
+
+/// Records waiting in a background writer's queue, and how long the last
+/// one waited before it was written.
+#[derive(Default)]
+pub struct QueueGauge {
+    depth: AtomicU64,
+    last_lag_micros: AtomicU64,
+    max_lag_micros: AtomicU64,
+}
+
+/// `QueueGauge` at one instant, for the debug dump.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueSnapshot {
+    pub depth: u64,
+    pub last_lag_ms: f64,
+    pub max_lag_ms: f64,
+}
+
+impl QueueGauge {
+    pub fn queued(&self) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A record queued at `queued_at` was taken off the queue.
+    pub fn taken(&self, queued_at: Instant) {
+        let micros = queued_at.elapsed().as_micros() as u64;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        self.last_lag_micros.store(micros, Ordering::Relaxed);
+        self.max_lag_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            depth: self.depth.load(Ordering::Relaxed),
+            last_lag_ms: self.last_lag_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            max_lag_ms: self.max_lag_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
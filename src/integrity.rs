@@ -0,0 +1,54 @@
+use crate::crypto;
+use crate::types::{KeyEvent, KeyLogEntry, Message, send_payload};
+use ed25519_dalek::{PublicKey, Signature};
+
+/// Messages the background pass checks between yields, so it never holds up
+/// requests for long.
+pub const BATCH_SIZE: usize = 100;
+
+/// Check a stored message's signature against the key its signer (the sender,
+/// or the delegate that sent it) had when the server accepted it. Messages
+/// from before epochs were recorded may match any key the signer has logged;
+/// signers missing from the log fall back to `current_key`. Notices and guest messages carry no signature and always pass.
+pub fn verify_message(message: &Message, key_log: &[KeyLogEntry], current_key: Option<&str>) -> Result<(), String> {
+    if message.notice.is_some() || message.guest.is_some() {
+        return Ok(());
+    }
+    let signature = message.signature.as_deref().ok_or("no signature")?;
+    let signature = hex::decode(signature).ok()
+        .and_then(|bytes| Signature::from_bytes(&bytes).ok())
+        .ok_or("malformed signature")?;
+
+    let signer = message.signer();
+    let registered = |entry: &&KeyLogEntry| entry.client_id == signer && entry.event == KeyEvent::Registered;
+    let mut keys: Vec<&str> = match message.key_epoch {
+        Some(epoch) => key_log.iter().take(epoch as usize).rfind(registered)
+            .map(|entry| entry.public_key.as_str())
+            .into_iter()
+            .collect(),
+        None => key_log.iter().filter(registered).map(|entry| entry.public_key.as_str()).collect(),
+    };
+    if keys.is_empty() && !key_log.iter().any(|entry| entry.client_id == signer) {
+        keys.extend(current_key);
+    }
+    if keys.is_empty() {
+        return Err(format!("no key on record for {}", signer));
+    }
+
+    // The payload Send verified: the ciphertext after the send time, id and
+    // recipient. A signature without a send time binds neither, so it could
+    // belong to any message with the same ciphertext
+    let sent_at = message.sent_at.ok_or_else(|| "no signed send time".to_string())?;
+    let ciphertext = hex::decode(&message.content).unwrap_or_default();
+    let payload = send_payload(&message.id, &message.recipient_id, &ciphertext, sent_at);
+    let verified = keys.iter().any(|key| {
+        hex::decode(key).ok()
+            .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+            .is_some_and(|key| crypto::verify_stored(crypto::context::SEND, &payload, &signature, &key).is_ok())
+    });
+    if verified {
+        Ok(())
+    } else {
+        Err("signature does not verify".to_string())
+    }
+}
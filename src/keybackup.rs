@@ -0,0 +1,295 @@
+use crate::config::ServerBinding;
+use crate::secure_fs;
+use anyhow::{Result, anyhow};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+
+/// First bytes of every `.msgkey` file.
+const MAGIC: &[u8; 6] = b"MSGKEY";
+
+/// Bump when the layout changes; older backups must keep restoring.
+pub const KEY_BACKUP_VERSION: u16 = 1;
+
+/// Set in the flags byte when the body is wrapped with a passphrase.
+const FLAG_PASSPHRASE: u8 = 0x01;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const MAC_LEN: usize = 32;
+/// Largest Argon2 memory cost (KiB) a backup may ask for, so a crafted file
+/// can't make restore allocate without bound.
+const MAX_M_COST: u32 = 1 << 20;
+/// Keys the MAC of backups without a passphrase. That only catches
+/// corruption, not tampering, but anyone able to tamper with such a file can
+/// read the keys in it anyway.
+const UNWRAPPED_MAC_DOMAIN: &[u8] = b"msgproto-keybackup-v1";
+
+/// What a key backup holds: the identity's secrets and the servers it is
+/// bound to, and nothing else.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyBackup {
+    pub client_id: String,
+    pub created_at: DateTime<Utc>,
+    pub ed25519_seed: String,
+    pub x25519_secret: String,
+    #[serde(default)]
+    pub bound_servers: Vec<ServerBinding>,
+}
+
+/// A key backup read back, with the header fields worth reporting.
+pub struct RestoredBackup {
+    pub version: u16,
+    pub passphrase: bool,
+    pub backup: KeyBackup,
+}
+
+/// v1 layout, integers big-endian:
+///
+/// ```text
+/// "MSGKEY" | version u16 | flags u8
+/// [argon2id m_cost u32 | t_cost u32 | p_cost u32 | salt 16 | nonce 12]  when passphrase-wrapped
+/// body length u32 | body (JSON, or its ChaCha20-Poly1305 ciphertext)
+/// HMAC-SHA256 over everything before it
+/// ```
+pub fn encode(backup: &KeyBackup, passphrase: Option<&str>) -> Result<Vec<u8>> {
+    let plaintext = serde_json::to_vec(backup)?;
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&KEY_BACKUP_VERSION.to_be_bytes());
+
+    let (body, mac_key) = match passphrase {
+        Some(passphrase) => {
+            let params = Params::default();
+            let salt = rand::random::<[u8; SALT_LEN]>();
+            let nonce = rand::random::<[u8; NONCE_LEN]>();
+            let (cipher_key, mac_key) = derive_keys(passphrase, &salt, params.m_cost(), params.t_cost(), params.p_cost())?;
+            out.push(FLAG_PASSPHRASE);
+            for cost in [params.m_cost(), params.t_cost(), params.p_cost()] {
+                out.extend_from_slice(&cost.to_be_bytes());
+            }
+            out.extend_from_slice(&salt);
+            out.extend_from_slice(&nonce);
+            let body = ChaCha20Poly1305::new(&cipher_key).encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+                .map_err(|e| anyhow!("Encryption failed: {}", e))?;
+            (body, mac_key)
+        }
+        None => {
+            out.push(0);
+            (plaintext, unwrapped_mac_key())
+        }
+    };
+
+    let body_len = u32::try_from(body.len()).map_err(|_| anyhow!("Key backup body is too large"))?;
+    out.extend_from_slice(&body_len.to_be_bytes());
+    out.extend_from_slice(&body);
+    let mac = mac(&mac_key, &out)?.finalize().into_bytes();
+    out.extend_from_slice(&mac);
+    Ok(out)
+}
+
+/// Read a backup, refusing anything truncated, padded, or failing its MAC
+/// before a single key is used.
+pub fn decode(bytes: &[u8], passphrase: Option<&str>) -> Result<RestoredBackup> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(anyhow!("Not a key backup (no MSGKEY header)"));
+    }
+    let mut reader = Reader { bytes, pos: MAGIC.len() };
+    let version = u16::from_be_bytes(reader.array()?);
+    if version == 0 || version > KEY_BACKUP_VERSION {
+        return Err(anyhow!("Key backup format v{} is not supported; this client reads up to v{}{}",
+            version, KEY_BACKUP_VERSION, if version > KEY_BACKUP_VERSION { " (it was written by a newer client)" } else { "" }));
+    }
+
+    let flags = reader.array::<1>()?[0];
+    if flags & !FLAG_PASSPHRASE != 0 {
+        return Err(anyhow!("Key backup uses features this client doesn't know (flags {:#04x})", flags));
+    }
+    let wrapping = if flags & FLAG_PASSPHRASE != 0 {
+        let m_cost = u32::from_be_bytes(reader.array()?);
+        let t_cost = u32::from_be_bytes(reader.array()?);
+        let p_cost = u32::from_be_bytes(reader.array()?);
+        let salt: [u8; SALT_LEN] = reader.array()?;
+        let nonce: [u8; NONCE_LEN] = reader.array()?;
+        if m_cost > MAX_M_COST {
+            return Err(anyhow!("Key backup asks for {} KiB of key derivation memory (at most {} allowed)", m_cost, MAX_M_COST));
+        }
+        Some((m_cost, t_cost, p_cost, salt, nonce))
+    } else {
+        None
+    };
+    let body_len = u32::from_be_bytes(reader.array()?) as usize;
+    let body = reader.take(body_len)?;
+    let signed_len = reader.pos;
+    let stored_mac = reader.take(MAC_LEN)?;
+    if reader.pos != bytes.len() {
+        return Err(anyhow!("Key backup has {} unexpected trailing byte(s)", bytes.len() - reader.pos));
+    }
+
+    let plaintext = match wrapping {
+        Some((m_cost, t_cost, p_cost, salt, nonce)) => {
+            let passphrase = passphrase.ok_or_else(|| anyhow!("Key backup is encrypted; pass --passphrase"))?;
+            let (cipher_key, mac_key) = derive_keys(passphrase, &salt, m_cost, t_cost, p_cost)?;
+            mac(&mac_key, &bytes[..signed_len])?.verify_slice(stored_mac)
+                .map_err(|_| anyhow!("Wrong passphrase or corrupted key backup"))?;
+            ChaCha20Poly1305::new(&cipher_key).decrypt(Nonce::from_slice(&nonce), body)
+                .map_err(|_| anyhow!("Wrong passphrase or corrupted key backup"))?
+        }
+        None => {
+            mac(&unwrapped_mac_key(), &bytes[..signed_len])?.verify_slice(stored_mac)
+                .map_err(|_| anyhow!("Key backup is corrupted (integrity check failed)"))?;
+            body.to_vec()
+        }
+    };
+    let backup = serde_json::from_slice(&plaintext).map_err(|e| anyhow!("Key backup body is invalid: {}", e))?;
+    Ok(RestoredBackup { version, passphrase: wrapping.is_some(), backup })
+}
+
+pub fn write(path: &str, backup: &KeyBackup, passphrase: Option<&str>) -> Result<()> {
+    secure_fs::write_private(path, encode(backup, passphrase)?)?;
+    Ok(())
+}
+
+pub fn read(path: &str, passphrase: Option<&str>) -> Result<RestoredBackup> {
+    decode(&fs::read(path)?, passphrase)
+}
+
+/// Cursor over the file that fails, rather than panics, on a short read.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("Key backup is truncated ({} bytes)", self.bytes.len()))?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+}
+
+/// One Argon2id run gives both the encryption key and the MAC key.
+fn derive_keys(passphrase: &str, salt: &[u8], m_cost: u32, t_cost: u32, p_cost: u32) -> Result<(Key, [u8; 32])> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(64))
+        .map_err(|e| anyhow!("Invalid key derivation parameters: {}", e))?;
+    let mut keys = [0u8; 64];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut keys)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    let mut mac_key = [0u8; 32];
+    mac_key.copy_from_slice(&keys[32..]);
+    Ok((*Key::from_slice(&keys[..32]), mac_key))
+}
+
+fn unwrapped_mac_key() -> [u8; 32] {
+    Sha256::digest(UNWRAPPED_MAC_DOMAIN).into()
+}
+
+fn mac(key: &[u8], data: &[u8]) -> Result<Hmac<Sha256>> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).map_err(|e| anyhow!("Invalid MAC key: {}", e))?;
+    mac.update(data);
+    Ok(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, CryptoManager};
+    use std::path::Path;
+
+    fn backup() -> KeyBackup {
+        let (ed25519, x25519) = CryptoManager::new().export_secrets();
+        KeyBackup {
+            client_id: "alice".to_string(),
+            created_at: Utc::now(),
+            ed25519_seed: hex::encode(ed25519),
+            x25519_secret: hex::encode(x25519),
+            bound_servers: vec![ServerBinding { fingerprint: "ab12 cd34".to_string(), addr: "127.0.0.1:8080".to_string(), bound_at: Utc::now() }],
+        }
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/keys").join(name)).unwrap()
+    }
+
+    fn error(bytes: &[u8], passphrase: Option<&str>) -> String {
+        decode(bytes, passphrase).err().expect("a bad backup was restored").to_string()
+    }
+
+    #[test]
+    fn backups_round_trip_with_and_without_a_passphrase() {
+        let original = backup();
+        for passphrase in [None, Some("correct horse")] {
+            let restored = decode(&encode(&original, passphrase).unwrap(), passphrase).unwrap();
+            assert_eq!(restored.version, KEY_BACKUP_VERSION);
+            assert_eq!(restored.passphrase, passphrase.is_some());
+            assert_eq!(restored.backup.client_id, original.client_id);
+            assert_eq!(restored.backup.created_at, original.created_at);
+            assert_eq!(restored.backup.ed25519_seed, original.ed25519_seed);
+            assert_eq!(restored.backup.x25519_secret, original.x25519_secret);
+            assert_eq!(restored.backup.bound_servers.len(), 1);
+            assert_eq!(restored.backup.bound_servers[0].fingerprint, "ab12 cd34");
+        }
+    }
+
+    #[test]
+    fn v1_fixtures_still_restore() {
+        for (name, passphrase) in [("v1-plain.msgkey", None), ("v1-passphrase.msgkey", Some("fixture"))] {
+            let restored = decode(&fixture(name), passphrase).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(restored.version, 1);
+            assert_eq!(restored.passphrase, passphrase.is_some());
+            assert_eq!(restored.backup.client_id, "fixture");
+            let identity = CryptoManager::from_secrets(
+                &hex::decode(&restored.backup.ed25519_seed).unwrap(),
+                &hex::decode(&restored.backup.x25519_secret).unwrap(),
+            ).unwrap();
+            assert_eq!(crypto::fingerprint(identity.get_ed25519_public_key().as_bytes()), "874c 829c 8769 7012 b089 5b2e 41f7 49d0");
+            assert_eq!(restored.backup.bound_servers.len(), 1);
+            assert_eq!(restored.backup.bound_servers[0].fingerprint, "8a9e 8c95 fc55 dacb 57fd 8b6b 5213 48fb");
+        }
+    }
+
+    #[test]
+    fn damaged_backups_are_refused() {
+        let plain = encode(&backup(), None).unwrap();
+        assert!(error(&plain[..plain.len() - 1], None).contains("truncated"));
+        assert!(error(&plain[..20], None).contains("truncated"));
+        assert!(error(&[plain.as_slice(), b"x"].concat(), None).contains("trailing"));
+        let mut flipped = plain.clone();
+        flipped[30] ^= 0x01;
+        assert!(error(&flipped, None).contains("integrity"));
+        assert!(error(b"not a backup", None).contains("MSGKEY"));
+    }
+
+    #[test]
+    fn wrong_or_missing_passphrases_are_refused() {
+        let wrapped = fixture("v1-passphrase.msgkey");
+        assert!(error(&wrapped, None).contains("--passphrase"));
+        assert!(error(&wrapped, Some("not it")).contains("Wrong passphrase"));
+    }
+
+    #[test]
+    fn unknown_versions_and_features_are_reported() {
+        let mut newer = encode(&backup(), None).unwrap();
+        newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(KEY_BACKUP_VERSION + 1).to_be_bytes());
+        assert!(error(&newer, None).contains("written by a newer client"));
+
+        let mut flagged = encode(&backup(), None).unwrap();
+        flagged[MAGIC.len() + 2] |= 0x80;
+        assert!(error(&flagged, None).contains("flags"));
+
+        // A crafted memory cost is refused before any key derivation
+        let mut greedy = fixture("v1-passphrase.msgkey");
+        greedy[MAGIC.len() + 3..MAGIC.len() + 7].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(error(&greedy, Some("fixture")).contains("memory"));
+    }
+}
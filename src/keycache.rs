@@ -0,0 +1,138 @@
+use ed25519_dalek::PublicKey;
+use log::debug;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+struct Inner {
+    keys: LruCache<String, PublicKey>,
+    /// Bumped on every invalidation so a lookup that raced one can't repopulate
+    /// the cache with the key it read before.
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Parsed client signing keys, so the hot paths skip the storage lookup and
+/// hex decoding. Only keys that were not revoked when read are cached.
+pub struct KeyCache {
+    inner: Mutex<Inner>,
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            inner: Mutex::new(Inner { keys: LruCache::new(capacity), generation: 0, hits: 0, misses: 0 }),
+        }
+    }
+
+    /// Returns the cached key, or the generation to pass to `insert` after
+    /// loading it from storage.
+    pub fn get(&self, client_id: &str) -> Result<PublicKey, u64> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        match inner.keys.get(client_id).copied() {
+            Some(key) => {
+                inner.hits += 1;
+                Ok(key)
+            }
+            None => {
+                inner.misses += 1;
+                debug!("🔑 Key cache miss for {} ({} hits, {} misses)", client_id, inner.hits, inner.misses);
+                Err(inner.generation)
+            }
+        }
+    }
+
+    /// Cache a key read from storage, unless an invalidation happened since `generation`.
+    pub fn insert(&self, client_id: &str, key: PublicKey, generation: u64) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if inner.generation == generation {
+            inner.keys.put(client_id.to_string(), key);
+        }
+    }
+
+    /// Must be called before acknowledging anything that changes or revokes a client's key.
+    pub fn invalidate(&self, client_id: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.keys.pop(client_id);
+        inner.generation += 1;
+    }
+
+    /// Drop every cached key, for when client records changed wholesale.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.keys.clear();
+        inner.generation += 1;
+    }
+
+    /// (hits, misses) since startup.
+    pub fn stats(&self) -> (u64, u64) {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        (inner.hits, inner.misses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CryptoManager;
+
+    fn key() -> PublicKey {
+        CryptoManager::new().get_ed25519_public_key()
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let cache = KeyCache::new();
+        let generation = cache.get("alice").unwrap_err();
+        let alice = key();
+        cache.insert("alice", alice, generation);
+        assert_eq!(cache.get("alice").unwrap(), alice);
+        assert_eq!(cache.stats(), (1, 1));
+    }
+
+    #[test]
+    fn a_lookup_that_raced_an_invalidation_is_not_cached() {
+        let cache = KeyCache::new();
+        // Read from storage before a rotation, cached after it was acknowledged
+        let generation = cache.get("alice").unwrap_err();
+        cache.invalidate("alice");
+        cache.insert("alice", key(), generation);
+        assert!(cache.get("alice").is_err(), "the pre-rotation key was cached");
+
+        let generation = cache.get("bob").unwrap_err();
+        cache.clear();
+        cache.insert("bob", key(), generation);
+        assert!(cache.get("bob").is_err());
+    }
+
+    #[test]
+    fn invalidation_drops_only_that_client() {
+        let cache = KeyCache::new();
+        for id in ["alice", "bob"] {
+            let generation = cache.get(id).unwrap_err();
+            cache.insert(id, key(), generation);
+        }
+        cache.invalidate("alice");
+        assert!(cache.get("alice").is_err());
+        assert!(cache.get("bob").is_ok());
+    }
+
+    #[test]
+    fn least_recently_used_keys_are_evicted() {
+        let cache = KeyCache::with_capacity(2);
+        for id in ["alice", "bob", "carol"] {
+            let generation = cache.get(id).unwrap_err();
+            cache.insert(id, key(), generation);
+        }
+        assert!(cache.get("alice").is_err());
+        assert!(cache.get("carol").is_ok());
+    }
+}
@@ -0,0 +1,62 @@
+//! A signed, end-to-end encrypted messaging protocol: its wire [`types`]
+//! and [`crypto`], the server's [`storage`] and its [`backend`], the
+//! [`client::Client`] and [`server::Server`] built on them, and [`bot`]s on
+//! the client. The `client`, `server` and `msgproto-conformance` binaries
+//! are thin wrappers around [`client::main`], [`server::main`] and
+//! [`conformance::main`].
+
+pub mod backend;
+pub mod bot;
+pub mod client;
+pub mod conformance;
+pub mod crypto;
+pub mod events;
+pub mod outgoing;
+pub mod paths;
+pub mod presence;
+pub mod render;
+pub mod server;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod storage;
+pub mod store;
+pub mod transport;
+pub mod types;
+
+mod alerts;
+mod annotations;
+mod auth;
+mod check;
+mod config;
+mod confusables;
+mod connections;
+mod contactsync;
+mod conversation;
+mod digest;
+mod features;
+mod frame;
+mod i18n;
+mod instrument;
+mod integrity;
+mod keybackup;
+mod keycache;
+mod metrics;
+mod output;
+mod pairlimit;
+mod payload;
+mod recovery;
+mod redact;
+mod reorder;
+mod replay;
+mod replication;
+mod rotate;
+mod rules;
+mod sanitize;
+#[cfg(any(test, feature = "sim", feature = "storage-conformance"))]
+mod scratch;
+mod secure_fs;
+mod server_config;
+mod shamir;
+mod state;
+mod telemetry;
+mod template;
@@ -0,0 +1,133 @@
+use crate::types::LatencyHistogram;
+use log::warn;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static PHASES: RefCell<PhaseTimes>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Parse,
+    Verify,
+    /// Storage calls, including the persistence they trigger.
+    Storage,
+    /// Writing data files to disk.
+    Persist,
+}
+
+#[derive(Debug, Default)]
+pub struct PhaseTimes {
+    totals: [Duration; 4],
+    // Nested timers of the same phase (storage methods calling each other) count once
+    depth: [u32; 4],
+}
+
+impl PhaseTimes {
+    fn get(&self, phase: Phase) -> Duration {
+        self.totals[phase as usize]
+    }
+}
+
+/// Adds the time until it is dropped to the current request's phase totals.
+/// Does nothing outside `scoped`.
+pub struct PhaseTimer {
+    phase: Phase,
+    start: Instant,
+    outermost: bool,
+}
+
+pub fn time(phase: Phase) -> PhaseTimer {
+    let outermost = PHASES.try_with(|phases| {
+        let mut phases = phases.borrow_mut();
+        phases.depth[phase as usize] += 1;
+        phases.depth[phase as usize] == 1
+    }).unwrap_or(false);
+    PhaseTimer { phase, start: Instant::now(), outermost }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let _ = PHASES.try_with(|phases| {
+            let mut phases = phases.borrow_mut();
+            phases.depth[self.phase as usize] = phases.depth[self.phase as usize].saturating_sub(1);
+            if self.outermost {
+                phases.totals[self.phase as usize] += elapsed;
+            }
+        });
+    }
+}
+
+/// Run one request with its own phase totals.
+pub async fn scoped<F: Future>(future: F) -> F::Output {
+    PHASES.scope(RefCell::new(PhaseTimes::default()), future).await
+}
+
+pub struct Metrics {
+    started: Instant,
+    slow_threshold: Duration,
+    latencies: Mutex<BTreeMap<String, LatencyHistogram>>,
+}
+
+impl Metrics {
+    pub fn new(slow_threshold: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            slow_threshold,
+            latencies: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record a finished request, logging a breakdown if it was slow. Call
+    /// inside `scoped` so the phase totals belong to this request.
+    pub fn record(&self, command: &str, client_id: Option<&str>, duration: Duration) {
+        self.latencies.lock().unwrap_or_else(|e| e.into_inner())
+            .entry(command.to_string())
+            .or_default()
+            .record(duration);
+
+        if duration < self.slow_threshold {
+            return;
+        }
+        let (parse, verify, storage, persist) = PHASES.try_with(|phases| {
+            let phases = phases.borrow();
+            (phases.get(Phase::Parse), phases.get(Phase::Verify), phases.get(Phase::Storage), phases.get(Phase::Persist))
+        }).unwrap_or_default();
+        let storage = storage.saturating_sub(persist);
+        let handler = duration.saturating_sub(parse + verify + storage + persist);
+        let dominant = [("parse", parse), ("verify", verify), ("storage", storage), ("persist", persist), ("handler", handler)]
+            .into_iter()
+            .max_by_key(|(_, time)| *time)
+            .map(|(name, _)| name)
+            .unwrap_or("handler");
+
+        warn!(
+            "🐢 slow request: command={} client={} duration_ms={:.1} dominant={} parse_ms={:.1} verify_ms={:.1} storage_ms={:.1} persist_ms={:.1}",
+            command,
+            client_id.unwrap_or("-"),
+            millis(duration),
+            dominant,
+            millis(parse),
+            millis(verify),
+            millis(storage),
+            millis(persist),
+        );
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn latencies(&self) -> BTreeMap<String, LatencyHistogram> {
+        self.latencies.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
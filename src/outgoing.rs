@@ -0,0 +1,56 @@
+//! How large a message will be on the wire, worked out before sealing it:
+//! what [`Client::validate_outgoing`](crate::client::Client::validate_outgoing)
+//! reports, and what the send path checks against the server's limit.
+
+use crate::crypto;
+use crate::payload::Payload;
+use anyhow::Result;
+use std::fmt;
+
+/// A message's size before and after sealing, and the limit it goes up
+/// against. Sealing adds a fixed overhead, so the sizes are exact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutgoingEstimate {
+    /// The encoded payload: its version byte, then the text and its fields.
+    pub payload_bytes: usize,
+    /// The sealed payload, which is what the server counts.
+    pub ciphertext_bytes: usize,
+    /// The most ciphertext bytes the server takes; `None` if it advertised
+    /// no limit.
+    pub max_message_size: Option<usize>,
+}
+
+impl OutgoingEstimate {
+    pub(crate) fn for_payload(payload: &Payload, max_message_size: Option<usize>) -> Result<Self> {
+        let payload_bytes = payload.encode()?.len();
+        Ok(OutgoingEstimate { payload_bytes, ciphertext_bytes: crypto::sealed_len(payload_bytes), max_message_size })
+    }
+
+    /// Whether the server will take it.
+    pub fn fits(&self) -> bool {
+        self.max_message_size.is_none_or(|max| self.ciphertext_bytes <= max)
+    }
+
+    /// Refuse it the way the server would, if it doesn't fit.
+    pub fn check(&self) -> Result<(), MessageTooLarge> {
+        match self.max_message_size {
+            Some(max) if !self.fits() => Err(MessageTooLarge { size: self.ciphertext_bytes, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A message larger, once sealed, than its server takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Messages are limited to {} bytes; this one would be {} encrypted", self.max, self.size)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
@@ -0,0 +1,316 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use tokio::sync::mpsc;
+
+/// Command-line flag that picks the output mode, e.g. `--output plain`.
+pub const OUTPUT_FLAG: &str = "--output";
+
+/// How user-facing text is presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    /// Emoji and color.
+    Rich,
+    /// No color or emoji; status is spelled out as `[OK]`, `[ERROR]` and so on.
+    /// Meant for screen readers, dumb terminals and pipes.
+    Plain,
+    /// Plain, minus confirmations, banners and help: only results and problems.
+    Quiet,
+}
+
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputMode::Rich => "rich",
+            OutputMode::Plain => "plain",
+            OutputMode::Quiet => "quiet",
+        })
+    }
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rich" => Ok(OutputMode::Rich),
+            "plain" => Ok(OutputMode::Plain),
+            "quiet" => Ok(OutputMode::Quiet),
+            other => Err(anyhow!("Unknown output mode '{}'; use rich, plain or quiet", other)),
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(0);
+/// Set once a mode is chosen explicitly, so a saved preference doesn't override the flag.
+static FROM_FLAG: AtomicBool = AtomicBool::new(false);
+/// Where lines go instead of the terminal while something else owns it.
+static REDIRECT: Mutex<Option<mpsc::UnboundedSender<String>>> = Mutex::new(None);
+
+/// Leading emoji that carry a status, and the word plain mode shows instead.
+/// Other leading emoji are decoration and are dropped.
+const STATUS_WORDS: &[(char, &str)] = &[
+    ('✅', "[OK]"),
+    ('❌', "[ERROR]"),
+    ('⚠', "[WARNING]"),
+    ('🚨', "[ALERT]"),
+    ('🚫', "[ALERT]"),
+];
+
+/// What a line is, which decides how each mode shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// Results and problems. Quiet mode still drops `✅` confirmations.
+    Normal,
+    /// Banners, help and other chatter quiet mode leaves out.
+    Note,
+    /// Other people's text, such as message bodies: shown as is in every mode,
+    /// so a body that starts with an emoji isn't reworded.
+    Data,
+}
+
+/// Pick the output mode: the flag's value, else auto-detected. Anything but
+/// rich also turns color off for the whole process.
+pub fn init(flag: Option<OutputMode>) {
+    FROM_FLAG.store(flag.is_some(), Ordering::Relaxed);
+    apply(flag.unwrap_or_else(detect), flag.is_some());
+}
+
+/// Switch to a saved or newly chosen mode, unless the flag picked one.
+pub fn prefer(mode: OutputMode) {
+    if !FROM_FLAG.load(Ordering::Relaxed) {
+        apply(mode, true);
+    }
+}
+
+pub fn mode() -> OutputMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => OutputMode::Plain,
+        2 => OutputMode::Quiet,
+        _ => OutputMode::Rich,
+    }
+}
+
+/// Plain when `NO_COLOR` is set, `TERM` is dumb or stdout isn't a terminal.
+fn detect() -> OutputMode {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+    if no_color || dumb || !std::io::stdout().is_terminal() {
+        OutputMode::Plain
+    } else {
+        OutputMode::Rich
+    }
+}
+
+fn apply(mode: OutputMode, explicit: bool) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+    match mode {
+        // An explicit rich keeps color even when piped
+        OutputMode::Rich if explicit => colored::control::set_override(true),
+        OutputMode::Rich => colored::control::unset_override(),
+        _ => colored::control::set_override(false),
+    }
+}
+
+/// `text` as the current mode shows it, or `None` if the mode leaves it out.
+pub fn present(level: Level, text: &str) -> Option<String> {
+    present_in(mode(), level, text)
+}
+
+/// `text` as `mode` shows it, or `None` if that mode leaves it out.
+fn present_in(mode: OutputMode, level: Level, text: &str) -> Option<String> {
+    if mode == OutputMode::Quiet && level == Level::Note {
+        return None;
+    }
+    if mode == OutputMode::Rich || level == Level::Data {
+        return Some(text.to_string());
+    }
+    let lines: Vec<String> = text.split('\n')
+        .filter(|line| mode != OutputMode::Quiet || !line.trim_start().starts_with('✅'))
+        .map(plain_line)
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+    Some(lines.join("\n"))
+}
+
+/// Swap a line's leading emoji for its status word, or drop it.
+fn plain_line(line: &str) -> String {
+    let rest = line.trim_start();
+    let indent = &line[..line.len() - rest.len()];
+    let Some(first) = rest.chars().next().filter(|c| is_emoji(*c)) else {
+        return line.to_string();
+    };
+    let body = rest.trim_start_matches(|c: char| is_emoji(c) || c == '\u{fe0f}' || c == '\u{200d}').trim_start();
+    match STATUS_WORDS.iter().find(|(emoji, _)| *emoji == first) {
+        Some((_, word)) => format!("{}{} {}", indent, word, body),
+        None => format!("{}{}", indent, body),
+    }
+}
+
+/// Pictographs used as status markers: the emoji planes plus the dingbat,
+/// arrow and miscellaneous-symbol blocks.
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32, 0x1F000..=0x1FAFF | 0x2190..=0x21FF | 0x2300..=0x23FF | 0x2600..=0x27BF | 0x2B00..=0x2BFF)
+}
+
+/// Send output to `sink` instead of stdout and stderr, as the TUI does
+/// while it owns the screen, or back to the terminal with `None`. Color is
+/// off while redirected.
+#[allow(dead_code)]
+pub fn redirect(sink: Option<mpsc::UnboundedSender<String>>) {
+    let redirected = sink.is_some();
+    *REDIRECT.lock().unwrap_or_else(|e| e.into_inner()) = sink;
+    if redirected {
+        colored::control::set_override(false);
+    } else {
+        apply(mode(), FROM_FLAG.load(Ordering::Relaxed));
+    }
+}
+
+/// Hand `text` to the redirect, if there is one.
+fn redirected(text: &str) -> bool {
+    match &*REDIRECT.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(sink) => {
+            for line in text.split('\n') {
+                let _ = sink.send(line.to_string());
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn print(level: Level, text: &str) {
+    if let Some(text) = present(level, text) {
+        if !redirected(&text) {
+            println!("{}", text);
+        }
+    }
+}
+
+pub fn eprint(text: &str) {
+    if let Some(text) = present(Level::Normal, text) {
+        if !redirected(&text) {
+            eprintln!("{}", text);
+        }
+    }
+}
+
+/// `out!(...)`: `println!` through the output mode.
+macro_rules! out {
+    () => {
+        $crate::output::print($crate::output::Level::Normal, "")
+    };
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Level::Normal, &format!($($arg)*))
+    };
+}
+
+/// `note!(...)`: like `out!`, but left out in quiet mode.
+macro_rules! note {
+    () => {
+        $crate::output::print($crate::output::Level::Note, "")
+    };
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Level::Note, &format!($($arg)*))
+    };
+}
+
+/// `eout!(...)`: `eprintln!` through the output mode.
+macro_rules! eout {
+    ($($arg:tt)*) => {
+        $crate::output::eprint(&format!($($arg)*))
+    };
+}
+
+pub(crate) use {eout, note, out};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use std::path::Path;
+
+    /// Lines from the main client and server flows, as each mode shows them.
+    const FLOWS: &[(Level, &str)] = &[
+        (Level::Note, "🚀 Server listening on 127.0.0.1:8080"),
+        (Level::Normal, "✅ Registered with server"),
+        (Level::Normal, "📤 Message sent to bob"),
+        (Level::Normal, "📬 2 new messages:"),
+        (Level::Data, "🎉 party at 8"),
+        (Level::Normal, "⚠️ Warning: Failed to read key log file: denied"),
+        (Level::Normal, "❌ Failed to send message: Unknown client: bob"),
+        (Level::Normal, "🚨 Signature check failed for a message from mallory"),
+        (Level::Note, "Commands:\n  send <id> <text>   - Send a message\n  quit               - Exit"),
+        (Level::Normal, "  ✅ alice (online)\n  ❌ bob (offline)"),
+    ];
+
+    fn snapshot(mode: OutputMode) -> String {
+        FLOWS.iter().filter_map(|(level, text)| present_in(mode, *level, text)).collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn rich_mode_shows_everything_as_written() {
+        let all: Vec<&str> = FLOWS.iter().map(|(_, text)| *text).collect();
+        assert_eq!(snapshot(OutputMode::Rich), all.join("\n"));
+    }
+
+    #[test]
+    fn plain_mode_spells_status_out() {
+        assert_eq!(snapshot(OutputMode::Plain), "\
+Server listening on 127.0.0.1:8080
+[OK] Registered with server
+Message sent to bob
+2 new messages:
+🎉 party at 8
+[WARNING] Warning: Failed to read key log file: denied
+[ERROR] Failed to send message: Unknown client: bob
+[ALERT] Signature check failed for a message from mallory
+Commands:
+  send <id> <text>   - Send a message
+  quit               - Exit
+  [OK] alice (online)
+  [ERROR] bob (offline)");
+    }
+
+    #[test]
+    fn quiet_mode_keeps_only_results_and_problems() {
+        assert_eq!(snapshot(OutputMode::Quiet), "\
+Message sent to bob
+2 new messages:
+🎉 party at 8
+[WARNING] Warning: Failed to read key log file: denied
+[ERROR] Failed to send message: Unknown client: bob
+[ALERT] Signature check failed for a message from mallory
+  [ERROR] bob (offline)");
+    }
+
+    #[test]
+    fn no_emoji_reach_plain_mode() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut files: Vec<_> = std::fs::read_dir(&src).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.extend(std::fs::read_dir(src.join("client")).unwrap().map(|entry| entry.unwrap().path()));
+        let literal = Regex::new(r#"\b(?:out|note|eout|say|tr)!\(\s*(?:"[^"]+",\s*)?"((?:[^"\\]|\\.)*)""#).unwrap();
+
+        let mut leaks = Vec::new();
+        // The TUI draws its own screen, not through the output modes
+        let printed = |path: &&std::path::PathBuf| path.extension().is_some_and(|ext| ext == "rs") && !path.ends_with("tui.rs");
+        for path in files.iter().filter(printed) {
+            let text = std::fs::read_to_string(path).unwrap();
+            for found in literal.captures_iter(&text) {
+                let Some(plain) = present_in(OutputMode::Plain, Level::Normal, &found[1]) else { continue };
+                if plain.chars().any(is_emoji) {
+                    leaks.push(format!("{}: {}", path.display(), plain));
+                }
+            }
+        }
+        assert!(leaks.is_empty(), "emoji left in plain mode:\n{}", leaks.join("\n"));
+    }
+}
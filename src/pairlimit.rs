@@ -0,0 +1,194 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Messages a sender may send one recipient per minute, on average.
+pub const DEFAULT_PAIR_RATE_PER_MIN: f64 = 20.0;
+/// Messages a sender may send one recipient in a quick burst.
+pub const DEFAULT_PAIR_BURST: f64 = 10.0;
+/// How long a report holds back a sender's messages to the reporter.
+pub const DEFAULT_REPORT_MUTE: Duration = Duration::from_secs(60 * 60);
+/// Idle buckets are dropped once there are this many.
+const PRUNE_AT: usize = 4096;
+
+/// Why a message between a pair was refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PairVerdict {
+    Allowed,
+    /// Over the pair's rate; a token frees up after this long.
+    Limited(Duration),
+    /// The recipient reported the sender; the mute lifts after this long.
+    Muted(Duration),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Inner {
+    buckets: HashMap<(String, String), Bucket>,
+    mutes: HashMap<(String, String), Instant>,
+    /// Reports against each sender since startup.
+    reports: BTreeMap<String, u64>,
+}
+
+/// Rate shaping per (sender, recipient) pair, on top of whatever limits apply
+/// to a sender overall: a token bucket per pair, and a temporary mute once the
+/// recipient reports the sender.
+pub struct PairLimiter {
+    inner: Mutex<Inner>,
+    per_sec: f64,
+    burst: f64,
+    mute_for: Duration,
+}
+
+impl PairLimiter {
+    pub fn new(rate_per_min: f64, burst: f64, mute_for: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Inner { buckets: HashMap::new(), mutes: HashMap::new(), reports: BTreeMap::new() }),
+            per_sec: rate_per_min / 60.0,
+            burst: burst.max(1.0),
+            mute_for,
+        }
+    }
+
+    /// Take a token for one message from `sender` to `recipient`, unless the
+    /// pair is muted or out of tokens.
+    pub fn check(&self, sender: &str, recipient: &str, now: Instant) -> PairVerdict {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let pair = (sender.to_string(), recipient.to_string());
+
+        match inner.mutes.get(&pair) {
+            Some(until) if *until > now => return PairVerdict::Muted(*until - now),
+            Some(_) => {
+                inner.mutes.remove(&pair);
+            }
+            None => {}
+        }
+
+        if inner.buckets.len() >= PRUNE_AT {
+            let (per_sec, burst) = (self.per_sec, self.burst);
+            inner.buckets.retain(|_, bucket| refilled(bucket, per_sec, burst, now) < burst);
+        }
+
+        let (per_sec, burst) = (self.per_sec, self.burst);
+        let bucket = inner.buckets.entry(pair).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = refilled(bucket, per_sec, burst, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            PairVerdict::Allowed
+        } else {
+            let wait = Duration::try_from_secs_f64((1.0 - bucket.tokens) / per_sec).unwrap_or(Duration::MAX);
+            PairVerdict::Limited(wait)
+        }
+    }
+
+    /// Record that `recipient` reported `sender`, and mute the pair. Returns
+    /// how long the mute lasts.
+    pub fn report(&self, sender: &str, recipient: &str, now: Instant) -> Duration {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        *inner.reports.entry(sender.to_string()).or_default() += 1;
+        inner.mutes.insert((sender.to_string(), recipient.to_string()), now + self.mute_for);
+        self.mute_for
+    }
+
+    /// Reports per sender since startup.
+    pub fn reports(&self) -> BTreeMap<String, u64> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).reports.clone()
+    }
+}
+
+fn refilled(bucket: &Bucket, per_sec: f64, burst: f64, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * per_sec).min(burst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MUTE: Duration = Duration::from_secs(600);
+
+    /// One message a second, in bursts of up to three.
+    fn limiter() -> PairLimiter {
+        PairLimiter::new(60.0, 3.0, MUTE)
+    }
+
+    #[test]
+    fn a_pair_gets_its_burst_then_waits_for_tokens() {
+        let (limiter, start) = (limiter(), Instant::now());
+        for _ in 0..3 {
+            assert_eq!(limiter.check("alice", "bob", start), PairVerdict::Allowed);
+        }
+        assert_eq!(limiter.check("alice", "bob", start), PairVerdict::Limited(Duration::from_secs(1)));
+        // Half a token later, half the wait is left
+        let half = start + Duration::from_millis(500);
+        assert_eq!(limiter.check("alice", "bob", half), PairVerdict::Limited(Duration::from_millis(500)));
+        assert_eq!(limiter.check("alice", "bob", start + Duration::from_secs(1)), PairVerdict::Allowed);
+        assert!(matches!(limiter.check("alice", "bob", start + Duration::from_secs(1)), PairVerdict::Limited(_)));
+    }
+
+    #[test]
+    fn tokens_refill_only_up_to_the_burst() {
+        let (limiter, start) = (limiter(), Instant::now());
+        assert_eq!(limiter.check("alice", "bob", start), PairVerdict::Allowed);
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("alice", "bob", later), PairVerdict::Allowed);
+        }
+        assert!(matches!(limiter.check("alice", "bob", later), PairVerdict::Limited(_)));
+    }
+
+    #[test]
+    fn each_pair_has_its_own_bucket() {
+        let (limiter, now) = (limiter(), Instant::now());
+        for _ in 0..3 {
+            limiter.check("alice", "bob", now);
+        }
+        assert!(matches!(limiter.check("alice", "bob", now), PairVerdict::Limited(_)));
+        // Neither the sender's other recipients nor the recipient's other
+        // senders are held back, as a sender-wide limit would
+        assert_eq!(limiter.check("alice", "carol", now), PairVerdict::Allowed);
+        assert_eq!(limiter.check("dave", "bob", now), PairVerdict::Allowed);
+        assert_eq!(limiter.check("bob", "alice", now), PairVerdict::Allowed);
+    }
+
+    #[test]
+    fn a_report_mutes_the_pair_until_it_expires() {
+        let (limiter, start) = (limiter(), Instant::now());
+        assert_eq!(limiter.report("mallory", "bob", start), MUTE);
+        assert_eq!(limiter.check("mallory", "bob", start), PairVerdict::Muted(MUTE));
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.check("mallory", "bob", later), PairVerdict::Muted(MUTE - Duration::from_secs(60)));
+        assert_eq!(limiter.check("mallory", "carol", later), PairVerdict::Allowed);
+        assert_eq!(limiter.check("bob", "mallory", later), PairVerdict::Allowed);
+
+        // Muted checks took no tokens, so the whole burst is there after
+        let lifted = start + MUTE;
+        for _ in 0..3 {
+            assert_eq!(limiter.check("mallory", "bob", lifted), PairVerdict::Allowed);
+        }
+        assert!(matches!(limiter.check("mallory", "bob", lifted), PairVerdict::Limited(_)));
+    }
+
+    #[test]
+    fn reports_are_counted_per_sender() {
+        let (limiter, now) = (limiter(), Instant::now());
+        limiter.report("mallory", "bob", now);
+        limiter.report("mallory", "carol", now);
+        limiter.report("eve", "bob", now);
+        assert_eq!(limiter.reports(), BTreeMap::from([("eve".to_string(), 1), ("mallory".to_string(), 2)]));
+    }
+
+    #[test]
+    fn a_second_report_starts_the_mute_over() {
+        let (limiter, start) = (limiter(), Instant::now());
+        limiter.report("mallory", "bob", start);
+        let again = start + Duration::from_secs(300);
+        limiter.report("mallory", "bob", again);
+        assert_eq!(limiter.check("mallory", "bob", start + MUTE), PairVerdict::Muted(Duration::from_secs(300)));
+        assert_eq!(limiter.check("mallory", "bob", again + MUTE), PairVerdict::Allowed);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::secure_fs;
+use crate::output::out;
+use anyhow::{Result, anyhow};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable that overrides where the client keeps its files.
+pub const HOME_ENV: &str = "MSGPROTO_HOME";
+
+const APP_DIR: &str = "msgproto";
+
+/// Where the client keeps its files. Config goes in the platform config
+/// directory and the local store in the data directory (XDG on Linux,
+/// Application Support on macOS, AppData on Windows). `--home` or
+/// `MSGPROTO_HOME` puts both in one directory instead.
+#[derive(Debug, Clone)]
+pub struct ClientPaths {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+    /// What decided the locations, for `client paths`.
+    pub source: &'static str,
+}
+
+impl ClientPaths {
+    pub fn resolve(home: Option<&str>) -> Result<Self> {
+        if let Some(home) = home {
+            return Ok(Self::single(PathBuf::from(home), "--home"));
+        }
+        if let Some(home) = std::env::var_os(HOME_ENV).filter(|home| !home.is_empty()) {
+            return Ok(Self::single(PathBuf::from(home), HOME_ENV));
+        }
+        let (config, data) = dirs::config_dir().zip(dirs::data_dir())
+            .ok_or_else(|| anyhow!("Could not find a home directory; use --home or {}", HOME_ENV))?;
+        Ok(Self { config_dir: config.join(APP_DIR), data_dir: data.join(APP_DIR), source: "platform default" })
+    }
+
+    fn single(home: PathBuf, source: &'static str) -> Self {
+        Self { config_dir: home.clone(), data_dir: home, source }
+    }
+
+    pub fn config_file(&self, client_id: &str) -> PathBuf {
+        self.config_dir.join(format!("{}.config.json", client_id))
+    }
+
+    /// The identity's secret keys, unless `--key-file` puts them elsewhere.
+    pub fn key_file(&self, client_id: &str) -> PathBuf {
+        self.config_dir.join(format!("{}.keys", client_id))
+    }
+
+    /// What first-run setup chose, including the id `client` starts as.
+    pub fn profile_file(&self) -> PathBuf {
+        self.config_dir.join("profile.json")
+    }
+
+    /// A local store file, e.g. `store_file(id, "sqlite")`.
+    pub fn store_file(&self, client_id: &str, extension: &str) -> PathBuf {
+        self.data_dir.join(format!("{}.store.{}", client_id, extension))
+    }
+
+    /// Create the directories, private to the user. Returns whether anything
+    /// had to be created, i.e. this is a first run.
+    pub fn create(&self) -> Result<bool> {
+        let mut created = false;
+        for dir in [&self.config_dir, &self.data_dir] {
+            if !dir.exists() {
+                secure_fs::create_private_dir(dir)?;
+                created = true;
+            }
+        }
+        Ok(created)
+    }
+
+    /// Move a client's files from the working directory, where older versions
+    /// kept them, unless the new location already has its own.
+    pub fn adopt_legacy_files(&self, client_id: &str) -> Result<Vec<PathBuf>> {
+        let mut moved = Vec::new();
+        let candidates = [
+            (format!("{}.config.json", client_id), self.config_file(client_id)),
+            (format!("{}.store.json", client_id), self.store_file(client_id, "json")),
+            (format!("{}.store.sqlite", client_id), self.store_file(client_id, "sqlite")),
+            (format!("{}.store.json.migrated", client_id), self.store_file(client_id, "json.migrated")),
+        ];
+        for (legacy, target) in candidates {
+            let legacy = Path::new(".").join(legacy);
+            if legacy.exists() && !target.exists() && !same_dir(&legacy, &target) {
+                // rename fails across filesystems, so fall back to copying
+                if fs::rename(&legacy, &target).is_err() {
+                    fs::copy(&legacy, &target)?;
+                    fs::remove_file(&legacy)?;
+                }
+                moved.push(target);
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Refuse to run on directories or files others can read.
+    pub fn check_permissions(&self, allow_insecure: bool) -> Result<()> {
+        secure_fs::check_private_tree(&self.config_dir, allow_insecure)?;
+        if self.data_dir != self.config_dir {
+            secure_fs::check_private_tree(&self.data_dir, allow_insecure)?;
+        }
+        Ok(())
+    }
+
+    pub fn print(&self, client_id: Option<&str>) {
+        out!("📁 Paths ({})", self.source);
+        out!("  Config: {}", self.config_dir.display());
+        out!("  Data:   {}", self.data_dir.display());
+        if let Some(id) = client_id {
+            out!("  Config file:  {}", self.config_file(id).display());
+            out!("  Key file:     {}", self.key_file(id).display());
+            out!("  Local store:  {} or {}", self.store_file(id, "json").display(), self.store_file(id, "sqlite").display());
+        }
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    let dir = |path: &Path| path.parent().and_then(|parent| parent.canonicalize().ok());
+    matches!((dir(a), dir(b)), (Some(a), Some(b)) if a == b)
+}
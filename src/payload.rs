@@ -0,0 +1,187 @@
+use crate::i18n::tr;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Version byte of a bare string, as sent before payloads were versioned.
+pub const VERSION_LEGACY: u8 = 0;
+/// Version byte of `PayloadV1`.
+pub const VERSION_1: u8 = 1;
+/// Bytes below this that start a payload are version bytes. Clients from
+/// before versioning sent bare text, which starts at or above it.
+const FIRST_TEXT_BYTE: u8 = 0x20;
+
+/// What goes inside the encryption: the text, plus what a later version
+/// might add. Encoded as the version byte and then this as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PayloadV1 {
+    pub text: String,
+    /// MIME type of `text`; plain text if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Id of the message this answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Id of an earlier message from the same sender that this one replaces,
+    /// such as the original of an edit. The envelope carries it too, so the
+    /// server can drop the original while it is still unfetched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Option<String>,
+    /// The group the message went to. The envelope carries it too, so the
+    /// server can check both ends are members.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Fields this version doesn't define. Kept as they came, so a message
+    /// passed on keeps what a newer client put in it.
+    #[serde(flatten)]
+    pub extensions: BTreeMap<String, serde_json::Value>,
+}
+
+/// A decrypted message body.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Legacy(String),
+    V1(PayloadV1),
+    /// A version this build doesn't know, from a newer client.
+    Unsupported { version: u8 },
+}
+
+impl Payload {
+    /// A plain-text message in the current version.
+    pub fn text(text: &str) -> Payload {
+        Payload::V1(PayloadV1 { text: text.to_string(), content_type: None, reply_to: None, supersedes: None, group: None, extensions: BTreeMap::new() })
+    }
+
+    /// The same message, marked as replacing `supersedes`.
+    pub fn with_supersedes(self, supersedes: Option<String>) -> Payload {
+        match self {
+            Payload::V1(payload) => Payload::V1(PayloadV1 { supersedes, ..payload }),
+            other => other,
+        }
+    }
+
+    /// The same message, marked as sent to `group`.
+    pub fn with_group(self, group: Option<String>) -> Payload {
+        match self {
+            Payload::V1(payload) => Payload::V1(PayloadV1 { group, ..payload }),
+            other => other,
+        }
+    }
+
+    /// The group the sender says the message went to.
+    pub fn group(&self) -> Option<&str> {
+        match self {
+            Payload::V1(payload) => payload.group.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The bytes to encrypt. Every version this build writes is valid UTF-8.
+    pub fn encode(&self) -> Result<String> {
+        match self {
+            Payload::Legacy(text) => Ok(format!("{}{}", VERSION_LEGACY as char, text)),
+            Payload::V1(payload) => Ok(format!("{}{}", VERSION_1 as char, serde_json::to_string(payload)?)),
+            Payload::Unsupported { version } => Err(anyhow::anyhow!("Can't write payload version {}", version)),
+        }
+    }
+
+    /// Read decrypted bytes. Anything that doesn't start with a version byte
+    /// is a bare string from a client that predates versioning; a v1 body
+    /// that doesn't parse is shown as unsupported rather than as raw JSON.
+    pub fn decode(bytes: &[u8]) -> Payload {
+        match bytes.first() {
+            None => Payload::Legacy(String::new()),
+            Some(&VERSION_LEGACY) => Payload::Legacy(String::from_utf8_lossy(&bytes[1..]).into_owned()),
+            Some(&VERSION_1) => match serde_json::from_slice(&bytes[1..]) {
+                Ok(payload) => Payload::V1(payload),
+                Err(_) => Payload::Unsupported { version: VERSION_1 },
+            },
+            Some(&version) if version < FIRST_TEXT_BYTE => Payload::Unsupported { version },
+            Some(_) => Payload::Legacy(String::from_utf8_lossy(bytes).into_owned()),
+        }
+    }
+
+    /// What to show for the message.
+    pub fn display(&self) -> String {
+        match self {
+            Payload::Legacy(text) => text.clone(),
+            Payload::V1(payload) => payload.text.clone(),
+            Payload::Unsupported { version } => tr!("payload.unsupported",
+                "[unsupported payload version {version}; update the client to read it]", version = version),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/payload").join(name)).unwrap()
+    }
+
+    /// What `v1.bin` holds.
+    fn full_v1() -> PayloadV1 {
+        PayloadV1 {
+            text: "see you at 7".to_string(),
+            content_type: Some("text/markdown".to_string()),
+            reply_to: Some("01J9ZQ4M2X8E5R7T3B6N1K0PCW".to_string()),
+            supersedes: Some("01J9ZQ3V7H2D9F4A8C1M6S5GQY".to_string()),
+            group: Some("team".to_string()),
+            extensions: BTreeMap::from([("x-mood".to_string(), serde_json::json!("sunny"))]),
+        }
+    }
+
+    #[test]
+    fn v1_is_written_in_the_pinned_layout() {
+        assert_eq!(Payload::V1(full_v1()).encode().unwrap().into_bytes(), fixture("v1.bin"));
+        assert_eq!(Payload::text("hello").encode().unwrap().into_bytes(), fixture("v1-text.bin"));
+    }
+
+    #[test]
+    fn v1_fixtures_are_read() {
+        assert_eq!(Payload::decode(&fixture("v1.bin")), Payload::V1(full_v1()));
+        let text = Payload::decode(&fixture("v1-text.bin"));
+        assert_eq!(text, Payload::text("hello"));
+        assert_eq!(text.display(), "hello");
+        assert_eq!(text.group(), None);
+    }
+
+    #[test]
+    fn legacy_payloads_are_read_as_text() {
+        assert_eq!(Payload::decode(&fixture("legacy-bare.bin")), Payload::Legacy("hello from before versions é".to_string()));
+        assert_eq!(Payload::decode(&fixture("legacy-tagged.bin")), Payload::Legacy("hello, tagged".to_string()));
+        assert_eq!(Payload::decode(b""), Payload::Legacy(String::new()));
+        // Bare text that happens to look like v1 JSON is still bare text
+        assert_eq!(Payload::decode(b"{\"text\":\"hi\"}"), Payload::Legacy("{\"text\":\"hi\"}".to_string()));
+    }
+
+    #[test]
+    fn newer_versions_are_unsupported_not_garbled() {
+        let future = Payload::decode(&fixture("v2.bin"));
+        assert_eq!(future, Payload::Unsupported { version: 2 });
+        assert!(!future.display().contains("from the future"), "{}", future.display());
+        assert!(future.encode().is_err());
+        assert_eq!(Payload::decode(&[0x1f]), Payload::Unsupported { version: 0x1f });
+        // A v1 body that doesn't parse isn't shown as raw JSON either
+        assert_eq!(Payload::decode(b"\x01{\"txt\":"), Payload::Unsupported { version: VERSION_1 });
+    }
+
+    #[test]
+    fn unknown_fields_survive_a_round_trip() {
+        let bytes = b"\x01{\"text\":\"hi\",\"x-later\":{\"n\":1}}";
+        let Payload::V1(payload) = Payload::decode(bytes) else { panic!("v1 wasn't read") };
+        assert_eq!(payload.extensions, BTreeMap::from([("x-later".to_string(), serde_json::json!({"n": 1}))]));
+        assert_eq!(Payload::V1(payload).encode().unwrap().as_bytes(), bytes);
+    }
+
+    #[test]
+    fn edits_and_groups_mark_only_v1() {
+        let payload = Payload::text("fixed").with_supersedes(Some("01J9".to_string())).with_group(Some("team".to_string()));
+        let Payload::V1(v1) = &payload else { panic!("not v1") };
+        assert_eq!((v1.supersedes.as_deref(), payload.group()), (Some("01J9"), Some("team")));
+        let legacy = Payload::Legacy("old".to_string()).with_group(Some("team".to_string()));
+        assert_eq!(legacy, Payload::Legacy("old".to_string()));
+    }
+}
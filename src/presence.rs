@@ -0,0 +1,54 @@
+use crate::sanitize::{self, ControlDisplay};
+use crate::types::PresenceEntry;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+/// A watched contact came online, went offline, or was seen for the first
+/// time since watching started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresenceEvent {
+    pub client_id: String,
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// The last presence answer, to turn each new one into events.
+#[derive(Debug, Default)]
+pub struct PresenceTracker {
+    known: BTreeMap<String, PresenceEntry>,
+}
+
+impl PresenceTracker {
+    /// Take in a fresh answer. Returns an event for every contact that is
+    /// new or whose online state changed; a `last_seen` that merely moved
+    /// on isn't one.
+    pub fn update(&mut self, entries: Vec<PresenceEntry>) -> Vec<PresenceEvent> {
+        let mut events = Vec::new();
+        for entry in entries {
+            let changed = self.known.get(&entry.client_id).is_none_or(|known| known.online != entry.online);
+            if changed {
+                events.push(PresenceEvent { client_id: entry.client_id.clone(), online: entry.online, last_seen: entry.last_seen });
+            }
+            self.known.insert(entry.client_id.clone(), entry);
+        }
+        events
+    }
+}
+
+/// The contacts as a table, online ones first, each group by id.
+pub fn table(contacts: &BTreeMap<String, PresenceEvent>, controls: ControlDisplay) -> Vec<String> {
+    let mut rows: Vec<&PresenceEvent> = contacts.values().collect();
+    rows.sort_by_key(|row| !row.online);
+    let width = rows.iter()
+        .map(|row| sanitize::width(&sanitize::line(&row.client_id, controls, sanitize::MAX_NAME_WIDTH)))
+        .max()
+        .unwrap_or(0);
+    rows.into_iter()
+        .map(|row| {
+            let id = sanitize::line(&row.client_id, controls, sanitize::MAX_NAME_WIDTH);
+            let pad = " ".repeat(width - sanitize::width(&id));
+            let seen = row.last_seen.map_or_else(|| "-".to_string(), |at| at.format("%Y-%m-%d %H:%M UTC").to_string());
+            format!("{} {}{}  {}", if row.online { "●" } else { "○" }, id, pad, seen)
+        })
+        .collect()
+}
@@ -0,0 +1,125 @@
+use crate::crypto::CryptoManager;
+use crate::shamir;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+/// Bump when the share layout changes; older shares must keep combining.
+pub const SHARE_VERSION: u32 = 1;
+
+/// Social-recovery traffic, carried as an ordinary message body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum RecoveryMessage {
+    Share(RecoveryShare),
+    /// Sent unencrypted from the new identity, which the holder can't decrypt
+    /// from yet; carries nothing secret.
+    Request { owner: String, reply_key: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryShare {
+    pub version: u32,
+    pub owner: String,
+    /// Ed25519 public key the reassembled identity must reproduce.
+    pub public_key: String,
+    pub threshold: u8,
+    pub total: u8,
+    pub index: u8,
+    pub share: String,
+}
+
+impl RecoveryMessage {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let message: Self = serde_json::from_slice(bytes).ok()?;
+        match &message {
+            RecoveryMessage::Share(share) if share.version > SHARE_VERSION => None,
+            _ => Some(message),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+}
+
+/// Split an identity's secret keys into `total` shares, `threshold` of which restore it.
+pub fn make_shares(crypto: &CryptoManager, owner: &str, threshold: u8, total: u8) -> Result<Vec<RecoveryShare>> {
+    let (ed25519, x25519) = crypto.export_secrets();
+    let shares = shamir::split(&[ed25519, x25519].concat(), threshold, total)?;
+    Ok(shares.into_iter()
+        .map(|(index, share)| RecoveryShare {
+            version: SHARE_VERSION,
+            owner: owner.to_string(),
+            public_key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+            threshold,
+            total,
+            index,
+            share: hex::encode(share),
+        })
+        .collect())
+}
+
+/// Rebuild the identity once at least `threshold` matching shares are present.
+pub fn reassemble(shares: &[RecoveryShare]) -> Result<CryptoManager> {
+    let first = shares.first().ok_or_else(|| anyhow!("No shares"))?;
+    if shares.iter().any(|s| s.owner != first.owner || s.public_key != first.public_key) {
+        return Err(anyhow!("Shares belong to different identities"));
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(anyhow!("Need {} shares, have {}", first.threshold, shares.len()));
+    }
+
+    let points = shares.iter()
+        .map(|s| Ok((s.index, hex::decode(&s.share)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let secret = shamir::combine(&points)?;
+    if secret.len() != 64 {
+        return Err(anyhow!("Reassembled secret has the wrong length"));
+    }
+    let crypto = CryptoManager::from_secrets(&secret[..32], &secret[32..])?;
+    if hex::encode(crypto.get_ed25519_public_key().as_bytes()) != first.public_key {
+        return Err(anyhow!("Shares do not reassemble to the expected identity"));
+    }
+    Ok(crypto)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_shares_restore_the_identity() {
+        let identity = CryptoManager::new();
+        let shares = make_shares(&identity, "alice", 2, 3).unwrap();
+        let restored = reassemble(&shares[1..]).unwrap();
+        assert_eq!(restored.export_secrets(), identity.export_secrets());
+
+        let parsed = RecoveryMessage::parse(&RecoveryMessage::Share(shares[0].clone()).to_bytes().unwrap());
+        assert!(matches!(parsed, Some(RecoveryMessage::Share(share)) if share.index == shares[0].index));
+    }
+
+    #[test]
+    fn too_few_or_mismatched_shares_are_refused() {
+        let identity = CryptoManager::new();
+        let shares = make_shares(&identity, "alice", 3, 5).unwrap();
+        assert!(reassemble(&shares[..2]).err().unwrap().to_string().contains("Need 3 shares"));
+
+        // Claiming a lower threshold doesn't get past the key check
+        let mut lowered = shares[..2].to_vec();
+        for share in &mut lowered {
+            share.threshold = 2;
+        }
+        assert!(reassemble(&lowered).is_err());
+
+        let mut others = make_shares(&CryptoManager::new(), "alice", 3, 5).unwrap();
+        others[0] = shares[0].clone();
+        assert!(reassemble(&others[..3]).is_err());
+    }
+
+    #[test]
+    fn shares_from_a_newer_version_are_not_parsed() {
+        let mut share = make_shares(&CryptoManager::new(), "alice", 2, 2).unwrap().remove(0);
+        share.version = SHARE_VERSION + 1;
+        assert!(RecoveryMessage::parse(&RecoveryMessage::Share(share).to_bytes().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,157 @@
+use std::borrow::Cow;
+
+/// Default number of characters of peer-supplied text a log line may carry.
+pub const DEFAULT_LOG_EXCERPT: usize = 200;
+/// Error detail sent back to peers is cut to this many characters.
+const WIRE_DETAIL_LEN: usize = 160;
+/// Hex runs this long or longer are taken to be keys, signatures or ciphertext.
+const MIN_SECRET_HEX: usize = 32;
+/// Request fields that hold key or ciphertext material, masked wherever they appear.
+const SECRET_FIELDS: &[&str] = &[
+    "public_key", "recovery_key", "x25519_public_key", "ephemeral_key",
+    "signature", "encrypted_content", "content", "value", "secret",
+];
+
+/// How much of what peers send may end up in the server's logs.
+#[derive(Debug, Clone, Copy)]
+pub struct Redaction {
+    pub excerpt_len: usize,
+    /// Log payloads whole and unmasked. Only honored in debug builds.
+    pub full_payloads: bool,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Self { excerpt_len: DEFAULT_LOG_EXCERPT, full_payloads: false }
+    }
+}
+
+impl Redaction {
+    /// Text that may contain peer input, made fit for a log line.
+    pub fn log<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.full_payloads {
+            return Cow::Borrowed(text);
+        }
+        Cow::Owned(truncate(&mask_hex(text), self.excerpt_len).into_owned())
+    }
+
+    /// A raw request for a log line: secret fields masked by name when it
+    /// parses as JSON, long hex runs masked either way, then cut short.
+    pub fn request(&self, request: &str) -> String {
+        if self.full_payloads {
+            return request.to_string();
+        }
+        let masked = match serde_json::from_str::<serde_json::Value>(request) {
+            Ok(mut value) => {
+                mask_fields(&mut value);
+                value.to_string()
+            }
+            Err(_) => request.to_string(),
+        };
+        self.log(&masked).into_owned()
+    }
+}
+
+/// Error detail for a response to a peer: short and masked, whatever the
+/// logging settings.
+pub fn wire(text: &str) -> String {
+    truncate(&mask_hex(text), WIRE_DETAIL_LEN).into_owned()
+}
+
+fn truncate(text: &str, max_chars: usize) -> Cow<'_, str> {
+    match text.char_indices().nth(max_chars) {
+        Some((cut, _)) => Cow::Owned(format!("{}… ({} bytes)", &text[..cut], text.len())),
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Replace runs of hex digits long enough to be key or ciphertext material.
+fn mask_hex(text: &str) -> Cow<'_, str> {
+    let mut masked = String::new();
+    let mut copied = 0;
+    let mut run_start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (c.is_ascii_hexdigit(), run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(start)) => {
+                if i - start >= MIN_SECRET_HEX {
+                    masked.push_str(&text[copied..start]);
+                    masked.push_str(&format!("<{} hex chars>", i - start));
+                    copied = i;
+                }
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    masked.push_str(&text[copied..]);
+    Cow::Owned(masked)
+}
+
+fn mask_fields(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                match field {
+                    serde_json::Value::String(text) if SECRET_FIELDS.contains(&name.as_str()) => {
+                        *field = serde_json::Value::String(format!("<{} chars>", text.len()));
+                    }
+                    _ => mask_fields(field),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(mask_fields),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ServerCommand;
+
+    /// A request the server can't parse, a megabyte long, whose parse error
+    /// quotes all of it back.
+    fn huge_malformed_request() -> (String, String) {
+        let request = format!("{{\"{}\":{{}}}}", "Z".repeat(1 << 20));
+        let error = serde_json::from_str::<ServerCommand>(&request).expect_err("the request parsed").to_string();
+        (request, error)
+    }
+
+    #[test]
+    fn a_huge_malformed_request_stays_out_of_the_response() {
+        let (_, error) = huge_malformed_request();
+        assert!(error.len() > 1 << 20, "the parser no longer quotes the request");
+        let detail = wire(&error);
+        assert!(detail.chars().count() < WIRE_DETAIL_LEN + 20, "{} chars went back", detail.len());
+        assert!(detail.ends_with(&format!("… ({} bytes)", error.len())), "{}", detail);
+    }
+
+    #[test]
+    fn a_huge_malformed_request_stays_out_of_the_default_log() {
+        let (request, error) = huge_malformed_request();
+        let redaction = Redaction::default();
+        for logged in [redaction.request(&request), redaction.log(&error).into_owned()] {
+            assert!(logged.chars().count() < DEFAULT_LOG_EXCERPT + 20, "{} chars were logged", logged.len());
+        }
+    }
+
+    #[test]
+    fn full_payloads_log_everything() {
+        let (request, _) = huge_malformed_request();
+        assert_eq!(Redaction { full_payloads: true, ..Redaction::default() }.request(&request), request);
+    }
+
+    #[test]
+    fn secret_fields_and_long_hex_are_masked() {
+        let key = "ab".repeat(32);
+        let request = format!(r#"{{"Send":{{"content":"hello","note":"{}"}}}}"#, key);
+        let logged = Redaction::default().request(&request);
+        assert_eq!(logged, r#"{"Send":{"content":"<5 chars>","note":"<64 hex chars>"}}"#);
+        // Short hex, such as ids and counts, is left as it is
+        assert_eq!(wire("message 0123abcd not found"), "message 0123abcd not found");
+    }
+}
@@ -0,0 +1,329 @@
+use crate::output::OutputMode;
+use crate::sanitize::{self, ControlDisplay};
+use crate::store::SignatureCheck;
+use chrono::{DateTime, Duration, Utc};
+use colored::*;
+
+// Consecutive messages from the same sender within this window share one header
+const COLLAPSE_WINDOW_SECS: i64 = 60;
+
+const VERIFIED_MARKER: &str = "✅";
+const PLAIN_VERIFIED_MARKER: &str = "[verified]";
+const UNVERIFIED_MARKER: &str = "⚠";
+const PLAIN_UNVERIFIED_MARKER: &str = "[UNVERIFIED]";
+const INVALID_MARKER: &str = "❌";
+const PLAIN_INVALID_MARKER: &str = "[INVALID SIGNATURE]";
+const STAR_MARKER: &str = "⭐";
+const PLAIN_STAR_MARKER: &str = "[starred]";
+const EXPIRY_MARKER: &str = "⏳";
+const CLOCK_MARKER: &str = "🕰";
+
+const SENDER_PALETTE: [Color; 10] = [
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::BrightRed,
+    Color::BrightGreen,
+    Color::BrightBlue,
+    Color::BrightMagenta,
+];
+
+/// A single message as it should appear on screen.
+#[derive(Debug, Clone)]
+pub struct MessageView {
+    pub sender: String,
+    pub timestamp: DateTime<Utc>,
+    pub body: String,
+    /// None for our own messages, which have nothing to check.
+    pub signature: Option<SignatureCheck>,
+    pub highlighted: bool,
+    /// From the message's annotations.
+    pub starred: bool,
+    pub labels: Vec<String>,
+    /// Time left before a disappearing message is scrubbed.
+    pub expires_in: Option<Duration>,
+    /// When the sender claims to have sent it, if that is far from
+    /// `timestamp`, the time the server attests it was received.
+    pub claimed_at: Option<DateTime<Utc>>,
+    /// The group the message went to, shown before the sender.
+    pub group: Option<String>,
+}
+
+pub struct Renderer {
+    color: bool,
+    /// Spell markers out instead of using symbols.
+    plain: bool,
+    /// What to do with control characters in senders, bodies and labels.
+    controls: ControlDisplay,
+}
+
+impl Renderer {
+    /// Color and symbols in rich mode; neither otherwise.
+    pub fn for_mode(mode: OutputMode, controls: ControlDisplay) -> Self {
+        let rich = mode == OutputMode::Rich;
+        Self { color: rich, plain: !rich, controls }
+    }
+
+    pub fn set_color(&mut self, enabled: bool) {
+        self.color = enabled;
+    }
+
+    pub fn set_controls(&mut self, controls: ControlDisplay) {
+        self.controls = controls;
+    }
+
+    /// Render messages as `HH:MM <sender> body`, collapsing runs from the same
+    /// sender under one header and indenting continuation lines under the body.
+    /// Everything in a view came from a peer, so it is sanitized first.
+    pub fn render(&self, messages: &[MessageView]) -> String {
+        let mut out = String::new();
+        let mut previous: Option<&MessageView> = None;
+        let mut indent = 0;
+
+        for msg in messages {
+            let collapse = previous.is_some_and(|prev| {
+                prev.sender == msg.sender
+                    && prev.group == msg.group
+                    && (msg.timestamp - prev.timestamp).num_seconds().abs() < COLLAPSE_WINDOW_SECS
+            });
+
+            let body = self.paint_body(msg);
+            let mut lines = body.lines();
+            let first = format!("{}{}", lines.next().unwrap_or(""), self.annotations(msg));
+            let marker = match (msg.signature, self.plain) {
+                (None, _) => String::new(),
+                (Some(SignatureCheck::Verified), false) => format!("{} ", VERIFIED_MARKER),
+                (Some(SignatureCheck::Verified), true) => format!("{} ", PLAIN_VERIFIED_MARKER),
+                (Some(SignatureCheck::Unverified), false) => format!("{} ", UNVERIFIED_MARKER),
+                (Some(SignatureCheck::Unverified), true) => format!("{} ", PLAIN_UNVERIFIED_MARKER),
+                (Some(SignatureCheck::Invalid), false) => format!("{} ", INVALID_MARKER),
+                (Some(SignatureCheck::Invalid), true) => format!("{} ", PLAIN_INVALID_MARKER),
+            };
+
+            if collapse {
+                out.push_str(&format!("{}{}{}\n", " ".repeat(indent), marker, first));
+            } else {
+                let time = msg.timestamp.format("%H:%M").to_string();
+                let sender = match &msg.group {
+                    Some(group) => format!("[{}] <{}>", sanitize::line(group, self.controls, sanitize::MAX_NAME_WIDTH),
+                        sanitize::line(&msg.sender, self.controls, sanitize::MAX_NAME_WIDTH)),
+                    None => format!("<{}>", sanitize::line(&msg.sender, self.controls, sanitize::MAX_NAME_WIDTH)),
+                };
+                indent = time.chars().count() + 1 + sanitize::width(&sender) + 1;
+                out.push_str(&format!("{} {} {}{}\n", time, self.paint_sender(&msg.sender, &sender), marker, first));
+            }
+
+            for line in lines {
+                out.push_str(&format!("{}{}\n", " ".repeat(indent), line));
+            }
+
+            previous = Some(msg);
+        }
+
+        out
+    }
+
+    /// Star and labels, to follow the first line of the body.
+    fn annotations(&self, msg: &MessageView) -> String {
+        let mut suffix = String::new();
+        if msg.starred {
+            suffix.push(' ');
+            suffix.push_str(if self.plain { PLAIN_STAR_MARKER } else { STAR_MARKER });
+        }
+        if !msg.labels.is_empty() {
+            let labels: Vec<String> = msg.labels.iter().map(|label| sanitize::line(label, self.controls, sanitize::MAX_LABEL_WIDTH)).collect();
+            let labels = format!("[{}]", labels.join(", "));
+            suffix.push(' ');
+            suffix.push_str(&if self.color { labels.dimmed().to_string() } else { labels });
+        }
+        if let Some(remaining) = msg.expires_in {
+            let countdown = if self.plain {
+                format!("[expires in {}]", countdown(remaining))
+            } else {
+                format!("{} {}", EXPIRY_MARKER, countdown(remaining))
+            };
+            suffix.push(' ');
+            suffix.push_str(&if self.color { countdown.yellow().to_string() } else { countdown });
+        }
+        if let Some(claimed_at) = msg.claimed_at {
+            // The day too, when the clocks disagree by that much
+            let format = if claimed_at.date_naive() == msg.timestamp.date_naive() { "%H:%M" } else { "%Y-%m-%d %H:%M" };
+            let times = format!("sent {} (claimed) / received {} (server-attested)", claimed_at.format(format), msg.timestamp.format(format));
+            let times = if self.plain { format!("[{}]", times) } else { format!("{} {}", CLOCK_MARKER, times) };
+            suffix.push(' ');
+            suffix.push_str(&if self.color { times.dimmed().to_string() } else { times });
+        }
+        suffix
+    }
+
+    fn paint_body(&self, msg: &MessageView) -> String {
+        let body = sanitize::text(&msg.body, self.controls);
+        match (msg.highlighted, self.color) {
+            (false, _) => body,
+            (true, true) => body.lines()
+                .map(|line| line.black().on_yellow().to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            (true, false) => format!(">> {}", body),
+        }
+    }
+
+    fn paint_sender(&self, sender_id: &str, text: &str) -> String {
+        if self.color {
+            text.color(sender_color(sender_id)).bold().to_string()
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Time left in its largest whole unit, e.g. `4m` or `2d`.
+fn countdown(remaining: Duration) -> String {
+    let secs = remaining.num_seconds().max(0);
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Stable color for a sender id (FNV-1a, so it doesn't change between runs or builds).
+pub fn sender_color(sender_id: &str) -> Color {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in sender_id.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    SENDER_PALETTE[(hash % SENDER_PALETTE.len() as u64) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, hour, minute, second).unwrap()
+    }
+
+    fn view(sender: &str, timestamp: DateTime<Utc>, body: &str, signature: Option<SignatureCheck>) -> MessageView {
+        MessageView {
+            sender: sender.to_string(),
+            timestamp,
+            body: body.to_string(),
+            signature,
+            highlighted: false,
+            starred: false,
+            labels: Vec::new(),
+            expires_in: None,
+            claimed_at: None,
+            group: None,
+        }
+    }
+
+    fn uncolored(mode: OutputMode) -> Renderer {
+        let mut renderer = Renderer::for_mode(mode, ControlDisplay::Strip);
+        renderer.set_color(false);
+        renderer
+    }
+
+    fn conversation() -> Vec<MessageView> {
+        vec![
+            view("alice", at(9, 5, 0), "hi bob", Some(SignatureCheck::Verified)),
+            view("alice", at(9, 5, 30), "are you there?\nit's about lunch", Some(SignatureCheck::Verified)),
+            view("bob", at(9, 5, 40), "yes", None),
+            view("alice", at(9, 7, 0), "noon?", Some(SignatureCheck::Unverified)),
+            view("mallory", at(9, 7, 5), "trust me", Some(SignatureCheck::Invalid)),
+        ]
+    }
+
+    #[test]
+    fn rich_layout() {
+        assert_eq!(
+            uncolored(OutputMode::Rich).render(&conversation()),
+            "09:05 <alice> ✅ hi bob\n\
+             \x20             ✅ are you there?\n\
+             \x20             it's about lunch\n\
+             09:05 <bob> yes\n\
+             09:07 <alice> ⚠ noon?\n\
+             09:07 <mallory> ❌ trust me\n"
+        );
+    }
+
+    #[test]
+    fn plain_layout() {
+        assert_eq!(
+            uncolored(OutputMode::Plain).render(&conversation()),
+            "09:05 <alice> [verified] hi bob\n\
+             \x20             [verified] are you there?\n\
+             \x20             it's about lunch\n\
+             09:05 <bob> yes\n\
+             09:07 <alice> [UNVERIFIED] noon?\n\
+             09:07 <mallory> [INVALID SIGNATURE] trust me\n"
+        );
+    }
+
+    #[test]
+    fn a_minute_apart_gets_a_new_header() {
+        let messages = [view("alice", at(9, 5, 0), "one", None), view("alice", at(9, 6, 0), "two", None)];
+        assert_eq!(uncolored(OutputMode::Plain).render(&messages), "09:05 <alice> one\n09:06 <alice> two\n");
+    }
+
+    #[test]
+    fn annotations_follow_the_first_line() {
+        let mut message = view("alice", at(9, 5, 0), "lunch\nat noon", None);
+        message.starred = true;
+        message.labels = vec!["food".to_string(), "today".to_string()];
+        message.expires_in = Some(Duration::minutes(4));
+        message.highlighted = true;
+        assert_eq!(
+            uncolored(OutputMode::Plain).render(&[message.clone()]),
+            "09:05 <alice> >> lunch [starred] [food, today] [expires in 4m]\n\
+             \x20             at noon\n"
+        );
+        assert_eq!(
+            uncolored(OutputMode::Rich).render(&[message]),
+            "09:05 <alice> >> lunch ⭐ [food, today] ⏳ 4m\n\
+             \x20             at noon\n"
+        );
+    }
+
+    #[test]
+    fn a_far_off_claimed_time_is_shown_beside_the_received_one() {
+        let mut message = view("alice", at(9, 5, 0), "hello", None);
+        message.claimed_at = Some(at(8, 0, 0));
+        assert_eq!(
+            uncolored(OutputMode::Plain).render(&[message]),
+            "09:05 <alice> hello [sent 08:00 (claimed) / received 09:05 (server-attested)]\n"
+        );
+    }
+
+    #[test]
+    fn group_messages_name_the_group_before_the_sender() {
+        let mut first = view("alice", at(9, 5, 0), "lunch?", None);
+        first.group = Some("team".to_string());
+        let mut second = view("alice", at(9, 5, 10), "at noon", None);
+        second.group = Some("team".to_string());
+        let direct = view("alice", at(9, 5, 20), "just you", None);
+        assert_eq!(uncolored(OutputMode::Plain).render(&[first, second, direct]),
+            "09:05 [team] <alice> lunch?\n                     at noon\n09:05 <alice> just you\n");
+    }
+
+    #[test]
+    fn peer_text_is_sanitized() {
+        let message = view("eve\x1b[31m", at(9, 5, 0), "clear\x1b[2J screen", None);
+        assert_eq!(uncolored(OutputMode::Plain).render(&[message]), "09:05 <eve> clear screen\n");
+    }
+
+    #[test]
+    fn sender_colors_are_stable() {
+        assert_eq!(sender_color("alice"), sender_color("alice"));
+        let distinct: std::collections::HashSet<String> =
+            ["alice", "bob", "carol", "dave", "erin", "frank"].iter().map(|id| format!("{:?}", sender_color(id))).collect();
+        assert!(distinct.len() > 1);
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::time::{Duration, Instant};
+
+/// How long `receive` waits for a missing earlier message by default.
+pub const DEFAULT_JITTER_MS: u64 = 500;
+
+/// Per-contact message numbers. Outgoing messages carry the next number for
+/// their recipient, so the recipient can show them in the order written
+/// even when they arrive out of order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sequences {
+    /// Jitter window picked with `set jitter`; `DEFAULT_JITTER_MS` if unset.
+    #[serde(default)]
+    pub jitter_ms: Option<u64>,
+    /// Last number sent to each contact.
+    #[serde(default)]
+    pub sent: BTreeMap<String, u64>,
+    /// Last number shown from each sender.
+    #[serde(default)]
+    pub shown: BTreeMap<String, u64>,
+}
+
+impl Sequences {
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.jitter_ms.unwrap_or(DEFAULT_JITTER_MS))
+    }
+
+    /// The number the next message to `recipient` gets. Not taken until
+    /// `sent` is called, so a failed send leaves no gap.
+    pub fn next(&self, recipient: &str) -> u64 {
+        self.sent.get(recipient).map_or(1, |last| last + 1)
+    }
+
+    pub fn sent(&mut self, recipient: &str, seq: u64) {
+        self.sent.insert(recipient.to_string(), seq);
+    }
+}
+
+/// What the buffer lets through, in the order it should be shown.
+#[derive(Debug, PartialEq)]
+pub enum Released<T> {
+    Item(T),
+    /// `missing` messages from `sender` never arrived within the window;
+    /// what follows was written after them.
+    Gap { sender: String, missing: u64 },
+}
+
+/// Holds numbered messages that arrive ahead of an earlier one from the same
+/// sender, for at most the window. Unnumbered messages, late ones and
+/// repeats pass straight through.
+pub struct JitterBuffer<T> {
+    window: Duration,
+    /// Held messages by sender, then number, with when each arrived.
+    held: BTreeMap<String, BTreeMap<u64, (Instant, T)>>,
+}
+
+impl<T> JitterBuffer<T> {
+    pub fn new(window: Duration) -> Self {
+        JitterBuffer { window, held: BTreeMap::new() }
+    }
+
+    /// Take one arrival. `shown` is the last number shown per sender; a
+    /// sender not in it starts their sequence with this message.
+    pub fn push(&mut self, shown: &mut BTreeMap<String, u64>, sender: &str, seq: Option<u64>, item: T, now: Instant) -> Vec<Released<T>> {
+        let Some(seq) = seq else { return vec![Released::Item(item)] };
+        let last = shown.get(sender).copied().unwrap_or(seq.saturating_sub(1));
+        if seq <= last {
+            return vec![Released::Item(item)];
+        }
+        if seq > last + 1 {
+            let held = self.held.entry(sender.to_string()).or_default();
+            if held.contains_key(&seq) {
+                return vec![Released::Item(item)];
+            }
+            held.insert(seq, (now, item));
+            return Vec::new();
+        }
+        shown.insert(sender.to_string(), seq);
+        let mut released = vec![Released::Item(item)];
+        released.extend(self.drain(shown, sender));
+        released
+    }
+
+    /// Let through whatever has waited out the window, each sender's run
+    /// preceded by a gap marker.
+    pub fn expire(&mut self, shown: &mut BTreeMap<String, u64>, now: Instant) -> Vec<Released<T>> {
+        let overdue: Vec<String> = self.held.iter()
+            .filter(|(_, held)| held.values().any(|(arrived, _)| now.duration_since(*arrived) >= self.window))
+            .map(|(sender, _)| sender.clone())
+            .collect();
+        let mut released = Vec::new();
+        for sender in overdue {
+            let Some(held) = self.held.get_mut(&sender) else { continue };
+            let Some((seq, (_, item))) = held.pop_first() else { continue };
+            let last = shown.get(&sender).copied().unwrap_or(0);
+            released.push(Released::Gap { sender: sender.clone(), missing: seq.saturating_sub(last + 1) });
+            released.push(Released::Item(item));
+            shown.insert(sender.clone(), seq);
+            released.extend(self.drain(shown, &sender));
+        }
+        released
+    }
+
+    /// When the oldest held message is due, or `None` if nothing is held.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held.values().flat_map(|held| held.values()).map(|(arrived, _)| *arrived + self.window).min()
+    }
+
+    /// Release `sender`'s held messages that now follow on from `shown`.
+    fn drain(&mut self, shown: &mut BTreeMap<String, u64>, sender: &str) -> Vec<Released<T>> {
+        let mut released = Vec::new();
+        let Some(held) = self.held.get_mut(sender) else { return released };
+        while let Some(entry) = held.first_entry() {
+            if *entry.key() != shown[sender] + 1 {
+                break;
+            }
+            let (seq, (_, item)) = entry.remove_entry();
+            shown.insert(sender.to_string(), seq);
+            released.push(Released::Item(item));
+        }
+        if held.is_empty() {
+            self.held.remove(sender);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(500);
+
+    /// A receive loop's view: arrivals go in as they come, and the
+    /// transcript is what gets shown, in order.
+    struct Receiver {
+        buffer: JitterBuffer<&'static str>,
+        shown: BTreeMap<String, u64>,
+        transcript: Vec<String>,
+    }
+
+    impl Receiver {
+        fn new() -> Self {
+            Receiver { buffer: JitterBuffer::new(WINDOW), shown: BTreeMap::new(), transcript: Vec::new() }
+        }
+
+        fn arrive(&mut self, sender: &str, seq: Option<u64>, text: &'static str) {
+            let released = self.buffer.push(&mut self.shown, sender, seq, text, Instant::now());
+            self.show(released);
+        }
+
+        fn expire(&mut self) {
+            let released = self.buffer.expire(&mut self.shown, Instant::now());
+            self.show(released);
+        }
+
+        fn show(&mut self, released: Vec<Released<&'static str>>) {
+            self.transcript.extend(released.into_iter().map(|released| match released {
+                Released::Item(text) => text.to_string(),
+                Released::Gap { sender, missing } => format!("[{} missing from {}]", missing, sender),
+            }));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn out_of_order_arrivals_are_shown_in_the_order_written() {
+        let mut receiver = Receiver::new();
+        receiver.arrive("alice", Some(1), "one");
+        receiver.arrive("alice", Some(3), "three");
+        receiver.arrive("alice", Some(4), "four");
+        assert_eq!(receiver.transcript, ["one"]);
+        tokio::time::advance(Duration::from_millis(100)).await;
+        receiver.arrive("alice", Some(2), "two");
+        assert_eq!(receiver.transcript, ["one", "two", "three", "four"]);
+        assert_eq!(receiver.buffer.deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_message_that_never_comes_leaves_a_gap_after_the_window() {
+        let mut receiver = Receiver::new();
+        receiver.arrive("alice", Some(1), "one");
+        let held_at = Instant::now();
+        receiver.arrive("alice", Some(3), "three");
+        assert_eq!(receiver.buffer.deadline(), Some(held_at + WINDOW));
+
+        tokio::time::advance(WINDOW - Duration::from_millis(1)).await;
+        receiver.arrive("alice", Some(4), "four");
+        receiver.expire();
+        assert_eq!(receiver.transcript, ["one"]);
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        receiver.expire();
+        assert_eq!(receiver.transcript, ["one", "[1 missing from alice]", "three", "four"]);
+
+        // The straggler is shown when it does turn up, after what it preceded
+        receiver.arrive("alice", Some(2), "two");
+        receiver.arrive("alice", Some(5), "five");
+        assert_eq!(receiver.transcript, ["one", "[1 missing from alice]", "three", "four", "two", "five"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn senders_are_ordered_independently() {
+        let mut receiver = Receiver::new();
+        receiver.arrive("alice", Some(1), "a1");
+        receiver.arrive("bob", Some(7), "b7");
+        receiver.arrive("alice", Some(3), "a3");
+        receiver.arrive("bob", Some(8), "b8");
+        receiver.arrive("carol", None, "unnumbered");
+        receiver.arrive("alice", Some(2), "a2");
+        assert_eq!(receiver.transcript, ["a1", "b7", "b8", "unnumbered", "a2", "a3"]);
+
+        // Bob's hold runs out without holding up alice
+        receiver.arrive("bob", Some(10), "b10");
+        receiver.arrive("alice", Some(4), "a4");
+        tokio::time::advance(WINDOW).await;
+        receiver.expire();
+        assert_eq!(receiver.transcript[6..], ["a4", "[1 missing from bob]", "b10"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeats_and_late_messages_pass_straight_through() {
+        let mut receiver = Receiver::new();
+        receiver.arrive("alice", Some(1), "one");
+        receiver.arrive("alice", Some(1), "one again");
+        receiver.arrive("alice", Some(3), "three");
+        receiver.arrive("alice", Some(3), "three again");
+        assert_eq!(receiver.transcript, ["one", "one again", "three again"]);
+        receiver.arrive("alice", Some(2), "two");
+        assert_eq!(receiver.transcript, ["one", "one again", "three again", "two", "three"]);
+    }
+
+    #[test]
+    fn message_numbers_count_up_per_recipient() {
+        let mut sequences = Sequences::default();
+        assert_eq!(sequences.next("alice"), 1);
+        // Not taken until the send goes through
+        assert_eq!(sequences.next("alice"), 1);
+        sequences.sent("alice", 1);
+        assert_eq!((sequences.next("alice"), sequences.next("bob")), (2, 1));
+        assert_eq!(sequences.window(), Duration::from_millis(DEFAULT_JITTER_MS));
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a message id stays remembered after it was first stored.
+pub const SEEN_ID_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// Most ids remembered per sender; the oldest are forgotten first.
+pub const SEEN_IDS_PER_SENDER: usize = 10_000;
+/// Senders with nothing left to remember are dropped once there are this many.
+const PRUNE_AT: usize = 4096;
+
+#[derive(Default)]
+struct Window {
+    order: VecDeque<(String, Instant)>,
+    ids: HashSet<String>,
+}
+
+impl Window {
+    fn expire(&mut self, now: Instant) {
+        while let Some((id, seen)) = self.order.front() {
+            if now.saturating_duration_since(*seen) < SEEN_ID_WINDOW && self.order.len() <= SEEN_IDS_PER_SENDER {
+                break;
+            }
+            self.ids.remove(id);
+            self.order.pop_front();
+        }
+    }
+}
+
+/// The message ids each sender used recently, remembered after the messages
+/// themselves are fetched and acked, so a captured `Send` can't be replayed
+/// to deliver the same message twice. Only kept in memory: after a restart,
+/// the clock skew check on `sent_at` is what turns old sends away. The
+/// server keeps another for the signatures on other signed requests.
+#[derive(Default)]
+pub struct SeenIds {
+    senders: Mutex<HashMap<String, Window>>,
+}
+
+impl SeenIds {
+    /// Whether `sender` used `message_id` within the window.
+    pub fn contains(&self, sender: &str, message_id: &str, now: Instant) -> bool {
+        let mut senders = self.senders.lock().unwrap_or_else(|e| e.into_inner());
+        match senders.get_mut(sender) {
+            Some(window) => {
+                window.expire(now);
+                window.ids.contains(message_id)
+            }
+            None => false,
+        }
+    }
+
+    /// Remember that `sender` used `id`, unless it already had within the
+    /// window; checked and recorded under one lock. Returns whether it was new.
+    pub fn first_use(&self, sender: &str, id: &str, now: Instant) -> bool {
+        let mut senders = self.senders.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(window) = senders.get_mut(sender) {
+            window.expire(now);
+            if window.ids.contains(id) {
+                return false;
+            }
+        }
+        Self::remember(&mut senders, sender, id, now);
+        true
+    }
+
+    /// Remember that `sender` used `message_id`.
+    pub fn insert(&self, sender: &str, message_id: &str, now: Instant) {
+        let mut senders = self.senders.lock().unwrap_or_else(|e| e.into_inner());
+        Self::remember(&mut senders, sender, message_id, now);
+    }
+
+    fn remember(senders: &mut HashMap<String, Window>, sender: &str, message_id: &str, now: Instant) {
+        if senders.len() >= PRUNE_AT {
+            senders.retain(|_, window| {
+                window.expire(now);
+                !window.order.is_empty()
+            });
+        }
+        let window = senders.entry(sender.to_string()).or_default();
+        if window.ids.insert(message_id.to_string()) {
+            window.order.push_back((message_id.to_string(), now));
+        }
+        window.expire(now);
+    }
+}
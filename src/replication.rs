@@ -0,0 +1,402 @@
+use crate::frame;
+use crate::storage::Storage;
+use crate::types::Hlc;
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+
+/// Changes a primary keeps for a standby that reconnects. One further behind
+/// than this is sent a snapshot instead.
+const BACKLOG: usize = 256;
+/// Largest replication frame. A snapshot carries every data file, so this is
+/// well over what clients may send.
+const MAX_FRAME_BYTES: usize = 512 * 1024 * 1024;
+/// How long a standby waits before dialing its primary again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// How long either side waits for the other during the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One storage write on the primary: the whole new contents of a data file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    /// Position in the primary's change log; one more than the change before.
+    pub seq: u64,
+    pub hlc: Hlc,
+    pub component: String,
+    pub contents: String,
+}
+
+/// What goes over a replication connection, one per frame.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum ReplicationFrame {
+    /// Standby to primary: a nonce to prove the primary against, and the
+    /// change log position it has, if any.
+    Hello { nonce: String, log_id: Option<String>, after_seq: u64 },
+    /// Primary to standby: proof it holds the secret, and a nonce for the
+    /// standby's proof.
+    Challenge { nonce: String, proof: String },
+    Proof { proof: String },
+    /// Every data file as of `seq`, for a standby that is new or too far
+    /// behind. Components without a file are empty on the primary.
+    Snapshot { log_id: String, seq: u64, files: BTreeMap<String, String> },
+    /// The standby's position is still in the backlog; changes follow from it.
+    Resume { log_id: String },
+    Change { change: Change },
+    /// Standby to primary: every change up to `seq` is applied.
+    Applied { seq: u64 },
+}
+
+/// The standbys following a log, and how far the furthest along has got.
+#[derive(Clone, Copy, Default)]
+struct Followers {
+    connected: usize,
+    applied: u64,
+}
+
+struct LogState {
+    seq: u64,
+    hlc: Hlc,
+    backlog: VecDeque<Change>,
+}
+
+/// A primary's ordered record of storage writes, for standbys to follow.
+pub struct ChangeLog {
+    /// Sequence numbers restart with the server, so a position only means
+    /// something in the log it came from.
+    id: String,
+    state: Mutex<LogState>,
+    live: broadcast::Sender<Change>,
+    followers: watch::Sender<Followers>,
+}
+
+impl ChangeLog {
+    pub fn new() -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            state: Mutex::new(LogState { seq: 0, hlc: Hlc::default(), backlog: VecDeque::new() }),
+            live: broadcast::channel(BACKLOG).0,
+            followers: watch::channel(Followers::default()).0,
+        }
+    }
+
+    /// The last change logged.
+    pub fn seq(&self) -> u64 {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).seq
+    }
+
+    /// Wait until a standby has applied change `seq`. True once one has, or
+    /// straight away if none is connected; false if `timeout` ran out first.
+    pub async fn wait_for_standbys(&self, seq: u64, timeout: Duration) -> bool {
+        let mut followers = self.followers.subscribe();
+        tokio::time::timeout(timeout, async {
+            loop {
+                let now = *followers.borrow_and_update();
+                if now.connected == 0 || now.applied >= seq || followers.changed().await.is_err() {
+                    return;
+                }
+            }
+        }).await.is_ok()
+    }
+
+    /// Run `write`, then log `contents` as the next change to `component` if
+    /// it succeeded. Both happen under one lock, so changes are numbered in
+    /// the order the files were written.
+    pub fn record(&self, component: &str, contents: String, write: impl FnOnce(String) -> Result<()>) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        write(contents.clone())?;
+        state.seq += 1;
+        state.hlc = state.hlc.next(Utc::now());
+        let change = Change { seq: state.seq, hlc: state.hlc, component: component.to_string(), contents };
+        if state.backlog.len() == BACKLOG {
+            state.backlog.pop_front();
+        }
+        state.backlog.push_back(change.clone());
+        // Nobody listening is fine; a standby that connects later catches up
+        let _ = self.live.send(change);
+        Ok(())
+    }
+
+    /// `read` with no change logged meanwhile, the position it's as of, and
+    /// the changes after it.
+    fn snapshot<T>(&self, read: impl FnOnce() -> Result<T>) -> Result<(T, u64, broadcast::Receiver<Change>)> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        Ok((read()?, state.seq, self.live.subscribe()))
+    }
+
+    /// The backlog after `after_seq` and the changes from there on, or `None`
+    /// if the backlog no longer reaches back that far.
+    fn follow(&self, after_seq: u64) -> Option<(Vec<Change>, broadcast::Receiver<Change>)> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let oldest = state.backlog.front().map_or(state.seq + 1, |change| change.seq);
+        if after_seq > state.seq || after_seq + 1 < oldest {
+            return None;
+        }
+        let missed = state.backlog.iter().filter(|change| change.seq > after_seq).cloned().collect();
+        Some((missed, self.live.subscribe()))
+    }
+}
+
+/// Counts a standby as connected while held.
+struct Following<'a>(&'a ChangeLog);
+
+impl<'a> Following<'a> {
+    fn new(log: &'a ChangeLog) -> Self {
+        log.followers.send_modify(|followers| followers.connected += 1);
+        Following(log)
+    }
+
+    fn applied(&self, seq: u64) {
+        self.0.followers.send_modify(|followers| followers.applied = followers.applied.max(seq));
+    }
+}
+
+impl Drop for Following<'_> {
+    fn drop(&mut self) {
+        self.0.followers.send_modify(|followers| followers.connected -= 1);
+    }
+}
+
+/// A standby's side: the primary it follows, until it is promoted.
+pub struct Standby {
+    primary: String,
+    promoted: AtomicBool,
+    // Held while a change is applied, so promotion waits for the one in hand
+    // and none is applied after it
+    applying: tokio::sync::Mutex<()>,
+}
+
+impl Standby {
+    pub fn new(primary: String) -> Self {
+        Self { primary, promoted: AtomicBool::new(false), applying: tokio::sync::Mutex::new(()) }
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    /// Whether writes are still refused.
+    pub fn is_read_only(&self) -> bool {
+        !self.promoted.load(Ordering::SeqCst)
+    }
+
+    /// Stop following the primary. False if this was already done.
+    pub async fn promote(&self) -> bool {
+        let _applying = self.applying.lock().await;
+        !self.promoted.swap(true, Ordering::SeqCst)
+    }
+}
+
+/// Shared secret both sides prove they hold, read from a private file.
+#[derive(Clone)]
+pub struct Secret(Arc<Vec<u8>>);
+
+impl Secret {
+    pub fn new(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.is_empty() {
+            return Err(anyhow!("The replication secret is empty"));
+        }
+        Ok(Secret(Arc::new(bytes)))
+    }
+
+    fn proof(&self, role: &str, standby_nonce: &str, primary_nonce: &str) -> Hmac<Sha256> {
+        // Any length is a valid HMAC key
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any length");
+        mac.update(format!("msgproto-replication\n{}\n{}\n{}", role, standby_nonce, primary_nonce).as_bytes());
+        mac
+    }
+
+    fn sign(&self, role: &str, standby_nonce: &str, primary_nonce: &str) -> String {
+        hex::encode(self.proof(role, standby_nonce, primary_nonce).finalize().into_bytes())
+    }
+
+    fn check(&self, role: &str, standby_nonce: &str, primary_nonce: &str, proof: &str) -> Result<()> {
+        let proof = hex::decode(proof).map_err(|_| anyhow!("Malformed {} proof", role))?;
+        self.proof(role, standby_nonce, primary_nonce).verify_slice(&proof)
+            .map_err(|_| anyhow!("The {} doesn't hold the replication secret", role))
+    }
+}
+
+fn new_nonce() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+async fn send(stream: &mut (impl AsyncWrite + Unpin), frame: &ReplicationFrame) -> Result<()> {
+    frame::write_frame(stream, serde_json::to_string(frame)?.as_bytes(), MAX_FRAME_BYTES).await?;
+    Ok(())
+}
+
+async fn receive(stream: &mut (impl AsyncRead + Unpin)) -> Result<ReplicationFrame> {
+    let body = frame::read_frame(stream, MAX_FRAME_BYTES).await?
+        .ok_or_else(|| anyhow!("The other server closed the connection"))?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn receive_within(stream: &mut TcpStream) -> Result<ReplicationFrame> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, receive(stream)).await
+        .map_err(|_| anyhow!("Timed out waiting for the other server"))?
+}
+
+/// Serve standbys on `addr`, streaming `storage`'s change log to each,
+/// until `stop` changes or its sender is dropped.
+pub async fn serve(addr: String, secret: Secret, storage: Arc<Storage>, mut stop: watch::Receiver<bool>) -> Result<()> {
+    let log = storage.change_log().ok_or_else(|| anyhow!("Storage isn't recording changes"))?;
+    let listener = TcpListener::bind(&addr).await?;
+    info!("🪞 Taking standbys on {}", addr);
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = stop.changed() => return Ok(()),
+        };
+        let (secret, storage, log, mut stop) = (secret.clone(), Arc::clone(&storage), Arc::clone(&log), stop.clone());
+        tokio::spawn(async move {
+            let streamed = tokio::select! {
+                streamed = stream_to(&mut stream, &secret, &storage, &log) => streamed,
+                _ = stop.changed() => Ok(()),
+            };
+            match streamed {
+                Ok(()) => info!("🪞 Standby {} disconnected", peer),
+                Err(e) => warn!("🪞 Replication to {} stopped: {}", peer, e),
+            }
+        });
+    }
+}
+
+async fn stream_to(stream: &mut TcpStream, secret: &Secret, storage: &Storage, log: &ChangeLog) -> Result<()> {
+    let ReplicationFrame::Hello { nonce: standby_nonce, log_id, after_seq } = receive_within(stream).await? else {
+        return Err(anyhow!("Expected a hello"));
+    };
+    let nonce = new_nonce();
+    send(stream, &ReplicationFrame::Challenge { nonce: nonce.clone(), proof: secret.sign("primary", &standby_nonce, &nonce) }).await?;
+    let ReplicationFrame::Proof { proof } = receive_within(stream).await? else {
+        return Err(anyhow!("Expected a proof"));
+    };
+    secret.check("standby", &standby_nonce, &nonce, &proof)?;
+    let following = Following::new(log);
+
+    let resumed = (log_id.as_deref() == Some(log.id.as_str())).then(|| log.follow(after_seq)).flatten();
+    let mut live = match resumed {
+        Some((missed, live)) => {
+            info!("🪞 Standby resumed after change {}; {} to catch up", after_seq, missed.len());
+            send(stream, &ReplicationFrame::Resume { log_id: log.id.clone() }).await?;
+            for change in missed {
+                send(stream, &ReplicationFrame::Change { change }).await?;
+            }
+            live
+        }
+        None => {
+            let (files, seq, live) = log.snapshot(|| storage.read_data_files())?;
+            info!("🪞 Sending a snapshot as of change {} to a standby", seq);
+            send(stream, &ReplicationFrame::Snapshot { log_id: log.id.clone(), seq, files }).await?;
+            live
+        }
+    };
+    let (mut reader, mut writer) = stream.split();
+    let changes = async {
+        loop {
+            match live.recv().await {
+                Ok(change) => send(&mut writer, &ReplicationFrame::Change { change }).await?,
+                // The standby resumes from where it got to, or takes a snapshot
+                Err(broadcast::error::RecvError::Lagged(missed)) => return Err(anyhow!("The standby fell {} changes behind", missed)),
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    };
+    let confirmations = async {
+        loop {
+            match receive(&mut reader).await? {
+                ReplicationFrame::Applied { seq } => following.applied(seq),
+                _ => return Err(anyhow!("Unexpected frame from the standby")),
+            }
+        }
+    };
+    tokio::select! {
+        streamed = changes => streamed,
+        confirmed = confirmations => confirmed,
+    }
+}
+
+/// Follow the primary until promoted, reconnecting whenever the connection
+/// drops. `applied` is told each component that changed.
+pub async fn follow(standby: Arc<Standby>, secret: Secret, storage: Arc<Storage>, applied: impl Fn(&str) + Send + Sync) {
+    let mut position: Option<(String, u64)> = None;
+    while standby.is_read_only() {
+        match follow_once(&standby, &secret, &storage, &mut position, &applied).await {
+            Ok(()) => info!("🪞 Stopped following {}", standby.primary),
+            Err(e) => warn!("🪞 Lost {}: {}; reconnecting in {}s", standby.primary, e, RECONNECT_DELAY.as_secs()),
+        }
+        if standby.is_read_only() {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+    info!("🪞 Promoted; no longer following {}", standby.primary);
+}
+
+async fn follow_once(standby: &Standby, secret: &Secret, storage: &Storage, position: &mut Option<(String, u64)>, applied: &impl Fn(&str)) -> Result<()> {
+    let mut stream = TcpStream::connect(&standby.primary).await?;
+    let nonce = new_nonce();
+    send(&mut stream, &ReplicationFrame::Hello {
+        nonce: nonce.clone(),
+        log_id: position.as_ref().map(|(log_id, _)| log_id.clone()),
+        after_seq: position.as_ref().map_or(0, |(_, seq)| *seq),
+    }).await?;
+    let ReplicationFrame::Challenge { nonce: primary_nonce, proof } = receive_within(&mut stream).await? else {
+        return Err(anyhow!("Expected a challenge"));
+    };
+    secret.check("primary", &nonce, &primary_nonce, &proof)?;
+    send(&mut stream, &ReplicationFrame::Proof { proof: secret.sign("standby", &nonce, &primary_nonce) }).await?;
+
+    loop {
+        let frame = receive(&mut stream).await?;
+        let _applying = standby.applying.lock().await;
+        if !standby.is_read_only() {
+            return Ok(());
+        }
+        match frame {
+            ReplicationFrame::Snapshot { log_id, seq, files } => {
+                // A snapshot half applied is no position at all
+                *position = None;
+                let mut files = files;
+                for component in Storage::components() {
+                    storage.apply_replicated(component, files.remove(component)).await?;
+                    applied(component);
+                }
+                info!("🪞 Loaded a snapshot of {} as of change {}", standby.primary, seq);
+                *position = Some((log_id, seq));
+                send(&mut stream, &ReplicationFrame::Applied { seq }).await?;
+            }
+            ReplicationFrame::Resume { log_id } => {
+                let Some((_, seq)) = position.as_ref().filter(|(id, _)| *id == log_id) else {
+                    return Err(anyhow!("The primary resumed a change log this standby never followed"));
+                };
+                send(&mut stream, &ReplicationFrame::Applied { seq: *seq }).await?;
+            }
+            ReplicationFrame::Change { change } => {
+                let Some((_, seq)) = position.as_mut() else {
+                    return Err(anyhow!("The primary sent a change before a snapshot"));
+                };
+                if change.seq != *seq + 1 {
+                    // Whatever was skipped is unknown, so start over from a snapshot
+                    let expected = *seq + 1;
+                    *position = None;
+                    return Err(anyhow!("Expected change {} but got {}; re-syncing", expected, change.seq));
+                }
+                storage.apply_replicated(&change.component, Some(change.contents)).await?;
+                applied(&change.component);
+                *seq = change.seq;
+                send(&mut stream, &ReplicationFrame::Applied { seq: change.seq }).await?;
+            }
+            _ => return Err(anyhow!("Unexpected frame from the primary")),
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use crate::instrument::{QueueGauge, QueueSnapshot};
+use crate::secure_fs;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use log::error;
+use tokio::sync::mpsc;
+
+/// Rotate a log once it reaches this size, by default.
+pub const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+/// Rotated files kept, by default; older ones are deleted.
+pub const DEFAULT_KEEP: usize = 10;
+/// zstd level for rotated files.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// When a log is rotated, and how many rotated files are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct RotationPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy { max_bytes: Some(DEFAULT_MAX_BYTES), max_age: None, keep: DEFAULT_KEEP }
+    }
+}
+
+/// First line of every file in a log.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    opened_at: DateTime<Utc>,
+    /// Hash of the last record written before this file was opened, so the
+    /// chain carries on across rotations. Empty at the start of the log.
+    prev_hash: String,
+}
+
+/// Every line after the header: a record, chained to the one before it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    prev_hash: String,
+    hash: String,
+    record: serde_json::Value,
+}
+
+fn entry_hash(prev_hash: &str, record: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(record.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// An append-only, hash-chained JSON-lines file that rotates itself. The
+/// live file is `<name>`; rotated ones are `<name>.<n>.zst`, numbered in the
+/// order they were rotated. Only one writer may own a log at a time.
+pub struct RotatingLog {
+    dir: PathBuf,
+    name: String,
+    policy: RotationPolicy,
+    file: File,
+    opened_at: DateTime<Utc>,
+    bytes: u64,
+    last_hash: String,
+}
+
+impl RotatingLog {
+    /// Open the log, carrying on from the live file if there is one.
+    pub fn open(dir: &Path, name: &str, policy: RotationPolicy) -> Result<Self> {
+        let path = dir.join(name);
+        let existing = match File::open(&path) {
+            Ok(file) => Some(read_file(BufReader::new(file), &path)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        match existing {
+            Some((header, entries)) => {
+                let last_hash = entries.last().map_or(header.prev_hash.clone(), |entry| entry.hash.clone());
+                let file = OpenOptions::new().append(true).open(&path)?;
+                let bytes = file.metadata()?.len();
+                Ok(RotatingLog { dir: dir.to_path_buf(), name: name.to_string(), policy, file, opened_at: header.opened_at, bytes, last_hash })
+            }
+            None => {
+                // A log whose live file went missing carries on from its newest rotated file
+                let last_hash = match archives(dir, name)?.last() {
+                    Some((_, archive)) => {
+                        let (header, entries) = read_archive(archive)?;
+                        entries.last().map_or(header.prev_hash, |entry| entry.hash.clone())
+                    }
+                    None => String::new(),
+                };
+                let (file, opened_at, bytes) = start_file(&path, &last_hash)?;
+                Ok(RotatingLog { dir: dir.to_path_buf(), name: name.to_string(), policy, file, opened_at, bytes, last_hash })
+            }
+        }
+    }
+
+    /// Append one record, rotating first if the live file is due. Returns
+    /// whether it rotated.
+    pub fn append(&mut self, record: &impl Serialize) -> Result<bool> {
+        let rotated = self.is_due(Utc::now());
+        if rotated {
+            self.rotate()?;
+        }
+        let record = serde_json::to_value(record)?;
+        let hash = entry_hash(&self.last_hash, &serde_json::to_string(&record)?);
+        let mut line = serde_json::to_string(&Entry { prev_hash: self.last_hash.clone(), hash: hash.clone(), record })?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.bytes += line.len() as u64;
+        self.last_hash = hash;
+        Ok(rotated)
+    }
+
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        let too_big = self.policy.max_bytes.is_some_and(|max| self.bytes >= max);
+        let too_old = self.policy.max_age.is_some_and(|max| {
+            now.signed_duration_since(self.opened_at).to_std().is_ok_and(|age| age >= max)
+        });
+        too_big || too_old
+    }
+
+    /// Move the live file aside, start a new one chained to it, then compress
+    /// the old one and drop rotated files past the limit. Nothing is written
+    /// in between, so no record is lost or repeated at the boundary.
+    pub fn rotate(&mut self) -> Result<()> {
+        let path = self.dir.join(&self.name);
+        let number = archives(&self.dir, &self.name)?.last().map_or(1, |(n, _)| n + 1);
+        let moved = self.dir.join(format!("{}.{:08}", self.name, number));
+        fs::rename(&path, &moved)?;
+        let (file, opened_at, bytes) = start_file(&path, &self.last_hash)?;
+        self.file = file;
+        self.opened_at = opened_at;
+        self.bytes = bytes;
+
+        compress(&moved)?;
+        let archived = archives(&self.dir, &self.name)?;
+        for (_, old) in archived.iter().take(archived.len().saturating_sub(self.policy.keep)) {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write a new live file holding only its header.
+fn start_file(path: &Path, prev_hash: &str) -> Result<(File, DateTime<Utc>, u64)> {
+    let opened_at = Utc::now();
+    let mut line = serde_json::to_string(&Header { opened_at, prev_hash: prev_hash.to_string() })?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create_new(true).append(true).open(path)?;
+    secure_fs::restrict_file(path)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_all()?;
+    Ok((file, opened_at, line.len() as u64))
+}
+
+/// Replace `path` with `path.zst`. The compressed copy is complete before
+/// the original goes, so a crash leaves one or the other.
+fn compress(path: &Path) -> Result<()> {
+    let target = PathBuf::from(format!("{}.zst", path.display()));
+    let partial = PathBuf::from(format!("{}.zst.tmp", path.display()));
+    {
+        let mut input = File::open(path)?;
+        let output = File::create(&partial)?;
+        secure_fs::restrict_file(&partial)?;
+        let mut encoder = zstd::Encoder::new(output, COMPRESSION_LEVEL)?;
+        std::io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+    }
+    fs::rename(&partial, &target)?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Rotated files of a log, oldest first, with their numbers. A file whose
+/// compression was interrupted is listed uncompressed.
+fn archives(dir: &Path, name: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let prefix = format!("{}.", name);
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+        let Some(rest) = file_name.strip_prefix(&prefix) else { continue };
+        let number = rest.strip_suffix(".zst").unwrap_or(rest);
+        if let Ok(number) = number.parse::<u64>() {
+            found.push((number, path));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn read_archive(path: &Path) -> Result<(Header, Vec<Entry>)> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|ext| ext == "zst") {
+        read_file(BufReader::new(zstd::Decoder::new(file)?), path)
+    } else {
+        read_file(BufReader::new(file), path)
+    }
+}
+
+fn read_file(reader: impl BufRead, path: &Path) -> Result<(Header, Vec<Entry>)> {
+    let mut lines = reader.lines();
+    let header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).map_err(|e| anyhow!("{}: bad header: {}", path.display(), e))?,
+        None => return Err(anyhow!("{}: empty file", path.display())),
+    };
+    let entries = lines
+        .enumerate()
+        .map(|(i, line)| serde_json::from_str(&line?).map_err(|e| anyhow!("{}: bad record {}: {}", path.display(), i + 1, e)))
+        .collect::<Result<Vec<Entry>>>()?;
+    Ok((header, entries))
+}
+
+/// Check the hash chain across every kept file of a log, oldest first.
+/// Each file's header must name the last hash of the one before it. The
+/// oldest kept file's header is taken on trust, since what it follows may
+/// have been deleted. Returns how many records there are; a log that
+/// doesn't exist has none.
+pub fn verify(dir: &Path, name: &str) -> Result<u64> {
+    let mut files: Vec<PathBuf> = archives(dir, name)?.into_iter().map(|(_, path)| path).collect();
+    let live = dir.join(name);
+    if live.exists() {
+        files.push(live);
+    }
+
+    let mut expected: Option<String> = None;
+    let mut count = 0;
+    for path in files {
+        let (header, entries) = read_archive(&path)?;
+        if expected.as_ref().is_some_and(|hash| *hash != header.prev_hash) {
+            return Err(anyhow!("{} does not follow on from the file before it", path.display()));
+        }
+        let mut prev_hash = header.prev_hash;
+        for (i, entry) in entries.into_iter().enumerate() {
+            if entry.prev_hash != prev_hash {
+                return Err(anyhow!("{}: record {} does not link to its predecessor", path.display(), i + 1));
+            }
+            if entry_hash(&prev_hash, &serde_json::to_string(&entry.record)?) != entry.hash {
+                return Err(anyhow!("{}: record {} has been altered", path.display(), i + 1));
+            }
+            prev_hash = entry.hash;
+            count += 1;
+        }
+        expected = Some(prev_hash);
+    }
+    Ok(count)
+}
+
+/// Hands records to a single writer thread that owns a `RotatingLog`, so
+/// callers never wait on the disk and a rotation always falls between two
+/// records. The queue is unbounded: records are never dropped to keep up.
+pub struct LogWriter {
+    name: String,
+    queue: mpsc::UnboundedSender<(Instant, serde_json::Value)>,
+    rotations: Arc<AtomicU64>,
+    backlog: Arc<QueueGauge>,
+}
+
+impl LogWriter {
+    pub fn start(name: &str, mut log: RotatingLog) -> Self {
+        let (queue, mut receiver) = mpsc::unbounded_channel::<(Instant, serde_json::Value)>();
+        let rotations = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&rotations);
+        let backlog = Arc::new(QueueGauge::default());
+        let gauge = Arc::clone(&backlog);
+        let log_name = name.to_string();
+        std::thread::spawn(move || {
+            while let Some((queued_at, record)) = receiver.blocking_recv() {
+                gauge.taken(queued_at);
+                match log.append(&record) {
+                    Ok(true) => {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(false) => {}
+                    Err(e) => error!("❌ Failed to write to {}: {}", log_name, e),
+                }
+            }
+        });
+        LogWriter { name: name.to_string(), queue, rotations, backlog }
+    }
+
+    pub fn append(&self, record: &impl Serialize) {
+        match serde_json::to_value(record) {
+            Ok(record) => {
+                self.backlog.queued();
+                if self.queue.send((Instant::now(), record)).is_err() {
+                    error!("❌ The writer for {} has stopped; a record was not written", self.name);
+                }
+            }
+            Err(e) => error!("❌ Failed to encode a record for {}: {}", self.name, e),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Records not yet written, and how long the last one waited.
+    pub fn backlog(&self) -> QueueSnapshot {
+        self.backlog.snapshot()
+    }
+
+    /// Rotations since startup.
+    pub fn rotations(&self) -> u64 {
+        self.rotations.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+    use std::io::Read;
+
+    /// Small enough that a few records fill a file.
+    fn small(keep: usize) -> RotationPolicy {
+        RotationPolicy { max_bytes: Some(400), max_age: None, keep }
+    }
+
+    /// Append records numbered `from..to`, returning how many rotations that took.
+    fn write(log: &mut RotatingLog, from: u64, to: u64) -> usize {
+        (from..to).map(|n| log.append(&serde_json::json!({ "n": n })).unwrap()).filter(|rotated| *rotated).count()
+    }
+
+    /// Every record kept, oldest first, read straight from the files.
+    fn numbers(dir: &Path) -> Vec<u64> {
+        let mut files: Vec<PathBuf> = archives(dir, "audit.log").unwrap().into_iter().map(|(_, path)| path).collect();
+        files.push(dir.join("audit.log"));
+        files.iter()
+            .flat_map(|path| read_archive(path).unwrap().1)
+            .map(|entry| entry.record["n"].as_u64().unwrap())
+            .collect()
+    }
+
+    /// Rewrite the lines of a rotated file, compressed as it was.
+    fn rewrite(path: &Path, edit: impl FnOnce(&mut Vec<String>)) {
+        let mut text = String::new();
+        zstd::Decoder::new(File::open(path).unwrap()).unwrap().read_to_string(&mut text).unwrap();
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        edit(&mut lines);
+        let body = lines.iter().map(|line| format!("{}\n", line)).collect::<String>();
+        fs::write(path, zstd::encode_all(body.as_bytes(), COMPRESSION_LEVEL).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn the_chain_runs_unbroken_across_size_rotations() {
+        let scratch = Scratch::new("rotate-chain");
+        let mut log = RotatingLog::open(&scratch.0, "audit.log", small(100)).unwrap();
+        assert!(write(&mut log, 0, 60) >= 4, "the log didn't rotate on size");
+        assert!(archives(&scratch.0, "audit.log").unwrap().iter().all(|(_, path)| path.extension().is_some_and(|ext| ext == "zst")));
+        assert_eq!(verify(&scratch.0, "audit.log").unwrap(), 60);
+        // Each record exactly once, in order, whichever file it landed in
+        assert_eq!(numbers(&scratch.0), (0..60).collect::<Vec<_>>());
+
+        // Reopened, it carries on from the live file
+        drop(log);
+        let mut log = RotatingLog::open(&scratch.0, "audit.log", small(100)).unwrap();
+        write(&mut log, 60, 90);
+        assert_eq!(verify(&scratch.0, "audit.log").unwrap(), 90);
+        assert_eq!(numbers(&scratch.0), (0..90).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dropping_old_files_leaves_a_verifiable_tail() {
+        let scratch = Scratch::new("rotate-keep");
+        let mut log = RotatingLog::open(&scratch.0, "audit.log", small(2)).unwrap();
+        write(&mut log, 0, 60);
+        assert_eq!(archives(&scratch.0, "audit.log").unwrap().len(), 2);
+        let kept = numbers(&scratch.0);
+        assert_eq!(verify(&scratch.0, "audit.log").unwrap(), kept.len() as u64);
+        assert_eq!(kept, (60 - kept.len() as u64..60).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_truncated_file_breaks_the_chain() {
+        let scratch = Scratch::new("rotate-truncated");
+        let mut log = RotatingLog::open(&scratch.0, "audit.log", small(100)).unwrap();
+        write(&mut log, 0, 60);
+        let (_, middle) = archives(&scratch.0, "audit.log").unwrap().swap_remove(1);
+        rewrite(&middle, |lines| { lines.pop(); });
+        assert!(verify(&scratch.0, "audit.log").unwrap_err().to_string().contains("does not follow on"));
+    }
+
+    #[test]
+    fn an_edited_or_removed_record_breaks_the_chain() {
+        let scratch = Scratch::new("rotate-edited");
+        let mut log = RotatingLog::open(&scratch.0, "audit.log", small(100)).unwrap();
+        write(&mut log, 0, 60);
+        let (_, first) = archives(&scratch.0, "audit.log").unwrap().swap_remove(0);
+        let original = fs::read(&first).unwrap();
+
+        rewrite(&first, |lines| lines[1] = lines[1].replace("\"n\":0", "\"n\":7"));
+        assert!(verify(&scratch.0, "audit.log").unwrap_err().to_string().contains("altered"));
+
+        fs::write(&first, &original).unwrap();
+        rewrite(&first, |lines| { lines.remove(1); });
+        assert!(verify(&scratch.0, "audit.log").unwrap_err().to_string().contains("does not link"));
+
+        // The live file is checked the same way
+        fs::write(&first, &original).unwrap();
+        let live = scratch.0.join("audit.log");
+        let text = fs::read_to_string(&live).unwrap();
+        let last = text.lines().last().unwrap().to_string();
+        fs::write(&live, text.replace(&last, &last.replace("\"n\":59", "\"n\":58"))).unwrap();
+        assert!(verify(&scratch.0, "audit.log").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::sanitize::{self, ControlDisplay};
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RuleAction {
+    /// Don't render or notify; the message is still fetched.
+    Mute,
+    /// Render with a distinct color and ring the terminal bell.
+    Highlight,
+    /// Run a shell command with the message exposed through the environment.
+    Run(String),
+}
+
+/// A client-side filtering rule, evaluated after decryption.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub sender: Option<String>,
+    pub pattern: Option<String>,
+    pub action: RuleAction,
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+impl Rule {
+    pub fn new(sender: Option<String>, pattern: Option<String>, action: RuleAction) -> Result<Self> {
+        let mut rule = Self { sender, pattern, action, regex: None };
+        rule.compile()?;
+        Ok(rule)
+    }
+
+    /// Parse `[from=<sender>] [text=<regex>] <mute|highlight|run <command...>>`.
+    pub fn parse(args: &[&str]) -> Result<Self> {
+        let mut sender = None;
+        let mut pattern = None;
+        let mut rest = args.iter();
+
+        let action = loop {
+            match rest.next() {
+                Some(arg) if arg.starts_with("from=") => sender = Some(arg["from=".len()..].to_string()),
+                Some(arg) if arg.starts_with("text=") => pattern = Some(arg["text=".len()..].to_string()),
+                Some(&"mute") => break RuleAction::Mute,
+                Some(&"highlight") => break RuleAction::Highlight,
+                Some(&"run") => {
+                    let command = rest.by_ref().copied().collect::<Vec<_>>().join(" ");
+                    if command.is_empty() {
+                        return Err(anyhow!("run requires a command"));
+                    }
+                    break RuleAction::Run(command);
+                }
+                Some(other) => return Err(anyhow!("Unexpected rule argument: {}", other)),
+                None => return Err(anyhow!("Missing action (mute, highlight or run <command>)")),
+            }
+        };
+
+        if rest.next().is_some() {
+            return Err(anyhow!("Unexpected arguments after action"));
+        }
+
+        Self::new(sender, pattern, action)
+    }
+
+    /// Compile the text pattern; called on creation and after loading from config.
+    pub fn compile(&mut self) -> Result<()> {
+        self.regex = match &self.pattern {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| anyhow!("Invalid regex: {}", e))?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn matches(&self, sender: &str, text: &str) -> bool {
+        if let Some(expected) = &self.sender {
+            if expected != sender {
+                return false;
+            }
+        }
+        match &self.regex {
+            Some(regex) => regex.is_match(text),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(sender) = &self.sender {
+            write!(f, "from={} ", sender)?;
+        }
+        if let Some(pattern) = &self.pattern {
+            write!(f, "text={} ", pattern)?;
+        }
+        match &self.action {
+            RuleAction::Mute => write!(f, "mute"),
+            RuleAction::Highlight => write!(f, "highlight"),
+            RuleAction::Run(command) => write!(f, "run {}", command),
+        }
+    }
+}
+
+/// Rules are evaluated in order; the first match wins.
+pub fn evaluate<'a>(rules: &'a [Rule], sender: &str, text: &str) -> Option<(usize, &'a Rule)> {
+    rules.iter().enumerate().find(|(_, rule)| rule.matches(sender, text))
+}
+
+pub fn run_command(command: &str, sender: &str, text: &str) -> Result<()> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    };
+    // Hooks often print or notify with these, so they get the same cleaning as the screen
+    cmd.env("MSG_SENDER", sanitize::line(sender, ControlDisplay::Strip, sanitize::MAX_NAME_WIDTH))
+        .env("MSG_TEXT", sanitize::text(text, ControlDisplay::Strip))
+        .spawn()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+
+    fn rule(args: &str) -> Rule {
+        Rule::parse(&args.split(' ').collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = [rule("from=bot text=^status mute"), rule("text=(?i)urgent highlight"), rule("from=bot run true")];
+        assert_eq!(evaluate(&rules, "bot", "status: ok").map(|(i, _)| i), Some(0));
+        assert_eq!(evaluate(&rules, "bot", "URGENT: disk full").map(|(i, _)| i), Some(1));
+        assert_eq!(evaluate(&rules, "bot", "hello").map(|(i, _)| i), Some(2));
+        assert_eq!(evaluate(&rules, "alice", "urgent?").map(|(i, _)| i), Some(1));
+        assert!(evaluate(&rules, "alice", "status: ok").is_none());
+    }
+
+    #[test]
+    fn each_action_parses_and_prints_back() {
+        for args in ["from=bot mute", "text=deploy highlight", "from=ci text=fail(ed)? run notify-send \"$MSG_TEXT\""] {
+            assert_eq!(rule(args).to_string(), args);
+        }
+        assert_eq!(rule("mute").action, RuleAction::Mute);
+        assert_eq!(rule("highlight").action, RuleAction::Highlight);
+        assert_eq!(rule("run echo hi").action, RuleAction::Run("echo hi".to_string()));
+    }
+
+    #[test]
+    fn bad_rules_are_refused_when_added() {
+        for args in [&["text=(unclosed", "mute"][..], &["from=bot"], &["run"], &["mute", "extra"], &["loudly"]] {
+            assert!(Rule::parse(args).is_err(), "{:?} was accepted", args);
+        }
+    }
+
+    #[test]
+    fn loaded_rules_match_once_compiled() {
+        let saved = serde_json::to_string(&rule("text=^ping mute")).unwrap();
+        let mut loaded: Rule = serde_json::from_str(&saved).unwrap();
+        loaded.compile().unwrap();
+        assert!(loaded.matches("anyone", "ping"));
+        assert!(!loaded.matches("anyone", "pong"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_exposes_the_message_to_the_command() {
+        let scratch = Scratch::new("rules-run");
+        let out = scratch.0.join("out");
+        let command = format!("printf '%s|%s' \"$MSG_SENDER\" \"$MSG_TEXT\" > {}", out.display());
+        run_command(&command, "bot\x1b[2J", "build failed").unwrap();
+        let mut written = String::new();
+        for _ in 0..50 {
+            written = std::fs::read_to_string(&out).unwrap_or_default();
+            if !written.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(written, "bot|build failed");
+    }
+}
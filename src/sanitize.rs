@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Widest a sender id or other name from a peer is shown.
+pub const MAX_NAME_WIDTH: usize = 40;
+/// Widest a label is shown.
+pub const MAX_LABEL_WIDTH: usize = 32;
+const TRUNCATION_MARKER: char = '…';
+
+/// What happens to control characters, terminal escape sequences and bidi
+/// overrides in text that came from someone else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlDisplay {
+    /// Drop them.
+    #[default]
+    Strip,
+    /// Show them spelled out, e.g. `\x1b[2J`, so it's visible that a peer
+    /// sent them.
+    Escape,
+}
+
+/// Make text from a peer safe to print: NFC-normalized, with no control
+/// characters, escape sequences or bidi overrides left to act on the
+/// terminal. Line breaks are kept, so a multi-line message stays one.
+pub fn text(input: &str, display: ControlDisplay) -> String {
+    clean(input, display, true)
+}
+
+/// Like [`text`], for a name that must stay on one line and within
+/// `max_width` columns.
+pub fn line(input: &str, display: ControlDisplay, max_width: usize) -> String {
+    truncate(&clean(input, display, false), max_width)
+}
+
+/// Columns `input` takes up on a terminal.
+pub fn width(input: &str) -> usize {
+    input.width()
+}
+
+/// Cut `input` to at most `max_width` columns, ending it with `…` if cut.
+pub fn truncate(input: &str, max_width: usize) -> String {
+    if input.width() <= max_width {
+        return input.to_string();
+    }
+    let mut out = String::new();
+    let mut width = 0;
+    for c in input.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if width + char_width + 1 > max_width {
+            break;
+        }
+        out.push(c);
+        width += char_width;
+    }
+    out.push(TRUNCATION_MARKER);
+    out
+}
+
+fn clean(input: &str, display: ControlDisplay, multiline: bool) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.nfc().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' if multiline => out.push('\n'),
+            '\n' | '\t' if display == ControlDisplay::Strip => out.push(' '),
+            // A whole escape sequence goes, not just the ESC, or what follows
+            // it would still be printed as text
+            '\u{1b}' | '\u{9b}' | '\u{9d}' if display == ControlDisplay::Strip => {
+                let kind = match c {
+                    '\u{9b}' => Some('['),
+                    '\u{9d}' => Some(']'),
+                    // A second ESC starts a sequence of its own
+                    _ => chars.next_if(|&c| c != '\u{1b}'),
+                };
+                match kind {
+                    // CSI: parameters and intermediates, then one final byte
+                    Some('[') => {
+                        for c in chars.by_ref() {
+                            if ('\u{40}'..='\u{7e}').contains(&c) {
+                                break;
+                            }
+                        }
+                    }
+                    // OSC and friends: up to BEL or ESC \
+                    Some(']' | 'P' | '^' | '_' | 'X') => {
+                        while let Some(c) = chars.next() {
+                            if c == '\u{7}' || c == '\u{9c}' {
+                                break;
+                            }
+                            if c == '\u{1b}' && chars.peek() == Some(&'\\') {
+                                chars.next();
+                                break;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            c if is_unsafe(c) => {
+                if display == ControlDisplay::Escape {
+                    out.push_str(&escaped(c));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Characters that act on the terminal or on the order text is shown in.
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || is_bidi_control(c)
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{061c}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+fn escaped(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        c if (c as u32) < 0x80 => format!("\\x{:02x}", c as u32),
+        c => format!("\\u{{{:x}}}", c as u32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRIP: ControlDisplay = ControlDisplay::Strip;
+    const ESCAPE: ControlDisplay = ControlDisplay::Escape;
+
+    #[test]
+    fn escape_sequences_go_whole() {
+        assert_eq!(text("clear\x1b[2Jscreen", STRIP), "clearscreen");
+        assert_eq!(text("\x1b[1;31mred\x1b[0m", STRIP), "red");
+        assert_eq!(text("\u{9b}31mred", STRIP), "red");
+        assert_eq!(text("a\x1b\x1b[2Jb", STRIP), "ab");
+        // Window titles and hyperlinks, ended by BEL or by ESC \
+        assert_eq!(text("a\x1b]0;pwned\x07b", STRIP), "ab");
+        assert_eq!(text("a\x1b]8;;https://evil.example\x1b\\link\x1b]8;;\x1b\\", STRIP), "alink");
+        assert_eq!(text("a\x1bPq#0;2;0;0;0\x1b\\b", STRIP), "ab");
+    }
+
+    #[test]
+    fn unterminated_sequences_leave_nothing_behind() {
+        assert_eq!(text("hi\x1b", STRIP), "hi");
+        assert_eq!(text("hi\x1b[", STRIP), "hi");
+        assert_eq!(text("a\x1b]0;title with no end", STRIP), "a");
+    }
+
+    #[test]
+    fn controls_and_bidi_overrides_are_dropped() {
+        assert_eq!(text("bell\x07\x08\x7f\u{85}", STRIP), "bell");
+        // "txt.exe" shown as "exe.txt" if the override acted
+        assert_eq!(text("invoice\u{202e}txt.exe", STRIP), "invoicetxt.exe");
+        assert_eq!(text("\u{2066}a\u{2069}\u{200f}b\u{061c}", STRIP), "ab");
+    }
+
+    #[test]
+    fn only_text_keeps_line_breaks() {
+        assert_eq!(text("one\ntwo\tthree\r", STRIP), "one\ntwo three");
+        assert_eq!(line("eve\nadmin: reset your key", STRIP, 100), "eve admin: reset your key");
+    }
+
+    #[test]
+    fn escaping_shows_what_was_sent() {
+        assert_eq!(text("a\x1b[2Jb", ESCAPE), "a\\x1b[2Jb");
+        assert_eq!(text("x\u{202e}y", ESCAPE), "x\\u{202e}y");
+        assert_eq!(line("a\nb\tc", ESCAPE, 100), "a\\nb\\tc");
+        assert_eq!(text("a\nb", ESCAPE), "a\nb");
+    }
+
+    #[test]
+    fn text_is_normalized() {
+        assert_eq!(text("e\u{301}", STRIP), "\u{e9}");
+    }
+
+    #[test]
+    fn lines_are_cut_by_columns() {
+        assert_eq!(line("alice", STRIP, 5), "alice");
+        assert_eq!(line("alice!", STRIP, 5), "alic…");
+        // Full-width letters take two columns each
+        assert_eq!(line("ａｂｃｄ", STRIP, 5), "ａｂ…");
+        assert_eq!(width(&line("ａｂｃｄ", STRIP, 5)), 5);
+        // The escape sequence is gone before the width is counted
+        assert_eq!(line("\x1b[31m\x1b[31m\x1b[31mbob", STRIP, 3), "bob");
+    }
+
+    #[test]
+    fn nothing_hostile_survives_stripping() {
+        let hostile = "\x1b\x1b[2J\x1b]0;x\x07\u{9b}1m\u{9d}2;y\u{9c}\u{202e}\u{2067}\x00".repeat(100);
+        let cleaned = text(&format!("start{}end", hostile), STRIP);
+        assert_eq!(cleaned, "startend");
+        assert!(!cleaned.chars().any(is_unsafe));
+    }
+}
@@ -0,0 +1,23 @@
+//! The scratch directory the crate's unit tests, the simulation harness
+//! and the storage conformance suite work in.
+
+use std::path::PathBuf;
+
+/// A fresh owner-only directory under the system temp dir, removed when
+/// dropped. `name` is in its path, to tell whose a leftover one was.
+pub(crate) struct Scratch(pub(crate) PathBuf);
+
+impl Scratch {
+    pub(crate) fn new(name: &str) -> Self {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        let path = std::env::temp_dir().join(format!("msgproto-{}-{}-{}", name, std::process::id(), nanos));
+        crate::secure_fs::create_private_dir(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
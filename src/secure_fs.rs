@@ -0,0 +1,251 @@
+use crate::output::eout;
+use anyhow::{Result, anyhow};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Command-line flag that turns permission refusals into warnings.
+pub const INSECURE_PERMISSIONS_FLAG: &str = "--insecure-permissions-ok";
+
+/// Write a file readable only by its owner (0600 on Unix), tightening the
+/// permissions of an existing file too.
+pub fn write_private(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    let path = path.as_ref();
+    // Created owner-only, so the contents are never readable by others;
+    // an existing file keeps its mode until it is restricted below
+    private_options().write(true).create(true).truncate(true).open(path)?.write_all(contents.as_ref())?;
+    restrict_file(path)
+}
+
+/// Like `write_private`, but the file is never left half written: the
+/// contents go to a temporary file beside it, synced to disk, then renamed
+/// over it. With `keep_backup`, the file being replaced stays as its
+/// `backup_path`, for one generation.
+pub fn write_private_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>, keep_backup: bool) -> Result<()> {
+    let path = path.as_ref();
+    let temp = sibling(path, "tmp");
+    let mut file = private_options().write(true).create(true).truncate(true).open(&temp)?;
+    restrict_file(&temp)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    drop(file);
+
+    if keep_backup && path.exists() {
+        let backup = backup_path(path);
+        match fs::remove_file(&backup) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        // A second name for the old file, which the rename leaves in place
+        if fs::hard_link(path, &backup).is_err() {
+            fs::copy(path, &backup)?;
+        }
+    }
+    fs::rename(&temp, path)?;
+    sync_parent(path)
+}
+
+/// Append to a file readable only by its owner, creating it if need be,
+/// and sync it to disk. If the write fails, the file is cut back to where
+/// it was, so it never ends in part of `contents`.
+pub fn append_private(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    let path = path.as_ref();
+    let created = !path.exists();
+    let mut file = private_options().create(true).append(true).open(path)?;
+    if created {
+        restrict_file(path)?;
+        sync_parent(path)?;
+    }
+    let before = file.metadata()?.len();
+    let written = file.write_all(contents.as_ref()).and_then(|_| file.sync_data());
+    if let Err(e) = written {
+        let _ = file.set_len(before);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Where `write_private_atomic` keeps the previous version of `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling(path, "bak")
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+    path.with_file_name(name)
+}
+
+/// Make a rename in `path`'s directory survive a crash.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    let parent = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    // std can't open a directory on Windows to sync it
+    Ok(())
+}
+
+/// Options that create a file owner-only (0600 on Unix) from the start.
+#[cfg(unix)]
+fn private_options() -> fs::OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut options = fs::OpenOptions::new();
+    options.mode(0o600);
+    options
+}
+
+#[cfg(not(unix))]
+fn private_options() -> fs::OpenOptions {
+    fs::OpenOptions::new()
+}
+
+/// Set owner-only permissions on an existing file, e.g. one created by SQLite.
+#[cfg(unix)]
+pub fn restrict_file(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn restrict_file(_path: &Path) -> Result<()> {
+    // Files under the user's profile inherit its owner-only ACL on Windows
+    Ok(())
+}
+
+/// Create a directory (and parents) accessible only by its owner.
+#[cfg(unix)]
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+/// Refuse a secret file or directory that others can read, naming the fix.
+/// With `allow_insecure` it only warns. Missing paths are fine.
+#[cfg(unix)]
+pub fn check_private(path: &Path, allow_insecure: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let Ok(metadata) = fs::metadata(path) else { return Ok(()) };
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+
+    let fix = if metadata.is_dir() { "700" } else { "600" };
+    let problem = format!("{} is accessible by group or others (mode {:o}); run 'chmod {} {}'",
+        path.display(), mode, fix, path.display());
+    if allow_insecure {
+        eout!("⚠️ Warning: {}", problem);
+        Ok(())
+    } else {
+        Err(anyhow!("{}, or pass {} to continue anyway", problem, INSECURE_PERMISSIONS_FLAG))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn check_private(_path: &Path, _allow_insecure: bool) -> Result<()> {
+    // Windows ACLs don't map onto mode bits; rely on the profile directory's ACL
+    Ok(())
+}
+
+/// `check_private` for a directory and every file directly inside it.
+pub fn check_private_tree(dir: &Path, allow_insecure: bool) -> Result<()> {
+    check_private(dir, allow_insecure)?;
+    let Ok(entries) = fs::read_dir(dir) else { return Ok(()) };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() {
+            check_private(&path, allow_insecure)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn chmod(path: &Path, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn a_key_file_others_can_read_is_refused() {
+        let scratch = Scratch::new("secure-fs-key");
+        let key = scratch.0.join("alice.key");
+        write_private(&key, "secret").unwrap();
+        check_private(&key, false).unwrap();
+
+        chmod(&key, 0o644);
+        let refusal = check_private(&key, false).unwrap_err().to_string();
+        assert!(refusal.contains("mode 644") && refusal.contains("chmod 600"), "{}", refusal);
+        assert!(refusal.contains(INSECURE_PERMISSIONS_FLAG), "{}", refusal);
+        // The flag lets it through with a warning, and leaves the mode alone
+        check_private(&key, true).unwrap();
+        assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o644);
+    }
+
+    #[test]
+    fn a_tree_is_refused_for_its_directory_or_any_file_in_it() {
+        let scratch = Scratch::new("secure-fs-tree");
+        let data = scratch.0.join("data");
+        create_private_dir(&data).unwrap();
+        write_private(data.join("clients.json"), "{}").unwrap();
+        check_private_tree(&data, false).unwrap();
+
+        chmod(&data.join("clients.json"), 0o640);
+        assert!(check_private_tree(&data, false).unwrap_err().to_string().contains("clients.json"));
+        check_private_tree(&data, true).unwrap();
+
+        chmod(&data.join("clients.json"), 0o600);
+        chmod(&data, 0o755);
+        assert!(check_private_tree(&data, false).unwrap_err().to_string().contains("chmod 700"));
+        check_private_tree(&data, true).unwrap();
+    }
+
+    #[test]
+    fn missing_paths_pass_and_writes_are_owner_only() {
+        let scratch = Scratch::new("secure-fs-writes");
+        check_private(&scratch.0.join("not there"), false).unwrap();
+
+        let file = scratch.0.join("config.json");
+        fs::write(&file, "old").unwrap();
+        chmod(&file, 0o644);
+        write_private_atomic(&file, "new", true).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert_eq!(fs::read_to_string(backup_path(&file)).unwrap(), "old");
+        check_private(&file, false).unwrap();
+    }
+
+    #[test]
+    fn files_are_created_owner_only() {
+        let scratch = Scratch::new("secure-fs-create");
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        // Owner-only from the moment they exist, not once restricted after
+        let fresh = scratch.0.join("fresh");
+        drop(private_options().write(true).create_new(true).open(&fresh).unwrap());
+        assert_eq!(mode(&fresh), 0o600);
+
+        let key = scratch.0.join("alice.key");
+        crate::crypto::CryptoManager::new().save_to_file(&key).unwrap();
+        assert_eq!(mode(&key), 0o600);
+        let log = scratch.0.join("audit.log");
+        append_private(&log, "line\n").unwrap();
+        assert_eq!(mode(&log), 0o600);
+    }
+}
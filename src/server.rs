@@ -1,197 +1,2786 @@
-mod types;
-mod crypto;
-mod storage;
-
-use crate::types::{ServerCommand, ServerResponse, Message};
+use crate::{alerts, auth, check, confusables, crypto, features, frame, integrity, metrics, output, pairlimit, redact, replication, secure_fs, transport};
+use crate::types::{ServerCommand, ServerResponse, ack_payload, challenge_payload, Delegation, DelegationAudit, GuestLink, GuestOrigin, Hlc, IntegrityProgress, error_code, Message, Registration, SystemNotice, EXPIRED_UNDELIVERED, INVITE_UNCLAIMED, notice_type, guest_link_payload, guest_link_ref_payload, key_directory_payload, message_ref_payload, receipt_payload, report_payload, retention_payload, send_payload, sender_key_payload, user_data_payload, user_data_ref_payload, client_details_payload, invite_code_payload, promote_payload, debug_dump_payload, admin_batch_payload, presence_payload, ClientInfo, PresenceEntry, SenderKey, AdminAction, AdminActionResult, Ban, delegation_payload, delegation_ref_payload, delegations_payload, usage_payload, check_message_id, new_message_id, group_payload, check_group_name, Group, GroupRole};
 use crate::crypto::CryptoManager;
-use crate::storage::Storage;
+use crate::backend::{self, StorageKind};
+use crate::storage::{BatchOp, DuplicateMessageId, ReplayedMessageId, Storage, StorageUnavailable, UserDataWrite};
+use crate::keycache::KeyCache;
+use crate::pairlimit::{PairLimiter, PairVerdict};
+use crate::connections::{ConnectionGuard, ConnectionRegistry, panic_message};
+use crate::metrics::{Metrics, Phase};
+use crate::redact::Redaction;
+use crate::alerts::{AlertConfig, AlertKind, Alerts, Sink};
+use crate::auth::{AuthDecision, AuthProvider, Challenges};
+use crate::features::{Feature, FeatureSet, NegotiatedFeatures};
+use crate::rotate::{LogWriter, RotatingLog, RotationPolicy};
+use crate::frame::FrameError;
+use crate::server_config::ServerConfigFile;
+use crate::replication::{Secret, Standby};
+use crate::replay::SeenIds;
+use crate::instrument::SweepStats;
+use crate::output::{eout, note, out, OutputMode};
 use ed25519_dalek::{PublicKey, Signature};
-use hex;
-use serde_json;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
-};
+use futures::{FutureExt, StreamExt};
+use std::collections::{BTreeSet, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
 use anyhow::{Result, anyhow};
-use log::{error, info};
+use clap::Parser;
+use serde::Deserialize;
+use log::{debug, error, info, warn};
+
+const DATA_DIR: &str = "./data";
+/// The server's hash-chained record of admin and delegation actions, in DATA_DIR.
+const AUDIT_LOG: &str = "audit.log";
+/// The server's own keys, in DATA_DIR. Kept so clients can recognize the
+/// server across restarts.
+const SERVER_KEYS: &str = "server_keys";
+const DEFAULT_SLOW_REQUEST_MS: u64 = 500;
+const DELIVERY_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+/// Recipients are warned once their mailbox reaches this share of the quota.
+const QUOTA_WARNING_PERCENT: usize = 80;
+/// How long messages for an unregistered id are held in invite mode.
+const DEFAULT_INVITE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Furthest a send's `sent_at` may be from the server's clock.
+const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// Most held invite messages one sender may have outstanding.
+const DEFAULT_INVITE_CAP: usize = 20;
+/// Most messages held for any one unregistered id.
+const MAX_INVITES_PER_RECIPIENT: usize = 50;
+/// Longest a guest link may stay open.
+const MAX_GUEST_LINK_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Most messages one guest link may take.
+const MAX_GUEST_LINK_MESSAGES: u32 = 100;
+/// Most usable guest links one client may have at a time.
+const MAX_GUEST_LINKS: usize = 20;
+/// Largest guest message, in bytes of content as sent.
+const MAX_GUEST_MESSAGE_BYTES: usize = 16 * 1024;
+/// How long expired guest links stay listed before they're forgotten.
+const GUEST_LINK_KEEP: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Longest user data key, in bytes.
+const MAX_USER_DATA_KEY_BYTES: usize = 128;
+/// Largest user data value, in bytes of ciphertext.
+const MAX_USER_DATA_VALUE_BYTES: usize = 16 * 1024;
+/// Most user data, keys and values together, one client may keep.
+const USER_DATA_QUOTA_BYTES: usize = 256 * 1024;
+/// Shortest invite code an admin may make; they stand in for a password.
+const MIN_INVITE_CODE_LEN: usize = 12;
+/// Most actions in one admin batch.
+const MAX_BATCH_ACTIONS: usize = 100;
+/// Most contacts one presence request can name.
+const MAX_PRESENCE_CONTACTS: usize = 1000;
+/// Longest a mailbox delegation may last.
+const MAX_DELEGATION_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// How far ahead of the server's clock a delegation may say it was issued.
+const DELEGATION_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// Most members a group can have.
+const MAX_GROUP_MEMBERS: usize = 256;
+/// Where clients connect unless `--bind` says otherwise.
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+/// Room in a frame for a message's fields besides its content.
+const FRAME_OVERHEAD_BYTES: usize = 16 * 1024;
+/// How long shutdown waits for open connections to finish.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// Longest an admin can let a drain run.
+const MAX_DRAIN_GRACE_SECS: u64 = 3600;
+/// What `server` exits with after a drain, so a supervisor can tell it
+/// apart from a crash or an ordinary stop and start the next version.
+pub const DRAINED_EXIT_CODE: i32 = 75;
+
+/// Why `run_until` returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stopped {
+    /// The shutdown future completed.
+    Shutdown,
+    /// An admin drained the server.
+    Drained,
+}
+/// How long a write's answer waits for a connected standby to apply it.
+const STANDBY_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What Send does with a message for an id nobody has registered.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UnknownRecipients {
+    /// Refuse it.
+    Reject,
+    /// Store it like any other; whoever registers the id later gets it.
+    Accept,
+    /// Hold it for a limited time as an invitation, and tell the sender it's pending.
+    Invite,
+}
+
+impl std::str::FromStr for UnknownRecipients {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(UnknownRecipients::Reject),
+            "accept" => Ok(UnknownRecipients::Accept),
+            "invite" => Ok(UnknownRecipients::Invite),
+            other => Err(anyhow!("unknown policy '{}'; use reject, accept or invite", other)),
+        }
+    }
+}
+
+/// What registration records keep of the address a client registered from.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SourceAddresses {
+    /// The IP address as seen.
+    Keep,
+    /// A salted hash, enough to tell whether two registrations came from the
+    /// same address.
+    Hash,
+    /// Nothing.
+    Drop,
+}
+
+impl std::str::FromStr for SourceAddresses {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(SourceAddresses::Keep),
+            "hash" => Ok(SourceAddresses::Hash),
+            "drop" => Ok(SourceAddresses::Drop),
+            other => Err(anyhow!("unknown policy '{}'; use keep, hash or drop", other)),
+        }
+    }
+}
+
+/// How the debug dump shows client ids and connection addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DebugDumpIds {
+    /// Salted hashes, enough to tell connections from the same client apart
+    /// from the rest.
+    Hash,
+    /// As they are.
+    Full,
+}
+
+impl std::str::FromStr for DebugDumpIds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hash" => Ok(DebugDumpIds::Hash),
+            "full" => Ok(DebugDumpIds::Full),
+            other => Err(anyhow!("unknown policy '{}'; use hash or full", other)),
+        }
+    }
+}
+
+/// How a [`Server`] behaves, normally from its command line.
+pub struct ServerOptions {
+    /// Where storage, the server's keys and the audit log live.
+    pub data_dir: PathBuf,
+    /// Where clients connect.
+    pub bind: String,
+    /// Which backend keeps mailboxes and clients.
+    pub storage: StorageKind,
+    slow_request_threshold: Duration,
+    /// Largest request or response frame, in bytes.
+    max_frame_bytes: usize,
+    /// Largest message ciphertext a client may send, in bytes; only the
+    /// frame limit applies if unset.
+    max_message_size: Option<usize>,
+    /// Most client messages a mailbox may hold; unlimited if unset.
+    mailbox_quota: Option<usize>,
+    /// Data size in bytes at which admins are warned.
+    disk_warning_bytes: Option<u64>,
+    /// Data size in bytes beyond which new messages are refused.
+    disk_limit_bytes: Option<u64>,
+    /// Identities that receive operator notices.
+    admins: Vec<String>,
+    /// Messages per minute, and burst size, one sender may send one recipient.
+    pair_rate_per_min: f64,
+    pair_burst: f64,
+    /// How long a spam report mutes the sender towards the reporter.
+    report_mute: Duration,
+    unknown_recipients: UnknownRecipients,
+    invite_ttl: Duration,
+    /// Most held invite messages one sender may have outstanding.
+    invite_cap: usize,
+    /// Furthest a send's `sent_at` may be from the server's clock.
+    max_clock_skew: Duration,
+    /// Re-verify stored message signatures in the background after startup.
+    verify_messages: bool,
+    /// Refuse new ids that look the same as a registered one.
+    reject_confusable_ids: bool,
+    redaction: Redaction,
+    source_addresses: SourceAddresses,
+    alerts: AlertConfig,
+    /// Optional features offered to clients.
+    features: FeatureSet,
+    /// Who may register new ids.
+    auth: Box<dyn AuthProvider>,
+    /// When the audit log is rotated.
+    audit_rotation: RotationPolicy,
+    replication: ReplicationOptions,
+    debug_dump_ids: DebugDumpIds,
+}
+
+/// The server's command line. Settings a config file can also hold are
+/// left unset when not given, so the file's value applies.
+#[derive(Debug, Parser)]
+#[command(name = "server", about = "The secure messaging server")]
+struct ServerArgs {
+    /// TOML file of settings; a flag wins over the same key in it
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Address clients connect to [default: 127.0.0.1:8080]
+    #[arg(long, visible_alias = "listen", value_name = "ADDR")]
+    bind: Option<String>,
+    /// Where storage, the server's keys and the audit log live [default: ./data]
+    #[arg(long, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+    /// Backend for mailboxes and clients: json, sqlite or redb
+    #[arg(long)]
+    storage: Option<StorageKind>,
+    /// Copy the clients and mailboxes of the JSON storage in this data
+    /// directory into --storage in --data-dir, and exit
+    #[arg(long, value_name = "PATH", conflicts_with = "check_data")]
+    import_json: Option<PathBuf>,
+    /// Check the data directory and exit
+    #[arg(long)]
+    check_data: bool,
+    /// With --check-data, fix what it can
+    #[arg(long, requires = "check_data")]
+    repair: bool,
+    /// Carry on when data or secret files are readable by others
+    #[arg(long)]
+    insecure_permissions_ok: bool,
+    /// rich, plain or quiet
+    #[arg(long, value_name = "MODE")]
+    output: Option<OutputMode>,
+
+    /// Largest message ciphertext a client may send, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_message_size: Option<usize>,
+    /// Largest request or response frame, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_frame_bytes: Option<usize>,
+    /// Most client messages a mailbox may hold
+    #[arg(long, value_name = "MESSAGES")]
+    mailbox_quota: Option<usize>,
+    /// Data size at which admins are warned
+    #[arg(long, value_name = "BYTES")]
+    disk_warning_bytes: Option<u64>,
+    /// Data size beyond which new messages are refused
+    #[arg(long, value_name = "BYTES")]
+    disk_limit_bytes: Option<u64>,
+    /// An identity that receives operator notices; may be repeated
+    #[arg(long = "admin", value_name = "ID")]
+    admins: Vec<String>,
+
+    /// Furthest a send's signed time may be from the server's clock [default: 300]
+    #[arg(long, value_name = "SECS")]
+    max_clock_skew_secs: Option<u64>,
+    /// Who may register new ids: open, invite-code or allow-list [default: open]
+    #[arg(long, value_name = "POLICY")]
+    registration: Option<String>,
+    /// File of ids that may register under --registration allow-list
+    #[arg(long, value_name = "PATH")]
+    allow_list: Option<String>,
+    /// Refuse new ids that look the same as a registered one
+    #[arg(long)]
+    reject_confusable_ids: bool,
+    /// Turn off an optional feature; may be repeated
+    #[arg(long = "disable-feature", value_name = "FEATURE")]
+    disabled_features: Vec<String>,
+
+    /// Messages per minute one sender may send one recipient [default: 20]
+    #[arg(long)]
+    pair_rate: Option<f64>,
+    /// Messages one sender may send one recipient in a burst [default: 10]
+    #[arg(long)]
+    pair_burst: Option<f64>,
+    /// How long a spam report mutes the sender towards the reporter [default: 3600]
+    #[arg(long, value_name = "SECS")]
+    report_mute_secs: Option<u64>,
+    /// What to do with messages for unregistered ids: reject, accept or invite [default: accept]
+    #[arg(long, value_name = "POLICY")]
+    unknown_recipients: Option<UnknownRecipients>,
+    /// How long an invitation is held [default: 604800]
+    #[arg(long, value_name = "SECS")]
+    invite_ttl_secs: Option<u64>,
+    /// Most held invitations one sender may have outstanding [default: 20]
+    #[arg(long)]
+    invite_cap: Option<usize>,
+
+    /// Re-verify stored message signatures in the background after startup
+    #[arg(long)]
+    verify_messages: bool,
+    /// Requests slower than this are logged [default: 500]
+    #[arg(long, value_name = "MS")]
+    slow_ms: Option<u64>,
+    /// What registrations keep of the client's address: keep, hash or drop [default: hash]
+    #[arg(long, value_name = "POLICY")]
+    source_addresses: Option<SourceAddresses>,
+    /// How the debug dump shows ids and addresses: hash or full [default: hash]
+    #[arg(long, value_name = "POLICY")]
+    debug_dump_ids: Option<DebugDumpIds>,
+    /// Longest excerpt of a peer's payload that reaches the logs [default: 200]
+    #[arg(long, value_name = "CHARS")]
+    log_excerpt: Option<usize>,
+    /// Log whole payloads; debug builds only
+    #[arg(long)]
+    log_full_payloads: bool,
 
-struct Server {
-    crypto: CryptoManager,
-    storage: Storage,
-    active_connections: Arc<Mutex<HashMap<String, tokio::net::TcpStream>>>,
+    /// Rotate the audit log at this size, 0 for never [default: 16 MiB]
+    #[arg(long, value_name = "BYTES")]
+    audit_log_max_bytes: Option<u64>,
+    /// Rotate the audit log at this age
+    #[arg(long, value_name = "SECS")]
+    audit_log_max_age_secs: Option<u64>,
+    /// Rotated audit logs to keep
+    #[arg(long, value_name = "FILES")]
+    audit_log_keep: Option<usize>,
+
+    /// Post signed alerts to this URL
+    #[arg(long, value_name = "URL", conflicts_with = "alert_command")]
+    alert_webhook: Option<String>,
+    /// The secret --alert-webhook signs with
+    #[arg(long, value_name = "PATH")]
+    alert_secret_file: Option<String>,
+    /// Run this program, with its arguments, for each alert
+    #[arg(long, value_name = "COMMAND")]
+    alert_command: Option<String>,
+    /// Alert types to send, comma separated [default: all]
+    #[arg(long, value_name = "TYPES")]
+    alerts: Option<String>,
+    /// How long a repeated alert is held back [default: 300]
+    #[arg(long, value_name = "SECS")]
+    alert_dedup_secs: Option<u64>,
+
+    /// Take standbys on this address; a write is answered once a standby has it
+    #[arg(long, value_name = "ADDR")]
+    replicate_on: Option<String>,
+    /// Follow this primary, read-only until promoted
+    #[arg(long, value_name = "ADDR")]
+    standby_of: Option<String>,
+    /// The secret primaries and standbys share
+    #[arg(long, value_name = "PATH")]
+    replication_secret_file: Option<String>,
+}
+
+impl ServerArgs {
+    /// Fill in what the flags left unset from the file named with
+    /// `--config`, if any.
+    fn with_config(mut self) -> Result<Self> {
+        let Some(path) = &self.config else {
+            return Ok(self);
+        };
+        let file = ServerConfigFile::load(path)?;
+        fn fill<T>(flag: &mut Option<T>, key: Option<T>) {
+            if flag.is_none() {
+                *flag = key;
+            }
+        }
+        fill(&mut self.bind, file.bind);
+        fill(&mut self.data_dir, file.data_dir);
+        fill(&mut self.storage, file.storage);
+        fill(&mut self.output, file.output);
+        fill(&mut self.max_message_size, file.max_message_size);
+        fill(&mut self.max_frame_bytes, file.max_frame_bytes);
+        fill(&mut self.mailbox_quota, file.mailbox_quota);
+        fill(&mut self.disk_warning_bytes, file.disk_warning_bytes);
+        fill(&mut self.disk_limit_bytes, file.disk_limit_bytes);
+        fill(&mut self.max_clock_skew_secs, file.max_clock_skew_secs);
+        fill(&mut self.registration, file.registration);
+        fill(&mut self.allow_list, file.allow_list);
+        fill(&mut self.pair_rate, file.pair_rate);
+        fill(&mut self.pair_burst, file.pair_burst);
+        fill(&mut self.report_mute_secs, file.report_mute_secs);
+        fill(&mut self.unknown_recipients, file.unknown_recipients);
+        fill(&mut self.invite_ttl_secs, file.invite_ttl_secs);
+        fill(&mut self.invite_cap, file.invite_cap);
+        fill(&mut self.slow_ms, file.slow_ms);
+        fill(&mut self.source_addresses, file.source_addresses);
+        fill(&mut self.debug_dump_ids, file.debug_dump_ids);
+        fill(&mut self.log_excerpt, file.log_excerpt);
+        fill(&mut self.audit_log_max_bytes, file.audit_log_max_bytes);
+        fill(&mut self.audit_log_max_age_secs, file.audit_log_max_age_secs);
+        fill(&mut self.audit_log_keep, file.audit_log_keep);
+        fill(&mut self.alert_webhook, file.alert_webhook);
+        fill(&mut self.alert_secret_file, file.alert_secret_file);
+        fill(&mut self.alert_command, file.alert_command);
+        fill(&mut self.alerts, file.alerts);
+        fill(&mut self.alert_dedup_secs, file.alert_dedup_secs);
+        fill(&mut self.replicate_on, file.replicate_on);
+        fill(&mut self.standby_of, file.standby_of);
+        fill(&mut self.replication_secret_file, file.replication_secret_file);
+        // Switches can only be turned on from the command line, so an
+        // absent one leaves the file's setting
+        self.insecure_permissions_ok |= file.insecure_permissions_ok.unwrap_or(false);
+        self.reject_confusable_ids |= file.reject_confusable_ids.unwrap_or(false);
+        self.verify_messages |= file.verify_messages.unwrap_or(false);
+        self.log_full_payloads |= file.log_full_payloads.unwrap_or(false);
+        // Repeated flags replace the file's list rather than add to it
+        if self.admins.is_empty() {
+            self.admins = file.admins.unwrap_or_default();
+        }
+        if self.disabled_features.is_empty() {
+            self.disabled_features = file.disabled_features.unwrap_or_default();
+        }
+        Ok(self)
+    }
+}
+
+impl ServerOptions {
+    /// The options set by the command line's flags, then by the file
+    /// named with `--config`, the rest defaulted. `args[0]` is the
+    /// program name. Unknown flags and `--help` are errors here;
+    /// [`main`] prints them.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        Self::from_flags(ServerArgs::try_parse_from(args)?.with_config()?)
+    }
+
+    /// The options from flags already filled in by `with_config`.
+    fn from_flags(flags: ServerArgs) -> Result<Self> {
+        // Messages travel hex-encoded, so a frame must hold twice their size
+        let max_message_size = flags.max_message_size;
+        let needed_frame = max_message_size.map(|size: usize| size.saturating_mul(2).saturating_add(FRAME_OVERHEAD_BYTES));
+        let max_frame_bytes = match (flags.max_frame_bytes, needed_frame) {
+            (Some(frame), Some(needed)) if frame < needed => {
+                return Err(anyhow!("A max frame size of {} bytes can't carry a {}-byte message; it needs at least {}",
+                    frame, max_message_size.unwrap_or_default(), needed));
+            }
+            (Some(frame), _) => frame,
+            (None, needed) => needed.map_or(frame::DEFAULT_MAX_FRAME_BYTES, |needed| needed.max(frame::DEFAULT_MAX_FRAME_BYTES)),
+        };
+    
+        // Full payloads are for development only; release builds ignore the flag
+        if flags.log_full_payloads && !cfg!(debug_assertions) {
+            eout!("⚠️ --log-full-payloads only works in debug builds; logs stay redacted");
+        }
+        let full_payloads = flags.log_full_payloads && cfg!(debug_assertions);
+
+        Ok(ServerOptions {
+            data_dir: flags.data_dir.clone().unwrap_or_else(|| PathBuf::from(DATA_DIR)),
+            bind: flags.bind.clone().unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string()),
+            storage: flags.storage.unwrap_or_default(),
+            slow_request_threshold: Duration::from_millis(flags.slow_ms.unwrap_or(DEFAULT_SLOW_REQUEST_MS)),
+            max_frame_bytes,
+            max_message_size,
+            mailbox_quota: flags.mailbox_quota,
+            disk_warning_bytes: flags.disk_warning_bytes,
+            disk_limit_bytes: flags.disk_limit_bytes,
+            admins: flags.admins.clone(),
+            pair_rate_per_min: flags.pair_rate.unwrap_or(pairlimit::DEFAULT_PAIR_RATE_PER_MIN),
+            pair_burst: flags.pair_burst.unwrap_or(pairlimit::DEFAULT_PAIR_BURST),
+            report_mute: flags.report_mute_secs.map(Duration::from_secs).unwrap_or(pairlimit::DEFAULT_REPORT_MUTE),
+            unknown_recipients: flags.unknown_recipients.unwrap_or(UnknownRecipients::Accept),
+            invite_ttl: flags.invite_ttl_secs.map(Duration::from_secs).unwrap_or(DEFAULT_INVITE_TTL),
+            invite_cap: flags.invite_cap.unwrap_or(DEFAULT_INVITE_CAP),
+            max_clock_skew: flags.max_clock_skew_secs.map(Duration::from_secs).unwrap_or(DEFAULT_MAX_CLOCK_SKEW),
+            verify_messages: flags.verify_messages,
+            reject_confusable_ids: flags.reject_confusable_ids,
+            source_addresses: flags.source_addresses.unwrap_or(SourceAddresses::Hash),
+            debug_dump_ids: flags.debug_dump_ids.unwrap_or(DebugDumpIds::Hash),
+            alerts: alert_config(&flags)?,
+            audit_rotation: audit_rotation(&flags),
+            replication: replication_options(&flags)?,
+            features: enabled_features(&flags.disabled_features)?,
+            auth: auth::provider(flags.registration.as_deref().unwrap_or("open"), flags.allow_list.as_deref())?,
+            redaction: Redaction {
+                excerpt_len: flags.log_excerpt.unwrap_or(redact::DEFAULT_LOG_EXCERPT),
+                full_payloads,
+            },
+        })
+    }
+}
+
+/// How this server takes part in warm standby replication, if at all.
+struct ReplicationOptions {
+    /// Where standbys may connect to follow this server.
+    listen: Option<String>,
+    /// The primary this server follows, read-only until promoted.
+    standby_of: Option<String>,
+    secret: Option<Secret>,
+}
+
+/// The messaging server: storage, the server's keys and the policies from
+/// its [`ServerOptions`], serving clients once [`Server::run`] is called.
+#[derive(Clone)]
+pub struct Server {
+    crypto: Arc<CryptoManager>,
+    max_frame_bytes: usize,
+    max_message_size: Option<usize>,
+    mailbox_quota: Option<usize>,
+    disk_warning_bytes: Option<u64>,
+    disk_limit_bytes: Option<u64>,
+    admins: Arc<Vec<String>>,
+    unknown_recipients: UnknownRecipients,
+    invite_ttl: Duration,
+    invite_cap: usize,
+    max_clock_skew: Duration,
+    reject_confusable_ids: bool,
+    // Whether usage was over the warning size at the last sweep
+    disk_warned: Arc<AtomicBool>,
+    storage: Arc<Storage>,
+    key_cache: Arc<KeyCache>,
+    pair_limiter: Arc<PairLimiter>,
+    metrics: Arc<Metrics>,
+    // Progress of the stored-message signature check, when enabled
+    integrity: Option<Arc<Mutex<IntegrityProgress>>>,
+    connections: Arc<ConnectionRegistry>,
+    // How much of what peers send may reach the logs
+    redaction: Redaction,
+    source_addresses: SourceAddresses,
+    // Since when storage writes have been failing, while they are
+    storage_degraded: Arc<Mutex<Option<chrono::DateTime<chrono::Utc>>>>,
+    alerts: Arc<Alerts>,
+    features: FeatureSet,
+    auth: Arc<dyn AuthProvider>,
+    // Nonces awaiting a signature before Register or GetMessages acts
+    challenges: Arc<Challenges>,
+    // Signatures on time-stamped requests, so each is acted on once
+    seen_requests: Arc<SeenIds>,
+    audit: Arc<LogWriter>,
+    // Following a primary, or was until promoted
+    standby: Option<Arc<Standby>>,
+    // Where standbys connect to follow this server
+    replication_listen: Option<String>,
+    // Proves each end of a replication connection to the other
+    replication_secret: Option<Secret>,
+    // How the background sweeps went, for the debug dump
+    sweeps: Arc<Mutex<SweepStats>>,
+    // Whether the debug dump shows client ids and addresses as they are
+    debug_dump_ids: DebugDumpIds,
+}
+
+/// One line of the audit log.
+#[derive(serde::Serialize)]
+struct AuditRecord<'a> {
+    at: chrono::DateTime<chrono::Utc>,
+    actor: &'a str,
+    action: &'a str,
+    subject: &'a str,
+    allowed: bool,
+    /// The actions of an admin batch, which is logged as one entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actions: Option<&'a [AdminAction]>,
 }
 
 impl Server {
-    fn new() -> Result<Self> {
-        let crypto = CryptoManager::new();
-        let storage = Storage::new("./data")?;
+    pub async fn new(options: ServerOptions) -> Result<Self> {
+        let storage = Storage::new(&options.data_dir.to_string_lossy(), options.storage).await?;
+        Self::with_storage(options, storage).await
+    }
+
+    /// A server over storage the caller opened in `options.data_dir`, such as
+    /// on a backend of its own; `options.storage` goes unused.
+    pub async fn with_storage(options: ServerOptions, mut storage: Storage) -> Result<Self> {
+        let crypto = Arc::new(load_server_keys(&options.data_dir.join(SERVER_KEYS))?);
+        let replication = options.replication;
+        if replication.listen.is_some() {
+            storage.record_changes();
+        }
+        let storage = Arc::new(storage);
+        let audit = RotatingLog::open(&options.data_dir, AUDIT_LOG, options.audit_rotation)?;
         
         Ok(Server {
             crypto,
+            max_frame_bytes: options.max_frame_bytes,
+            max_message_size: options.max_message_size,
+            mailbox_quota: options.mailbox_quota,
+            disk_warning_bytes: options.disk_warning_bytes,
+            disk_limit_bytes: options.disk_limit_bytes,
+            admins: Arc::new(options.admins),
+            unknown_recipients: options.unknown_recipients,
+            invite_ttl: options.invite_ttl,
+            invite_cap: options.invite_cap,
+            max_clock_skew: options.max_clock_skew,
+            reject_confusable_ids: options.reject_confusable_ids,
+            disk_warned: Arc::new(AtomicBool::new(false)),
             storage,
-            active_connections: Arc::new(Mutex::new(HashMap::new())),
+            key_cache: Arc::new(KeyCache::new()),
+            pair_limiter: Arc::new(PairLimiter::new(options.pair_rate_per_min, options.pair_burst, options.report_mute)),
+            metrics: Arc::new(Metrics::new(options.slow_request_threshold)),
+            integrity: options.verify_messages.then(|| Arc::new(Mutex::new(IntegrityProgress::default()))),
+            connections: Arc::new(ConnectionRegistry::new()),
+            redaction: options.redaction,
+            source_addresses: options.source_addresses,
+            storage_degraded: Arc::new(Mutex::new(None)),
+            alerts: Arc::new(Alerts::start(options.alerts)),
+            features: options.features,
+            auth: Arc::from(options.auth),
+            challenges: Arc::new(Challenges::default()),
+            seen_requests: Arc::new(SeenIds::default()),
+            audit: Arc::new(LogWriter::start(AUDIT_LOG, audit)),
+            standby: replication.standby_of.map(|primary| Arc::new(Standby::new(primary))),
+            replication_listen: replication.listen,
+            replication_secret: replication.secret,
+            sweeps: Arc::new(Mutex::new(SweepStats::default())),
+            debug_dump_ids: options.debug_dump_ids,
         })
     }
 
-    async fn run(&self, addr: &str) -> Result<()> {
+    /// Whether this is a standby that hasn't been promoted yet.
+    fn is_read_only(&self) -> bool {
+        self.standby.as_ref().is_some_and(|standby| standby.is_read_only())
+    }
+
+    /// Serve clients on `addr` until interrupted by Ctrl-C, or SIGTERM on
+    /// Unix, or drained.
+    pub async fn run(&self, addr: &str) -> Result<Stopped> {
+        self.run_until(addr, shutdown_requested()).await
+    }
+
+    /// Serve clients on `addr` until `shutdown` completes or an admin drains
+    /// the server. Then stop taking connections, give open ones
+    /// `SHUTDOWN_GRACE` to finish the request in hand, and save messages and
+    /// clients a last time. A drain first tells open connections with a
+    /// GoAway frame and serves them until they close or its deadline.
+    pub async fn run_until(&self, addr: &str, shutdown: impl std::future::Future<Output = ()>) -> Result<Stopped> {
         let listener = TcpListener::bind(addr).await?;
-        println!("🚀 Secure messaging server listening on {}", addr);
-        println!("📊 Server public key: {}", hex::encode(self.crypto.get_ed25519_public_key().as_bytes()));
+        out!("🚀 Secure messaging server listening on {}", addr);
+        self.serve_until(transport::accept(listener), shutdown).await
+    }
+
+    /// `run_until`, taking connections from `incoming` instead of a TCP
+    /// listener; dropping it stops them.
+    pub async fn serve_until(&self, incoming: transport::Incoming, shutdown: impl std::future::Future<Output = ()>) -> Result<Stopped> {
+        let mut incoming = incoming;
+        out!("📊 Server public key: {}", hex::encode(self.crypto.get_ed25519_public_key().as_bytes()));
+        out!("🔏 Server fingerprint: {}", crypto::fingerprint(self.crypto.get_ed25519_public_key().as_bytes()));
+        // Replication outlives the connections, so a standby gets every write
+        // they were answered for; it stops when this is dropped on the way out
+        let _replicating = self.start_replication()?;
+        // One copy shared by the sweeper, the integrity check and every connection
+        let shared = Arc::new(self.clone());
+
+        // Bounce messages nobody fetched in time, even if the recipient never polls,
+        // compact mailboxes, and keep an eye on disk usage
+        let sweeper = Arc::clone(&shared);
+        let mut stop_sweeping = self.connections.shutdown_signal();
+        let sweeps = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DELIVERY_SWEEP_INTERVAL);
+            loop {
+                // A sweep under way finishes before shutdown saves storage
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = stop_sweeping.changed() => break,
+                }
+                // A standby's data is the primary's; it sweeps once promoted
+                if sweeper.is_read_only() {
+                    sweeper.sweeps.lock().unwrap_or_else(|e| e.into_inner()).skipped += 1;
+                    continue;
+                }
+                // The sweeps write too, so while storage is failing only probe it
+                if !sweeper.probe_storage().await {
+                    sweeper.sweeps.lock().unwrap_or_else(|e| e.into_inner()).skipped += 1;
+                    continue;
+                }
+                let (started_at, started) = (chrono::Utc::now(), Instant::now());
+                let mut errors = Vec::new();
+                let bounced = sweeper.bounce_undelivered().await.unwrap_or_else(|e| {
+                    sweeper.storage_failed(&e);
+                    error!("❌ Delivery sweep failed: {}", e);
+                    errors.push(e.to_string());
+                    0
+                });
+                let expired_invites = sweeper.expire_invites().await.unwrap_or_else(|e| {
+                    sweeper.storage_failed(&e);
+                    error!("❌ Invite sweep failed: {}", e);
+                    errors.push(e.to_string());
+                    0
+                });
+                let pruned_guest_links = sweeper.prune_guest_links().await.unwrap_or_else(|e| {
+                    sweeper.storage_failed(&e);
+                    error!("❌ Guest link sweep failed: {}", e);
+                    errors.push(e.to_string());
+                    0
+                });
+                // Compaction only drops what's already gone, so a failure leaves storage usable
+                let compacted_mailboxes = sweeper.storage.compact_mailboxes().await.unwrap_or_else(|e| {
+                    error!("❌ Mailbox compaction failed: {}", e);
+                    errors.push(e.to_string());
+                    0
+                });
+                if let Err(e) = sweeper.check_disk_usage().await {
+                    error!("❌ Disk usage check failed: {}", e);
+                    errors.push(e.to_string());
+                }
+                let mut sweeps = sweeper.sweeps.lock().unwrap_or_else(|e| e.into_inner());
+                sweeps.runs += 1;
+                sweeps.last_started = Some(started_at);
+                sweeps.last_ms = started.elapsed().as_secs_f64() * 1000.0;
+                sweeps.bounced = bounced;
+                sweeps.expired_invites = expired_invites;
+                sweeps.pruned_guest_links = pruned_guest_links;
+                sweeps.compacted_mailboxes = compacted_mailboxes;
+                sweeps.last_error = (!errors.is_empty()).then(|| errors.join("; "));
+            }
+        });
 
-        loop {
-            let (socket, addr) = listener.accept().await?;
-            println!("📱 New connection from {}", addr);
+        // Quarantining writes, which a standby can't do
+        if let Some(progress) = self.integrity.clone().filter(|_| !self.is_read_only()) {
+            let checker = Arc::clone(&shared);
+            tokio::spawn(async move {
+                if let Err(e) = checker.verify_stored_messages(&progress).await {
+                    error!("❌ Message integrity check failed: {}", e);
+                }
+            });
+        }
+
+        tokio::pin!(shutdown);
+        let mut draining = self.connections.draining_signal();
+        let stopped = loop {
+            let (socket, addr) = tokio::select! {
+                accepted = incoming.next() => accepted.ok_or_else(|| anyhow!("Stopped taking connections"))??,
+                _ = &mut shutdown => break Stopped::Shutdown,
+                _ = draining.changed() => break Stopped::Drained,
+            };
+            out!("📱 New connection from {}", addr);
             
-            let server = Arc::new(self.clone());
+            let server = Arc::clone(&shared);
+            let (conn, pushes) = self.connections.open(addr);
+            tokio::spawn(async move {
+                // The guard lives until the task ends, panic or not, so the
+                // connection is always unregistered the same way
+                match AssertUnwindSafe(server.handle_connection(socket, &conn, pushes)).catch_unwind().await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eout!("❌ Connection error: {}", e),
+                    Err(panic) => error!("💥 Connection {} from {} panicked: {}", conn.id, conn.addr, panic_message(panic.as_ref())),
+                }
+            });
+        };
+
+        // Stop taking connections, let open ones finish the request in hand, then go
+        drop(incoming);
+        if let Some(deadline) = self.connections.draining().filter(|_| stopped == Stopped::Drained) {
+            let frame = serde_json::to_string(&ServerResponse::GoAway { deadline })?;
+            let told = self.connections.broadcast(&frame);
+            out!("🚰 Draining: told {} connection(s) to reconnect after {}", told, deadline.format("%H:%M:%S UTC"));
+            let grace = (deadline - chrono::Utc::now()).to_std().unwrap_or_default();
+            let still_open = self.connections.drain(grace).await;
+            if !still_open.is_empty() {
+                out!("🚰 Drain deadline reached with {} connection(s) open", still_open.len());
+            }
+        }
+        out!("🛑 Shutting down; closing {} connection(s)", self.connections.len());
+        self.connections.shutdown();
+        let still_open = self.connections.drain(SHUTDOWN_GRACE).await;
+        if !still_open.is_empty() {
+            eout!("⚠️ {} connection(s) still open after {}s; exiting anyway", still_open.len(), SHUTDOWN_GRACE.as_secs());
+            for addr in still_open {
+                warn!("🔌 Abandoned connection from {}", addr);
+            }
+        }
+        let _ = sweeps.await;
+
+        // A standby's files are the primary's to write
+        if !self.is_read_only() {
+            if let Err(e) = self.storage.flush().await {
+                eout!("❌ Final save failed: {}", e);
+                return Err(e);
+            }
+            out!("💾 Saved messages and clients");
+        }
+        Ok(stopped)
+    }
+
+    /// Take standbys, follow a primary, or both, as configured, until the
+    /// returned sender is dropped.
+    fn start_replication(&self) -> Result<watch::Sender<bool>> {
+        let (replicating, stop) = watch::channel(false);
+        let secret = || self.replication_secret.clone().ok_or_else(|| anyhow!("Replication needs --replication-secret-file"));
+        if let Some(addr) = self.replication_listen.clone() {
+            let (secret, storage, stop) = (secret()?, Arc::clone(&self.storage), stop.clone());
+            tokio::spawn(async move {
+                if let Err(e) = replication::serve(addr, secret, storage, stop).await {
+                    error!("❌ Replication listener failed: {}", e);
+                }
+            });
+        }
+        if let Some(standby) = self.standby.clone() {
+            out!("🪞 Standby of {}; read-only until promoted", standby.primary());
+            let (secret, storage, key_cache, mut stop) = (secret()?, Arc::clone(&self.storage), Arc::clone(&self.key_cache), stop);
+            let following = replication::follow(standby, secret, storage, move |component| {
+                // Keys may have changed or been revoked on the primary
+                if component == "clients" || component == "revocations" {
+                    key_cache.clear();
+                }
+            });
             tokio::spawn(async move {
-                if let Err(e) = server.handle_connection(socket).await {
-                    eprintln!("❌ Connection error: {}", e);
+                tokio::select! {
+                    _ = following => {}
+                    _ = stop.changed() => {}
                 }
             });
         }
+        Ok(replicating)
     }
 
-    async fn handle_connection(&self, mut socket: tokio::net::TcpStream) -> Result<()> {
-        let mut buf = [0; 4096];
+    async fn handle_connection(&self, socket: transport::Connection, conn: &ConnectionGuard, mut pushes: mpsc::UnboundedReceiver<String>) -> Result<()> {
+        let mut shutdown = self.connections.shutdown_signal();
+        let (mut reader, writer) = tokio::io::split(socket);
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+
+        // Pushed frames go out between responses, never inside one. The
+        // channel closes when the connection is unregistered
+        let max_frame_bytes = self.max_frame_bytes;
+        let push_writer = Arc::clone(&writer);
+        tokio::spawn(async move {
+            while let Some(push) = pushes.recv().await {
+                if let Err(e) = frame::write_frame(&mut *push_writer.lock().await, push.as_bytes(), max_frame_bytes).await {
+                    debug!("📲 Dropped a push: {}", e);
+                }
+            }
+        });
         
-        loop {
-            let n = match socket.read(&mut buf).await {
-                Ok(n) if n == 0 => {
+        while !*shutdown.borrow_and_update() {
+            let read = tokio::select! {
+                read = frame::read_frame(&mut reader, self.max_frame_bytes) => read,
+                _ = shutdown.changed() => break,
+            };
+            let body = match read {
+                Ok(None) => {
+                    break;
+                }
+                Ok(Some(body)) => body,
+                // The oversized body is still unread, so the connection can't carry on
+                Err(e @ FrameError::TooLarge { .. }) => {
+                    warn!("📏 Connection {} from {}: {}", conn.id, conn.addr, e);
+                    let response = coded_error(error_code::FRAME_TOO_LARGE, e.to_string());
+                    let _ = frame::write_frame(&mut *writer.lock().await, serde_json::to_string(&response)?.as_bytes(), self.max_frame_bytes).await;
                     break;
                 }
-                Ok(n) => n,
                 Err(e) => {
                     error!("❌ Read error: {}", e);
                     break;
                 }
             };
 
-            let request = String::from_utf8_lossy(&buf[..n]);
+            let request = String::from_utf8_lossy(&body);
+            debug!("📥 Request on connection {}: {}", conn.id, self.redaction.request(&request));
             
-            let response = match self.process_request(&request).await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    eprintln!("❌ Error processing request: {}", e);
-                    ServerResponse::Error { message: e.to_string() }
+            let logged_before = self.storage.change_log().map(|log| log.seq());
+            // A bug in one handler shouldn't take the server down with it: report
+            // the panic and drop just this connection
+            let outcome = AssertUnwindSafe(metrics::scoped(self.process_request(&request, conn))).catch_unwind().await;
+            let response = match outcome {
+                Ok(Ok(resp)) => resp,
+                // Whatever failed to write is already undone; the details stay in the log
+                Ok(Err(e)) if self.storage_failed(&e) => {
+                    eout!("❌ Error processing request: {}", self.redaction.log(&e.to_string()));
+                    storage_unavailable()
+                }
+                Ok(Err(e)) if e.downcast_ref::<DuplicateMessageId>().is_some() => {
+                    coded_error(error_code::DUPLICATE_MESSAGE_ID, e.to_string())
+                }
+                Ok(Err(e)) if e.downcast_ref::<ReplayedMessageId>().is_some() => {
+                    coded_error(error_code::REPLAYED_MESSAGE_ID, e.to_string())
+                }
+                Ok(Err(e)) => {
+                    let error = e.to_string();
+                    eout!("❌ Error processing request: {}", self.redaction.log(&error));
+                    ServerResponse::Error { message: redact::wire(&error), code: None, retry_after_secs: None }
+                }
+                Err(panic) => {
+                    error!("💥 Request on connection {} from {} panicked: {}", conn.id, conn.addr, self.redaction.log(panic_message(panic.as_ref())));
+                    let response = coded_error(error_code::INTERNAL_ERROR, "Internal server error");
+                    let _ = frame::write_frame(&mut *writer.lock().await, serde_json::to_string(&response)?.as_bytes(), self.max_frame_bytes).await;
+                    return Err(anyhow!("Closed connection {} after a panic", conn.id));
                 }
             };
+
+            // A write is answered once a standby has it too, so promoting the
+            // standby loses nothing this server acknowledged
+            if let Some((log, before)) = self.storage.change_log().zip(logged_before) {
+                let seq = log.seq();
+                if seq > before && !log.wait_for_standbys(seq, STANDBY_ACK_TIMEOUT).await {
+                    warn!("🪞 No standby applied change {} within {}s; answering anyway", seq, STANDBY_ACK_TIMEOUT.as_secs());
+                }
+            }
             
             let response_json = serde_json::to_string(&response)?;
-            socket.write_all(response_json.as_bytes()).await?;
+            let written = frame::write_frame(&mut *writer.lock().await, response_json.as_bytes(), self.max_frame_bytes).await;
+            match written {
+                Err(FrameError::TooLarge { size, max }) => {
+                    warn!("📏 Response on connection {} is {} bytes, over the {}-byte frame limit", conn.id, size, max);
+                    let response = coded_error(error_code::FRAME_TOO_LARGE, format!("The response is {} bytes, over the {}-byte frame limit", size, max));
+                    frame::write_frame(&mut *writer.lock().await, serde_json::to_string(&response)?.as_bytes(), self.max_frame_bytes).await?;
+                }
+                result => result?,
+            }
         }
         
         Ok(())
     }
 
-    async fn process_request(&self, request: &str) -> Result<ServerResponse> {
-        let command: ServerCommand = serde_json::from_str(request)
-            .map_err(|e| anyhow!("Invalid JSON: {}", e))?;
+    /// What the server is doing right now, for an admin chasing a slowdown.
+    /// Client ids and addresses are hashed unless configured otherwise.
+    fn debug_dump(&self) -> serde_json::Value {
+        let identity = |id: &str| match self.debug_dump_ids {
+            DebugDumpIds::Full => id.to_string(),
+            DebugDumpIds::Hash => self.storage.hash_source(id),
+        };
+        let connections: Vec<_> = self.connections.snapshot().into_iter().map(|connection| serde_json::json!({
+            "id": connection.id,
+            "peer": identity(&connection.addr.ip().to_string()),
+            "client_id": connection.client_id.as_deref().map(identity),
+            "presence_only": connection.presence_only,
+            "open_secs": connection.open_for.as_secs(),
+            "requests": connection.requests,
+            "in_flight": connection.in_flight.map(|(command, running)| serde_json::json!({
+                "command": command,
+                "running_ms": running.as_millis() as u64,
+            })),
+        })).collect();
+        let alerts = self.alerts.queue_depth().map(|(depth, capacity, dropped)| serde_json::json!({
+            "depth": depth,
+            "capacity": capacity,
+            "dropped": dropped,
+        }));
+        let sweeps = self.sweeps.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        serde_json::json!({
+            "at": chrono::Utc::now(),
+            "uptime_secs": self.metrics.uptime().as_secs(),
+            "ids": if self.debug_dump_ids == DebugDumpIds::Full { "full" } else { "hash" },
+            "connections": connections,
+            "queues": {
+                "audit_log": self.audit.backlog(),
+                "alerts": alerts,
+            },
+            "persistence": self.storage.persist_stats(),
+            "storage_degraded_since": self.storage_degraded_since(),
+            "sweeps": sweeps,
+            "locks": self.storage.lock_stats(),
+            "read_only_standby": self.is_read_only(),
+        })
+    }
 
-        match command {
-            ServerCommand::Register { client_id, public_key } => {
-                match self.storage.register_client(client_id.clone(), public_key).await {
-                    Ok(_) => {
-                        let response = ServerResponse::Registered {
-                            server_public_key: hex::encode(self.crypto.get_ed25519_public_key().as_bytes()),
-                        };
-                        Ok(response)
-                    }
-                    Err(e) => {
-                        eprintln!("❌ Failed to register client: {}", e);
-                        Err(e)
+    fn storage_degraded_since(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        *self.storage_degraded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// If `e` is a storage write failure, go into degraded mode: new messages
+    /// are refused until a probe write succeeds. Returns whether it was.
+    fn storage_failed(&self, e: &anyhow::Error) -> bool {
+        let Some(failure) = e.downcast_ref::<StorageUnavailable>() else { return false };
+        let mut degraded = self.storage_degraded.lock().unwrap_or_else(|e| e.into_inner());
+        if degraded.is_none() {
+            error!("💽 Storage writes are failing ({}); refusing new messages until they work again", failure);
+            *degraded = Some(chrono::Utc::now());
+            self.alerts.raise(AlertKind::StorageDegraded, &failure.component, "Storage writes are failing; new messages are refused", &[
+                ("component", failure.component.clone()),
+                ("reason", failure.reason.clone()),
+            ]);
+        }
+        true
+    }
+
+    /// Leave degraded mode if storage takes writes again. Returns whether
+    /// storage is healthy.
+    async fn probe_storage(&self) -> bool {
+        let Some(since) = self.storage_degraded_since() else { return true };
+        if self.storage.probe().await.is_err() {
+            return false;
+        }
+        *self.storage_degraded.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        let down_secs = (chrono::Utc::now() - since).num_seconds();
+        info!("💽 Storage is writable again after {}s; accepting messages", down_secs);
+        self.alerts.raise(AlertKind::StorageRecovered, "", "Storage is writable again; new messages are accepted", &[
+            ("degraded_secs", down_secs.to_string()),
+        ]);
+        true
+    }
+
+    /// Register and GetMessages act only once the client proves it holds
+    /// the id's key: without an answer they get a fresh challenge, with
+    /// one that doesn't verify a refusal. `None` lets the command through.
+    async fn challenge(&self, command: &ServerCommand) -> Result<Option<ServerResponse>> {
+        let (client_id, presented, answer) = match command {
+            ServerCommand::Register { client_id, public_key, challenge, .. } => (client_id, Some(public_key), challenge),
+            ServerCommand::GetMessages { client_id, challenge, .. } => (client_id, None, challenge),
+            _ => return Ok(None),
+        };
+        let now = Instant::now();
+        let Some(answer) = answer else {
+            return Ok(Some(ServerResponse::Challenge { nonce: self.challenges.issue(client_id, now) }));
+        };
+        if !self.challenges.redeem(&answer.nonce, client_id, now) {
+            warn!("🔐 Unknown or expired challenge answered for {}", client_id);
+            return Ok(Some(coded_error(error_code::CHALLENGE_FAILED, "The challenge is unknown, expired or for another id")));
+        }
+        // A registered id proves the key on file, and a new one the key it
+        // registers with. Once the key on file is revoked, only the id's
+        // recovery key may register a replacement
+        let key = match (self.storage.get_client_info(client_id).await, presented) {
+            (Some(info), _) if !self.storage.is_key_revoked(&info.public_key).await => info.public_key,
+            (Some(ClientInfo { recovery_key: Some(recovery_key), .. }), Some(_)) => recovery_key,
+            (Some(_), _) => {
+                warn!("🔐 {} for {} refused: its key is revoked", command.name(), client_id);
+                return Ok(Some(coded_error(error_code::CHALLENGE_FAILED, format!("{}'s key is revoked; only its recovery key can register a new one", client_id))));
+            }
+            (None, Some(key)) => key.clone(),
+            (None, None) => return Err(anyhow!("Unknown client: {}", client_id)),
+        };
+        let key = PublicKey::from_bytes(&hex::decode(&key)?)?;
+        let signature = Signature::from_bytes(&hex::decode(&answer.signature)?)?;
+        if self.verify(crypto::context::CHALLENGE, &challenge_payload(client_id, &answer.nonce), &signature, &key).is_err() {
+            warn!("🔐 {} for {} failed its challenge", command.name(), client_id);
+            return Ok(Some(coded_error(error_code::CHALLENGE_FAILED, format!("The challenge answer isn't signed by {}'s key", client_id))));
+        }
+        Ok(None)
+    }
+
+    /// A client's signing key, from the cache or storage. Fails for unknown
+    /// clients and revoked keys.
+    async fn client_key(&self, client_id: &str) -> Result<PublicKey> {
+        if self.storage.is_banned(client_id).await {
+            return Err(anyhow!("{} is banned from this server", client_id));
+        }
+        let generation = match self.key_cache.get(client_id) {
+            Ok(key) => return Ok(key),
+            Err(generation) => generation,
+        };
+
+        let client_info = self.storage.get_client_info(client_id).await
+            .ok_or_else(|| anyhow!("Unknown client: {}", client_id))?;
+        if self.storage.is_key_revoked(&client_info.public_key).await {
+            return Err(anyhow!("Key for {} has been revoked", client_id));
+        }
+        let key = PublicKey::from_bytes(&hex::decode(&client_info.public_key)?)?;
+        self.key_cache.insert(client_id, key, generation);
+        Ok(key)
+    }
+
+    /// Add an entry to the audit log. `subject` is who or what the action was
+    /// about, or "" for nothing in particular.
+    fn audit(&self, actor: &str, action: &str, subject: &str, allowed: bool) {
+        self.audit.append(&AuditRecord { at: chrono::Utc::now(), actor, action, subject, allowed, actions: None });
+    }
+
+    /// Add an admin batch to the audit log as one entry. `subject` says
+    /// whether it applied.
+    fn audit_batch(&self, actor: &str, actions: &[AdminAction], applied: bool) {
+        let subject = if applied { "applied" } else { "not applied" };
+        self.audit.append(&AuditRecord { at: chrono::Utc::now(), actor, action: "AdminBatch", subject, allowed: true, actions: Some(actions) });
+    }
+
+    /// Alert that `actor` tried an admin command without being an admin.
+    fn refuse_non_admin(&self, actor: &str, command: &str) {
+        self.audit(actor, command, "", false);
+        self.alerts.raise(AlertKind::AdminAction, actor, format!("{} tried an admin command without being an admin", actor), &[
+            ("command", command.to_string()),
+            ("actor", actor.to_string()),
+            ("allowed", "false".to_string()),
+        ]);
+    }
+
+    /// Whether `owner` lets `delegate` act for it right now, and send as it
+    /// if `send`. Looked up on every request, so a revoked or expired grant
+    /// cuts the delegate off at once.
+    async fn check_delegation(&self, owner: &str, delegate: &str, send: bool) -> Result<(), ServerResponse> {
+        let refusal = match self.storage.get_delegation(owner, delegate).await {
+            None => format!("{} hasn't delegated their mailbox to {}", owner, delegate),
+            Some(grant) if grant.revoked_at.is_some() => format!("{} revoked {}'s access to their mailbox", owner, delegate),
+            Some(grant) if grant.expires_at <= chrono::Utc::now() => format!("{}'s delegation to {} expired at {}", owner, delegate, grant.expires_at),
+            Some(grant) if send && !grant.scope.allows_send() => format!("{} may read {}'s mailbox but not send as them", delegate, owner),
+            Some(_) => return Ok(()),
+        };
+        warn!("🛂 {}", refusal);
+        Err(coded_error(error_code::NOT_DELEGATED, refusal))
+    }
+
+    /// Record what a delegate did with `owner`'s mailbox.
+    async fn audit_delegate(&self, owner: &str, delegate: &str, action: &str, message_id: &str) -> Result<()> {
+        info!("🛂 {} {} message {} for {}", delegate, action, message_id, owner);
+        self.audit(delegate, &format!("delegate {}", action), owner, true);
+        self.storage.record_delegated_action(DelegationAudit {
+            at: chrono::Utc::now(),
+            owner: owner.to_string(),
+            delegate: delegate.to_string(),
+            action: action.to_string(),
+            message_id: Some(message_id.to_string()),
+        }).await
+    }
+
+    /// The server's signature attesting when it accepted `message`.
+    fn receipt(&self, message: &Message) -> String {
+        let payload = receipt_payload(&message.id, &message.sender_id, &message.recipient_id, message.timestamp);
+        hex::encode(self.crypto.sign_with_context(crypto::context::RECEIPT, &payload).to_bytes())
+    }
+
+    /// Queue a system notice in a client's mailbox.
+    async fn notify(&self, client_id: &str, notice: SystemNotice) -> Result<()> {
+        if !self.has_mailbox(client_id).await {
+            debug!("📢 Dropped {} notice for presence-only {}", notice.notice_type, client_id);
+            return Ok(());
+        }
+        info!("📢 {} notice for {}", notice.notice_type, client_id);
+        let message = self.notice_message(client_id, notice);
+        let (hlc, _) = self.storage.add_message(message.clone()).await?;
+        self.push(Message { hlc, ..message });
+        Ok(())
+    }
+
+    /// Push a stored message to its recipient's connections that take
+    /// pushes. It stays queued either way; the push only saves a poll.
+    fn push(&self, message: Message) {
+        let connections = self.connections.pushes_for(&message.recipient_id);
+        if connections.is_empty() {
+            return;
+        }
+        let (id, recipient) = (message.id.clone(), message.recipient_id.clone());
+        let frame = match serde_json::to_string(&ServerResponse::Incoming { message: Box::new(message) }) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("📲 Couldn't encode message {} to push: {}", id, e);
+                return;
+            }
+        };
+        for connection in connections {
+            // A closed channel is a connection going away; the message waits for a poll
+            let _ = connection.send(frame.clone());
+        }
+        debug!("📲 Pushed message {} to {}", id, recipient);
+    }
+
+    /// Whether anything may be queued for `client_id`: unknown ids may have
+    /// messages held for them, presence-only ones never do.
+    async fn has_mailbox(&self, client_id: &str) -> bool {
+        !self.storage.get_client_info(client_id).await.is_some_and(|info| is_presence_only(&info))
+    }
+
+    /// A system notice for `client_id`, with the server's receipt.
+    fn notice_message(&self, client_id: &str, notice: SystemNotice) -> Message {
+        let message = Message {
+            id: new_message_id(),
+            sender_id: String::new(),
+            recipient_id: client_id.to_string(),
+            content: String::new(),
+            timestamp: chrono::Utc::now(),
+            encrypted: false,
+            signature: None,
+            expires_at: None,
+            deliver_by: None,
+            delivered_at: None,
+            notice: Some(notice),
+            hlc: Hlc::default(),
+            sender_key: None,
+            key_epoch: None,
+            guest: None,
+            server_signature: None,
+            seq: None,
+            sent_by: None,
+            supersedes: None,
+            replaced_queued: false,
+            sent_at: None,
+            group: None,
+        };
+        Message { server_signature: Some(self.receipt(&message)), ..message }
+    }
+
+    /// The storage changes one admin action makes, or why it can't be done.
+    async fn plan_admin_action(&self, admin_id: &str, action: &AdminAction) -> Result<Vec<BatchOp>, String> {
+        match action {
+            AdminAction::Ban { client_id, reason } => {
+                if self.admins.contains(client_id) {
+                    return Err(format!("{} is an admin and can't be banned", client_id));
+                }
+                let ban = Ban { by: admin_id.to_string(), at: chrono::Utc::now(), reason: reason.clone() };
+                Ok(vec![BatchOp::Ban { client_id: client_id.clone(), ban }])
+            }
+            AdminAction::Unban { client_id } => Ok(vec![BatchOp::Unban { client_id: client_id.clone() }]),
+            AdminAction::PruneMailbox { client_id } => Ok(vec![BatchOp::PruneMailbox { client_id: client_id.clone() }]),
+            AdminAction::Notice { text, recipients } => {
+                if text.trim().is_empty() {
+                    return Err("the notice has no text".to_string());
+                }
+                let registered = self.storage.get_all_clients().await;
+                let mut with_mailbox = Vec::with_capacity(registered.len());
+                for id in &registered {
+                    if self.has_mailbox(id).await {
+                        with_mailbox.push(id.clone());
                     }
                 }
+                let recipients = if recipients.is_empty() { with_mailbox.clone() } else { recipients.clone() };
+                if let Some(unknown) = recipients.iter().find(|id| !registered.contains(id)) {
+                    return Err(format!("Unknown client: {}", unknown));
+                }
+                if let Some(board) = recipients.iter().find(|id| !with_mailbox.contains(id)) {
+                    return Err(format!("{} is presence-only and has no mailbox", board));
+                }
+                Ok(recipients.iter()
+                    .map(|id| {
+                        let notice = SystemNotice::new(notice_type::ADMIN_NOTICE, &[("from", admin_id.to_string())], text.clone());
+                        BatchOp::Deliver(Box::new(self.notice_message(id, notice)))
+                    })
+                    .collect())
             }
+            AdminAction::CreateInviteCode { code } => {
+                if code.len() < MIN_INVITE_CODE_LEN {
+                    return Err(format!("Invite codes must be at least {} characters", MIN_INVITE_CODE_LEN));
+                }
+                Ok(vec![BatchOp::AddInviteCode { code: code.clone(), created_by: admin_id.to_string() }])
+            }
+            // Nothing to store; it starts once the batch has applied
+            AdminAction::Drain { grace_secs } => {
+                if !(1..=MAX_DRAIN_GRACE_SECS).contains(grace_secs) {
+                    return Err(format!("A drain's grace period is 1 to {} seconds", MAX_DRAIN_GRACE_SECS));
+                }
+                match self.connections.draining() {
+                    Some(deadline) => Err(format!("The server is already draining, until {}", deadline.format("%H:%M:%S UTC"))),
+                    None => Ok(Vec::new()),
+                }
+            }
+        }
+    }
 
-            ServerCommand::Send { sender_id, recipient_id, encrypted_content, signature, message_id } => {
-                info!("📤 Message from {} to {}", sender_id, recipient_id);
-                
-                // Verify sender exists
-                let sender_info = self.storage.get_client_info(&sender_id).await
-                    .ok_or_else(|| anyhow!("Unknown sender: {}", sender_id))?;
-                
-                // Verify signature
-                let sender_pubkey = PublicKey::from_bytes(&hex::decode(&sender_info.public_key)?)?;
-                let signature_bytes = hex::decode(&signature)?;
-                let signature = Signature::from_bytes(&signature_bytes)?;
-                
-                self.crypto.verify(encrypted_content.as_bytes(), &signature, &sender_pubkey)?;
-                
-                // Create message
-                let message = Message {
-                    id: message_id.clone(),
-                    sender_id: sender_id.clone(),
-                    recipient_id: recipient_id.clone(),
-                    content: encrypted_content,
-                    timestamp: chrono::Utc::now(),
-                    encrypted: true,
-                    signature: Some(hex::encode(signature.to_bytes())), // Store as hex string
-                };
-                
-                // Store message
-                self.storage.add_message(message.clone()).await?;
-                
-                // Update sender's last seen
-                self.storage.update_client_last_seen(&sender_id).await?;
-                
-                info!("✅ Message stored successfully");
-                Ok(ServerResponse::MessageSent { message_id })
+    /// Check every action, then apply them: all together or none if
+    /// `transactional`, each on its own otherwise. Returns whether any
+    /// were applied, and how each went.
+    async fn run_admin_batch(&self, admin_id: &str, actions: &[AdminAction], transactional: bool) -> (bool, Vec<AdminActionResult>) {
+        let failed = |detail: String| AdminActionResult { ok: false, detail };
+        let mut plans = Vec::with_capacity(actions.len());
+        for action in actions {
+            plans.push(self.plan_admin_action(admin_id, action).await);
+        }
+
+        if !transactional {
+            let mut results = Vec::with_capacity(actions.len());
+            for (action, plan) in actions.iter().zip(plans) {
+                results.push(match plan {
+                    Ok(ops) => match self.storage.transaction(&ops).await {
+                        Ok(counts) => self.applied_action(action, counts.iter().sum()),
+                        Err(e) => failed(e.reason),
+                    },
+                    Err(reason) => failed(reason),
+                });
             }
+            return (results.iter().any(|result| result.ok), results);
+        }
 
-            ServerCommand::GetMessages { client_id } => {
-                info!("📥 Retrieving messages for: {}", client_id);
-                let messages = self.storage.get_messages_for_client(&client_id).await?;
-                
-                if let Some(message) = messages.last() {
-                    Ok(ServerResponse::MessageReceived { message: message.clone() })
-                } else {
-                    Ok(ServerResponse::Error { message: "No messages found".to_string() })
+        if plans.iter().any(Result::is_err) {
+            let results = plans.into_iter()
+                .map(|plan| failed(plan.err().unwrap_or_else(|| "not applied: another action in the batch is invalid".to_string())))
+                .collect();
+            return (false, results);
+        }
+        // Which action each op belongs to, to split the counts back up
+        let mut owners = Vec::new();
+        let mut ops = Vec::new();
+        for (index, plan) in plans.into_iter().enumerate() {
+            for op in plan.unwrap_or_default() {
+                owners.push(index);
+                ops.push(op);
+            }
+        }
+        match self.storage.transaction(&ops).await {
+            Ok(counts) => {
+                let mut totals = vec![0; actions.len()];
+                for (owner, count) in owners.iter().zip(counts) {
+                    totals[*owner] += count;
                 }
+                (true, actions.iter().zip(totals).map(|(action, total)| self.applied_action(action, total)).collect())
+            }
+            Err(e) => {
+                let culprit = e.op.map(|op| owners[op]);
+                let results = (0..actions.len())
+                    .map(|index| match culprit {
+                        Some(culprit) if culprit == index => failed(e.reason.clone()),
+                        Some(_) => failed("rolled back: another action in the batch failed".to_string()),
+                        None => failed(format!("rolled back: {}", e)),
+                    })
+                    .collect();
+                (false, results)
             }
+        }
+    }
 
-            ServerCommand::GetClients => {
-                let clients = self.storage.get_all_clients().await;
-                Ok(ServerResponse::ClientList { clients })
+    /// The result of an action that applied, changing `count` things.
+    fn applied_action(&self, action: &AdminAction, count: usize) -> AdminActionResult {
+        let detail = match action {
+            AdminAction::Ban { client_id, .. } => {
+                self.key_cache.invalidate(client_id);
+                format!("banned {}", client_id)
+            }
+            AdminAction::Unban { client_id } => format!("unbanned {}", client_id),
+            AdminAction::PruneMailbox { client_id } => format!("deleted {} message(s) for {}", count, client_id),
+            AdminAction::Notice { .. } => format!("queued in {} mailbox(es)", count),
+            AdminAction::CreateInviteCode { .. } => "made an invite code".to_string(),
+            AdminAction::Drain { grace_secs } => {
+                let deadline = chrono::Utc::now() + chrono::Duration::seconds(*grace_secs as i64);
+                if self.connections.start_draining(deadline) {
+                    warn!("🚰 Draining; exiting by {}", deadline.format("%H:%M:%S UTC"));
+                    format!("draining; open connections have until {}", deadline.format("%H:%M:%S UTC"))
+                } else {
+                    return AdminActionResult { ok: false, detail: "the server was already draining".to_string() };
+                }
             }
+        };
+        AdminActionResult { ok: true, detail }
+    }
 
-            ServerCommand::Heartbeat { client_id } => {
-                self.storage.update_client_last_seen(&client_id).await?;
-                Ok(ServerResponse::Ok)
+    /// Re-verify the signature of every stored message, a batch at a time, and
+    /// quarantine those that fail instead of serving them.
+    async fn verify_stored_messages(&self, progress: &Mutex<IntegrityProgress>) -> Result<()> {
+        let snapshot = self.storage.snapshot().await;
+        let messages = snapshot.stored_messages();
+        progress.lock().unwrap_or_else(|e| e.into_inner()).total = messages.len() as u64;
+        info!("🔏 Verifying signatures of {} stored message(s)", messages.len());
+
+        for batch in messages.chunks(integrity::BATCH_SIZE) {
+            let mut failed = HashSet::new();
+            for message in batch {
+                let current_key = snapshot.client_info(message.signer()).map(|info| info.public_key.as_str());
+                if let Err(reason) = integrity::verify_message(message, snapshot.key_log(), current_key) {
+                    warn!("🔏 Quarantining message {} from {} to {}: {}", message.id, message.sender_id, message.recipient_id, reason);
+                    failed.insert(message.id.clone());
+                }
+            }
+            let quarantined = self.storage.quarantine_messages(&failed).await?;
+            {
+                let mut progress = progress.lock().unwrap_or_else(|e| e.into_inner());
+                progress.checked += batch.len() as u64;
+                progress.quarantined += quarantined as u64;
             }
+            tokio::task::yield_now().await;
         }
+
+        let mut progress = progress.lock().unwrap_or_else(|e| e.into_inner());
+        progress.finished = true;
+        info!("🔏 Integrity check done: {} checked, {} quarantined", progress.checked, progress.quarantined);
+        if progress.quarantined > 0 {
+            self.alerts.raise(AlertKind::IntegrityFailure, "", format!("{} stored message(s) failed signature verification and were quarantined", progress.quarantined), &[
+                ("checked", progress.checked.to_string()),
+                ("quarantined", progress.quarantined.to_string()),
+            ]);
+        }
+        Ok(())
     }
-}
 
-impl Clone for Server {
-    fn clone(&self) -> Self {
-        Self {
-            crypto: CryptoManager::new(),
-            storage: Storage::new("./data").expect("Failed to create storage"),
-            active_connections: Arc::clone(&self.active_connections),
+    /// Forget guest links that expired a while ago. Returns how many.
+    async fn prune_guest_links(&self) -> Result<usize> {
+        let cutoff = chrono::Utc::now() - chrono::Duration::from_std(GUEST_LINK_KEEP)?;
+        let pruned = self.storage.prune_guest_links(cutoff).await?;
+        if pruned > 0 {
+            info!("🔗 Forgot {} expired guest link(s)", pruned);
         }
+        Ok(pruned)
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
-    
-    println!("🔐 Secure Messaging Protocol Server");
-    println!("=====================================");
-    
-    let server = Server::new()?;
-    println!("✅ Server initialized successfully");
-    println!("🚀 Starting server on 127.0.0.1:8080...");
-    
-    match server.run("127.0.0.1:8080").await {
-        Ok(_) => {
-            println!("✅ Server shutdown gracefully");
+    /// Drop messages held for ids nobody registered in time and tell each
+    /// sender. Returns how many were dropped.
+    async fn expire_invites(&self) -> Result<usize> {
+        let held_since = chrono::Utc::now() - chrono::Duration::from_std(self.invite_ttl)?;
+        let expired = self.storage.take_unclaimed_invites(held_since).await?;
+        for message in &expired {
+            info!("📭 Invite {} to {} was not claimed in time", message.id, message.recipient_id);
+            let notice = SystemNotice::new(notice_type::DELIVERY_FAILED, &[
+                ("message_id", message.id.clone()),
+                ("recipient_id", message.recipient_id.clone()),
+                ("reason", INVITE_UNCLAIMED.to_string()),
+            ], format!("Nobody registered as {} in time to receive your message", message.recipient_id));
+            self.notify(&message.sender_id, notice).await?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Drop messages past their delivery deadline and tell each sender.
+    /// Returns how many were dropped.
+    async fn bounce_undelivered(&self) -> Result<usize> {
+        let bounced = self.storage.take_undelivered().await?;
+        for message in &bounced {
+            info!("📭 Message {} to {} was not fetched in time", message.id, message.recipient_id);
+            let notice = SystemNotice::new(notice_type::DELIVERY_FAILED, &[
+                ("message_id", message.id.clone()),
+                ("recipient_id", message.recipient_id.clone()),
+                ("reason", EXPIRED_UNDELIVERED.to_string()),
+            ], format!("Your message to {} was not fetched before its delivery deadline", message.recipient_id));
+            self.notify(&message.sender_id, notice).await?;
+        }
+        Ok(bounced.len())
+    }
+
+    /// Correct tracked disk usage against the files, and warn admins when it
+    /// crosses the warning size (once per crossing).
+    async fn check_disk_usage(&self) -> Result<()> {
+        for (component, tracked, actual) in self.storage.reconcile_disk_usage().await {
+            warn!("💽 {} was tracked at {} bytes but is {} on disk", component, tracked, actual);
+        }
+        let Some(threshold) = self.disk_warning_bytes else { return Ok(()) };
+        let used = self.storage.total_disk_usage().await;
+        let over = used >= threshold;
+        if self.disk_warned.swap(over, Ordering::Relaxed) == over {
+            return Ok(());
+        }
+        if !over {
+            info!("💽 Data is back under the warning size ({} of {} bytes)", used, threshold);
+            return Ok(());
+        }
+
+        warn!("💽 Data uses {} bytes, over the warning size of {}", used, threshold);
+        self.alerts.raise(AlertKind::DiskThreshold, "", format!("Server data uses {} bytes, over the warning size of {}", used, threshold), &[
+            ("used", used.to_string()),
+            ("threshold", threshold.to_string()),
+        ]);
+        for admin in self.admins.iter() {
+            let notice = SystemNotice::new(notice_type::DISK_USAGE, &[
+                ("used", used.to_string()),
+                ("threshold", threshold.to_string()),
+            ], format!("Server data uses {} bytes, over the warning size of {}", used, threshold));
+            self.notify(admin, notice).await?;
+        }
+        Ok(())
+    }
+
+    fn verify(&self, context: &str, payload: &[u8], signature: &Signature, public_key: &PublicKey) -> Result<()> {
+        self.verify_from(false, context, payload, signature, public_key)
+    }
+
+    /// As `verify`, also taking a signature without a context label for the
+    /// contexts old clients signed, when `unlabeled` says the peer didn't
+    /// negotiate and may be one of them.
+    fn verify_from(&self, unlabeled: bool, context: &str, payload: &[u8], signature: &Signature, public_key: &PublicKey) -> Result<()> {
+        let _timer = metrics::time(Phase::Verify);
+        if self.crypto.verify_with_context(context, payload, signature, public_key).is_ok() {
+            return Ok(());
+        }
+        if unlabeled && crypto::context::LEGACY.contains(&context)
+            && self.crypto.verify_legacy(payload, signature, public_key).is_ok()
+        {
+            warn!("⚠️ Accepted a {} signature without a context label", context);
+            return Ok(());
+        }
+        Err(anyhow!("Invalid {} signature", context))
+    }
+
+    /// Refuse a signed request made too far from the server's clock, or
+    /// whose signature was already used, so a captured one can't be made
+    /// again. Call once the signature has verified. Signatures are kept for
+    /// a day, longer than any request stays within the clock skew.
+    fn check_fresh(&self, signer: &str, signed_at: chrono::DateTime<chrono::Utc>, signature: &Signature) -> Option<ServerResponse> {
+        let skew = (chrono::Utc::now() - signed_at).abs().to_std().unwrap_or(Duration::MAX);
+        if skew > self.max_clock_skew {
+            return Some(coded_error(error_code::STALE_REQUEST, format!("Request was signed {}s off the server's clock; at most {}s allowed", skew.as_secs(), self.max_clock_skew.as_secs())));
+        }
+        if !self.seen_requests.first_use(signer, &hex::encode(signature.to_bytes()), Instant::now()) {
+            return Some(coded_error(error_code::REPLAYED_REQUEST, "This request was already made".to_string()));
+        }
+        None
+    }
+
+    async fn process_request(&self, request: &str, conn: &ConnectionGuard) -> Result<ServerResponse> {
+        let started = Instant::now();
+        let command: ServerCommand = {
+            let _timer = metrics::time(Phase::Parse);
+            // serde quotes offending values, which may be anything the peer sent
+            match serde_json::from_str(request) {
+                Ok(command) => command,
+                Err(e) => {
+                    let error = e.to_string();
+                    eout!("❌ Invalid request: {}", self.redaction.log(&error));
+                    return Ok(coded_error(error_code::INVALID_REQUEST, format!("Invalid JSON: {}", redact::wire(&error))));
+                }
+            }
+        };
+
+        let name = command.name();
+        let client_id = command.client_id().map(str::to_string);
+        conn.begin(name, client_id.as_deref());
+        let response = self.handle_command(command, conn).await;
+        if let Ok(ServerResponse::Registered { protocol_version, features, .. }) = &response {
+            let agreed = FeatureSet::from_bits(features.unwrap_or_default());
+            conn.set_legacy_client(client_id.as_deref().filter(|_| protocol_version.is_none()));
+            conn.set_presence_only(agreed.contains(&features::PRESENCE_ONLY));
+            conn.set_pushed_to(client_id.as_deref().filter(|_| agreed.contains(&features::PUSH_DELIVERY)));
+        }
+        conn.end();
+        self.metrics.record(name, client_id.as_deref(), started.elapsed());
+        response
+    }
+
+    async fn handle_command(&self, command: ServerCommand, conn: &ConnectionGuard) -> Result<ServerResponse> {
+        let peer = conn.addr;
+        if let Some(feature) = feature_of(&command).filter(|feature| !self.features.contains(feature)) {
+            return Ok(coded_error(error_code::FEATURE_DISABLED, format!("This server has {} switched off", feature.id)));
+        }
+        if let Some(standby) = self.standby.as_ref().filter(|standby| standby.is_read_only() && writes_storage(&command)) {
+            return Ok(read_only(standby));
+        }
+        if let Some(response) = self.challenge(&command).await? {
+            return Ok(response);
+        }
+        match command {
+            ServerCommand::Register { client_id, public_key, recovery_key, protocol_version, features, credential, x25519_public_key, x25519_signature, .. } => {
+                if self.storage.is_key_revoked(&public_key).await {
+                    return Err(anyhow!("Key has been revoked"));
+                }
+                let offered = protocol_version.map(|version| (version, features.unwrap_or_default()));
+                let negotiated = NegotiatedFeatures::negotiate(self.features, offered);
+                // Published for others to encrypt to, so it must be the identity's own
+                let x25519_key = match (x25519_public_key, x25519_signature) {
+                    (None, _) => None,
+                    (Some(_), None) => return Err(anyhow!("x25519_public_key needs an x25519_signature")),
+                    (Some(key), Some(signature)) => {
+                        if hex::decode(&key).map_or(true, |bytes| bytes.len() != 32) {
+                            return Err(anyhow!("x25519_public_key is not a hex X25519 key"));
+                        }
+                        let identity = PublicKey::from_bytes(&hex::decode(&public_key)?)?;
+                        let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                        self.verify_from(negotiated.unlabeled_signatures, crypto::context::SENDER_KEY, &sender_key_payload(&client_id, &key), &signature, &identity)?;
+                        Some(SenderKey { x25519_public_key: key, signature: hex::encode(signature.to_bytes()) })
+                    }
+                };
+                if self.storage.is_banned(&client_id).await {
+                    warn!("🚫 Refused to register {}: banned", client_id);
+                    return Ok(coded_error(error_code::REGISTRATION_DENIED, format!("{} is banned from this server", client_id)));
+                }
+                // Whether the provider let a new id in, and may have used up
+                // its credential doing so
+                let mut admitted = false;
+                if self.storage.get_client_info(&client_id).await.is_none() {
+                    if self.reject_confusable_ids {
+                        let clients = self.storage.get_all_clients().await;
+                        let lookalikes = confusables::Lookalikes::new(clients.iter().map(String::as_str));
+                        if let Some(existing) = lookalikes.of(&client_id) {
+                            warn!("🚫 Refused to register {}: looks like {}", client_id, existing);
+                            return Ok(coded_error(error_code::CONFUSABLE_ID, format!("{} looks the same as the registered id {}", client_id, existing)));
+                        }
+                    }
+                    match self.auth.check(&self.storage, &client_id, credential.as_deref()).await? {
+                        AuthDecision::Allow => admitted = true,
+                        AuthDecision::Deny { reason } => {
+                            warn!("🚫 Refused to register {} ({}): {}", client_id, self.auth.name(), reason);
+                            return Ok(coded_error(error_code::REGISTRATION_DENIED, reason));
+                        }
+                        AuthDecision::RequireCredential => {
+                            return Ok(coded_error(error_code::CREDENTIAL_REQUIRED, format!("Registering {} on this server needs a credential ({})", client_id, self.auth.name())));
+                        }
+                    }
+                }
+                let registered = ServerResponse::Registered {
+                    server_public_key: hex::encode(self.crypto.get_ed25519_public_key().as_bytes()),
+                    protocol_version: offered.map(|_| negotiated.protocol),
+                    features: offered.map(|_| negotiated.features.bits()),
+                    max_message_size: self.max_message_size,
+                };
+                // A standby can't record a registration, but one it already
+                // has from the primary needs no recording
+                if let Some(standby) = self.standby.as_ref().filter(|standby| standby.is_read_only()) {
+                    let known = self.storage.get_client_info(&client_id).await.is_some_and(|info| info.public_key == public_key);
+                    if admitted {
+                        self.auth.release(&self.storage, &client_id, credential.as_deref()).await?;
+                    }
+                    return Ok(if known { registered } else { read_only(standby) });
+                }
+
+                let registration = Registration {
+                    source: match self.source_addresses {
+                        SourceAddresses::Keep => Some(peer.ip().to_string()),
+                        SourceAddresses::Hash => Some(self.storage.hash_source(&peer.ip().to_string())),
+                        SourceAddresses::Drop => None,
+                    },
+                    transport: "tcp".to_string(),
+                    protocol_version: offered.map(|_| negotiated.protocol.to_string()),
+                    capabilities: negotiated.features.ids(),
+                    via: (self.storage.invite_counts("", &client_id).await.1 > 0).then(|| "invite".to_string()),
+                };
+                let audit = format!("source {}, via {}",
+                    registration.source.as_deref().unwrap_or("not kept"),
+                    registration.via.as_deref().unwrap_or("direct"));
+                match self.storage.register_client(client_id.clone(), public_key, recovery_key, x25519_key, registration).await {
+                    Ok(_) => {
+                        info!("🆕 Registered {} over tcp ({})", client_id, audit);
+                        self.key_cache.invalidate(&client_id);
+                        for message in self.storage.claim_invites(&client_id).await? {
+                            info!("📬 Invite {} from {} claimed by {}", message.id, message.sender_id, client_id);
+                            let notice = SystemNotice::new(notice_type::INVITE_CLAIMED, &[
+                                ("message_id", message.id.clone()),
+                                ("recipient_id", client_id.clone()),
+                            ], format!("{} registered and received your message", client_id));
+                            self.notify(&message.sender_id, notice).await?;
+                        }
+                        Ok(registered)
+                    }
+                    Err(e) => {
+                        eout!("❌ Failed to register client: {}", e);
+                        if admitted {
+                            if let Err(e) = self.auth.release(&self.storage, &client_id, credential.as_deref()).await {
+                                eout!("❌ Failed to give back {}'s credential: {}", client_id, e);
+                            }
+                        }
+                        Err(e)
+                    }
+                }
+            }
+
+            ServerCommand::Send { mut sender_id, recipient_id, encrypted_content, signature, message_id, ttl_secs, deliver_by, sender_key, seq, on_behalf_of, supersedes, sent_at, group } => {
+                info!("📤 Message from {} to {}", sender_id, recipient_id);
+                if let Err(reason) = check_message_id(&message_id) {
+                    return Ok(coded_error(error_code::INVALID_MESSAGE_ID, reason));
+                }
+                
+                if deliver_by.is_some_and(|by| by <= chrono::Utc::now()) {
+                    return Err(anyhow!("Delivery deadline is already past"));
+                }
+                // Sends older than the skew can't be replayed even once their
+                // ids are forgotten. Without a send time the signature covers
+                // only the ciphertext, so such a send is refused from anyone
+                let Some(sent_at) = sent_at else {
+                    return Ok(coded_error(error_code::STALE_SEND, "Sends must carry a signed sent_at".to_string()));
+                };
+                let skew = (chrono::Utc::now() - sent_at).abs().to_std().unwrap_or(Duration::MAX);
+                if skew > self.max_clock_skew {
+                    return Ok(coded_error(error_code::STALE_SEND, format!("Send time is {}s off the server's clock; at most {}s allowed", skew.as_secs(), self.max_clock_skew.as_secs())));
+                }
+                let unlabeled = conn.unlabeled_signatures(&sender_id);
+                
+                // Verify signature against the sender's registered, unrevoked key
+                let sender_pubkey = self.client_key(&sender_id).await?;
+                let signature_bytes = hex::decode(&signature)?;
+                let signature = Signature::from_bytes(&signature_bytes)?;
+                
+                // Clients sign the ciphertext itself; the hex is only its wire encoding
+                let ciphertext = hex::decode(&encrypted_content)
+                    .map_err(|e| anyhow!("encrypted_content is not valid hex: {}", e))?;
+                if let Some(max) = self.max_message_size.filter(|max| ciphertext.len() > *max) {
+                    return Ok(coded_error(error_code::MESSAGE_TOO_LARGE, format!("Messages are limited to {} bytes", max)));
+                }
+                self.verify_from(unlabeled, crypto::context::SEND, &send_payload(&message_id, &recipient_id, &ciphertext, sent_at), &signature, &sender_pubkey)?;
+                let key_epoch = self.storage.key_epoch().await;
+                if let Some(sender_key) = &sender_key {
+                    let key_signature = Signature::from_bytes(&hex::decode(&sender_key.signature)?)?;
+                    self.verify_from(unlabeled, crypto::context::SENDER_KEY, &sender_key_payload(&sender_id, &sender_key.x25519_public_key), &key_signature, &sender_pubkey)?;
+                }
+                
+                // A delegate signs as itself; from here on the message is the owner's
+                let sent_by = match on_behalf_of {
+                    Some(owner) => {
+                        if sender_key.is_some() {
+                            return Err(anyhow!("Delegates can't attach a sender key to the owner's messages"));
+                        }
+                        if let Err(refusal) = self.check_delegation(&owner, &sender_id, true).await {
+                            return Ok(refusal);
+                        }
+                        Some(std::mem::replace(&mut sender_id, owner))
+                    }
+                    None => None,
+                };
+
+                // Whoever left a group can't write to it, or be written to through it
+                if let Some(name) = &group {
+                    let group = match self.member_group(&sender_id, name).await {
+                        Ok(group) => group,
+                        Err(refusal) => return Ok(refusal),
+                    };
+                    if !group.members.contains_key(&recipient_id) {
+                        return Ok(coded_error(error_code::NOT_GROUP_MEMBER, format!("{} isn't in group {}", recipient_id, name)));
+                    }
+                }
+                
+                // Checked after the signature, so nobody can use up someone else's allowance
+                match self.pair_limiter.check(&sender_id, &recipient_id, Instant::now()) {
+                    PairVerdict::Allowed => {}
+                    PairVerdict::Limited(wait) => {
+                        return Ok(ServerResponse::Error {
+                            message: format!("Too many messages to {}; try again in {}s", recipient_id, wait.as_secs().max(1)),
+                            code: Some(error_code::PAIR_RATE_LIMITED.to_string()),
+                            retry_after_secs: Some(wait.as_secs().max(1)),
+                        });
+                    }
+                    PairVerdict::Muted(_) => {
+                        return Ok(ServerResponse::Error {
+                            message: format!("{} is not accepting messages from you for now", recipient_id),
+                            code: Some(error_code::SENDER_MUTED.to_string()),
+                            retry_after_secs: None,
+                        });
+                    }
+                }
+                
+                // Reads carry on when storage is full; only new messages are refused
+                let disk_used = self.storage.total_disk_usage().await;
+                if self.disk_limit_bytes.is_some_and(|limit| disk_used >= limit) {
+                    warn!("💽 Refused a message to {}: server storage is full", recipient_id);
+                    return Ok(ServerResponse::Error {
+                        message: "Server storage is full".to_string(),
+                        code: Some(error_code::SERVER_STORAGE_FULL.to_string()),
+                        retry_after_secs: None,
+                    });
+                }
+                if self.storage_degraded_since().is_some() {
+                    return Ok(storage_unavailable());
+                }
+                
+                let hold = match self.unknown_recipients {
+                    UnknownRecipients::Accept => false,
+                    _ if self.storage.get_client_info(&recipient_id).await.is_some() => false,
+                    UnknownRecipients::Reject => return Err(anyhow!("Unknown recipient: {}", recipient_id)),
+                    UnknownRecipients::Invite => {
+                        let (from_sender, for_recipient) = self.storage.invite_counts(&sender_id, &recipient_id).await;
+                        if from_sender >= self.invite_cap {
+                            return Err(anyhow!("Too many messages waiting for unregistered recipients; at most {}", self.invite_cap));
+                        }
+                        if for_recipient >= MAX_INVITES_PER_RECIPIENT {
+                            return Err(anyhow!("Too many messages already waiting for {}", recipient_id));
+                        }
+                        true
+                    }
+                };
+                
+                if !self.has_mailbox(&recipient_id).await {
+                    return Ok(coded_error(error_code::NO_MAILBOX, format!("{} only watches presence and takes no messages", recipient_id)));
+                }
+                // A message that will replace an unfetched one in the same mailbox takes no new room
+                let frees = match &supersedes {
+                    Some(old) => !hold && self.storage.is_replaceable(&recipient_id, &sender_id, old).await,
+                    None => false,
+                };
+                let usage = self.storage.mailbox_usage(&recipient_id).await.saturating_sub(usize::from(frees));
+                if self.mailbox_quota.is_some_and(|quota| usage >= quota) {
+                    return Err(anyhow!("Mailbox of {} is full", recipient_id));
+                }
+                
+                // The shorter of the sender's TTL and the recipient's retention policy wins
+                let now = chrono::Utc::now();
+                let policy = self.storage.get_retention(&recipient_id, &sender_id).await;
+                let retention_applied = policy.is_some_and(|p| ttl_secs.is_none_or(|t| p < t));
+                let expires_at = match (ttl_secs, policy) {
+                    (Some(t), Some(p)) => Some(t.min(p)),
+                    (t, p) => t.or(p),
+                }.map(|secs| now + chrono::Duration::seconds(secs as i64));
+                
+                // Create message
+                let message = Message {
+                    id: message_id.clone(),
+                    sender_id: sender_id.clone(),
+                    recipient_id: recipient_id.clone(),
+                    content: encrypted_content,
+                    timestamp: now,
+                    encrypted: true,
+                    signature: Some(hex::encode(signature.to_bytes())), // Store as hex string
+                    expires_at,
+                    deliver_by,
+                    delivered_at: None,
+                    notice: None,
+                    hlc: Hlc::default(),
+                    sender_key,
+                    key_epoch: Some(key_epoch),
+                    guest: None,
+                    server_signature: None,
+                    seq,
+                    sent_by: sent_by.clone(),
+                    supersedes,
+                    replaced_queued: false,
+                    sent_at: Some(sent_at),
+                    group,
+                };
+                let receipt = self.receipt(&message);
+                let message = Message { server_signature: Some(receipt.clone()), ..message };
+                
+                if hold {
+                    let pending_until = now + chrono::Duration::from_std(self.invite_ttl)?;
+                    let replaced = self.storage.hold_invite(message).await?;
+                    if let Some(delegate) = &sent_by {
+                        self.audit_delegate(&sender_id, delegate, "sent", &message_id).await?;
+                    }
+                    info!("📨 Holding message for unregistered {} until {}", recipient_id, pending_until);
+                    return Ok(ServerResponse::MessageSent { message_id, expires_at, retention_applied, pending_until: Some(pending_until), hlc: None, received_at: Some(now), server_signature: Some(receipt), replaced });
+                }
+                
+                // Store message
+                let (hlc, replaced) = self.storage.add_message(message.clone()).await?;
+                if replaced {
+                    info!("♻️ Message {} replaced an unfetched one", message_id);
+                }
+                self.push(Message { hlc, ..message });
+                
+                // Warn the recipient once, as this message crosses the threshold
+                if let Some(quota) = self.mailbox_quota.filter(|_| !replaced) {
+                    let threshold = (quota * QUOTA_WARNING_PERCENT).div_ceil(100);
+                    if usage + 1 == threshold {
+                        let notice = SystemNotice::new(notice_type::QUOTA_WARNING, &[
+                            ("used", threshold.to_string()),
+                            ("limit", quota.to_string()),
+                        ], format!("Your mailbox holds {} of {} messages; fetch them before new mail is refused", threshold, quota));
+                        self.notify(&recipient_id, notice).await?;
+                    }
+                }
+                
+                if let Some(delegate) = &sent_by {
+                    self.audit_delegate(&sender_id, delegate, "sent", &message_id).await?;
+                }
+                
+                // Update sender's last seen
+                self.storage.update_client_last_seen(sent_by.as_deref().unwrap_or(&sender_id)).await?;
+                
+                info!("✅ Message stored successfully");
+                Ok(ServerResponse::MessageSent { message_id, expires_at, retention_applied, pending_until: None, hlc: Some(hlc), received_at: Some(now), server_signature: Some(receipt), replaced })
+            }
+
+            ServerCommand::GetMessages { client_id, since, on_behalf_of, signature, .. } => {
+                let mailbox = match on_behalf_of {
+                    Some(owner) => {
+                        let delegate_pubkey = self.client_key(&client_id).await?;
+                        let signature = signature.ok_or_else(|| anyhow!("Fetching for {} needs a signature", owner))?;
+                        let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                        self.verify(crypto::context::DELEGATED_FETCH, &delegation_ref_payload(&owner, &client_id), &signature, &delegate_pubkey)?;
+                        if let Err(refusal) = self.check_delegation(&owner, &client_id, false).await {
+                            return Ok(refusal);
+                        }
+                        owner
+                    }
+                    None => client_id.clone(),
+                };
+                info!("📥 Retrieving messages for: {}", mailbox);
+                // Answering the challenge proved the key, so this counts as seen
+                self.storage.update_client_last_seen(&client_id).await?;
+                self.bounce_undelivered().await?;
+                let messages = self.storage.fetch_messages(&mailbox, since).await?;
+                if mailbox != client_id {
+                    for message in &messages {
+                        self.audit_delegate(&mailbox, &client_id, "fetched", &message.id).await?;
+                    }
+                }
+                Ok(ServerResponse::Messages { messages })
+            }
+
+            ServerCommand::Ack { client_id, message_ids, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::ACK, &ack_payload(&client_id, &message_ids, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                let deleted = self.storage.delete_messages(&client_id, &message_ids).await?;
+                self.storage.update_client_last_seen(&client_id).await?;
+                info!("🗑️ {} acknowledged {} message(s); {} deleted", client_id, message_ids.len(), deleted);
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::GetClients => {
+                let clients = self.storage.get_all_clients().await;
+                Ok(ServerResponse::ClientList { clients })
+            }
+
+            ServerCommand::GetClientsDelta { since_revision } => {
+                let delta = self.storage.directory_delta(since_revision).await;
+                Ok(ServerResponse::ClientsDelta {
+                    revision: delta.revision,
+                    reset: delta.reset,
+                    changes: delta.changes,
+                    more: delta.more,
+                })
+            }
+
+            ServerCommand::GetServerKey => {
+                Ok(ServerResponse::ServerKey {
+                    server_public_key: hex::encode(self.crypto.get_ed25519_public_key().as_bytes()),
+                })
+            }
+
+            // Unsigned, so anyone could send one for any id: it keeps the
+            // connection open but says nothing about when its id was seen
+            ServerCommand::Heartbeat { .. } => Ok(ServerResponse::Ok),
+
+            ServerCommand::Revoke { revocation } => {
+                let client_info = self.storage.get_client_info(&revocation.client_id).await
+                    .ok_or_else(|| anyhow!("Unknown client: {}", revocation.client_id))?;
+
+                let payload = revocation.signed_payload();
+                let signature = Signature::from_bytes(&hex::decode(&revocation.signature)?)?;
+
+                // The key may revoke itself, but only if it is the one registered for this client
+                let signed_by_key = revocation.key == client_info.public_key
+                    && self.verify(crypto::context::REVOKE, &payload, &signature, &PublicKey::from_bytes(&hex::decode(&revocation.key)?)?).is_ok();
+                let signed_by_recovery = match &client_info.recovery_key {
+                    Some(recovery_key) => self.verify(crypto::context::REVOKE, &payload, &signature, &PublicKey::from_bytes(&hex::decode(recovery_key)?)?).is_ok(),
+                    None => false,
+                };
+                if !signed_by_key && !signed_by_recovery {
+                    return Err(anyhow!("Invalid revocation signature"));
+                }
+
+                info!("🚫 Key revoked for {}: {}", revocation.client_id, revocation.reason);
+                let client_id = revocation.client_id.clone();
+                self.storage.add_revocation(revocation).await?;
+                self.key_cache.invalidate(&client_id);
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::GetRevocations { client_id } => {
+                let revocations = self.storage.get_revocations(&client_id).await;
+                Ok(ServerResponse::Revocations { client_id, revocations })
+            }
+
+            ServerCommand::SetRetention { client_id, sender_id, ttl_secs, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::RETENTION, &retention_payload(&client_id, &sender_id, ttl_secs, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+
+                self.storage.set_retention(&client_id, &sender_id, ttl_secs).await?;
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::GetKeyHistory { client_id } => {
+                let entries = self.storage.get_key_log().await;
+                let public_key = self.storage.get_client_info(&client_id).await.map(|info| info.public_key);
+                let epoch = entries.len() as u64;
+                let head_hash = entries.last().map(|e| e.hash.clone()).unwrap_or_default();
+
+                let payload = key_directory_payload(&client_id, public_key.as_deref(), epoch, &head_hash);
+                let signature = hex::encode(self.crypto.sign_with_context(crypto::context::KEY_DIRECTORY, &payload).to_bytes());
+
+                Ok(ServerResponse::KeyHistory { client_id, public_key, epoch, head_hash, entries, signature })
+            }
+
+            ServerCommand::GetPublicKey { client_id } => {
+                let info = self.storage.get_client_info(&client_id).await
+                    .ok_or_else(|| anyhow!("Unknown client: {}", client_id))?;
+                if self.storage.is_key_revoked(&info.public_key).await {
+                    return Err(anyhow!("Key for {} has been revoked", client_id));
+                }
+                Ok(ServerResponse::PublicKeys { client_id, ed25519: info.public_key, x25519: info.x25519_key })
+            }
+
+            ServerCommand::GetMessageStatus { sender_id, message_id, signature } => {
+                let client_pubkey = self.client_key(&sender_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::MESSAGE_STATUS, &message_ref_payload(&sender_id, &message_id), &signature, &client_pubkey)?;
+
+                let status = self.storage.message_status(&sender_id, &message_id).await;
+                Ok(ServerResponse::MessageStatus { message_id, status })
+            }
+
+            ServerCommand::CancelMessage { sender_id, message_id, signature } => {
+                let client_pubkey = self.client_key(&sender_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::CANCEL, &message_ref_payload(&sender_id, &message_id), &signature, &client_pubkey)?;
+
+                let status = self.storage.cancel_message(&sender_id, &message_id).await?;
+                info!("🗑️ Cancel of message {} by {}: {:?}", message_id, sender_id, status);
+                Ok(ServerResponse::MessageStatus { message_id, status })
+            }
+
+            ServerCommand::SearchMessages { client_id, search, signed_at, signature } => {
+                // Signed by the mailbox owner, so nobody can search someone else's mail
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::SEARCH, &search.payload(&client_id, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+
+                self.bounce_undelivered().await?;
+                let (results, truncated) = self.storage.search_messages(&client_id, &search).await?;
+                Ok(ServerResponse::SearchResults { results, truncated })
+            }
+
+            ServerCommand::Stats => {
+                let (key_cache_hits, key_cache_misses) = self.key_cache.stats();
+                Ok(ServerResponse::Stats {
+                    uptime_secs: self.metrics.uptime().as_secs(),
+                    latencies: self.metrics.latencies(),
+                    key_cache_hits,
+                    key_cache_misses,
+                    disk_usage: self.storage.disk_usage().await,
+                    reported_senders: self.pair_limiter.reports(),
+                    integrity: self.integrity.as_ref().map(|progress| progress.lock().unwrap_or_else(|e| e.into_inner()).clone()),
+                    storage_degraded_since: self.storage_degraded_since(),
+                    features: self.features.ids(),
+                    log_rotations: [(self.audit.name().to_string(), self.audit.rotations())].into(),
+                })
+            }
+
+            ServerCommand::ReportSender { client_id, sender_id, reason, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::REPORT, &report_payload(&client_id, &sender_id, &reason, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+
+                let muted_for = self.pair_limiter.report(&sender_id, &client_id, Instant::now());
+                warn!("🚩 {} reported {}: {}", client_id, sender_id, self.redaction.log(&reason));
+                let muted_until = chrono::Utc::now() + chrono::Duration::from_std(muted_for)?;
+                Ok(ServerResponse::SenderReported { sender_id, muted_until })
+            }
+
+            ServerCommand::CreateGuestLink { client_id, ttl_secs, max_messages, x25519_public_key, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                let payload = guest_link_payload(&client_id, ttl_secs, max_messages, x25519_public_key.as_deref(), signed_at);
+                self.verify(crypto::context::GUEST_LINK, &payload, &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+
+                if ttl_secs == 0 || ttl_secs > MAX_GUEST_LINK_TTL.as_secs() {
+                    return Err(anyhow!("Guest links may last at most {} days", MAX_GUEST_LINK_TTL.as_secs() / 86400));
+                }
+                if max_messages == 0 || max_messages > MAX_GUEST_LINK_MESSAGES {
+                    return Err(anyhow!("Guest links take between 1 and {} messages", MAX_GUEST_LINK_MESSAGES));
+                }
+                if x25519_public_key.as_deref().is_some_and(|key| hex::decode(key).map_or(true, |bytes| bytes.len() != 32)) {
+                    return Err(anyhow!("Invalid X25519 key"));
+                }
+                let now = chrono::Utc::now();
+                let usable = self.storage.guest_links_for(&client_id).await.iter()
+                    .filter(|link| !link.revoked && link.expires_at > now && link.used < link.max_messages)
+                    .count();
+                if usable >= MAX_GUEST_LINKS {
+                    return Err(anyhow!("Too many open guest links; revoke one first (at most {})", MAX_GUEST_LINKS));
+                }
+
+                let secret = hex::encode(rand::random::<[u8; 32]>());
+                let link = GuestLink {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    owner: client_id.clone(),
+                    secret_hash: GuestLink::hash_secret(&secret),
+                    created_at: now,
+                    expires_at: now + chrono::Duration::seconds(ttl_secs as i64),
+                    max_messages,
+                    used: 0,
+                    revoked: false,
+                    x25519_public_key,
+                };
+                self.storage.add_guest_link(link.clone()).await?;
+                info!("🔗 {} opened guest link {} for {} message(s)", client_id, link.id, max_messages);
+                Ok(ServerResponse::GuestLinkCreated { link, secret })
+            }
+
+            ServerCommand::ListGuestLinks { client_id, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::GUEST_LINK_REF, &guest_link_ref_payload(&client_id, ""), &signature, &client_pubkey)?;
+                Ok(ServerResponse::GuestLinks { links: self.storage.guest_links_for(&client_id).await })
+            }
+
+            ServerCommand::RevokeGuestLink { client_id, link_id, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::GUEST_LINK_REF, &guest_link_ref_payload(&client_id, &link_id), &signature, &client_pubkey)?;
+                if !self.storage.revoke_guest_link(&client_id, &link_id).await? {
+                    return Err(anyhow!("No guest link {}", link_id));
+                }
+                info!("🔗 {} revoked guest link {}", client_id, link_id);
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::GuestSend { link_id, secret, message_id, content, ephemeral_key } => {
+                info!("📤 Guest message through link {}", link_id);
+                if let Err(reason) = check_message_id(&message_id) {
+                    return Ok(coded_error(error_code::INVALID_MESSAGE_ID, reason));
+                }
+                let now = chrono::Utc::now();
+                let link = match self.storage.get_guest_link(&link_id).await {
+                    Some(link) if link.secret_hash == GuestLink::hash_secret(&secret) => link,
+                    _ => return Ok(coded_error(error_code::GUEST_LINK_INVALID, "Unknown guest link")),
+                };
+                if link.revoked {
+                    return Ok(coded_error(error_code::GUEST_LINK_REVOKED, "This guest link has been revoked"));
+                }
+                if link.expires_at <= now {
+                    return Ok(coded_error(error_code::GUEST_LINK_EXPIRED, "This guest link has expired"));
+                }
+                if link.used >= link.max_messages {
+                    return Ok(coded_error(error_code::GUEST_LINK_USED_UP, "This guest link takes no more messages"));
+                }
+
+                if content.len() > MAX_GUEST_MESSAGE_BYTES {
+                    return Err(anyhow!("Guest messages are limited to {} bytes", MAX_GUEST_MESSAGE_BYTES));
+                }
+                // A link with the owner's key only takes messages encrypted to it
+                let encrypted = link.x25519_public_key.is_some();
+                match &ephemeral_key {
+                    Some(_) if !encrypted => return Err(anyhow!("This guest link takes plain text only")),
+                    None if encrypted => return Err(anyhow!("This guest link requires encrypted messages")),
+                    Some(key) if hex::decode(key).map_or(true, |bytes| bytes.len() != 32) || hex::decode(&content).is_err() => {
+                        return Err(anyhow!("Malformed encrypted guest message"));
+                    }
+                    _ => {}
+                }
+
+                let sender_id = format!("guest:{}", &link.id[..8]);
+                match self.pair_limiter.check(&sender_id, &link.owner, Instant::now()) {
+                    PairVerdict::Allowed => {}
+                    PairVerdict::Limited(wait) => {
+                        return Ok(coded_error(error_code::PAIR_RATE_LIMITED, format!("Too many messages; try again in {}s", wait.as_secs().max(1))));
+                    }
+                    PairVerdict::Muted(_) => {
+                        return Ok(coded_error(error_code::SENDER_MUTED, format!("{} is not accepting messages from this link for now", link.owner)));
+                    }
+                }
+                let disk_used = self.storage.total_disk_usage().await;
+                if self.disk_limit_bytes.is_some_and(|limit| disk_used >= limit) {
+                    return Ok(coded_error(error_code::SERVER_STORAGE_FULL, "Server storage is full"));
+                }
+                if self.storage_degraded_since().is_some() {
+                    return Ok(storage_unavailable());
+                }
+                let usage = self.storage.mailbox_usage(&link.owner).await;
+                if self.mailbox_quota.is_some_and(|quota| usage >= quota) {
+                    return Err(anyhow!("Mailbox of {} is full", link.owner));
+                }
+                // Checked again under the lock, in case other guests got there first
+                if !self.storage.use_guest_link(&link.id).await? {
+                    return Ok(coded_error(error_code::GUEST_LINK_USED_UP, "This guest link takes no more messages"));
+                }
+
+                let message = Message {
+                    id: message_id.clone(),
+                    sender_id,
+                    recipient_id: link.owner.clone(),
+                    content,
+                    timestamp: now,
+                    encrypted,
+                    signature: None,
+                    expires_at: None,
+                    deliver_by: None,
+                    delivered_at: None,
+                    notice: None,
+                    hlc: Hlc::default(),
+                    sender_key: None,
+                    key_epoch: None,
+                    guest: Some(GuestOrigin { link_id: link.id, ephemeral_key }),
+                    server_signature: None,
+                    seq: None,
+                    sent_by: None,
+                    supersedes: None,
+                    replaced_queued: false,
+                    sent_at: None,
+                    group: None,
+                };
+                let receipt = self.receipt(&message);
+                let message = Message { server_signature: Some(receipt.clone()), ..message };
+                let (hlc, _) = self.storage.add_message(message.clone()).await?;
+                self.push(Message { hlc, ..message });
+                Ok(ServerResponse::MessageSent { message_id, expires_at: None, retention_applied: false, pending_until: None, hlc: Some(hlc), received_at: Some(now), server_signature: Some(receipt), replaced: false })
+            }
+
+            ServerCommand::PutUserData { client_id, key, value, version, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::USER_DATA, &user_data_payload(&client_id, &key, value.as_deref(), version), &signature, &client_pubkey)?;
+
+                if key.is_empty() || key.len() > MAX_USER_DATA_KEY_BYTES {
+                    return Err(anyhow!("User data keys must be 1 to {} bytes", MAX_USER_DATA_KEY_BYTES));
+                }
+                if let Some(value) = &value {
+                    let bytes = hex::decode(value).map_err(|_| anyhow!("User data values must be hex"))?.len();
+                    if bytes > MAX_USER_DATA_VALUE_BYTES {
+                        return Err(anyhow!("User data values are limited to {} bytes", MAX_USER_DATA_VALUE_BYTES));
+                    }
+                    let disk_used = self.storage.total_disk_usage().await;
+                    if self.disk_limit_bytes.is_some_and(|limit| disk_used >= limit) {
+                        return Ok(coded_error(error_code::SERVER_STORAGE_FULL, "Server storage is full"));
+                    }
+                }
+
+                match self.storage.put_user_data(&client_id, &key, value, version, USER_DATA_QUOTA_BYTES).await? {
+                    UserDataWrite::Written(version) => Ok(ServerResponse::UserData { key, value: None, version }),
+                    UserDataWrite::Conflict(current) => Ok(coded_error(error_code::USER_DATA_CONFLICT,
+                        format!("{} is at version {}, not {}", key, current, version))),
+                    UserDataWrite::OverQuota(total) => Ok(coded_error(error_code::USER_DATA_QUOTA,
+                        format!("User data would take {} bytes; the limit is {}", total, USER_DATA_QUOTA_BYTES))),
+                }
+            }
+
+            ServerCommand::GetUserData { client_id, key, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::USER_DATA_REF, &user_data_ref_payload(&client_id, &key, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+
+                let entry = self.storage.get_user_data(&client_id, &key).await;
+                Ok(ServerResponse::UserData {
+                    key,
+                    version: entry.as_ref().map_or(0, |entry| entry.version),
+                    value: entry.map(|entry| entry.value),
+                })
+            }
+
+            ServerCommand::GetClientDetails { admin_id, client_id, signed_at, signature } => {
+                let admin_pubkey = self.client_key(&admin_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::CLIENT_DETAILS, &client_details_payload(&admin_id, &client_id, signed_at), &signature, &admin_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&admin_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                if !self.admins.contains(&admin_id) {
+                    warn!("🚫 {} asked for {}'s registration details but is not an admin", admin_id, client_id);
+                    self.refuse_non_admin(&admin_id, "GetClientDetails");
+                    return Err(anyhow!("Only admins can see client details"));
+                }
+
+                info!("🔎 {} looked up {}'s registration details", admin_id, client_id);
+                self.audit(&admin_id, "GetClientDetails", &client_id, true);
+                self.alerts.raise(AlertKind::AdminAction, &format!("{} {}", admin_id, client_id), format!("{} looked up {}'s registration details", admin_id, client_id), &[
+                    ("command", "GetClientDetails".to_string()),
+                    ("actor", admin_id.clone()),
+                    ("client_id", client_id.clone()),
+                    ("allowed", "true".to_string()),
+                ]);
+                let client = self.storage.get_client_info(&client_id).await
+                    .ok_or_else(|| anyhow!("Unknown client: {}", client_id))?;
+                Ok(ServerResponse::ClientDetails { client })
+            }
+
+            ServerCommand::CreateInviteCode { admin_id, code, signed_at, signature } => {
+                let admin_pubkey = self.client_key(&admin_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::INVITE_CODE, &invite_code_payload(&admin_id, &code, signed_at), &signature, &admin_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&admin_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                if !self.admins.contains(&admin_id) {
+                    warn!("🚫 {} tried to make an invite code but is not an admin", admin_id);
+                    self.refuse_non_admin(&admin_id, "CreateInviteCode");
+                    return Err(anyhow!("Only admins can make invite codes"));
+                }
+                if code.len() < MIN_INVITE_CODE_LEN {
+                    return Err(anyhow!("Invite codes must be at least {} characters", MIN_INVITE_CODE_LEN));
+                }
+                if !self.storage.add_invite_code(&code, &admin_id).await? {
+                    return Err(anyhow!("That invite code was made before"));
+                }
+
+                info!("🎟️ {} made an invite code", admin_id);
+                self.audit(&admin_id, "CreateInviteCode", "", true);
+                self.alerts.raise(AlertKind::AdminAction, &admin_id, format!("{} made an invite code", admin_id), &[
+                    ("command", "CreateInviteCode".to_string()),
+                    ("actor", admin_id.clone()),
+                    ("allowed", "true".to_string()),
+                ]);
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::Promote { admin_id, signed_at, signature } => {
+                let admin_pubkey = self.client_key(&admin_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::PROMOTE, &promote_payload(&admin_id, signed_at), &signature, &admin_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&admin_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                if !self.admins.contains(&admin_id) {
+                    warn!("🚫 {} tried to promote this server but is not an admin", admin_id);
+                    self.refuse_non_admin(&admin_id, "Promote");
+                    return Err(anyhow!("Only admins can promote a standby"));
+                }
+                let standby = self.standby.as_ref().ok_or_else(|| anyhow!("This server isn't a standby"))?;
+                if !standby.promote().await {
+                    return Err(anyhow!("This server was already promoted"));
+                }
+
+                info!("🪞 {} promoted this server; it no longer follows {}", admin_id, standby.primary());
+                self.audit(&admin_id, "Promote", standby.primary(), true);
+                self.alerts.raise(AlertKind::AdminAction, &admin_id, format!("{} promoted this standby to primary", admin_id), &[
+                    ("command", "Promote".to_string()),
+                    ("actor", admin_id.clone()),
+                    ("primary", standby.primary().to_string()),
+                    ("allowed", "true".to_string()),
+                ]);
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::DebugDump { admin_id, signed_at, signature } => {
+                let admin_pubkey = self.client_key(&admin_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::DEBUG_DUMP, &debug_dump_payload(&admin_id, signed_at), &signature, &admin_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&admin_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                if !self.admins.contains(&admin_id) {
+                    warn!("🚫 {} asked for a debug dump but is not an admin", admin_id);
+                    self.refuse_non_admin(&admin_id, "DebugDump");
+                    return Err(anyhow!("Only admins can take a debug dump"));
+                }
+
+                info!("🩺 {} took a debug dump", admin_id);
+                self.audit(&admin_id, "DebugDump", "", true);
+                self.alerts.raise(AlertKind::AdminAction, &admin_id, format!("{} took a debug dump", admin_id), &[
+                    ("command", "DebugDump".to_string()),
+                    ("actor", admin_id.clone()),
+                    ("allowed", "true".to_string()),
+                ]);
+                Ok(ServerResponse::DebugDump { dump: self.debug_dump() })
+            }
+
+            ServerCommand::AdminBatch { admin_id, actions, transactional, signed_at, signature } => {
+                let admin_pubkey = self.client_key(&admin_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::ADMIN_BATCH, &admin_batch_payload(&admin_id, &actions, transactional, signed_at), &signature, &admin_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&admin_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                if !self.admins.contains(&admin_id) {
+                    warn!("🚫 {} tried to run an admin batch but is not an admin", admin_id);
+                    self.refuse_non_admin(&admin_id, "AdminBatch");
+                    return Err(anyhow!("Only admins can run admin batches"));
+                }
+                if actions.is_empty() || actions.len() > MAX_BATCH_ACTIONS {
+                    return Err(anyhow!("A batch has 1 to {} actions", MAX_BATCH_ACTIONS));
+                }
+
+                let (applied, results) = self.run_admin_batch(&admin_id, &actions, transactional).await;
+                let summary = actions.iter().map(AdminAction::name).collect::<Vec<_>>().join(", ");
+                info!("🧺 {} ran a{} admin batch ({}); {}", admin_id, if transactional { " transactional" } else { "" }, summary,
+                    if applied { "applied" } else { "nothing applied" });
+                self.audit_batch(&admin_id, &actions, applied);
+                self.alerts.raise(AlertKind::AdminAction, &admin_id, format!("{} ran an admin batch: {}", admin_id, summary), &[
+                    ("command", "AdminBatch".to_string()),
+                    ("actor", admin_id.clone()),
+                    ("actions", summary.clone()),
+                    ("applied", applied.to_string()),
+                    ("allowed", "true".to_string()),
+                ]);
+                Ok(ServerResponse::AdminBatch { applied, results })
+            }
+
+            ServerCommand::GetPresence { client_id, contacts, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::PRESENCE, &presence_payload(&client_id, &contacts), &signature, &client_pubkey)?;
+                let ids = if contacts.is_empty() {
+                    if !self.admins.contains(&client_id) {
+                        warn!("🚫 {} asked who in the directory is online but is not an admin", client_id);
+                        self.refuse_non_admin(&client_id, "GetPresence");
+                        return Err(anyhow!("Only admins can watch the whole directory; name the contacts to watch"));
+                    }
+                    self.storage.get_all_clients().await
+                } else if contacts.len() > MAX_PRESENCE_CONTACTS {
+                    return Err(anyhow!("At most {} contacts can be watched at once", MAX_PRESENCE_CONTACTS));
+                } else {
+                    contacts
+                };
+
+                let online = self.connections.online();
+                let mut entries = Vec::with_capacity(ids.len());
+                for id in ids {
+                    let info = self.storage.get_client_info(&id).await;
+                    // Status boards aren't anyone to watch
+                    if info.as_ref().is_some_and(is_presence_only) {
+                        continue;
+                    }
+                    entries.push(PresenceEntry { online: online.contains(&id), last_seen: info.map(|info| info.last_seen), client_id: id });
+                }
+                Ok(ServerResponse::Presence { entries })
+            }
+
+            ServerCommand::Delegate { delegation } => {
+                let owner_pubkey = self.client_key(&delegation.owner).await?;
+                let signature = Signature::from_bytes(&hex::decode(&delegation.signature)?)?;
+                let payload = delegation_payload(&delegation.owner, &delegation.delegate, delegation.scope, delegation.issued_at, delegation.expires_at);
+                self.verify(crypto::context::DELEGATE, &payload, &signature, &owner_pubkey)?;
+
+                if delegation.delegate == delegation.owner {
+                    return Err(anyhow!("A mailbox can't be delegated to its owner"));
+                }
+                if self.storage.get_client_info(&delegation.delegate).await.is_none() {
+                    return Err(anyhow!("Unknown client: {}", delegation.delegate));
+                }
+                let now = chrono::Utc::now();
+                if delegation.issued_at > now + chrono::Duration::from_std(DELEGATION_CLOCK_SKEW)? {
+                    return Err(anyhow!("The delegation is dated in the future"));
+                }
+                if delegation.expires_at <= now || delegation.expires_at > now + chrono::Duration::from_std(MAX_DELEGATION_TTL)? {
+                    return Err(anyhow!("Delegations must expire within {} days", MAX_DELEGATION_TTL.as_secs() / 86400));
+                }
+                // Replaying an older grant must not undo a revocation
+                if let Some(existing) = self.storage.get_delegation(&delegation.owner, &delegation.delegate).await {
+                    if existing.issued_at >= delegation.issued_at {
+                        return Err(anyhow!("A newer delegation to {} is already on record", delegation.delegate));
+                    }
+                }
+
+                info!("🛂 {} gave {} {} access until {}", delegation.owner, delegation.delegate, delegation.scope, delegation.expires_at);
+                self.storage.set_delegation(Delegation { revoked_at: None, ..delegation.clone() }).await?;
+                self.audit(&delegation.owner, &format!("Delegate {}", delegation.scope), &delegation.delegate, true);
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::RevokeDelegation { owner, delegate, signature } => {
+                let owner_pubkey = self.client_key(&owner).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::DELEGATION_REF, &delegation_ref_payload(&owner, &delegate), &signature, &owner_pubkey)?;
+                if !self.storage.revoke_delegation(&owner, &delegate).await? {
+                    return Err(anyhow!("{} has no access to revoke", delegate));
+                }
+                info!("🛂 {} revoked {}'s access", owner, delegate);
+                self.audit(&owner, "RevokeDelegation", &delegate, true);
+                Ok(ServerResponse::Ok)
+            }
+
+            ServerCommand::ListDelegations { client_id, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::DELEGATION_REF, &delegations_payload(&client_id, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                Ok(ServerResponse::Delegations {
+                    delegations: self.storage.delegations_for(&client_id).await,
+                    audit: self.storage.delegation_audit_for(&client_id).await,
+                })
+            }
+
+            ServerCommand::MyUsage { client_id, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::USAGE, &usage_payload(&client_id, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                let mut usage = self.storage.account_usage(&client_id).await;
+                usage.mailbox_quota = self.mailbox_quota;
+                usage.user_data_quota_bytes = USER_DATA_QUOTA_BYTES;
+                Ok(ServerResponse::Usage { usage })
+            }
+
+            ServerCommand::CreateGroup { client_id, group, members, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::GROUP, &group_payload(&client_id, "CreateGroup", &group, &members.join(","), signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                check_group_name(&group).map_err(|reason| anyhow!(reason))?;
+                let mut members: BTreeSet<String> = members.into_iter().filter(|member| *member != client_id).collect();
+                if members.len() + 1 > MAX_GROUP_MEMBERS {
+                    return Err(anyhow!("Groups are limited to {} members", MAX_GROUP_MEMBERS));
+                }
+                for member in &members {
+                    if self.storage.get_client_info(member).await.is_none() {
+                        return Ok(coded_error(error_code::UNKNOWN_CLIENT, format!("Unknown client: {}", member)));
+                    }
+                }
+
+                let now = chrono::Utc::now();
+                members.insert(client_id.clone());
+                let created = Group {
+                    name: group.clone(),
+                    members: members.iter().map(|member| (member.clone(), if *member == client_id { GroupRole::Owner } else { GroupRole::Member })).collect(),
+                    joined_at: members.iter().map(|member| (member.clone(), now)).collect(),
+                    created_by: client_id.clone(),
+                    created_at: now,
+                };
+                if !self.storage.create_group(created.clone()).await? {
+                    return Ok(coded_error(error_code::GROUP_EXISTS, format!("There is already a group called {}", group)));
+                }
+                info!("👥 {} created group {} with {} member(s)", client_id, group, created.members.len());
+                for member in members.iter().filter(|member| **member != client_id) {
+                    let notice = SystemNotice::new(notice_type::GROUP_ADDED, &[
+                        ("group", group.clone()),
+                        ("by", client_id.clone()),
+                    ], format!("{} added you to group {}", client_id, group));
+                    self.notify(member, notice).await?;
+                }
+                Ok(ServerResponse::Group { group: created })
+            }
+
+            ServerCommand::GetGroup { client_id, group, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::GROUP, &group_payload(&client_id, "GetGroup", &group, "", signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                match self.member_group(&client_id, &group).await {
+                    Ok(group) => Ok(ServerResponse::Group { group }),
+                    Err(refusal) => Ok(refusal),
+                }
+            }
+
+            ServerCommand::ListGroups { client_id, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::GROUP, &group_payload(&client_id, "ListGroups", "", "", signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                Ok(ServerResponse::Groups { groups: self.storage.groups_for(&client_id).await })
+            }
+
+            ServerCommand::RenameGroup { client_id, group, new_name, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::GROUP, &group_payload(&client_id, "RenameGroup", &group, &new_name, signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                check_group_name(&new_name).map_err(|reason| anyhow!(reason))?;
+                let existing = match self.member_group(&client_id, &group).await {
+                    Ok(existing) => existing,
+                    Err(refusal) => return Ok(refusal),
+                };
+                if existing.members.get(&client_id) != Some(&GroupRole::Owner) {
+                    return Ok(coded_error(error_code::NOT_GROUP_OWNER, format!("Only the owners of {} can rename it", group)));
+                }
+                if !self.storage.rename_group(&group, &new_name).await? {
+                    return Ok(coded_error(error_code::GROUP_EXISTS, format!("There is already a group called {}", new_name)));
+                }
+                info!("👥 {} renamed group {} to {}", client_id, group, new_name);
+                Ok(ServerResponse::Group { group: Group { name: new_name, ..existing } })
+            }
+
+            ServerCommand::LeaveGroup { client_id, group, signed_at, signature } => {
+                let client_pubkey = self.client_key(&client_id).await?;
+                let signature = Signature::from_bytes(&hex::decode(&signature)?)?;
+                self.verify(crypto::context::GROUP, &group_payload(&client_id, "LeaveGroup", &group, "", signed_at), &signature, &client_pubkey)?;
+                if let Some(refusal) = self.check_fresh(&client_id, signed_at, &signature) {
+                    return Ok(refusal);
+                }
+                if !self.storage.leave_group(&group, &client_id).await? {
+                    return Ok(unknown_group(&group));
+                }
+                info!("👥 {} left group {}", client_id, group);
+                Ok(ServerResponse::Ok)
+            }
+        }
+    }
+
+    /// The group called `name`, if `client_id` is in it; otherwise the
+    /// refusal to answer with.
+    async fn member_group(&self, client_id: &str, name: &str) -> Result<Group, ServerResponse> {
+        match self.storage.get_group(name).await {
+            Some(group) if group.members.contains_key(client_id) => Ok(group),
+            Some(_) => Err(coded_error(error_code::NOT_GROUP_MEMBER, format!("You aren't a member of group {}", name))),
+            None => Err(unknown_group(name)),
+        }
+    }
+}
+
+/// The optional feature a command belongs to, if any.
+fn feature_of(command: &ServerCommand) -> Option<&'static Feature> {
+    match command {
+        ServerCommand::SearchMessages { .. } => Some(&features::MESSAGE_SEARCH),
+        ServerCommand::CreateGuestLink { .. }
+        | ServerCommand::ListGuestLinks { .. }
+        | ServerCommand::RevokeGuestLink { .. }
+        | ServerCommand::GuestSend { .. } => Some(&features::GUEST_LINKS),
+        ServerCommand::PutUserData { .. } | ServerCommand::GetUserData { .. } => Some(&features::USER_DATA),
+        ServerCommand::GetMessages { since: Some(_), .. } => Some(&features::SYNC_CURSORS),
+        ServerCommand::GetPresence { .. } => Some(&features::PRESENCE_ONLY),
+        _ => None,
+    }
+}
+
+/// Whether a command may change storage, which a standby refuses. New
+/// commands count as writes until listed here.
+fn writes_storage(command: &ServerCommand) -> bool {
+    !matches!(command,
+        // Answered by a standby only for ids it already has
+        ServerCommand::Register { .. }
+        | ServerCommand::GetClients
+        | ServerCommand::GetClientsDelta { .. }
+        | ServerCommand::GetServerKey
+        | ServerCommand::GetRevocations { .. }
+        | ServerCommand::GetKeyHistory { .. }
+        | ServerCommand::GetPublicKey { .. }
+        | ServerCommand::GetMessageStatus { .. }
+        | ServerCommand::SearchMessages { .. }
+        | ServerCommand::Stats
+        | ServerCommand::ListGuestLinks { .. }
+        | ServerCommand::GetUserData { .. }
+        | ServerCommand::GetClientDetails { .. }
+        | ServerCommand::ListDelegations { .. }
+        | ServerCommand::MyUsage { .. }
+        | ServerCommand::Promote { .. }
+        | ServerCommand::DebugDump { .. }
+        | ServerCommand::GetPresence { .. }
+        | ServerCommand::GetGroup { .. }
+        | ServerCommand::ListGroups { .. })
+}
+
+/// Every feature this build has, less those named by `--disable-feature`.
+fn enabled_features(disabled: &[String]) -> Result<FeatureSet> {
+    disabled.iter().try_fold(FeatureSet::supported(features::PROTOCOL_VERSION), |set, id| {
+        Ok(set.without(features::find(id).map_err(|e| anyhow!("Invalid --disable-feature: {}", e))?))
+    })
+}
+
+/// The server's keys from `path`, or new ones saved there on first start.
+fn load_server_keys(path: &Path) -> Result<CryptoManager> {
+    if path.exists() {
+        return CryptoManager::load_from_file(path).map_err(|e| anyhow!("Failed to load the server keys from {}: {}", path.display(), e));
+    }
+    let crypto = CryptoManager::new();
+    crypto.save_to_file(path).map_err(|e| anyhow!("Failed to save the server keys to {}: {}", path.display(), e))?;
+    out!("🔑 Generated new server keys in {}", path.display());
+    Ok(crypto)
+}
+
+/// Whether the id was first registered by a presence-only session, such as
+/// a status board.
+fn is_presence_only(info: &ClientInfo) -> bool {
+    info.registration.as_ref().is_some_and(|registration| registration.capabilities.iter().any(|id| id == features::PRESENCE_ONLY.id))
+}
+
+/// An error response with a machine-readable code.
+fn coded_error(code: &str, message: impl Into<String>) -> ServerResponse {
+    ServerResponse::Error { message: message.into(), code: Some(code.to_string()), retry_after_secs: None }
+}
+
+fn unknown_group(name: &str) -> ServerResponse {
+    coded_error(error_code::UNKNOWN_GROUP, format!("No group called {}", name))
+}
+
+/// The response to a request that needs a write, from a standby.
+fn read_only(standby: &Standby) -> ServerResponse {
+    coded_error(error_code::READ_ONLY_STANDBY, format!("This server is a standby of {} and takes no writes until promoted", standby.primary()))
+}
+
+/// The response to a request that needed a write while storage is failing.
+fn storage_unavailable() -> ServerResponse {
+    ServerResponse::Error {
+        message: "Server storage is unavailable; try again later".to_string(),
+        code: Some(error_code::SERVER_STORAGE_UNAVAILABLE.to_string()),
+        retry_after_secs: Some(DELIVERY_SWEEP_INTERVAL.as_secs()),
+    }
+}
+
+/// Ctrl-C, or SIGTERM on Unix, the usual way a service manager stops the server.
+async fn shutdown_requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(e) => {
+                warn!("⚠️ Can't listen for SIGTERM: {}; only Ctrl-C shuts down cleanly", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// When the audit log rotates: `--audit-log-max-bytes <n>` (default 16 MiB,
+/// 0 for never), `--audit-log-max-age-secs <n>` and `--audit-log-keep <n>`
+/// rotated files.
+fn audit_rotation(flags: &ServerArgs) -> RotationPolicy {
+    let defaults = RotationPolicy::default();
+    RotationPolicy {
+        max_bytes: match flags.audit_log_max_bytes {
+            Some(0) => None,
+            Some(bytes) => Some(bytes),
+            None => defaults.max_bytes,
+        },
+        max_age: flags.audit_log_max_age_secs.map(Duration::from_secs),
+        keep: flags.audit_log_keep.unwrap_or(defaults.keep),
+    }
+}
+
+/// Where operator alerts go and which are sent: `--alert-webhook <url>` with
+/// `--alert-secret-file <path>`, or `--alert-command <program [args]>`;
+/// `--alerts <type,...>` and `--alert-dedup-secs <n>`.
+fn alert_config(flags: &ServerArgs) -> Result<AlertConfig> {
+    let sink = match (&flags.alert_webhook, &flags.alert_command) {
+        (Some(_), Some(_)) => return Err(anyhow!("Use either --alert-webhook or --alert-command, not both")),
+        (Some(url), None) => {
+            let secret_file = flags.alert_secret_file.as_ref()
+                .ok_or_else(|| anyhow!("--alert-webhook needs --alert-secret-file; alerts are always signed"))?;
+            secure_fs::check_private(Path::new(secret_file), flags.insecure_permissions_ok)?;
+            let secret = std::fs::read_to_string(secret_file)
+                .map_err(|e| anyhow!("Failed to read {}: {}", secret_file, e))?;
+            Some(Sink::Webhook { url: url.clone(), secret: secret.trim().as_bytes().to_vec() })
+        }
+        (None, Some(command)) => {
+            let mut words = command.split_whitespace().map(str::to_string);
+            let program = words.next().ok_or_else(|| anyhow!("--alert-command needs a program"))?;
+            Some(Sink::Command { program, args: words.collect() })
+        }
+        (None, None) => None,
+    };
+    let enabled = match &flags.alerts {
+        Some(list) => list.split(',').map(|kind| kind.trim().parse()).collect::<Result<BTreeSet<AlertKind>>>()?,
+        None => AlertKind::ALL.into_iter().collect(),
+    };
+    Ok(AlertConfig {
+        sink,
+        enabled,
+        dedup_window: flags.alert_dedup_secs.map(Duration::from_secs).unwrap_or(alerts::DEFAULT_DEDUP_WINDOW),
+    })
+}
+
+/// Replication flags: `--replicate-on <addr>` to take standbys,
+/// `--standby-of <addr>` to follow a primary, and `--replication-secret-file
+/// <path>` for the secret both ends share.
+fn replication_options(flags: &ServerArgs) -> Result<ReplicationOptions> {
+    let (listen, standby_of) = (flags.replicate_on.clone(), flags.standby_of.clone());
+    let secret = match &flags.replication_secret_file {
+        Some(secret_file) => {
+            secure_fs::check_private(Path::new(secret_file), flags.insecure_permissions_ok)?;
+            let secret = std::fs::read_to_string(secret_file)
+                .map_err(|e| anyhow!("Failed to read {}: {}", secret_file, e))?;
+            Some(Secret::new(secret.trim().as_bytes().to_vec())?)
+        }
+        None if listen.is_some() || standby_of.is_some() => {
+            return Err(anyhow!("Replication needs --replication-secret-file; standbys and primaries always authenticate"));
+        }
+        None => None,
+    };
+    Ok(ReplicationOptions { listen, standby_of, secret })
+}
+
+/// `server [flags]`, given the whole command line.
+pub async fn main(args: Vec<String>) -> Result<()> {
+    // Prints --help, or the usage for a flag it doesn't know, and exits
+    let flags = ServerArgs::parse_from(&args).with_config()?;
+    output::init(flags.output);
+    let (check_data, repair, insecure_permissions_ok) = (flags.check_data, flags.repair, flags.insecure_permissions_ok);
+    let import_json = flags.import_json.clone();
+    let options = ServerOptions::from_flags(flags)?;
+    secure_fs::check_private_tree(&options.data_dir, insecure_permissions_ok)?;
+    if let Some(json_dir) = import_json {
+        if options.storage == StorageKind::Json {
+            return Err(anyhow!("--import-json needs --storage sqlite or redb"));
+        }
+        if json_dir.canonicalize().ok() == options.data_dir.canonicalize().ok() {
+            return Err(anyhow!("JSON storage in the data directory is taken over when the server first starts with --storage {}", options.storage.name()));
+        }
+        secure_fs::create_private_dir(&options.data_dir)?;
+        let target = backend::open(options.storage, &options.data_dir)?;
+        let (clients, messages) = backend::import_json(&json_dir, target.as_ref())?;
+        target.flush()?;
+        out!("📦 Imported {} client(s) and {} message(s) from {} into {}", clients, messages, json_dir.display(), target.name());
+        return Ok(());
+    }
+    if check_data {
+        let report = check::check_data(&options.data_dir.to_string_lossy(), repair)?;
+        report.print();
+        std::process::exit(if report.is_clean() { 0 } else { 1 });
+    }
+    
+    note!("🔐 Secure Messaging Protocol Server");
+    note!("=====================================");
+    
+    let listen = options.bind.clone();
+    let server = Server::new(options).await?;
+    out!("✅ Server initialized successfully");
+    out!("🚀 Starting server on {}...", listen);
+    
+    match server.run(&listen).await {
+        Ok(Stopped::Shutdown) => {
+            out!("✅ Server shutdown gracefully");
+        }
+        Ok(Stopped::Drained) => {
+            out!("✅ Server drained; exiting with status {}", DRAINED_EXIT_CODE);
+            std::process::exit(DRAINED_EXIT_CODE);
         }
         Err(e) => {
-            eprintln!("❌ Server error: {}", e);
+            eout!("❌ Server error: {}", e);
             return Err(e);
         }
     }
@@ -0,0 +1,74 @@
+use crate::backend::StorageKind;
+use crate::output::OutputMode;
+use crate::server::{DebugDumpIds, SourceAddresses, UnknownRecipients};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Server settings read from a TOML file given with `--config`. Keys are
+/// named after the flags they stand in for, and a flag on the command line
+/// wins over its key; a repeatable flag's key takes a list, which the flags
+/// replace if any are given. Every flag has a key but `--config`,
+/// `--check-data`, `--repair` and `--import-json`, which are for one run.
+///
+/// ```toml
+/// bind = "0.0.0.0:9000"
+/// data-dir = "/var/lib/msgproto"
+/// max-message-size = 65536
+/// storage = "sqlite"
+/// admins = ["alice"]
+/// registration = "invite-code"
+/// unknown-recipients = "invite"
+/// pair-rate = 60.0
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct ServerConfigFile {
+    pub bind: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub storage: Option<StorageKind>,
+    pub insecure_permissions_ok: Option<bool>,
+    pub output: Option<OutputMode>,
+    pub max_message_size: Option<usize>,
+    pub max_frame_bytes: Option<usize>,
+    pub mailbox_quota: Option<usize>,
+    pub disk_warning_bytes: Option<u64>,
+    pub disk_limit_bytes: Option<u64>,
+    pub admins: Option<Vec<String>>,
+    pub max_clock_skew_secs: Option<u64>,
+    pub registration: Option<String>,
+    pub allow_list: Option<String>,
+    pub reject_confusable_ids: Option<bool>,
+    pub disabled_features: Option<Vec<String>>,
+    pub pair_rate: Option<f64>,
+    pub pair_burst: Option<f64>,
+    pub report_mute_secs: Option<u64>,
+    pub unknown_recipients: Option<UnknownRecipients>,
+    pub invite_ttl_secs: Option<u64>,
+    pub invite_cap: Option<usize>,
+    pub verify_messages: Option<bool>,
+    pub slow_ms: Option<u64>,
+    pub source_addresses: Option<SourceAddresses>,
+    pub debug_dump_ids: Option<DebugDumpIds>,
+    pub log_excerpt: Option<usize>,
+    pub log_full_payloads: Option<bool>,
+    pub audit_log_max_bytes: Option<u64>,
+    pub audit_log_max_age_secs: Option<u64>,
+    pub audit_log_keep: Option<usize>,
+    pub alert_webhook: Option<String>,
+    pub alert_secret_file: Option<String>,
+    pub alert_command: Option<String>,
+    pub alerts: Option<String>,
+    pub alert_dedup_secs: Option<u64>,
+    pub replicate_on: Option<String>,
+    pub standby_of: Option<String>,
+    pub replication_secret_file: Option<String>,
+}
+
+impl ServerConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Couldn't read config file {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))
+    }
+}
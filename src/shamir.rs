@@ -0,0 +1,151 @@
+use anyhow::{Result, anyhow};
+
+/// Multiply in GF(2^8) with the AES polynomial x^8 + x^4 + x^3 + x + 1.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse (a^254); `a` must be non-zero.
+fn inverse(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = mul(result, a);
+    }
+    result
+}
+
+/// Shamir-split `secret` into `count` shares over GF(2^8), one polynomial per
+/// byte; any `threshold` of them rebuild it.
+/// Shares are `(x, bytes)` with x in 1..=count.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<(u8, Vec<u8>)>> {
+    if threshold < 2 || threshold > count {
+        return Err(anyhow!("Threshold must be between 2 and the number of shares"));
+    }
+
+    let mut shares: Vec<(u8, Vec<u8>)> = (1..=count).map(|x| (x, Vec::with_capacity(secret.len()))).collect();
+    for &byte in secret {
+        let mut coefficients = vec![byte];
+        coefficients.extend((1..threshold).map(|_| rand::random::<u8>()));
+        for (x, share) in &mut shares {
+            // Horner's rule, highest coefficient first
+            let y = coefficients.iter().rev().fold(0, |acc, &c| mul(acc, *x) ^ c);
+            share.push(y);
+        }
+    }
+    Ok(shares)
+}
+
+/// Rebuild a secret from shares by Lagrange interpolation at x = 0. Fewer
+/// shares than the threshold silently yield garbage, so callers must check the
+/// result against something they know.
+pub fn combine(shares: &[(u8, Vec<u8>)]) -> Result<Vec<u8>> {
+    let len = shares.first().map(|(_, s)| s.len()).ok_or_else(|| anyhow!("No shares"))?;
+    if shares.iter().any(|(x, s)| *x == 0 || s.len() != len) {
+        return Err(anyhow!("Shares are malformed or from different secrets"));
+    }
+    for (i, (x, _)) in shares.iter().enumerate() {
+        if shares[..i].iter().any(|(other, _)| other == x) {
+            return Err(anyhow!("Duplicate share {}", x));
+        }
+    }
+
+    let mut secret = vec![0u8; len];
+    for (i, (xi, yi)) in shares.iter().enumerate() {
+        let mut basis = 1;
+        for (j, (xj, _)) in shares.iter().enumerate() {
+            if i != j {
+                basis = mul(basis, mul(*xj, inverse(xj ^ xi)));
+            }
+        }
+        for (byte, y) in secret.iter_mut().zip(yi) {
+            *byte ^= mul(*y, basis);
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"a secret several dozen bytes long, to split";
+
+    /// Every way of picking `k` of `items`.
+    fn subsets<T: Clone>(items: &[T], k: usize) -> Vec<Vec<T>> {
+        if k == 0 {
+            return vec![Vec::new()];
+        }
+        (0..items.len())
+            .flat_map(|i| subsets(&items[i + 1..], k - 1).into_iter().map(move |mut rest| {
+                rest.insert(0, items[i].clone());
+                rest
+            }))
+            .collect()
+    }
+
+    #[test]
+    fn mul_and_inverse_agree() {
+        // The worked example from FIPS-197
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        for a in 0..=255u8 {
+            assert_eq!(mul(a, 1), a);
+            assert_eq!(mul(a, 0), 0);
+            assert_eq!(mul(a, 0x53), mul(0x53, a));
+            if a != 0 {
+                assert_eq!(mul(a, inverse(a)), 1, "inverse({}) is wrong", a);
+            }
+        }
+    }
+
+    #[test]
+    fn any_threshold_shares_rebuild_the_secret() {
+        for (threshold, count) in [(2, 2), (2, 3), (3, 5), (4, 6), (5, 5)] {
+            let shares = split(SECRET, threshold, count).unwrap();
+            assert_eq!(shares.len(), count as usize);
+            for subset in subsets(&shares, threshold as usize) {
+                assert_eq!(combine(&subset).unwrap(), SECRET, "{}-of-{} failed", threshold, count);
+            }
+            // More than enough works too
+            assert_eq!(combine(&shares).unwrap(), SECRET);
+        }
+    }
+
+    #[test]
+    fn fewer_than_threshold_shares_do_not_rebuild_it() {
+        for (threshold, count) in [(2, 3), (3, 5), (5, 5)] {
+            let shares = split(SECRET, threshold, count).unwrap();
+            for subset in subsets(&shares, threshold as usize - 1) {
+                assert_ne!(combine(&subset).unwrap(), SECRET, "{} of a {}-of-{} split rebuilt it", threshold - 1, threshold, count);
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_and_malformed_shares_are_refused() {
+        let shares = split(SECRET, 2, 3).unwrap();
+        assert!(combine(&[shares[0].clone(), shares[0].clone()]).unwrap_err().to_string().contains("Duplicate"));
+        assert!(combine(&[]).is_err());
+        assert!(combine(&[(0, shares[0].1.clone()), shares[1].clone()]).is_err());
+        let mut short = shares[1].clone();
+        short.1.pop();
+        assert!(combine(&[shares[0].clone(), short]).is_err());
+    }
+
+    #[test]
+    fn impossible_thresholds_are_refused() {
+        assert!(split(SECRET, 1, 3).is_err());
+        assert!(split(SECRET, 4, 3).is_err());
+    }
+}
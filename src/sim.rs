@@ -0,0 +1,422 @@
+//! Deterministic simulation: a [`Server`] and several [`Client`]s on a fake
+//! network that delays frames, loses them and partitions hosts, all on
+//! tokio's paused clock and a seeded RNG, so a scenario plays out the same
+//! way every time its seed is run. Needs a runtime with the clock paused,
+//! as `#[tokio::test(start_paused = true)]` gives.
+//!
+//! A scenario sends and receives through [`Sim`], which keeps a ledger of
+//! what the server acknowledged and what each client rendered, and ends
+//! with [`Sim::check`]. Every step has `STEP_LIMIT` of simulated time, so a
+//! deadlock fails the scenario instead of hanging it. Failures name the
+//! seed.
+//!
+//! ```ignore
+//! #[tokio::test(start_paused = true)]
+//! async fn sends_survive_a_partition() {
+//!     let mut sim = Sim::new("partition", 7, NetConfig::lossy());
+//!     let server = sim.start_server().await;
+//!     sim.add_client("bob").await;
+//!     sim.add_client("carol").await;
+//!     let network = sim.network.clone();
+//!     tokio::join!(sim.send_many("bob", "carol", 5), async move {
+//!         network.partition("bob", SERVER_ADDR);
+//!     });
+//!     sim.check(server).await;
+//! }
+//! ```
+
+use crate::client::Client;
+use crate::frame;
+use crate::paths::ClientPaths;
+use crate::scratch::Scratch;
+use crate::server::{Server, ServerOptions, Stopped};
+use crate::transport::{self, Connector};
+use futures::future::BoxFuture;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Where the simulated server listens.
+pub const SERVER_ADDR: &str = "sim-server:9000";
+/// Simulated time any one step gets before it counts as deadlocked.
+pub const STEP_LIMIT: Duration = Duration::from_secs(600);
+/// How many bytes a link buffers each way; more than any frame the
+/// scenarios send.
+const LINK_BUFFER_BYTES: usize = 16 * 1024 * 1024;
+/// Times `add_client` tries to register through a lossy network.
+const REGISTER_ATTEMPTS: usize = 50;
+/// Rounds of receiving `check` does on a calm network before judging.
+const SETTLE_ROUNDS: usize = 3;
+
+/// How the network treats frames.
+#[derive(Debug, Clone, Copy)]
+pub struct NetConfig {
+    /// Each frame is delayed by a time picked evenly from this range.
+    pub latency: (Duration, Duration),
+    /// The chance a frame is lost. Over TCP that means the connection
+    /// resets, so it does here too.
+    pub drop_rate: f64,
+}
+
+impl NetConfig {
+    /// A few milliseconds of latency and nothing lost.
+    pub fn calm() -> Self {
+        Self { latency: (Duration::from_millis(1), Duration::from_millis(5)), drop_rate: 0.0 }
+    }
+
+    /// Up to a quarter second of latency, and one frame in fifty lost.
+    pub fn lossy() -> Self {
+        Self { latency: (Duration::from_millis(5), Duration::from_millis(250)), drop_rate: 0.02 }
+    }
+}
+
+/// A fake network between named hosts and listening addresses. Frames on
+/// one connection stay in order, as over TCP; frames on different
+/// connections each get their own latency, so they reorder freely. Clones
+/// share the network.
+#[derive(Clone)]
+pub struct Network(Arc<Mutex<NetState>>);
+
+struct NetState {
+    rng: StdRng,
+    config: NetConfig,
+    listeners: HashMap<String, mpsc::UnboundedSender<(transport::Connection, SocketAddr)>>,
+    /// (host, address) pairs that can't reach each other.
+    partitions: HashSet<(String, String)>,
+    /// Bumped whenever `partitions` changes, so open links look again.
+    partitioned: watch::Sender<u64>,
+    next_port: u16,
+}
+
+impl Network {
+    pub fn new(seed: u64, config: NetConfig) -> Self {
+        Network(Arc::new(Mutex::new(NetState {
+            rng: StdRng::seed_from_u64(seed),
+            config,
+            listeners: HashMap::new(),
+            partitions: HashSet::new(),
+            partitioned: watch::channel(0).0,
+            next_port: 1024,
+        })))
+    }
+
+    fn state(&self) -> MutexGuard<'_, NetState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn configure(&self, config: NetConfig) {
+        self.state().config = config;
+    }
+
+    /// Take connections on `addr` until the returned stream is dropped.
+    pub fn listen(&self, addr: &str) -> transport::Incoming {
+        let (accept, accepted) = mpsc::unbounded_channel();
+        self.state().listeners.insert(addr.to_string(), accept);
+        Box::pin(futures::stream::unfold(accepted, |mut accepted| async move {
+            let connection = accepted.recv().await?;
+            Some((Ok(connection), accepted))
+        }))
+    }
+
+    /// Dials from `host`.
+    pub fn connector(&self, host: &str) -> Arc<dyn Connector> {
+        Arc::new(Host { network: self.clone(), name: host.to_string() })
+    }
+
+    /// Cut `host` off from `addr`: open connections between them reset,
+    /// and new ones time out.
+    pub fn partition(&self, host: &str, addr: &str) {
+        let mut state = self.state();
+        state.partitions.insert((host.to_string(), addr.to_string()));
+        state.partitioned.send_modify(|generation| *generation += 1);
+    }
+
+    pub fn heal(&self, host: &str, addr: &str) {
+        self.state().partitions.remove(&(host.to_string(), addr.to_string()));
+    }
+
+    pub fn heal_all(&self) {
+        self.state().partitions.clear();
+    }
+
+    fn is_partitioned(&self, host: &str, addr: &str) -> bool {
+        self.state().partitions.contains(&(host.to_string(), addr.to_string()))
+    }
+
+    /// A time picked evenly between `min` and `max`.
+    pub fn pick(&self, min: Duration, max: Duration) -> Duration {
+        let (min, max) = (min.as_micros() as u64, max.as_micros() as u64);
+        Duration::from_micros(self.state().rng.gen_range(min, max.max(min) + 1))
+    }
+
+    fn latency(&self) -> Duration {
+        let (min, max) = self.state().config.latency;
+        self.pick(min, max)
+    }
+
+    fn loses_frame(&self) -> bool {
+        let mut state = self.state();
+        let drop_rate = state.config.drop_rate;
+        drop_rate > 0.0 && state.rng.gen_bool(drop_rate)
+    }
+
+    async fn connect(&self, host: &str, addr: &str) -> io::Result<transport::Connection> {
+        tokio::time::sleep(self.latency()).await;
+        if self.is_partitioned(host, addr) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} can't reach {}", host, addr)));
+        }
+        let (local, client_wire) = tokio::io::duplex(LINK_BUFFER_BYTES);
+        let (remote, server_wire) = tokio::io::duplex(LINK_BUFFER_BYTES);
+        let peer = {
+            let mut state = self.state();
+            state.next_port = state.next_port.wrapping_add(1);
+            SocketAddr::from(([10, 0, 0, 1], state.next_port))
+        };
+        let listener = self.state().listeners.get(addr).cloned();
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, format!("nothing listens on {}", addr));
+        listener.ok_or_else(refused)?.send((Box::new(remote), peer)).map_err(|_| refused())?;
+        self.relay(host, addr, client_wire, server_wire);
+        Ok(Box::new(local))
+    }
+
+    /// Carry frames both ways between the two ends of a link until one end
+    /// closes, a frame is lost, or the link is partitioned. Then both ends
+    /// see it close.
+    fn relay(&self, host: &str, addr: &str, client_wire: DuplexStream, server_wire: DuplexStream) {
+        let (cut, _) = watch::channel(false);
+        let cut = Arc::new(cut);
+        let (client_read, client_write) = tokio::io::split(client_wire);
+        let (server_read, server_write) = tokio::io::split(server_wire);
+        for (from, to) in [(client_read, server_write), (server_read, client_write)] {
+            let (network, cut) = (self.clone(), Arc::clone(&cut));
+            let (host, addr) = (host.to_string(), addr.to_string());
+            tokio::spawn(async move {
+                let mut cut_off = cut.subscribe();
+                let mut partitioned = network.state().partitioned.subscribe();
+                let (mut from, mut to) = (from, to);
+                let carried = async {
+                    while let Ok(Some(body)) = frame::read_frame(&mut from, LINK_BUFFER_BYTES).await {
+                        if network.loses_frame() {
+                            break;
+                        }
+                        tokio::time::sleep(network.latency()).await;
+                        if frame::write_frame(&mut to, &body, LINK_BUFFER_BYTES).await.is_err() {
+                            break;
+                        }
+                    }
+                };
+                let partition = async {
+                    while partitioned.changed().await.is_ok() {
+                        if network.is_partitioned(&host, &addr) {
+                            break;
+                        }
+                    }
+                };
+                tokio::select! {
+                    _ = carried => {}
+                    _ = partition => {}
+                    _ = cut_off.wait_for(|cut| *cut) => {}
+                }
+                cut.send_replace(true);
+                let _ = to.shutdown().await;
+            });
+        }
+    }
+}
+
+struct Host {
+    network: Network,
+    name: String,
+}
+
+impl Connector for Host {
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<transport::Connection>> {
+        Box::pin(self.network.connect(&self.name, addr))
+    }
+}
+
+/// A server on the simulated network, stopped and started over the same
+/// data directory.
+pub struct SimServer {
+    network: Network,
+    data_dir: PathBuf,
+    running: Option<(oneshot::Sender<()>, tokio::task::JoinHandle<anyhow::Result<Stopped>>)>,
+}
+
+impl SimServer {
+    async fn start(network: &Network, data_dir: &Path) -> Self {
+        let mut server = SimServer { network: network.clone(), data_dir: data_dir.to_path_buf(), running: None };
+        server.restart().await;
+        server
+    }
+
+    /// Start again if stopped.
+    pub async fn restart(&mut self) {
+        if self.running.is_some() {
+            return;
+        }
+        let mut options = ServerOptions::from_args(&[]).expect("default server options");
+        options.data_dir = self.data_dir.clone();
+        options.bind = SERVER_ADDR.to_string();
+        let server = Server::new(options).await.expect("the simulated server didn't start");
+        let incoming = self.network.listen(SERVER_ADDR);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(async move { server.serve_until(incoming, async { let _ = stopped.await; }).await });
+        self.running = Some((stop, running));
+    }
+
+    /// Shut down the way Ctrl-C does, and wait until it has.
+    pub async fn stop(&mut self) {
+        let Some((stop, running)) = self.running.take() else { return };
+        let _ = stop.send(());
+        let stopped = tokio::time::timeout(STEP_LIMIT, running).await.expect("deadlock: the server never finished shutting down");
+        assert_eq!(stopped.unwrap().unwrap(), Stopped::Shutdown);
+    }
+
+    /// Stop, then start again on the same data.
+    pub async fn bounce(&mut self) {
+        self.stop().await;
+        self.restart().await;
+    }
+}
+
+/// A simulation run: the network, the clients on it, and the ledger the
+/// invariants are checked against.
+pub struct Sim {
+    seed: u64,
+    pub network: Network,
+    scratch: Scratch,
+    clients: BTreeMap<String, Client>,
+    /// (recipient, text) of every send the server acknowledged.
+    acknowledged: Vec<(String, String)>,
+    /// (recipient, text) -> times the recipient rendered it.
+    rendered: HashMap<(String, String), usize>,
+    sent: usize,
+}
+
+impl Sim {
+    /// A run named `name` (for its scratch directory) on a network seeded
+    /// with `seed`.
+    pub fn new(name: &str, seed: u64, config: NetConfig) -> Self {
+        Sim {
+            seed,
+            network: Network::new(seed, config),
+            scratch: Scratch::new(&format!("sim-{}", name)),
+            clients: BTreeMap::new(),
+            acknowledged: Vec::new(),
+            rendered: HashMap::new(),
+            sent: 0,
+        }
+    }
+
+    pub async fn start_server(&self) -> SimServer {
+        SimServer::start(&self.network, &self.scratch.0.join("server")).await
+    }
+
+    /// Run `step`, failing the scenario if it doesn't finish in `STEP_LIMIT`.
+    async fn step<T>(&self, what: &str, step: impl Future<Output = T>) -> T {
+        match tokio::time::timeout(STEP_LIMIT, step).await {
+            Ok(done) => done,
+            Err(_) => panic!("seed {}: deadlock: {} didn't finish within {}s", self.seed, what, STEP_LIMIT.as_secs()),
+        }
+    }
+
+    /// Make a client for `id` and register it, retrying while the network
+    /// loses the exchange.
+    pub async fn add_client(&mut self, id: &str) {
+        let home = self.scratch.0.join("clients").join(id);
+        let paths = ClientPaths::resolve(Some(&home.to_string_lossy())).unwrap();
+        paths.create().unwrap();
+        let key_file = paths.key_file(id);
+        let mut client = Client::new(id, paths, key_file, false).unwrap();
+        client.connect_through(self.network.connector(id));
+        for _ in 0..REGISTER_ATTEMPTS {
+            if self.step(&format!("registering {}", id), client.connect_to(SERVER_ADDR)).await.is_ok() {
+                self.clients.insert(id.to_string(), client);
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        panic!("seed {}: {} couldn't register in {} attempts", self.seed, id, REGISTER_ATTEMPTS);
+    }
+
+    pub fn client(&mut self, id: &str) -> &mut Client {
+        self.clients.get_mut(id).unwrap_or_else(|| panic!("no simulated client {}", id))
+    }
+
+    /// Send a message with text no other send uses, and record it if the
+    /// server acknowledged it. Whether it did.
+    pub async fn send(&mut self, from: &str, to: &str) -> bool {
+        self.sent += 1;
+        let text = format!("{} -> {} #{}", from, to, self.sent);
+        let client = self.clients.get_mut(from).unwrap_or_else(|| panic!("no simulated client {}", from));
+        let what = format!("{} sending to {}", from, to);
+        let sent = match tokio::time::timeout(STEP_LIMIT, client.send(to, &text)).await {
+            Ok(sent) => sent.is_ok(),
+            Err(_) => panic!("seed {}: deadlock: {} didn't finish within {}s", self.seed, what, STEP_LIMIT.as_secs()),
+        };
+        if sent {
+            self.acknowledged.push((to.to_string(), text));
+        }
+        sent
+    }
+
+    /// `count` sends, a random moment apart. How many were acknowledged.
+    pub async fn send_many(&mut self, from: &str, to: &str, count: usize) -> usize {
+        let mut acknowledged = 0;
+        for _ in 0..count {
+            tokio::time::sleep(self.network.pick(Duration::ZERO, Duration::from_millis(500))).await;
+            acknowledged += usize::from(self.send(from, to).await);
+        }
+        acknowledged
+    }
+
+    /// Fetch and render `id`'s new messages, tallying each one.
+    pub async fn receive(&mut self, id: &str) {
+        let client = self.clients.get_mut(id).unwrap_or_else(|| panic!("no simulated client {}", id));
+        let views = match tokio::time::timeout(STEP_LIMIT, client.receive()).await {
+            Ok(views) => views,
+            Err(_) => panic!("seed {}: deadlock: {} receiving didn't finish within {}s", self.seed, id, STEP_LIMIT.as_secs()),
+        };
+        for view in views {
+            *self.rendered.entry((id.to_string(), view.body)).or_default() += 1;
+        }
+    }
+
+    /// Heal the network, calm it, restart the server if it is down, let
+    /// every client receive until nothing more arrives, and check the
+    /// invariants: no acknowledged message lost, none rendered twice, and
+    /// (by the step limits) no deadlock. Then stop the server.
+    pub async fn check(&mut self, mut server: SimServer) {
+        self.network.heal_all();
+        self.network.configure(NetConfig::calm());
+        self.step("restarting the server", server.restart()).await;
+        let ids: Vec<String> = self.clients.keys().cloned().collect();
+        for _ in 0..SETTLE_ROUNDS {
+            for id in &ids {
+                self.receive(id).await;
+            }
+        }
+        for (recipient, text) in &self.acknowledged {
+            assert!(self.rendered.contains_key(&(recipient.clone(), text.clone())),
+                "seed {}: acknowledged message lost: {} never saw {:?}", self.seed, recipient, text);
+        }
+        for ((recipient, text), times) in &self.rendered {
+            assert!(*times == 1, "seed {}: {} rendered {:?} {} times", self.seed, recipient, text, times);
+        }
+        self.step("stopping the server", server.stop()).await;
+    }
+
+    /// How many sends the server acknowledged so far.
+    pub fn acknowledged(&self) -> usize {
+        self.acknowledged.len()
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::ClientConfig;
+use crate::secure_fs;
+use anyhow::{Result, anyhow};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+const STATE_FORMAT: &str = "msgproto-state";
+
+/// Bump when the bundle layout changes; older archives must keep importing.
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactRecord {
+    pub server: String,
+    pub client_id: String,
+    pub x25519_public_key: String,
+    #[serde(default)]
+    pub trusted_at: Option<DateTime<Utc>>,
+}
+
+/// Everything a client installation needs to carry over to another machine.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateBundle {
+    pub client_id: String,
+    pub exported_at: DateTime<Utc>,
+    #[serde(default)]
+    pub ed25519_secret: Option<String>,
+    #[serde(default)]
+    pub x25519_secret: Option<String>,
+    #[serde(default)]
+    pub contacts: Vec<ContactRecord>,
+    #[serde(default)]
+    pub config: Option<ClientConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveEncryption {
+    kdf: String,
+    salt: String,
+    nonce: String,
+}
+
+/// On-disk wrapper: the payload is the bundle itself, or its hex ciphertext when
+/// a passphrase was given.
+#[derive(Debug, Serialize, Deserialize)]
+struct StateArchive {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    encryption: Option<ArchiveEncryption>,
+    payload: serde_json::Value,
+}
+
+pub fn write_archive(path: &str, bundle: &StateBundle, passphrase: Option<&str>) -> Result<()> {
+    let (encryption, payload) = match passphrase {
+        Some(passphrase) => {
+            let salt = rand::random::<[u8; 16]>();
+            let nonce = rand::random::<[u8; 12]>();
+            let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+            let plaintext = serde_json::to_vec(bundle)?;
+            let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+                .map_err(|e| anyhow!("Encryption failed: {}", e))?;
+            let encryption = ArchiveEncryption {
+                kdf: "argon2id".to_string(),
+                salt: hex::encode(salt),
+                nonce: hex::encode(nonce),
+            };
+            (Some(encryption), serde_json::Value::String(hex::encode(ciphertext)))
+        }
+        None => (None, serde_json::to_value(bundle)?),
+    };
+
+    let archive = StateArchive {
+        format: STATE_FORMAT.to_string(),
+        version: STATE_VERSION,
+        encryption,
+        payload,
+    };
+    secure_fs::write_private(path, serde_json::to_string_pretty(&archive)?)?;
+    Ok(())
+}
+
+pub fn read_archive(path: &str, passphrase: Option<&str>) -> Result<StateBundle> {
+    let archive: StateArchive = serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| anyhow!("Not a state archive: {}", e))?;
+    if archive.format != STATE_FORMAT {
+        return Err(anyhow!("Not a state archive (format {})", archive.format));
+    }
+    if archive.version > STATE_VERSION {
+        return Err(anyhow!("Archive version {} was written by a newer client (this one reads up to {})",
+            archive.version, STATE_VERSION));
+    }
+
+    let bundle = match archive.encryption {
+        Some(encryption) => {
+            let passphrase = passphrase.ok_or_else(|| anyhow!("Archive is encrypted; pass --passphrase"))?;
+            if encryption.kdf != "argon2id" {
+                return Err(anyhow!("Unsupported key derivation: {}", encryption.kdf));
+            }
+            let ciphertext = archive.payload.as_str()
+                .ok_or_else(|| anyhow!("Encrypted payload must be a hex string"))?;
+            let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &hex::decode(&encryption.salt)?)?);
+            let plaintext = cipher.decrypt(Nonce::from_slice(&hex::decode(&encryption.nonce)?), hex::decode(ciphertext)?.as_slice())
+                .map_err(|_| anyhow!("Wrong passphrase or corrupted archive"))?;
+            serde_json::from_slice(&plaintext)?
+        }
+        None => serde_json::from_value(archive.payload)?,
+    };
+    Ok(bundle)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    Ok(*Key::from_slice(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, CryptoManager};
+    use crate::scratch::Scratch;
+    use std::path::Path;
+
+    /// Where an archive goes in `scratch`.
+    fn archive(scratch: &Scratch) -> String {
+        scratch.0.join("state.json").to_str().unwrap().to_string()
+    }
+
+    fn fixture(name: &str) -> String {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/state").join(name).to_str().unwrap().to_string()
+    }
+
+    fn error(path: &str, passphrase: Option<&str>) -> String {
+        read_archive(path, passphrase).expect_err("a bad archive was imported").to_string()
+    }
+
+    #[test]
+    fn archives_round_trip_with_and_without_a_passphrase() {
+        let (ed25519, x25519) = CryptoManager::new().export_secrets();
+        let bundle = StateBundle {
+            client_id: "alice".to_string(),
+            exported_at: Utc::now(),
+            ed25519_secret: Some(hex::encode(ed25519)),
+            x25519_secret: Some(hex::encode(x25519)),
+            contacts: vec![ContactRecord {
+                server: "127.0.0.1:8080".to_string(),
+                client_id: "bob".to_string(),
+                x25519_public_key: hex::encode([9u8; 32]),
+                trusted_at: Some(Utc::now()),
+            }],
+            config: Some(ClientConfig::default()),
+        };
+        for passphrase in [None, Some("correct horse")] {
+            let scratch = Scratch::new("state-round-trip");
+            write_archive(&archive(&scratch), &bundle, passphrase).unwrap();
+            let restored = read_archive(&archive(&scratch), passphrase).unwrap();
+            assert_eq!(restored.client_id, bundle.client_id);
+            assert_eq!(restored.exported_at, bundle.exported_at);
+            assert_eq!(restored.ed25519_secret, bundle.ed25519_secret);
+            assert_eq!(restored.x25519_secret, bundle.x25519_secret);
+            assert_eq!(restored.contacts.len(), 1);
+            assert_eq!(restored.contacts[0].trusted_at, bundle.contacts[0].trusted_at);
+            assert!(restored.config.is_some());
+        }
+    }
+
+    /// The fixtures hold the config as the first release wrote it, before
+    /// most of today's settings existed.
+    #[test]
+    fn v1_fixtures_still_import() {
+        for (name, passphrase) in [("v1-plain.json", None), ("v1-passphrase.json", Some("fixture"))] {
+            let bundle = read_archive(&fixture(name), passphrase).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(bundle.client_id, "fixture");
+            let identity = CryptoManager::from_secrets(
+                &hex::decode(bundle.ed25519_secret.unwrap()).unwrap(),
+                &hex::decode(bundle.x25519_secret.unwrap()).unwrap(),
+            ).unwrap();
+            assert_eq!(crypto::fingerprint(identity.get_ed25519_public_key().as_bytes()), "2a60 dbd9 c9f7 e4f7 d833 21ae 28cb 1e2d");
+
+            let trusted: Vec<(&str, bool)> = bundle.contacts.iter()
+                .map(|c| (c.client_id.as_str(), c.trusted_at.is_some()))
+                .collect();
+            assert_eq!(trusted, [("alice", true), ("bob", false)]);
+
+            let config = bundle.config.unwrap();
+            assert!(config.muted.contains("bob"));
+            assert_eq!(config.servers["work"].addr, "10.0.0.5:8080");
+            assert!(config.servers["work"].separate_identity);
+            assert_eq!(config.key_log_heads["127.0.0.1:8080"].epoch, 3);
+            assert!(config.bound_servers.is_empty() && config.groups.is_empty());
+        }
+    }
+
+    #[test]
+    fn bad_archives_are_refused() {
+        let wrapped = fixture("v1-passphrase.json");
+        assert!(error(&wrapped, None).contains("--passphrase"));
+        assert!(error(&wrapped, Some("not it")).contains("Wrong passphrase"));
+
+        let mut contents: serde_json::Value = serde_json::from_str(&fs::read_to_string(fixture("v1-plain.json")).unwrap()).unwrap();
+        contents["version"] = (STATE_VERSION + 1).into();
+        let newer = Scratch::new("state-newer");
+        fs::write(archive(&newer), contents.to_string()).unwrap();
+        assert!(error(&archive(&newer), None).contains("written by a newer client"));
+
+        contents["format"] = "something-else".into();
+        let foreign = Scratch::new("state-foreign");
+        fs::write(archive(&foreign), contents.to_string()).unwrap();
+        assert!(error(&archive(&foreign), None).contains("Not a state archive"));
+    }
+}
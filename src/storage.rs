@@ -1,147 +1,2317 @@
-use crate::types::{Message, ClientInfo};
-use serde_json;
-use std::collections::HashMap;
+#[cfg(feature = "storage-conformance")]
+pub mod conformance;
+
+use crate::backend::{self, StorageBackend, StorageKind};
+use crate::types::{AccountUsage, Ban, DirectoryChange, DirectoryChangeKind, Group, GroupRole, Hlc, Message, MessageMetadata, MessageSearch, ClientInfo, DeliveryStatus, Delegation, DelegationAudit, GuestLink, InviteCode, Revocation, KeyEvent, KeyLogEntry, Registration, SenderKey, UserDataEntry};
+use crate::metrics::{self, Phase};
+use crate::secure_fs;
+use crate::output::eout;
+use crate::replay::SeenIds;
+use crate::replication::ChangeLog;
+use crate::instrument::{LockSnapshot, PersistStats, TimedRwLock};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::Path;
-use anyhow::Result;
-use chrono::Utc;
-use tokio::sync::RwLock;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
-use futures;
+use std::time::Instant;
 
 pub struct Storage {
-    messages: Arc<RwLock<HashMap<String, Vec<Message>>>>,
-    clients: Arc<RwLock<HashMap<String, ClientInfo>>>,
+    // Mailboxes and clients as the backend has them, kept in memory for
+    // lookups across all of them
+    messages: Arc<TimedRwLock<HashMap<String, Vec<Message>>>>,
+    clients: Arc<TimedRwLock<HashMap<String, ClientInfo>>>,
+    // Where mailboxes and clients are kept on disk
+    backend: Arc<dyn StorageBackend>,
+    revocations: Arc<TimedRwLock<HashMap<String, Vec<Revocation>>>>,
+    key_log: Arc<TimedRwLock<Vec<KeyLogEntry>>>,
+    // recipient -> sender -> ttl in seconds
+    retention: Arc<TimedRwLock<HashMap<String, HashMap<String, u64>>>>,
+    // Last clock value handed out, and the mark persisted ahead of it so a
+    // restart never goes back
+    clock: Arc<TimedRwLock<Clock>>,
+    // invited id -> messages held until someone registers it
+    invites: Arc<TimedRwLock<HashMap<String, Vec<Message>>>>,
+    // Messages taken out of service because their signature no longer verifies
+    quarantine: Arc<TimedRwLock<Vec<Message>>>,
+    // link id -> guest link
+    guest_links: Arc<TimedRwLock<HashMap<String, GuestLink>>>,
+    // client -> key -> value
+    user_data: Arc<TimedRwLock<HashMap<String, BTreeMap<String, UserDataEntry>>>>,
+    // code hash -> invite code for registering
+    invite_codes: Arc<TimedRwLock<HashMap<String, InviteCode>>>,
+    // owner -> grants to delegates
+    delegations: Arc<TimedRwLock<HashMap<String, Vec<Delegation>>>>,
+    // owner -> what its delegates did, oldest first
+    delegation_audit: Arc<TimedRwLock<HashMap<String, Vec<DelegationAudit>>>>,
+    // client_id -> ban, for ids an admin shut out
+    banned: Arc<TimedRwLock<HashMap<String, Ban>>>,
+    // group name -> group
+    groups: Arc<TimedRwLock<HashMap<String, Group>>>,
+    // Revisions of the client list, for clients that sync it incrementally
+    directory: Arc<TimedRwLock<DirectoryLog>>,
+    // component -> bytes on disk, updated on every write
+    disk_usage: Arc<TimedRwLock<BTreeMap<String, u64>>>,
+    // Held shared by updates that touch several maps, and exclusively while
+    // a snapshot copies them, so a snapshot never sees such an update half done
+    updates: Arc<TimedRwLock<()>>,
+    // component -> how its data file writes have gone since startup
+    persist: std::sync::Mutex<BTreeMap<String, PersistStats>>,
+    // Message ids senders used recently, to refuse replays
+    seen_ids: SeenIds,
+    // Salt for hashing client source addresses; one per data directory
+    source_salt: String,
     data_dir: String,
+    // Every data file write, in order, when standbys may follow this server
+    changes: Option<Arc<ChangeLog>>,
 }
 
-impl Storage {
-    pub fn new(data_dir: &str) -> Result<Self> {
-        // Create data directory if it doesn't exist
-        match fs::create_dir_all(data_dir) {
-            Ok(_) => {},
-            Err(e) => {
-                eprintln!("❌ Failed to create data directory: {}", e);
-                // Try to continue anyway
-            }
+/// Storage as it was at one instant, for reads that take a while. Nothing
+/// written after it was taken shows up, and no update that spans several
+/// maps is seen half done. Taking one only blocks writers for the copy.
+pub struct Snapshot {
+    messages: HashMap<String, Vec<Message>>,
+    invites: HashMap<String, Vec<Message>>,
+    clients: HashMap<String, ClientInfo>,
+    key_log: Vec<KeyLogEntry>,
+}
+
+impl Snapshot {
+    /// Every client message waiting in a mailbox or held for an invitee.
+    pub fn stored_messages(&self) -> Vec<&Message> {
+        self.messages.values().flatten()
+            .filter(|m| m.notice.is_none())
+            .chain(self.invites.values().flatten())
+            .collect()
+    }
+
+    pub fn client_info(&self, client_id: &str) -> Option<&ClientInfo> {
+        self.clients.get(client_id)
+    }
+
+    pub fn key_log(&self) -> &[KeyLogEntry] {
+        &self.key_log
+    }
+}
+
+/// Data files by storage component, for disk usage accounting. Mailboxes
+/// and clients are the backend's, so only their component names are used.
+const DATA_FILES: &[(&str, &str)] = &[
+    ("mailboxes", "messages.json"),
+    ("clients", "clients.json"),
+    ("revocations", "revocations.json"),
+    ("key_log", "key_log.json"),
+    ("retention", "retention.json"),
+    ("clock", "clock.json"),
+    ("invites", "invites.json"),
+    ("quarantine", "quarantine.json"),
+    ("guest_links", "guest_links.json"),
+    ("user_data", "user_data.json"),
+    ("invite_codes", "invite_codes.json"),
+    ("delegations", "delegations.json"),
+    ("delegation_audit", "delegation_audit.json"),
+    ("directory", "directory.json"),
+    ("banned", "banned.json"),
+    ("groups", "groups.json"),
+];
+
+/// Components kept by the storage backend rather than in a data file.
+const BACKEND_COMPONENTS: &[&str] = &["mailboxes", "clients"];
+
+/// Audit entries kept per mailbox owner; the oldest go first.
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// Directory changes kept; a client further behind fetches the whole list.
+const MAX_DIRECTORY_CHANGES: usize = 10_000;
+
+/// Most directory changes in one delta response.
+pub const DIRECTORY_CHUNK: usize = 500;
+
+/// The client list's revision history. Every registration of a new id, key
+/// change and revocation bumps the revision and logs a change.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct DirectoryLog {
+    revision: u64,
+    /// Changes up to this revision were dropped to keep the log short.
+    compacted_through: u64,
+    changes: Vec<DirectoryChange>,
+}
+
+/// How far ahead of the clock its saved mark is put, so it's saved about
+/// once in this many milliseconds rather than on every tick.
+const CLOCK_RESERVE_MS: i64 = 10_000;
+
+/// The clock, and the mark saved in `clock.json` that it hasn't passed.
+/// After a restart it carries on from the mark, past any value it could
+/// have handed out before. Only the mark is saved, or sent to standbys.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(from = "Hlc", into = "Hlc")]
+struct Clock {
+    last: Hlc,
+    reserved: Hlc,
+}
+
+impl From<Hlc> for Clock {
+    fn from(reserved: Hlc) -> Self {
+        Clock { last: reserved, reserved }
+    }
+}
+
+impl From<Clock> for Hlc {
+    fn from(clock: Clock) -> Self {
+        clock.reserved
+    }
+}
+
+/// One storage change in a `Storage::transaction`.
+pub enum BatchOp {
+    Ban { client_id: String, ban: Ban },
+    Unban { client_id: String },
+    PruneMailbox { client_id: String },
+    /// Queue a message; it gets its clock stamp when the batch applies.
+    Deliver(Box<Message>),
+    AddInviteCode { code: String, created_by: String },
+}
+
+/// Why a transaction didn't apply: the failing op's index, if the failure
+/// was in one op rather than in persisting them.
+#[derive(Debug)]
+pub struct BatchFailed {
+    pub op: Option<usize>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for BatchFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.op {
+            Some(op) => write!(f, "change {} failed: {}", op + 1, self.reason),
+            None => write!(f, "couldn't save the changes: {}", self.reason),
         }
-        
+    }
+}
+
+impl std::error::Error for BatchFailed {}
+
+/// A chunk of directory changes; see `ServerResponse::ClientsDelta`.
+pub struct DirectoryDelta {
+    pub revision: u64,
+    pub reset: bool,
+    pub changes: Vec<DirectoryChange>,
+    pub more: bool,
+}
+
+/// While this file exists in the data directory, debug builds fail every
+/// storage write, to exercise the server's degraded mode. If it lists
+/// components, one per line, only their writes fail.
+pub const INJECT_FAILURES_FILE: &str = "inject_write_failures";
+
+/// A data file couldn't be written: the disk is full, read-only, or gone.
+/// Whatever change needed the write has been rolled back in memory.
+#[derive(Debug)]
+pub struct StorageUnavailable {
+    pub component: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for StorageUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to write {} data: {}", self.component, self.reason)
+    }
+}
+
+impl std::error::Error for StorageUnavailable {}
+
+/// The sender already has a message with this id waiting on the server.
+#[derive(Debug)]
+pub struct DuplicateMessageId {
+    pub message_id: String,
+}
+
+impl std::fmt::Display for DuplicateMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A message with id {} is already stored", self.message_id)
+    }
+}
+
+impl std::error::Error for DuplicateMessageId {}
+
+/// The sender used this message id recently, for a message that has since
+/// been fetched or deleted: most likely a replay of a captured send.
+#[derive(Debug)]
+pub struct ReplayedMessageId {
+    pub message_id: String,
+}
+
+impl std::fmt::Display for ReplayedMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message id {} was already used", self.message_id)
+    }
+}
+
+impl std::error::Error for ReplayedMessageId {}
+
+/// How a user data write went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserDataWrite {
+    /// Stored at this version; 0 after a delete.
+    Written(u64),
+    /// The key is at this version, not the one the write expected.
+    Conflict(u64),
+    /// The client would hold this many bytes, over its allowance.
+    OverQuota(usize),
+}
+
+impl Storage {
+    pub async fn new(data_dir: &str, kind: StorageKind) -> Result<Self> {
+        // Create data directory if it doesn't exist, and refuse one that
+        // nothing could be saved to rather than fail on the first write
+        secure_fs::create_private_dir(Path::new(data_dir))
+            .map_err(|e| anyhow!("Can't create data directory {}: {}", data_dir, e))?;
+        let probe = Path::new(data_dir).join("write_probe");
+        fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe))
+            .map_err(|e| anyhow!("Data directory {} is not writable: {}", data_dir, e))?;
+        let backend = backend::open(kind, Path::new(data_dir))
+            .map_err(|e| anyhow!("Can't open {} storage in {}: {}", kind.name(), data_dir, e))?;
+        Self::with_backend(data_dir, backend).await
+    }
+
+    /// Storage over a backend the caller opened, keeping its other data
+    /// files in `data_dir`, which must exist.
+    pub async fn with_backend(data_dir: &str, backend: Box<dyn StorageBackend>) -> Result<Self> {
+        let backend: Arc<dyn StorageBackend> = Arc::from(backend);
         let storage = Self {
-            messages: Arc::new(RwLock::new(HashMap::new())),
-            clients: Arc::new(RwLock::new(HashMap::new())),
+            messages: Arc::new(TimedRwLock::new("messages", HashMap::new())),
+            clients: Arc::new(TimedRwLock::new("clients", HashMap::new())),
+            disk_usage: Arc::new(TimedRwLock::new("disk_usage", measure_data_files(data_dir, &*backend))),
+            backend,
+            revocations: Arc::new(TimedRwLock::new("revocations", HashMap::new())),
+            key_log: Arc::new(TimedRwLock::new("key_log", Vec::new())),
+            retention: Arc::new(TimedRwLock::new("retention", HashMap::new())),
+            clock: Arc::new(TimedRwLock::new("clock", Clock::default())),
+            invites: Arc::new(TimedRwLock::new("invites", HashMap::new())),
+            quarantine: Arc::new(TimedRwLock::new("quarantine", Vec::new())),
+            guest_links: Arc::new(TimedRwLock::new("guest_links", HashMap::new())),
+            user_data: Arc::new(TimedRwLock::new("user_data", HashMap::new())),
+            invite_codes: Arc::new(TimedRwLock::new("invite_codes", HashMap::new())),
+            delegations: Arc::new(TimedRwLock::new("delegations", HashMap::new())),
+            delegation_audit: Arc::new(TimedRwLock::new("delegation_audit", HashMap::new())),
+            banned: Arc::new(TimedRwLock::new("banned", HashMap::new())),
+            groups: Arc::new(TimedRwLock::new("groups", HashMap::new())),
+            directory: Arc::new(TimedRwLock::new("directory", DirectoryLog::default())),
+            updates: Arc::new(TimedRwLock::new("updates", ())),
+            persist: std::sync::Mutex::new(BTreeMap::new()),
+            seen_ids: SeenIds::default(),
+            source_salt: load_source_salt(data_dir),
             data_dir: data_dir.to_string(),
+            changes: None,
         };
         
         // Load existing data (ignore errors for now)
-        if let Err(e) = storage.load_data() {
-            eprintln!("⚠️ Warning: Failed to load existing data: {}", e);
+        if let Err(e) = storage.load_data().await {
+            eout!("⚠️ Warning: Failed to load existing data: {}", e);
         }
         
         Ok(storage)
     }
 
-    pub async fn add_message(&self, message: Message) -> Result<()> {
-        let mut messages = self.messages.write().await;
-        let recipient_messages = messages.entry(message.recipient_id.clone()).or_insert_with(Vec::new);
-        recipient_messages.push(message);
-        
-        // Save to disk
-        self.save_messages().await?;
+    /// Log every data file write from now on, for standbys to follow.
+    pub fn record_changes(&mut self) {
+        self.changes = Some(Arc::new(ChangeLog::new()));
+    }
+
+    pub fn change_log(&self) -> Option<Arc<ChangeLog>> {
+        self.changes.clone()
+    }
+
+    /// Wait counters for every storage lock.
+    pub fn lock_stats(&self) -> Vec<LockSnapshot> {
+        vec![
+            self.messages.stats(), self.clients.stats(), self.revocations.stats(),
+            self.key_log.stats(), self.retention.stats(), self.clock.stats(),
+            self.invites.stats(), self.quarantine.stats(), self.guest_links.stats(),
+            self.user_data.stats(), self.invite_codes.stats(), self.delegations.stats(),
+            self.delegation_audit.stats(), self.banned.stats(), self.groups.stats(), self.directory.stats(), self.disk_usage.stats(),
+            self.updates.stats(),
+        ]
+    }
+
+    /// How data file writes have gone since startup, by component.
+    pub fn persist_stats(&self) -> BTreeMap<String, PersistStats> {
+        self.persist.lock().unwrap().clone()
+    }
+
+    /// Names of the components that have a data file.
+    pub fn components() -> impl Iterator<Item = &'static str> {
+        DATA_FILES.iter().map(|(component, _)| *component)
+    }
+
+    /// Each component's data file as it is on disk; missing files are left
+    /// out. Mailboxes and clients are as the backend has them, in the JSON
+    /// backend's format whichever is in use.
+    pub fn read_data_files(&self) -> Result<BTreeMap<String, String>> {
+        let mut files = BTreeMap::new();
+        files.insert("mailboxes".to_string(), serde_json::to_string_pretty(&self.backend.get_all_messages()?)?);
+        files.insert("clients".to_string(), serde_json::to_string_pretty(&self.backend.get_all_clients()?)?);
+        for (component, file) in DATA_FILES.iter().filter(|(component, _)| !BACKEND_COMPONENTS.contains(component)) {
+            match fs::read_to_string(Path::new(&self.data_dir).join(file)) {
+                Ok(contents) => { files.insert(component.to_string(), contents); }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(anyhow!("Failed to read {}: {}", file, e)),
+            }
+        }
+        Ok(files)
+    }
+
+    /// Replace a component with the data file a primary wrote, or empty it
+    /// if the primary has none. For standbys.
+    pub async fn apply_replicated(&self, component: &str, contents: Option<String>) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let _updates = self.updates.read().await;
+        match component {
+            "mailboxes" => self.install_in_backend(&self.messages, component, contents, |backend, messages| backend.replace_all_messages(messages)).await,
+            "clients" => self.install_in_backend(&self.clients, component, contents, |backend, clients| backend.replace_all_clients(clients)).await,
+            "revocations" => self.install(&self.revocations, component, contents).await,
+            "key_log" => self.install(&self.key_log, component, contents).await,
+            "retention" => self.install(&self.retention, component, contents).await,
+            "clock" => self.install(&self.clock, component, contents).await,
+            "invites" => self.install(&self.invites, component, contents).await,
+            "quarantine" => self.install(&self.quarantine, component, contents).await,
+            "guest_links" => self.install(&self.guest_links, component, contents).await,
+            "user_data" => self.install(&self.user_data, component, contents).await,
+            "invite_codes" => self.install(&self.invite_codes, component, contents).await,
+            "delegations" => self.install(&self.delegations, component, contents).await,
+            "delegation_audit" => self.install(&self.delegation_audit, component, contents).await,
+            "directory" => self.install(&self.directory, component, contents).await,
+            "banned" => self.install(&self.banned, component, contents).await,
+            "groups" => self.install(&self.groups, component, contents).await,
+            other => Err(anyhow!("Unknown storage component {}", other)),
+        }
+    }
+
+    /// Parse `contents` into `slot`, writing it to the component's file first.
+    async fn install<T: Serialize + DeserializeOwned + Default>(&self, slot: &TimedRwLock<T>, component: &str, contents: Option<String>) -> Result<()> {
+        let (value, contents) = match contents {
+            Some(contents) => (serde_json::from_str::<T>(&contents)?, contents),
+            None => (T::default(), serde_json::to_string_pretty(&T::default())?),
+        };
+        let file = DATA_FILES.iter().find(|(name, _)| *name == component).map(|(_, file)| file)
+            .ok_or_else(|| anyhow!("Unknown storage component {}", component))?;
+        let mut slot = slot.write().await;
+        self.write_data(component, &format!("{}/{}", self.data_dir, file), contents).await?;
+        *slot = value;
+        Ok(())
+    }
+
+    /// Parse `contents` into `slot`, handing it to the backend first.
+    async fn install_in_backend<T>(&self, slot: &TimedRwLock<T>, component: &str, contents: Option<String>, replace: fn(&dyn StorageBackend, &T) -> Result<()>) -> Result<()>
+    where
+        T: Serialize + DeserializeOwned + Default + Clone + Send + Sync + 'static,
+    {
+        let (value, contents) = match contents {
+            Some(contents) => (serde_json::from_str::<T>(&contents)?, contents),
+            None => (T::default(), serde_json::to_string_pretty(&T::default())?),
+        };
+        let mut slot = slot.write().await;
+        let installed = value.clone();
+        self.persist_locked(component, Some(contents), move |backend| replace(backend, &installed)).await?;
+        *slot = value;
+        Ok(())
+    }
+
+    /// Store a message, stamping it with the next clock value. Returns that
+    /// value, and whether the message it supersedes was still unfetched and
+    /// so was dropped. Fails with `DuplicateMessageId` if the sender already
+    /// has a message with the same id stored or held, and `ReplayedMessageId`
+    /// if it had one recently.
+    pub async fn add_message(&self, mut message: Message) -> Result<(Hlc, bool)> {
+        let _timer = metrics::time(Phase::Storage);
+        message.hlc = self.tick().await?;
+        let hlc = message.hlc;
+        let (recipient_id, sender_id, message_id) = (message.recipient_id.clone(), message.sender_id.clone(), message.id.clone());
+        let replaced = {
+            let mut messages = self.messages.write().await;
+            let invites = self.invites.read().await;
+            if id_in_use(&messages, &invites, &message.sender_id, &message.id) {
+                return Err(DuplicateMessageId { message_id }.into());
+            }
+            if self.seen_ids.contains(&message.sender_id, &message.id, Instant::now()) {
+                return Err(ReplayedMessageId { message_id }.into());
+            }
+            let recipient_messages = messages.entry(message.recipient_id.clone()).or_insert_with(Vec::new);
+            let replaced = take_superseded(recipient_messages, &mut message);
+            recipient_messages.push(message.clone());
+            (replaced, message)
+        };
+        let (replaced, stored) = replaced;
+        let replaced_id = replaced.as_ref().map(|m| m.id.clone());
+        
+        // Save to disk. Unsaved, the message was never stored: it's about to
+        // be refused, and must not be delivered from memory regardless
+        let saved = self.persist("mailboxes", &self.messages, move |backend| {
+            if let Some(replaced_id) = replaced_id {
+                backend.delete_messages(&stored.recipient_id, &[replaced_id])?;
+            }
+            backend.add_message(&stored)
+        }).await;
+        if let Err(e) = saved {
+            let mut messages = self.messages.write().await;
+            if let Some(mailbox) = messages.get_mut(&recipient_id) {
+                mailbox.retain(|m| !(m.id == message_id && m.sender_id == sender_id));
+                mailbox.extend(replaced);
+            }
+            return Err(e);
+        }
+        self.seen_ids.insert(&sender_id, &message_id, Instant::now());
+        Ok((hlc, replaced.is_some()))
+    }
+
+    /// Advance the clock once; see `ticks`.
+    async fn tick(&self) -> Result<Hlc> {
+        Ok(self.ticks(1).await?[0])
+    }
+
+    /// Advance the clock `count` times. Once that passes the saved mark, a
+    /// new one is saved before the values are used.
+    async fn ticks(&self, count: usize) -> Result<Vec<Hlc>> {
+        let _timer = metrics::time(Phase::Storage);
+        let mut clock = self.clock.write().await;
+        let now = Utc::now();
+        let mut values = Vec::with_capacity(count);
+        let mut last = clock.last;
+        for _ in 0..count {
+            last = last.next(now);
+            values.push(last);
+        }
+        if last > clock.reserved {
+            let reserved = Hlc { wall_ms: last.wall_ms + CLOCK_RESERVE_MS, counter: 0 };
+            let clock_path = format!("{}/clock.json", self.data_dir);
+            self.write_data("clock", &clock_path, serde_json::to_string(&reserved)?).await?;
+            clock.reserved = reserved;
+        }
+        clock.last = last;
+        Ok(values)
+    }
+
+    /// Messages from clients waiting in a mailbox; server notices don't count.
+    pub async fn mailbox_usage(&self, client_id: &str) -> usize {
+        let _timer = metrics::time(Phase::Storage);
+        let messages = self.messages.read().await;
+        messages.get(client_id).map_or(0, |mailbox| mailbox.iter().filter(|m| m.notice.is_none()).count())
+    }
+
+    /// Whether `message_id` from `sender_id` waits unfetched in `recipient_id`'s
+    /// mailbox, so a message superseding it would take its place there.
+    pub async fn is_replaceable(&self, recipient_id: &str, sender_id: &str, message_id: &str) -> bool {
+        let _timer = metrics::time(Phase::Storage);
+        let messages = self.messages.read().await;
+        messages.get(recipient_id).is_some_and(|mailbox| {
+            mailbox.iter().any(|m| m.id == message_id && m.sender_id == sender_id && m.delivered_at.is_none())
+        })
+    }
+
+    /// A mailbox in clock order, optionally only what was stored after
+    /// `since`, recorded as delivered. Read and marked under one lock, so a
+    /// message is either handed over or still replaceable, never both.
+    pub async fn fetch_messages(&self, client_id: &str, since: Option<Hlc>) -> Result<Vec<Message>> {
+        let _timer = metrics::time(Phase::Storage);
+        self.purge_expired(client_id).await?;
+        let now = Utc::now();
+        let (mut mailbox, marked) = {
+            let mut messages = self.messages.write().await;
+            let mut fetched = Vec::new();
+            let mut marked = Vec::new();
+            for message in messages.get_mut(client_id).into_iter().flatten().filter(|m| since.is_none_or(|since| m.hlc > since)) {
+                fetched.push(message.clone());
+                if message.delivered_at.is_none() {
+                    message.delivered_at = Some(now);
+                    marked.push(message.clone());
+                }
+            }
+            (fetched, marked)
+        };
+        mailbox.sort_by_key(|m| m.hlc);
+
+        // Unsaved, the messages weren't delivered: they stay fetchable
+        if !marked.is_empty() {
+            let marked_ids: Vec<String> = marked.iter().map(|m| m.id.clone()).collect();
+            let owner = client_id.to_string();
+            let saved = self.persist("mailboxes", &self.messages, move |backend| backend.update_messages(&owner, &marked)).await;
+            if let Err(e) = saved {
+                let mut messages = self.messages.write().await;
+                for message in messages.get_mut(client_id).into_iter().flatten() {
+                    if message.delivered_at == Some(now) && marked_ids.contains(&message.id) {
+                        message.delivered_at = None;
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(mailbox)
+    }
+
+    /// Drop a mailbox's messages whose expiry has passed.
+    pub async fn purge_expired(&self, client_id: &str) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let now = Utc::now();
+        self.remove_messages(client_id, |m| m.expires_at.is_some_and(|at| at <= now)).await?;
+        Ok(())
+    }
+
+    /// Remove messages from a mailbox, with one write. Returns how many went;
+    /// ids not in the mailbox are ignored.
+    pub async fn delete_messages(&self, client_id: &str, message_ids: &[String]) -> Result<usize> {
+        let _timer = metrics::time(Phase::Storage);
+        self.remove_messages(client_id, |m| message_ids.contains(&m.id)).await
+    }
+
+    /// Remove a mailbox's messages that match, with one write. Unsaved, they
+    /// go back where they were, so memory still agrees with the disk.
+    async fn remove_messages(&self, client_id: &str, matches: impl Fn(&Message) -> bool) -> Result<usize> {
+        let removed: Vec<(usize, Message)> = {
+            let mut messages = self.messages.write().await;
+            let mailbox = messages.get_mut(client_id).map(std::mem::take).unwrap_or_default();
+            let (removed, kept): (Vec<_>, Vec<_>) = mailbox.into_iter().enumerate().partition(|(_, m)| matches(m));
+            if let Some(mailbox) = messages.get_mut(client_id) {
+                *mailbox = kept.into_iter().map(|(_, m)| m).collect();
+            }
+            removed
+        };
+        if removed.is_empty() {
+            return Ok(0);
+        }
+
+        let ids = removed.iter().map(|(_, m)| m.id.clone()).collect();
+        if let Err(e) = self.persist_deletions(HashMap::from([(client_id.to_string(), ids)])).await {
+            let mut messages = self.messages.write().await;
+            let mailbox = messages.entry(client_id.to_string()).or_default();
+            for (index, message) in removed {
+                mailbox.insert(index.min(mailbox.len()), message);
+            }
+            return Err(e);
+        }
+        Ok(removed.len())
+    }
+
+    /// Metadata of the newest messages in a mailbox matching `search`, and
+    /// whether more matched than the limit. A linear scan of the one mailbox.
+    pub async fn search_messages(&self, client_id: &str, search: &MessageSearch) -> Result<(Vec<MessageMetadata>, bool)> {
+        let _timer = metrics::time(Phase::Storage);
+        self.purge_expired(client_id).await?;
+        let messages = self.messages.read().await;
+        let mut matches: Vec<&Message> = messages.get(client_id).into_iter().flatten()
+            .filter(|m| search.matches(m))
+            .collect();
+        matches.sort_by_key(|m| std::cmp::Reverse(m.hlc));
+        let truncated = matches.len() > search.limit();
+        Ok((matches.into_iter().take(search.limit()).map(MessageMetadata::from).collect(), truncated))
+    }
+
+    /// Status of a message, reported only to the client that sent it.
+    pub async fn message_status(&self, sender_id: &str, message_id: &str) -> DeliveryStatus {
+        let _timer = metrics::time(Phase::Storage);
+        let messages = self.messages.read().await;
+        let invites = self.invites.read().await;
+        match messages.values().flatten().find(|m| m.id == message_id && m.sender_id == sender_id) {
+            Some(message) if message.delivered_at.is_some() => DeliveryStatus::Delivered,
+            Some(_) => DeliveryStatus::Stored,
+            None if invites.values().flatten().any(|m| m.id == message_id && m.sender_id == sender_id) => DeliveryStatus::Stored,
+            // Known only while the message that replaced it is still stored
+            None => match messages.values().chain(invites.values()).flatten()
+                .find(|m| m.sender_id == sender_id && m.replaced_queued && m.supersedes.as_deref() == Some(message_id)) {
+                Some(replacement) => DeliveryStatus::Superseded { by: replacement.id.clone() },
+                None => DeliveryStatus::Unknown,
+            },
+        }
+    }
+
+    /// Remove a message for its sender if the recipient has not fetched it yet.
+    /// Checked and removed under one lock so a concurrent fetch can't slip in
+    /// between. Unsaved, it goes back where it was.
+    pub async fn cancel_message(&self, sender_id: &str, message_id: &str) -> Result<DeliveryStatus> {
+        let _timer = metrics::time(Phase::Storage);
+        let in_mailbox = {
+            let mut messages = self.messages.write().await;
+            let found = messages.iter_mut().find_map(|(owner, mailbox)| {
+                mailbox.iter().position(|m| m.id == message_id && m.sender_id == sender_id)
+                    .map(|index| (owner, mailbox, index))
+            });
+            match found {
+                Some((owner, mailbox, index)) if mailbox[index].delivered_at.is_none() => {
+                    Some((owner.clone(), index, mailbox.remove(index)))
+                }
+                Some(_) => return Ok(DeliveryStatus::Delivered),
+                None => None,
+            }
+        };
+        let Some((owner, index, message)) = in_mailbox else {
+            return self.cancel_invite(sender_id, message_id).await;
+        };
+
+        if let Err(e) = self.persist_deletions(HashMap::from([(owner.clone(), vec![message_id.to_string()])])).await {
+            let mut messages = self.messages.write().await;
+            let mailbox = messages.entry(owner).or_default();
+            mailbox.insert(index.min(mailbox.len()), message);
+            return Err(e);
+        }
+        Ok(DeliveryStatus::Cancelled)
+    }
+
+    /// Remove a message still held for an unregistered id. Unsaved, it goes
+    /// back where it was.
+    async fn cancel_invite(&self, sender_id: &str, message_id: &str) -> Result<DeliveryStatus> {
+        let (recipient_id, index, message) = {
+            let mut invites = self.invites.write().await;
+            let found = invites.iter_mut().find_map(|(recipient_id, held)| {
+                held.iter().position(|m| m.id == message_id && m.sender_id == sender_id)
+                    .map(|index| (recipient_id, held, index))
+            });
+            match found {
+                Some((recipient_id, held, index)) => (recipient_id.clone(), index, held.remove(index)),
+                // Unknown, or already replaced by the sender's own newer message
+                None => {
+                    drop(invites);
+                    return Ok(self.message_status(sender_id, message_id).await);
+                }
+            }
+        };
+
+        if let Err(e) = self.save_invites().await {
+            let mut invites = self.invites.write().await;
+            let held = invites.entry(recipient_id).or_default();
+            held.insert(index.min(held.len()), message);
+            return Err(e);
+        }
+        Ok(DeliveryStatus::Cancelled)
+    }
+
+    /// Hold a message for an id nobody has registered yet. Returns whether
+    /// it replaced the held message it supersedes.
+    pub async fn hold_invite(&self, mut message: Message) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        let (recipient_id, sender_id, message_id) = (message.recipient_id.clone(), message.sender_id.clone(), message.id.clone());
+        let replaced = {
+            let messages = self.messages.read().await;
+            let mut invites = self.invites.write().await;
+            if id_in_use(&messages, &invites, &message.sender_id, &message.id) {
+                return Err(DuplicateMessageId { message_id }.into());
+            }
+            if self.seen_ids.contains(&message.sender_id, &message.id, Instant::now()) {
+                return Err(ReplayedMessageId { message_id }.into());
+            }
+            let held = invites.entry(message.recipient_id.clone()).or_default();
+            let replaced = take_superseded(held, &mut message);
+            held.push(message);
+            replaced
+        };
+
+        if let Err(e) = self.save_invites().await {
+            let mut invites = self.invites.write().await;
+            if let Some(held) = invites.get_mut(&recipient_id) {
+                held.retain(|m| !(m.id == message_id && m.sender_id == sender_id));
+                held.extend(replaced);
+            }
+            return Err(e);
+        }
+        self.seen_ids.insert(&sender_id, &message_id, Instant::now());
+        Ok(replaced.is_some())
+    }
+
+    /// Held messages from `sender_id` in all, and held messages waiting for `recipient_id`.
+    pub async fn invite_counts(&self, sender_id: &str, recipient_id: &str) -> (usize, usize) {
+        let _timer = metrics::time(Phase::Storage);
+        let invites = self.invites.read().await;
+        let from_sender = invites.values().flatten().filter(|m| m.sender_id == sender_id).count();
+        (from_sender, invites.get(recipient_id).map_or(0, Vec::len))
+    }
+
+    /// Move the messages held for a newly registered id into its mailbox,
+    /// stamped as stored now. Returns them. If either file can't be written,
+    /// they stay held, in memory and on disk.
+    pub async fn claim_invites(&self, client_id: &str) -> Result<Vec<Message>> {
+        let _timer = metrics::time(Phase::Storage);
+        // The messages move between two maps; nothing else sees them halfway
+        let _updates = self.updates.write().await;
+        let held = self.invites.write().await.remove(client_id).unwrap_or_default();
+        if held.is_empty() {
+            return Ok(held);
+        }
+
+        let claimed: Vec<Message> = match self.ticks(held.len()).await {
+            Ok(hlcs) => held.iter().zip(hlcs).map(|(message, hlc)| Message { hlc, ..message.clone() }).collect(),
+            Err(e) => {
+                self.unclaim(client_id, held, &[]).await;
+                return Err(e);
+            }
+        };
+        self.messages.write().await.entry(client_id.to_string()).or_default().extend(claimed.iter().cloned());
+        let added = claimed.clone();
+        if let Err(e) = self.persist("mailboxes", &self.messages, move |backend| added.iter().try_for_each(|message| backend.add_message(message))).await {
+            self.unclaim(client_id, held, &claimed).await;
+            return Err(e);
+        }
+        if let Err(e) = self.save_invites().await {
+            self.unclaim(client_id, held, &claimed).await;
+            // The backend has them in the mailbox already
+            let ids = claimed.iter().map(|m| m.id.clone()).collect();
+            if let Err(undo) = self.persist_deletions(HashMap::from([(client_id.to_string(), ids)])).await {
+                eout!("⚠️ Warning: Failed to restore mailboxes after a failed claim: {}", undo);
+            }
+            return Err(e);
+        }
+        Ok(claimed)
+    }
+
+    /// Undo a claim in memory: take `claimed` back out of the mailbox and
+    /// hold `held` again, ahead of anything held since.
+    async fn unclaim(&self, client_id: &str, held: Vec<Message>, claimed: &[Message]) {
+        if let Some(mailbox) = self.messages.write().await.get_mut(client_id) {
+            mailbox.retain(|m| !claimed.iter().any(|c| c.id == m.id && c.sender_id == m.sender_id));
+        }
+        self.invites.write().await.entry(client_id.to_string()).or_default().splice(0..0, held);
+    }
+
+    /// Remove held messages that were sent before `held_since` or whose own
+    /// expiry or delivery deadline passed while waiting.
+    pub async fn take_unclaimed_invites(&self, held_since: DateTime<Utc>) -> Result<Vec<Message>> {
+        let _timer = metrics::time(Phase::Storage);
+        let now = Utc::now();
+        let dropped = {
+            let mut invites = self.invites.write().await;
+            let dropped = take_matching(&mut invites, |m| {
+                m.timestamp < held_since
+                    || m.expires_at.is_some_and(|at| at <= now)
+                    || m.deliver_by.is_some_and(|by| by <= now)
+            });
+            invites.retain(|_, held| !held.is_empty());
+            dropped
+        };
+        if dropped.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if let Err(e) = self.save_invites().await {
+            put_back(&mut *self.invites.write().await, dropped);
+            return Err(e);
+        }
+        Ok(dropped.into_iter().map(|(_, _, message)| message).collect())
+    }
+
+    /// A consistent copy of the mailboxes, held invites, clients and key log.
+    /// Waits for updates spanning several of them to finish first.
+    pub async fn snapshot(&self) -> Snapshot {
+        let _timer = metrics::time(Phase::Storage);
+        let _updates = self.updates.write().await;
+        Snapshot {
+            messages: self.messages.read().await.clone(),
+            invites: self.invites.read().await.clone(),
+            clients: self.clients.read().await.clone(),
+            key_log: self.key_log.read().await.clone(),
+        }
+    }
+
+    /// Move the given messages out of mailboxes and held invites into the
+    /// quarantine file. Returns how many were found.
+    pub async fn quarantine_messages(&self, ids: &HashSet<String>) -> Result<usize> {
+        let _timer = metrics::time(Phase::Storage);
+        let _updates = self.updates.read().await;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let from_mailboxes = take_matching(&mut *self.messages.write().await, |m| ids.contains(&m.id));
+        let from_invites = take_matching(&mut *self.invites.write().await, |m| ids.contains(&m.id));
+        let count = from_mailboxes.len() + from_invites.len();
+        if count == 0 {
+            return Ok(0);
+        }
+        let start = {
+            let mut quarantine = self.quarantine.write().await;
+            let start = quarantine.len();
+            quarantine.extend(from_mailboxes.iter().chain(&from_invites).map(|(_, _, message)| message.clone()));
+            start
+        };
+
+        // Into the quarantine first, so a failure never leaves a message in
+        // neither; the backend last, as it's the one that can't be rewritten whole
+        let moved: Result<()> = async {
+            self.save_quarantine().await?;
+            if !from_invites.is_empty() {
+                self.save_invites().await?;
+            }
+            if !from_mailboxes.is_empty() {
+                self.persist_deletions(deletions(&from_mailboxes)).await?;
+            }
+            Ok(())
+        }.await;
+        if let Err(e) = moved {
+            put_back(&mut *self.messages.write().await, from_mailboxes);
+            put_back(&mut *self.invites.write().await, from_invites);
+            self.quarantine.write().await.drain(start..start + count);
+            for (component, restored) in [("quarantine", self.save_quarantine().await), ("invites", self.save_invites().await)] {
+                if let Err(undo) = restored {
+                    eout!("⚠️ Warning: Failed to restore {} after a failed quarantine: {}", component, undo);
+                }
+            }
+            return Err(e);
+        }
+        Ok(count)
+    }
+
+    pub async fn add_guest_link(&self, link: GuestLink) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let link_id = link.id.clone();
+        let previous = self.guest_links.write().await.insert(link_id.clone(), link);
+        if let Err(e) = self.save_guest_links().await {
+            let mut links = self.guest_links.write().await;
+            match previous {
+                Some(previous) => links.insert(link_id, previous),
+                None => links.remove(&link_id),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn get_guest_link(&self, link_id: &str) -> Option<GuestLink> {
+        let _timer = metrics::time(Phase::Storage);
+        self.guest_links.read().await.get(link_id).cloned()
+    }
+
+    /// An owner's guest links, oldest first.
+    pub async fn guest_links_for(&self, owner: &str) -> Vec<GuestLink> {
+        let _timer = metrics::time(Phase::Storage);
+        let mut links: Vec<GuestLink> = self.guest_links.read().await.values()
+            .filter(|link| link.owner == owner)
+            .cloned()
+            .collect();
+        links.sort_by_key(|link| link.created_at);
+        links
+    }
+
+    /// Revoke one of an owner's links. False if they have no such link.
+    pub async fn revoke_guest_link(&self, owner: &str, link_id: &str) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        {
+            let mut links = self.guest_links.write().await;
+            match links.get_mut(link_id) {
+                Some(link) if link.owner == owner && !link.revoked => link.revoked = true,
+                Some(link) if link.owner == owner => return Ok(true),
+                _ => return Ok(false),
+            }
+        }
+        if let Err(e) = self.save_guest_links().await {
+            if let Some(link) = self.guest_links.write().await.get_mut(link_id) {
+                link.revoked = false;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Count a message against a guest link. False if it has none left.
+    pub async fn use_guest_link(&self, link_id: &str) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        {
+            let mut links = self.guest_links.write().await;
+            match links.get_mut(link_id) {
+                Some(link) if link.used < link.max_messages => link.used += 1,
+                _ => return Ok(false),
+            }
+        }
+        if let Err(e) = self.save_guest_links().await {
+            if let Some(link) = self.guest_links.write().await.get_mut(link_id) {
+                link.used -= 1;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Forget guest links that expired before `cutoff`. Returns how many.
+    pub async fn prune_guest_links(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let _timer = metrics::time(Phase::Storage);
+        let pruned: Vec<GuestLink> = {
+            let mut links = self.guest_links.write().await;
+            let expired: Vec<String> = links.values().filter(|link| link.expires_at < cutoff).map(|link| link.id.clone()).collect();
+            expired.iter().filter_map(|id| links.remove(id)).collect()
+        };
+        if pruned.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = self.save_guest_links().await {
+            let mut links = self.guest_links.write().await;
+            for link in pruned {
+                links.insert(link.id.clone(), link);
+            }
+            return Err(e);
+        }
+        Ok(pruned.len())
+    }
+
+    pub async fn get_user_data(&self, client_id: &str, key: &str) -> Option<UserDataEntry> {
+        let _timer = metrics::time(Phase::Storage);
+        self.user_data.read().await.get(client_id)?.get(key).cloned()
+    }
+
+    /// Write or, with no value, delete a client's key if it is still at
+    /// `expected_version`. A client's keys and values may take up to
+    /// `quota_bytes` in all.
+    pub async fn put_user_data(&self, client_id: &str, key: &str, value: Option<String>, expected_version: u64, quota_bytes: usize) -> Result<UserDataWrite> {
+        let _timer = metrics::time(Phase::Storage);
+        let previous = self.get_user_data(client_id, key).await;
+        let outcome = {
+            let mut user_data = self.user_data.write().await;
+            let entries = user_data.entry(client_id.to_string()).or_default();
+            let current = entries.get(key).map_or(0, |entry| entry.version);
+            if current != expected_version {
+                return Ok(UserDataWrite::Conflict(current));
+            }
+            match value {
+                Some(value) => {
+                    let size = |key: &str, value: &str| key.len() + value.len() / 2;
+                    let others: usize = entries.iter()
+                        .filter(|(other, _)| other.as_str() != key)
+                        .map(|(other, entry)| size(other, &entry.value))
+                        .sum();
+                    let total = others + size(key, &value);
+                    if total > quota_bytes {
+                        return Ok(UserDataWrite::OverQuota(total));
+                    }
+                    let version = current + 1;
+                    entries.insert(key.to_string(), UserDataEntry { value, version, updated_at: Utc::now() });
+                    UserDataWrite::Written(version)
+                }
+                None => {
+                    entries.remove(key);
+                    if entries.is_empty() {
+                        user_data.remove(client_id);
+                    }
+                    UserDataWrite::Written(0)
+                }
+            }
+        };
+        if let Err(e) = self.save_user_data().await {
+            let mut user_data = self.user_data.write().await;
+            let entries = user_data.entry(client_id.to_string()).or_default();
+            match previous {
+                Some(entry) => entries.insert(key.to_string(), entry),
+                None => entries.remove(key),
+            };
+            if entries.is_empty() {
+                user_data.remove(client_id);
+            }
+            return Err(e);
+        }
+        Ok(outcome)
+    }
+
+    /// Keep a new invite code. Returns false, changing nothing, if the code
+    /// was made before.
+    pub async fn add_invite_code(&self, code: &str, created_by: &str) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        let hash = InviteCode::hash_code(code);
+        {
+            let mut codes = self.invite_codes.write().await;
+            if codes.contains_key(&hash) {
+                return Ok(false);
+            }
+            codes.insert(hash.clone(), InviteCode { created_by: created_by.to_string(), created_at: Utc::now(), used_by: None, used_at: None });
+        }
+
+        if let Err(e) = self.save_invite_codes().await {
+            self.invite_codes.write().await.remove(&hash);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Use up an invite code for `client_id`. Returns false if the code is
+    /// unknown or already used.
+    pub async fn redeem_invite_code(&self, code: &str, client_id: &str) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        let hash = InviteCode::hash_code(code);
+        {
+            let mut codes = self.invite_codes.write().await;
+            match codes.get_mut(&hash) {
+                Some(invite) if invite.used_by.is_none() => {
+                    invite.used_by = Some(client_id.to_string());
+                    invite.used_at = Some(Utc::now());
+                }
+                _ => return Ok(false),
+            }
+        }
+
+        if let Err(e) = self.save_invite_codes().await {
+            if let Some(invite) = self.invite_codes.write().await.get_mut(&hash) {
+                invite.used_by = None;
+                invite.used_at = None;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Make an invite code `client_id` used usable again, as when the
+    /// registration it was redeemed for failed. A code someone else used
+    /// is left alone.
+    pub async fn restore_invite_code(&self, code: &str, client_id: &str) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let hash = InviteCode::hash_code(code);
+        let previous = {
+            let mut codes = self.invite_codes.write().await;
+            match codes.get_mut(&hash) {
+                Some(invite) if invite.used_by.as_deref() == Some(client_id) => {
+                    (invite.used_by.take(), invite.used_at.take())
+                }
+                _ => return Ok(()),
+            }
+        };
+
+        if let Err(e) = self.save_invite_codes().await {
+            if let Some(invite) = self.invite_codes.write().await.get_mut(&hash) {
+                (invite.used_by, invite.used_at) = previous;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Store a grant, replacing the owner's earlier one to the same delegate.
+    pub async fn set_delegation(&self, delegation: Delegation) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let previous = {
+            let mut delegations = self.delegations.write().await;
+            let grants = delegations.entry(delegation.owner.clone()).or_default();
+            let previous = grants.iter().position(|d| d.delegate == delegation.delegate).map(|i| grants.remove(i));
+            grants.push(delegation.clone());
+            previous
+        };
+
+        if let Err(e) = self.save_delegations().await {
+            let mut delegations = self.delegations.write().await;
+            let grants = delegations.entry(delegation.owner.clone()).or_default();
+            grants.retain(|d| d.delegate != delegation.delegate);
+            grants.extend(previous);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Mark an owner's grant to `delegate` revoked. The grant is kept so an
+    /// old signed statement can't be replayed to bring it back. False if
+    /// there was no live grant.
+    pub async fn revoke_delegation(&self, owner: &str, delegate: &str) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        {
+            let mut delegations = self.delegations.write().await;
+            let grant = delegations.get_mut(owner)
+                .and_then(|grants| grants.iter_mut().find(|d| d.delegate == delegate));
+            match grant {
+                Some(grant) if grant.revoked_at.is_none() => grant.revoked_at = Some(Utc::now()),
+                _ => return Ok(false),
+            }
+        }
+
+        if let Err(e) = self.save_delegations().await {
+            if let Some(grant) = self.delegations.write().await.get_mut(owner)
+                .and_then(|grants| grants.iter_mut().find(|d| d.delegate == delegate)) {
+                grant.revoked_at = None;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// The owner's grant to `delegate`, even if expired or revoked.
+    pub async fn get_delegation(&self, owner: &str, delegate: &str) -> Option<Delegation> {
+        let _timer = metrics::time(Phase::Storage);
+        self.delegations.read().await.get(owner)?
+            .iter()
+            .find(|d| d.delegate == delegate)
+            .cloned()
+    }
+
+    pub async fn delegations_for(&self, owner: &str) -> Vec<Delegation> {
+        let _timer = metrics::time(Phase::Storage);
+        self.delegations.read().await.get(owner).cloned().unwrap_or_default()
+    }
+
+    /// Note something a delegate did, for the owner to review.
+    pub async fn record_delegated_action(&self, entry: DelegationAudit) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let owner = entry.owner.clone();
+        let previous = {
+            let mut audit = self.delegation_audit.write().await;
+            let entries = audit.entry(owner.clone()).or_default();
+            let previous = entries.clone();
+            entries.push(entry);
+            let excess = entries.len().saturating_sub(MAX_AUDIT_ENTRIES);
+            entries.drain(..excess);
+            previous
+        };
+        if let Err(e) = self.save_delegation_audit().await {
+            let mut audit = self.delegation_audit.write().await;
+            if previous.is_empty() {
+                audit.remove(&owner);
+            } else {
+                audit.insert(owner, previous);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn delegation_audit_for(&self, owner: &str) -> Vec<DelegationAudit> {
+        let _timer = metrics::time(Phase::Storage);
+        self.delegation_audit.read().await.get(owner).cloned().unwrap_or_default()
+    }
+
+    /// Store a new group. False if the name is taken.
+    pub async fn create_group(&self, group: Group) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        let name = group.name.clone();
+        match self.groups.write().await.entry(name.clone()) {
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(entry) => { entry.insert(group); }
+        }
+        if let Err(e) = self.save_groups().await {
+            self.groups.write().await.remove(&name);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    pub async fn get_group(&self, name: &str) -> Option<Group> {
+        let _timer = metrics::time(Phase::Storage);
+        self.groups.read().await.get(name).cloned()
+    }
+
+    /// The groups `client_id` is a member of, by name.
+    pub async fn groups_for(&self, client_id: &str) -> Vec<Group> {
+        let _timer = metrics::time(Phase::Storage);
+        let mut groups: Vec<Group> = self.groups.read().await.values()
+            .filter(|group| group.members.contains_key(client_id))
+            .cloned()
+            .collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups
+    }
+
+    /// Give a group a new name. False if the group is gone or the new name
+    /// is taken.
+    pub async fn rename_group(&self, name: &str, new_name: &str) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        {
+            let mut groups = self.groups.write().await;
+            if groups.contains_key(new_name) {
+                return Ok(false);
+            }
+            let Some(mut group) = groups.remove(name) else { return Ok(false) };
+            group.name = new_name.to_string();
+            groups.insert(new_name.to_string(), group);
+        }
+        if let Err(e) = self.save_groups().await {
+            let mut groups = self.groups.write().await;
+            if let Some(mut group) = groups.remove(new_name) {
+                group.name = name.to_string();
+                groups.insert(name.to_string(), group);
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Take `client_id` out of a group. The last member out removes it, and
+    /// when the last owner leaves the longest-standing member takes over.
+    /// False if the client wasn't a member.
+    pub async fn leave_group(&self, name: &str, client_id: &str) -> Result<bool> {
+        let _timer = metrics::time(Phase::Storage);
+        let previous = {
+            let mut groups = self.groups.write().await;
+            let Some(group) = groups.get_mut(name) else { return Ok(false) };
+            if !group.members.contains_key(client_id) {
+                return Ok(false);
+            }
+            let previous = group.clone();
+            group.members.remove(client_id);
+            group.joined_at.remove(client_id);
+            if group.members.is_empty() {
+                groups.remove(name);
+            } else if !group.members.values().any(|role| *role == GroupRole::Owner) {
+                let joined = |id: &String| group.joined_at.get(id).copied().unwrap_or(group.created_at);
+                if let Some(heir) = group.members.keys().min_by_key(|id| (joined(id), (*id).clone())).cloned() {
+                    group.members.insert(heir, GroupRole::Owner);
+                }
+            }
+            previous
+        };
+        if let Err(e) = self.save_groups().await {
+            self.groups.write().await.insert(name.to_string(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Everything kept about one client, gathered from each component under
+    /// its own read lock. Quotas are the server's to fill in.
+    pub async fn account_usage(&self, client_id: &str) -> AccountUsage {
+        let _timer = metrics::time(Phase::Storage);
+        let now = Utc::now();
+        let mut usage = AccountUsage { client_id: client_id.to_string(), ..AccountUsage::default() };
+        if let Some(client) = self.clients.read().await.get(client_id) {
+            usage.registered_at = Some(client.registered_at);
+            usage.last_seen = Some(client.last_seen);
+        }
+        {
+            let messages = self.messages.read().await;
+            let stored: Vec<&Message> = messages.get(client_id).into_iter().flatten().filter(|m| m.notice.is_none()).collect();
+            usage.stored_messages = stored.len();
+            usage.stored_bytes = stored.iter().map(|m| m.content.len() as u64 / 2).sum();
+            usage.oldest_stored = stored.iter().map(|m| m.timestamp).min();
+            usage.newest_stored = stored.iter().map(|m| m.timestamp).max();
+            usage.sent_undelivered = messages.values().flatten()
+                .filter(|m| m.sender_id == client_id && m.delivered_at.is_none())
+                .count();
+        }
+        usage.sent_held = self.invites.read().await.values().flatten().filter(|m| m.sender_id == client_id).count();
+        usage.guest_links = self.guest_links.read().await.values()
+            .filter(|link| link.owner == client_id && !link.revoked && link.expires_at > now)
+            .count();
+        if let Some(entries) = self.user_data.read().await.get(client_id) {
+            usage.user_data_keys = entries.len();
+            usage.user_data_bytes = entries.iter().map(|(key, entry)| key.len() + entry.value.len() / 2).sum();
+        }
+        {
+            let delegations = self.delegations.read().await;
+            usage.delegations_granted = delegations.get(client_id).cloned().unwrap_or_default();
+            usage.delegations_received = delegations.values().flatten().filter(|d| d.delegate == client_id).cloned().collect();
+        }
+        usage
+    }
+
+    /// Remove every message whose delivery deadline passed before it was fetched.
+    pub async fn take_undelivered(&self) -> Result<Vec<Message>> {
+        let _timer = metrics::time(Phase::Storage);
+        let now = Utc::now();
+        let bounced = take_matching(&mut *self.messages.write().await, |m| m.delivered_at.is_none() && m.deliver_by.is_some_and(|by| by <= now));
+        if bounced.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if let Err(e) = self.persist_deletions(deletions(&bounced)).await {
+            put_back(&mut *self.messages.write().await, bounced);
+            return Err(e);
+        }
+        Ok(bounced.into_iter().map(|(_, _, message)| message).collect())
+    }
+
+    pub async fn set_retention(&self, client_id: &str, sender_id: &str, ttl_secs: Option<u64>) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let set = |retention: &mut HashMap<String, HashMap<String, u64>>, ttl_secs: Option<u64>| {
+            let policies = retention.entry(client_id.to_string()).or_default();
+            match ttl_secs {
+                Some(ttl) => policies.insert(sender_id.to_string(), ttl),
+                None => policies.remove(sender_id),
+            }
+        };
+        let previous = set(&mut *self.retention.write().await, ttl_secs);
+
+        if let Err(e) = self.save_retention().await {
+            set(&mut *self.retention.write().await, previous);
+            return Err(e);
+        }
         Ok(())
     }
 
-    pub async fn get_messages_for_client(&self, client_id: &str) -> Result<Vec<Message>> {
-        let messages = self.messages.read().await;
-        Ok(messages.get(client_id).cloned().unwrap_or_default())
+    pub async fn get_retention(&self, client_id: &str, sender_id: &str) -> Option<u64> {
+        let _timer = metrics::time(Phase::Storage);
+        let retention = self.retention.read().await;
+        retention.get(client_id).and_then(|policies| policies.get(sender_id)).copied()
     }
 
-    pub async fn register_client(&self, client_id: String, public_key: String) -> Result<()> {
-        let mut clients = self.clients.write().await;
+    /// Register or re-register a client. `registration` is only kept the
+    /// first time, so it always describes how the id came to exist. Without
+    /// `x25519_key` the one registered before is kept, as long as the
+    /// identity key is the same.
+    pub async fn register_client(&self, client_id: String, public_key: String, recovery_key: Option<String>, x25519_key: Option<SenderKey>, registration: Registration) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let _updates = self.updates.read().await;
+        let previous = self.get_client_info(&client_id).await;
+        if previous.as_ref().map(|info| info.public_key.as_str()) != Some(public_key.as_str()) {
+            self.append_key_log(&client_id, &public_key, KeyEvent::Registered).await?;
+        }
+        let registration = previous.as_ref().and_then(|info| info.registration.clone()).unwrap_or(registration);
+        let x25519_key = x25519_key.or_else(|| previous.as_ref()
+            .filter(|info| info.public_key == public_key)
+            .and_then(|info| info.x25519_key.clone()));
+
         let client_info = ClientInfo {
             id: client_id.clone(),
             public_key,
+            recovery_key,
             registered_at: Utc::now(),
             last_seen: Utc::now(),
+            registration: Some(registration),
+            x25519_key,
+        };
+        let changed = match &previous {
+            None => Some(DirectoryChangeKind::Added),
+            Some(info) if info.public_key != client_info.public_key => Some(DirectoryChangeKind::Changed),
+            Some(_) => None,
         };
-        clients.insert(client_id, client_info);
+        self.clients.write().await.insert(client_id.clone(), client_info.clone());
         
         // Save to disk
-        self.save_clients().await?;
+        if let Err(e) = self.persist("clients", &self.clients, move |backend| backend.register_client(&client_info)).await {
+            let mut clients = self.clients.write().await;
+            match previous {
+                Some(info) => clients.insert(client_id, info),
+                None => clients.remove(&client_id),
+            };
+            return Err(e);
+        }
+        match changed {
+            Some(kind) => self.record_directory_change(&client_id, kind).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn is_banned(&self, client_id: &str) -> bool {
+        let _timer = metrics::time(Phase::Storage);
+        self.banned.read().await.contains_key(client_id)
+    }
+
+    /// Apply `ops` all together or not at all. They are staged in memory
+    /// with every other update shut out, then each touched file is written
+    /// once; if an op or a write fails, memory and the files written so far
+    /// go back to how they were. Returns how many things each op changed.
+    pub async fn transaction(&self, ops: &[BatchOp]) -> Result<Vec<usize>, BatchFailed> {
+        let _timer = metrics::time(Phase::Storage);
+        let _updates = self.updates.write().await;
+        let mut messages = self.messages.write().await;
+        let mut banned = self.banned.write().await;
+        let mut codes = self.invite_codes.write().await;
+        let touches_messages = ops.iter().any(|op| matches!(op, BatchOp::PruneMailbox { .. } | BatchOp::Deliver(_)));
+        let before_messages = touches_messages.then(|| messages.clone());
+        let before_banned = banned.clone();
+        let before_codes = codes.clone();
+
+        // What the backend is told: mailbox owner -> pruned messages, and delivered messages
+        let mut pruned: Vec<(String, Vec<Message>)> = Vec::new();
+        let mut delivered: Vec<Message> = Vec::new();
+        let mut counts = Vec::with_capacity(ops.len());
+        let mut failed = None;
+        for (index, op) in ops.iter().enumerate() {
+            let applied = match op {
+                BatchOp::Ban { client_id, ban } => {
+                    Ok(usize::from(banned.insert(client_id.clone(), ban.clone()).is_none()))
+                }
+                BatchOp::Unban { client_id } => match banned.remove(client_id) {
+                    Some(_) => Ok(1),
+                    None => Err(format!("{} isn't banned", client_id)),
+                },
+                BatchOp::PruneMailbox { client_id } => {
+                    let mut mailbox = messages.remove(client_id).unwrap_or_default();
+                    let count = mailbox.len();
+                    // Messages delivered earlier in the batch never reach the backend
+                    let (fresh, kept): (Vec<Message>, _) = delivered.drain(..).partition(|m| m.recipient_id == *client_id);
+                    delivered = kept;
+                    mailbox.retain(|m| !fresh.iter().any(|f| f.id == m.id && f.sender_id == m.sender_id));
+                    pruned.push((client_id.clone(), mailbox));
+                    Ok(count)
+                }
+                BatchOp::Deliver(message) => match self.tick().await {
+                    Ok(hlc) => {
+                        let message = Message { hlc, ..(**message).clone() };
+                        messages.entry(message.recipient_id.clone()).or_default().push(message.clone());
+                        delivered.push(message);
+                        Ok(1)
+                    }
+                    Err(e) => Err(e.to_string()),
+                },
+                BatchOp::AddInviteCode { code, created_by } => {
+                    let hash = InviteCode::hash_code(code);
+                    match codes.entry(hash) {
+                        Entry::Occupied(_) => Err("that invite code was made before".to_string()),
+                        Entry::Vacant(entry) => {
+                            entry.insert(InviteCode { created_by: created_by.clone(), created_at: Utc::now(), used_by: None, used_at: None });
+                            Ok(1)
+                        }
+                    }
+                }
+            };
+            match applied {
+                Ok(count) => counts.push(count),
+                Err(reason) => {
+                    failed = Some(BatchFailed { op: Some(index), reason });
+                    break;
+                }
+            }
+        }
+
+        // Persist each touched component once, remembering what was written
+        let mut written: Vec<(&str, String)> = Vec::new();
+        if failed.is_none() && touches_messages {
+            let change = (pruned.clone(), delivered.clone());
+            let stored = match self.replica_contents(&*messages) {
+                Ok(contents) => self.persist_locked("mailboxes", contents, move |backend| apply_batch(backend, &change.0, &change.1)).await,
+                Err(e) => Err(e),
+            };
+            match stored {
+                Ok(()) => written.push(("mailboxes", String::new())),
+                Err(e) => failed = Some(BatchFailed { op: None, reason: e.to_string() }),
+            }
+        }
+        if failed.is_none() {
+            let mut staged: Vec<(&str, &str, serde_json::Result<String>)> = Vec::new();
+            if *banned != before_banned {
+                staged.push(("banned", "banned.json", serde_json::to_string_pretty(&*banned)));
+            }
+            if codes.len() != before_codes.len() {
+                staged.push(("invite_codes", "invite_codes.json", serde_json::to_string_pretty(&*codes)));
+            }
+            for (component, file, json) in staged {
+                let path = format!("{}/{}", self.data_dir, file);
+                let result = match json {
+                    Ok(json) => self.write_data(component, &path, json).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    failed = Some(BatchFailed { op: None, reason: e.to_string() });
+                    break;
+                }
+                written.push((component, path));
+            }
+        }
+
+        let Some(failed) = failed else { return Ok(counts) };
+        if let Some(before) = before_messages {
+            *messages = before;
+        }
+        *banned = before_banned;
+        *codes = before_codes;
+        for (component, path) in written {
+            if component == "mailboxes" {
+                // Undo in reverse: take the delivered messages back out, then return the pruned ones
+                let undo = (delivered.clone(), pruned.clone());
+                let restored = match self.replica_contents(&*messages) {
+                    Ok(contents) => self.persist_locked(component, contents, move |backend| undo_batch(backend, &undo.0, &undo.1)).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = restored {
+                    eout!("⚠️ Warning: Failed to restore {} after a failed batch: {}", component, e);
+                }
+                continue;
+            }
+            let json = match component {
+                "banned" => serde_json::to_string_pretty(&*banned),
+                _ => serde_json::to_string_pretty(&*codes),
+            };
+            let restored = match json {
+                Ok(json) => self.write_data(component, &path, json).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = restored {
+                eout!("⚠️ Warning: Failed to restore {} after a failed batch: {}", component, e);
+            }
+        }
+        Err(failed)
+    }
+
+    /// Log a change to the client list under the next revision, dropping
+    /// the oldest changes once there are too many.
+    async fn record_directory_change(&self, client_id: &str, kind: DirectoryChangeKind) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let previous = {
+            let mut directory = self.directory.write().await;
+            let previous = directory.clone();
+            directory.revision += 1;
+            let revision = directory.revision;
+            directory.changes.push(DirectoryChange { revision, client_id: client_id.to_string(), kind });
+            if directory.changes.len() > MAX_DIRECTORY_CHANGES {
+                let dropped = directory.changes.len() - MAX_DIRECTORY_CHANGES;
+                directory.compacted_through = directory.changes[dropped - 1].revision;
+                directory.changes.drain(..dropped);
+            }
+            previous
+        };
+
+        if let Err(e) = self.save_directory().await {
+            *self.directory.write().await = previous;
+            return Err(e);
+        }
         Ok(())
     }
 
+    /// Directory changes after `since`, at most `DIRECTORY_CHUNK` of them.
+    /// A client from before the oldest kept change, or claiming a revision
+    /// this server never reached, gets the whole list instead.
+    pub async fn directory_delta(&self, since: u64) -> DirectoryDelta {
+        let _timer = metrics::time(Phase::Storage);
+        let directory = self.directory.read().await;
+        if since < directory.compacted_through || since > directory.revision {
+            let clients = self.clients.read().await;
+            let mut ids: Vec<&String> = clients.keys().collect();
+            ids.sort();
+            return DirectoryDelta {
+                revision: directory.revision,
+                reset: true,
+                changes: ids.into_iter().map(|id| DirectoryChange {
+                    revision: directory.revision,
+                    client_id: id.clone(),
+                    kind: DirectoryChangeKind::Added,
+                }).collect(),
+                more: false,
+            };
+        }
+        let start = directory.changes.partition_point(|change| change.revision <= since);
+        let changes: Vec<DirectoryChange> = directory.changes[start..].iter().take(DIRECTORY_CHUNK).cloned().collect();
+        let more = directory.changes.len() - start > changes.len();
+        DirectoryDelta {
+            revision: changes.last().map_or(directory.revision, |change| change.revision),
+            reset: false,
+            changes,
+            more,
+        }
+    }
+
     pub async fn update_client_last_seen(&self, client_id: &str) -> Result<()> {
-        let mut clients = self.clients.write().await;
-        if let Some(client_info) = clients.get_mut(client_id) {
-            client_info.last_seen = Utc::now();
+        let _timer = metrics::time(Phase::Storage);
+        let now = Utc::now();
+        let before = match self.clients.write().await.get_mut(client_id) {
+            Some(client_info) => std::mem::replace(&mut client_info.last_seen, now),
+            None => return Ok(()),
+        };
+
+        // Save to disk, once the write lock is gone: saving takes a read lock
+        let id = client_id.to_string();
+        if let Err(e) = self.persist("clients", &self.clients, move |backend| backend.update_last_seen(&id, now)).await {
+            if let Some(client_info) = self.clients.write().await.get_mut(client_id).filter(|info| info.last_seen == now) {
+                client_info.last_seen = before;
+            }
+            return Err(e);
         }
-        
-        // Save to disk
-        self.save_clients().await?;
         Ok(())
     }
 
+    /// A source address in a form that can be matched against other
+    /// registrations on this server, but not read back.
+    pub fn hash_source(&self, addr: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.source_salt.as_bytes());
+        hasher.update(addr.as_bytes());
+        format!("sha256:{}", hex::encode(&hasher.finalize()[..16]))
+    }
+
     pub async fn get_client_info(&self, client_id: &str) -> Option<ClientInfo> {
+        let _timer = metrics::time(Phase::Storage);
         let clients = self.clients.read().await;
         clients.get(client_id).cloned()
     }
 
     pub async fn get_all_clients(&self) -> Vec<String> {
+        let _timer = metrics::time(Phase::Storage);
         let clients = self.clients.read().await;
         clients.keys().cloned().collect()
     }
 
-    async fn save_messages(&self) -> Result<()> {
-        let messages = self.messages.read().await;
-        let messages_path = format!("{}/messages.json", self.data_dir);
-        let json = serde_json::to_string_pretty(&*messages)?;
-        fs::write(messages_path, json)?;
+    pub async fn add_revocation(&self, revocation: Revocation) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let _updates = self.updates.read().await;
+        {
+            let mut revocations = self.revocations.write().await;
+            revocations.entry(revocation.client_id.clone()).or_default().push(revocation.clone());
+        }
+
+        if let Err(e) = self.save_revocations().await {
+            if let Some(list) = self.revocations.write().await.get_mut(&revocation.client_id) {
+                list.retain(|r| r.signature != revocation.signature);
+            }
+            return Err(e);
+        }
+        // Saved, the revocation stands even if logging it fails: a key is
+        // better revoked without a log entry than trusted again
+        self.append_key_log(&revocation.client_id, &revocation.key, KeyEvent::Revoked).await?;
+        self.record_directory_change(&revocation.client_id, DirectoryChangeKind::Changed).await
+    }
+
+    pub async fn get_revocations(&self, client_id: &str) -> Vec<Revocation> {
+        let _timer = metrics::time(Phase::Storage);
+        let revocations = self.revocations.read().await;
+        revocations.get(client_id).cloned().unwrap_or_default()
+    }
+
+    pub async fn is_key_revoked(&self, public_key: &str) -> bool {
+        let _timer = metrics::time(Phase::Storage);
+        let revocations = self.revocations.read().await;
+        revocations.values().flatten().any(|r| r.key == public_key)
+    }
+
+    async fn append_key_log(&self, client_id: &str, public_key: &str, event: KeyEvent) -> Result<()> {
+        let _timer = metrics::time(Phase::Storage);
+        let hash = {
+            let mut key_log = self.key_log.write().await;
+            let mut entry = KeyLogEntry {
+                seq: key_log.len() as u64,
+                client_id: client_id.to_string(),
+                public_key: public_key.to_string(),
+                event,
+                timestamp: Utc::now(),
+                prev_hash: key_log.last().map(|e| e.hash.clone()).unwrap_or_default(),
+                hash: String::new(),
+            };
+            entry.hash = entry.compute_hash();
+            key_log.push(entry.clone());
+            entry.hash
+        };
+
+        if let Err(e) = self.save_key_log().await {
+            let mut key_log = self.key_log.write().await;
+            if key_log.last().is_some_and(|last| last.hash == hash) {
+                key_log.pop();
+            }
+            return Err(e);
+        }
         Ok(())
     }
 
-    async fn save_clients(&self) -> Result<()> {
-        let clients = self.clients.read().await;
-        let clients_path = format!("{}/clients.json", self.data_dir);
-        let json = serde_json::to_string_pretty(&*clients)?;
-        fs::write(clients_path, json)?;
+    /// Current length of the key log; recorded on messages as their key epoch.
+    pub async fn key_epoch(&self) -> u64 {
+        self.key_log.read().await.len() as u64
+    }
+
+    pub async fn get_key_log(&self) -> Vec<KeyLogEntry> {
+        let _timer = metrics::time(Phase::Storage);
+        self.key_log.read().await.clone()
+    }
+
+    /// Bytes on disk per component, as tracked through writes.
+    pub async fn disk_usage(&self) -> BTreeMap<String, u64> {
+        self.disk_usage.read().await.clone()
+    }
+
+    pub async fn total_disk_usage(&self) -> u64 {
+        self.disk_usage.read().await.values().sum()
+    }
+
+    /// Re-measure the data files and correct the tracked sizes. Returns the
+    /// components that had drifted, with their tracked and actual sizes.
+    pub async fn reconcile_disk_usage(&self) -> Vec<(String, u64, u64)> {
+        let _timer = metrics::time(Phase::Storage);
+        let actual = measure_data_files(&self.data_dir, &*self.backend);
+        let mut tracked = self.disk_usage.write().await;
+        let drifted = actual.iter()
+            .filter_map(|(component, &bytes)| {
+                let was = tracked.get(component).copied().unwrap_or(0);
+                (was != bytes).then(|| (component.clone(), was, bytes))
+            })
+            .collect();
+        *tracked = actual;
+        drifted
+    }
+
+    /// Write a data file on the blocking pool, so a slow disk stalls only
+    /// the request that's saving rather than a runtime worker, and record
+    /// its new size under `component`. Fails with `StorageUnavailable`.
+    async fn write_data(&self, component: &str, path: &str, contents: String) -> Result<()> {
+        let bytes = contents.len() as u64;
+        let started = std::time::Instant::now();
+        let changes = self.changes.clone()
+            .filter(|_| DATA_FILES.iter().any(|(name, _)| *name == component));
+        let (data_dir, component_name, path) = (self.data_dir.clone(), component.to_string(), path.to_string());
+        let written = tokio::task::spawn_blocking(move || match changes {
+            Some(changes) => changes.record(&component_name, contents, |contents| write_file(&data_dir, &component_name, &path, contents)),
+            None => write_file(&data_dir, &component_name, &path, contents),
+        })
+        .await
+        .unwrap_or_else(|e| Err(anyhow!("write task failed: {}", e)));
+        self.persist.lock().unwrap().entry(component.to_string()).or_default()
+            .record(started.elapsed(), bytes, written.is_ok());
+        written.map_err(|e| StorageUnavailable {
+            component: component.to_string(),
+            reason: e.to_string(),
+        })?;
+        self.disk_usage.write().await.insert(component.to_string(), bytes);
+        Ok(())
+    }
+
+    /// Check that data can be written again, without touching any data file.
+    pub async fn probe(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let path = format!("{}/write_probe", self.data_dir);
+        self.write_data("probe", &path, Utc::now().to_rfc3339()).await?;
+        self.disk_usage.write().await.remove("probe");
+        tokio::fs::remove_file(&path).await?;
         Ok(())
     }
 
-    fn load_data(&self) -> Result<()> {
+    /// Have the backend put messages and clients on disk once more, with no
+    /// update half done, so a shutdown leaves both whole and current.
+    pub async fn flush(&self) -> Result<()> {
+        let _updates = self.updates.write().await;
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || backend.flush())
+            .await
+            .unwrap_or_else(|e| Err(anyhow!("flush task failed: {}", e)))
+    }
+
+    /// Have the backend compact the mailboxes that have grown enough since
+    /// it last did. Returns how many it compacted.
+    pub async fn compact_mailboxes(&self) -> Result<usize> {
+        let _timer = metrics::time(Phase::Persist);
+        let backend = self.backend.clone();
+        let compacted = tokio::task::spawn_blocking(move || backend.compact())
+            .await
+            .unwrap_or_else(|e| Err(anyhow!("compaction task failed: {}", e)))?;
+        if compacted > 0 {
+            let mut tracked = self.disk_usage.write().await;
+            for (component, bytes) in self.backend.disk_usage() {
+                tracked.insert(component.to_string(), bytes);
+            }
+        }
+        Ok(compacted)
+    }
+
+    /// Make a change in the backend, with `slot`'s lock held for reading
+    /// the way a data file is written, so standbys get its contents.
+    async fn persist<S: Serialize, T: Send + 'static>(&self, component: &str, slot: &TimedRwLock<S>, change: impl FnOnce(&dyn StorageBackend) -> Result<T> + Send + 'static) -> Result<T> {
+        let _timer = metrics::time(Phase::Persist);
+        let slot = slot.read().await;
+        let contents = self.replica_contents(&*slot)?;
+        self.persist_locked(component, contents, change).await
+    }
+
+    /// Make a change in the backend on the blocking pool, recording
+    /// `contents` for standbys when they may follow. Counted and reported
+    /// like `write_data`: fails with `StorageUnavailable`.
+    async fn persist_locked<T: Send + 'static>(&self, component: &str, contents: Option<String>, change: impl FnOnce(&dyn StorageBackend) -> Result<T> + Send + 'static) -> Result<T> {
+        let started = std::time::Instant::now();
+        let (backend, data_dir, component_name) = (self.backend.clone(), self.data_dir.clone(), component.to_string());
+        let changes = self.changes.clone().zip(contents);
+        let changed = tokio::task::spawn_blocking(move || {
+            fail_if_injected(&data_dir, &component_name)?;
+            match changes {
+                Some((changes, contents)) => {
+                    let mut changed = None;
+                    changes.record(&component_name, contents, |_| {
+                        changed = Some(change(&*backend)?);
+                        Ok(())
+                    })?;
+                    changed.ok_or_else(|| anyhow!("change was not applied"))
+                }
+                None => change(&*backend),
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(anyhow!("write task failed: {}", e)));
+
+        let usage = self.backend.disk_usage();
+        let bytes = usage.iter().find(|(name, _)| *name == component).map_or(0, |(_, bytes)| *bytes);
+        self.persist.lock().unwrap().entry(component.to_string()).or_default()
+            .record(started.elapsed(), bytes, changed.is_ok());
+        let changed = changed.map_err(|e| StorageUnavailable {
+            component: component.to_string(),
+            reason: e.to_string(),
+        })?;
+        let mut tracked = self.disk_usage.write().await;
+        for (name, bytes) in usage {
+            tracked.insert(name.to_string(), bytes);
+        }
+        Ok(changed)
+    }
+
+    /// A component's whole contents for standbys, if they may follow.
+    fn replica_contents<S: Serialize>(&self, value: &S) -> Result<Option<String>> {
+        Ok(self.changes.as_ref().map(|_| serde_json::to_string_pretty(value)).transpose()?)
+    }
+
+    /// Remove messages from the backend: mailbox owner -> message ids.
+    async fn persist_deletions(&self, deleted: HashMap<String, Vec<String>>) -> Result<()> {
+        self.persist("mailboxes", &self.messages, move |backend| {
+            deleted.iter().try_for_each(|(owner, ids)| backend.delete_messages(owner, ids).map(|_| ()))
+        }).await
+    }
+
+    async fn save_revocations(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let revocations = self.revocations.read().await;
+        let revocations_path = format!("{}/revocations.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*revocations)?;
+        self.write_data("revocations", &revocations_path, json).await
+    }
+
+    async fn save_key_log(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let key_log = self.key_log.read().await;
+        let key_log_path = format!("{}/key_log.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*key_log)?;
+        self.write_data("key_log", &key_log_path, json).await
+    }
+
+    async fn save_retention(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let retention = self.retention.read().await;
+        let retention_path = format!("{}/retention.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*retention)?;
+        self.write_data("retention", &retention_path, json).await
+    }
+
+    async fn save_invites(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let invites = self.invites.read().await;
+        let invites_path = format!("{}/invites.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*invites)?;
+        self.write_data("invites", &invites_path, json).await
+    }
+
+    async fn save_quarantine(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let quarantine = self.quarantine.read().await;
+        let quarantine_path = format!("{}/quarantine.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*quarantine)?;
+        self.write_data("quarantine", &quarantine_path, json).await
+    }
+
+    async fn save_guest_links(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let guest_links = self.guest_links.read().await;
+        let guest_links_path = format!("{}/guest_links.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*guest_links)?;
+        self.write_data("guest_links", &guest_links_path, json).await
+    }
+
+    async fn save_user_data(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let user_data = self.user_data.read().await;
+        let user_data_path = format!("{}/user_data.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*user_data)?;
+        self.write_data("user_data", &user_data_path, json).await
+    }
+
+    async fn save_directory(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let directory = self.directory.read().await;
+        let directory_path = format!("{}/directory.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*directory)?;
+        self.write_data("directory", &directory_path, json).await
+    }
+
+    async fn save_invite_codes(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let invite_codes = self.invite_codes.read().await;
+        let invite_codes_path = format!("{}/invite_codes.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*invite_codes)?;
+        self.write_data("invite_codes", &invite_codes_path, json).await
+    }
+
+    async fn save_delegations(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let delegations = self.delegations.read().await;
+        let delegations_path = format!("{}/delegations.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*delegations)?;
+        self.write_data("delegations", &delegations_path, json).await
+    }
+
+    async fn save_delegation_audit(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let delegation_audit = self.delegation_audit.read().await;
+        let delegation_audit_path = format!("{}/delegation_audit.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*delegation_audit)?;
+        self.write_data("delegation_audit", &delegation_audit_path, json).await
+    }
+
+    async fn save_groups(&self) -> Result<()> {
+        let _timer = metrics::time(Phase::Persist);
+        let groups = self.groups.read().await;
+        let groups_path = format!("{}/groups.json", self.data_dir);
+        let json = serde_json::to_string_pretty(&*groups)?;
+        self.write_data("groups", &groups_path, json).await
+    }
+
+    async fn load_data(&self) -> Result<()> {
+        // Load the clock first; stored messages can only move it forward
+        let clock_path = format!("{}/clock.json", self.data_dir);
+        if Path::new(&clock_path).exists() {
+            match tokio::fs::read_to_string(&clock_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<Hlc>(&content) {
+                        Ok(reserved) => {
+                            let mut clock_guard = self.clock.write().await;
+                            *clock_guard = Clock::from(reserved);
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse clock file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read clock file: {}", e),
+            }
+        }
+
         // Load messages
-        let messages_path = format!("{}/messages.json", self.data_dir);
-        if Path::new(&messages_path).exists() {
-            match fs::read_to_string(&messages_path) {
+        match self.backend.get_all_messages() {
+            Err(e) => eout!("⚠️ Warning: Failed to load messages from {} storage: {}", self.backend.name(), e),
+            Ok(mut messages) => {
+                // Messages from before the clock existed order by their timestamp
+                for message in messages.values_mut().flatten().filter(|m| m.hlc.is_unset()) {
+                    message.hlc = Hlc::from_timestamp(message.timestamp);
+                }
+                let newest = messages.values().flatten().map(|m| m.hlc).max().unwrap_or_default();
+                let mut clock_guard = self.clock.write().await;
+                clock_guard.last = clock_guard.last.max(newest);
+                drop(clock_guard);
+                let mut messages_guard = self.messages.write().await;
+                *messages_guard = messages;
+            }
+        }
+
+        // Load clients
+        match self.backend.get_all_clients() {
+            Ok(clients) => *self.clients.write().await = clients,
+            Err(e) => eout!("⚠️ Warning: Failed to load clients from {} storage: {}", self.backend.name(), e),
+        }
+
+        // Load revocations
+        let revocations_path = format!("{}/revocations.json", self.data_dir);
+        if Path::new(&revocations_path).exists() {
+            match tokio::fs::read_to_string(&revocations_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, Vec<Revocation>>>(&content) {
+                        Ok(revocations) => {
+                            let mut revocations_guard = self.revocations.write().await;
+                            *revocations_guard = revocations;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse revocations file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read revocations file: {}", e),
+            }
+        }
+
+        // Load key log
+        let key_log_path = format!("{}/key_log.json", self.data_dir);
+        if Path::new(&key_log_path).exists() {
+            match tokio::fs::read_to_string(&key_log_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<Vec<KeyLogEntry>>(&content) {
+                        Ok(key_log) => {
+                            if let Err(e) = KeyLogEntry::verify_chain(&key_log) {
+                                eout!("⚠️ Warning: Key log chain is broken: {}", e);
+                            }
+                            let mut key_log_guard = self.key_log.write().await;
+                            *key_log_guard = key_log;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse key log file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read key log file: {}", e),
+            }
+        }
+
+        // Load retention policies
+        let retention_path = format!("{}/retention.json", self.data_dir);
+        if Path::new(&retention_path).exists() {
+            match tokio::fs::read_to_string(&retention_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, HashMap<String, u64>>>(&content) {
+                        Ok(retention) => {
+                            let mut retention_guard = self.retention.write().await;
+                            *retention_guard = retention;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse retention file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read retention file: {}", e),
+            }
+        }
+
+        // Load messages held for unregistered ids
+        let invites_path = format!("{}/invites.json", self.data_dir);
+        if Path::new(&invites_path).exists() {
+            match tokio::fs::read_to_string(&invites_path).await {
                 Ok(content) => {
                     match serde_json::from_str::<HashMap<String, Vec<Message>>>(&content) {
-                        Ok(messages) => {
-                            let mut messages_guard = futures::executor::block_on(self.messages.write());
-                            *messages_guard = messages;
+                        Ok(invites) => {
+                            let mut invites_guard = self.invites.write().await;
+                            *invites_guard = invites;
                         }
-                        Err(e) => eprintln!("⚠️ Warning: Failed to parse messages file: {}", e),
+                        Err(e) => eout!("⚠️ Warning: Failed to parse invites file: {}", e),
                     }
                 }
-                Err(e) => eprintln!("⚠️ Warning: Failed to read messages file: {}", e),
+                Err(e) => eout!("⚠️ Warning: Failed to read invites file: {}", e),
             }
         }
 
-        // Load clients
-        let clients_path = format!("{}/clients.json", self.data_dir);
-        if Path::new(&clients_path).exists() {
-            match fs::read_to_string(&clients_path) {
+        // Load quarantined messages, so new ones are added to them
+        let quarantine_path = format!("{}/quarantine.json", self.data_dir);
+        if Path::new(&quarantine_path).exists() {
+            match tokio::fs::read_to_string(&quarantine_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<Vec<Message>>(&content) {
+                        Ok(quarantine) => {
+                            let mut quarantine_guard = self.quarantine.write().await;
+                            *quarantine_guard = quarantine;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse quarantine file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read quarantine file: {}", e),
+            }
+        }
+
+        // Load guest links
+        let guest_links_path = format!("{}/guest_links.json", self.data_dir);
+        if Path::new(&guest_links_path).exists() {
+            match tokio::fs::read_to_string(&guest_links_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, GuestLink>>(&content) {
+                        Ok(guest_links) => {
+                            let mut guest_links_guard = self.guest_links.write().await;
+                            *guest_links_guard = guest_links;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse guest links file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read guest links file: {}", e),
+            }
+        }
+
+        // Load user data
+        let user_data_path = format!("{}/user_data.json", self.data_dir);
+        if Path::new(&user_data_path).exists() {
+            match tokio::fs::read_to_string(&user_data_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, BTreeMap<String, UserDataEntry>>>(&content) {
+                        Ok(user_data) => {
+                            let mut user_data_guard = self.user_data.write().await;
+                            *user_data_guard = user_data;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse user data file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read user data file: {}", e),
+            }
+        }
+
+        // Load invite codes
+        let invite_codes_path = format!("{}/invite_codes.json", self.data_dir);
+        if Path::new(&invite_codes_path).exists() {
+            match tokio::fs::read_to_string(&invite_codes_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, InviteCode>>(&content) {
+                        Ok(invite_codes) => {
+                            let mut invite_codes_guard = self.invite_codes.write().await;
+                            *invite_codes_guard = invite_codes;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse invite codes file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read invite codes file: {}", e),
+            }
+        }
+
+        // Load delegations
+        let delegations_path = format!("{}/delegations.json", self.data_dir);
+        if Path::new(&delegations_path).exists() {
+            match tokio::fs::read_to_string(&delegations_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, Vec<Delegation>>>(&content) {
+                        Ok(delegations) => {
+                            let mut delegations_guard = self.delegations.write().await;
+                            *delegations_guard = delegations;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse delegations file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read delegations file: {}", e),
+            }
+        }
+
+        // Load the delegation audit log
+        let delegation_audit_path = format!("{}/delegation_audit.json", self.data_dir);
+        if Path::new(&delegation_audit_path).exists() {
+            match tokio::fs::read_to_string(&delegation_audit_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, Vec<DelegationAudit>>>(&content) {
+                        Ok(delegation_audit) => {
+                            let mut delegation_audit_guard = self.delegation_audit.write().await;
+                            *delegation_audit_guard = delegation_audit;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse delegation audit file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read delegation audit file: {}", e),
+            }
+        }
+
+        // Load bans
+        let banned_path = format!("{}/banned.json", self.data_dir);
+        if Path::new(&banned_path).exists() {
+            match tokio::fs::read_to_string(&banned_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<HashMap<String, Ban>>(&content) {
+                        Ok(banned) => {
+                            let mut banned_guard = self.banned.write().await;
+                            *banned_guard = banned;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse banned file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read banned file: {}", e),
+            }
+        }
+
+        // Load groups
+        let groups_path = format!("{}/groups.json", self.data_dir);
+        if Path::new(&groups_path).exists() {
+            match tokio::fs::read_to_string(&groups_path).await {
                 Ok(content) => {
-                    match serde_json::from_str::<HashMap<String, ClientInfo>>(&content) {
-                        Ok(clients) => {
-                            let mut clients_guard = futures::executor::block_on(self.clients.write());
-                            *clients_guard = clients;
+                    match serde_json::from_str::<HashMap<String, Group>>(&content) {
+                        Ok(groups) => {
+                            let mut groups_guard = self.groups.write().await;
+                            *groups_guard = groups;
                         }
-                        Err(e) => eprintln!("⚠️ Warning: Failed to parse clients file: {}", e),
+                        Err(e) => eout!("⚠️ Warning: Failed to parse groups file: {}", e),
                     }
                 }
-                Err(e) => eprintln!("⚠️ Warning: Failed to read clients file: {}", e),
+                Err(e) => eout!("⚠️ Warning: Failed to read groups file: {}", e),
             }
         }
 
+        // Load the directory revisions. Data from before they were kept has
+        // clients but no history, so everyone starts with a full fetch
+        let directory_path = format!("{}/directory.json", self.data_dir);
+        if Path::new(&directory_path).exists() {
+            match tokio::fs::read_to_string(&directory_path).await {
+                Ok(content) => {
+                    match serde_json::from_str::<DirectoryLog>(&content) {
+                        Ok(directory) => {
+                            let mut directory_guard = self.directory.write().await;
+                            *directory_guard = directory;
+                        }
+                        Err(e) => eout!("⚠️ Warning: Failed to parse directory file: {}", e),
+                    }
+                }
+                Err(e) => eout!("⚠️ Warning: Failed to read directory file: {}", e),
+            }
+        } else if !self.clients.read().await.is_empty() {
+            let mut directory_guard = self.directory.write().await;
+            *directory_guard = DirectoryLog { revision: 1, compacted_through: 1, changes: Vec::new() };
+        }
+
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+/// The data directory's source address salt, made on first use. It lives
+/// as long as the data does, so hashes stay comparable across restarts.
+fn load_source_salt(data_dir: &str) -> String {
+    let path = Path::new(data_dir).join("source_salt");
+    match fs::read_to_string(&path) {
+        Ok(salt) if !salt.trim().is_empty() => salt.trim().to_string(),
+        _ => {
+            let salt = hex::encode(rand::random::<[u8; 16]>());
+            if let Err(e) = secure_fs::write_private(&path, &salt) {
+                eout!("⚠️ Warning: Failed to save the source address salt: {}", e);
+            }
+            salt
+        }
+    }
+}
+
+/// Size of each component's data file, or the backend's share of its own
+/// for the components it keeps; missing files count as empty.
+fn measure_data_files(data_dir: &str, backend: &dyn StorageBackend) -> BTreeMap<String, u64> {
+    DATA_FILES.iter()
+        .filter(|(component, _)| !BACKEND_COMPONENTS.contains(component))
+        .map(|(component, file)| {
+            let bytes = fs::metadata(Path::new(data_dir).join(file)).map(|m| m.len()).unwrap_or(0);
+            (component.to_string(), bytes)
+        })
+        .chain(backend.disk_usage().into_iter().map(|(component, bytes)| (component.to_string(), bytes)))
+        .collect()
+}
+
+/// Whether `sender_id` has a stored or held message with this id.
+fn id_in_use(messages: &HashMap<String, Vec<Message>>, invites: &HashMap<String, Vec<Message>>, sender_id: &str, message_id: &str) -> bool {
+    messages.values().chain(invites.values()).flatten().any(|m| m.id == message_id && m.sender_id == sender_id)
+}
+
+/// Take the messages that match out of every mailbox or held list in
+/// `store`, each with its owner and where it was.
+fn take_matching(store: &mut HashMap<String, Vec<Message>>, matches: impl Fn(&Message) -> bool) -> Vec<(String, usize, Message)> {
+    let mut taken = Vec::new();
+    for (owner, list) in store.iter_mut() {
+        let (matched, kept): (Vec<_>, Vec<_>) = std::mem::take(list).into_iter().enumerate().partition(|(_, m)| matches(m));
+        *list = kept.into_iter().map(|(_, m)| m).collect();
+        taken.extend(matched.into_iter().map(|(index, m)| (owner.clone(), index, m)));
+    }
+    taken
+}
+
+/// Put what `take_matching` took back where it was.
+fn put_back(store: &mut HashMap<String, Vec<Message>>, taken: Vec<(String, usize, Message)>) {
+    for (owner, index, message) in taken {
+        let list = store.entry(owner).or_default();
+        list.insert(index.min(list.len()), message);
+    }
+}
+
+/// The deletions that take `taken` out of the backend: owner -> message ids.
+fn deletions(taken: &[(String, usize, Message)]) -> HashMap<String, Vec<String>> {
+    let mut deletions: HashMap<String, Vec<String>> = HashMap::new();
+    for (owner, _, message) in taken {
+        deletions.entry(owner.clone()).or_default().push(message.id.clone());
+    }
+    deletions
+}
+
+/// Remove and return the message `message` supersedes, if it is in
+/// `mailbox`, from the same sender, and not yet fetched. An unnumbered
+/// replacement takes over the original's number, so its removal leaves no
+/// gap for the recipient to wait on.
+fn take_superseded(mailbox: &mut Vec<Message>, message: &mut Message) -> Option<Message> {
+    let superseded = message.supersedes.as_deref()?;
+    let index = mailbox.iter().position(|m| m.id == superseded && m.sender_id == message.sender_id && m.delivered_at.is_none())?;
+    let original = mailbox.remove(index);
+    message.seq = message.seq.or(original.seq);
+    message.replaced_queued = true;
+    Some(original)
+}
+
+/// Tell the backend about a transaction's pruned mailboxes and delivered messages.
+fn apply_batch(backend: &dyn StorageBackend, pruned: &[(String, Vec<Message>)], delivered: &[Message]) -> Result<()> {
+    for (owner, mailbox) in pruned {
+        backend.delete_messages(owner, &mailbox.iter().map(|m| m.id.clone()).collect::<Vec<_>>())?;
+    }
+    delivered.iter().try_for_each(|message| backend.add_message(message))
+}
+
+/// Take back what `apply_batch` told the backend.
+fn undo_batch(backend: &dyn StorageBackend, delivered: &[Message], pruned: &[(String, Vec<Message>)]) -> Result<()> {
+    for message in delivered {
+        backend.delete_messages(&message.recipient_id, std::slice::from_ref(&message.id))?;
+    }
+    pruned.iter().flat_map(|(_, mailbox)| mailbox).try_for_each(|message| backend.add_message(message))
+}
+
+/// Fail a write to `component` on purpose in debug builds while the failure
+/// injection marker in `data_dir` is there and doesn't leave it out.
+fn fail_if_injected(data_dir: &str, component: &str) -> Result<()> {
+    if !cfg!(debug_assertions) {
+        return Ok(());
+    }
+    let Ok(marker) = fs::read_to_string(Path::new(data_dir).join(INJECT_FAILURES_FILE)) else { return Ok(()) };
+    let mut listed = marker.lines().map(str::trim).filter(|line| !line.is_empty()).peekable();
+    if listed.peek().is_none() || listed.any(|listed| listed == component) {
+        return Err(anyhow!("injected write failure"));
+    }
+    Ok(())
+}
+
+/// Write one data file, or fail on purpose while failures are injected.
+fn write_file(data_dir: &str, component: &str, path: &str, contents: String) -> Result<()> {
+    fail_if_injected(data_dir, component)?;
+    secure_fs::write_private_atomic(path, contents, false)
+}
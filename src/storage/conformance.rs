@@ -0,0 +1,294 @@
+//! The behavior every `StorageBackend` must have, as one suite each
+//! backend's tests run with a function that opens it. Checks panic with
+//! the backend's name, like the assertions they are.
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn json_backend_conforms() {
+//!     conformance::run(|dir| Box::new(JsonBackend::open(dir).unwrap())).await;
+//! }
+//! ```
+
+use crate::backend::{self, JsonBackend, StorageBackend};
+use crate::scratch::Scratch;
+use crate::storage::{DuplicateMessageId, Storage};
+use crate::types::{ClientInfo, Hlc, Message, MessageSearch};
+use chrono::{DateTime, Duration, Utc};
+use std::path::Path;
+
+/// Opens a backend in a directory, empty or as a backend it opened before
+/// left it.
+pub trait Factory: Fn(&Path) -> Box<dyn StorageBackend> {}
+
+impl<F: Fn(&Path) -> Box<dyn StorageBackend>> Factory for F {}
+
+/// Run every check, each in a fresh directory.
+pub async fn run(open: impl Factory) {
+    clients_are_kept(&open);
+    mailboxes_are_kept(&open);
+    mailboxes_are_in_clock_order(&open);
+    everything_can_be_replaced(&open);
+    message_ids_are_unique_per_sender(&open).await;
+    fetches_follow_the_clock(&open).await;
+    searches_page_from_the_newest(&open).await;
+    mailbox_usage_counts_client_messages(&open).await;
+    expired_messages_are_purged(&open).await;
+    compaction_keeps_every_live_message(&open).await;
+}
+
+fn client(id: &str, seen: DateTime<Utc>) -> ClientInfo {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "public_key": format!("{}-key", id),
+        "registered_at": seen,
+        "last_seen": seen,
+    }))
+    .unwrap()
+}
+
+fn message(id: &str, from: &str, to: &str, at: DateTime<Utc>) -> Message {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "sender_id": from,
+        "recipient_id": to,
+        "content": format!("ciphertext of {}", id),
+        "timestamp": at,
+        "encrypted": true,
+        "signature": null,
+    }))
+    .unwrap()
+}
+
+fn ids(messages: &[Message]) -> Vec<&str> {
+    messages.iter().map(|m| m.id.as_str()).collect()
+}
+
+/// Storage over the backend `open` gives for `scratch`.
+async fn storage(open: &impl Factory, scratch: &Scratch) -> Storage {
+    Storage::with_backend(&scratch.0.to_string_lossy(), open(&scratch.0)).await.unwrap()
+}
+
+/// Registering an id again replaces the client; last-seen times of unknown
+/// clients are ignored.
+fn clients_are_kept(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-clients");
+    let start = Utc::now();
+    let name = {
+        let backend = open(&scratch.0);
+        assert!(backend.get_client_info("alice").unwrap().is_none());
+        backend.register_client(&client("alice", start)).unwrap();
+        backend.register_client(&client("bob", start)).unwrap();
+        let mut rekeyed = client("bob", start);
+        rekeyed.public_key = "bob-new-key".to_string();
+        backend.register_client(&rekeyed).unwrap();
+        backend.update_last_seen("alice", start + Duration::minutes(5)).unwrap();
+        backend.update_last_seen("nobody", start).unwrap();
+        backend.name()
+    };
+
+    let backend = open(&scratch.0);
+    let clients = backend.get_all_clients().unwrap();
+    assert_eq!(clients.len(), 2, "{} kept {:?}", name, clients.keys());
+    assert_eq!(clients["bob"].public_key, "bob-new-key", "{} kept the old key", name);
+    let alice = backend.get_client_info("alice").unwrap().unwrap();
+    assert_eq!(alice.last_seen, start + Duration::minutes(5), "{} lost the last-seen time", name);
+    assert_eq!(alice.registered_at, start);
+}
+
+/// Messages are updated and deleted by id within one mailbox only.
+fn mailboxes_are_kept(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-mailboxes");
+    let start = Utc::now();
+    {
+        let backend = open(&scratch.0);
+        for (i, id) in ["m1", "m2", "m3"].iter().enumerate() {
+            backend.add_message(&message(id, "alice", "bob", start + Duration::seconds(i as i64))).unwrap();
+        }
+        backend.add_message(&message("m1", "bob", "alice", start)).unwrap();
+
+        let mut fetched = message("m2", "alice", "bob", start + Duration::seconds(1));
+        fetched.delivered_at = Some(start + Duration::seconds(10));
+        backend.update_messages("bob", &[fetched, message("gone", "alice", "bob", start)]).unwrap();
+
+        assert_eq!(backend.delete_messages("bob", &["m1".to_string(), "unknown".to_string()]).unwrap(), 1);
+        assert_eq!(backend.delete_messages("carol", &["m1".to_string()]).unwrap(), 0);
+    }
+
+    let backend = open(&scratch.0);
+    let name = backend.name();
+    let bob = backend.get_messages_for_client("bob").unwrap();
+    assert_eq!(ids(&bob), ["m2", "m3"], "{} mailbox out of order", name);
+    assert_eq!(bob[0].delivered_at, Some(start + Duration::seconds(10)), "{} lost an update", name);
+    assert_eq!(bob[1].delivered_at, None);
+    assert_eq!(ids(&backend.get_messages_for_client("alice").unwrap()), ["m1"], "{} deleted from the wrong mailbox", name);
+    assert!(backend.get_messages_for_client("carol").unwrap().is_empty());
+
+    let all = backend.get_all_messages().unwrap();
+    assert_eq!(all.values().map(Vec::len).sum::<usize>(), 3);
+    assert_eq!(ids(&all["bob"]), ["m2", "m3"]);
+}
+
+/// A mailbox comes back in clock order, whatever order it was written in.
+fn mailboxes_are_in_clock_order(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-clock-order");
+    let start = Utc::now();
+    let backend = open(&scratch.0);
+    let name = backend.name();
+    let mut replicated = Vec::new();
+    for (id, wall_ms) in [("late", 30), ("early", 10), ("middle", 20)] {
+        let mut stored = message(id, "alice", "bob", start);
+        stored.hlc = Hlc { wall_ms, counter: 0 };
+        backend.add_message(&stored).unwrap();
+        replicated.push(stored);
+    }
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), ["early", "middle", "late"], "{} kept append order", name);
+    backend.replace_all_messages(&[("bob".to_string(), replicated)].into()).unwrap();
+    drop(backend);
+
+    let backend = open(&scratch.0);
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), ["early", "middle", "late"], "{} kept replicated order", name);
+    assert_eq!(ids(&backend.get_all_messages().unwrap()["bob"]), ["early", "middle", "late"]);
+}
+
+/// Replacing everything, as a standby does, leaves nothing of before.
+fn everything_can_be_replaced(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-replace");
+    let start = Utc::now();
+    let backend = open(&scratch.0);
+    let name = backend.name();
+    backend.register_client(&client("alice", start)).unwrap();
+    backend.add_message(&message("old", "alice", "bob", start)).unwrap();
+
+    let source = Scratch::new("conformance-replace-source");
+    let other = JsonBackend::open(&source.0).unwrap();
+    other.register_client(&client("carol", start)).unwrap();
+    other.add_message(&message("new", "carol", "dave", start)).unwrap();
+    assert_eq!(backend::migrate(&other, backend.as_ref()).unwrap(), (1, 1));
+    backend.flush().unwrap();
+    drop(backend);
+
+    let backend = open(&scratch.0);
+    assert_eq!(backend.get_all_clients().unwrap().keys().collect::<Vec<_>>(), ["carol"], "{} kept old clients", name);
+    assert!(backend.get_messages_for_client("bob").unwrap().is_empty(), "{} kept old messages", name);
+    assert_eq!(ids(&backend.get_messages_for_client("dave").unwrap()), ["new"]);
+    assert!(backend.disk_usage().iter().any(|(component, bytes)| *component == "mailboxes" && *bytes > 0),
+        "{} reports no mailbox bytes", name);
+}
+
+/// A sender can't reuse a message id while the message is stored, even
+/// after a restart; another sender can use the same id.
+async fn message_ids_are_unique_per_sender(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-duplicate-ids");
+    let start = Utc::now();
+    {
+        let storage = storage(open, &scratch).await;
+        storage.add_message(message("m1", "alice", "bob", start)).await.unwrap();
+    }
+    let storage = storage(open, &scratch).await;
+    let refused = storage.add_message(message("m1", "alice", "carol", start)).await.unwrap_err();
+    assert!(refused.is::<DuplicateMessageId>(), "a reused id was refused with {}", refused);
+    storage.add_message(message("m1", "carol", "bob", start)).await.unwrap();
+    assert_eq!(storage.mailbox_usage("bob").await, 2);
+}
+
+/// Fetches come in clock order, `since` skips what was fetched, and
+/// delivery marks survive a restart.
+async fn fetches_follow_the_clock(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-fetch");
+    let start = Utc::now();
+    let first = {
+        let storage = storage(open, &scratch).await;
+        for (i, id) in ["m1", "m2"].iter().enumerate() {
+            storage.add_message(message(id, "alice", "bob", start + Duration::seconds(i as i64))).await.unwrap();
+        }
+        let fetched = storage.fetch_messages("bob", None).await.unwrap();
+        assert_eq!(ids(&fetched), ["m1", "m2"]);
+        fetched[1].hlc
+    };
+
+    let storage = storage(open, &scratch).await;
+    storage.add_message(message("m3", "alice", "bob", start)).await.unwrap();
+    let fetched = storage.fetch_messages("bob", None).await.unwrap();
+    assert_eq!(ids(&fetched), ["m1", "m2", "m3"], "a message stored after a restart sorted before older ones");
+    assert!(fetched[..2].iter().all(|m| m.delivered_at.is_some()), "delivery marks were lost on restart");
+    assert_eq!(ids(&storage.fetch_messages("bob", Some(first)).await.unwrap()), ["m3"]);
+}
+
+/// A search returns the newest matches first, and a cursor continues it.
+async fn searches_page_from_the_newest(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-search");
+    let start = Utc::now();
+    let storage = storage(open, &scratch).await;
+    for i in 0..5 {
+        storage.add_message(message(&format!("m{}", i), "alice", "bob", start + Duration::seconds(i))).await.unwrap();
+    }
+    storage.add_message(message("other", "carol", "bob", start)).await.unwrap();
+
+    let mut search = MessageSearch { from: Some("alice".to_string()), limit: Some(2), ..MessageSearch::default() };
+    let mut pages = Vec::new();
+    loop {
+        let (page, truncated) = storage.search_messages("bob", &search).await.unwrap();
+        pages.push(page.iter().map(|meta| meta.id.clone()).collect::<Vec<_>>());
+        if !truncated {
+            break;
+        }
+        search.cursor = page.last().map(|meta| meta.hlc);
+    }
+    assert_eq!(pages, [vec!["m4", "m3"], vec!["m2", "m1"], vec!["m0"]]);
+}
+
+/// Usage, which quotas are checked against, counts what clients sent and
+/// follows deletes and restarts.
+async fn mailbox_usage_counts_client_messages(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-usage");
+    let start = Utc::now();
+    {
+        let storage = storage(open, &scratch).await;
+        for i in 0..3 {
+            storage.add_message(message(&format!("m{}", i), "alice", "bob", start)).await.unwrap();
+        }
+        assert_eq!(storage.delete_messages("bob", &["m0".to_string()]).await.unwrap(), 1);
+    }
+    let storage = storage(open, &scratch).await;
+    assert_eq!(storage.mailbox_usage("bob").await, 2);
+    assert_eq!(storage.mailbox_usage("carol").await, 0);
+}
+
+/// Messages past their expiry are never fetched, and stay gone.
+async fn expired_messages_are_purged(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-expiry");
+    let start = Utc::now();
+    {
+        let storage = storage(open, &scratch).await;
+        let mut expired = message("expired", "alice", "bob", start);
+        expired.expires_at = Some(start - Duration::seconds(1));
+        let mut live = message("live", "alice", "bob", start);
+        live.expires_at = Some(start + Duration::hours(1));
+        storage.add_message(expired).await.unwrap();
+        storage.add_message(live).await.unwrap();
+        assert_eq!(ids(&storage.fetch_messages("bob", None).await.unwrap()), ["live"]);
+    }
+    let backend = open(&scratch.0);
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), ["live"], "{} kept an expired message", backend.name());
+}
+
+/// Pruning deleted and rewritten messages keeps every live one as it is.
+async fn compaction_keeps_every_live_message(open: &impl Factory) {
+    let scratch = Scratch::new("conformance-compaction");
+    let start = Utc::now();
+    let storage = storage(open, &scratch).await;
+    for i in 0..20 {
+        storage.add_message(message(&format!("m{}", i), "alice", "bob", start + Duration::seconds(i))).await.unwrap();
+    }
+    let fetched = storage.fetch_messages("bob", None).await.unwrap();
+    let acked: Vec<String> = fetched[..15].iter().map(|m| m.id.clone()).collect();
+    storage.delete_messages("bob", &acked).await.unwrap();
+    storage.compact_mailboxes().await.unwrap();
+    storage.flush().await.unwrap();
+    drop(storage);
+
+    let backend = open(&scratch.0);
+    let kept = backend.get_messages_for_client("bob").unwrap();
+    assert_eq!(ids(&kept), ["m15", "m16", "m17", "m18", "m19"], "{} lost messages compacting", backend.name());
+    assert!(kept.iter().all(|m| m.delivered_at.is_some()), "{} compacted away delivery marks", backend.name());
+}
@@ -0,0 +1,799 @@
+use crate::paths::ClientPaths;
+use crate::secure_fs;
+use crate::output::out;
+use log::warn;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::time::Duration;
+
+/// Which backend keeps the client's contacts and history on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalStoreKind {
+    #[default]
+    File,
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredContact {
+    pub server: String,
+    pub client_id: String,
+    pub x25519_public_key: String,
+    #[serde(default)]
+    pub trusted_at: Option<DateTime<Utc>>,
+}
+
+/// What happened to a message after it was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageState {
+    /// Removed from the server before the recipient fetched it.
+    Cancelled,
+    /// The sender asked for it to be disregarded after delivery.
+    Retracted,
+    /// Never delivered; the server bounced it.
+    Failed,
+    /// Replaced by a newer message before the recipient fetched it.
+    Superseded,
+}
+
+impl MessageState {
+    fn as_str(self) -> &'static str {
+        match self {
+            MessageState::Cancelled => "cancelled",
+            MessageState::Retracted => "retracted",
+            MessageState::Failed => "failed",
+            MessageState::Superseded => "superseded",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "cancelled" => Some(MessageState::Cancelled),
+            "retracted" => Some(MessageState::Retracted),
+            "failed" => Some(MessageState::Failed),
+            "superseded" => Some(MessageState::Superseded),
+            _ => None,
+        }
+    }
+}
+
+/// What checking a received message's signature against its signer's
+/// identity key showed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureCheck {
+    Verified,
+    /// No signature, or no key to check it with.
+    Unverified,
+    /// Signed, but not by the key the signer had.
+    Invalid,
+}
+
+impl SignatureCheck {
+    fn as_str(self) -> &'static str {
+        match self {
+            SignatureCheck::Verified => "verified",
+            SignatureCheck::Unverified => "unverified",
+            SignatureCheck::Invalid => "invalid",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "verified" => Some(SignatureCheck::Verified),
+            "unverified" => Some(SignatureCheck::Unverified),
+            "invalid" => Some(SignatureCheck::Invalid),
+            _ => None,
+        }
+    }
+}
+
+/// One message in a conversation. `sealed_body` is encrypted by the caller
+/// before it reaches the store, so neither backend sees plaintext.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub server: String,
+    pub peer: String,
+    pub outgoing: bool,
+    pub message_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub sealed_body: String,
+    #[serde(default)]
+    pub state: Option<MessageState>,
+    /// Hex X25519 key an incoming message was encrypted with, taken from its
+    /// envelope once the sender's identity key was checked to have signed it.
+    #[serde(default)]
+    pub sender_key: Option<String>,
+    /// A disappearing message: the janitor scrubs it once this passes.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// How an incoming message's signature checked out when it arrived.
+    #[serde(default)]
+    pub signature: Option<SignatureCheck>,
+}
+
+impl HistoryRecord {
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+pub trait LocalStore: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn save_contact(&self, contact: &StoredContact) -> Result<()>;
+    fn contacts(&self, server: &str) -> Result<Vec<StoredContact>>;
+    fn append_history(&self, record: &HistoryRecord) -> Result<()>;
+    fn find_history(&self, message_id: &str) -> Result<Option<HistoryRecord>>;
+    fn set_state(&self, message_id: &str, state: MessageState) -> Result<()>;
+    /// The latest `limit` records with a peer, oldest first.
+    fn history(&self, server: &str, peer: &str, limit: usize) -> Result<Vec<HistoryRecord>>;
+    fn all_contacts(&self) -> Result<Vec<StoredContact>>;
+    fn all_history(&self) -> Result<Vec<HistoryRecord>>;
+    /// Add a message's search tokens to the index. Tokens are opaque to the
+    /// store; the client hashes them before they get here.
+    fn index_message(&self, message_id: &str, tokens: &[String]) -> Result<()>;
+    /// Ids of indexed messages containing every token.
+    fn search_index(&self, tokens: &[String]) -> Result<Vec<String>>;
+    fn clear_index(&self) -> Result<()>;
+    /// Overwrite and delete every record that expired by `now`, with its
+    /// search index entries. Returns the ids of the scrubbed messages.
+    fn scrub_expired(&self, now: DateTime<Utc>) -> Result<Vec<String>>;
+    /// The cached client list for a server and the revision it's current
+    /// to, if it was ever synced.
+    fn directory(&self, server: &str) -> Result<Option<(u64, Vec<String>)>>;
+    fn save_directory(&self, server: &str, revision: u64, clients: &[String]) -> Result<()>;
+}
+
+/// How often the janitor looks for expired messages.
+const JANITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Scrub expired messages from `store` now and every `JANITOR_INTERVAL`
+/// until the store is dropped, reporting the ids scrubbed on `scrubbed`.
+/// Needs a tokio runtime.
+pub fn spawn_janitor(store: &Arc<dyn LocalStore>, scrubbed: mpsc::Sender<Vec<String>>) {
+    let store = Arc::downgrade(store);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(JANITOR_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let Some(store) = store.upgrade() else { break };
+            match store.scrub_expired(Utc::now()) {
+                Ok(ids) if ids.is_empty() => {}
+                Ok(ids) => {
+                    if scrubbed.send(ids).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("🧹 Failed to scrub expired messages from the {} store: {}", store.name(), e),
+            }
+        }
+    });
+}
+
+/// Open the configured backend for a client, migrating the file store into
+/// SQLite the first time SQLite is selected.
+pub fn open(kind: LocalStoreKind, paths: &ClientPaths, client_id: &str) -> Result<Box<dyn LocalStore>> {
+    let file_path = paths.store_file(client_id, "json");
+    match kind {
+        LocalStoreKind::File => Ok(Box::new(FileStore::open(&file_path)?)),
+        LocalStoreKind::Sqlite => {
+            let store = SqliteStore::open(&paths.store_file(client_id, "sqlite"))?;
+            if file_path.exists() {
+                let (contacts, history) = migrate(&FileStore::open(&file_path)?, &store)?;
+                fs::rename(&file_path, paths.store_file(client_id, "json.migrated"))?;
+                out!("📦 Migrated {} contact(s) and {} message(s) to SQLite", contacts, history);
+            }
+            Ok(Box::new(store))
+        }
+    }
+}
+
+/// Copy everything from one store into another, records still sealed.
+pub fn migrate(from: &dyn LocalStore, to: &dyn LocalStore) -> Result<(usize, usize)> {
+    let contacts = from.all_contacts()?;
+    for contact in &contacts {
+        to.save_contact(contact)?;
+    }
+    let history = from.all_history()?;
+    for record in &history {
+        to.append_history(record)?;
+    }
+    Ok((contacts.len(), history.len()))
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct FileData {
+    #[serde(default)]
+    contacts: Vec<StoredContact>,
+    #[serde(default)]
+    history: Vec<HistoryRecord>,
+    /// Inverted index: token -> message ids.
+    #[serde(default)]
+    index: BTreeMap<String, BTreeSet<String>>,
+    /// Server -> cached client list, at the revision it was synced to.
+    #[serde(default)]
+    directories: BTreeMap<String, (u64, Vec<String>)>,
+}
+
+/// Everything in one JSON file, rewritten on every change.
+pub struct FileStore {
+    path: PathBuf,
+    data: Mutex<FileData>,
+}
+
+impl FileStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(path)?)?
+        } else {
+            FileData::default()
+        };
+        Ok(Self { path: path.to_path_buf(), data: Mutex::new(data) })
+    }
+
+    fn update(&self, change: impl FnOnce(&mut FileData)) -> Result<()> {
+        let mut data = self.data.lock().map_err(|_| anyhow!("File store lock poisoned"))?;
+        change(&mut data);
+        secure_fs::write_private(&self.path, serde_json::to_string_pretty(&*data)?)?;
+        Ok(())
+    }
+
+    fn read<T>(&self, query: impl FnOnce(&FileData) -> T) -> Result<T> {
+        let data = self.data.lock().map_err(|_| anyhow!("File store lock poisoned"))?;
+        Ok(query(&data))
+    }
+}
+
+impl LocalStore for FileStore {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn save_contact(&self, contact: &StoredContact) -> Result<()> {
+        self.update(|data| {
+            data.contacts.retain(|c| c.server != contact.server || c.client_id != contact.client_id);
+            data.contacts.push(contact.clone());
+        })
+    }
+
+    fn contacts(&self, server: &str) -> Result<Vec<StoredContact>> {
+        self.read(|data| data.contacts.iter().filter(|c| c.server == server).cloned().collect())
+    }
+
+    fn append_history(&self, record: &HistoryRecord) -> Result<()> {
+        self.update(|data| data.history.push(record.clone()))
+    }
+
+    fn find_history(&self, message_id: &str) -> Result<Option<HistoryRecord>> {
+        self.read(|data| data.history.iter().find(|r| r.message_id == message_id).cloned())
+    }
+
+    fn set_state(&self, message_id: &str, state: MessageState) -> Result<()> {
+        self.update(|data| {
+            for record in data.history.iter_mut().filter(|r| r.message_id == message_id) {
+                record.state = Some(state);
+            }
+        })
+    }
+
+    fn history(&self, server: &str, peer: &str, limit: usize) -> Result<Vec<HistoryRecord>> {
+        self.read(|data| {
+            let mut records: Vec<_> = data.history.iter()
+                .filter(|r| r.server == server && r.peer == peer)
+                .cloned()
+                .collect();
+            records.sort_by_key(|r| r.timestamp);
+            records.split_off(records.len().saturating_sub(limit))
+        })
+    }
+
+    fn all_contacts(&self) -> Result<Vec<StoredContact>> {
+        self.read(|data| data.contacts.clone())
+    }
+
+    fn all_history(&self) -> Result<Vec<HistoryRecord>> {
+        self.read(|data| data.history.clone())
+    }
+
+    fn index_message(&self, message_id: &str, tokens: &[String]) -> Result<()> {
+        self.update(|data| {
+            for token in tokens {
+                data.index.entry(token.clone()).or_default().insert(message_id.to_string());
+            }
+        })
+    }
+
+    fn search_index(&self, tokens: &[String]) -> Result<Vec<String>> {
+        self.read(|data| {
+            let mut postings = tokens.iter().map(|token| data.index.get(token));
+            let Some(Some(first)) = postings.next() else { return Vec::new() };
+            let mut ids = first.clone();
+            for posting in postings {
+                match posting {
+                    Some(posting) => ids.retain(|id| posting.contains(id)),
+                    None => return Vec::new(),
+                }
+            }
+            ids.into_iter().collect()
+        })
+    }
+
+    fn clear_index(&self) -> Result<()> {
+        self.update(|data| data.index.clear())
+    }
+
+    /// The file is rewritten whole on every change, so once the records are
+    /// gone it no longer holds their sealed bodies.
+    fn scrub_expired(&self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        let expired: Vec<String> = self.read(|data| data.history.iter()
+            .filter(|r| r.expired(now))
+            .map(|r| r.message_id.clone())
+            .collect())?;
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        self.update(|data| {
+            data.history.retain(|r| !r.expired(now));
+            for ids in data.index.values_mut() {
+                ids.retain(|id| !expired.contains(id));
+            }
+            data.index.retain(|_, ids| !ids.is_empty());
+        })?;
+        Ok(expired)
+    }
+
+    fn directory(&self, server: &str) -> Result<Option<(u64, Vec<String>)>> {
+        self.read(|data| data.directories.get(server).cloned())
+    }
+
+    fn save_directory(&self, server: &str, revision: u64, clients: &[String]) -> Result<()> {
+        self.update(|data| {
+            data.directories.insert(server.to_string(), (revision, clients.to_vec()));
+        })
+    }
+}
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        secure_fs::restrict_file(path)?;
+        conn.execute_batch(
+            "PRAGMA secure_delete = ON;
+            CREATE TABLE IF NOT EXISTS contacts (
+                server TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                x25519_public_key TEXT NOT NULL,
+                trusted_at INTEGER,
+                PRIMARY KEY (server, client_id)
+            );
+            CREATE TABLE IF NOT EXISTS history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                server TEXT NOT NULL,
+                peer TEXT NOT NULL,
+                outgoing INTEGER NOT NULL,
+                message_id TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                sealed_body TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS history_by_peer ON history (server, peer, timestamp);",
+        )?;
+
+        // Stores from older versions lack the later columns
+        for (column, kind) in [("state", "TEXT"), ("sender_key", "TEXT"), ("expires_at", "INTEGER"), ("signature", "TEXT")] {
+            let exists: bool = conn.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('history') WHERE name = ?1", [column], |row| row.get(0))?;
+            if !exists {
+                conn.execute_batch(&format!("ALTER TABLE history ADD COLUMN {} {};", column, kind))?;
+            }
+        }
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS history_by_message ON history (message_id);
+            CREATE INDEX IF NOT EXISTS history_by_expiry ON history (expires_at) WHERE expires_at IS NOT NULL;
+            CREATE VIRTUAL TABLE IF NOT EXISTS search USING fts5 (message_id UNINDEXED, tokens);
+            CREATE TABLE IF NOT EXISTS directory_revisions (
+                server TEXT PRIMARY KEY,
+                revision INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS directory (
+                server TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                PRIMARY KEY (server, client_id)
+            );",
+        )?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>> {
+        self.conn.lock().map_err(|_| anyhow!("SQLite store lock poisoned"))
+    }
+
+    fn query_history(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<HistoryRecord>> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(sql)?;
+        let rows = statement.query_map(params, |row| {
+            Ok(HistoryRecord {
+                server: row.get(0)?,
+                peer: row.get(1)?,
+                outgoing: row.get(2)?,
+                message_id: row.get(3)?,
+                timestamp: from_millis(row.get(4)?),
+                sealed_body: row.get(5)?,
+                state: row.get::<_, Option<String>>(6)?.as_deref().and_then(MessageState::parse),
+                sender_key: row.get(7)?,
+                expires_at: row.get::<_, Option<i64>>(8)?.map(from_millis),
+                signature: row.get::<_, Option<String>>(9)?.as_deref().and_then(SignatureCheck::parse),
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn query_contacts(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<StoredContact>> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(sql)?;
+        let rows = statement.query_map(params, |row| {
+            Ok(StoredContact {
+                server: row.get(0)?,
+                client_id: row.get(1)?,
+                x25519_public_key: row.get(2)?,
+                trusted_at: row.get::<_, Option<i64>>(3)?.map(from_millis),
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+impl LocalStore for SqliteStore {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn save_contact(&self, contact: &StoredContact) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT OR REPLACE INTO contacts (server, client_id, x25519_public_key, trusted_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![contact.server, contact.client_id, contact.x25519_public_key,
+                contact.trusted_at.map(|t| t.timestamp_millis())],
+        )?;
+        Ok(())
+    }
+
+    fn contacts(&self, server: &str) -> Result<Vec<StoredContact>> {
+        self.query_contacts(
+            "SELECT server, client_id, x25519_public_key, trusted_at FROM contacts WHERE server = ?1",
+            params![server],
+        )
+    }
+
+    fn append_history(&self, record: &HistoryRecord) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO history (server, peer, outgoing, message_id, timestamp, sealed_body, state, sender_key, expires_at, signature)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![record.server, record.peer, record.outgoing, record.message_id,
+                record.timestamp.timestamp_millis(), record.sealed_body, record.state.map(MessageState::as_str), record.sender_key,
+                record.expires_at.map(|t| t.timestamp_millis()), record.signature.map(SignatureCheck::as_str)],
+        )?;
+        Ok(())
+    }
+
+    fn find_history(&self, message_id: &str) -> Result<Option<HistoryRecord>> {
+        let records = self.query_history(
+            "SELECT server, peer, outgoing, message_id, timestamp, sealed_body, state, sender_key, expires_at, signature FROM history
+             WHERE message_id = ?1 LIMIT 1",
+            params![message_id],
+        )?;
+        Ok(records.into_iter().next())
+    }
+
+    fn set_state(&self, message_id: &str, state: MessageState) -> Result<()> {
+        self.conn()?.execute("UPDATE history SET state = ?1 WHERE message_id = ?2", params![state.as_str(), message_id])?;
+        Ok(())
+    }
+
+    fn history(&self, server: &str, peer: &str, limit: usize) -> Result<Vec<HistoryRecord>> {
+        let mut records = self.query_history(
+            "SELECT server, peer, outgoing, message_id, timestamp, sealed_body, state, sender_key, expires_at, signature FROM history
+             WHERE server = ?1 AND peer = ?2 ORDER BY timestamp DESC, id DESC LIMIT ?3",
+            params![server, peer, limit as i64],
+        )?;
+        records.reverse();
+        Ok(records)
+    }
+
+    fn all_contacts(&self) -> Result<Vec<StoredContact>> {
+        self.query_contacts("SELECT server, client_id, x25519_public_key, trusted_at FROM contacts", [])
+    }
+
+    fn all_history(&self) -> Result<Vec<HistoryRecord>> {
+        self.query_history(
+            "SELECT server, peer, outgoing, message_id, timestamp, sealed_body, state, sender_key, expires_at, signature FROM history ORDER BY id",
+            [],
+        )
+    }
+
+    fn index_message(&self, message_id: &str, tokens: &[String]) -> Result<()> {
+        self.conn()?.execute("INSERT INTO search (message_id, tokens) VALUES (?1, ?2)", params![message_id, tokens.join(" ")])?;
+        Ok(())
+    }
+
+    fn search_index(&self, tokens: &[String]) -> Result<Vec<String>> {
+        if tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Tokens are hex, so they need no quoting in the match expression
+        let conn = self.conn()?;
+        let mut statement = conn.prepare("SELECT DISTINCT message_id FROM search WHERE tokens MATCH ?1")?;
+        let ids = statement.query_map(params![tokens.join(" AND ")], |row| row.get(0))?;
+        Ok(ids.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn clear_index(&self) -> Result<()> {
+        self.conn()?.execute("DELETE FROM search", [])?;
+        Ok(())
+    }
+
+    /// Bodies are blanked before the rows go, and `secure_delete` has SQLite
+    /// zero the freed pages, so the ciphertext doesn't linger in the file.
+    fn scrub_expired(&self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        let mut conn = self.conn()?;
+        let now = now.timestamp_millis();
+        let expired: Vec<String> = {
+            let mut statement = conn.prepare("SELECT message_id FROM history WHERE expires_at <= ?1")?;
+            let ids = statement.query_map(params![now], |row| row.get(0))?;
+            ids.collect::<rusqlite::Result<_>>()?
+        };
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        let tx = conn.transaction()?;
+        tx.execute("UPDATE history SET sealed_body = '' WHERE expires_at <= ?1", params![now])?;
+        tx.execute("DELETE FROM history WHERE expires_at <= ?1", params![now])?;
+        for id in &expired {
+            tx.execute("DELETE FROM search WHERE message_id = ?1", params![id])?;
+        }
+        tx.commit()?;
+        Ok(expired)
+    }
+
+    fn directory(&self, server: &str) -> Result<Option<(u64, Vec<String>)>> {
+        let conn = self.conn()?;
+        let revision: Option<i64> = conn.query_row(
+            "SELECT revision FROM directory_revisions WHERE server = ?1", params![server], |row| row.get(0))
+            .optional()?;
+        let Some(revision) = revision else { return Ok(None) };
+        let mut statement = conn.prepare("SELECT client_id FROM directory WHERE server = ?1 ORDER BY client_id")?;
+        let clients = statement.query_map(params![server], |row| row.get(0))?;
+        Ok(Some((revision as u64, clients.collect::<rusqlite::Result<_>>()?)))
+    }
+
+    fn save_directory(&self, server: &str, revision: u64, clients: &[String]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM directory WHERE server = ?1", params![server])?;
+        for client_id in clients {
+            tx.execute("INSERT INTO directory (server, client_id) VALUES (?1, ?2)", params![server, client_id])?;
+        }
+        tx.execute(
+            "INSERT INTO directory_revisions (server, revision) VALUES (?1, ?2)
+            ON CONFLICT (server) DO UPDATE SET revision = excluded.revision",
+            params![server, revision as i64],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(millis).single().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+    use chrono::Duration as TimeDelta;
+
+    /// One store of each kind, fresh, in `scratch`.
+    fn stores(scratch: &Scratch) -> Vec<Box<dyn LocalStore>> {
+        vec![
+            Box::new(FileStore::open(&scratch.0.join("store.json")).unwrap()),
+            Box::new(SqliteStore::open(&scratch.0.join("store.sqlite")).unwrap()),
+        ]
+    }
+
+    fn contact(server: &str, client_id: &str, key: u8) -> StoredContact {
+        StoredContact {
+            server: server.to_string(),
+            client_id: client_id.to_string(),
+            x25519_public_key: hex::encode([key; 32]),
+            trusted_at: None,
+        }
+    }
+
+    /// A record `age_secs` old; timestamps are whole milliseconds, as SQLite keeps them.
+    fn record(peer: &str, message_id: &str, age_secs: i64) -> HistoryRecord {
+        let now = Utc.timestamp_millis_opt(Utc::now().timestamp_millis()).unwrap();
+        HistoryRecord {
+            server: "127.0.0.1:8080".to_string(),
+            peer: peer.to_string(),
+            outgoing: false,
+            message_id: message_id.to_string(),
+            timestamp: now - TimeDelta::seconds(age_secs),
+            sealed_body: format!("sealed {}", message_id),
+            state: None,
+            sender_key: None,
+            expires_at: None,
+            signature: None,
+        }
+    }
+
+    fn ids(records: &[HistoryRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.message_id.as_str()).collect()
+    }
+
+    #[test]
+    fn contacts_are_kept_per_server_and_replaced_in_place() {
+        let scratch = Scratch::new("store-contacts");
+        for store in stores(&scratch) {
+            store.save_contact(&contact("a", "bob", 1)).unwrap();
+            store.save_contact(&contact("b", "bob", 2)).unwrap();
+            let mut trusted = contact("a", "bob", 3);
+            trusted.trusted_at = Some(Utc.timestamp_millis_opt(1_700_000_000_000).unwrap());
+            store.save_contact(&trusted).unwrap();
+
+            let contacts = store.contacts("a").unwrap();
+            assert_eq!(contacts.len(), 1, "{}", store.name());
+            assert_eq!(contacts[0].x25519_public_key, trusted.x25519_public_key, "{}", store.name());
+            assert_eq!(contacts[0].trusted_at, trusted.trusted_at, "{}", store.name());
+            assert_eq!(store.all_contacts().unwrap().len(), 2, "{}", store.name());
+        }
+    }
+
+    #[test]
+    fn history_is_returned_oldest_first_up_to_the_limit() {
+        let scratch = Scratch::new("store-history");
+        for store in stores(&scratch) {
+            for (id, age) in [("m2", 20), ("m1", 30), ("m4", 0), ("m3", 10)] {
+                store.append_history(&record("bob", id, age)).unwrap();
+            }
+            store.append_history(&record("carol", "c1", 5)).unwrap();
+
+            assert_eq!(ids(&store.history("127.0.0.1:8080", "bob", 10).unwrap()), ["m1", "m2", "m3", "m4"], "{}", store.name());
+            assert_eq!(ids(&store.history("127.0.0.1:8080", "bob", 2).unwrap()), ["m3", "m4"], "{}", store.name());
+            assert!(store.history("elsewhere", "bob", 10).unwrap().is_empty(), "{}", store.name());
+
+            store.set_state("m2", MessageState::Retracted).unwrap();
+            let found = store.find_history("m2").unwrap().unwrap();
+            assert_eq!(found.state, Some(MessageState::Retracted), "{}", store.name());
+            assert_eq!(found.sealed_body, "sealed m2", "{}", store.name());
+            assert!(store.find_history("missing").unwrap().is_none(), "{}", store.name());
+        }
+    }
+
+    #[test]
+    fn the_search_index_matches_every_token() {
+        let scratch = Scratch::new("store-search");
+        for store in stores(&scratch) {
+            let tokens = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+            store.index_message("m1", &tokens(&["aa", "bb"])).unwrap();
+            store.index_message("m2", &tokens(&["bb", "cc"])).unwrap();
+
+            let mut found = store.search_index(&tokens(&["bb"])).unwrap();
+            found.sort();
+            assert_eq!(found, ["m1", "m2"], "{}", store.name());
+            assert_eq!(store.search_index(&tokens(&["bb", "cc"])).unwrap(), ["m2"], "{}", store.name());
+            assert!(store.search_index(&tokens(&["aa", "cc"])).unwrap().is_empty(), "{}", store.name());
+            assert!(store.search_index(&[]).unwrap().is_empty(), "{}", store.name());
+
+            store.clear_index().unwrap();
+            assert!(store.search_index(&tokens(&["bb"])).unwrap().is_empty(), "{}", store.name());
+        }
+    }
+
+    #[test]
+    fn expired_messages_are_scrubbed_with_their_index_entries() {
+        let scratch = Scratch::new("store-scrub");
+        for store in stores(&scratch) {
+            let now = Utc::now();
+            let mut expiring = record("bob", "gone", 60);
+            expiring.expires_at = Some(now - TimeDelta::seconds(1));
+            let mut later = record("bob", "later", 60);
+            later.expires_at = Some(now + TimeDelta::hours(1));
+            for record in [&expiring, &later, &record("bob", "kept", 60)] {
+                store.append_history(record).unwrap();
+                store.index_message(&record.message_id, &["word".to_string()]).unwrap();
+            }
+
+            assert_eq!(store.scrub_expired(now).unwrap(), ["gone"], "{}", store.name());
+            assert!(store.find_history("gone").unwrap().is_none(), "{}", store.name());
+            let mut left = store.search_index(&["word".to_string()]).unwrap();
+            left.sort();
+            assert_eq!(left, ["kept", "later"], "{}", store.name());
+            assert!(store.scrub_expired(now).unwrap().is_empty(), "{}", store.name());
+        }
+        let file = fs::read_to_string(scratch.0.join("store.json")).unwrap();
+        assert!(!file.contains("sealed gone"));
+    }
+
+    #[test]
+    fn directories_are_cached_per_server() {
+        let scratch = Scratch::new("store-directory");
+        for store in stores(&scratch) {
+            assert!(store.directory("a").unwrap().is_none(), "{}", store.name());
+            store.save_directory("a", 3, &["alice".to_string(), "bob".to_string()]).unwrap();
+            store.save_directory("a", 4, &["alice".to_string()]).unwrap();
+            store.save_directory("b", 1, &[]).unwrap();
+            assert_eq!(store.directory("a").unwrap(), Some((4, vec!["alice".to_string()])), "{}", store.name());
+            assert_eq!(store.directory("b").unwrap(), Some((1, Vec::new())), "{}", store.name());
+        }
+    }
+
+    #[test]
+    fn stores_reopen_with_what_was_written() {
+        let scratch = Scratch::new("store-reopen");
+        for store in stores(&scratch) {
+            store.save_contact(&contact("a", "bob", 1)).unwrap();
+            let mut signed = record("bob", "m1", 0);
+            signed.signature = Some(SignatureCheck::Verified);
+            signed.sender_key = Some(hex::encode([5u8; 32]));
+            store.append_history(&signed).unwrap();
+        }
+        for store in stores(&scratch) {
+            assert_eq!(store.all_contacts().unwrap().len(), 1, "{}", store.name());
+            let found = store.find_history("m1").unwrap().unwrap();
+            assert_eq!(found.signature, Some(SignatureCheck::Verified), "{}", store.name());
+            assert_eq!(found.sender_key, Some(hex::encode([5u8; 32])), "{}", store.name());
+        }
+    }
+
+    #[test]
+    fn migration_copies_the_file_store_into_sqlite() {
+        let scratch = Scratch::new("store-migrate");
+        let file = FileStore::open(&scratch.0.join("store.json")).unwrap();
+        file.save_contact(&contact("a", "bob", 1)).unwrap();
+        file.append_history(&record("bob", "m1", 10)).unwrap();
+        file.append_history(&record("bob", "m2", 0)).unwrap();
+
+        let sqlite = SqliteStore::open(&scratch.0.join("store.sqlite")).unwrap();
+        assert_eq!(migrate(&file, &sqlite).unwrap(), (1, 2));
+        assert_eq!(sqlite.contacts("a").unwrap()[0].client_id, "bob");
+        assert_eq!(ids(&sqlite.history("127.0.0.1:8080", "bob", 10).unwrap()), ["m1", "m2"]);
+    }
+
+    #[test]
+    fn sqlite_stores_from_older_versions_gain_the_later_columns() {
+        let scratch = Scratch::new("store-old-schema");
+        let path = scratch.0.join("store.sqlite");
+        Connection::open(&path).unwrap().execute_batch(
+            "CREATE TABLE history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                server TEXT NOT NULL,
+                peer TEXT NOT NULL,
+                outgoing INTEGER NOT NULL,
+                message_id TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                sealed_body TEXT NOT NULL
+            );
+            INSERT INTO history (server, peer, outgoing, message_id, timestamp, sealed_body)
+            VALUES ('127.0.0.1:8080', 'bob', 0, 'old', 1700000000000, 'sealed old');",
+        ).unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        let old = store.find_history("old").unwrap().unwrap();
+        assert_eq!(old.sealed_body, "sealed old");
+        assert!(old.state.is_none() && old.expires_at.is_none() && old.signature.is_none());
+        store.set_state("old", MessageState::Cancelled).unwrap();
+        assert_eq!(store.find_history("old").unwrap().unwrap().state, Some(MessageState::Cancelled));
+    }
+}
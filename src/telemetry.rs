@@ -0,0 +1,176 @@
+//! Counters for what this client does over the network, for hosts that want
+//! to watch their messaging layer. Built only with the `client-metrics`
+//! feature; without it every recording call is empty and `snapshot` is None.
+
+use crate::types::{LatencyHistogram, LATENCY_BUCKETS_MS};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// How a message send ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendOutcome {
+    /// The server stored it.
+    Accepted,
+    /// The server answered with an error.
+    Refused,
+    /// No answer: the connection failed or dropped.
+    Failed,
+}
+
+impl SendOutcome {
+    fn name(self) -> &'static str {
+        match self {
+            SendOutcome::Accepted => "accepted",
+            SendOutcome::Refused => "refused",
+            SendOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// The counters at one instant.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Snapshot {
+    pub sends: BTreeMap<SendOutcome, u64>,
+    pub received: u64,
+    /// History bodies this client couldn't decrypt.
+    pub decrypt_failures: u64,
+    /// Envelope keys and server receipts that didn't verify.
+    pub verify_failures: u64,
+    /// Dials after a server connection dropped, and how many of them failed.
+    pub reconnects: u64,
+    pub reconnect_failures: u64,
+    /// Request round trips by command, including failed ones.
+    pub latencies: BTreeMap<String, LatencyHistogram>,
+}
+
+impl Snapshot {
+    /// The counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "# TYPE msgproto_client_sends_total counter");
+        for (outcome, count) in &self.sends {
+            let _ = writeln!(text, "msgproto_client_sends_total{{outcome=\"{}\"}} {}", outcome.name(), count);
+        }
+        for (name, value) in [
+            ("received_total", self.received),
+            ("decrypt_failures_total", self.decrypt_failures),
+            ("verify_failures_total", self.verify_failures),
+            ("reconnects_total", self.reconnects),
+            ("reconnect_failures_total", self.reconnect_failures),
+        ] {
+            let _ = writeln!(text, "# TYPE msgproto_client_{} counter", name);
+            let _ = writeln!(text, "msgproto_client_{} {}", name, value);
+        }
+        let _ = writeln!(text, "# TYPE msgproto_client_request_seconds histogram");
+        for (command, histogram) in &self.latencies {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(text, "msgproto_client_request_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, *bound as f64 / 1000.0, cumulative);
+            }
+            let _ = writeln!(text, "msgproto_client_request_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", command, histogram.count);
+            let _ = writeln!(text, "msgproto_client_request_seconds_sum{{command=\"{}\"}} {}", command, histogram.total_micros as f64 / 1e6);
+            let _ = writeln!(text, "msgproto_client_request_seconds_count{{command=\"{}\"}} {}", command, histogram.count);
+        }
+        text
+    }
+}
+
+#[cfg(feature = "client-metrics")]
+mod counters {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub struct Counters {
+        pub sends: [AtomicU64; 3],
+        pub received: AtomicU64,
+        pub decrypt_failures: AtomicU64,
+        pub verify_failures: AtomicU64,
+        pub reconnects: AtomicU64,
+        pub reconnect_failures: AtomicU64,
+        pub latencies: Mutex<BTreeMap<&'static str, LatencyHistogram>>,
+    }
+
+    pub static COUNTERS: Counters = Counters {
+        sends: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        received: AtomicU64::new(0),
+        decrypt_failures: AtomicU64::new(0),
+        verify_failures: AtomicU64::new(0),
+        reconnects: AtomicU64::new(0),
+        reconnect_failures: AtomicU64::new(0),
+        latencies: Mutex::new(BTreeMap::new()),
+    };
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn snapshot() -> Snapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let outcomes = [SendOutcome::Accepted, SendOutcome::Refused, SendOutcome::Failed];
+        Snapshot {
+            sends: outcomes.iter().map(|outcome| (*outcome, load(&COUNTERS.sends[*outcome as usize]))).collect(),
+            received: load(&COUNTERS.received),
+            decrypt_failures: load(&COUNTERS.decrypt_failures),
+            verify_failures: load(&COUNTERS.verify_failures),
+            reconnects: load(&COUNTERS.reconnects),
+            reconnect_failures: load(&COUNTERS.reconnect_failures),
+            latencies: COUNTERS.latencies.lock().unwrap_or_else(|e| e.into_inner()).iter()
+                .map(|(command, histogram)| (command.to_string(), histogram.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// The counters so far; None when built without `client-metrics`.
+#[cfg(feature = "client-metrics")]
+pub fn snapshot() -> Option<Snapshot> {
+    Some(counters::snapshot())
+}
+
+#[cfg(not(feature = "client-metrics"))]
+pub fn snapshot() -> Option<Snapshot> {
+    None
+}
+
+pub fn sent(_outcome: SendOutcome) {
+    #[cfg(feature = "client-metrics")]
+    counters::add(&counters::COUNTERS.sends[_outcome as usize], 1);
+}
+
+pub fn received(_count: usize) {
+    #[cfg(feature = "client-metrics")]
+    counters::add(&counters::COUNTERS.received, _count as u64);
+}
+
+pub fn decrypt_failed() {
+    #[cfg(feature = "client-metrics")]
+    counters::add(&counters::COUNTERS.decrypt_failures, 1);
+}
+
+pub fn verify_failed() {
+    #[cfg(feature = "client-metrics")]
+    counters::add(&counters::COUNTERS.verify_failures, 1);
+}
+
+/// A dropped connection was dialed again; `ok` is whether that worked.
+pub fn reconnected(_ok: bool) {
+    #[cfg(feature = "client-metrics")]
+    {
+        counters::add(&counters::COUNTERS.reconnects, 1);
+        if !_ok {
+            counters::add(&counters::COUNTERS.reconnect_failures, 1);
+        }
+    }
+}
+
+pub fn request_took(_command: &'static str, _took: Duration) {
+    #[cfg(feature = "client-metrics")]
+    counters::COUNTERS.latencies.lock().unwrap_or_else(|e| e.into_inner())
+        .entry(_command).or_default().record(_took);
+}
@@ -0,0 +1,138 @@
+use anyhow::{Result, anyhow};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A piece of a parsed template body.
+enum Part<'a> {
+    Text(&'a str),
+    /// `{{` or `}}`, written as a single brace.
+    Brace(char),
+    Placeholder(&'a str),
+}
+
+/// Split a body into text and `{name}` placeholders. `{{` and `}}` stand for
+/// literal braces; any other stray brace is an error.
+fn parse(body: &str) -> Result<Vec<Part<'_>>> {
+    let mut parts = Vec::new();
+    let mut rest = body;
+    while let Some(index) = rest.find(['{', '}']) {
+        if index > 0 {
+            parts.push(Part::Text(&rest[..index]));
+        }
+        let brace = rest[index..].chars().next().unwrap_or('{');
+        let after = &rest[index + 1..];
+        if after.starts_with(brace) {
+            parts.push(Part::Brace(brace));
+            rest = &after[1..];
+            continue;
+        }
+        if brace == '}' {
+            return Err(anyhow!("Unmatched '}}'; write '}}}}' for a literal brace"));
+        }
+        let end = after.find('}').ok_or_else(|| anyhow!("Unclosed '{{'; write '{{{{' for a literal brace"))?;
+        let name = &after[..end];
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+            return Err(anyhow!("Invalid placeholder '{{{}}}'", name));
+        }
+        parts.push(Part::Placeholder(name));
+        rest = &after[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Ok(parts)
+}
+
+/// Placeholder names in a body, each once.
+pub fn placeholders(body: &str) -> Result<BTreeSet<String>> {
+    Ok(parse(body)?.into_iter()
+        .filter_map(|part| match part {
+            Part::Placeholder(name) => Some(name.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Fill in every placeholder. Missing values and values for placeholders the
+/// template doesn't have are both errors, so typos don't go out unnoticed.
+pub fn expand(body: &str, values: &BTreeMap<String, String>) -> Result<String> {
+    let parts = parse(body)?;
+    let names = placeholders(body)?;
+    let missing: Vec<_> = names.iter().filter(|name| !values.contains_key(*name)).cloned().collect();
+    if !missing.is_empty() {
+        return Err(anyhow!("Missing value(s) for {}", missing.join(", ")));
+    }
+    let unknown: Vec<_> = values.keys().filter(|key| !names.contains(*key)).cloned().collect();
+    if !unknown.is_empty() {
+        return Err(anyhow!("Template has no placeholder(s) {}", unknown.join(", ")));
+    }
+
+    let mut expanded = String::with_capacity(body.len());
+    for part in parts {
+        match part {
+            Part::Text(text) => expanded.push_str(text),
+            Part::Brace(brace) => expanded.push(brace),
+            Part::Placeholder(name) => expanded.push_str(&values[name]),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Parse `name=value` arguments.
+pub fn parse_values(args: &[&str]) -> Result<BTreeMap<String, String>> {
+    args.iter()
+        .map(|arg| arg.split_once('=')
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .ok_or_else(|| anyhow!("Expected name=value, got '{}'", arg)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn every_placeholder_is_filled_in_wherever_it_appears() {
+        let body = "Hi {name}, {name}! Your code is {code}.";
+        assert_eq!(expand(body, &values(&[("name", "bob"), ("code", "42")])).unwrap(), "Hi bob, bob! Your code is 42.");
+        assert_eq!(expand("no placeholders", &values(&[])).unwrap(), "no placeholders");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let body = "{{name}} is written {name}, }}{{ and {{{name}}}";
+        assert_eq!(expand(body, &values(&[("name", "bob")])).unwrap(), "{name} is written bob, }{ and {bob}");
+        assert_eq!(placeholders(body).unwrap(), BTreeSet::from(["name".to_string()]));
+        assert!(placeholders("{{only}} literals").unwrap().is_empty());
+    }
+
+    #[test]
+    fn values_are_put_in_as_they_are() {
+        assert_eq!(expand("{a}", &values(&[("a", "{b} and {{")])).unwrap(), "{b} and {{");
+    }
+
+    #[test]
+    fn stray_braces_and_bad_names_are_refused() {
+        for body in ["a } b", "a { b", "{unclosed", "{}", "{two words}", "{a{b}"] {
+            assert!(parse(body).is_err(), "{:?} parsed", body);
+        }
+        assert!(expand("}", &values(&[])).unwrap_err().to_string().contains("write '}}' for a literal brace"));
+    }
+
+    #[test]
+    fn missing_and_unknown_values_are_both_errors() {
+        let missing = expand("{a} {b}", &values(&[("a", "1")])).unwrap_err();
+        assert_eq!(missing.to_string(), "Missing value(s) for b");
+        let unknown = expand("{a}", &values(&[("a", "1"), ("typo", "2")])).unwrap_err();
+        assert_eq!(unknown.to_string(), "Template has no placeholder(s) typo");
+    }
+
+    #[test]
+    fn values_split_at_the_first_equals_sign() {
+        assert_eq!(parse_values(&["a=b=c", "empty="]).unwrap(), values(&[("a", "b=c"), ("empty", "")]));
+        assert!(parse_values(&["no-equals"]).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! What the client and server talk over: TCP, or in simulations, the fake
+//! links of [`crate::sim`]. Everything above this reads and writes frames
+//! on a [`Connection`] and doesn't know which.
+
+use futures::future::BoxFuture;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A connection's byte stream.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+/// An open connection to or from a peer.
+pub type Connection = Box<dyn Stream>;
+
+/// Connections as a server takes them, with the peer's address.
+pub type Incoming = futures::stream::BoxStream<'static, io::Result<(Connection, SocketAddr)>>;
+
+/// Opens connections to addresses.
+pub trait Connector: Send + Sync {
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Connection>>;
+}
+
+/// Real TCP connections.
+pub struct Tcp;
+
+impl Connector for Tcp {
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move { Ok(Box::new(TcpStream::connect(addr).await?) as Connection) })
+    }
+}
+
+/// The connections `listener` accepts, until it is dropped with the stream.
+pub fn accept(listener: TcpListener) -> Incoming {
+    Box::pin(futures::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await.map(|(socket, addr)| (Box::new(socket) as Connection, addr));
+        Some((accepted, listener))
+    }))
+}
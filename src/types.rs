@@ -1,5 +1,11 @@
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
@@ -7,44 +13,1642 @@ pub struct Message {
     pub sender_id: String,
     pub recipient_id: String,
     pub content: String,
+    /// Wall-clock time the server stored it; informational, order by `hlc`.
     pub timestamp: DateTime<Utc>,
     pub encrypted: bool,
     pub signature: Option<String>, // Store as hex string
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Bounced back to the sender if not fetched by then.
+    #[serde(default)]
+    pub deliver_by: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub delivered_at: Option<DateTime<Utc>>,
+    /// Set when the server, not a client, wrote the message.
+    #[serde(default)]
+    pub notice: Option<SystemNotice>,
+    /// Position in the server's message order, assigned when stored.
+    #[serde(default)]
+    pub hlc: Hlc,
+    /// The X25519 key the sender encrypted with, so a recipient without them
+    /// as a contact can still decrypt.
+    #[serde(default)]
+    pub sender_key: Option<SenderKey>,
+    /// Length of the key log when the server checked the signature; the
+    /// sender's key as of then is the one it must verify against.
+    #[serde(default)]
+    pub key_epoch: Option<u64>,
+    /// Set when the message came in through a guest link rather than from a
+    /// registered client.
+    #[serde(default)]
+    pub guest: Option<GuestOrigin>,
+    /// Server signature over `receipt_payload`, attesting `timestamp` as
+    /// when it accepted the message. Unset from servers that predate it.
+    #[serde(default)]
+    pub server_signature: Option<String>,
+    /// The sender's running count of messages to this recipient, so they
+    /// can be shown in the order written. Unset from older clients.
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// Set when a delegate sent the message as `sender_id`; `signature` is
+    /// the delegate's.
+    #[serde(default)]
+    pub sent_by: Option<String>,
+    /// Id of an earlier message from the same sender that this one replaces,
+    /// such as the original of an edit.
+    #[serde(default)]
+    pub supersedes: Option<String>,
+    /// The superseded message was still unfetched, so the server dropped it
+    /// and the recipient never sees it.
+    #[serde(default)]
+    pub replaced_queued: bool,
+    /// The sender's clock when they sent it, covered by `signature`. Unset
+    /// from older clients, whose signature covers the ciphertext alone.
+    #[serde(default)]
+    pub sent_at: Option<DateTime<Utc>>,
+    /// The group the message went to; the sender sends each member a copy.
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// Where a guest message came from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestOrigin {
+    pub link_id: String,
+    /// The guest's one-off X25519 key, when the message is encrypted.
+    #[serde(default)]
+    pub ephemeral_key: Option<String>,
+}
+
+/// A time-boxed link through which people without an identity can message
+/// its owner.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestLink {
+    pub id: String,
+    pub owner: String,
+    /// SHA-256 of the token secret; the secret itself is never stored.
+    pub secret_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub max_messages: u32,
+    pub used: u32,
+    pub revoked: bool,
+    /// The owner's X25519 key. When set, guests must encrypt to it.
+    #[serde(default)]
+    pub x25519_public_key: Option<String>,
+}
+
+impl GuestLink {
+    pub fn hash_secret(secret: &str) -> String {
+        hex::encode(Sha256::digest(secret.as_bytes()))
+    }
+}
+
+/// How a client directory entry changed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectoryChangeKind {
+    Added,
+    Removed,
+    /// Its key was replaced or revoked.
+    Changed,
+}
+
+/// One change to the client directory, and the revision it made.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryChange {
+    pub revision: u64,
+    pub client_id: String,
+    pub kind: DirectoryChangeKind,
+}
+
+/// One change in an `AdminBatch`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminAction {
+    /// Refuse the id's signed requests and registration until it's unbanned.
+    Ban {
+        client_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    Unban { client_id: String },
+    /// Delete everything waiting in the id's mailbox.
+    PruneMailbox { client_id: String },
+    /// Queue a notice in each listed mailbox, or every registered one if
+    /// none are listed.
+    Notice {
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        recipients: Vec<String>,
+    },
+    CreateInviteCode { code: String },
+    /// Stop taking connections, tell the open ones the server is going
+    /// away, serve them for up to `grace_secs` more, then save and exit.
+    Drain { grace_secs: u64 },
+}
+
+impl AdminAction {
+    /// Short name for logs and the audit log.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AdminAction::Ban { .. } => "ban",
+            AdminAction::Unban { .. } => "unban",
+            AdminAction::PruneMailbox { .. } => "prune_mailbox",
+            AdminAction::Notice { .. } => "notice",
+            AdminAction::CreateInviteCode { .. } => "create_invite_code",
+            AdminAction::Drain { .. } => "drain",
+        }
+    }
+}
+
+/// How one action of an `AdminBatch` went.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminActionResult {
+    pub ok: bool,
+    /// What it did, or why it failed or wasn't applied.
+    pub detail: String,
+}
+
+/// Why an id was banned, and by whom.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    pub by: String,
+    pub at: DateTime<Utc>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// A single-use code an admin made for registering on a server that asks
+/// for one. Stored under the hash of the code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteCode {
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub used_by: Option<String>,
+    #[serde(default)]
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+impl InviteCode {
+    pub fn hash_code(code: &str) -> String {
+        hex::encode(Sha256::digest(code.as_bytes()))
+    }
+}
+
+/// What a delegate may do with a mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DelegationScope {
+    Read,
+    /// Read, and send as the owner.
+    ReadSend,
+}
+
+impl DelegationScope {
+    pub fn allows_send(self) -> bool {
+        self == DelegationScope::ReadSend
+    }
+}
+
+impl fmt::Display for DelegationScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DelegationScope::Read => "read",
+            DelegationScope::ReadSend => "read-send",
+        })
+    }
+}
+
+impl FromStr for DelegationScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(DelegationScope::Read),
+            "read-send" => Ok(DelegationScope::ReadSend),
+            other => Err(anyhow!("unknown delegation scope '{}'; use read or read-send", other)),
+        }
+    }
+}
+
+/// A mailbox owner's signed grant letting `delegate` fetch its messages,
+/// and with `ReadSend` send as it, until `expires_at`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delegation {
+    pub owner: String,
+    pub delegate: String,
+    pub scope: DelegationScope,
+    /// A grant only replaces one issued before it, so an old statement
+    /// can't be replayed to undo a revocation.
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// The owner's signature over `delegation_payload`.
+    pub signature: String,
+    /// Set by the server when the owner revokes the grant.
+    #[serde(default)]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// What a member may do in a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    /// May rename the group. Whoever created it, or the longest-standing
+    /// member once every owner has left.
+    Owner,
+    Member,
+}
+
+impl fmt::Display for GroupRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GroupRole::Owner => "owner",
+            GroupRole::Member => "member",
+        })
+    }
+}
+
+/// A named set of clients that messages can be addressed to. Names are
+/// unique on a server; only members see a group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub members: BTreeMap<String, GroupRole>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    /// When each member joined, so ownership passes to the longest-standing.
+    #[serde(default)]
+    pub joined_at: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// One thing a delegate did with a mailbox, kept for its owner to review.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DelegationAudit {
+    pub at: DateTime<Utc>,
+    pub owner: String,
+    pub delegate: String,
+    /// `fetch` or `send`.
+    pub action: String,
+    #[serde(default)]
+    pub message_id: Option<String>,
+}
+
+/// Everything the server keeps about one client, as shown to that client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccountUsage {
+    pub client_id: String,
+    pub registered_at: Option<DateTime<Utc>>,
+    /// Last time the client registered or sent a heartbeat. The server keeps
+    /// no per-device sessions, so this is the only activity it knows of.
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Messages from clients waiting in the mailbox; server notices excluded.
+    pub stored_messages: usize,
+    /// Ciphertext bytes of those messages.
+    pub stored_bytes: u64,
+    pub oldest_stored: Option<DateTime<Utc>>,
+    pub newest_stored: Option<DateTime<Utc>>,
+    /// Messages the client sent that wait, unfetched, in recipients' mailboxes.
+    pub sent_undelivered: usize,
+    /// Messages the client sent that are held until their recipient registers.
+    pub sent_held: usize,
+    /// Guest links that are neither revoked nor expired.
+    pub guest_links: usize,
+    pub user_data_keys: usize,
+    pub user_data_bytes: usize,
+    /// Grants the client gave, and grants others gave it, including
+    /// expired and revoked ones.
+    pub delegations_granted: Vec<Delegation>,
+    pub delegations_received: Vec<Delegation>,
+    /// Most messages the mailbox may hold; unlimited if unset.
+    pub mailbox_quota: Option<usize>,
+    pub user_data_quota_bytes: usize,
+}
+
+/// One value a client keeps on the server for itself, such as state shared
+/// between its devices. The server only sees an opaque, client-encrypted blob.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDataEntry {
+    /// Hex ciphertext.
+    pub value: String,
+    /// Starts at 1 and goes up by one with every write.
+    pub version: u64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Everything a guest needs to message a link's owner, passed around as one
+/// opaque string.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestToken {
+    /// Address of the owner's server.
+    pub server: String,
+    pub owner: String,
+    pub link_id: String,
+    pub secret: String,
+    #[serde(default)]
+    pub x25519_public_key: Option<String>,
+}
+
+const GUEST_TOKEN_PREFIX: &str = "guest-";
+
+impl fmt::Display for GuestToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_vec(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}{}", GUEST_TOKEN_PREFIX, hex::encode(json))
+    }
+}
+
+impl FromStr for GuestToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let hex_json = s.strip_prefix(GUEST_TOKEN_PREFIX).ok_or_else(|| anyhow!("Not a guest token"))?;
+        let json = hex::decode(hex_json).map_err(|_| anyhow!("Guest token is damaged"))?;
+        serde_json::from_slice(&json).map_err(|_| anyhow!("Guest token is damaged"))
+    }
+}
+
+/// A sender's X25519 public key, signed by their Ed25519 identity key over
+/// `sender_key_payload`. Its fingerprint serves as the key id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SenderKey {
+    pub x25519_public_key: String,
+    pub signature: String,
+}
+
+/// A client's answer to a `Challenge`: the nonce, and its signature over
+/// `challenge_payload` by the id's key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeAnswer {
+    pub nonce: String,
+    pub signature: String,
+}
+
+/// Hybrid logical clock value: milliseconds of wall time plus a counter that
+/// breaks ties and carries the order on while the wall clock stands still or
+/// steps backwards. Written as `<millis>.<counter>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Hlc {
+    pub wall_ms: i64,
+    pub counter: u32,
+}
+
+impl Hlc {
+    /// The next value after `self` given the current wall time: the wall time
+    /// if it moved forward, otherwise `self` with the counter bumped.
+    pub fn next(self, now: DateTime<Utc>) -> Hlc {
+        let wall_ms = now.timestamp_millis();
+        if wall_ms > self.wall_ms {
+            Hlc { wall_ms, counter: 0 }
+        } else {
+            Hlc { wall_ms: self.wall_ms, counter: self.counter + 1 }
+        }
+    }
+
+    /// Value for a message stored before clocks existed.
+    pub fn from_timestamp(timestamp: DateTime<Utc>) -> Hlc {
+        Hlc { wall_ms: timestamp.timestamp_millis(), counter: 0 }
+    }
+
+    pub fn is_unset(&self) -> bool {
+        *self == Hlc::default()
+    }
+}
+
+impl fmt::Display for Hlc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.wall_ms, self.counter)
+    }
+}
+
+impl FromStr for Hlc {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (wall_ms, counter) = s.split_once('.').ok_or_else(|| anyhow!("Invalid cursor {}; expected <millis>.<counter>", s))?;
+        Ok(Hlc { wall_ms: wall_ms.parse()?, counter: counter.parse()? })
+    }
+}
+
+/// Something the server tells a client, queued in its mailbox like a message.
+/// `notice_type` and `fields` are for programs; `text` is for people.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemNotice {
+    pub notice_type: String,
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+/// Known `SystemNotice::notice_type` values and their fields.
+pub mod notice_type {
+    /// A message was dropped undelivered: `message_id`, `recipient_id`, `reason`.
+    pub const DELIVERY_FAILED: &str = "delivery_failed";
+    /// The mailbox is close to its quota: `used`, `limit`.
+    pub const QUOTA_WARNING: &str = "quota_warning";
+    /// Sent to admins when server data crosses the warning size: `used`, `threshold`.
+    pub const DISK_USAGE: &str = "disk_usage";
+    /// A message held for an unregistered id reached its mailbox when the id
+    /// was registered: `message_id`, `recipient_id`.
+    pub const INVITE_CLAIMED: &str = "invite_claimed";
+    /// A message from the server's admins: `from`.
+    pub const ADMIN_NOTICE: &str = "admin_notice";
+    /// Someone made a group with the recipient in it: `group`, `by`.
+    pub const GROUP_ADDED: &str = "group_added";
+}
+
+/// `reason` of a delivery_failed notice when the delivery deadline passed.
+pub const EXPIRED_UNDELIVERED: &str = "expired_undelivered";
+/// `reason` of a delivery_failed notice when nobody registered the invited id in time.
+pub const INVITE_UNCLAIMED: &str = "invite_unclaimed";
+
+impl SystemNotice {
+    pub fn new(notice_type: &str, fields: &[(&str, String)], text: String) -> Self {
+        Self {
+            notice_type: notice_type.to_string(),
+            fields: fields.iter().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+            text: Some(text),
+        }
+    }
+
+    pub fn field(&self, name: &str) -> &str {
+        self.fields.get(name).map(String::as_str).unwrap_or("")
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        if self.notice.is_some() { MessageKind::Notice } else { MessageKind::Message }
+    }
+
+    /// Size of the stored content in bytes (not of its hex encoding).
+    pub fn size(&self) -> usize {
+        self.content.len() / 2
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Message,
+    /// Generated by the server: a SystemNotice.
+    Notice,
+}
+
+/// Most results a mailbox search returns, and the default when no limit is given.
+pub const MAX_SEARCH_RESULTS: usize = 500;
+pub const DEFAULT_SEARCH_RESULTS: usize = 50;
+
+/// Metadata filters for searching one's own mailbox. Unset fields match everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessageSearch {
+    #[serde(default)]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub after: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub min_size: Option<usize>,
+    #[serde(default)]
+    pub kind: Option<MessageKind>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Continue a truncated search: only messages older than this.
+    #[serde(default)]
+    pub cursor: Option<Hlc>,
+}
+
+impl MessageSearch {
+    pub fn matches(&self, message: &Message) -> bool {
+        self.from.as_ref().is_none_or(|from| &message.sender_id == from)
+            && self.after.is_none_or(|after| message.timestamp >= after)
+            && self.before.is_none_or(|before| message.timestamp < before)
+            && self.min_size.is_none_or(|min| message.size() >= min)
+            && self.kind.is_none_or(|kind| message.kind() == kind)
+            && self.cursor.is_none_or(|cursor| message.hlc < cursor)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_SEARCH_RESULTS).min(MAX_SEARCH_RESULTS)
+    }
+
+    /// Bytes a client signs to search its mailbox at `signed_at`.
+    pub fn payload(&self, client_id: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+        let time = |t: Option<DateTime<Utc>>| t.map(|t| t.to_rfc3339()).unwrap_or_default();
+        format!("search\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            client_id,
+            signed_at.timestamp_millis(),
+            self.from.as_deref().unwrap_or(""),
+            time(self.after),
+            time(self.before),
+            self.min_size.map(|n| n.to_string()).unwrap_or_default(),
+            self.kind.map(|k| format!("{:?}", k)).unwrap_or_default(),
+            self.limit.map(|n| n.to_string()).unwrap_or_default(),
+            self.cursor.map(|c| c.to_string()).unwrap_or_default(),
+        ).into_bytes()
+    }
+}
+
+/// What a mailbox search reveals about a message: never its content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageMetadata {
+    pub id: String,
+    pub sender_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub size: usize,
+    pub kind: MessageKind,
+    pub delivered: bool,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub hlc: Hlc,
+}
+
+impl From<&Message> for MessageMetadata {
+    fn from(message: &Message) -> Self {
+        Self {
+            id: message.id.clone(),
+            sender_id: message.sender_id.clone(),
+            timestamp: message.timestamp,
+            size: message.size(),
+            kind: message.kind(),
+            delivered: message.delivered_at.is_some(),
+            expires_at: message.expires_at,
+            hlc: message.hlc,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientInfo {
     pub id: String,
     pub public_key: String,
+    #[serde(default)]
+    pub recovery_key: Option<String>, // Ed25519 key allowed to revoke on the client's behalf
     pub registered_at: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
+    /// How the id first came to be registered. Missing for clients
+    /// registered before this was recorded.
+    #[serde(default)]
+    pub registration: Option<Registration>,
+    /// The key others encrypt to, signed by `public_key`. Unset for clients
+    /// that registered without one.
+    #[serde(default)]
+    pub x25519_key: Option<SenderKey>,
+}
+
+/// Whether one client is online, as a `GetPresence` answer sees it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresenceEntry {
+    pub client_id: String,
+    /// Holds an open connection to the server.
+    pub online: bool,
+    /// Unset for ids that aren't registered.
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Where and how a client first registered, for abuse investigations. Only
+/// ever shown to admins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Registration {
+    /// Peer address, its salted hash, or nothing, per the server's
+    /// source address policy.
+    #[serde(default)]
+    pub source: Option<String>,
+    pub transport: String,
+    /// Agreed protocol version; unset for clients that predate negotiation.
+    #[serde(default)]
+    pub protocol_version: Option<String>,
+    /// Optional features agreed at registration.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// "invite" when messages were already being held for the id.
+    #[serde(default)]
+    pub via: Option<String>,
+}
+
+/// Signed statement that a client's Ed25519 key must no longer be trusted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revocation {
+    pub client_id: String,
+    pub key: String, // Hex Ed25519 key being revoked
+    pub reason: String,
+    pub timestamp: DateTime<Utc>,
+    pub signature: String, // By the revoked key itself or the registered recovery key
+}
+
+impl Revocation {
+    /// Bytes covered by the signature.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        format!("revoke\n{}\n{}\n{}\n{}", self.client_id, self.key, self.reason, self.timestamp.to_rfc3339())
+            .into_bytes()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KeyEvent {
+    Registered,
+    Revoked,
+}
+
+/// One entry in the server's append-only, hash-chained log of key changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyLogEntry {
+    pub seq: u64,
+    pub client_id: String,
+    pub public_key: String,
+    pub event: KeyEvent,
+    pub timestamp: DateTime<Utc>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl KeyLogEntry {
+    pub fn compute_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.prev_hash.as_bytes());
+        hasher.update(self.seq.to_be_bytes());
+        hasher.update(self.client_id.as_bytes());
+        hasher.update(self.public_key.as_bytes());
+        hasher.update(format!("{:?}", self.event).as_bytes());
+        hasher.update(self.timestamp.to_rfc3339().as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    /// Check that every entry hashes correctly and links to its predecessor.
+    pub fn verify_chain(entries: &[KeyLogEntry]) -> Result<(), String> {
+        let mut prev_hash = String::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.seq != i as u64 {
+                return Err(format!("entry {} has sequence number {}", i, entry.seq));
+            }
+            if entry.prev_hash != prev_hash {
+                return Err(format!("entry {} does not link to its predecessor", i));
+            }
+            if entry.compute_hash() != entry.hash {
+                return Err(format!("entry {} has been altered", i));
+            }
+            prev_hash = entry.hash.clone();
+        }
+        Ok(())
+    }
+}
+
+/// Bytes a recipient signs to set a retention policy at `signed_at`.
+pub fn retention_payload(client_id: &str, sender_id: &str, ttl_secs: Option<u64>, signed_at: DateTime<Utc>) -> Vec<u8> {
+    let ttl = ttl_secs.map(|t| t.to_string()).unwrap_or_else(|| "off".to_string());
+    format!("retention\n{}\n{}\n{}\n{}", client_id, sender_id, ttl, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes a sender signs to send a message: the send time, recipient and
+/// message id, then the raw ciphertext. Clients that predate `sent_at`
+/// signed the ciphertext alone, which bound neither the id nor the
+/// recipient; those sends are refused.
+pub fn send_payload(message_id: &str, recipient_id: &str, ciphertext: &[u8], sent_at: DateTime<Utc>) -> Vec<u8> {
+    let mut payload = format!("send\n{}\n{}\n{}\n", sent_at.timestamp_millis(), recipient_id, message_id).into_bytes();
+    payload.extend_from_slice(ciphertext);
+    payload
+}
+
+/// Bytes a sender signs to vouch for the X25519 key in their envelopes.
+pub fn sender_key_payload(sender_id: &str, x25519_public_key: &str) -> Vec<u8> {
+    format!("sender-key\n{}\n{}", sender_id, x25519_public_key).into_bytes()
+}
+
+/// Bytes a recipient signs to report a sender at `signed_at`.
+pub fn report_payload(client_id: &str, sender_id: &str, reason: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("report\n{}\n{}\n{}\n{}", client_id, sender_id, signed_at.timestamp_millis(), reason).into_bytes()
+}
+
+/// Bytes the server signs to attest when it accepted a message.
+pub fn receipt_payload(message_id: &str, sender_id: &str, recipient_id: &str, received_at: DateTime<Utc>) -> Vec<u8> {
+    format!("receipt\n{}\n{}\n{}\n{}", message_id, sender_id, recipient_id, received_at.to_rfc3339()).into_bytes()
+}
+
+/// Bytes a client signs to create a guest link at `signed_at`.
+pub fn guest_link_payload(client_id: &str, ttl_secs: u64, max_messages: u32, x25519_public_key: Option<&str>, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("guest-link\n{}\n{}\n{}\n{}\n{}", client_id, ttl_secs, max_messages, x25519_public_key.unwrap_or(""), signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes a client signs to list its guest links, or revoke one (`link_id`).
+pub fn guest_link_ref_payload(client_id: &str, link_id: &str) -> Vec<u8> {
+    format!("guest-link-ref\n{}\n{}", client_id, link_id).into_bytes()
+}
+
+/// Bytes an owner signs to delegate its mailbox.
+pub fn delegation_payload(owner: &str, delegate: &str, scope: DelegationScope, issued_at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Vec<u8> {
+    format!("delegate\n{}\n{}\n{}\n{}\n{}", owner, delegate, scope, issued_at.to_rfc3339(), expires_at.to_rfc3339()).into_bytes()
+}
+
+/// Bytes an owner signs to revoke a delegation, and a delegate signs to
+/// fetch the owner's messages.
+pub fn delegation_ref_payload(owner: &str, delegate: &str) -> Vec<u8> {
+    format!("delegation-ref\n{}\n{}", owner, delegate).into_bytes()
+}
+
+/// Bytes an owner signs to list its delegations at `signed_at`.
+pub fn delegations_payload(owner: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("delegations\n{}\n{}", owner, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes a client signs to write one of its user data keys. `value` is None
+/// to delete the key.
+pub fn user_data_payload(client_id: &str, key: &str, value: Option<&str>, version: u64) -> Vec<u8> {
+    format!("user-data\n{}\n{}\n{}\n{}", client_id, key, version, value.unwrap_or("")).into_bytes()
+}
+
+/// Bytes an admin signs to look up a client's registration details at `signed_at`.
+pub fn client_details_payload(admin_id: &str, client_id: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("client-details\n{}\n{}\n{}", admin_id, client_id, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes an admin signs to make an invite code at `signed_at`.
+pub fn invite_code_payload(admin_id: &str, code: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("invite-code\n{}\n{}\n{}", admin_id, code, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes an admin signs to promote a standby at `signed_at`.
+pub fn promote_payload(admin_id: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("promote\n{}\n{}", admin_id, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes an admin signs to run a batch of admin actions at `signed_at`.
+pub fn admin_batch_payload(admin_id: &str, actions: &[AdminAction], transactional: bool, signed_at: DateTime<Utc>) -> Vec<u8> {
+    let actions = serde_json::to_string(actions).unwrap_or_default();
+    format!("admin-batch\n{}\n{}\n{}\n{}", admin_id, signed_at.timestamp_millis(), transactional, actions).into_bytes()
+}
+
+/// Bytes a client signs to ask who of `contacts` is online.
+pub fn presence_payload(client_id: &str, contacts: &[String]) -> Vec<u8> {
+    format!("presence\n{}\n{}", client_id, contacts.join(",")).into_bytes()
+}
+
+/// Bytes an admin signs to take a debug dump of the server's internals at
+/// `signed_at`.
+pub fn debug_dump_payload(admin_id: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("debug-dump\n{}\n{}", admin_id, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes a client signs to read one of its user data keys at `signed_at`.
+pub fn user_data_ref_payload(client_id: &str, key: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("user-data-ref\n{}\n{}\n{}", client_id, key, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes a client signs to see what the server keeps about it, at `signed_at`.
+pub fn usage_payload(client_id: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("usage\n{}\n{}", client_id, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes a client signs to act on a group. `action` is the command's name,
+/// and `detail` its members or new name, comma-separated.
+pub fn group_payload(client_id: &str, action: &str, group: &str, detail: &str, signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("group\n{}\n{}\n{}\n{}\n{}", client_id, action, group, detail, signed_at.timestamp_millis()).into_bytes()
+}
+
+/// Bytes a client signs to prove it holds `client_id`'s key.
+pub fn challenge_payload(client_id: &str, nonce: &str) -> Vec<u8> {
+    format!("challenge\n{}\n{}", client_id, nonce).into_bytes()
+}
+
+/// Bytes a client signs to have fetched messages deleted, at `signed_at`.
+pub fn ack_payload(client_id: &str, message_ids: &[String], signed_at: DateTime<Utc>) -> Vec<u8> {
+    format!("ack\n{}\n{}\n{}", client_id, signed_at.timestamp_millis(), message_ids.join("\n")).into_bytes()
+}
+
+/// Bytes a sender signs to ask about, or cancel, one of their messages.
+pub fn message_ref_payload(sender_id: &str, message_id: &str) -> Vec<u8> {
+    format!("message\n{}\n{}", sender_id, message_id).into_bytes()
+}
+
+/// Where a sent message stands, as far as its sender may know.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    /// Waiting in the recipient's mailbox.
+    Stored,
+    /// Fetched by the recipient at least once.
+    Delivered,
+    /// Removed by its sender before it was fetched.
+    Cancelled,
+    /// Dropped unfetched when the sender's message `by` replaced it.
+    Superseded { by: String },
+    /// Not found, expired, or not sent by the requester.
+    Unknown,
+}
+
+/// Bytes the server signs to vouch for a client's key at a directory epoch.
+pub fn key_directory_payload(client_id: &str, public_key: Option<&str>, epoch: u64, head_hash: &str) -> Vec<u8> {
+    format!("key-directory\n{}\n{}\n{}\n{}", client_id, public_key.unwrap_or(""), epoch, head_hash).into_bytes()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerCommand {
-    Register { client_id: String, public_key: String },
+    Register {
+        client_id: String,
+        public_key: String,
+        #[serde(default)]
+        recovery_key: Option<String>,
+        /// Highest protocol version the client speaks; unset from clients
+        /// that predate negotiation.
+        #[serde(default)]
+        protocol_version: Option<u32>,
+        /// Bitset of the optional features the client wants; see `features`.
+        #[serde(default)]
+        features: Option<u64>,
+        /// Invite code or token, for servers that gate who may register.
+        #[serde(default)]
+        credential: Option<String>,
+        /// Hex X25519 key to publish for others to encrypt to, with
+        /// `x25519_signature` over `sender_key_payload` by `public_key`.
+        #[serde(default)]
+        x25519_public_key: Option<String>,
+        #[serde(default)]
+        x25519_signature: Option<String>,
+        /// Unset at first; the server answers with a `Challenge`, and the
+        /// command is sent again with it signed.
+        #[serde(default)]
+        challenge: Option<ChallengeAnswer>,
+    },
     Send { 
         sender_id: String, 
         recipient_id: String, 
         encrypted_content: String,
         signature: String,
         message_id: String,
+        #[serde(default)]
+        ttl_secs: Option<u64>,
+        #[serde(default)]
+        deliver_by: Option<DateTime<Utc>>,
+        #[serde(default)]
+        sender_key: Option<SenderKey>,
+        #[serde(default)]
+        seq: Option<u64>,
+        /// Send as this owner, as their delegate. `sender_id` still signs.
+        #[serde(default)]
+        on_behalf_of: Option<String>,
+        /// An earlier message to the same recipient that this one replaces.
+        /// The server drops it if the recipient hasn't fetched it yet.
+        #[serde(default)]
+        supersedes: Option<String>,
+        /// The sender's clock, signed along with the ciphertext; see
+        /// `send_payload`. The server refuses sends without one, or too
+        /// far from its own.
+        #[serde(default)]
+        sent_at: Option<DateTime<Utc>>,
+        /// The group this is a copy of a message to. Sender and recipient
+        /// must both be members.
+        #[serde(default)]
+        group: Option<String>,
+    },
+    /// `since` is a sync cursor: only messages stored after that clock value.
+    GetMessages {
+        client_id: String,
+        #[serde(default)]
+        since: Option<Hlc>,
+        /// Fetch this owner's messages instead, as their delegate.
+        #[serde(default)]
+        on_behalf_of: Option<String>,
+        /// Required with `on_behalf_of`: `client_id`'s signature over
+        /// delegation_ref_payload(owner, client_id).
+        #[serde(default)]
+        signature: Option<String>,
+        /// As with `Register`: set when sending again to a `Challenge`.
+        #[serde(default)]
+        challenge: Option<ChallengeAnswer>,
+    },
+    /// Delete fetched messages from the client's mailbox once it has them.
+    Ack {
+        client_id: String,
+        message_ids: Vec<String>,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over ack_payload
     },
-    GetMessages { client_id: String },
     GetClients,
+    /// What changed in the client directory after `since_revision`, a
+    /// chunk at a time. 0 asks for everything.
+    GetClientsDelta { since_revision: u64 },
+    /// The server's signing key, asked for before registering.
+    GetServerKey,
+    /// Keeps a connection open. It isn't signed, so it doesn't count
+    /// towards the id's `last_seen`; sends, fetches and acks do.
     Heartbeat { client_id: String },
+    Revoke { revocation: Revocation },
+    GetRevocations { client_id: String },
+    GetKeyHistory { client_id: String },
+    /// A client's registered keys, so others can write to them without
+    /// exchanging keys by hand.
+    GetPublicKey { client_id: String },
+    /// Retention for messages `client_id` receives from `sender_id`; None clears it.
+    SetRetention {
+        client_id: String,
+        sender_id: String,
+        ttl_secs: Option<u64>,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String,
+    },
+    Stats,
+    GetMessageStatus {
+        sender_id: String,
+        message_id: String,
+        signature: String, // Signature over message_ref_payload
+    },
+    CancelMessage {
+        sender_id: String,
+        message_id: String,
+        signature: String, // Signature over message_ref_payload
+    },
+    SearchMessages {
+        client_id: String,
+        #[serde(flatten)]
+        search: MessageSearch,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over MessageSearch::payload
+    },
+    /// `client_id` reports `sender_id` for spam, muting them for a while.
+    ReportSender {
+        client_id: String,
+        sender_id: String,
+        reason: String,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over report_payload
+    },
+    CreateGuestLink {
+        client_id: String,
+        ttl_secs: u64,
+        max_messages: u32,
+        /// Embed this X25519 key so guests encrypt to it.
+        #[serde(default)]
+        x25519_public_key: Option<String>,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over guest_link_payload
+    },
+    ListGuestLinks {
+        client_id: String,
+        signature: String, // Signature over guest_link_ref_payload with an empty link id
+    },
+    RevokeGuestLink {
+        client_id: String,
+        link_id: String,
+        signature: String, // Signature over guest_link_ref_payload
+    },
+    /// Write a user data key, if it is still at `version` (0 for a key that
+    /// doesn't exist yet). `value` None deletes the key.
+    PutUserData {
+        client_id: String,
+        key: String,
+        #[serde(default)]
+        value: Option<String>,
+        version: u64,
+        signature: String, // Signature over user_data_payload
+    },
+    GetUserData {
+        client_id: String,
+        key: String,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over user_data_ref_payload
+    },
+    /// Admins only: everything the server records about a client.
+    GetClientDetails {
+        admin_id: String,
+        client_id: String,
+        /// The admin's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Admin's signature over client_details_payload
+    },
+    /// Admins only: make `code` a single-use invite code for registering.
+    CreateInviteCode {
+        admin_id: String,
+        code: String,
+        /// The admin's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Admin's signature over invite_code_payload
+    },
+    /// Admins only: make a standby the primary. It stops following its
+    /// primary and starts taking writes.
+    Promote {
+        admin_id: String,
+        /// The admin's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Admin's signature over promote_payload
+    },
+    /// Admins only: a snapshot of connections, queues, sweeps and lock
+    /// contention, for diagnosing a server that has slowed down.
+    DebugDump {
+        admin_id: String,
+        /// The admin's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Admin's signature over debug_dump_payload
+    },
+    /// Admins only: several changes at once. Transactional batches apply
+    /// all of them or none; others apply what they can.
+    AdminBatch {
+        admin_id: String,
+        actions: Vec<AdminAction>,
+        transactional: bool,
+        /// The admin's clock, signed along with the batch. The server
+        /// refuses batches too far from its own, and any it already ran.
+        signed_at: DateTime<Utc>,
+        signature: String, // Admin's signature over admin_batch_payload
+    },
+    /// Who of `contacts` is online. An empty list asks about everyone
+    /// registered, which only admins may.
+    GetPresence {
+        client_id: String,
+        #[serde(default)]
+        contacts: Vec<String>,
+        signature: String, // Client's signature over presence_payload
+    },
+    /// Store an owner's grant to a delegate, replacing any earlier one.
+    Delegate { delegation: Delegation },
+    /// Withdraw a grant; the delegate is cut off at once.
+    RevokeDelegation {
+        owner: String,
+        delegate: String,
+        signature: String, // Signature over delegation_ref_payload
+    },
+    /// An owner's grants and what its delegates did.
+    ListDelegations {
+        client_id: String,
+        /// The owner's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over delegations_payload
+    },
+    /// What the server keeps about the requester, and its limits.
+    MyUsage {
+        client_id: String,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over usage_payload
+    },
+    /// Create a group of the client and `members`, with the client as owner.
+    CreateGroup {
+        client_id: String,
+        group: String,
+        members: Vec<String>,
+        /// The client's clock, signed; as with `AdminBatch`.
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over group_payload
+    },
+    /// A group's members and their roles; members only.
+    GetGroup {
+        client_id: String,
+        group: String,
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over group_payload
+    },
+    /// Every group the client is a member of.
+    ListGroups {
+        client_id: String,
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over group_payload with an empty group
+    },
+    /// Owners only.
+    RenameGroup {
+        client_id: String,
+        group: String,
+        new_name: String,
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over group_payload
+    },
+    LeaveGroup {
+        client_id: String,
+        group: String,
+        signed_at: DateTime<Utc>,
+        signature: String, // Signature over group_payload
+    },
+    /// A message from someone holding a guest token, not a registered client.
+    GuestSend {
+        link_id: String,
+        secret: String,
+        message_id: String,
+        content: String,
+        /// The guest's one-off X25519 key; required when the link has a key.
+        #[serde(default)]
+        ephemeral_key: Option<String>,
+    },
+}
+
+impl ServerCommand {
+    /// Variant name, used to label per-command metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerCommand::Register { .. } => "Register",
+            ServerCommand::Send { .. } => "Send",
+            ServerCommand::GetMessages { .. } => "GetMessages",
+            ServerCommand::Ack { .. } => "Ack",
+            ServerCommand::GetClients => "GetClients",
+            ServerCommand::GetClientsDelta { .. } => "GetClientsDelta",
+            ServerCommand::GetServerKey => "GetServerKey",
+            ServerCommand::Heartbeat { .. } => "Heartbeat",
+            ServerCommand::Revoke { .. } => "Revoke",
+            ServerCommand::GetRevocations { .. } => "GetRevocations",
+            ServerCommand::GetKeyHistory { .. } => "GetKeyHistory",
+            ServerCommand::GetPublicKey { .. } => "GetPublicKey",
+            ServerCommand::SetRetention { .. } => "SetRetention",
+            ServerCommand::Stats => "Stats",
+            ServerCommand::GetMessageStatus { .. } => "GetMessageStatus",
+            ServerCommand::CancelMessage { .. } => "CancelMessage",
+            ServerCommand::SearchMessages { .. } => "SearchMessages",
+            ServerCommand::ReportSender { .. } => "ReportSender",
+            ServerCommand::CreateGuestLink { .. } => "CreateGuestLink",
+            ServerCommand::ListGuestLinks { .. } => "ListGuestLinks",
+            ServerCommand::RevokeGuestLink { .. } => "RevokeGuestLink",
+            ServerCommand::GuestSend { .. } => "GuestSend",
+            ServerCommand::PutUserData { .. } => "PutUserData",
+            ServerCommand::GetUserData { .. } => "GetUserData",
+            ServerCommand::GetClientDetails { .. } => "GetClientDetails",
+            ServerCommand::CreateInviteCode { .. } => "CreateInviteCode",
+            ServerCommand::Promote { .. } => "Promote",
+            ServerCommand::DebugDump { .. } => "DebugDump",
+            ServerCommand::AdminBatch { .. } => "AdminBatch",
+            ServerCommand::GetPresence { .. } => "GetPresence",
+            ServerCommand::Delegate { .. } => "Delegate",
+            ServerCommand::RevokeDelegation { .. } => "RevokeDelegation",
+            ServerCommand::ListDelegations { .. } => "ListDelegations",
+            ServerCommand::MyUsage { .. } => "MyUsage",
+            ServerCommand::CreateGroup { .. } => "CreateGroup",
+            ServerCommand::GetGroup { .. } => "GetGroup",
+            ServerCommand::ListGroups { .. } => "ListGroups",
+            ServerCommand::RenameGroup { .. } => "RenameGroup",
+            ServerCommand::LeaveGroup { .. } => "LeaveGroup",
+        }
+    }
+
+    /// This command with `answer` to a challenge for it. Commands the
+    /// server doesn't challenge come back as they are.
+    pub fn answering(mut self, answer: ChallengeAnswer) -> ServerCommand {
+        if let ServerCommand::Register { challenge, .. } | ServerCommand::GetMessages { challenge, .. } = &mut self {
+            *challenge = Some(answer);
+        }
+        self
+    }
+
+    /// The client the command acts for, if any.
+    pub fn client_id(&self) -> Option<&str> {
+        match self {
+            ServerCommand::Register { client_id, .. }
+            | ServerCommand::GetMessages { client_id, .. }
+            | ServerCommand::Ack { client_id, .. }
+            | ServerCommand::Heartbeat { client_id }
+            | ServerCommand::SetRetention { client_id, .. }
+            | ServerCommand::SearchMessages { client_id, .. }
+            | ServerCommand::ReportSender { client_id, .. }
+            | ServerCommand::CreateGuestLink { client_id, .. }
+            | ServerCommand::ListGuestLinks { client_id, .. }
+            | ServerCommand::RevokeGuestLink { client_id, .. }
+            | ServerCommand::PutUserData { client_id, .. }
+            | ServerCommand::GetUserData { client_id, .. }
+            | ServerCommand::ListDelegations { client_id, .. }
+            | ServerCommand::MyUsage { client_id, .. }
+            | ServerCommand::GetPresence { client_id, .. }
+            | ServerCommand::CreateGroup { client_id, .. }
+            | ServerCommand::GetGroup { client_id, .. }
+            | ServerCommand::ListGroups { client_id, .. }
+            | ServerCommand::RenameGroup { client_id, .. }
+            | ServerCommand::LeaveGroup { client_id, .. } => Some(client_id),
+            ServerCommand::Delegate { delegation } => Some(&delegation.owner),
+            ServerCommand::RevokeDelegation { owner, .. } => Some(owner),
+            ServerCommand::GetClientDetails { admin_id, .. }
+            | ServerCommand::CreateInviteCode { admin_id, .. }
+            | ServerCommand::Promote { admin_id, .. }
+            | ServerCommand::DebugDump { admin_id, .. }
+            | ServerCommand::AdminBatch { admin_id, .. } => Some(admin_id),
+            ServerCommand::Send { sender_id, .. }
+            | ServerCommand::GetMessageStatus { sender_id, .. }
+            | ServerCommand::CancelMessage { sender_id, .. } => Some(sender_id),
+            ServerCommand::Revoke { revocation } => Some(&revocation.client_id),
+            ServerCommand::GetRevocations { .. }
+            | ServerCommand::GetKeyHistory { .. }
+            | ServerCommand::GetPublicKey { .. }
+            | ServerCommand::GetClients
+            | ServerCommand::GetClientsDelta { .. }
+            | ServerCommand::GetServerKey
+            | ServerCommand::GuestSend { .. }
+            | ServerCommand::Stats => None,
+        }
+    }
+}
+
+/// Upper bounds of the latency histogram buckets, in milliseconds.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    pub total_micros: u64,
+    pub max_micros: u64,
+    /// One count per `LATENCY_BUCKETS_MS` bound, then one for slower requests.
+    pub buckets: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        if self.buckets.len() != LATENCY_BUCKETS_MS.len() + 1 {
+            self.buckets = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        }
+        let micros = duration.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter()
+            .position(|bound| micros <= bound * 1000)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_micros += micros;
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    /// Upper bound (ms) of the bucket holding the given percentile; None past
+    /// the last bound or with no samples.
+    pub fn percentile_ms(&self, percentile: f64) -> Option<u64> {
+        let target = ((self.count as f64) * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return LATENCY_BUCKETS_MS.get(i).copied();
+            }
+        }
+        None
+    }
+
+    pub fn mean_ms(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total_micros as f64 / self.count as f64 / 1000.0
+    }
+}
+
+/// How far the server has got re-verifying stored message signatures.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntegrityProgress {
+    pub checked: u64,
+    pub total: u64,
+    /// Messages whose signature failed and that were taken out of service.
+    pub quarantined: u64,
+    pub finished: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerResponse {
-    Registered { server_public_key: String },
-    MessageSent { message_id: String },
-    MessageReceived { message: Message },
+    Registered {
+        server_public_key: String,
+        /// The protocol version and feature bitset agreed for this client;
+        /// unset from servers that predate negotiation.
+        #[serde(default)]
+        protocol_version: Option<u32>,
+        #[serde(default)]
+        features: Option<u64>,
+        /// The most ciphertext bytes a message may have; unset when the
+        /// server sets no limit, or predates advertising it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_message_size: Option<usize>,
+    },
+    /// Not acted on yet: prove the key of the command's client by sending
+    /// it again with this nonce signed.
+    Challenge { nonce: String },
+    MessageSent {
+        message_id: String,
+        #[serde(default)]
+        expires_at: Option<DateTime<Utc>>,
+        /// The recipient's retention policy shortened the message's life.
+        #[serde(default)]
+        retention_applied: bool,
+        /// The recipient isn't registered yet; the message is held for them until then.
+        #[serde(default)]
+        pending_until: Option<DateTime<Utc>>,
+        /// Where the message falls in the server's order; unset while it is held.
+        #[serde(default)]
+        hlc: Option<Hlc>,
+        /// When the server accepted the message, and its signature over
+        /// `receipt_payload`; unset from servers that predate receipts.
+        #[serde(default)]
+        received_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        server_signature: Option<String>,
+        /// The message it supersedes was still unfetched and is gone.
+        #[serde(default)]
+        replaced: bool,
+    },
+    /// What servers before `Messages` answered GetMessages with: only the
+    /// newest message. Still read by clients for one release.
+    MessageReceived { message: Box<Message> },
+    /// Not an answer: pushed to a connection that negotiated push_delivery
+    /// when a message for its client is stored. The message stays queued
+    /// until fetched and acknowledged as usual.
+    Incoming { message: Box<Message> },
+    /// Not an answer: sent to every connection when the server starts
+    /// draining. It takes no new connections, serves open ones until
+    /// `deadline`, then exits; reconnect after that.
+    GoAway { deadline: DateTime<Utc> },
+    /// A mailbox in clock order; empty when there is nothing to fetch.
+    Messages { messages: Vec<Message> },
     ClientList { clients: Vec<String> },
-    Error { message: String },
+    /// Directory changes up to `revision`, oldest first. With `reset` the
+    /// asker was too far behind: `changes` adds every client there is and
+    /// anything cached before should go. With `more`, ask again from
+    /// `revision` for the next chunk.
+    ClientsDelta { revision: u64, reset: bool, changes: Vec<DirectoryChange>, more: bool },
+    ServerKey { server_public_key: String },
+    PublicKeys {
+        client_id: String,
+        ed25519: String,
+        /// Unset if the client registered without an X25519 key.
+        #[serde(default)]
+        x25519: Option<SenderKey>,
+    },
+    Revocations { client_id: String, revocations: Vec<Revocation> },
+    KeyHistory {
+        client_id: String,
+        public_key: Option<String>,
+        epoch: u64, // Number of entries in the key log
+        head_hash: String,
+        entries: Vec<KeyLogEntry>,
+        signature: String, // Server signature over key_directory_payload
+    },
+    Stats {
+        uptime_secs: u64,
+        latencies: BTreeMap<String, LatencyHistogram>,
+        key_cache_hits: u64,
+        key_cache_misses: u64,
+        /// Bytes on disk per storage component.
+        #[serde(default)]
+        disk_usage: BTreeMap<String, u64>,
+        /// Spam reports per sender since startup.
+        #[serde(default)]
+        reported_senders: BTreeMap<String, u64>,
+        /// Progress of the stored-message signature check, if it's enabled.
+        #[serde(default)]
+        integrity: Option<IntegrityProgress>,
+        /// Since when writes have been failing, while they are. Reads still
+        /// work; new messages are refused.
+        #[serde(default)]
+        storage_degraded_since: Option<DateTime<Utc>>,
+        /// Optional features the server has switched on.
+        #[serde(default)]
+        features: Vec<String>,
+        /// Rotations since startup per server log file.
+        #[serde(default)]
+        log_rotations: BTreeMap<String, u64>,
+    },
+    MessageStatus { message_id: String, status: DeliveryStatus },
+    SearchResults {
+        results: Vec<MessageMetadata>,
+        /// More messages matched than the limit allowed.
+        truncated: bool,
+    },
+    Error {
+        message: String,
+        /// Machine-readable reason, from `error_code`, when there is one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+        /// Seconds to wait before trying again, when the failure is temporary.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_secs: Option<u64>,
+    },
+    /// The reported sender's messages to the reporter are dropped until `muted_until`.
+    SenderReported { sender_id: String, muted_until: DateTime<Utc> },
+    /// `secret` is only ever returned here.
+    GuestLinkCreated { link: GuestLink, secret: String },
+    GuestLinks { links: Vec<GuestLink> },
+    /// An owner's grants, and its delegates' actions, oldest first.
+    Delegations { delegations: Vec<Delegation>, audit: Vec<DelegationAudit> },
+    /// A user data key's version after a read or write, and its value after
+    /// a read. Version 0 means the key doesn't exist.
+    UserData { key: String, value: Option<String>, version: u64 },
+    ClientDetails { client: ClientInfo },
+    /// Free-form: the fields change as the server's internals do.
+    DebugDump { dump: serde_json::Value },
+    /// One result per action, in order. `applied` is false when a
+    /// transactional batch was rolled back.
+    AdminBatch { applied: bool, results: Vec<AdminActionResult> },
+    /// One entry per id asked about, in the order asked.
+    Presence { entries: Vec<PresenceEntry> },
+    Usage { usage: AccountUsage },
+    Group { group: Group },
+    Groups { groups: Vec<Group> },
     Ok,
 }
 
+/// Known `ServerResponse::Error` codes.
+pub mod error_code {
+    pub const SERVER_STORAGE_FULL: &str = "server_storage_full";
+    /// The server can't write its data right now; retry after a while.
+    pub const SERVER_STORAGE_UNAVAILABLE: &str = "server_storage_unavailable";
+    /// The request couldn't be parsed.
+    pub const INVALID_REQUEST: &str = "invalid_request";
+    /// A request or response frame was over the size limit.
+    pub const FRAME_TOO_LARGE: &str = "frame_too_large";
+    /// The message is over the server's `--max-message-size`.
+    pub const MESSAGE_TOO_LARGE: &str = "message_too_large";
+    /// The server failed while handling the request.
+    pub const INTERNAL_ERROR: &str = "internal_error";
+    /// Too many messages to one recipient in a short time.
+    pub const PAIR_RATE_LIMITED: &str = "pair_rate_limited";
+    /// The recipient reported the sender, who is muted for a while.
+    pub const SENDER_MUTED: &str = "sender_muted";
+    /// No guest link matches the token.
+    pub const GUEST_LINK_INVALID: &str = "guest_link_invalid";
+    pub const GUEST_LINK_EXPIRED: &str = "guest_link_expired";
+    pub const GUEST_LINK_REVOKED: &str = "guest_link_revoked";
+    /// The link has taken all the messages it allows.
+    pub const GUEST_LINK_USED_UP: &str = "guest_link_used_up";
+    /// The message id is empty, too long, or has characters outside `[A-Za-z0-9_-]`.
+    pub const INVALID_MESSAGE_ID: &str = "invalid_message_id";
+    /// The sender already has a stored message with this id.
+    pub const DUPLICATE_MESSAGE_ID: &str = "duplicate_message_id";
+    /// The sender used this message id recently, for a message since
+    /// fetched or deleted; the send looks like a replay.
+    pub const REPLAYED_MESSAGE_ID: &str = "replayed_message_id";
+    /// The send's `sent_at` is missing, or too far from the server's clock.
+    pub const STALE_SEND: &str = "stale_send";
+    /// A signed request's `signed_at` is too far from the server's clock.
+    pub const STALE_REQUEST: &str = "stale_request";
+    /// A signed request was already made once; it looks like a replay.
+    pub const REPLAYED_REQUEST: &str = "replayed_request";
+    /// The command belongs to an optional feature this server has switched off.
+    pub const FEATURE_DISABLED: &str = "feature_disabled";
+    /// The server's registration policy refused the id.
+    pub const REGISTRATION_DENIED: &str = "registration_denied";
+    /// Registering here needs a credential, such as an invite code.
+    pub const CREDENTIAL_REQUIRED: &str = "credential_required";
+    /// The user data key has moved past the version the write expected.
+    pub const USER_DATA_CONFLICT: &str = "user_data_conflict";
+    /// The write would take the client over its user data allowance.
+    pub const USER_DATA_QUOTA: &str = "user_data_quota";
+    /// The owner hasn't granted this access, or the grant expired or was revoked.
+    pub const NOT_DELEGATED: &str = "not_delegated";
+    /// The server is a standby and takes no writes until it is promoted.
+    pub const READ_ONLY_STANDBY: &str = "read_only_standby";
+    /// The id looks the same as one already registered.
+    pub const CONFUSABLE_ID: &str = "confusable_id";
+    /// The recipient is a presence-only client, which has no mailbox.
+    pub const NO_MAILBOX: &str = "no_mailbox";
+    /// The challenge answer was missing its nonce, or its signature isn't
+    /// by the id's key.
+    pub const CHALLENGE_FAILED: &str = "challenge_failed";
+    /// No group by that name, or none the requester is in.
+    pub const UNKNOWN_GROUP: &str = "unknown_group";
+    /// The sender or recipient of a group message isn't in the group, such
+    /// as after leaving it.
+    pub const NOT_GROUP_MEMBER: &str = "not_group_member";
+    /// Only the group's owners may do that.
+    pub const NOT_GROUP_OWNER: &str = "not_group_owner";
+    pub const GROUP_EXISTS: &str = "group_exists";
+    /// A client named in the request isn't registered.
+    pub const UNKNOWN_CLIENT: &str = "unknown_client";
+}
+
 impl Message {
     // Removed unused new function to fix dead code warning
-} 
\ No newline at end of file
+
+    /// Whose key `signature` is under: the delegate that sent the message,
+    /// or else its sender.
+    pub fn signer(&self) -> &str {
+        self.sent_by.as_deref().unwrap_or(&self.sender_id)
+    }
+}
+
+/// Longest group name a server accepts.
+pub const MAX_GROUP_NAME_LEN: usize = 64;
+
+/// Why a group name is refused, if it is: as with message ids, plus `.`.
+pub fn check_group_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_GROUP_NAME_LEN {
+        return Err(format!("Group names must be 1 to {} characters long", MAX_GROUP_NAME_LEN));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err("Group names may only contain letters, digits, '-', '_' and '.'".to_string());
+    }
+    Ok(())
+}
+
+/// Longest message id a server accepts.
+pub const MAX_MESSAGE_ID_LEN: usize = 64;
+
+/// A fresh message id. ULIDs sort by creation time, unlike the UUIDs older
+/// clients send, which servers still accept.
+pub fn new_message_id() -> String {
+    ulid::Ulid::new().to_string()
+}
+
+/// When a ULID message id says the message was created. The sender's own
+/// clock set it, so it is only a claim; UUID ids carry no time.
+pub fn message_id_time(id: &str) -> Option<DateTime<Utc>> {
+    ulid::Ulid::from_string(id).ok().map(|ulid| DateTime::<Utc>::from(ulid.datetime()))
+}
+
+/// Why a client-chosen message id is refused, if it is: ids must be
+/// non-empty, at most `MAX_MESSAGE_ID_LEN` long, and letters, digits, `-`
+/// or `_` only.
+pub fn check_message_id(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("Message id is empty".to_string());
+    }
+    if id.len() > MAX_MESSAGE_ID_LEN {
+        return Err(format!("Message id is {} characters long; at most {} are allowed", id.len(), MAX_MESSAGE_ID_LEN));
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Message ids may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_log(keys: &[(&str, &str, KeyEvent)]) -> Vec<KeyLogEntry> {
+        let mut entries: Vec<KeyLogEntry> = Vec::new();
+        for (seq, (client_id, public_key, event)) in keys.iter().enumerate() {
+            let mut entry = KeyLogEntry {
+                seq: seq as u64,
+                client_id: client_id.to_string(),
+                public_key: public_key.to_string(),
+                event: event.clone(),
+                timestamp: Utc::now(),
+                prev_hash: entries.last().map(|e| e.hash.clone()).unwrap_or_default(),
+                hash: String::new(),
+            };
+            entry.hash = entry.compute_hash();
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[test]
+    fn the_clock_keeps_its_order_when_the_wall_clock_steps_back() {
+        let start = Utc::now();
+        let mut clock = Hlc::default();
+        let mut values = Vec::new();
+        for wall in [0, 1, 1, 2, -5_000, -4_999, -4_999, 3, 10] {
+            clock = clock.next(start + chrono::Duration::milliseconds(wall));
+            values.push(clock);
+        }
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "out of order: {:?}", values);
+
+        // A cursor taken just before the step still sees everything after it
+        let cursor = values[3];
+        let after: Vec<Hlc> = values.iter().copied().filter(|hlc| *hlc > cursor).collect();
+        assert_eq!(after, values[4..]);
+        // Values handed out while the wall clock was behind carry its last reading on
+        assert!(values[4..7].iter().all(|hlc| hlc.wall_ms == values[3].wall_ms));
+        assert_eq!(values[7].wall_ms, values[3].wall_ms + 1);
+        assert_eq!(cursor.to_string().parse::<Hlc>().unwrap(), cursor);
+    }
+
+    fn sample() -> Vec<KeyLogEntry> {
+        key_log(&[("alice", "a1", KeyEvent::Registered), ("bob", "b1", KeyEvent::Registered), ("alice", "a1", KeyEvent::Revoked)])
+    }
+
+    #[test]
+    fn an_intact_key_log_verifies() {
+        assert!(KeyLogEntry::verify_chain(&sample()).is_ok());
+        assert!(KeyLogEntry::verify_chain(&[]).is_ok());
+    }
+
+    #[test]
+    fn tampering_with_the_key_log_is_detected() {
+        let mut altered = sample();
+        altered[1].public_key = "mallory".to_string();
+        assert!(KeyLogEntry::verify_chain(&altered).unwrap_err().contains("altered"));
+
+        let mut dropped = sample();
+        dropped.remove(1);
+        assert!(KeyLogEntry::verify_chain(&dropped).is_err());
+
+        let mut reordered = sample();
+        reordered.swap(0, 1);
+        assert!(KeyLogEntry::verify_chain(&reordered).is_err());
+
+        // Rehashing the altered entry alone still breaks the link after it
+        let mut rehashed = sample();
+        rehashed[0].public_key = "mallory".to_string();
+        rehashed[0].hash = rehashed[0].compute_hash();
+        assert!(KeyLogEntry::verify_chain(&rehashed).unwrap_err().contains("predecessor"));
+    }
+
+    #[test]
+    fn message_ids_are_checked_for_length_and_characters() {
+        check_message_id(&new_message_id()).unwrap();
+        check_message_id(&uuid::Uuid::new_v4().to_string()).unwrap();
+        check_message_id("a_b-C9").unwrap();
+        check_message_id(&"x".repeat(MAX_MESSAGE_ID_LEN)).unwrap();
+
+        assert!(check_message_id("").unwrap_err().contains("empty"));
+        assert!(check_message_id(&"x".repeat(MAX_MESSAGE_ID_LEN + 1)).unwrap_err().contains("at most"));
+        for bad in ["has space", "dot.ted", "slash/ed", "new\nline", "../etc", "ünï", "semi;colon"] {
+            assert!(check_message_id(bad).unwrap_err().contains("may only contain"), "{:?} was accepted", bad);
+        }
+        // Length is in bytes, so multi-byte ids can't slip past it
+        assert!(check_message_id(&"é".repeat(MAX_MESSAGE_ID_LEN)).unwrap_err().contains("at most"));
+    }
+}
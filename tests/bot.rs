@@ -0,0 +1,153 @@
+//! Bots against an in-process server.
+
+mod common;
+
+use messaging_proto::bot::{AllowList, BotBuilder, RateLimit};
+use messaging_proto::client::Client;
+use common::{connect_client, start_server, TempDir};
+use regex::Regex;
+use std::future::Future;
+use std::time::Duration;
+
+/// How often the bots under test fetch.
+const POLL: Duration = Duration::from_millis(20);
+
+/// The bodies of the next messages `client` receives, waiting up to five
+/// seconds for any to arrive.
+async fn next_bodies(client: &mut Client) -> Vec<String> {
+    for _ in 0..250 {
+        let received = client.receive().await;
+        if !received.is_empty() {
+            return received.into_iter().map(|view| view.body).collect();
+        }
+        tokio::time::sleep(POLL).await;
+    }
+    panic!("nothing arrived");
+}
+
+/// Run `bot` alongside `scenario`, and stop it when the scenario is done.
+async fn with_bot(bot: messaging_proto::bot::Bot, scenario: impl Future<Output = ()>) -> Client {
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let (client, ()) = tokio::join!(bot.run_until(stopped), async move {
+        scenario.await;
+        stop.send(()).unwrap();
+    });
+    client
+}
+
+#[tokio::test]
+async fn commands_and_patterns_are_answered_and_other_text_ignored() {
+    let dir = TempDir::new("bot-routes");
+    let addr = start_server(&dir.0.join("server")).await;
+    let bot = connect_client(&dir.0.join("bot"), "bot", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+
+    let bot = BotBuilder::new(bot)
+        .poll_interval(POLL)
+        .command("!echo", |ctx| async move {
+            ctx.reply(ctx.args.clone());
+            Ok(())
+        })
+        .pattern(Regex::new(r"^add (\d+) (\d+)$").unwrap(), |ctx| async move {
+            let number = |i: usize| ctx.captures[i].as_deref().unwrap().parse::<u64>();
+            ctx.reply((number(1)? + number(2)?).to_string());
+            Ok(())
+        })
+        .build();
+    with_bot(bot, async {
+        alice.send("bot", "!echo hello there").await.unwrap();
+        assert_eq!(next_bodies(&mut alice).await, ["hello there"]);
+        // A longer word only starting with the command isn't it
+        alice.send("bot", "!echoes").await.unwrap();
+        alice.send("bot", "add 2 40").await.unwrap();
+        assert_eq!(next_bodies(&mut alice).await, ["42"]);
+    }).await;
+}
+
+#[tokio::test]
+async fn a_panicking_handler_does_not_stop_the_bot() {
+    let dir = TempDir::new("bot-panic");
+    let addr = start_server(&dir.0.join("server")).await;
+    let bot = connect_client(&dir.0.join("bot"), "bot", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+
+    let bot = BotBuilder::new(bot)
+        .poll_interval(POLL)
+        .command("!boom", |_| async move { panic!("boom") })
+        .command("!ping", |ctx| async move {
+            ctx.reply("pong");
+            Ok(())
+        })
+        .build();
+    with_bot(bot, async {
+        alice.send("bot", "!boom").await.unwrap();
+        tokio::time::sleep(POLL * 5).await;
+        alice.send("bot", "!ping").await.unwrap();
+        assert_eq!(next_bodies(&mut alice).await, ["pong"]);
+    }).await;
+}
+
+#[tokio::test]
+async fn middleware_drops_strangers_and_senders_over_their_rate() {
+    let dir = TempDir::new("bot-middleware");
+    let addr = start_server(&dir.0.join("server")).await;
+    let bot = connect_client(&dir.0.join("bot"), "bot", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    let mut mallory = connect_client(&dir.0.join("mallory"), "mallory", &addr).await;
+
+    let bot = BotBuilder::new(bot)
+        .poll_interval(POLL)
+        .middleware(AllowList::new(["alice"]))
+        .middleware(RateLimit::per_user(2, Duration::from_secs(60)))
+        .command("!ping", |ctx| async move {
+            ctx.reply("pong");
+            Ok(())
+        })
+        .build();
+    with_bot(bot, async {
+        mallory.send("bot", "!ping").await.unwrap();
+        for _ in 0..3 {
+            alice.send("bot", "!ping").await.unwrap();
+        }
+        let mut answers = Vec::new();
+        while answers.len() < 2 {
+            answers.extend(next_bodies(&mut alice).await);
+        }
+        tokio::time::sleep(POLL * 10).await;
+        answers.extend(alice.receive().await.into_iter().map(|view| view.body));
+        assert_eq!(answers, ["pong", "pong"]);
+        assert!(mallory.receive().await.is_empty(), "a sender off the allow-list was answered");
+    }).await;
+}
+
+#[tokio::test]
+async fn shutdown_lets_running_handlers_finish_and_sends_their_replies() {
+    let dir = TempDir::new("bot-shutdown");
+    let addr = start_server(&dir.0.join("server")).await;
+    let bot = connect_client(&dir.0.join("bot"), "bot", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+
+    let (started, mut handler_started) = tokio::sync::mpsc::unbounded_channel();
+    let bot = BotBuilder::new(bot)
+        .poll_interval(POLL)
+        .shutdown_grace(Duration::from_secs(5))
+        .command("!slow", move |ctx| {
+            let started = started.clone();
+            async move {
+                started.send(()).unwrap();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                ctx.reply("done");
+                Ok(())
+            }
+        })
+        .build();
+    alice.send("bot", "!slow").await.unwrap();
+    let mut bot = with_bot(bot, async {
+        handler_started.recv().await.unwrap();
+    }).await;
+    assert_eq!(next_bodies(&mut alice).await, ["done"]);
+
+    // The client comes back usable
+    bot.send("alice", "still here").await.unwrap();
+    assert_eq!(next_bodies(&mut alice).await, ["still here"]);
+}
@@ -0,0 +1,71 @@
+//! Helpers shared by the integration tests. Each test binary uses only some
+//! of them.
+
+#![allow(dead_code)]
+
+use messaging_proto::client::Client;
+use messaging_proto::paths::ClientPaths;
+use messaging_proto::server::{Server, ServerOptions};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The crate's `Scratch` for the integration tests, which can't reach it:
+/// a fresh directory under the system temp dir, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        let path = std::env::temp_dir().join(format!("msgproto-{}-{}-{}", name, std::process::id(), nanos));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A local address nothing is listening on.
+pub fn free_addr() -> String {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+/// Start a server with its data in `dir` on a free port, and return its address once it takes connections.
+pub async fn start_server(dir: &Path) -> String {
+    let mut options = ServerOptions::from_args(&[]).unwrap();
+    options.data_dir = dir.to_path_buf();
+    options.bind = free_addr();
+    serve(options).await
+}
+
+/// Start a server on `options.bind`, and return that address once it takes connections.
+pub async fn serve(options: ServerOptions) -> String {
+    let addr = options.bind.clone();
+    let server = Server::new(options).await.unwrap();
+    let listen = addr.clone();
+    tokio::spawn(async move { server.run(&listen).await });
+    wait_for_listener(&addr).await;
+    addr
+}
+
+pub async fn wait_for_listener(addr: &str) {
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the server never started listening on {}", addr);
+}
+
+pub async fn connect_client(home: &Path, id: &str, addr: &str) -> Client {
+    let paths = ClientPaths::resolve(Some(&home.to_string_lossy())).unwrap();
+    paths.create().unwrap();
+    let key_file = paths.key_file(id);
+    let mut client = Client::new(id, paths, key_file, false).unwrap();
+    client.connect_to(addr).await.unwrap();
+    client
+}
@@ -0,0 +1,2375 @@
+//! Drives an in-process server with two clients through the library API.
+
+mod common;
+
+use messaging_proto::backend::{JsonBackend, StorageBackend};
+use messaging_proto::client::Client;
+use messaging_proto::crypto::CryptoManager;
+use messaging_proto::events::ClientEvent;
+use messaging_proto::outgoing::MessageTooLarge;
+use messaging_proto::paths::ClientPaths;
+use messaging_proto::server::{Server, ServerOptions, Stopped};
+use messaging_proto::storage;
+use messaging_proto::store::SignatureCheck;
+use messaging_proto::crypto;
+use messaging_proto::types::{ack_payload, admin_batch_payload, challenge_payload, client_details_payload, debug_dump_payload, delegation_payload, delegation_ref_payload, delegations_payload, error_code, group_payload, guest_link_payload, guest_link_ref_payload, invite_code_payload, presence_payload, report_payload, retention_payload, message_ref_payload, message_id_time, new_message_id, notice_type, promote_payload, send_payload, sender_key_payload, user_data_payload, user_data_ref_payload, AdminAction, ChallengeAnswer, Delegation, DelegationScope, DeliveryStatus, GroupRole, Hlc, KeyLogEntry, Message, Revocation, ClientInfo, SenderKey, ServerCommand, ServerResponse, SystemNotice};
+use chrono::{DateTime, Utc};
+use common::{connect_client, free_addr, serve, start_server, wait_for_listener, TempDir};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Start a server with its data in `dir`, configured by `flags`, on a free port.
+async fn start_server_with(dir: &Path, flags: &[&str]) -> String {
+    let args: Vec<String> = std::iter::once("server").chain(flags.iter().copied()).map(|arg| arg.to_string()).collect();
+    let mut options = ServerOptions::from_args(&args).unwrap();
+    options.data_dir = dir.to_path_buf();
+    options.bind = free_addr();
+    serve(options).await
+}
+
+/// Start a server with its data in `dir` on a free port, and return its
+/// address plus a handle that shuts it down.
+async fn start_stoppable_server(dir: &Path) -> (String, StopServer) {
+    let mut options = ServerOptions::from_args(&[]).unwrap();
+    options.data_dir = dir.to_path_buf();
+    options.bind = free_addr();
+    serve_stoppable(options).await
+}
+
+/// `serve`, plus a handle that shuts the server down.
+async fn serve_stoppable(options: ServerOptions) -> (String, StopServer) {
+    let addr = options.bind.clone();
+    let server = Server::new(options).await.unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let listen = addr.clone();
+    let running = tokio::spawn(async move { server.run_until(&listen, async { let _ = stopped.await; }).await });
+    wait_for_listener(&addr).await;
+    (addr, StopServer { stop, running })
+}
+
+struct StopServer {
+    stop: tokio::sync::oneshot::Sender<()>,
+    running: tokio::task::JoinHandle<anyhow::Result<Stopped>>,
+}
+
+impl StopServer {
+    /// Shut the server down, and wait until it has.
+    async fn stop(self) {
+        self.stop.send(()).unwrap();
+        let stopped = tokio::time::timeout(Duration::from_secs(10), self.running).await.expect("shutdown hung").unwrap().unwrap();
+        assert_eq!(stopped, Stopped::Shutdown);
+    }
+}
+
+/// Another device of the identity whose client lives in `first_home`: its
+/// own home, on the same key.
+async fn connect_device(home: &Path, first_home: &Path, id: &str, addr: &str) -> Client {
+    let key_file = ClientPaths::resolve(Some(&first_home.to_string_lossy())).unwrap().key_file(id);
+    let paths = ClientPaths::resolve(Some(&home.to_string_lossy())).unwrap();
+    paths.create().unwrap();
+    let mut client = Client::new(id, paths, key_file, false).unwrap();
+    client.connect_to(addr).await.unwrap();
+    client
+}
+
+/// Send one command as a raw frame and read back the response.
+async fn exchange(stream: &mut TcpStream, command: &ServerCommand) -> ServerResponse {
+    try_exchange(stream, command).await.unwrap()
+}
+
+/// `exchange`, for a server that may have gone away.
+async fn try_exchange(stream: &mut TcpStream, command: &ServerCommand) -> std::io::Result<ServerResponse> {
+    let body = serde_json::to_vec(command).unwrap();
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&body).await?;
+    try_read_response(stream).await
+}
+
+/// Read one frame the server sent, answer or push.
+async fn try_read_response(stream: &mut TcpStream) -> std::io::Result<ServerResponse> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut body).await?;
+    Ok(serde_json::from_slice(&body).unwrap())
+}
+
+/// Send `command`, expect a challenge, and answer it with `signer`'s key.
+async fn answer_as(stream: &mut TcpStream, command: ServerCommand, signer: &CryptoManager) -> ServerResponse {
+    let ServerResponse::Challenge { nonce } = exchange(stream, &command).await else {
+        panic!("{} wasn't challenged", command.name());
+    };
+    let answer = signer.answer_challenge(command.client_id().unwrap(), &nonce);
+    exchange(stream, &command.answering(answer)).await
+}
+
+fn assert_challenge_failed(response: ServerResponse) {
+    assert_refused(response, error_code::CHALLENGE_FAILED);
+}
+
+fn assert_refused(response: ServerResponse, expected: &str) {
+    match response {
+        ServerResponse::Error { code, .. } => assert_eq!(code.as_deref(), Some(expected)),
+        other => panic!("expected {}, got {:?}", expected, other),
+    }
+}
+
+/// A Register of `id` with `crypto`'s keys, still to be challenged,
+/// negotiating as a current client does.
+fn registration(id: &str, crypto: &CryptoManager, recovery_key: Option<String>) -> ServerCommand {
+    let x25519_public_key = hex::encode(crypto.get_x25519_public_key().as_bytes());
+    let x25519_signature = crypto.sign_with_context(crypto::context::SENDER_KEY, &sender_key_payload(id, &x25519_public_key));
+    ServerCommand::Register {
+        client_id: id.to_string(),
+        public_key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+        recovery_key,
+        protocol_version: Some(1),
+        features: Some(0),
+        credential: None,
+        x25519_public_key: Some(x25519_public_key),
+        x25519_signature: Some(hex::encode(x25519_signature.to_bytes())),
+        challenge: None,
+    }
+}
+
+/// Register `id` with `crypto`'s keys over a raw connection.
+async fn register_raw(stream: &mut TcpStream, id: &str, crypto: &CryptoManager) {
+    match answer_as(stream, registration(id, crypto, None), crypto).await {
+        ServerResponse::Registered { .. } => {}
+        other => panic!("{} didn't register: {:?}", id, other),
+    }
+}
+
+/// Revoke `id`'s key, signed by the key itself.
+async fn revoke_own_key(stream: &mut TcpStream, id: &str, crypto: &CryptoManager) {
+    let mut revocation = Revocation {
+        client_id: id.to_string(),
+        key: hex::encode(crypto.get_ed25519_public_key().as_bytes()),
+        reason: "lost laptop".to_string(),
+        timestamp: Utc::now(),
+        signature: String::new(),
+    };
+    revocation.signature = hex::encode(crypto.sign_with_context(crypto::context::REVOKE, &revocation.signed_payload()).to_bytes());
+    assert!(matches!(exchange(stream, &ServerCommand::Revoke { revocation }).await, ServerResponse::Ok));
+}
+
+/// A Send of `text` from `sender` to `recipient`, as a client signs it at `sent_at`.
+async fn raw_send(stream: &mut TcpStream, sender: (&str, &CryptoManager), recipient: &str, text: &str, sent_at: DateTime<Utc>) -> ServerCommand {
+    let ServerResponse::PublicKeys { x25519: Some(published), .. } = exchange(stream, &ServerCommand::GetPublicKey { client_id: recipient.to_string() }).await else {
+        panic!("{} has no published X25519 key", recipient);
+    };
+    let key: [u8; 32] = hex::decode(&published.x25519_public_key).unwrap().try_into().unwrap();
+    sealed_send(sender, (recipient, key), text, sent_at)
+}
+
+/// A Send of `text` from `sender`, sealed to the recipient's X25519 key
+/// however the sender came by it.
+fn sealed_send(sender: (&str, &CryptoManager), recipient: (&str, [u8; 32]), text: &str, sent_at: DateTime<Utc>) -> ServerCommand {
+    let (recipient, key) = recipient;
+    let encrypted = sender.1.encrypt_message(&key.into(), text).unwrap();
+    let x25519_public_key = hex::encode(sender.1.get_x25519_public_key().as_bytes());
+    let key_signature = sender.1.sign_with_context(crypto::context::SENDER_KEY, &sender_key_payload(sender.0, &x25519_public_key));
+    let message_id = new_message_id();
+    ServerCommand::Send {
+        sender_id: sender.0.to_string(),
+        recipient_id: recipient.to_string(),
+        signature: hex::encode(sender.1.sign_with_context(crypto::context::SEND, &send_payload(&message_id, recipient, &encrypted, sent_at)).to_bytes()),
+        encrypted_content: hex::encode(encrypted),
+        message_id,
+        ttl_secs: None,
+        deliver_by: None,
+        sender_key: Some(SenderKey { x25519_public_key, signature: hex::encode(key_signature.to_bytes()) }),
+        seq: None,
+        on_behalf_of: None,
+        supersedes: None,
+        sent_at: Some(sent_at),
+        group: None,
+    }
+}
+
+#[tokio::test]
+async fn uuid_ids_from_older_clients_are_taken_alongside_ulids() {
+    let dir = TempDir::new("uuid-ids");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let uuid = uuid::Uuid::new_v4().to_string();
+    let mut older = raw_send(&mut stream, ("carol", &carol), "bob", "from an older client", Utc::now()).await;
+    let ServerCommand::Send { message_id, recipient_id, encrypted_content, sent_at, signature, .. } = &mut older else { unreachable!() };
+    *message_id = uuid;
+    let payload = send_payload(message_id, recipient_id, &hex::decode(&*encrypted_content).unwrap(), sent_at.unwrap());
+    *signature = hex::encode(carol.sign_with_context(crypto::context::SEND, &payload).to_bytes());
+    let newer = raw_send(&mut stream, ("carol", &carol), "bob", "from a current client", Utc::now()).await;
+    for send in [&older, &newer] {
+        assert!(matches!(exchange(&mut stream, send).await, ServerResponse::MessageSent { .. }));
+    }
+    // A UUID id is only accepted once, like any other
+    assert!(matches!(exchange(&mut stream, &older).await, ServerResponse::Error { .. }));
+
+    let received = bob.receive().await;
+    let mut bodies: Vec<&str> = received.iter().map(|view| view.body.as_str()).collect();
+    bodies.sort();
+    assert_eq!(bodies, ["from a current client", "from an older client"]);
+    assert!(received.iter().all(|view| view.signature == Some(SignatureCheck::Verified)));
+}
+
+/// The server's receipt times a message by its own clock, so it verifies
+/// however far off the sender's and recipient's are; the time the sender
+/// claims is only shown beside it once the two are far apart.
+#[tokio::test]
+async fn a_skewed_senders_claimed_time_is_shown_beside_the_attested_one() {
+    let dir = TempDir::new("skewed-clocks");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    bob.skew_clock(chrono::Duration::seconds(200));
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    // Carol's clock runs behind; her ids and signed times come from it
+    let mut claimed = HashMap::new();
+    for (text, behind) in [("from far behind", 200), ("from just behind", 30)] {
+        let sent_at = Utc::now() - chrono::Duration::seconds(behind);
+        let mut send = raw_send(&mut stream, ("carol", &carol), "bob", text, sent_at).await;
+        let ServerCommand::Send { message_id, recipient_id, encrypted_content, signature, .. } = &mut send else { unreachable!() };
+        *message_id = ulid::Ulid::from_datetime(sent_at.into()).to_string();
+        let payload = send_payload(message_id, recipient_id, &hex::decode(&*encrypted_content).unwrap(), sent_at);
+        *signature = hex::encode(carol.sign_with_context(crypto::context::SEND, &payload).to_bytes());
+        claimed.insert(text, message_id_time(message_id).unwrap());
+        assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+    }
+
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 2);
+    for view in &received {
+        assert_eq!(view.signature, Some(SignatureCheck::Verified));
+        assert!((view.timestamp - Utc::now()).num_seconds().abs() < 30, "{} was timed by a client's clock: {}", view.body, view.timestamp);
+    }
+    let shown = |text: &str| received.iter().find(|view| view.body == text).unwrap().claimed_at;
+    // Only there with a receipt that verified against the pinned server key
+    assert_eq!(shown("from far behind"), Some(claimed["from far behind"]));
+    assert_eq!(shown("from just behind"), None);
+}
+
+#[tokio::test]
+async fn message_is_delivered_decrypted_and_verified() {
+    let dir = TempDir::new("end-to-end");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+
+    // Alice has never exchanged keys with bob; she looks up the one he published
+    alice.send("bob", "hello bob").await.unwrap();
+
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].sender, "alice");
+    assert_eq!(received[0].body, "hello bob");
+    assert_eq!(received[0].signature, Some(SignatureCheck::Verified));
+    assert!(bob.receive().await.is_empty(), "acknowledged messages came back");
+}
+
+/// Someone bob has no contact for is read on the strength of their
+/// identity's signature over the key in the envelope, which isn't kept.
+#[tokio::test]
+async fn a_first_contact_is_decrypted_with_the_key_their_identity_signed() {
+    let dir = TempDir::new("first-contact");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    assert!(bob.contact("carol").is_none());
+
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "we haven't met", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].sender, "carol");
+    assert_eq!(received[0].body, "we haven't met");
+    assert_eq!(received[0].signature, Some(SignatureCheck::Verified));
+    assert!(bob.contact("carol").is_none(), "a first contact's key was kept without asking");
+}
+
+/// A server that reseals a message under a key of its own can't pass it
+/// off as the sender's: carol's identity never signed that key, so nothing
+/// is decrypted with it.
+#[tokio::test]
+async fn an_envelope_key_the_senders_identity_did_not_sign_is_refused() {
+    let dir = TempDir::new("forged-key");
+    let data = dir.0.join("server");
+    let addr = start_server(&data).await;
+    let bob_home = dir.0.join("bob");
+    let bob = connect_client(&bob_home, "bob", &addr).await;
+    drop(bob);
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    let ServerResponse::PublicKeys { x25519: Some(published), .. } = exchange(&mut stream, &ServerCommand::GetPublicKey { client_id: "bob".to_string() }).await else {
+        panic!("bob has no published X25519 key");
+    };
+    let bob_key: [u8; 32] = hex::decode(&published.x25519_public_key).unwrap().try_into().unwrap();
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "the real one", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+
+    // The same mailboxes, served with carol's message swapped for mallory's
+    let mallory = CryptoManager::new();
+    let mut backend = Rigged::open(&data);
+    backend.forge = Box::new(move |message| {
+        if message.sender_id != "carol" {
+            return;
+        }
+        let encrypted = mallory.encrypt_message(&bob_key.into(), "pay mallory").unwrap();
+        let x25519_public_key = hex::encode(mallory.get_x25519_public_key().as_bytes());
+        let key_signature = mallory.sign_with_context(crypto::context::SENDER_KEY, &sender_key_payload("carol", &x25519_public_key));
+        let payload = send_payload(&message.id, &message.recipient_id, &encrypted, message.sent_at.unwrap());
+        message.signature = Some(hex::encode(mallory.sign_with_context(crypto::context::SEND, &payload).to_bytes()));
+        message.content = hex::encode(encrypted);
+        message.sender_key = Some(SenderKey { x25519_public_key, signature: hex::encode(key_signature.to_bytes()) });
+    });
+    let forged = backend.serve(&data, &[]).await;
+
+    // Shown even though it doesn't verify, to see what decrypted
+    let mut bob = connect_client(&bob_home, "bob", &forged).await;
+    bob.show_invalid_bodies();
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].sender, "carol");
+    assert!(!received[0].body.contains("mallory"), "the forged message was decrypted: {}", received[0].body);
+    assert!(received[0].body.contains("can't decrypt"), "{}", received[0].body);
+    assert_eq!(received[0].signature, Some(SignatureCheck::Invalid));
+}
+
+/// The signature covers the ciphertext bytes, not the hex they travel as.
+#[tokio::test]
+async fn a_send_is_verified_against_its_ciphertext() {
+    let dir = TempDir::new("send-signature");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let mut altered = raw_send(&mut stream, ("carol", &carol), "bob", "altered on the way", Utc::now()).await;
+    let ServerCommand::Send { encrypted_content, .. } = &mut altered else { unreachable!() };
+    let mut ciphertext = hex::decode(&*encrypted_content).unwrap();
+    ciphertext[0] ^= 1;
+    *encrypted_content = hex::encode(ciphertext);
+    assert!(matches!(exchange(&mut stream, &altered).await, ServerResponse::Error { .. }), "an altered ciphertext was accepted");
+
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "sent as signed", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].sender, "carol");
+    assert_eq!(received[0].signature, Some(SignatureCheck::Verified));
+}
+
+#[tokio::test]
+async fn connected_recipient_is_told_of_new_messages() {
+    let dir = TempDir::new("push");
+    let addr = start_server(&dir.0.join("server")).await;
+    let home = dir.0.join("bob");
+    let paths = ClientPaths::resolve(Some(&home.to_string_lossy())).unwrap();
+    paths.create().unwrap();
+    let key_file = paths.key_file("bob");
+    let mut bob = Client::new("bob", paths, key_file, false).unwrap();
+    bob.offer_push_delivery();
+    bob.connect_to(&addr).await.unwrap();
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+
+    alice.send("bob", "are you there").await.unwrap();
+
+    let pushed = tokio::time::timeout(Duration::from_secs(5), bob.next_push()).await.expect("no push arrived");
+    assert_eq!(pushed.as_deref(), Some("default"));
+    // The push doesn't take the message out of the mailbox, and answers still line up
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, "are you there");
+}
+
+/// The next push or connection event `client` has, waiting up to five seconds.
+async fn next_event(client: &mut Client) -> ClientEvent {
+    tokio::time::timeout(Duration::from_secs(5), client.next_event()).await.expect("no event came")
+}
+
+#[tokio::test]
+async fn connection_events_come_in_order_with_pushes() {
+    let dir = TempDir::new("events");
+    let (addr, server) = start_stoppable_server(&dir.0.join("server")).await;
+    let home = dir.0.join("bob");
+    let paths = ClientPaths::resolve(Some(&home.to_string_lossy())).unwrap();
+    paths.create().unwrap();
+    let key_file = paths.key_file("bob");
+    let mut bob = Client::new("bob", paths, key_file, false).unwrap();
+    bob.offer_push_delivery();
+    bob.connect_to(&addr).await.unwrap();
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    let ClientEvent::Connected { server: name, info } = next_event(&mut bob).await else { panic!("no Connected first") };
+    assert_eq!((name.as_str(), info.addr.as_str(), info.push_delivery), ("default", addr.as_str(), true));
+
+    // The push is read before the server goes, so it's told of first
+    alice.send("bob", "just before the end").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    server.stop().await;
+    assert_eq!(next_event(&mut bob).await, ClientEvent::Pushed { server: "default".to_string() });
+    assert!(matches!(next_event(&mut bob).await, ClientEvent::Disconnected { server, .. } if server == "default"));
+    assert_eq!(next_event(&mut bob).await, ClientEvent::SessionExpired { server: "default".to_string() });
+
+    // A server with another key in its place is refused on redial
+    let mut options = ServerOptions::from_args(&[]).unwrap();
+    options.data_dir = dir.0.join("impostor");
+    options.bind = addr.clone();
+    let _impostor = serve(options).await;
+    assert!(bob.receive().await.is_empty());
+    assert_eq!(next_event(&mut bob).await, ClientEvent::Reconnecting { server: "default".to_string(), attempt: 1 });
+    let ClientEvent::KeyMismatch { expected, actual, .. } = next_event(&mut bob).await else { panic!("the new key went unnoticed") };
+    assert_eq!(expected, info.fingerprint);
+    assert_ne!(actual, expected);
+}
+
+#[tokio::test]
+async fn forged_client_id_cannot_read_a_mailbox() {
+    let dir = TempDir::new("forged-read");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "for bob only").await.unwrap();
+
+    let mallory = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let fetch = ServerCommand::GetMessages { client_id: "bob".to_string(), since: None, on_behalf_of: None, signature: None, challenge: None };
+    assert_challenge_failed(answer_as(&mut stream, fetch.clone(), &mallory).await);
+    // A nonce the server never handed out doesn't get past either
+    let made_up = ChallengeAnswer { nonce: "00".repeat(32), signature: "00".repeat(64) };
+    assert_challenge_failed(exchange(&mut stream, &fetch.answering(made_up)).await);
+
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1, "the forged reads took bob's message");
+    assert_eq!(received[0].body, "for bob only");
+}
+
+#[tokio::test]
+async fn forged_client_id_cannot_take_over_a_registration() {
+    let dir = TempDir::new("forged-register");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+
+    let mallory = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let register = ServerCommand::Register {
+        client_id: "bob".to_string(),
+        public_key: hex::encode(mallory.get_ed25519_public_key().as_bytes()),
+        recovery_key: None,
+        protocol_version: None,
+        features: None,
+        credential: None,
+        x25519_public_key: None,
+        x25519_signature: None,
+        challenge: None,
+    };
+    assert_challenge_failed(answer_as(&mut stream, register, &mallory).await);
+
+    // Bob's key is still the one on file, so messages to him still verify
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "still you?").await.unwrap();
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].signature, Some(SignatureCheck::Verified));
+}
+
+#[tokio::test]
+async fn replayed_send_is_refused_after_the_message_is_acked() {
+    let dir = TempDir::new("replay");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "only once", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, "only once");
+
+    // The message is gone from the mailbox, but its id is remembered
+    assert_refused(exchange(&mut stream, &send).await, error_code::REPLAYED_MESSAGE_ID);
+    assert!(bob.receive().await.is_empty(), "the replay was delivered");
+}
+
+#[tokio::test]
+async fn a_captured_send_cant_be_replayed_under_a_new_id_or_to_someone_else() {
+    let dir = TempDir::new("replay-rewritten");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut dave = connect_client(&dir.0.join("dave"), "dave", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "only for bob", Utc::now()).await;
+    let mut renamed = send.clone();
+    let ServerCommand::Send { message_id, .. } = &mut renamed else { unreachable!() };
+    *message_id = new_message_id();
+    assert!(matches!(exchange(&mut stream, &renamed).await, ServerResponse::Error { .. }), "a send was accepted under a new id");
+    let mut redirected = send.clone();
+    let ServerCommand::Send { recipient_id, .. } = &mut redirected else { unreachable!() };
+    *recipient_id = "dave".to_string();
+    assert!(matches!(exchange(&mut stream, &redirected).await, ServerResponse::Error { .. }), "a send was accepted for another recipient");
+
+    // Without a send time there'd be nothing to age it out once its id is forgotten
+    let mut timeless = send;
+    let ServerCommand::Send { signature, encrypted_content, sent_at, .. } = &mut timeless else { unreachable!() };
+    *sent_at = None;
+    *signature = hex::encode(carol.sign_with_context(crypto::context::SEND, &hex::decode(&*encrypted_content).unwrap()).to_bytes());
+    assert_refused(exchange(&mut stream, &timeless).await, error_code::STALE_SEND);
+
+    assert!(bob.receive().await.is_empty(), "a rewritten send was delivered");
+    assert!(dave.receive().await.is_empty(), "a redirected send was delivered");
+}
+
+/// An AdminBatch of `actions` from `admin`, signed at `signed_at`.
+fn admin_batch(admin: (&str, &CryptoManager), actions: Vec<AdminAction>, signed_at: DateTime<Utc>) -> ServerCommand {
+    let signature = admin.1.sign_with_context(crypto::context::ADMIN_BATCH, &admin_batch_payload(admin.0, &actions, true, signed_at));
+    ServerCommand::AdminBatch { admin_id: admin.0.to_string(), actions, transactional: true, signed_at, signature: hex::encode(signature.to_bytes()) }
+}
+
+#[tokio::test]
+async fn a_captured_admin_batch_runs_only_once() {
+    let dir = TempDir::new("batch-replay");
+    let args: Vec<String> = ["server", "--admin", "root"].iter().map(|arg| arg.to_string()).collect();
+    let mut options = ServerOptions::from_args(&args).unwrap();
+    options.data_dir = dir.0.join("server");
+    options.bind = free_addr();
+    let addr = serve(options).await;
+    let (root, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "root", &root).await;
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let ban = admin_batch(("root", &root), vec![AdminAction::Ban { client_id: "carol".to_string(), reason: None }], Utc::now());
+    assert!(matches!(exchange(&mut stream, &ban).await, ServerResponse::AdminBatch { applied: true, .. }));
+    let unban = admin_batch(("root", &root), vec![AdminAction::Unban { client_id: "carol".to_string() }], Utc::now());
+    assert!(matches!(exchange(&mut stream, &unban).await, ServerResponse::AdminBatch { applied: true, .. }));
+
+    // Replaying the ban, now or once it has aged, doesn't ban carol again
+    assert_refused(exchange(&mut stream, &ban).await, error_code::REPLAYED_REQUEST);
+    let aged = admin_batch(("root", &root), vec![AdminAction::Ban { client_id: "carol".to_string(), reason: None }], Utc::now() - chrono::Duration::hours(1));
+    assert_refused(exchange(&mut stream, &aged).await, error_code::STALE_REQUEST);
+    let send = raw_send(&mut stream, ("carol", &carol), "root", "still here", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }), "carol was banned again");
+}
+
+#[tokio::test]
+async fn a_drained_server_says_goodbye_serves_open_connections_and_exits() {
+    let dir = TempDir::new("drain");
+    let args: Vec<String> = ["server", "--admin", "root"].iter().map(|arg| arg.to_string()).collect();
+    let mut options = ServerOptions::from_args(&args).unwrap();
+    options.data_dir = dir.0.join("server");
+    options.bind = free_addr();
+    let (addr, server) = serve_stoppable(options).await;
+    let (root, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut admin = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut admin, "root", &root).await;
+    let mut open = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut open, "carol", &carol).await;
+
+    // The GoAway may overtake the batch's own answer on the admin's connection
+    let drain = admin_batch(("root", &root), vec![AdminAction::Drain { grace_secs: 2 }], Utc::now());
+    let mut answer = exchange(&mut admin, &drain).await;
+    while let ServerResponse::GoAway { .. } = answer {
+        answer = try_read_response(&mut admin).await.unwrap();
+    }
+    assert!(matches!(answer, ServerResponse::AdminBatch { applied: true, .. }), "{:?}", answer);
+
+    // Open connections are told when to come back, and still served until then
+    let ServerResponse::GoAway { deadline } = try_read_response(&mut open).await.unwrap() else {
+        panic!("carol's connection wasn't told the server is going away");
+    };
+    assert!(deadline > Utc::now() && deadline <= Utc::now() + chrono::Duration::seconds(2));
+    let send = raw_send(&mut open, ("carol", &carol), "root", "one for the road", Utc::now()).await;
+    assert!(matches!(exchange(&mut open, &send).await, ServerResponse::MessageSent { .. }));
+    assert!(TcpStream::connect(&addr).await.is_err(), "a draining server took a new connection");
+
+    let stopped = tokio::time::timeout(Duration::from_secs(10), server.running).await.expect("the drain never finished").unwrap().unwrap();
+    assert_eq!(stopped, Stopped::Drained);
+    assert!(Utc::now() >= deadline, "the server left before its deadline with a connection open");
+}
+
+#[tokio::test]
+async fn the_debug_dump_shows_who_is_connected_and_runs_once() {
+    let dir = TempDir::new("debug-dump");
+    let args: Vec<String> = ["server", "--admin", "root"].iter().map(|arg| arg.to_string()).collect();
+    let mut options = ServerOptions::from_args(&args).unwrap();
+    options.data_dir = dir.0.join("server");
+    options.bind = free_addr();
+    let addr = serve(options).await;
+    let (root, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut admin = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut admin, "root", &root).await;
+    let mut other = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut other, "carol", &carol).await;
+
+    let signed_at = Utc::now();
+    let signature = root.sign_with_context(crypto::context::DEBUG_DUMP, &debug_dump_payload("root", signed_at));
+    let command = ServerCommand::DebugDump { admin_id: "root".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) };
+    let ServerResponse::DebugDump { dump } = exchange(&mut admin, &command).await else { panic!("no debug dump") };
+    let connections = dump["connections"].as_array().unwrap();
+    assert_eq!(connections.len(), 2, "{:#}", dump);
+    // Both registered, but neither id is shown as it is
+    assert!(connections.iter().all(|c| c["client_id"].is_string()), "{:#}", dump);
+    assert!(!dump.to_string().contains("carol"), "an id wasn't hashed: {:#}", dump);
+    let in_flight: Vec<&str> = connections.iter().filter_map(|c| c["in_flight"]["command"].as_str()).collect();
+    assert_eq!(in_flight, ["DebugDump"]);
+
+    assert_refused(exchange(&mut admin, &command).await, error_code::REPLAYED_REQUEST);
+}
+
+/// A JSON backend that panics the next time it is asked its size once
+/// `armed`. `Storage` asks right after each write, on the handler's task, so
+/// the panic unwinds through the request being handled. Each message it
+/// loads goes through `forge` first, as a server rewriting its mailboxes
+/// would hand them out.
+struct Rigged {
+    inner: JsonBackend,
+    armed: Arc<AtomicBool>,
+    forge: Box<dyn Fn(&mut Message) + Send + Sync>,
+}
+
+impl Rigged {
+    fn open(dir: &Path) -> Self {
+        Self { inner: JsonBackend::open(dir).unwrap(), armed: Arc::new(AtomicBool::new(false)), forge: Box::new(|_| ()) }
+    }
+
+    /// A server on a free port over this backend, with `args` for its flags.
+    async fn serve(self, dir: &Path, args: &[&str]) -> String {
+        let storage = storage::Storage::with_backend(&dir.to_string_lossy(), Box::new(self)).await.unwrap();
+        let args: Vec<String> = std::iter::once("server").chain(args.iter().copied()).map(str::to_string).collect();
+        let mut options = ServerOptions::from_args(&args).unwrap();
+        options.data_dir = dir.to_path_buf();
+        options.bind = free_addr();
+        let addr = options.bind.clone();
+        let server = Server::with_storage(options, storage).await.unwrap();
+        let listen = addr.clone();
+        tokio::spawn(async move { server.run(&listen).await });
+        wait_for_listener(&addr).await;
+        addr
+    }
+}
+
+impl StorageBackend for Rigged {
+    fn name(&self) -> &'static str {
+        "rigged"
+    }
+    fn register_client(&self, info: &ClientInfo) -> anyhow::Result<()> {
+        self.inner.register_client(info)
+    }
+    fn get_client_info(&self, client_id: &str) -> anyhow::Result<Option<ClientInfo>> {
+        self.inner.get_client_info(client_id)
+    }
+    fn get_all_clients(&self) -> anyhow::Result<HashMap<String, ClientInfo>> {
+        self.inner.get_all_clients()
+    }
+    fn update_last_seen(&self, client_id: &str, at: DateTime<Utc>) -> anyhow::Result<()> {
+        self.inner.update_last_seen(client_id, at)
+    }
+    fn add_message(&self, message: &Message) -> anyhow::Result<()> {
+        self.inner.add_message(message)
+    }
+    fn get_messages_for_client(&self, client_id: &str) -> anyhow::Result<Vec<Message>> {
+        self.inner.get_messages_for_client(client_id)
+    }
+    fn delete_messages(&self, client_id: &str, message_ids: &[String]) -> anyhow::Result<usize> {
+        self.inner.delete_messages(client_id, message_ids)
+    }
+    fn update_messages(&self, client_id: &str, messages: &[Message]) -> anyhow::Result<()> {
+        self.inner.update_messages(client_id, messages)
+    }
+    fn get_all_messages(&self) -> anyhow::Result<HashMap<String, Vec<Message>>> {
+        let mut mailboxes = self.inner.get_all_messages()?;
+        mailboxes.values_mut().flatten().for_each(|message| (self.forge)(message));
+        Ok(mailboxes)
+    }
+    fn replace_all_messages(&self, messages: &HashMap<String, Vec<Message>>) -> anyhow::Result<()> {
+        self.inner.replace_all_messages(messages)
+    }
+    fn replace_all_clients(&self, clients: &HashMap<String, ClientInfo>) -> anyhow::Result<()> {
+        self.inner.replace_all_clients(clients)
+    }
+    fn flush(&self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+    fn disk_usage(&self) -> Vec<(&'static str, u64)> {
+        if self.armed.swap(false, Ordering::SeqCst) {
+            panic!("poisoned storage");
+        }
+        self.inner.disk_usage()
+    }
+}
+
+/// The connections root's debug dump lists.
+async fn connection_count(admin: &mut TcpStream, root: &CryptoManager) -> usize {
+    let signed_at = Utc::now();
+    let signature = root.sign_with_context(crypto::context::DEBUG_DUMP, &debug_dump_payload("root", signed_at));
+    let command = ServerCommand::DebugDump { admin_id: "root".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) };
+    let ServerResponse::DebugDump { dump } = exchange(admin, &command).await else { panic!("no debug dump") };
+    dump["connections"].as_array().unwrap().len()
+}
+
+#[tokio::test]
+async fn a_panicking_handler_closes_only_its_own_connection() {
+    let dir = TempDir::new("panic");
+    let data = dir.0.join("server");
+    std::fs::create_dir_all(&data).unwrap();
+    let backend = Rigged::open(&data);
+    let armed = Arc::clone(&backend.armed);
+    let addr = backend.serve(&data, &["--admin", "root"]).await;
+
+    let (root, carol, bob) = (CryptoManager::new(), CryptoManager::new(), CryptoManager::new());
+    let mut admin = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut admin, "root", &root).await;
+    register_raw(&mut admin, "bob", &bob).await;
+    let mut doomed = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut doomed, "carol", &carol).await;
+    assert_eq!(connection_count(&mut admin, &root).await, 2);
+
+    // The send's write panics; carol is told, and her connection closed
+    let send = raw_send(&mut doomed, ("carol", &carol), "bob", "this one breaks", Utc::now()).await;
+    armed.store(true, Ordering::SeqCst);
+    assert_refused(exchange(&mut doomed, &send).await, error_code::INTERNAL_ERROR);
+    assert!(!armed.load(Ordering::SeqCst), "nothing asked the rigged backend");
+    assert!(try_read_response(&mut doomed).await.is_err(), "the connection stayed open after a panic");
+
+    // Its registration went with it, while root's connection carries on
+    let mut open = connection_count(&mut admin, &root).await;
+    for _ in 0..50 {
+        if open == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        open = connection_count(&mut admin, &root).await;
+    }
+    assert_eq!(open, 1, "the panicked connection was never cleaned up");
+
+    // The locks the handler held were let go: new connections are served as before
+    let mut again = TcpStream::connect(&addr).await.unwrap();
+    let send = raw_send(&mut again, ("carol", &carol), "bob", "try again", Utc::now()).await;
+    assert!(matches!(exchange(&mut again, &send).await, ServerResponse::MessageSent { .. }));
+    assert!(mail(fetch_raw(&mut admin, "bob", &bob).await).iter().any(|m| m.sender_id == "carol"));
+}
+
+#[tokio::test]
+async fn a_huge_malformed_request_is_not_echoed_back() {
+    let dir = TempDir::new("malformed");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    // serde quotes the unknown command name, all megabyte of it
+    let body = format!("{{\"{}\":{{}}}}", "Z".repeat(1 << 20));
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await.unwrap();
+    stream.write_all(body.as_bytes()).await.unwrap();
+    let response = try_read_response(&mut stream).await.unwrap();
+    let ServerResponse::Error { message, code, .. } = &response else { panic!("a malformed request was taken: {:?}", response) };
+    assert_eq!(code.as_deref(), Some(error_code::INVALID_REQUEST));
+    assert!(message.len() < 300, "{} bytes came back", message.len());
+    // The connection is still good for the next request
+    register_raw(&mut stream, "carol", &CryptoManager::new()).await;
+}
+
+#[tokio::test]
+async fn client_detail_lookups_cannot_be_replayed() {
+    let dir = TempDir::new("client-details");
+    let addr = start_server_with(&dir.0.join("server"), &["--admin", "root"]).await;
+    let (root, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut admin = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut admin, "root", &root).await;
+    register_raw(&mut admin, "carol", &carol).await;
+
+    let lookup = |signed_at: DateTime<Utc>| {
+        let signature = root.sign_with_context(crypto::context::CLIENT_DETAILS, &client_details_payload("root", "carol", signed_at));
+        ServerCommand::GetClientDetails { admin_id: "root".to_string(), client_id: "carol".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) }
+    };
+    let command = lookup(Utc::now());
+    let ServerResponse::ClientDetails { client } = exchange(&mut admin, &command).await else { panic!("no client details") };
+    assert_eq!(client.id, "carol");
+
+    // Captured, the lookup can't be made again, now or later
+    assert_refused(exchange(&mut admin, &command).await, error_code::REPLAYED_REQUEST);
+    assert_refused(exchange(&mut admin, &lookup(Utc::now() - chrono::Duration::hours(1))).await, error_code::STALE_REQUEST);
+}
+
+#[tokio::test]
+async fn user_data_reads_cannot_be_replayed() {
+    let dir = TempDir::new("user-data");
+    let addr = start_server(&dir.0.join("server")).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    let signature = carol.sign_with_context(crypto::context::USER_DATA, &user_data_payload("carol", "drafts", Some("5ea1ed"), 0));
+    let put = ServerCommand::PutUserData {
+        client_id: "carol".to_string(),
+        key: "drafts".to_string(),
+        value: Some("5ea1ed".to_string()),
+        version: 0,
+        signature: hex::encode(signature.to_bytes()),
+    };
+    let written = exchange(&mut stream, &put).await;
+    assert!(matches!(written, ServerResponse::UserData { version: 1, .. }), "{:?}", written);
+
+    let read = |signed_at: DateTime<Utc>| {
+        let signature = carol.sign_with_context(crypto::context::USER_DATA_REF, &user_data_ref_payload("carol", "drafts", signed_at));
+        ServerCommand::GetUserData { client_id: "carol".to_string(), key: "drafts".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) }
+    };
+    let command = read(Utc::now());
+    let ServerResponse::UserData { value, version, .. } = exchange(&mut stream, &command).await else { panic!("carol couldn't read her data") };
+    assert_eq!((value.as_deref(), version), (Some("5ea1ed"), 1));
+
+    assert_refused(exchange(&mut stream, &command).await, error_code::REPLAYED_REQUEST);
+    assert_refused(exchange(&mut stream, &read(Utc::now() - chrono::Duration::hours(1))).await, error_code::STALE_REQUEST);
+}
+
+/// Fetch `id`'s mailbox over a raw connection.
+async fn fetch_raw(stream: &mut TcpStream, id: &str, crypto: &CryptoManager) -> Vec<Message> {
+    let fetch = ServerCommand::GetMessages { client_id: id.to_string(), since: None, on_behalf_of: None, signature: None, challenge: None };
+    match answer_as(stream, fetch, crypto).await {
+        ServerResponse::Messages { messages } => messages,
+        other => panic!("{} couldn't fetch: {:?}", id, other),
+    }
+}
+
+/// Carol's send to dave, who hasn't registered; she knows his key out of band.
+fn send_to_dave(carol: &CryptoManager, dave: &CryptoManager) -> ServerCommand {
+    sealed_send(("carol", carol), ("dave", *dave.get_x25519_public_key().as_bytes()), "before you joined", Utc::now())
+}
+
+#[tokio::test]
+async fn unknown_recipients_are_refused_under_reject() {
+    let dir = TempDir::new("unknown-reject");
+    let addr = start_server_with(&dir.0.join("server"), &["--unknown-recipients", "reject"]).await;
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let refused = exchange(&mut stream, &send_to_dave(&carol, &dave)).await;
+    assert!(matches!(&refused, ServerResponse::Error { message, .. } if message.contains("Unknown recipient")), "{:?}", refused);
+    register_raw(&mut stream, "dave", &dave).await;
+    assert!(fetch_raw(&mut stream, "dave", &dave).await.is_empty());
+}
+
+#[tokio::test]
+async fn unknown_recipients_are_stored_under_accept() {
+    let dir = TempDir::new("unknown-accept");
+    let addr = start_server_with(&dir.0.join("server"), &["--unknown-recipients", "accept"]).await;
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let send = send_to_dave(&carol, &dave);
+    let ServerResponse::MessageSent { pending_until, hlc, .. } = exchange(&mut stream, &send).await else { panic!("carol's send was refused") };
+    assert!(pending_until.is_none() && hlc.is_some(), "an accepted message was held");
+    register_raw(&mut stream, "dave", &dave).await;
+    assert_eq!(fetch_raw(&mut stream, "dave", &dave).await.len(), 1);
+    assert!(fetch_raw(&mut stream, "carol", &carol).await.is_empty(), "carol was told of a claim");
+}
+
+/// `client_id`'s retention policy for messages from `sender_id`, signed at `signed_at`.
+fn set_retention(client: (&str, &CryptoManager), sender_id: &str, ttl_secs: Option<u64>, signed_at: DateTime<Utc>) -> ServerCommand {
+    let signature = client.1.sign_with_context(crypto::context::RETENTION, &retention_payload(client.0, sender_id, ttl_secs, signed_at));
+    ServerCommand::SetRetention { client_id: client.0.to_string(), sender_id: sender_id.to_string(), ttl_secs, signed_at, signature: hex::encode(signature.to_bytes()) }
+}
+
+/// How long the server will keep a send from carol to dave with `ttl_secs`,
+/// and whether dave's retention policy is what cut it short.
+async fn kept_for(stream: &mut TcpStream, carol: &CryptoManager, ttl_secs: Option<u64>) -> (Option<i64>, bool) {
+    let mut send = raw_send(stream, ("carol", carol), "dave", "kept a while", Utc::now()).await;
+    let ServerCommand::Send { ttl_secs: ttl, .. } = &mut send else { unreachable!() };
+    *ttl = ttl_secs;
+    match exchange(stream, &send).await {
+        ServerResponse::MessageSent { expires_at, retention_applied, received_at: Some(received_at), .. } =>
+            (expires_at.map(|at| (at - received_at).num_seconds()), retention_applied),
+        other => panic!("carol's send was refused: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn retention_policies_cut_message_lifetimes_short() {
+    let dir = TempDir::new("retention");
+    let addr = start_server(&dir.0.join("server")).await;
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    register_raw(&mut stream, "dave", &dave).await;
+    assert_eq!(kept_for(&mut stream, &carol, None).await, (None, false));
+
+    let policy = set_retention(("dave", &dave), "carol", Some(60), Utc::now());
+    assert!(matches!(exchange(&mut stream, &policy).await, ServerResponse::Ok));
+    // The shorter of the sender's TTL and the policy wins
+    assert_eq!(kept_for(&mut stream, &carol, None).await, (Some(60), true));
+    assert_eq!(kept_for(&mut stream, &carol, Some(600)).await, (Some(60), true));
+    assert_eq!(kept_for(&mut stream, &carol, Some(30)).await, (Some(30), false));
+
+    // Neither a captured change nor one signed long ago can be made again
+    let clear = set_retention(("dave", &dave), "carol", None, Utc::now());
+    assert!(matches!(exchange(&mut stream, &clear).await, ServerResponse::Ok));
+    assert_refused(exchange(&mut stream, &policy).await, error_code::REPLAYED_REQUEST);
+    let stale = set_retention(("dave", &dave), "carol", Some(60), Utc::now() - chrono::Duration::hours(1));
+    assert_refused(exchange(&mut stream, &stale).await, error_code::STALE_REQUEST);
+    assert_eq!(kept_for(&mut stream, &carol, Some(600)).await, (Some(600), false));
+}
+
+/// Carol's new plain-text guest link, as (link id, secret).
+async fn open_guest_link(stream: &mut TcpStream, carol: &CryptoManager, ttl_secs: u64, max_messages: u32) -> (String, String) {
+    let signed_at = Utc::now();
+    let signature = carol.sign_with_context(crypto::context::GUEST_LINK, &guest_link_payload("carol", ttl_secs, max_messages, None, signed_at));
+    let command = ServerCommand::CreateGuestLink { client_id: "carol".to_string(), ttl_secs, max_messages, x25519_public_key: None, signed_at, signature: hex::encode(signature.to_bytes()) };
+    match exchange(stream, &command).await {
+        ServerResponse::GuestLinkCreated { link, secret } => (link.id, secret),
+        other => panic!("carol couldn't open a guest link: {:?}", other),
+    }
+}
+
+fn guest_send(link_id: &str, secret: &str, text: &str) -> ServerCommand {
+    ServerCommand::GuestSend {
+        link_id: link_id.to_string(),
+        secret: secret.to_string(),
+        message_id: new_message_id(),
+        content: text.to_string(),
+        ephemeral_key: None,
+    }
+}
+
+fn revoke_guest_link(id: &str, crypto: &CryptoManager, link_id: &str) -> ServerCommand {
+    let signature = crypto.sign_with_context(crypto::context::GUEST_LINK_REF, &guest_link_ref_payload(id, link_id));
+    ServerCommand::RevokeGuestLink { client_id: id.to_string(), link_id: link_id.to_string(), signature: hex::encode(signature.to_bytes()) }
+}
+
+#[tokio::test]
+async fn guest_links_refuse_guests_once_expired_used_up_or_revoked() {
+    let dir = TempDir::new("guest-abuse");
+    let addr = start_server(&dir.0.join("server")).await;
+    let (carol, mallory) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    register_raw(&mut stream, "mallory", &mallory).await;
+
+    // Used up: the second message over a one-message link is refused
+    let (once, secret) = open_guest_link(&mut stream, &carol, 3600, 1).await;
+    assert!(matches!(exchange(&mut stream, &guest_send(&once, &secret, "hi")).await, ServerResponse::MessageSent { .. }));
+    assert_refused(exchange(&mut stream, &guest_send(&once, &secret, "hi again")).await, error_code::GUEST_LINK_USED_UP);
+
+    // A guess at the secret looks like no link at all
+    let (open, secret) = open_guest_link(&mut stream, &carol, 3600, 5).await;
+    assert_refused(exchange(&mut stream, &guest_send(&open, &"0".repeat(64), "let me in")).await, error_code::GUEST_LINK_INVALID);
+    assert_refused(exchange(&mut stream, &guest_send("no-such-link", &secret, "let me in")).await, error_code::GUEST_LINK_INVALID);
+
+    // Revoked: only its owner can, and then no guest gets through
+    assert!(matches!(exchange(&mut stream, &revoke_guest_link("mallory", &mallory, &open)).await, ServerResponse::Error { .. }));
+    assert!(matches!(exchange(&mut stream, &guest_send(&open, &secret, "still open")).await, ServerResponse::MessageSent { .. }));
+    assert!(matches!(exchange(&mut stream, &revoke_guest_link("carol", &carol, &open)).await, ServerResponse::Ok));
+    assert_refused(exchange(&mut stream, &guest_send(&open, &secret, "after revoking")).await, error_code::GUEST_LINK_REVOKED);
+
+    // Expired: a one-second link, tried after the second is up
+    let (brief, secret) = open_guest_link(&mut stream, &carol, 1, 5).await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_refused(exchange(&mut stream, &guest_send(&brief, &secret, "too late")).await, error_code::GUEST_LINK_EXPIRED);
+
+    // Only what got through reached carol
+    let texts: Vec<String> = mail(fetch_raw(&mut stream, "carol", &carol).await).into_iter().map(|m| m.content).collect();
+    assert_eq!(texts, ["hi", "still open"]);
+}
+
+#[tokio::test]
+async fn reports_guest_links_and_invite_codes_cannot_be_replayed() {
+    let dir = TempDir::new("signed-settings");
+    let addr = start_server_with(&dir.0.join("server"), &["--admin", "root"]).await;
+    let (root, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "root", &root).await;
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let report = |signed_at: DateTime<Utc>| {
+        let signature = root.sign_with_context(crypto::context::REPORT, &report_payload("root", "carol", "spam", signed_at));
+        ServerCommand::ReportSender { client_id: "root".to_string(), sender_id: "carol".to_string(), reason: "spam".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) }
+    };
+    let guest_link = |signed_at: DateTime<Utc>| {
+        let signature = root.sign_with_context(crypto::context::GUEST_LINK, &guest_link_payload("root", 3600, 1, None, signed_at));
+        ServerCommand::CreateGuestLink { client_id: "root".to_string(), ttl_secs: 3600, max_messages: 1, x25519_public_key: None, signed_at, signature: hex::encode(signature.to_bytes()) }
+    };
+    let invite_code = |signed_at: DateTime<Utc>| {
+        let code = format!("invite code signed at {}", signed_at.timestamp_millis());
+        let signature = root.sign_with_context(crypto::context::INVITE_CODE, &invite_code_payload("root", &code, signed_at));
+        ServerCommand::CreateInviteCode { admin_id: "root".to_string(), code, signed_at, signature: hex::encode(signature.to_bytes()) }
+    };
+    let commands: [&dyn Fn(DateTime<Utc>) -> ServerCommand; 3] = [&report, &guest_link, &invite_code];
+    for command in commands {
+        let fresh = command(Utc::now());
+        assert!(!matches!(exchange(&mut stream, &fresh).await, ServerResponse::Error { .. }), "{} was refused", fresh.name());
+        assert_refused(exchange(&mut stream, &fresh).await, error_code::REPLAYED_REQUEST);
+        assert_refused(exchange(&mut stream, &command(Utc::now() - chrono::Duration::hours(1))).await, error_code::STALE_REQUEST);
+    }
+}
+
+/// Register `id` presenting `credential`, answering the challenge.
+async fn register_with(stream: &mut TcpStream, id: &str, crypto: &CryptoManager, credential: Option<&str>) -> ServerResponse {
+    let mut register = registration(id, crypto, None);
+    let ServerCommand::Register { credential: presented, .. } = &mut register else { unreachable!() };
+    *presented = credential.map(str::to_string);
+    answer_as(stream, register, crypto).await
+}
+
+#[tokio::test]
+async fn open_registration_takes_any_free_id() {
+    let dir = TempDir::new("registration-open");
+    let addr = start_server_with(&dir.0.join("server"), &["--registration", "open"]).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let carol = CryptoManager::new();
+    assert!(matches!(register_with(&mut stream, "carol", &carol, None).await, ServerResponse::Registered { .. }));
+    assert!(matches!(register_with(&mut stream, "carol", &carol, Some("ignored")).await, ServerResponse::Registered { .. }));
+}
+
+#[tokio::test]
+async fn allow_list_registration_takes_only_listed_ids() {
+    let dir = TempDir::new("registration-allow-list");
+    let list = dir.0.join("allowed");
+    std::fs::write(&list, "# staff\ncarol\n\n").unwrap();
+    let addr = start_server_with(&dir.0.join("server"), &["--registration", "allow-list", "--allow-list", &list.to_string_lossy()]).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let (carol, mallory) = (CryptoManager::new(), CryptoManager::new());
+    assert!(matches!(register_with(&mut stream, "carol", &carol, None).await, ServerResponse::Registered { .. }));
+    assert_refused(register_with(&mut stream, "mallory", &mallory, None).await, error_code::REGISTRATION_DENIED);
+    assert_refused(register_with(&mut stream, "# staff", &mallory, None).await, error_code::REGISTRATION_DENIED);
+}
+
+#[tokio::test]
+async fn invite_codes_admit_one_registration_each() {
+    let dir = TempDir::new("registration-invite");
+    let root = CryptoManager::new();
+    let code = "a code long enough to use";
+    // The admin registers while the server is still open, and makes a code
+    let mut options = ServerOptions::from_args(&["server".to_string(), "--admin".to_string(), "root".to_string()]).unwrap();
+    options.data_dir = dir.0.join("server");
+    options.bind = free_addr();
+    let (addr, stop) = serve_stoppable(options).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "root", &root).await;
+    let signed_at = Utc::now();
+    let signature = root.sign_with_context(crypto::context::INVITE_CODE, &invite_code_payload("root", code, signed_at));
+    let create = ServerCommand::CreateInviteCode { admin_id: "root".to_string(), code: code.to_string(), signed_at, signature: hex::encode(signature.to_bytes()) };
+    assert!(matches!(exchange(&mut stream, &create).await, ServerResponse::Ok));
+    drop(stream);
+    stop.stop().await;
+
+    let addr = start_server_with(&dir.0.join("server"), &["--admin", "root", "--registration", "invite-code"]).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    assert_refused(register_with(&mut stream, "carol", &carol, None).await, error_code::CREDENTIAL_REQUIRED);
+    assert_refused(register_with(&mut stream, "carol", &carol, Some("not a code anyone made")).await, error_code::REGISTRATION_DENIED);
+    // A registration that can't be saved gives its code back
+    let inject = dir.0.join("server").join(storage::INJECT_FAILURES_FILE);
+    std::fs::write(&inject, "clients\n").unwrap();
+    assert!(matches!(register_with(&mut stream, "carol", &carol, Some(code)).await, ServerResponse::Error { .. }));
+    std::fs::remove_file(&inject).unwrap();
+    assert!(matches!(register_with(&mut stream, "carol", &carol, Some(code)).await, ServerResponse::Registered { .. }));
+    assert_refused(register_with(&mut stream, "dave", &dave, Some(code)).await, error_code::REGISTRATION_DENIED);
+    // Registered ids come back without one
+    assert!(matches!(register_with(&mut stream, "carol", &carol, None).await, ServerResponse::Registered { .. }));
+    register_raw(&mut stream, "root", &root).await;
+}
+
+#[tokio::test]
+async fn held_invites_move_to_the_mailbox_on_registration() {
+    let dir = TempDir::new("unknown-invite");
+    let addr = start_server_with(&dir.0.join("server"), &["--unknown-recipients", "invite"]).await;
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let send = send_to_dave(&carol, &dave);
+    let ServerResponse::MessageSent { message_id, pending_until, hlc, .. } = exchange(&mut stream, &send).await else { panic!("carol's send was refused") };
+    assert!(pending_until.is_some() && hlc.is_none(), "the message wasn't held");
+    register_raw(&mut stream, "dave", &dave).await;
+    let claimed = fetch_raw(&mut stream, "dave", &dave).await;
+    assert_eq!(claimed.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), [message_id.as_str()]);
+    assert!(claimed[0].hlc > Hlc::default(), "the claimed message wasn't stamped");
+
+    let notices = fetch_raw(&mut stream, "carol", &carol).await;
+    let notice = notices.iter().find_map(|m| m.notice.as_ref()).expect("carol wasn't told dave joined");
+    assert_eq!(notice.notice_type, notice_type::INVITE_CLAIMED);
+    assert_eq!(notice.field("message_id"), message_id);
+}
+
+/// A GetMessageStatus or CancelMessage of `message_id` as `sender_id`, signed by `signer`.
+fn message_ref(cancel: bool, sender_id: &str, message_id: &str, signer: &CryptoManager) -> ServerCommand {
+    let context = if cancel { crypto::context::CANCEL } else { crypto::context::MESSAGE_STATUS };
+    let signature = hex::encode(signer.sign_with_context(context, &message_ref_payload(sender_id, message_id)).to_bytes());
+    let (sender_id, message_id) = (sender_id.to_string(), message_id.to_string());
+    if cancel {
+        ServerCommand::CancelMessage { sender_id, message_id, signature }
+    } else {
+        ServerCommand::GetMessageStatus { sender_id, message_id, signature }
+    }
+}
+
+#[tokio::test]
+async fn only_the_sender_can_look_up_or_cancel_a_message() {
+    let dir = TempDir::new("status-probe");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let (carol, mallory) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    register_raw(&mut stream, "mallory", &mallory).await;
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "not yours", Utc::now()).await;
+    let ServerResponse::MessageSent { message_id, .. } = exchange(&mut stream, &send).await else { panic!("carol's send was refused") };
+
+    // Asking as herself, mallory learns nothing of carol's message, and can't cancel it
+    for cancel in [false, true] {
+        let probe = message_ref(cancel, "mallory", &message_id, &mallory);
+        assert!(matches!(exchange(&mut stream, &probe).await, ServerResponse::MessageStatus { status: DeliveryStatus::Unknown, .. }));
+    }
+    // Nor can she ask as carol without carol's key
+    for cancel in [false, true] {
+        let forged = message_ref(cancel, "carol", &message_id, &mallory);
+        assert!(matches!(exchange(&mut stream, &forged).await, ServerResponse::Error { .. }));
+    }
+    let status = message_ref(false, "carol", &message_id, &carol);
+    assert!(matches!(exchange(&mut stream, &status).await, ServerResponse::MessageStatus { status: DeliveryStatus::Stored, .. }));
+    assert_eq!(bob.receive().await.len(), 1, "a probe removed carol's message");
+}
+
+/// Carol's send of `text` to `recipient`, replacing `old` if that is still unfetched.
+async fn carol_sends(stream: &mut TcpStream, carol: &CryptoManager, recipient: &str, text: &str, old: Option<&str>) -> ServerResponse {
+    let mut send = raw_send(stream, ("carol", carol), recipient, text, Utc::now()).await;
+    if let ServerCommand::Send { supersedes, .. } = &mut send {
+        *supersedes = old.map(str::to_string);
+    }
+    exchange(stream, &send).await
+}
+
+/// A send's answer, expected to say it was stored: its id and whether it replaced a message.
+fn sent(response: ServerResponse) -> (String, bool) {
+    match response {
+        ServerResponse::MessageSent { message_id, replaced, .. } => (message_id, replaced),
+        other => panic!("the send was refused: {:?}", other),
+    }
+}
+
+async fn status_of(stream: &mut TcpStream, message_id: &str, carol: &CryptoManager) -> DeliveryStatus {
+    match exchange(stream, &message_ref(false, "carol", message_id, carol)).await {
+        ServerResponse::MessageStatus { status, .. } => status,
+        other => panic!("no status for {}: {:?}", message_id, other),
+    }
+}
+
+/// Fetched messages without the server's notices, such as quota warnings.
+fn mail(fetched: Vec<Message>) -> Vec<Message> {
+    fetched.into_iter().filter(|m| m.notice.is_none()).collect()
+}
+
+/// The notices `client` has been handed since its last look, with the
+/// servers they came from. Other events are passed over.
+fn notices(client: &mut Client) -> Vec<(String, SystemNotice)> {
+    std::iter::from_fn(|| client.try_next_event())
+        .filter_map(|event| match event {
+            ClientEvent::Notice { server, notice } => Some((server, notice)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn a_filling_mailbox_is_warned_of_through_an_event() {
+    let dir = TempDir::new("quota-notice");
+    let addr = start_server_with(&dir.0.join("server"), &["--mailbox-quota", "5"]).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    for text in ["one", "two", "three", "four"] {
+        alice.send("bob", text).await.unwrap();
+    }
+
+    // The fourth message reaches 80% of five; the warning isn't one of the messages
+    let received = bob.receive().await;
+    assert_eq!(received.iter().map(|m| m.body.as_str()).collect::<Vec<_>>(), ["one", "two", "three", "four"]);
+    let warnings = notices(&mut bob);
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    let (server, notice) = &warnings[0];
+    assert_eq!(server, "default");
+    assert_eq!(notice.notice_type, notice_type::QUOTA_WARNING);
+    assert_eq!((notice.field("used"), notice.field("limit")), ("4", "5"));
+
+    // It came once, and isn't fetched again
+    alice.send("bob", "five").await.unwrap();
+    assert_eq!(bob.receive().await.len(), 1);
+    assert!(notices(&mut bob).is_empty());
+}
+
+fn assert_mailbox_full(response: ServerResponse) {
+    match response {
+        ServerResponse::Error { message, .. } => assert!(message.contains("is full"), "{}", message),
+        other => panic!("a send past the quota was stored: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn a_replacement_takes_the_unfetched_messages_place() {
+    let dir = TempDir::new("supersede-queued");
+    let addr = start_server_with(&dir.0.join("server"), &["--mailbox-quota", "2"]).await;
+    let (carol, bob) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    register_raw(&mut stream, "bob", &bob).await;
+    let (first, _) = sent(carol_sends(&mut stream, &carol, "bob", "see you at 6", None).await);
+    let (second, _) = sent(carol_sends(&mut stream, &carol, "bob", "bring the map", None).await);
+    assert_mailbox_full(carol_sends(&mut stream, &carol, "bob", "one more", None).await);
+
+    // The mailbox is full, but the replacement only takes the original's room
+    let (third, replaced) = sent(carol_sends(&mut stream, &carol, "bob", "see you at 7", Some(&first)).await);
+    assert!(replaced, "the unfetched original wasn't replaced");
+    assert_eq!(status_of(&mut stream, &first, &carol).await, DeliveryStatus::Superseded { by: third.clone() });
+    assert_eq!(status_of(&mut stream, &third, &carol).await, DeliveryStatus::Stored);
+
+    let fetched = mail(fetch_raw(&mut stream, "bob", &bob).await);
+    assert_eq!(fetched.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), [second.as_str(), third.as_str()]);
+    assert!(fetched[1].replaced_queued);
+}
+
+#[tokio::test]
+async fn a_replacement_after_the_fetch_arrives_alongside_the_original() {
+    let dir = TempDir::new("supersede-fetched");
+    let addr = start_server_with(&dir.0.join("server"), &["--mailbox-quota", "2"]).await;
+    let (carol, bob) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    register_raw(&mut stream, "bob", &bob).await;
+    let (first, _) = sent(carol_sends(&mut stream, &carol, "bob", "see you at 6", None).await);
+    // Bob takes the original just before the correction comes in
+    assert_eq!(fetch_raw(&mut stream, "bob", &bob).await.len(), 1);
+
+    let (second, replaced) = sent(carol_sends(&mut stream, &carol, "bob", "see you at 7", Some(&first)).await);
+    assert!(!replaced, "a fetched message was replaced");
+    assert_eq!(status_of(&mut stream, &first, &carol).await, DeliveryStatus::Delivered);
+    // The fetched original still holds its slot until bob acks it
+    assert_mailbox_full(carol_sends(&mut stream, &carol, "bob", "or 8", Some(&first)).await);
+
+    let fetched = mail(fetch_raw(&mut stream, "bob", &bob).await);
+    assert_eq!(fetched.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), [first.as_str(), second.as_str()]);
+    assert!(!fetched[1].replaced_queued);
+    assert_eq!(fetched[1].supersedes.as_deref(), Some(first.as_str()));
+}
+
+#[tokio::test]
+async fn superseding_a_message_to_someone_else_frees_no_room() {
+    let dir = TempDir::new("supersede-elsewhere");
+    let addr = start_server_with(&dir.0.join("server"), &["--mailbox-quota", "1"]).await;
+    let (carol, bob, dave) = (CryptoManager::new(), CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    for (id, crypto) in [("carol", &carol), ("bob", &bob), ("dave", &dave)] {
+        register_raw(&mut stream, id, crypto).await;
+    }
+    let (for_dave, _) = sent(carol_sends(&mut stream, &carol, "dave", "for dave", None).await);
+
+    // Bob's mailbox is empty; naming dave's message must not count below zero
+    let (_, replaced) = sent(carol_sends(&mut stream, &carol, "bob", "for bob", Some(&for_dave)).await);
+    assert!(!replaced, "a message in another mailbox was replaced");
+    assert_eq!(status_of(&mut stream, &for_dave, &carol).await, DeliveryStatus::Stored);
+
+    // Now full, and dave's message frees nothing in it
+    assert_mailbox_full(carol_sends(&mut stream, &carol, "bob", "for bob, again", Some(&for_dave)).await);
+    assert_eq!(mail(fetch_raw(&mut stream, "dave", &dave).await).len(), 1);
+}
+
+/// Carol's grant of her mailbox to dave, issued now.
+async fn grant_dave(stream: &mut TcpStream, carol: &CryptoManager, scope: DelegationScope, expires_at: DateTime<Utc>) -> ServerResponse {
+    let issued_at = Utc::now();
+    let signature = carol.sign_with_context(crypto::context::DELEGATE, &delegation_payload("carol", "dave", scope, issued_at, expires_at));
+    let delegation = Delegation {
+        owner: "carol".to_string(),
+        delegate: "dave".to_string(),
+        scope,
+        issued_at,
+        expires_at,
+        signature: hex::encode(signature.to_bytes()),
+        revoked_at: None,
+    };
+    exchange(stream, &ServerCommand::Delegate { delegation }).await
+}
+
+/// Dave's fetch of carol's mailbox, as her delegate.
+async fn dave_fetches_for_carol(stream: &mut TcpStream, dave: &CryptoManager) -> ServerResponse {
+    let signature = dave.sign_with_context(crypto::context::DELEGATED_FETCH, &delegation_ref_payload("carol", "dave"));
+    let fetch = ServerCommand::GetMessages {
+        client_id: "dave".to_string(),
+        since: None,
+        on_behalf_of: Some("carol".to_string()),
+        signature: Some(hex::encode(signature.to_bytes())),
+        challenge: None,
+    };
+    answer_as(stream, fetch, dave).await
+}
+
+/// Dave's send of `text` to bob, as carol.
+async fn dave_sends_as_carol(stream: &mut TcpStream, dave: &CryptoManager, text: &str) -> ServerResponse {
+    let mut send = raw_send(stream, ("dave", dave), "bob", text, Utc::now()).await;
+    if let ServerCommand::Send { on_behalf_of, sender_key, .. } = &mut send {
+        *on_behalf_of = Some("carol".to_string());
+        *sender_key = None;
+    }
+    exchange(stream, &send).await
+}
+
+fn list_delegations(carol: &CryptoManager, signed_at: DateTime<Utc>) -> ServerCommand {
+    let signature = carol.sign_with_context(crypto::context::DELEGATION_REF, &delegations_payload("carol", signed_at));
+    ServerCommand::ListDelegations { client_id: "carol".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) }
+}
+
+#[tokio::test]
+async fn delegates_act_only_within_their_grant_until_it_is_revoked() {
+    let dir = TempDir::new("delegation");
+    let addr = start_server(&dir.0.join("server")).await;
+    let (carol, dave, bob) = (CryptoManager::new(), CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    for (id, crypto) in [("carol", &carol), ("dave", &dave), ("bob", &bob)] {
+        register_raw(&mut stream, id, crypto).await;
+    }
+    let to_carol = raw_send(&mut stream, ("bob", &bob), "carol", "for carol", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &to_carol).await, ServerResponse::MessageSent { .. }));
+
+    assert_refused(dave_fetches_for_carol(&mut stream, &dave).await, error_code::NOT_DELEGATED);
+    let in_an_hour = Utc::now() + chrono::Duration::hours(1);
+    assert!(matches!(grant_dave(&mut stream, &carol, DelegationScope::Read, in_an_hour).await, ServerResponse::Ok));
+    let ServerResponse::Messages { messages } = dave_fetches_for_carol(&mut stream, &dave).await else { panic!("dave couldn't read for carol") };
+    assert_eq!(messages.len(), 1);
+    // Reading doesn't let him write as her
+    assert_refused(dave_sends_as_carol(&mut stream, &dave, "from carol, honest").await, error_code::NOT_DELEGATED);
+
+    assert!(matches!(grant_dave(&mut stream, &carol, DelegationScope::ReadSend, in_an_hour).await, ServerResponse::Ok));
+    assert!(matches!(dave_sends_as_carol(&mut stream, &dave, "on carol's behalf").await, ServerResponse::MessageSent { .. }));
+    let received = mail(fetch_raw(&mut stream, "bob", &bob).await);
+    assert_eq!((received[0].sender_id.as_str(), received[0].sent_by.as_deref()), ("carol", Some("dave")));
+
+    // Revoked, he's cut off on his next request, on the connection he already has
+    let signature = carol.sign_with_context(crypto::context::DELEGATION_REF, &delegation_ref_payload("carol", "dave"));
+    let revoke = ServerCommand::RevokeDelegation { owner: "carol".to_string(), delegate: "dave".to_string(), signature: hex::encode(signature.to_bytes()) };
+    assert!(matches!(exchange(&mut stream, &revoke).await, ServerResponse::Ok));
+    assert_refused(dave_fetches_for_carol(&mut stream, &dave).await, error_code::NOT_DELEGATED);
+    assert_refused(dave_sends_as_carol(&mut stream, &dave, "one more").await, error_code::NOT_DELEGATED);
+
+    let list = list_delegations(&carol, Utc::now());
+    let ServerResponse::Delegations { delegations, audit } = exchange(&mut stream, &list).await else { panic!("carol couldn't list her delegations") };
+    assert_eq!(delegations.len(), 1);
+    assert!(delegations[0].revoked_at.is_some());
+    let actions: Vec<&str> = audit.iter().map(|a| a.action.as_str()).collect();
+    assert_eq!(actions, ["fetched", "sent"]);
+    // A captured listing can't be asked again
+    assert_refused(exchange(&mut stream, &list).await, error_code::REPLAYED_REQUEST);
+    assert_refused(exchange(&mut stream, &list_delegations(&carol, Utc::now() - chrono::Duration::hours(1))).await, error_code::STALE_REQUEST);
+}
+
+#[tokio::test]
+async fn delegations_lapse_when_they_expire() {
+    let dir = TempDir::new("delegation-expiry");
+    let addr = start_server(&dir.0.join("server")).await;
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    register_raw(&mut stream, "dave", &dave).await;
+
+    let expires_at = Utc::now() + chrono::Duration::milliseconds(1500);
+    assert!(matches!(grant_dave(&mut stream, &carol, DelegationScope::Read, expires_at).await, ServerResponse::Ok));
+    assert!(matches!(dave_fetches_for_carol(&mut stream, &dave).await, ServerResponse::Messages { .. }));
+    tokio::time::sleep((expires_at - Utc::now()).to_std().unwrap_or_default() + Duration::from_millis(100)).await;
+    match dave_fetches_for_carol(&mut stream, &dave).await {
+        ServerResponse::Error { code, message, .. } => {
+            assert_eq!(code.as_deref(), Some(error_code::NOT_DELEGATED));
+            assert!(message.contains("expired"), "{}", message);
+        }
+        other => panic!("an expired delegation still reads: {:?}", other),
+    }
+}
+
+/// A group command from `id`, signed now, built by `command` from the
+/// signing time and signature.
+fn group_command(id: &str, crypto: &CryptoManager, action: &str, group: &str, detail: &str,
+    command: impl FnOnce(DateTime<Utc>, String) -> ServerCommand) -> ServerCommand {
+    let signed_at = Utc::now();
+    let signature = crypto.sign_with_context(crypto::context::GROUP, &group_payload(id, action, group, detail, signed_at));
+    command(signed_at, hex::encode(signature.to_bytes()))
+}
+
+/// `send`, as one member's copy of a message to `group`.
+fn in_group(mut send: ServerCommand, group: &str) -> ServerCommand {
+    if let ServerCommand::Send { group: field, .. } = &mut send {
+        *field = Some(group.to_string());
+    }
+    send
+}
+
+#[tokio::test]
+async fn only_members_send_to_a_group_and_only_owners_rename_it() {
+    let dir = TempDir::new("groups");
+    let addr = start_server(&dir.0.join("server")).await;
+    let (carol, dave, erin) = (CryptoManager::new(), CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    for (id, crypto) in [("carol", &carol), ("dave", &dave), ("erin", &erin)] {
+        register_raw(&mut stream, id, crypto).await;
+    }
+
+    let members = |list: &[&str]| list.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    let create = |group: &str, list: Vec<String>| group_command("carol", &carol, "CreateGroup", group, &list.join(","), |signed_at, signature| ServerCommand::CreateGroup {
+        client_id: "carol".to_string(), group: group.to_string(), members: list, signed_at, signature,
+    });
+    assert_refused(exchange(&mut stream, &create("team", members(&["dave", "nobody"]))).await, error_code::UNKNOWN_CLIENT);
+    let ServerResponse::Group { group } = exchange(&mut stream, &create("team", members(&["dave"]))).await else { panic!("carol couldn't create the group") };
+    assert_eq!(group.members.get("carol"), Some(&GroupRole::Owner));
+    assert_eq!(group.members.get("dave"), Some(&GroupRole::Member));
+    assert_refused(exchange(&mut stream, &create("team", members(&["erin"]))).await, error_code::GROUP_EXISTS);
+
+    // Members can send each other group copies; erin is neither sender nor recipient
+    let send = raw_send(&mut stream, ("carol", &carol), "dave", "lunch?", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &in_group(send, "team")).await, ServerResponse::MessageSent { .. }));
+    let send = raw_send(&mut stream, ("erin", &erin), "dave", "let me in", Utc::now()).await;
+    assert_refused(exchange(&mut stream, &in_group(send, "team")).await, error_code::NOT_GROUP_MEMBER);
+    let send = raw_send(&mut stream, ("carol", &carol), "erin", "psst", Utc::now()).await;
+    assert_refused(exchange(&mut stream, &in_group(send, "team")).await, error_code::NOT_GROUP_MEMBER);
+    let delivered = mail(fetch_raw(&mut stream, "dave", &dave).await);
+    assert_eq!(delivered.iter().map(|message| message.group.as_deref()).collect::<Vec<_>>(), [Some("team")]);
+
+    let rename = |id: &str, crypto: &CryptoManager| group_command(id, crypto, "RenameGroup", "team", "crew", |signed_at, signature| ServerCommand::RenameGroup {
+        client_id: id.to_string(), group: "team".to_string(), new_name: "crew".to_string(), signed_at, signature,
+    });
+    assert_refused(exchange(&mut stream, &rename("dave", &dave)).await, error_code::NOT_GROUP_OWNER);
+    assert!(matches!(exchange(&mut stream, &rename("carol", &carol)).await, ServerResponse::Group { group } if group.name == "crew"));
+
+    // Once carol leaves, dave is the owner and she can't send to the group
+    let leave = group_command("carol", &carol, "LeaveGroup", "crew", "", |signed_at, signature| ServerCommand::LeaveGroup {
+        client_id: "carol".to_string(), group: "crew".to_string(), signed_at, signature,
+    });
+    assert!(matches!(exchange(&mut stream, &leave).await, ServerResponse::Ok));
+    let send = raw_send(&mut stream, ("carol", &carol), "dave", "still here?", Utc::now()).await;
+    assert_refused(exchange(&mut stream, &in_group(send, "crew")).await, error_code::NOT_GROUP_MEMBER);
+    let list = group_command("dave", &dave, "ListGroups", "", "", |signed_at, signature| ServerCommand::ListGroups {
+        client_id: "dave".to_string(), signed_at, signature,
+    });
+    let ServerResponse::Groups { groups } = exchange(&mut stream, &list).await else { panic!("dave couldn't list groups") };
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].members.len(), 1);
+    assert_eq!(groups[0].members.get("dave"), Some(&GroupRole::Owner));
+    let get = group_command("carol", &carol, "GetGroup", "crew", "", |signed_at, signature| ServerCommand::GetGroup {
+        client_id: "carol".to_string(), group: "crew".to_string(), signed_at, signature,
+    });
+    assert_refused(exchange(&mut stream, &get).await, error_code::NOT_GROUP_MEMBER);
+}
+
+#[tokio::test]
+async fn members_are_told_when_a_group_is_made_with_them() {
+    let dir = TempDir::new("group-notice");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut dave = connect_client(&dir.0.join("dave"), "dave", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let members = vec!["dave".to_string()];
+    let create = group_command("carol", &carol, "CreateGroup", "team", "dave", |signed_at, signature| ServerCommand::CreateGroup {
+        client_id: "carol".to_string(), group: "team".to_string(), members, signed_at, signature,
+    });
+    assert!(matches!(exchange(&mut stream, &create).await, ServerResponse::Group { .. }));
+
+    assert!(dave.receive().await.is_empty(), "the notice came as a message");
+    let told = notices(&mut dave);
+    assert_eq!(told.len(), 1, "{:?}", told);
+    let (_, notice) = &told[0];
+    assert_eq!(notice.notice_type, notice_type::GROUP_ADDED);
+    assert_eq!((notice.field("group"), notice.field("by")), ("team", "carol"));
+
+    // The creator isn't told of their own group
+    assert!(fetch_raw(&mut stream, "carol", &carol).await.is_empty());
+}
+
+/// An Ack of `message_ids` by `id`, signed now.
+fn ack(id: &str, crypto: &CryptoManager, message_ids: Vec<String>) -> ServerCommand {
+    let signed_at = Utc::now();
+    let signature = crypto.sign_with_context(crypto::context::ACK, &ack_payload(id, &message_ids, signed_at));
+    ServerCommand::Ack { client_id: id.to_string(), message_ids, signed_at, signature: hex::encode(signature.to_bytes()) }
+}
+
+#[tokio::test]
+async fn an_ack_that_cant_be_saved_deletes_nothing() {
+    let dir = TempDir::new("ack-failure");
+    let addr = start_server(&dir.0.join("server")).await;
+    let (bob, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "bob", &bob).await;
+    register_raw(&mut stream, "carol", &carol).await;
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "keep me", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+    let ids: Vec<String> = fetch_raw(&mut stream, "bob", &bob).await.into_iter().map(|m| m.id).collect();
+    assert_eq!(ids.len(), 1);
+
+    let inject = dir.0.join("server").join(storage::INJECT_FAILURES_FILE);
+    std::fs::write(&inject, "mailboxes").unwrap();
+    assert_refused(exchange(&mut stream, &ack("bob", &bob, ids.clone())).await, error_code::SERVER_STORAGE_UNAVAILABLE);
+    std::fs::remove_file(&inject).unwrap();
+
+    let still_there: Vec<String> = fetch_raw(&mut stream, "bob", &bob).await.into_iter().map(|m| m.id).collect();
+    assert_eq!(still_there, ids, "the failed ack deleted the message");
+    assert!(matches!(exchange(&mut stream, &ack("bob", &bob, ids)).await, ServerResponse::Ok));
+    assert!(fetch_raw(&mut stream, "bob", &bob).await.is_empty(), "a second fetch came back with acknowledged messages");
+}
+
+#[tokio::test]
+async fn send_with_a_stale_timestamp_is_refused() {
+    let dir = TempDir::new("stale-send");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let stale = raw_send(&mut stream, ("carol", &carol), "bob", "an hour late", Utc::now() - chrono::Duration::hours(1)).await;
+    assert_refused(exchange(&mut stream, &stale).await, error_code::STALE_SEND);
+    let ahead = raw_send(&mut stream, ("carol", &carol), "bob", "an hour early", Utc::now() + chrono::Duration::hours(1)).await;
+    assert_refused(exchange(&mut stream, &ahead).await, error_code::STALE_SEND);
+    assert!(bob.receive().await.is_empty(), "a stale send was delivered");
+}
+
+#[tokio::test]
+async fn config_file_settings_yield_to_flags() {
+    let dir = TempDir::new("config");
+    let config = dir.0.join("server.toml");
+    let data_dir = dir.0.join("from-file");
+    std::fs::write(&config, format!("bind = \"127.0.0.1:1\"\ndata-dir = {:?}\nmax-message-size = 256\n", data_dir)).unwrap();
+    let addr = free_addr();
+    let args: Vec<String> = ["server", "--config", &config.to_string_lossy(), "--bind", &addr].iter().map(|arg| arg.to_string()).collect();
+    let options = ServerOptions::from_args(&args).unwrap();
+    assert_eq!(options.bind, addr);
+    assert_eq!(options.data_dir, data_dir);
+    serve(options).await;
+
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    let long = raw_send(&mut stream, ("carol", &carol), "bob", &"x".repeat(300), Utc::now()).await;
+    assert_refused(exchange(&mut stream, &long).await, error_code::MESSAGE_TOO_LARGE);
+    let short = raw_send(&mut stream, ("carol", &carol), "bob", "short", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &short).await, ServerResponse::MessageSent { .. }));
+    assert_eq!(bob.receive().await.len(), 1);
+}
+
+#[tokio::test]
+async fn every_flag_can_come_from_the_config_file() {
+    let dir = TempDir::new("config-flags");
+    let config = dir.0.join("server.toml");
+    std::fs::write(&config, "admins = [\"root\"]\nunknown-recipients = \"reject\"\nregistration = \"open\"\npair-burst = 50.0\n").unwrap();
+    let start = |name: &str, flags: &[&str]| {
+        let mut args: Vec<String> = ["server", "--config", &config.to_string_lossy()].iter().map(|arg| arg.to_string()).collect();
+        args.extend(flags.iter().map(|flag| flag.to_string()));
+        let mut options = ServerOptions::from_args(&args).unwrap();
+        options.data_dir = dir.0.join(name);
+        options.bind = free_addr();
+        serve(options)
+    };
+
+    for (addr, overridden) in [(start("from-file", &[]).await, false), (start("from-flags", &["--admin", "carol", "--unknown-recipients", "accept"]).await, true)] {
+        let (root, carol, dave) = (CryptoManager::new(), CryptoManager::new(), CryptoManager::new());
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        register_raw(&mut stream, "root", &root).await;
+        register_raw(&mut stream, "carol", &carol).await;
+
+        let ban = admin_batch(("root", &root), vec![AdminAction::Ban { client_id: "carol".to_string(), reason: None }], Utc::now());
+        let banned = matches!(exchange(&mut stream, &ban).await, ServerResponse::AdminBatch { applied: true, .. });
+        assert_eq!(banned, !overridden, "--admin didn't replace the file's admins");
+        if banned {
+            let unban = admin_batch(("root", &root), vec![AdminAction::Unban { client_id: "carol".to_string() }], Utc::now());
+            assert!(matches!(exchange(&mut stream, &unban).await, ServerResponse::AdminBatch { applied: true, .. }));
+        }
+        let stored = matches!(exchange(&mut stream, &send_to_dave(&carol, &dave)).await, ServerResponse::MessageSent { .. });
+        assert_eq!(stored, overridden, "the unknown-recipients policy came from the wrong place");
+    }
+
+    let bad_key = dir.0.join("bad.toml");
+    std::fs::write(&bad_key, "unknown-recipients = \"sometimes\"\n").unwrap();
+    let args: Vec<String> = ["server", "--config", &bad_key.to_string_lossy()].iter().map(|arg| arg.to_string()).collect();
+    assert!(ServerOptions::from_args(&args).is_err());
+}
+
+#[test]
+fn unknown_and_malformed_flags_are_refused() {
+    let parse = |args: &[&str]| ServerOptions::from_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+    let refused: [&[&str]; 6] = [
+        &["server", "--bogus"],
+        &["server", "stray"],
+        &["server", "--bind"],
+        &["server", "--mailbox-quota", "lots"],
+        &["server", "--storage", "paper"],
+        &["server", "--repair"],
+    ];
+    for args in refused {
+        assert!(parse(args).is_err(), "{:?} was accepted", args);
+    }
+    let help = parse(&["server", "--help"]).err().expect("--help started a server").to_string();
+    assert!(help.contains("--data-dir") && help.contains("--max-clock-skew-secs"), "help is missing flags:\n{}", help);
+
+    assert_eq!(parse(&["server", "--listen=127.0.0.1:9", "--admin", "alice", "--admin", "bob"]).unwrap().bind, "127.0.0.1:9");
+}
+
+#[tokio::test]
+async fn unusable_data_dir_stops_startup() {
+    let dir = TempDir::new("bad-data-dir");
+    let file = dir.0.join("not-a-dir");
+    std::fs::write(&file, "").unwrap();
+    let args: Vec<String> = ["server", "--data-dir", &file.join("data").to_string_lossy()].iter().map(|arg| arg.to_string()).collect();
+    let error = Server::new(ServerOptions::from_args(&args).unwrap()).await.err().expect("the server started without a data directory");
+    assert!(error.to_string().contains("data directory"), "unclear error: {}", error);
+
+    let args: Vec<String> = ["server", "--max-message-size", "65536", "--max-frame-bytes", "1024"].iter().map(|arg| arg.to_string()).collect();
+    assert!(ServerOptions::from_args(&args).is_err(), "a frame too small for the largest message was accepted");
+}
+
+#[tokio::test]
+async fn messages_sent_just_before_shutdown_survive_a_restart() {
+    let dir = TempDir::new("restart");
+    let data_dir = dir.0.join("server");
+    let (addr, server) = start_stoppable_server(&data_dir).await;
+    connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "see you on the other side").await.unwrap();
+
+    server.stop().await;
+    assert!(TcpStream::connect(&addr).await.is_err(), "still taking connections after shutdown");
+
+    let addr = start_server(&data_dir).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, "see you on the other side");
+    assert_eq!(received[0].signature, Some(SignatureCheck::Verified));
+}
+
+#[tokio::test]
+async fn a_crash_mid_append_costs_only_the_last_message() {
+    let dir = TempDir::new("truncated");
+    let data_dir = dir.0.join("server");
+    let (addr, server) = start_stoppable_server(&data_dir).await;
+    connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "first").await.unwrap();
+    alice.send("bob", "second").await.unwrap();
+    server.stop().await;
+
+    // A crash partway through appending the second message to bob's log
+    let log = data_dir.join("messages").join("bob.jsonl");
+    let whole = std::fs::read(&log).unwrap();
+    std::fs::write(&log, &whole[..whole.len() - 40]).unwrap();
+
+    let addr = start_server(&data_dir).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "third").await.unwrap();
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let received = bob.receive().await;
+    let bodies: Vec<&str> = received.iter().map(|view| view.body.as_str()).collect();
+    assert!(bodies.contains(&"first") && bodies.contains(&"third"), "messages around the torn line were lost: {:?}", bodies);
+    assert!(!bodies.contains(&"second"));
+}
+
+/// Forward connections to `target` until aborted, which cuts those open too.
+async fn cuttable_link(target: String) -> (String, tokio::task::JoinHandle<()>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let link = tokio::spawn(async move {
+        let mut open = tokio::task::JoinSet::new();
+        loop {
+            let (mut inbound, _) = listener.accept().await.unwrap();
+            let target = target.clone();
+            open.spawn(async move {
+                if let Ok(mut outbound) = TcpStream::connect(&target).await {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+            });
+        }
+    });
+    (addr, link)
+}
+
+#[tokio::test]
+async fn a_promoted_standby_has_every_message_the_primary_acknowledged() {
+    let dir = TempDir::new("standby");
+    let secret = dir.0.join("replication_secret");
+    std::fs::write(&secret, "shared by primary and standby").unwrap();
+    let (replication, secret) = (free_addr(), secret.to_string_lossy().to_string());
+    let options = |data: &str, role: [&str; 2]| {
+        // One sender to one recipient, as fast as it can go
+        let args: Vec<String> = ["server", "--admin", "root", "--replication-secret-file", &secret, "--insecure-permissions-ok", "--pair-burst", "1000", role[0], role[1]]
+            .iter().map(|arg| arg.to_string()).collect();
+        let mut options = ServerOptions::from_args(&args).unwrap();
+        options.data_dir = dir.0.join(data);
+        options.bind = free_addr();
+        options
+    };
+    let (primary, kill) = serve_stoppable(options("primary", ["--replicate-on", &replication])).await;
+    let (root, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&primary).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    register_raw(&mut stream, "root", &root).await;
+    register_raw(&mut stream, "carol", &carol).await;
+    drop(connect_client(&dir.0.join("bob"), "bob", &primary).await);
+
+    // The standby takes over the primary's identity along with its data.
+    // Once it has caught up, it confirms each write before the primary answers it
+    std::fs::create_dir_all(dir.0.join("standby")).unwrap();
+    std::fs::copy(dir.0.join("primary").join("server_keys"), dir.0.join("standby").join("server_keys")).unwrap();
+    let (link, cut) = cuttable_link(replication.clone()).await;
+    let standby = serve(options("standby", ["--standby-of", &link])).await;
+    let mut watching = TcpStream::connect(&standby).await.unwrap();
+    for attempt in 0.. {
+        let lookup = ServerCommand::GetPublicKey { client_id: "bob".to_string() };
+        if matches!(exchange(&mut watching, &lookup).await, ServerResponse::PublicKeys { .. }) {
+            break;
+        }
+        assert!(attempt < 100, "the standby never caught up");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let mut sends = Vec::new();
+    for i in 0..200 {
+        let text = format!("message {}", i);
+        sends.push((raw_send(&mut stream, ("carol", &carol), "bob", &text, Utc::now()).await, text));
+    }
+    let acknowledged = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let load = {
+        let (addr, acknowledged) = (primary.clone(), std::sync::Arc::clone(&acknowledged));
+        tokio::spawn(async move {
+            let mut stream = TcpStream::connect(&addr).await.unwrap();
+            stream.set_nodelay(true).unwrap();
+            for (send, text) in sends {
+                match try_exchange(&mut stream, &send).await {
+                    Ok(ServerResponse::MessageSent { .. }) => acknowledged.lock().unwrap().push(text),
+                    _ => return,
+                }
+            }
+        })
+    };
+    while acknowledged.lock().unwrap().len() < 20 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    // The primary dies: the standby and the clients lose it at once
+    cut.abort();
+    load.abort();
+    kill.stop().await;
+    let acknowledged = acknowledged.lock().unwrap().clone();
+    assert!(acknowledged.len() < 200, "the primary went down after the load, not during it");
+
+    let signed_at = Utc::now();
+    let signature = root.sign_with_context(crypto::context::PROMOTE, &promote_payload("root", signed_at));
+    let promote = ServerCommand::Promote { admin_id: "root".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) };
+    assert!(matches!(exchange(&mut watching, &promote).await, ServerResponse::Ok));
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &standby).await;
+    let received: Vec<String> = bob.receive().await.into_iter().map(|message| message.body).collect();
+    for text in &acknowledged {
+        assert!(received.contains(text), "{} was acknowledged but never reached the standby", text);
+    }
+}
+
+#[tokio::test]
+async fn no_acknowledged_send_is_lost_across_a_burst_of_write_failures() {
+    let dir = TempDir::new("failure-burst");
+    let data = dir.0.join("server");
+    let options = || {
+        let args: Vec<String> = ["server", "--pair-burst", "1000"].iter().map(|arg| arg.to_string()).collect();
+        let mut options = ServerOptions::from_args(&args).unwrap();
+        options.data_dir = data.clone();
+        options.bind = free_addr();
+        options
+    };
+    let (addr, server) = serve_stoppable(options()).await;
+    let (bob, carol) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    register_raw(&mut stream, "bob", &bob).await;
+    register_raw(&mut stream, "carol", &carol).await;
+    let mut sends = Vec::new();
+    for i in 0..40 {
+        sends.push(raw_send(&mut stream, ("carol", &carol), "bob", &format!("message {}", i), Utc::now()).await);
+    }
+
+    // Sends during the burst are refused; those after it are retried until
+    // the server has found its storage working again
+    let inject = data.join(storage::INJECT_FAILURES_FILE);
+    let (mut acknowledged, mut refused) = (Vec::new(), 0);
+    for (i, send) in sends.iter().enumerate() {
+        if i == 10 {
+            std::fs::write(&inject, "").unwrap();
+        }
+        if i == 20 {
+            std::fs::remove_file(&inject).unwrap();
+        }
+        loop {
+            match exchange(&mut stream, send).await {
+                ServerResponse::MessageSent { message_id, .. } => {
+                    acknowledged.push(message_id);
+                    break;
+                }
+                response => assert_refused(response, error_code::SERVER_STORAGE_UNAVAILABLE),
+            }
+            refused += 1;
+            if i < 20 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+    assert!(refused >= 10, "the burst refused only {} sends", refused);
+    assert_eq!(acknowledged.len(), 30);
+
+    // Exactly the acknowledged messages are stored, in memory and on disk
+    let mut fetched: Vec<String> = fetch_raw(&mut stream, "bob", &bob).await.into_iter().map(|m| m.id).collect();
+    fetched.sort();
+    acknowledged.sort();
+    assert_eq!(fetched, acknowledged);
+    server.stop().await;
+    let (addr, _server) = serve_stoppable(options()).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let mut reloaded: Vec<String> = fetch_raw(&mut stream, "bob", &bob).await.into_iter().map(|m| m.id).collect();
+    reloaded.sort();
+    assert_eq!(reloaded, acknowledged);
+}
+
+#[tokio::test]
+async fn switching_to_sqlite_storage_keeps_messages_and_clients() {
+    let dir = TempDir::new("sqlite");
+    let data_dir = dir.0.join("server");
+    let (addr, server) = start_stoppable_server(&data_dir).await;
+    connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "sent while on json").await.unwrap();
+    server.stop().await;
+
+    let args: Vec<String> = ["server", "--storage", "sqlite"].iter().map(|arg| arg.to_string()).collect();
+    let mut options = ServerOptions::from_args(&args).unwrap();
+    options.data_dir = data_dir.clone();
+    options.bind = free_addr();
+    let addr = serve(options).await;
+    assert!(data_dir.join("messages.migrated").exists(), "the mailbox logs weren't taken over");
+
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "sent on sqlite").await.unwrap();
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let received = bob.receive().await;
+    let bodies: Vec<&str> = received.iter().map(|view| view.body.as_str()).collect();
+    assert_eq!(bodies, ["sent while on json", "sent on sqlite"]);
+    assert!(received.iter().all(|view| view.signature == Some(SignatureCheck::Verified)));
+}
+
+/// Stop the server, let `tamper` rewrite its stored key log, and start it
+/// again; returns the new address.
+async fn tamper_with_key_log(data_dir: &Path, server: StopServer, tamper: impl FnOnce(&mut Vec<KeyLogEntry>)) -> String {
+    server.stop().await;
+    let path = data_dir.join("key_log.json");
+    let mut entries: Vec<KeyLogEntry> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    tamper(&mut entries);
+    std::fs::write(&path, serde_json::to_string_pretty(&entries).unwrap()).unwrap();
+    start_server(data_dir).await
+}
+
+#[tokio::test]
+async fn an_altered_key_log_is_not_trusted() {
+    let dir = TempDir::new("key-log-altered");
+    let data_dir = dir.0.join("server");
+    let (addr, server) = start_stoppable_server(&data_dir).await;
+    connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "signed by the real alice").await.unwrap();
+
+    // Swap alice's logged key without fixing up the chain
+    let forged = hex::encode(CryptoManager::new().get_ed25519_public_key().as_bytes());
+    let addr = tamper_with_key_log(&data_dir, server, |entries| {
+        entries.iter_mut().find(|entry| entry.client_id == "alice").unwrap().public_key = forged;
+    }).await;
+
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].signature, Some(SignatureCheck::Unverified), "a broken chain was trusted");
+}
+
+#[tokio::test]
+async fn a_rewritten_key_log_does_not_vouch_for_the_message() {
+    let dir = TempDir::new("key-log-rewritten");
+    let data_dir = dir.0.join("server");
+    let (addr, server) = start_stoppable_server(&data_dir).await;
+    connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    alice.send("bob", "signed by the real alice").await.unwrap();
+
+    // Substitute alice's key and rebuild the chain so it hashes cleanly,
+    // as a server that meant to hide it would
+    let forged = hex::encode(CryptoManager::new().get_ed25519_public_key().as_bytes());
+    let addr = tamper_with_key_log(&data_dir, server, |entries| {
+        entries.iter_mut().find(|entry| entry.client_id == "alice").unwrap().public_key = forged;
+        let mut prev_hash = String::new();
+        for entry in entries.iter_mut() {
+            entry.prev_hash = prev_hash;
+            entry.hash = entry.compute_hash();
+            prev_hash = entry.hash.clone();
+        }
+        assert!(KeyLogEntry::verify_chain(entries).is_ok());
+    }).await;
+
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].signature, Some(SignatureCheck::Invalid), "the substituted key vouched for alice's message");
+}
+
+#[tokio::test]
+async fn a_revoked_key_is_refused_as_soon_as_the_revocation_is_acknowledged() {
+    let dir = TempDir::new("revoked-cached-key");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    // Sending puts carol's key in the server's cache
+    let send = raw_send(&mut stream, ("carol", &carol), "bob", "before", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+
+    revoke_own_key(&mut stream, "carol", &carol).await;
+
+    // Straight after, on another connection, the cached key must be gone
+    let mut other = TcpStream::connect(&addr).await.unwrap();
+    let send = raw_send(&mut other, ("carol", &carol), "bob", "after", Utc::now()).await;
+    assert!(matches!(exchange(&mut other, &send).await, ServerResponse::Error { .. }), "the revoked key still signed a send");
+
+    let bodies: Vec<String> = bob.receive().await.into_iter().map(|view| view.body).collect();
+    assert_eq!(bodies, ["before"]);
+}
+
+#[tokio::test]
+async fn a_revoked_id_is_not_up_for_grabs() {
+    let dir = TempDir::new("revoked-id");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let carol = CryptoManager::new();
+    register_raw(&mut stream, "carol", &carol).await;
+    revoke_own_key(&mut stream, "carol", &carol).await;
+
+    // With the key on file revoked, a stranger's own key doesn't make the id theirs
+    let mallory = CryptoManager::new();
+    assert_challenge_failed(answer_as(&mut stream, registration("carol", &mallory, None), &mallory).await);
+    let fetch = ServerCommand::GetMessages { client_id: "carol".to_string(), since: None, on_behalf_of: None, signature: None, challenge: None };
+    assert_challenge_failed(answer_as(&mut stream, fetch, &mallory).await);
+    let ServerResponse::KeyHistory { public_key, .. } = exchange(&mut stream, &ServerCommand::GetKeyHistory { client_id: "carol".to_string() }).await else {
+        panic!("carol has no key history");
+    };
+    assert_eq!(public_key, Some(hex::encode(carol.get_ed25519_public_key().as_bytes())), "mallory's key replaced carol's");
+}
+
+#[tokio::test]
+async fn a_recovery_key_registers_a_replacement_for_a_revoked_one() {
+    let dir = TempDir::new("revoked-recovery");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let (recovery_secret, recovery_public) = crypto::generate_recovery_keypair();
+    let dave = CryptoManager::new();
+    assert!(matches!(answer_as(&mut stream, registration("dave", &dave, Some(recovery_public.clone())), &dave).await, ServerResponse::Registered { .. }));
+    revoke_own_key(&mut stream, "dave", &dave).await;
+
+    // The replacement key alone can't vouch for itself
+    let replacement = CryptoManager::new();
+    let register = registration("dave", &replacement, Some(recovery_public));
+    assert_challenge_failed(answer_as(&mut stream, register.clone(), &replacement).await);
+
+    let ServerResponse::Challenge { nonce } = exchange(&mut stream, &register).await else { panic!("Register wasn't challenged") };
+    let signature = crypto::sign_with_secret(&recovery_secret, crypto::context::CHALLENGE, &challenge_payload("dave", &nonce)).unwrap();
+    let answer = ChallengeAnswer { signature: hex::encode(signature.to_bytes()), nonce };
+    assert!(matches!(exchange(&mut stream, &register.answering(answer)).await, ServerResponse::Registered { .. }));
+    let send = raw_send(&mut stream, ("dave", &replacement), "dave", "new key", Utc::now()).await;
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+}
+
+/// Sign `payload` with `crypto`'s identity key but no context label, as
+/// clients from before the labels did.
+fn sign_unlabeled(crypto: &CryptoManager, payload: &[u8]) -> String {
+    use ed25519_dalek::Signer;
+    let secret = ed25519_dalek::SecretKey::from_bytes(&crypto.export_secrets().0).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    hex::encode(ed25519_dalek::Keypair { secret, public }.sign(payload).to_bytes())
+}
+
+/// `send` re-signed without a context label; without a send time, over the
+/// ciphertext alone as clients from before `sent_at` did.
+fn unlabeled(mut send: ServerCommand, crypto: &CryptoManager) -> ServerCommand {
+    let ServerCommand::Send { signature, encrypted_content, sent_at, message_id, recipient_id, .. } = &mut send else { unreachable!() };
+    let ciphertext = hex::decode(encrypted_content).unwrap();
+    *signature = sign_unlabeled(crypto, &match sent_at {
+        Some(at) => send_payload(message_id, recipient_id, &ciphertext, *at),
+        None => ciphertext,
+    });
+    send
+}
+
+/// Register `id` on `stream` as an old client would: offering no protocol
+/// version, and signing its key without a label.
+async fn register_legacy(stream: &mut TcpStream, id: &str, crypto: &CryptoManager) {
+    let mut register = registration(id, crypto, None);
+    let ServerCommand::Register { protocol_version, features, x25519_public_key, x25519_signature, .. } = &mut register else { unreachable!() };
+    (*protocol_version, *features) = (None, None);
+    *x25519_signature = Some(sign_unlabeled(crypto, &sender_key_payload(id, x25519_public_key.as_deref().unwrap())));
+    assert!(matches!(answer_as(stream, register, crypto).await, ServerResponse::Registered { protocol_version: None, .. }));
+}
+
+/// Register `id` offering `protocol` and `features`, and return what the
+/// server agreed to.
+async fn negotiate_raw(stream: &mut TcpStream, id: &str, crypto: &CryptoManager, protocol: u32, features: u64) -> (Option<u32>, Option<u64>) {
+    let mut register = registration(id, crypto, None);
+    let ServerCommand::Register { protocol_version, features: offered, .. } = &mut register else { unreachable!() };
+    (*protocol_version, *offered) = (Some(protocol), Some(features));
+    match answer_as(stream, register, crypto).await {
+        ServerResponse::Registered { protocol_version, features, .. } => (protocol_version, features),
+        other => panic!("{} didn't register: {:?}", id, other),
+    }
+}
+
+#[tokio::test]
+async fn mismatched_peers_agree_on_what_both_have() {
+    let dir = TempDir::new("negotiation");
+    let addr = start_server_with(&dir.0.join("server"), &["--disable-feature", "user_data"]).await;
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+
+    // A newer client, offering everything and more, is met at protocol 2
+    // without the feature the server switched off or the bits it doesn't know
+    let (protocol, features) = negotiate_raw(&mut stream, "carol", &carol, 9, u64::MAX).await;
+    assert_eq!(protocol, Some(2));
+    let features = features.unwrap();
+    assert_eq!(features & 1 << 2, 0, "user_data was agreed: {:b}", features);
+    assert_eq!(features >> 6, 0, "unknown bits came back: {:b}", features);
+    assert_ne!(features & 1, 0, "message_search wasn't agreed: {:b}", features);
+
+    // An older one gets nothing its version didn't have, whatever it claims
+    let (protocol, features) = negotiate_raw(&mut stream, "dave", &dave, 1, 1 | 1 << 5).await;
+    assert_eq!((protocol, features), (Some(1), Some(1)));
+
+    // And the switched-off feature is refused
+    let signed_at = Utc::now();
+    let signature = carol.sign_with_context(crypto::context::USER_DATA_REF, &user_data_ref_payload("carol", "theme", signed_at));
+    let get = ServerCommand::GetUserData { client_id: "carol".to_string(), key: "theme".to_string(), signed_at, signature: hex::encode(signature.to_bytes()) };
+    assert_refused(exchange(&mut stream, &get).await, error_code::FEATURE_DISABLED);
+}
+
+#[tokio::test]
+async fn unlabeled_signatures_are_refused_from_negotiating_clients() {
+    let dir = TempDir::new("unlabeled-default");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+
+    let send = unlabeled(raw_send(&mut stream, ("carol", &carol), "bob", "old client", Utc::now()).await, &carol);
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::Error { .. }), "an unlabeled send signature was accepted");
+    let mut undated = raw_send(&mut stream, ("carol", &carol), "bob", "no send time", Utc::now()).await;
+    let ServerCommand::Send { sent_at, .. } = &mut undated else { unreachable!() };
+    *sent_at = None;
+    assert_refused(exchange(&mut stream, &unlabeled(undated, &carol)).await, error_code::STALE_SEND);
+    // Nor does one that never registered get the benefit of the doubt
+    let mut unregistered = TcpStream::connect(&addr).await.unwrap();
+    assert!(matches!(exchange(&mut unregistered, &send).await, ServerResponse::Error { .. }));
+    assert!(bob.receive().await.is_empty());
+}
+
+#[tokio::test]
+async fn another_old_client_cannot_replay_an_old_clients_send() {
+    let dir = TempDir::new("unlabeled-replay");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut carol = connect_client(&dir.0.join("carol"), "carol", &addr).await;
+    let mut dave = connect_client(&dir.0.join("dave"), "dave", &addr).await;
+    let bob = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_legacy(&mut stream, "bob", &bob).await;
+    let send = unlabeled(raw_send(&mut stream, ("bob", &bob), "carol", "old client", Utc::now()).await, &bob);
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+    assert_eq!(carol.receive().await.len(), 1);
+    // A signature over the ciphertext alone could be moved to any id or
+    // recipient, so not even bob may send one
+    let mut undated = raw_send(&mut stream, ("bob", &bob), "carol", "no send time", Utc::now()).await;
+    let ServerCommand::Send { sent_at, .. } = &mut undated else { unreachable!() };
+    *sent_at = None;
+    let undated = unlabeled(undated, &bob);
+    assert_refused(exchange(&mut stream, &undated).await, error_code::STALE_SEND);
+
+    // Nor does a throwaway id that didn't negotiate get bob's allowance
+    let mallory = CryptoManager::new();
+    let mut other = TcpStream::connect(&addr).await.unwrap();
+    register_legacy(&mut other, "mallory", &mallory).await;
+    let mut replay = undated;
+    let ServerCommand::Send { message_id, recipient_id, .. } = &mut replay else { unreachable!() };
+    (*message_id, *recipient_id) = (new_message_id(), "dave".to_string());
+    assert!(matches!(exchange(&mut other, &replay).await, ServerResponse::Error { .. }), "bob's send was replayed to dave");
+    assert!(dave.receive().await.is_empty());
+}
+
+#[tokio::test]
+async fn unlabeled_signatures_cover_sends_from_clients_that_dont_negotiate() {
+    let dir = TempDir::new("unlabeled-legacy");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_legacy(&mut stream, "carol", &carol).await;
+
+    let send = unlabeled(raw_send(&mut stream, ("carol", &carol), "bob", "old client", Utc::now()).await, &carol);
+    assert!(matches!(exchange(&mut stream, &send).await, ServerResponse::MessageSent { .. }));
+    let mut undated = raw_send(&mut stream, ("carol", &carol), "bob", "no send time", Utc::now()).await;
+    let ServerCommand::Send { sent_at, .. } = &mut undated else { unreachable!() };
+    *sent_at = None;
+    assert_refused(exchange(&mut stream, &unlabeled(undated, &carol)).await, error_code::STALE_SEND);
+
+    // The same send on a connection that negotiated is refused
+    let mut current = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut current, "carol", &carol).await;
+    let replay = unlabeled(raw_send(&mut current, ("carol", &carol), "bob", "old client again", Utc::now()).await, &carol);
+    assert!(matches!(exchange(&mut current, &replay).await, ServerResponse::Error { .. }));
+
+    // A challenge no old client ever answered takes only a labeled signature
+    let fetch = ServerCommand::GetMessages { client_id: "carol".to_string(), since: None, on_behalf_of: None, signature: None, challenge: None };
+    let ServerResponse::Challenge { nonce } = exchange(&mut stream, &fetch).await else { panic!("GetMessages wasn't challenged") };
+    let answer = ChallengeAnswer { signature: sign_unlabeled(&carol, &challenge_payload("carol", &nonce)), nonce };
+    assert_challenge_failed(exchange(&mut stream, &fetch.clone().answering(answer)).await);
+
+    // Nor does a signature made for sending pass as an answer
+    let ServerResponse::Challenge { nonce } = exchange(&mut stream, &fetch).await else { panic!("GetMessages wasn't challenged") };
+    let signature = carol.sign_with_context(crypto::context::SEND, &challenge_payload("carol", &nonce));
+    let answer = ChallengeAnswer { signature: hex::encode(signature.to_bytes()), nonce };
+    assert_challenge_failed(exchange(&mut stream, &fetch.answering(answer)).await);
+
+    let senders: Vec<String> = bob.receive().await.into_iter().map(|view| view.sender).collect();
+    assert_eq!(senders, ["carol"]);
+}
+
+#[tokio::test]
+async fn a_frame_over_the_limit_is_refused() {
+    let dir = TempDir::new("frame-limit");
+    let args: Vec<String> = ["server", "--max-frame-bytes", "1024"].iter().map(|arg| arg.to_string()).collect();
+    let mut options = ServerOptions::from_args(&args).unwrap();
+    options.data_dir = dir.0.join("server");
+    options.bind = free_addr();
+    let addr = serve(options).await;
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let lookup = ServerCommand::GetPublicKey { client_id: "x".repeat(2000) };
+    assert_refused(exchange(&mut stream, &lookup).await, error_code::FRAME_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn a_100_kb_message_arrives_whole() {
+    let dir = TempDir::new("large-message");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+
+    let text: String = (0..100 * 1024).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    alice.send("bob", &text).await.unwrap();
+
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body.len(), text.len());
+    assert!(received[0].body == text, "the body came back altered");
+    assert_eq!(received[0].signature, Some(SignatureCheck::Verified));
+}
+
+#[tokio::test]
+async fn every_connection_shares_one_server_identity_and_storage() {
+    let dir = TempDir::new("shared-server");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    let carol = CryptoManager::new();
+
+    let mut first = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut first, "carol", &carol).await;
+    drop(first);
+
+    // A fresh connection knows carol, and sends as her
+    let mut second = TcpStream::connect(&addr).await.unwrap();
+    let send = raw_send(&mut second, ("carol", &carol), "bob", "from another connection", Utc::now()).await;
+    assert!(matches!(exchange(&mut second, &send).await, ServerResponse::MessageSent { .. }));
+
+    let received = bob.receive().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, "from another connection");
+    assert_eq!(received[0].signature, Some(SignatureCheck::Verified), "the directory was signed by a different server key");
+}
+
+fn new_contact_key() -> String {
+    hex::encode(CryptoManager::new().get_x25519_public_key().as_bytes())
+}
+
+#[tokio::test]
+async fn contacts_converge_across_devices_sharing_an_identity() {
+    let dir = TempDir::new("contact-sync");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut laptop = connect_client(&dir.0.join("laptop"), "bob", &addr).await;
+    let mut phone = connect_device(&dir.0.join("phone"), &dir.0.join("laptop"), "bob", &addr).await;
+
+    let carol = new_contact_key();
+    laptop.add_contact_key("carol", &carol).await.unwrap();
+    laptop.trust_contact("carol").await.unwrap();
+    phone.receive().await;
+    assert_eq!(phone.contact("carol"), Some((carol.clone(), true)));
+
+    let dave = new_contact_key();
+    phone.add_contact_key("dave", &dave).await.unwrap();
+    laptop.receive().await;
+    assert_eq!(laptop.contact("dave"), Some((dave.clone(), false)));
+
+    // Each trusts a different key for erin without seeing the other's: the
+    // later one is a conflict, not a silent overwrite
+    let (erin_laptop, erin_phone) = (new_contact_key(), new_contact_key());
+    laptop.add_contact_key("erin", &erin_laptop).await.unwrap();
+    laptop.trust_contact("erin").await.unwrap();
+    phone.add_contact_key("erin", &erin_phone).await.unwrap();
+    phone.trust_contact("erin").await.unwrap();
+    laptop.receive().await;
+    assert_eq!(laptop.contact("erin"), Some((erin_laptop.clone(), true)));
+    assert_eq!(phone.contact("erin"), Some((erin_phone.clone(), true)));
+
+    // Settling it on the laptop carries over to the phone
+    laptop.add_contact_key("erin", &erin_laptop).await.unwrap();
+    laptop.trust_contact("erin").await.unwrap();
+    phone.receive().await;
+    assert_eq!(phone.contact("erin"), Some((erin_laptop.clone(), true)));
+
+    // A device added later catches up from a full sync
+    let mut tablet = connect_device(&dir.0.join("tablet"), &dir.0.join("laptop"), "bob", &addr).await;
+    assert_eq!(laptop.sync_contacts().await.unwrap(), 3);
+    tablet.receive().await;
+    assert_eq!(tablet.contact("carol"), Some((carol, true)));
+    assert_eq!(tablet.contact("dave"), Some((dave, false)));
+    assert_eq!(tablet.contact("erin"), Some((erin_laptop, true)));
+}
+
+/// When the server last saw `id`, as `watcher` is told.
+async fn last_seen(stream: &mut TcpStream, watcher: (&str, &CryptoManager), id: &str) -> Option<DateTime<Utc>> {
+    let contacts = vec![id.to_string()];
+    let signature = watcher.1.sign_with_context(crypto::context::PRESENCE, &presence_payload(watcher.0, &contacts));
+    let ask = ServerCommand::GetPresence { client_id: watcher.0.to_string(), contacts, signature: hex::encode(signature.to_bytes()) };
+    match exchange(stream, &ask).await {
+        ServerResponse::Presence { entries } => entries.into_iter().find(|entry| entry.client_id == id).and_then(|entry| entry.last_seen),
+        other => panic!("{} couldn't see who is online: {:?}", watcher.0, other),
+    }
+}
+
+#[tokio::test]
+async fn only_signed_activity_counts_as_seen() {
+    let dir = TempDir::new("last-seen");
+    let addr = start_server(&dir.0.join("server")).await;
+    let (carol, dave) = (CryptoManager::new(), CryptoManager::new());
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "carol", &carol).await;
+    register_raw(&mut stream, "dave", &dave).await;
+    let registered = last_seen(&mut stream, ("dave", &dave), "carol").await;
+    assert!(registered.is_some());
+
+    // Anyone can send a heartbeat naming carol
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let mut anyone = TcpStream::connect(&addr).await.unwrap();
+    assert!(matches!(exchange(&mut anyone, &ServerCommand::Heartbeat { client_id: "carol".to_string() }).await, ServerResponse::Ok));
+    assert_eq!(last_seen(&mut stream, ("dave", &dave), "carol").await, registered, "an unsigned heartbeat moved last_seen");
+
+    // Only she can fetch her mail
+    fetch_raw(&mut stream, "carol", &carol).await;
+    assert!(last_seen(&mut stream, ("dave", &dave), "carol").await > registered);
+}
+
+#[tokio::test]
+async fn a_presence_watch_fetches_and_acks_nothing() {
+    let dir = TempDir::new("presence-watch");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    alice.send("bob", "while you were watching").await.unwrap();
+
+    // A status board on bob's identity, as `presence-watch` runs one
+    let key_file = ClientPaths::resolve(Some(&dir.0.join("bob").to_string_lossy())).unwrap().key_file("bob");
+    let paths = ClientPaths::resolve(Some(&dir.0.join("board").to_string_lossy())).unwrap();
+    paths.create().unwrap();
+    let mut board = Client::new("bob", paths, key_file, false).unwrap();
+    board.offer_presence_only();
+    board.connect_to(&addr).await.unwrap();
+
+    let watcher = CryptoManager::new();
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    register_raw(&mut stream, "watcher", &watcher).await;
+    let before = last_seen(&mut stream, ("watcher", &watcher), "bob").await;
+    {
+        let events = board.presence_events(vec!["alice".to_string()]);
+        futures::pin_mut!(events);
+        let first = events.next().await.unwrap().unwrap();
+        assert_eq!((first.client_id.as_str(), first.online), ("alice", true));
+    }
+    assert_eq!(last_seen(&mut stream, ("watcher", &watcher), "bob").await, before, "the board fetched or acked bob's mail");
+
+    // Everything sent to bob, before the board and since, is still his to fetch
+    alice.send("bob", "after the board came up").await.unwrap();
+    let bodies: Vec<String> = bob.receive().await.into_iter().map(|view| view.body).collect();
+    assert_eq!(bodies, ["while you were watching", "after the board came up"]);
+}
+
+#[tokio::test]
+async fn sync_messages_outlast_the_device_that_sent_them() {
+    let dir = TempDir::new("contact-sync-first");
+    let addr = start_server(&dir.0.join("server")).await;
+    let mut laptop = connect_client(&dir.0.join("laptop"), "bob", &addr).await;
+    let mut phone = connect_device(&dir.0.join("phone"), &dir.0.join("laptop"), "bob", &addr).await;
+
+    // The laptop drops its own sync messages, but mustn't delete them for the phone
+    let carol = new_contact_key();
+    laptop.add_contact_key("carol", &carol).await.unwrap();
+    laptop.trust_contact("carol").await.unwrap();
+    laptop.receive().await;
+    laptop.receive().await;
+    phone.receive().await;
+    assert_eq!(phone.contact("carol"), Some((carol.clone(), true)));
+
+    // Nor the phone, having applied them, for a device that fetches later
+    let mut tablet = connect_device(&dir.0.join("tablet"), &dir.0.join("laptop"), "bob", &addr).await;
+    phone.receive().await;
+    tablet.receive().await;
+    assert_eq!(tablet.contact("carol"), Some((carol, true)));
+}
+
+#[tokio::test]
+async fn validate_outgoing_agrees_with_the_servers_size_limit() {
+    let dir = TempDir::new("outgoing-size");
+    let addr = start_server_with(&dir.0.join("server"), &["--max-message-size", "256"]).await;
+    let mut alice = connect_client(&dir.0.join("alice"), "alice", &addr).await;
+    let mut bob = connect_client(&dir.0.join("bob"), "bob", &addr).await;
+    alice.send("bob", "hi").await.unwrap();
+    bob.receive().await;
+
+    // The largest text that fits, found from the estimate alone
+    let fitting = (1..256).map(|len| "x".repeat(len)).take_while(|text| alice.validate_outgoing("bob", text).unwrap().fits()).last().unwrap();
+    let estimate = alice.validate_outgoing("bob", &fitting).unwrap();
+    assert_eq!((estimate.ciphertext_bytes, estimate.max_message_size), (256, Some(256)));
+    alice.send("bob", &fitting).await.unwrap();
+    let over = format!("{}x", fitting);
+    assert_eq!(bob.receive().await.into_iter().map(|view| view.body).collect::<Vec<_>>(), [fitting]);
+
+    // One byte more is refused before it reaches the server
+    let estimate = alice.validate_outgoing("bob", &over).unwrap();
+    assert!(!estimate.fits());
+    let refused = alice.send("bob", &over).await.unwrap_err();
+    assert_eq!(refused.downcast_ref::<MessageTooLarge>(), Some(&MessageTooLarge { size: 257, max: 256 }));
+    assert!(bob.receive().await.is_empty());
+}
@@ -0,0 +1,57 @@
+//! Simulation scenarios on fixed seeds, so a failure reproduces exactly by
+//! running the same test again.
+
+use messaging_proto::sim::{NetConfig, Sim, SERVER_ADDR};
+use std::time::Duration;
+
+#[tokio::test(start_paused = true)]
+async fn partition_during_send() {
+    let mut sim = Sim::new("partition", 7, NetConfig::lossy());
+    let server = sim.start_server().await;
+    sim.add_client("bob").await;
+    sim.add_client("carol").await;
+    let network = sim.network.clone();
+    let cut = async move {
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        network.partition("bob", SERVER_ADDR);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        network.heal("bob", SERVER_ADDR);
+    };
+    tokio::join!(sim.send_many("bob", "carol", 8), cut);
+    sim.send_many("carol", "bob", 3).await;
+    sim.check(server).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn server_restart_mid_transfer() {
+    let mut sim = Sim::new("restart", 11, NetConfig::calm());
+    let mut server = sim.start_server().await;
+    sim.add_client("bob").await;
+    sim.add_client("carol").await;
+    assert_eq!(sim.send_many("bob", "carol", 4).await, 4);
+    sim.receive("carol").await;
+    sim.network.configure(NetConfig::lossy());
+    sim.send_many("bob", "carol", 4).await;
+    server.bounce().await;
+    sim.send_many("bob", "carol", 4).await;
+    sim.receive("carol").await;
+    server.stop().await;
+    sim.send_many("carol", "bob", 2).await;
+    sim.check(server).await;
+    assert!(sim.acknowledged() >= 4);
+}
+
+#[tokio::test(start_paused = true)]
+async fn clock_skew_between_client_and_server() {
+    let mut sim = Sim::new("skew", 23, NetConfig::calm());
+    let server = sim.start_server().await;
+    sim.add_client("bob").await;
+    sim.add_client("carol").await;
+    sim.client("bob").skew_clock(chrono::Duration::seconds(90));
+    sim.client("carol").skew_clock(chrono::Duration::seconds(-90));
+    sim.send_many("bob", "carol", 3).await;
+    sim.send_many("carol", "bob", 3).await;
+    sim.receive("bob").await;
+    sim.receive("carol").await;
+    sim.check(server).await;
+}
@@ -0,0 +1,504 @@
+//! The storage conformance suite against every backend, and checks of
+//! what each does beyond it.
+
+mod common;
+
+use messaging_proto::backend::{self, JsonBackend, RedbBackend, SqliteBackend, StorageBackend, StorageKind};
+use messaging_proto::storage::{self, conformance, BatchOp, Storage, StorageUnavailable};
+use messaging_proto::types::{Ban, ClientInfo, DeliveryStatus, Message};
+use chrono::{DateTime, Duration, Utc};
+use common::TempDir;
+use std::path::Path;
+
+fn open(kind: StorageKind, dir: &Path) -> Box<dyn StorageBackend> {
+    match kind {
+        StorageKind::Json => Box::new(JsonBackend::open(dir).unwrap()),
+        StorageKind::Sqlite => Box::new(SqliteBackend::open(&dir.join(backend::SQLITE_FILE)).unwrap()),
+        StorageKind::Redb => Box::new(RedbBackend::open(&dir.join(backend::REDB_FILE)).unwrap()),
+    }
+}
+
+fn client(id: &str, seen: DateTime<Utc>) -> ClientInfo {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "public_key": format!("{}-key", id),
+        "registered_at": seen,
+        "last_seen": seen,
+    }))
+    .unwrap()
+}
+
+fn message(id: &str, from: &str, to: &str, at: DateTime<Utc>) -> Message {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "sender_id": from,
+        "recipient_id": to,
+        "content": format!("ciphertext of {}", id),
+        "timestamp": at,
+        "encrypted": true,
+        "signature": null,
+    }))
+    .unwrap()
+}
+
+fn ids(messages: &[Message]) -> Vec<&str> {
+    messages.iter().map(|m| m.id.as_str()).collect()
+}
+
+/// Each check below runs once per backend, as `<check>::json`,
+/// `<check>::sqlite` and `<check>::redb`.
+macro_rules! on_every_backend {
+    ($($check:ident),* $(,)?) => {
+        $(
+            mod $check {
+                use super::*;
+
+                #[tokio::test]
+                async fn json() {
+                    super::$check(StorageKind::Json).await;
+                }
+
+                #[tokio::test]
+                async fn sqlite() {
+                    super::$check(StorageKind::Sqlite).await;
+                }
+
+                #[tokio::test]
+                async fn redb() {
+                    super::$check(StorageKind::Redb).await;
+                }
+            }
+        )*
+    };
+}
+
+on_every_backend!(
+    conforms,
+    failed_writes_leave_the_mailbox_alone,
+    failed_batches_roll_back,
+    failed_claims_stay_held,
+    failed_cancels_keep_the_message,
+    failed_removals_put_messages_back,
+);
+
+async fn conforms(kind: StorageKind) {
+    conformance::run(|dir| open(kind, dir)).await;
+}
+
+/// A delivery mark or delete that can't be saved leaves the mailbox as it
+/// was, in memory as well as on disk.
+async fn failed_writes_leave_the_mailbox_alone(kind: StorageKind) {
+    let dir = TempDir::new(&format!("storage-failures-{}", kind.name()));
+    let data_dir = dir.0.to_str().unwrap();
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    for id in ["m1", "m2", "m3"] {
+        storage.add_message(message(id, "alice", "bob", Utc::now())).await.unwrap();
+    }
+
+    let inject = dir.0.join(storage::INJECT_FAILURES_FILE);
+    std::fs::write(&inject, "").unwrap();
+    let failed = storage.fetch_messages("bob", None).await.unwrap_err();
+    assert!(failed.is::<StorageUnavailable>(), "{}", failed);
+    assert_eq!(storage.message_status("alice", "m1").await, DeliveryStatus::Stored);
+    let failed = storage.delete_messages("bob", &["m2".to_string(), "m3".to_string()]).await.unwrap_err();
+    assert!(failed.is::<StorageUnavailable>(), "{}", failed);
+    std::fs::remove_file(&inject).unwrap();
+
+    assert_eq!(ids(&storage.fetch_messages("bob", None).await.unwrap()), ["m1", "m2", "m3"]);
+    drop(storage);
+    let reopened = open(kind, &dir.0);
+    assert_eq!(ids(&reopened.get_messages_for_client("bob").unwrap()), ["m1", "m2", "m3"]);
+}
+
+/// An admin batch that fails partway, or can't be saved, leaves everything
+/// as it was: earlier changes in it are rolled back.
+async fn failed_batches_roll_back(kind: StorageKind) {
+    let dir = TempDir::new(&format!("storage-batch-{}", kind.name()));
+    let data_dir = dir.0.to_str().unwrap();
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    storage.add_message(message("m1", "alice", "bob", Utc::now())).await.unwrap();
+    assert!(storage.add_invite_code("taken", "root").await.unwrap());
+    let ops = [
+        BatchOp::Ban { client_id: "mallory".to_string(), ban: Ban { by: "root".to_string(), at: Utc::now(), reason: None } },
+        BatchOp::PruneMailbox { client_id: "bob".to_string() },
+        BatchOp::AddInviteCode { code: "taken".to_string(), created_by: "root".to_string() },
+    ];
+
+    let failed = storage.transaction(&ops).await.unwrap_err();
+    assert_eq!(failed.op, Some(2), "{}", failed);
+    assert!(!storage.is_banned("mallory").await);
+    assert_eq!(storage.message_status("alice", "m1").await, DeliveryStatus::Stored);
+
+    let inject = dir.0.join(storage::INJECT_FAILURES_FILE);
+    std::fs::write(&inject, "").unwrap();
+    let failed = storage.transaction(&ops[..2]).await.unwrap_err();
+    assert_eq!(failed.op, None, "{}", failed);
+    std::fs::remove_file(&inject).unwrap();
+    assert!(!storage.is_banned("mallory").await);
+    assert_eq!(storage.message_status("alice", "m1").await, DeliveryStatus::Stored);
+    drop(storage);
+
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    assert!(!storage.is_banned("mallory").await);
+    assert_eq!(storage.message_status("alice", "m1").await, DeliveryStatus::Stored);
+    assert_eq!(storage.transaction(&ops[..2]).await.unwrap(), [1, 1]);
+    assert!(storage.is_banned("mallory").await);
+    assert_eq!(storage.message_status("alice", "m1").await, DeliveryStatus::Unknown);
+}
+
+/// A claim of held invites that can't be saved, whichever file fails,
+/// leaves the messages held and the mailbox empty, in memory and on disk.
+async fn failed_claims_stay_held(kind: StorageKind) {
+    let dir = TempDir::new(&format!("storage-claim-{}", kind.name()));
+    let data_dir = dir.0.to_str().unwrap();
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    storage.hold_invite(message("m1", "carol", "dave", Utc::now())).await.unwrap();
+    storage.hold_invite(message("m2", "erin", "dave", Utc::now())).await.unwrap();
+
+    let inject = dir.0.join(storage::INJECT_FAILURES_FILE);
+    for component in ["clock", "mailboxes", "invites"] {
+        std::fs::write(&inject, component).unwrap();
+        let failed = storage.claim_invites("dave").await.unwrap_err();
+        assert!(failed.is::<StorageUnavailable>(), "{}", failed);
+        std::fs::remove_file(&inject).unwrap();
+        assert_eq!(storage.invite_counts("carol", "dave").await, (1, 2), "{} failed", component);
+        assert!(storage.fetch_messages("dave", None).await.unwrap().is_empty(), "{} failed", component);
+    }
+    drop(storage);
+
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    assert_eq!(storage.invite_counts("carol", "dave").await, (1, 2));
+    assert!(storage.fetch_messages("dave", None).await.unwrap().is_empty());
+    assert_eq!(ids(&storage.claim_invites("dave").await.unwrap()), ["m1", "m2"]);
+    assert_eq!(storage.invite_counts("carol", "dave").await, (0, 0));
+    assert_eq!(ids(&storage.fetch_messages("dave", None).await.unwrap()), ["m1", "m2"]);
+}
+
+/// A cancel that can't be saved leaves the message where it was, whether
+/// in a mailbox or held for an unregistered id.
+async fn failed_cancels_keep_the_message(kind: StorageKind) {
+    let dir = TempDir::new(&format!("storage-cancel-{}", kind.name()));
+    let data_dir = dir.0.to_str().unwrap();
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    storage.add_message(message("m1", "alice", "bob", Utc::now())).await.unwrap();
+    storage.hold_invite(message("h1", "alice", "dave", Utc::now())).await.unwrap();
+
+    let inject = dir.0.join(storage::INJECT_FAILURES_FILE);
+    std::fs::write(&inject, "").unwrap();
+    for id in ["m1", "h1"] {
+        let failed = storage.cancel_message("alice", id).await.unwrap_err();
+        assert!(failed.is::<StorageUnavailable>(), "{}", failed);
+        assert_eq!(storage.message_status("alice", id).await, DeliveryStatus::Stored);
+    }
+    std::fs::remove_file(&inject).unwrap();
+    drop(storage);
+
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    assert_eq!(storage.invite_counts("alice", "dave").await, (1, 1));
+    for id in ["m1", "h1"] {
+        assert_eq!(storage.cancel_message("alice", id).await.unwrap(), DeliveryStatus::Cancelled);
+    }
+    assert!(storage.fetch_messages("bob", None).await.unwrap().is_empty());
+    assert_eq!(storage.invite_counts("alice", "dave").await, (0, 0));
+}
+
+/// Sweeps and quarantines that can't be saved put the messages back.
+async fn failed_removals_put_messages_back(kind: StorageKind) {
+    let dir = TempDir::new(&format!("storage-removals-{}", kind.name()));
+    let data_dir = dir.0.to_str().unwrap();
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    let mut late = message("late", "alice", "bob", Utc::now());
+    late.deliver_by = Some(Utc::now() - Duration::minutes(1));
+    storage.add_message(late).await.unwrap();
+    storage.add_message(message("bad", "alice", "bob", Utc::now())).await.unwrap();
+    storage.hold_invite(message("bad-held", "alice", "dave", Utc::now())).await.unwrap();
+    let bad = ["bad".to_string(), "bad-held".to_string()].into_iter().collect();
+
+    let inject = dir.0.join(storage::INJECT_FAILURES_FILE);
+    for component in ["mailboxes", "invites", "quarantine"] {
+        std::fs::write(&inject, component).unwrap();
+        if component == "mailboxes" {
+            assert!(storage.take_undelivered().await.unwrap_err().is::<StorageUnavailable>());
+        }
+        assert!(storage.quarantine_messages(&bad).await.unwrap_err().is::<StorageUnavailable>(), "{} failed", component);
+        for id in ["late", "bad", "bad-held"] {
+            assert_eq!(storage.message_status("alice", id).await, DeliveryStatus::Stored, "{} failed", component);
+        }
+    }
+    std::fs::remove_file(&inject).unwrap();
+    drop(storage);
+
+    let storage = Storage::new(data_dir, kind).await.unwrap();
+    assert_eq!(ids(&storage.take_undelivered().await.unwrap()), ["late"]);
+    assert_eq!(storage.quarantine_messages(&bad).await.unwrap(), 2);
+    assert!(storage.fetch_messages("bob", None).await.unwrap().is_empty());
+    assert_eq!(storage.invite_counts("alice", "dave").await, (0, 0));
+}
+
+/// `#[tokio::test]` runs on one thread, where a load that blocked the
+/// runtime instead of awaiting it would never finish.
+#[tokio::test]
+async fn the_clock_is_saved_ahead_rather_than_on_every_message() {
+    let dir = TempDir::new("storage-clock");
+    let data_dir = dir.0.to_str().unwrap();
+    let storage = Storage::new(data_dir, StorageKind::Json).await.unwrap();
+    let (first, _) = storage.add_message(message("m0", "alice", "bob", Utc::now())).await.unwrap();
+    // The mark saved with the first message covers the ones after it
+    std::fs::write(dir.0.join(storage::INJECT_FAILURES_FILE), "clock").unwrap();
+    let mut handed_out = vec![first];
+    for i in 1..50 {
+        handed_out.push(storage.add_message(message(&format!("m{}", i), "alice", "bob", Utc::now())).await.unwrap().0);
+    }
+    std::fs::remove_file(dir.0.join(storage::INJECT_FAILURES_FILE)).unwrap();
+    assert!(handed_out.windows(2).all(|pair| pair[0] < pair[1]));
+    // With the messages gone, only the saved mark keeps a restart from going back
+    let all: Vec<String> = (0..50).map(|i| format!("m{}", i)).collect();
+    storage.delete_messages("bob", &all).await.unwrap();
+    drop(storage);
+
+    let storage = Storage::new(data_dir, StorageKind::Json).await.unwrap();
+    let (after, _) = storage.add_message(message("m50", "alice", "bob", Utc::now())).await.unwrap();
+    assert!(after > handed_out[49], "{} came after {}", after, handed_out[49]);
+}
+
+#[tokio::test]
+async fn storage_loads_on_a_current_thread_runtime() {
+    let load = |dir: &Path, kind| {
+        let data_dir = dir.to_str().unwrap().to_string();
+        async move {
+            tokio::time::timeout(std::time::Duration::from_secs(10), Storage::new(&data_dir, kind))
+                .await
+                .expect("loading storage hung")
+                .unwrap()
+        }
+    };
+    for kind in [StorageKind::Json, StorageKind::Sqlite, StorageKind::Redb] {
+        let dir = TempDir::new(&format!("storage-load-{}", kind.name()));
+        let storage = load(&dir.0, kind).await;
+        storage.add_message(message("m1", "alice", "bob", Utc::now())).await.unwrap();
+        drop(storage);
+
+        let reloaded = load(&dir.0, kind).await;
+        assert_eq!(ids(&reloaded.fetch_messages("bob", None).await.unwrap()), ["m1"], "{} lost the message", kind.name());
+    }
+}
+
+#[test]
+fn sqlite_and_redb_take_over_the_json_files() {
+    for kind in [StorageKind::Sqlite, StorageKind::Redb] {
+        let dir = TempDir::new(&format!("backend-migrate-{}", kind.name()));
+        let start = Utc::now();
+        {
+            let json = backend::open(StorageKind::Json, &dir.0).unwrap();
+            json.register_client(&client("alice", start)).unwrap();
+            json.add_message(&message("m1", "alice", "bob", start)).unwrap();
+        }
+
+        let taken = backend::open(kind, &dir.0).unwrap();
+        assert_eq!(taken.name(), kind.name());
+        assert!(taken.get_client_info("alice").unwrap().is_some());
+        assert_eq!(ids(&taken.get_messages_for_client("bob").unwrap()), ["m1"]);
+        assert!(!dir.0.join(backend::MESSAGES_DIR).exists());
+        assert!(dir.0.join("messages.migrated").exists());
+    }
+}
+
+#[test]
+fn json_storage_imports_only_into_an_empty_backend() {
+    let (source, target) = (TempDir::new("import-source"), TempDir::new("import-target"));
+    let start = Utc::now();
+    {
+        let json = JsonBackend::open(&source.0).unwrap();
+        json.register_client(&client("alice", start)).unwrap();
+        for (i, id) in ["m1", "m2"].iter().enumerate() {
+            json.add_message(&message(id, "alice", "bob", start + Duration::seconds(i as i64))).unwrap();
+        }
+    }
+
+    let redb = RedbBackend::open(&target.0.join(backend::REDB_FILE)).unwrap();
+    assert!(backend::import_json(&target.0.join("nothing-here"), &redb).is_err());
+    assert_eq!(backend::import_json(&source.0, &redb).unwrap(), (1, 2));
+    assert_eq!(ids(&redb.get_messages_for_client("bob").unwrap()), ["m1", "m2"]);
+    assert!(source.0.join(backend::MESSAGES_DIR).exists(), "the import moved the JSON files");
+    assert!(backend::import_json(&source.0, &redb).is_err(), "a second import overwrote the first");
+}
+
+fn log_size(dir: &Path, client_id: &str) -> u64 {
+    std::fs::metadata(dir.join(backend::MESSAGES_DIR).join(format!("{}.jsonl", client_id))).map_or(0, |m| m.len())
+}
+
+#[test]
+fn compaction_keeps_every_live_message() {
+    let dir = TempDir::new("backend-compact");
+    let start = Utc::now();
+    let backend = JsonBackend::open(&dir.0).unwrap().compact_at(4096);
+    for i in 0..60 {
+        backend.add_message(&message(&format!("m{}", i), "alice", "bob", start + Duration::seconds(i))).unwrap();
+    }
+    backend.add_message(&message("small", "bob", "alice", start)).unwrap();
+    // Fetch the first 40, then ack the first 30 of those
+    let mut fetched = backend.get_messages_for_client("bob").unwrap();
+    fetched.truncate(40);
+    for m in &mut fetched {
+        m.delivered_at = Some(start + Duration::minutes(1));
+    }
+    backend.update_messages("bob", &fetched).unwrap();
+    let acked: Vec<String> = (0..30).map(|i| format!("m{}", i)).collect();
+    assert_eq!(backend.delete_messages("bob", &acked).unwrap(), 30);
+
+    let before = backend.get_all_messages().unwrap();
+    let grown = log_size(&dir.0, "bob");
+    assert_eq!(backend.compact().unwrap(), 1, "only bob's log is over the threshold");
+    assert!(log_size(&dir.0, "bob") < grown / 2, "compaction left {} of {} bytes", log_size(&dir.0, "bob"), grown);
+    assert_eq!(backend.compact().unwrap(), 0, "a freshly compacted log was compacted again");
+
+    let after = backend.get_messages_for_client("bob").unwrap();
+    assert_eq!(ids(&after), ids(&before["bob"]));
+    assert_eq!(after.iter().filter(|m| m.delivered_at.is_some()).count(), 10);
+    drop(backend);
+    let reopened = JsonBackend::open(&dir.0).unwrap();
+    assert_eq!(ids(&reopened.get_messages_for_client("bob").unwrap()), ids(&before["bob"]));
+    assert_eq!(ids(&reopened.get_messages_for_client("alice").unwrap()), ["small"]);
+}
+
+#[test]
+fn last_seen_times_are_logged_until_the_clients_file_is_written() {
+    let dir = TempDir::new("backend-last-seen");
+    let start = Utc::now();
+    {
+        let backend = JsonBackend::open(&dir.0).unwrap().compact_at(4096);
+        backend.register_client(&client("alice", start)).unwrap();
+        backend.register_client(&client("bob", start)).unwrap();
+        let written = std::fs::read(dir.0.join(backend::CLIENTS_FILE)).unwrap();
+        for i in 1..=20 {
+            backend.update_last_seen("alice", start + Duration::seconds(i)).unwrap();
+        }
+        assert_eq!(std::fs::read(dir.0.join(backend::CLIENTS_FILE)).unwrap(), written, "a last-seen time rewrote the clients file");
+        assert!(dir.0.join(backend::LAST_SEEN_FILE).exists());
+    }
+
+    let backend = JsonBackend::open(&dir.0).unwrap().compact_at(4096);
+    assert_eq!(backend.get_client_info("alice").unwrap().unwrap().last_seen, start + Duration::seconds(20));
+    assert_eq!(backend.get_client_info("bob").unwrap().unwrap().last_seen, start);
+    // Past the threshold, the log is folded into the clients file
+    for i in 21..=100 {
+        backend.update_last_seen("bob", start + Duration::seconds(i)).unwrap();
+    }
+    backend.compact().unwrap();
+    assert!(!dir.0.join(backend::LAST_SEEN_FILE).exists());
+    // A registration writes the clients file, which then has every time
+    backend.update_last_seen("alice", start + Duration::seconds(200)).unwrap();
+    backend.register_client(&client("carol", start)).unwrap();
+    assert!(!dir.0.join(backend::LAST_SEEN_FILE).exists());
+    // A log left behind by a crash before its removal moves nothing back
+    std::fs::write(dir.0.join(backend::LAST_SEEN_FILE), format!("{}\n", serde_json::json!({ "id": "alice", "at": start }))).unwrap();
+    drop(backend);
+
+    let backend = JsonBackend::open(&dir.0).unwrap();
+    assert_eq!(backend.get_client_info("alice").unwrap().unwrap().last_seen, start + Duration::seconds(200));
+    assert_eq!(backend.get_client_info("bob").unwrap().unwrap().last_seen, start + Duration::seconds(100));
+    assert!(backend.get_client_info("carol").unwrap().is_some());
+}
+
+#[test]
+fn the_mailbox_index_follows_every_change() {
+    let dir = TempDir::new("backend-index");
+    let start = Utc::now();
+    let backend = JsonBackend::open(&dir.0).unwrap().compact_at(1);
+    backend.add_message(&message("m1", "alice", "bob", start)).unwrap();
+    backend.add_message(&message("m2", "alice", "bob", start)).unwrap();
+    assert_eq!(backend.delete_messages("bob", &["m1".to_string(), "m1".to_string(), "m9".to_string()]).unwrap(), 1);
+    assert_eq!(backend.delete_messages("bob", &["m1".to_string()]).unwrap(), 0);
+    assert_eq!(backend.delete_messages("nobody", &["m1".to_string()]).unwrap(), 0);
+    // A deleted message isn't brought back by an update
+    let mut delivered = message("m1", "alice", "bob", start);
+    delivered.delivered_at = Some(start);
+    backend.update_messages("bob", &[delivered]).unwrap();
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), ["m2"]);
+
+    assert_eq!(backend.compact().unwrap(), 1);
+    backend.add_message(&message("m3", "alice", "bob", start)).unwrap();
+    assert_eq!(backend.delete_messages("bob", &["m2".to_string(), "m3".to_string()]).unwrap(), 2);
+    drop(backend);
+
+    // Reopened, the index is read from the log again
+    let backend = JsonBackend::open(&dir.0).unwrap();
+    backend.add_message(&message("m4", "alice", "bob", start)).unwrap();
+    assert_eq!(backend.delete_messages("bob", &["m2".to_string(), "m4".to_string()]).unwrap(), 1);
+    assert!(backend.get_messages_for_client("bob").unwrap().is_empty());
+}
+
+#[test]
+fn messages_added_during_compaction_are_not_lost() {
+    let dir = TempDir::new("backend-compact-busy");
+    let start = Utc::now();
+    let backend = std::sync::Arc::new(JsonBackend::open(&dir.0).unwrap().compact_at(1024));
+    let sender = {
+        let backend = backend.clone();
+        std::thread::spawn(move || {
+            for i in 0..200 {
+                let id = format!("m{}", i);
+                backend.add_message(&message(&id, "alice", "bob", start)).unwrap();
+                // Keep every fourth; the rest are acked straight away
+                if i % 4 != 0 {
+                    backend.delete_messages("bob", &[id]).unwrap();
+                }
+            }
+        })
+    };
+    let mut compactions = 0;
+    while !sender.is_finished() {
+        compactions += backend.compact().unwrap();
+    }
+    sender.join().unwrap();
+    compactions += backend.compact().unwrap();
+    assert!(compactions > 0, "the log was never compacted");
+
+    let expected: Vec<String> = (0..200).step_by(4).map(|i| format!("m{}", i)).collect();
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), expected);
+    drop(backend);
+    assert_eq!(ids(&JsonBackend::open(&dir.0).unwrap().get_messages_for_client("bob").unwrap()), expected);
+}
+
+#[test]
+fn a_torn_log_line_costs_only_that_line() {
+    let dir = TempDir::new("backend-torn");
+    let start = Utc::now();
+    {
+        let backend = JsonBackend::open(&dir.0).unwrap();
+        backend.add_message(&message("m1", "alice", "bob", start)).unwrap();
+        backend.add_message(&message("m2", "alice", "bob", start)).unwrap();
+        backend.add_message(&message("m1", "bob", "alice", start)).unwrap();
+    }
+    // A crash partway through an append
+    let log = dir.0.join(backend::MESSAGES_DIR).join("bob.jsonl");
+    let mut torn = std::fs::read(&log).unwrap();
+    torn.truncate(torn.len() - 20);
+    std::fs::write(&log, torn).unwrap();
+
+    let backend = JsonBackend::open(&dir.0).unwrap();
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), ["m1"]);
+    assert_eq!(ids(&backend.get_messages_for_client("alice").unwrap()), ["m1"]);
+    backend.add_message(&message("m3", "alice", "bob", start)).unwrap();
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), ["m1", "m3"]);
+}
+
+#[test]
+fn an_old_messages_file_is_split_into_logs() {
+    let dir = TempDir::new("backend-split");
+    let start = Utc::now();
+    let old = serde_json::json!({
+        "bob": [message("m1", "alice", "bob", start), message("m2", "alice", "bob", start)],
+        "../x": [message("m1", "alice", "../x", start)],
+    });
+    std::fs::write(dir.0.join(backend::MESSAGES_FILE), old.to_string()).unwrap();
+
+    let backend = JsonBackend::open(&dir.0).unwrap();
+    assert_eq!(ids(&backend.get_messages_for_client("bob").unwrap()), ["m1", "m2"]);
+    assert_eq!(ids(&backend.get_messages_for_client("../x").unwrap()), ["m1"]);
+    assert!(log_size(&dir.0, "bob") > 0);
+    assert!(!dir.0.join(backend::MESSAGES_FILE).exists());
+    assert!(dir.0.join("messages.json.migrated").exists());
+    // Nothing escaped the logs directory
+    assert!(!dir.0.join("x.jsonl").exists());
+}